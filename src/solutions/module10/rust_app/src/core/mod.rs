@@ -2,6 +2,5 @@ mod configuration;
 mod core;
 
 pub use configuration::Config;
-pub use core::{
-    ApplicationError, DataAccess, LoginRequest, RegisterUserRequest, User, UserDetails,
-};
+pub use core::DataAccess;
+pub use workshop_core::{ApplicationError, LoginRequest, RegisterUserRequest, User, UserDetails};
@@ -275,7 +275,8 @@ mod tests {
             Json(RegisterUserRequest {
                 email_address: "test@test.com".to_string(),
                 name: "Test User".to_string(),
-                password: "Testing!23".to_string(),
+                password: "Purple-Otter-Canoe-42".to_string(),
+                phone_number: None,
             }),
         )
         .await;
@@ -299,7 +300,8 @@ mod tests {
             Json(RegisterUserRequest {
                 email_address: "test@test.com".to_string(),
                 name: "Test User".to_string(),
-                password: "Testing!23".to_string(),
+                password: "Purple-Otter-Canoe-42".to_string(),
+                phone_number: None,
             }),
         )
         .await;
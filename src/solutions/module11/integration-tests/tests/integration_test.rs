@@ -22,7 +22,7 @@ async fn when_a_user_registers_they_should_then_be_able_to_login() {
     let result = http_client
         .post(format!("{}users", api_endpoint))
         .header("Content-Type", "application/json")
-        .body(serde_json::json!({"emailAddress": email_under_test, "password": "Testing!23", "name": "James"}).to_string())
+        .body(serde_json::json!({"emailAddress": email_under_test, "password": "Purple-Otter-Canoe-42", "name": "James"}).to_string())
         .send()
         .await;
 
@@ -36,7 +36,7 @@ async fn when_a_user_registers_they_should_then_be_able_to_login() {
         .post(format!("{}login", api_endpoint))
         .header("Content-Type", "application/json")
         .body(
-            serde_json::json!({"emailAddress": email_under_test, "password": "Testing!23"})
+            serde_json::json!({"emailAddress": email_under_test, "password": "Purple-Otter-Canoe-42"})
                 .to_string(),
         )
         .send()
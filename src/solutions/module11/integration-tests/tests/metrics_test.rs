@@ -0,0 +1,36 @@
+use reqwest::Client;
+use rust_users_lib::metrics::{USER_REGISTERED_TOTAL, USER_REGISTRATION_FAILED_TOTAL};
+use rust_users_lib::testing::TestApp;
+use uuid::Uuid;
+
+async fn register(http_client: &Client, app: &TestApp, email_address: &str) -> u16 {
+    http_client
+        .post(format!("{}/users", app.base_url))
+        .header("Content-Type", "application/json")
+        .body(serde_json::json!({"emailAddress": email_address, "password": "Purple-Otter-Canoe-42", "name": "James"}).to_string())
+        .send()
+        .await
+        .unwrap()
+        .status()
+        .as_u16()
+}
+
+#[tokio::test]
+async fn each_successful_registration_should_increment_user_registered_total_exactly_once() {
+    let app = TestApp::spawn().await.unwrap();
+    let http_client = Client::new();
+    let email_under_test = format!("{}@test.com", Uuid::new_v4());
+
+    let before = app.metrics();
+    assert_eq!(register(&http_client, &app, &email_under_test).await, 201);
+    let after_first = app.metrics();
+    assert_eq!(register(&http_client, &app, &email_under_test).await, 409);
+    let after_duplicate = app.metrics();
+
+    assert_eq!(after_first.counter_increase(&before, USER_REGISTERED_TOTAL), 1);
+    assert_eq!(after_duplicate.counter_increase(&after_first, USER_REGISTERED_TOTAL), 0);
+    assert_eq!(
+        after_duplicate.counter_increase(&after_first, USER_REGISTRATION_FAILED_TOTAL),
+        1
+    );
+}
@@ -0,0 +1,4 @@
+// `sqlx::migrate!` embeds the migrations when the crate is compiled, so a new file has to rebuild it
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
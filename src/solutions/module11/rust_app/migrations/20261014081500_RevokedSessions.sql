@@ -0,0 +1,5 @@
+-- Add migration script here
+CREATE TABLE revoked_sessions (
+    token_id VARCHAR(255) PRIMARY KEY,
+    expires_at BIGINT NOT NULL
+);
@@ -0,0 +1,5 @@
+CREATE TABLE magic_link_tokens (
+    token_id VARCHAR(255) PRIMARY KEY,
+    email_address VARCHAR(255) NOT NULL,
+    expires_at BIGINT NOT NULL
+);
@@ -0,0 +1,11 @@
+CREATE TABLE active_sessions (
+    token_id VARCHAR(255) PRIMARY KEY,
+    email_address VARCHAR(255) NOT NULL,
+    user_agent TEXT,
+    last_seen_ip VARCHAR(255),
+    created_at BIGINT NOT NULL,
+    last_seen_at BIGINT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    impersonated_by VARCHAR(255)
+);
+CREATE INDEX active_sessions_email_address ON active_sessions (email_address);
@@ -0,0 +1,10 @@
+CREATE TABLE outbox (
+    id VARCHAR(255) PRIMARY KEY,
+    topic VARCHAR(255) NOT NULL,
+    key VARCHAR(255) NOT NULL,
+    payload TEXT NOT NULL,
+    trace_context VARCHAR(255),
+    created_at BIGINT NOT NULL,
+    published_at BIGINT
+);
+CREATE INDEX outbox_pending ON outbox (created_at, id) WHERE published_at IS NULL;
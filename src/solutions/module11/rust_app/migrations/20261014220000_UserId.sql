@@ -0,0 +1,6 @@
+ALTER TABLE users ADD COLUMN id UUID;
+UPDATE users SET id = gen_random_uuid() WHERE id IS NULL;
+ALTER TABLE users ALTER COLUMN id SET NOT NULL;
+ALTER TABLE users DROP CONSTRAINT users_pkey;
+ALTER TABLE users ADD PRIMARY KEY (id);
+ALTER TABLE users ADD CONSTRAINT users_email_address_key UNIQUE (email_address);
@@ -0,0 +1,8 @@
+CREATE TABLE login_history (
+    email_address VARCHAR(255) NOT NULL,
+    ip_address VARCHAR(255) NOT NULL,
+    user_agent TEXT NOT NULL,
+    first_seen_at BIGINT NOT NULL,
+    last_seen_at BIGINT NOT NULL,
+    PRIMARY KEY (email_address, ip_address, user_agent)
+);
@@ -0,0 +1,6 @@
+-- No foreign key, the record has to outlive the user's row if it is hard deleted later
+CREATE TABLE user_erasures (
+    user_id UUID PRIMARY KEY,
+    erased_at BIGINT NOT NULL,
+    requested_by VARCHAR(255)
+);
@@ -0,0 +1,11 @@
+CREATE TABLE audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    user_id UUID NOT NULL,
+    operation VARCHAR(64) NOT NULL,
+    actor VARCHAR(255),
+    impersonated_by VARCHAR(255),
+    before JSONB,
+    after JSONB,
+    recorded_at BIGINT NOT NULL
+);
+CREATE INDEX audit_log_user_id ON audit_log (user_id, id);
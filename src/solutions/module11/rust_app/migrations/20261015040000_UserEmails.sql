@@ -0,0 +1,14 @@
+-- Secondary addresses a user can also be found by. Anyone may claim an address until one of them
+-- verifies it, after which it belongs to that user alone.
+CREATE TABLE user_emails (
+    email_address VARCHAR(255) NOT NULL,
+    user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    verification_token_hash VARCHAR(64),
+    created_at BIGINT NOT NULL,
+    verified_at BIGINT,
+    PRIMARY KEY (email_address, user_id)
+);
+
+CREATE UNIQUE INDEX user_emails_verified ON user_emails (email_address) WHERE verified_at IS NOT NULL;
+CREATE UNIQUE INDEX user_emails_verification_token ON user_emails (verification_token_hash);
+CREATE INDEX user_emails_user_id ON user_emails (user_id);
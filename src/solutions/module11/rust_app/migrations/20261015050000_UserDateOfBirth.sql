@@ -0,0 +1,4 @@
+-- The age is worked out from the date of birth when the user is read. A stored age can't be turned
+-- into a date of birth, so it is dropped and users set their birthday again.
+ALTER TABLE users ADD COLUMN date_of_birth DATE;
+ALTER TABLE users DROP COLUMN age;
@@ -0,0 +1,3 @@
+-- Stored in lower case, so the unique constraint is case-insensitive. Soft deleted users keep
+-- theirs until they are hard deleted or erased.
+ALTER TABLE users ADD COLUMN username VARCHAR(30) UNIQUE;
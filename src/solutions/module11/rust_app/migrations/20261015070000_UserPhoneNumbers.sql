@@ -0,0 +1,2 @@
+-- Always stored in E.164 form, a + and at most 15 digits
+ALTER TABLE users ADD COLUMN phone_number VARCHAR(16);
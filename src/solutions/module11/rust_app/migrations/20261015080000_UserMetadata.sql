@@ -0,0 +1,2 @@
+-- Whatever workshop teams add to their users, a JSON object of at most 4 KiB checked by the app
+ALTER TABLE users ADD COLUMN metadata JSONB NOT NULL DEFAULT '{}';
@@ -0,0 +1,2 @@
+-- The premium tier by its lowercase name, e.g. 'gold'. Standard users have none.
+ALTER TABLE users ADD COLUMN tier VARCHAR(16);
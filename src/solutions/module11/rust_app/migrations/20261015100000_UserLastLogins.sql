@@ -0,0 +1,3 @@
+-- The last successful login, as seconds since the epoch and the address it came from. 45
+-- characters fit any IPv6 address in its text form.
+ALTER TABLE users ADD COLUMN last_login_at BIGINT, ADD COLUMN last_login_ip VARCHAR(45);
@@ -0,0 +1,9 @@
+-- One row for each user merged into another, both are kept by id. No foreign keys, like erasures
+-- the record has to outlive the rows if they are hard deleted later.
+CREATE TABLE user_merges (
+    merged_user_id UUID PRIMARY KEY,
+    kept_user_id UUID NOT NULL,
+    merged_at BIGINT NOT NULL,
+    requested_by VARCHAR(255)
+);
+CREATE INDEX user_merges_kept_user_id ON user_merges (kept_user_id);
@@ -0,0 +1,8 @@
+-- A user's name and the words of their email address as search terms, names weigh more. The
+-- simple configuration doesn't stem, names aren't words in any one language. Generated, so every
+-- write keeps it current without the application knowing about it.
+ALTER TABLE users ADD COLUMN search TSVECTOR GENERATED ALWAYS AS (
+    setweight(to_tsvector('simple', name), 'A')
+        || setweight(to_tsvector('simple', regexp_replace(email_address, '[@.+_-]', ' ', 'g')), 'B')
+) STORED;
+CREATE INDEX users_search ON users USING GIN (search);
@@ -0,0 +1,17 @@
+-- Every change to a user is announced on `users_changed` as they were before it, see
+-- `data_access::listen_for_user_changes`. Postgres delivers it when the transaction commits, to
+-- whoever is listening at the time, nothing is kept for listeners that connect later. New users
+-- have nothing to announce, so inserts don't.
+CREATE FUNCTION notify_user_changed() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify('users_changed', json_build_object(
+        'id', OLD.id,
+        'email_address', OLD.email_address,
+        'username', OLD.username
+    )::TEXT);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER users_changed AFTER UPDATE OR DELETE ON users
+    FOR EACH ROW EXECUTE FUNCTION notify_user_changed();
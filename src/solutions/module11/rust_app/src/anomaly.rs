@@ -0,0 +1,203 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::core::Config;
+use crate::metrics::{
+    Metrics, RATE_ANOMALY_DETECTED_TOTAL, USER_LOGIN_FAILED_TOTAL, USER_REGISTRATION_FAILED_TOTAL,
+};
+use crate::AppState;
+
+#[derive(Clone, Debug)]
+pub struct AnomalyDetectionSettings {
+    pub interval: Duration,
+    pub baseline_windows: usize,
+    pub threshold_multiplier: f64,
+    pub min_events: u64,
+}
+
+impl AnomalyDetectionSettings {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            interval: Duration::from_secs(config.anomaly_detection_interval_seconds()),
+            baseline_windows: config.anomaly_detection_baseline_windows(),
+            threshold_multiplier: config.anomaly_detection_threshold_multiplier(),
+            min_events: config.anomaly_detection_min_events(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RateAnomaly {
+    pub signal: &'static str,
+    pub current: u64,
+    pub baseline: f64,
+}
+
+struct Signal {
+    name: &'static str,
+    metric: &'static str,
+    last_total: Option<u64>,
+    baseline: VecDeque<u64>,
+}
+
+// Compares the number of failures seen in the latest interval against a rolling baseline of the
+// previous intervals, SRE style. Anomalous windows are kept out of the baseline so a sustained
+// attack keeps alerting instead of becoming the new normal.
+pub struct RateAnomalyDetector {
+    settings: AnomalyDetectionSettings,
+    signals: Vec<Signal>,
+}
+
+impl RateAnomalyDetector {
+    pub fn new(settings: AnomalyDetectionSettings) -> Self {
+        let signal = |name, metric| Signal {
+            name,
+            metric,
+            last_total: None,
+            baseline: VecDeque::new(),
+        };
+
+        Self {
+            settings,
+            signals: vec![
+                signal("registration_failures", USER_REGISTRATION_FAILED_TOTAL),
+                signal("login_failures", USER_LOGIN_FAILED_TOTAL),
+            ],
+        }
+    }
+
+    pub fn observe(&mut self, metrics: &Metrics) -> Vec<RateAnomaly> {
+        let mut anomalies = Vec::new();
+
+        for signal in self.signals.iter_mut() {
+            let total = metrics.counter(signal.metric);
+            let Some(last_total) = signal.last_total.replace(total) else {
+                continue;
+            };
+            let current = total.saturating_sub(last_total);
+
+            if signal.baseline.len() >= self.settings.baseline_windows {
+                let baseline =
+                    signal.baseline.iter().sum::<u64>() as f64 / signal.baseline.len() as f64;
+
+                if current >= self.settings.min_events
+                    && current as f64 > baseline * self.settings.threshold_multiplier
+                {
+                    anomalies.push(RateAnomaly {
+                        signal: signal.name,
+                        current,
+                        baseline,
+                    });
+                    continue;
+                }
+            }
+
+            signal.baseline.push_back(current);
+            if signal.baseline.len() > self.settings.baseline_windows {
+                signal.baseline.pop_front();
+            }
+        }
+
+        anomalies
+    }
+}
+
+pub async fn run_anomaly_detection(state: Arc<AppState>, settings: AnomalyDetectionSettings) {
+    log::info!("Starting rate anomaly detection every {:?}", settings.interval);
+
+    let mut interval = tokio::time::interval(settings.interval);
+    let mut detector = RateAnomalyDetector::new(settings);
+
+    loop {
+        interval.tick().await;
+
+        for anomaly in detector.observe(&state.metrics) {
+            log::warn!(
+                "rate-anomaly-detected: {} {} in the last interval against a baseline of {:.2}",
+                anomaly.signal,
+                anomaly.current,
+                anomaly.baseline
+            );
+            tracing::warn!(
+                name: "rate-anomaly-detected",
+                signal = anomaly.signal,
+                current = anomaly.current,
+                baseline = anomaly.baseline
+            );
+            state
+                .metrics
+                .increment_with_labels(RATE_ANOMALY_DETECTED_TOTAL, &[("signal", anomaly.signal)]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> AnomalyDetectionSettings {
+        AnomalyDetectionSettings {
+            interval: Duration::from_secs(60),
+            baseline_windows: 3,
+            threshold_multiplier: 3.0,
+            min_events: 5,
+        }
+    }
+
+    fn record_failures(metrics: &Metrics, count: u64) {
+        for _ in 0..count {
+            metrics.increment(USER_LOGIN_FAILED_TOTAL);
+        }
+    }
+
+    #[test]
+    fn when_failures_spike_above_the_baseline_should_detect_an_anomaly() {
+        let metrics = Metrics::default();
+        let mut detector = RateAnomalyDetector::new(settings());
+
+        detector.observe(&metrics);
+        for _ in 0..3 {
+            record_failures(&metrics, 2);
+            assert!(detector.observe(&metrics).is_empty());
+        }
+
+        record_failures(&metrics, 20);
+        let anomalies = detector.observe(&metrics);
+
+        assert_eq!(
+            anomalies,
+            vec![RateAnomaly {
+                signal: "login_failures",
+                current: 20,
+                baseline: 2.0
+            }]
+        );
+    }
+
+    #[test]
+    fn when_the_spike_is_below_the_minimum_event_count_should_not_detect_an_anomaly() {
+        let metrics = Metrics::default();
+        let mut detector = RateAnomalyDetector::new(settings());
+
+        detector.observe(&metrics);
+        for _ in 0..3 {
+            assert!(detector.observe(&metrics).is_empty());
+        }
+
+        record_failures(&metrics, 4);
+
+        assert!(detector.observe(&metrics).is_empty());
+    }
+
+    #[test]
+    fn when_the_baseline_is_still_being_collected_should_not_detect_an_anomaly() {
+        let metrics = Metrics::default();
+        let mut detector = RateAnomalyDetector::new(settings());
+
+        detector.observe(&metrics);
+        record_failures(&metrics, 50);
+
+        assert!(detector.observe(&metrics).is_empty());
+    }
+}
@@ -6,7 +6,10 @@ async fn main() -> Result<(), ApplicationError> {
     info!("Starting the application");
 
     rust_users_lib::init_logger();
-    let _otel_guard = init_tracing_subscriber();
+    let runtime_guard = init_tracing_subscriber()?;
 
-    rust_users_lib::start_api().await
+    let result = rust_users_lib::start_api().await;
+    runtime_guard.shut_down(&result).await;
+
+    result
 }
@@ -0,0 +1,40 @@
+use std::sync::Arc;
+
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+
+use super::{session_claims, SessionClaims};
+use crate::core::{ApplicationError, User};
+use crate::AppState;
+
+// The user the request's session belongs to, loaded fresh from `data_access` so handlers see the
+// current state of the account rather than what it was when the session was issued. Rejects with
+// a 401 when there is no valid session or the user no longer exists.
+pub struct CurrentUser {
+    pub user: User,
+    pub claims: SessionClaims,
+}
+
+impl FromRequestParts<Arc<AppState>> for CurrentUser {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let claims = session_claims(parts, state).await?;
+
+        match state.data_access.with_email_address(&claims.sub).await {
+            Ok(user) => Ok(CurrentUser { user, claims }),
+            Err(ApplicationError::UserDoesNotExist) => {
+                log::warn!("Session presented for a user that no longer exists");
+                Err(StatusCode::UNAUTHORIZED)
+            }
+            Err(e) => {
+                log::error!("{:?}", e);
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use serde::Serialize;
+
+use super::scopes::USERS_ADMIN;
+use super::{scopes_for, SessionClaims, SessionManager};
+use crate::core::{ApplicationError, User};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImpersonationResponse {
+    pub token: String,
+    pub email_address: String,
+    pub impersonated_by: String,
+    pub expires_at: u64,
+}
+
+impl SessionManager {
+    // A short-lived session acting as `user`. It carries the user's own scopes without the admin
+    // scope, even if the user is an admin, so an impersonated session can't be used to
+    // impersonate someone else.
+    pub fn issue_impersonation(
+        &self,
+        user: &User,
+        admin: &SessionClaims,
+    ) -> Result<(String, SessionClaims), ApplicationError> {
+        let scopes = scopes_for(user)
+            .into_iter()
+            .filter(|scope| scope != USERS_ADMIN)
+            .collect();
+        let (token, claims) = self.sign(
+            user.email_address(),
+            scopes,
+            self.impersonation_ttl_seconds,
+            Some(admin.sub.clone()),
+        )?;
+
+        log::info!(
+            target: "audit",
+            "{} started impersonating {} until {} (session {})",
+            admin.sub,
+            claims.sub,
+            claims.exp,
+            claims.jti
+        );
+
+        Ok((token, claims))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{AuthMode, Role};
+
+    #[test]
+    fn an_impersonated_session_should_be_short_lived_and_not_an_admin() {
+        let sessions = SessionManager::new(AuthMode::Cookie, "test-signing-key", 3600, false)
+            .with_impersonation_ttl(60);
+        let (_, admin) = sessions
+            .issue(&User::from("admin@test.com", "Admin", "hashed").with_role(Role::Admin))
+            .unwrap();
+        let target = User::from("admin-too@test.com", "Target", "hashed").with_role(Role::Admin);
+
+        let (token, _) = sessions.issue_impersonation(&target, &admin).unwrap();
+        let claims = sessions.verify(&token).unwrap();
+
+        assert_eq!(claims.sub, "admin-too@test.com");
+        assert_eq!(claims.impersonated_by.as_deref(), Some("admin@test.com"));
+        assert_eq!(claims.exp, claims.iat + 60);
+        assert!(!claims.has_scope(USERS_ADMIN));
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::Arc;
+
+use jsonwebtoken::{decode, encode, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+use super::SessionManager;
+use crate::core::{ApplicationError, Config, StoreKind};
+use crate::data_access::{InMemoryMagicLinkTokens, MagicLinkTokens, PostgresMagicLinkTokens};
+
+// Magic links are signed with the session key, the audience stops a link being presented as a
+// session cookie and a session being replayed as a link.
+const MAGIC_LINK_AUDIENCE: &str = "magic-link";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MagicLinkClaims {
+    pub sub: String,
+    pub iat: u64,
+    pub exp: u64,
+    pub jti: String,
+    pub aud: String,
+}
+
+impl SessionManager {
+    pub fn issue_magic_link(
+        &self,
+        email_address: &str,
+        ttl_seconds: u64,
+    ) -> Result<(String, MagicLinkClaims), ApplicationError> {
+        let now = self.clock().now();
+
+        let claims = MagicLinkClaims {
+            sub: email_address.to_string(),
+            iat: now,
+            exp: now + ttl_seconds,
+            jti: uuid::Uuid::new_v4().to_string(),
+            aud: MAGIC_LINK_AUDIENCE.to_string(),
+        };
+
+        let token = encode(&Header::default(), &claims, &self.encoding_key)
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+
+        Ok((token, claims))
+    }
+
+    pub fn verify_magic_link(&self, token: &str) -> Result<MagicLinkClaims, ApplicationError> {
+        let mut validation = Validation::default();
+        validation.set_audience(&[MAGIC_LINK_AUDIENCE]);
+        validation.set_required_spec_claims(&["exp", "aud"]);
+        validation.validate_exp = false;
+
+        let claims = decode::<MagicLinkClaims>(token, &self.decoding_key, &validation)
+            .map(|data| data.claims)
+            .map_err(|_| ApplicationError::InvalidSession)?;
+
+        self.unexpired(claims.exp, &validation)?;
+
+        Ok(claims)
+    }
+}
+
+#[async_trait::async_trait]
+pub trait MagicLinkSender: Send + Sync {
+    async fn send(&self, email_address: &str, link: &str) -> Result<(), ApplicationError>;
+}
+
+// For local development, the link is written to the log instead of being emailed
+pub struct LogMagicLinkSender;
+
+#[async_trait::async_trait]
+impl MagicLinkSender for LogMagicLinkSender {
+    async fn send(&self, email_address: &str, link: &str) -> Result<(), ApplicationError> {
+        log::info!("Magic link for {}: {}", email_address, link);
+
+        Ok(())
+    }
+}
+
+pub struct MagicLinks {
+    pub tokens: Arc<dyn MagicLinkTokens>,
+    pub sender: Arc<dyn MagicLinkSender>,
+    base_url: String,
+    ttl_seconds: u64,
+}
+
+impl MagicLinks {
+    pub fn new(
+        tokens: Arc<dyn MagicLinkTokens>,
+        sender: Arc<dyn MagicLinkSender>,
+        base_url: &str,
+        ttl_seconds: u64,
+    ) -> Self {
+        Self {
+            tokens,
+            sender,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            ttl_seconds,
+        }
+    }
+
+    pub async fn from_config(config: &Config) -> Result<Self, ApplicationError> {
+        if config.magic_link_enabled() && config.auth_signing_key().unwrap_or_default().is_empty() {
+            return Err(ApplicationError::ApplicationError(
+                "auth.signing_key must be set when magic links are enabled".to_string(),
+            ));
+        }
+
+        let tokens: Arc<dyn MagicLinkTokens> = match config.magic_link_store() {
+            StoreKind::Memory => Arc::new(InMemoryMagicLinkTokens::default()),
+            StoreKind::Postgres => {
+                let db = crate::data_access::connect(
+                    &config.connection_string(),
+                    &crate::data_access::PoolSettings::from_config(config),
+                )
+                .await?;
+
+                Arc::new(PostgresMagicLinkTokens::new(db))
+            }
+        };
+
+        Ok(Self::new(
+            tokens,
+            Arc::new(LogMagicLinkSender),
+            &config.magic_link_base_url(),
+            config.magic_link_ttl_seconds(),
+        ))
+    }
+
+    pub fn ttl_seconds(&self) -> u64 {
+        self.ttl_seconds
+    }
+
+    pub fn link(&self, token: &str) -> String {
+        format!("{}/login/magic/{}", self.base_url, token)
+    }
+
+    // Sent to a secondary address to prove the user receives mail there, it doesn't sign anyone in
+    pub fn verification_link(&self, token: &str) -> String {
+        format!("{}/emails/verify/{}", self.base_url, token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{AuthMode, User};
+
+    #[test]
+    fn a_magic_link_should_not_be_accepted_as_a_session_or_the_reverse() {
+        let sessions = SessionManager::new(AuthMode::Cookie, "test-signing-key", 60, false);
+
+        let (magic_link, claims) = sessions.issue_magic_link("test@test.com", 60).unwrap();
+        let (session, _) = sessions
+            .issue(&User::from("test@test.com", "Test User", "hashed"))
+            .unwrap();
+
+        assert_eq!(sessions.verify_magic_link(&magic_link).unwrap().jti, claims.jti);
+        assert!(sessions.verify(&magic_link).is_err());
+        assert!(sessions.verify_magic_link(&session).is_err());
+    }
+}
@@ -0,0 +1,408 @@
+use std::sync::Arc;
+
+use axum::extract::{FromRequestParts, Request, State};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+use crate::core::{ApplicationError, AuthMode, Config, StoreKind, User};
+use crate::data_access::{
+    with_actor, ActiveSession, ActiveSessions, Actor, InMemoryActiveSessions,
+    PostgresActiveSessions,
+};
+use crate::AppState;
+
+mod current_user;
+mod impersonation;
+mod magic_link;
+mod revocation;
+mod scopes;
+
+pub use current_user::CurrentUser;
+pub use impersonation::ImpersonationResponse;
+pub use magic_link::{MagicLinkSender, MagicLinks};
+#[cfg(test)]
+pub use magic_link::LogMagicLinkSender;
+pub use revocation::{InMemoryRevocations, PostgresRevocations, RevocationStore};
+pub use scopes::{promote_admins, scopes_for, RequireScope, UsersAdmin, UsersRead, UsersWrite, USERS_ADMIN};
+#[cfg(test)]
+pub use scopes::UsersPremium;
+
+pub const SESSION_COOKIE_NAME: &str = "users_session";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionClaims {
+    pub sub: String,
+    pub iat: u64,
+    pub exp: u64,
+    pub jti: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    // The admin acting as `sub`, only set on sessions issued by `POST /admin/impersonate`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impersonated_by: Option<String>,
+}
+
+impl SessionClaims {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|granted| granted == scope)
+    }
+}
+
+pub struct SessionManager {
+    mode: AuthMode,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    session_ttl_seconds: u64,
+    secure_cookie: bool,
+    impersonation_ttl_seconds: u64,
+    clock: Arc<Clock>,
+}
+
+impl SessionManager {
+    pub fn new(
+        mode: AuthMode,
+        signing_key: &str,
+        session_ttl_seconds: u64,
+        secure_cookie: bool,
+    ) -> Self {
+        Self {
+            mode,
+            encoding_key: EncodingKey::from_secret(signing_key.as_bytes()),
+            decoding_key: DecodingKey::from_secret(signing_key.as_bytes()),
+            session_ttl_seconds,
+            secure_cookie,
+            impersonation_ttl_seconds: 900,
+            clock: Arc::new(Clock::default()),
+        }
+    }
+
+    // Sessions and magic links are issued and expire by this clock
+    pub fn with_clock(mut self, clock: Arc<Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn with_impersonation_ttl(mut self, impersonation_ttl_seconds: u64) -> Self {
+        self.impersonation_ttl_seconds = impersonation_ttl_seconds;
+        self
+    }
+
+    pub fn from_config(config: &Config) -> Result<Self, ApplicationError> {
+        let mode = config.auth_mode();
+
+        let signing_key = match (mode, config.auth_signing_key()) {
+            (AuthMode::None, key) => key.unwrap_or_default(),
+            (AuthMode::Cookie, Some(key)) if !key.is_empty() => key,
+            (AuthMode::Cookie, _) => {
+                return Err(ApplicationError::ApplicationError(
+                    "auth.signing_key must be set when using cookie authentication".to_string(),
+                ))
+            }
+        };
+
+        Ok(Self::new(
+            mode,
+            &signing_key,
+            config.session_ttl_seconds(),
+            config.secure_cookie(),
+        )
+        .with_impersonation_ttl(config.impersonation_ttl_seconds()))
+    }
+
+    pub fn mode(&self) -> AuthMode {
+        self.mode
+    }
+
+    pub fn clock(&self) -> &Arc<Clock> {
+        &self.clock
+    }
+
+    pub fn issue(&self, user: &User) -> Result<(String, SessionClaims), ApplicationError> {
+        self.sign(user.email_address(), scopes_for(user), self.session_ttl_seconds, None)
+    }
+
+    fn sign(
+        &self,
+        sub: String,
+        scopes: Vec<String>,
+        ttl_seconds: u64,
+        impersonated_by: Option<String>,
+    ) -> Result<(String, SessionClaims), ApplicationError> {
+        let now = self.clock.now();
+
+        let claims = SessionClaims {
+            sub,
+            iat: now,
+            exp: now + ttl_seconds,
+            jti: uuid::Uuid::new_v4().to_string(),
+            scopes,
+            impersonated_by,
+        };
+
+        let token = encode(&Header::default(), &claims, &self.encoding_key)
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+
+        Ok((token, claims))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<SessionClaims, ApplicationError> {
+        let mut validation = Validation::default();
+        validation.validate_exp = false;
+
+        let claims = decode::<SessionClaims>(token, &self.decoding_key, &validation)
+            .map(|data| data.claims)
+            .map_err(|_| ApplicationError::InvalidSession)?;
+
+        self.unexpired(claims.exp, &validation)?;
+
+        Ok(claims)
+    }
+
+    // Expiry is checked against `clock` rather than by `jsonwebtoken`, which only knows the system
+    // time, with the same leeway it would have allowed
+    fn unexpired(&self, exp: u64, validation: &Validation) -> Result<(), ApplicationError> {
+        match exp + validation.leeway < self.clock.now() {
+            true => Err(ApplicationError::InvalidSession),
+            false => Ok(()),
+        }
+    }
+
+    pub fn session_cookie(&self, token: String) -> Cookie<'static> {
+        Cookie::build((SESSION_COOKIE_NAME, token))
+            .path("/")
+            .http_only(true)
+            .secure(self.secure_cookie)
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::seconds(self.session_ttl_seconds as i64))
+            .build()
+    }
+
+    pub fn removal_cookie(&self) -> Cookie<'static> {
+        Cookie::build(SESSION_COOKIE_NAME).path("/").build()
+    }
+}
+
+pub async fn revocation_store_from_config(
+    config: &Config,
+) -> Result<Arc<dyn RevocationStore>, ApplicationError> {
+    match config.revocation_store() {
+        StoreKind::Memory => Ok(Arc::new(InMemoryRevocations::default())),
+        StoreKind::Postgres => {
+            let db = crate::data_access::connect(
+                &config.connection_string(),
+                &crate::data_access::PoolSettings::from_config(config),
+            )
+            .await?;
+
+            Ok(Arc::new(PostgresRevocations::new(db)))
+        }
+    }
+}
+
+pub async fn active_sessions_from_config(
+    config: &Config,
+) -> Result<Arc<dyn ActiveSessions>, ApplicationError> {
+    match config.session_store() {
+        StoreKind::Memory => Ok(Arc::new(InMemoryActiveSessions::default())),
+        StoreKind::Postgres => {
+            let db = crate::data_access::connect(
+                &config.connection_string(),
+                &crate::data_access::PoolSettings::from_config(config),
+            )
+            .await?;
+
+            Ok(Arc::new(PostgresActiveSessions::new(db)))
+        }
+    }
+}
+
+// Only meaningful behind a proxy that sets the header, so treat it as a hint
+pub fn forwarded_ip(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(str::trim)
+}
+
+// Remembers where a newly issued session was started from, for `GET /users/{email_address}/sessions`.
+// The session is valid whether or not this succeeds, so a failure is only logged.
+pub async fn record_session(state: &AppState, claims: &SessionClaims, headers: &HeaderMap) {
+    let session = ActiveSession {
+        token_id: claims.jti.clone(),
+        email_address: claims.sub.clone(),
+        user_agent: headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        last_seen_ip: forwarded_ip(headers).map(str::to_string),
+        created_at: claims.iat,
+        last_seen_at: claims.iat,
+        expires_at: claims.exp,
+        impersonated_by: claims.impersonated_by.clone(),
+    };
+
+    if let Err(e) = state.active_sessions.record(session).await {
+        log::error!("{:?}", e);
+    }
+}
+
+// Extracts and verifies the session cookie set by `login`, rejecting the request with a 401
+// if it is missing, tampered with, expired or has been revoked by `logout`.
+pub struct SessionCookie(pub SessionClaims);
+
+impl FromRequestParts<Arc<AppState>> for SessionCookie {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let jar = CookieJar::from_headers(&parts.headers);
+
+        let cookie = jar
+            .get(SESSION_COOKIE_NAME)
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        let claims = state.sessions.verify(cookie.value()).map_err(|e| {
+            log::warn!("{:?}", e);
+            StatusCode::UNAUTHORIZED
+        })?;
+
+        match state.revocations.is_revoked(&claims.jti).await {
+            Ok(false) => Ok(SessionCookie(claims)),
+            Ok(true) => Err(StatusCode::UNAUTHORIZED),
+            Err(e) => {
+                log::error!("{:?}", e);
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+// The claims `require_session` has already verified on routes it is layered on, otherwise the
+// session cookie is verified here
+async fn session_claims(
+    parts: &mut Parts,
+    state: &Arc<AppState>,
+) -> Result<SessionClaims, StatusCode> {
+    match parts.extensions.get::<SessionClaims>() {
+        Some(claims) => Ok(claims.clone()),
+        None => Ok(SessionCookie::from_request_parts(parts, state).await?.0),
+    }
+}
+
+pub async fn require_session(
+    State(state): State<Arc<AppState>>,
+    SessionCookie(claims): SessionCookie,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Err(e) = state
+        .active_sessions
+        .touch(&claims.jti, state.sessions.clock().now(), forwarded_ip(request.headers()))
+        .await
+    {
+        log::error!("{:?}", e);
+    }
+
+    if let Some(admin) = &claims.impersonated_by {
+        log::info!(
+            target: "audit",
+            "{} acting as {}: {} {}",
+            admin,
+            claims.sub,
+            request.method(),
+            request.uri().path()
+        );
+    }
+
+    // Writes the handler makes are audited as the session's user
+    let actor = Actor {
+        email_address: Some(claims.sub.clone()),
+        impersonated_by: claims.impersonated_by.clone(),
+    };
+    request.extensions_mut().insert(claims);
+
+    with_actor(actor, next.run(request)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Role;
+
+    fn test_user() -> User {
+        User::from("test@test.com", "Test User", "hashed")
+    }
+
+    #[test]
+    fn when_a_session_is_issued_should_verify_with_the_same_key() {
+        let sessions = SessionManager::new(AuthMode::Cookie, "test-signing-key", 60, false);
+
+        let (token, _) = sessions.issue(&test_user()).unwrap();
+        let claims = sessions.verify(&token).unwrap();
+
+        assert_eq!(claims.sub, "test@test.com");
+        assert_eq!(claims.exp, claims.iat + 60);
+        assert!(claims.has_scope(scopes::USERS_WRITE));
+    }
+
+    #[test]
+    fn when_a_session_is_signed_with_a_different_key_should_fail_verification() {
+        let sessions = SessionManager::new(AuthMode::Cookie, "test-signing-key", 60, false);
+        let other_sessions = SessionManager::new(AuthMode::Cookie, "another-key", 60, false);
+
+        let (token, _) = other_sessions.issue(&test_user()).unwrap();
+
+        assert!(sessions.verify(&token).is_err());
+    }
+
+    #[test]
+    fn a_session_should_expire_once_the_clock_passes_its_expiry() {
+        let clock = Arc::new(Clock::adjustable());
+        let sessions = SessionManager::new(AuthMode::Cookie, "test-signing-key", 60, false)
+            .with_clock(clock.clone());
+        let (token, _) = sessions.issue(&test_user()).unwrap();
+
+        clock.advance(60);
+        assert!(sessions.verify(&token).is_ok());
+
+        clock.advance(Validation::default().leeway + 1);
+        assert!(sessions.verify(&token).is_err());
+    }
+
+    #[test]
+    fn only_admins_should_be_granted_the_admin_scope() {
+        let sessions = SessionManager::new(AuthMode::Cookie, "test-signing-key", 60, false);
+
+        let admin = sessions
+            .verify(
+                &sessions
+                    .issue(&User::from("admin@test.com", "Admin", "hashed").with_role(Role::Admin))
+                    .unwrap()
+                    .0,
+            )
+            .unwrap();
+        let user = sessions.verify(&sessions.issue(&test_user()).unwrap().0).unwrap();
+
+        assert!(admin.has_scope(scopes::USERS_ADMIN));
+        assert!(!user.has_scope(scopes::USERS_ADMIN));
+    }
+
+    #[test]
+    fn session_cookie_should_be_http_only() {
+        let sessions = SessionManager::new(AuthMode::Cookie, "test-signing-key", 60, false);
+
+        let cookie = sessions.session_cookie("token".to_string());
+
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.name(), SESSION_COOKIE_NAME);
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sqlx::PgPool;
+
+use crate::core::ApplicationError;
+
+// Sessions are stateless tokens, so logging out records the token id until the token would have
+// expired anyway. After that the signature check rejects it and the entry can be dropped.
+#[async_trait::async_trait]
+pub trait RevocationStore: Send + Sync {
+    async fn revoke(&self, token_id: &str, expires_at: u64) -> Result<(), ApplicationError>;
+    async fn is_revoked(&self, token_id: &str) -> Result<bool, ApplicationError>;
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Default)]
+pub struct InMemoryRevocations {
+    revoked: Mutex<HashMap<String, u64>>,
+}
+
+#[async_trait::async_trait]
+impl RevocationStore for InMemoryRevocations {
+    async fn revoke(&self, token_id: &str, expires_at: u64) -> Result<(), ApplicationError> {
+        let mut revoked = self.revoked.lock().unwrap();
+
+        let now = now();
+        revoked.retain(|_, expiry| *expiry > now);
+        revoked.insert(token_id.to_string(), expires_at);
+
+        Ok(())
+    }
+
+    async fn is_revoked(&self, token_id: &str) -> Result<bool, ApplicationError> {
+        Ok(self.revoked.lock().unwrap().contains_key(token_id))
+    }
+}
+
+pub struct PostgresRevocations {
+    db: PgPool,
+}
+
+impl PostgresRevocations {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait::async_trait]
+impl RevocationStore for PostgresRevocations {
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "revoke"))]
+    async fn revoke(&self, token_id: &str, expires_at: u64) -> Result<(), ApplicationError> {
+        log::info!("Revoking session");
+
+        sqlx::query!(
+            r#"
+            INSERT INTO revoked_sessions ( token_id, expires_at )
+            VALUES ( $1, $2 )
+            ON CONFLICT (token_id) DO NOTHING
+            "#,
+            token_id,
+            expires_at as i64,
+        )
+            .execute(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM revoked_sessions
+            WHERE expires_at < $1
+            "#,
+            now() as i64,
+        )
+            .execute(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "is_revoked"))]
+    async fn is_revoked(&self, token_id: &str) -> Result<bool, ApplicationError> {
+        let record = sqlx::query!(
+            r#"
+            SELECT token_id
+            FROM revoked_sessions
+            WHERE token_id = $1
+            "#,
+            token_id,
+        )
+            .fetch_optional(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        Ok(record.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn when_a_session_is_revoked_should_be_reported_as_revoked() {
+        let revocations = InMemoryRevocations::default();
+
+        revocations.revoke("token-id", now() + 60).await.unwrap();
+
+        assert!(revocations.is_revoked("token-id").await.unwrap());
+        assert!(!revocations.is_revoked("another-token-id").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn when_a_revocation_has_expired_should_be_pruned() {
+        let revocations = InMemoryRevocations::default();
+
+        revocations.revoke("expired-token-id", now() - 60).await.unwrap();
+        revocations.revoke("token-id", now() + 60).await.unwrap();
+
+        assert!(!revocations.is_revoked("expired-token-id").await.unwrap());
+    }
+}
@@ -0,0 +1,155 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+
+use super::session_claims;
+use crate::core::{ApplicationError, AuthMode, DataAccess, Role, User};
+use crate::AppState;
+
+pub const USERS_READ: &str = "users:read";
+pub const USERS_WRITE: &str = "users:write";
+pub const USERS_PREMIUM: &str = "users:premium";
+// Granted to users with the admin role
+pub const USERS_ADMIN: &str = "users:admin";
+
+// The scopes written into a session when it is issued, derived from the user's tier and role
+pub fn scopes_for(user: &User) -> Vec<String> {
+    let mut scopes = vec![USERS_READ.to_string(), USERS_WRITE.to_string()];
+
+    if let User::Premium { .. } = user {
+        scopes.push(USERS_PREMIUM.to_string());
+    }
+    if user.role() == Role::Admin {
+        scopes.push(USERS_ADMIN.to_string());
+    }
+
+    scopes
+}
+
+// Gives the users listed in `auth.admins` the admin role, so a new deployment has someone who can
+// grant it. The role is what authorizes them from then on, taking an address off the list
+// doesn't demote it.
+pub async fn promote_admins(
+    data_access: &dyn DataAccess,
+    admins: &[String],
+) -> Result<(), ApplicationError> {
+    for email_address in admins {
+        let user = match data_access.with_email_address(email_address).await {
+            Ok(user) => user,
+            Err(ApplicationError::UserDoesNotExist) => {
+                log::warn!("{} is listed as an admin but has not registered", email_address);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        if user.role() != Role::Admin {
+            log::info!(target: "audit", "{} was given the admin role from the configuration", email_address);
+            data_access.update(user.with_role(Role::Admin), None).await?;
+        }
+    }
+
+    Ok(())
+}
+
+// A scope a handler can require, e.g. `RequireScope<UsersWrite>` for "users:write"
+pub trait Scope {
+    const NAME: &'static str;
+}
+
+pub struct UsersRead;
+pub struct UsersWrite;
+// Not yet required by any handler, but issued so premium endpoints can opt in
+#[allow(dead_code)]
+pub struct UsersPremium;
+pub struct UsersAdmin;
+
+impl Scope for UsersRead {
+    const NAME: &'static str = USERS_READ;
+}
+
+impl Scope for UsersWrite {
+    const NAME: &'static str = USERS_WRITE;
+}
+
+impl Scope for UsersPremium {
+    const NAME: &'static str = USERS_PREMIUM;
+}
+
+impl Scope for UsersAdmin {
+    const NAME: &'static str = USERS_ADMIN;
+}
+
+// Rejects the request with a 401 if there is no valid session and a 403 if the session was not
+// granted the scope. Sessions issued before scopes existed carry none and need to log in again.
+// When authentication is disabled there are no sessions, so every request is let through.
+pub struct RequireScope<TScope: Scope>(PhantomData<TScope>);
+
+impl<TScope: Scope> FromRequestParts<Arc<AppState>> for RequireScope<TScope> {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        if state.sessions.mode() == AuthMode::None {
+            return Ok(RequireScope(PhantomData));
+        }
+
+        let claims = session_claims(parts, state).await?;
+
+        if claims.has_scope(TScope::NAME) {
+            Ok(RequireScope(PhantomData))
+        } else {
+            log::warn!("Session for {} is missing the {} scope", claims.sub, TScope::NAME);
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Tier;
+
+    #[test]
+    fn premium_users_should_be_granted_the_premium_scope() {
+        let user = User::from("test@test.com", "Test User", "hashed");
+
+        let premium_user = user.clone().with_tier(Some(Tier::Gold));
+
+        assert_eq!(scopes_for(&user), vec![USERS_READ, USERS_WRITE]);
+        assert!(scopes_for(&premium_user).contains(&USERS_PREMIUM.to_string()));
+    }
+
+    #[test]
+    fn only_users_with_the_admin_role_should_be_granted_the_admin_scope() {
+        let user = User::from("test@test.com", "Test User", "hashed");
+        let admin = user.clone().with_role(Role::Admin);
+
+        assert!(!scopes_for(&user).contains(&USERS_ADMIN.to_string()));
+        assert!(scopes_for(&admin).contains(&USERS_ADMIN.to_string()));
+    }
+
+    #[tokio::test]
+    async fn configured_admins_should_be_promoted_once_they_have_registered() {
+        let data_access = crate::data_access::InMemoryUsers::default();
+        data_access
+            .store(User::from("admin@test.com", "Admin", "hashed"))
+            .await
+            .unwrap();
+
+        promote_admins(
+            &data_access,
+            &["admin@test.com".to_string(), "unregistered@test.com".to_string()],
+        )
+        .await
+        .unwrap();
+
+        let admin = data_access.with_email_address("admin@test.com").await.unwrap();
+        assert_eq!(admin.role(), Role::Admin);
+    }
+}
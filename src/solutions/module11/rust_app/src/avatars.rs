@@ -0,0 +1,66 @@
+use std::sync::Arc;
+
+use uuid::Uuid;
+
+use crate::blobs::{blob_store_from_config, BlobStore};
+use crate::core::{ApplicationError, Config};
+
+// How long browsers may reuse an avatar before checking its ETag again
+pub const AVATAR_MAX_AGE_SECONDS: u64 = 300;
+
+pub struct Avatars {
+    pub store: Arc<dyn BlobStore>,
+    max_bytes: usize,
+}
+
+impl Avatars {
+    pub fn new(store: Arc<dyn BlobStore>, max_bytes: usize) -> Self {
+        Self { store, max_bytes }
+    }
+
+    pub async fn from_config(config: &Config) -> Result<Self, ApplicationError> {
+        Ok(Self::new(
+            blob_store_from_config(config).await?,
+            config.avatar_max_bytes(),
+        ))
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    // By id rather than email address, so an avatar doesn't have to move if the address changes
+    pub fn key(id: Uuid) -> String {
+        format!("avatars/{}", id)
+    }
+}
+
+// The image type from the file's leading bytes. The content type the client sent isn't trusted,
+// it would let any file be served back as an image.
+pub fn image_content_type(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => Some("image/png"),
+        [0xff, 0xd8, 0xff, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_known_image_formats_should_be_recognised() {
+        assert_eq!(
+            image_content_type(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some("image/png")
+        );
+        assert_eq!(image_content_type(b"\xff\xd8\xff\xe0\0\x10JFIF"), Some("image/jpeg"));
+        assert_eq!(image_content_type(b"GIF89a\x01\0"), Some("image/gif"));
+        assert_eq!(image_content_type(b"RIFF\x24\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(image_content_type(b"<svg xmlns=\"http://www.w3.org/2000/svg\">"), None);
+        assert_eq!(image_content_type(b""), None);
+    }
+}
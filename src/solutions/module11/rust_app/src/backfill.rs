@@ -0,0 +1,229 @@
+use std::time::Duration;
+
+use tokio::time::MissedTickBehavior;
+
+use crate::core::{ApplicationError, DataAccess};
+use crate::events::{EventPublisher, UserRegisteredEvent, USER_REGISTERED_TOPIC};
+
+#[derive(Clone, Debug)]
+pub struct BackfillSettings {
+    pub batch_size: i64,
+    // 0 publishes as fast as the broker accepts
+    pub max_events_per_second: u32,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BackfillReport {
+    pub published: u64,
+    pub batches: u64,
+}
+
+impl BackfillSettings {
+    // Batches are spaced out so the average rate stays under `max_events_per_second`
+    fn batch_interval(&self) -> Option<Duration> {
+        if self.max_events_per_second == 0 {
+            return None;
+        }
+
+        Some(Duration::from_secs_f64(
+            self.batch_size as f64 / self.max_events_per_second as f64,
+        ))
+    }
+}
+
+pub async fn backfill_user_registered_events<TDataAccess: DataAccess, TPublisher: EventPublisher>(
+    data_access: &TDataAccess,
+    publisher: &TPublisher,
+    settings: &BackfillSettings,
+) -> Result<BackfillReport, ApplicationError> {
+    let mut report = BackfillReport::default();
+    // The last email address published, each batch carries on after it
+    let mut cursor: Option<String> = None;
+
+    let mut rate_limit = settings.batch_interval().map(|period| {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
+
+    loop {
+        if let Some(interval) = rate_limit.as_mut() {
+            interval.tick().await;
+        }
+
+        let users = data_access
+            .list_after(cursor.as_deref(), settings.batch_size)
+            .await?;
+        if users.is_empty() {
+            break;
+        }
+        cursor = users.last().map(|user| user.email_address());
+
+        for user in &users {
+            let event = UserRegisteredEvent::synthetic(user, crate::outbox::now());
+            let payload = serde_json::to_string(&event)
+                .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+
+            publisher
+                .publish(USER_REGISTERED_TOPIC, &event.partition_key, &payload)
+                .await?;
+            report.published += 1;
+        }
+
+        report.batches += 1;
+        log::info!("Backfilled {} user-registered events", report.published);
+
+        if (users.len() as i64) < settings.batch_size {
+            break;
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::User;
+    use std::sync::Mutex;
+
+    struct InMemoryUsers {
+        // In email address order, as a store returns them
+        users: Vec<User>,
+        rows_read: Mutex<usize>,
+    }
+
+    #[async_trait::async_trait]
+    impl DataAccess for InMemoryUsers {
+        async fn with_email_address(&self, _email_address: &str) -> Result<User, ApplicationError> {
+            Err(ApplicationError::UserDoesNotExist)
+        }
+
+        async fn list(&self, offset: i64, limit: i64) -> Result<Vec<User>, ApplicationError> {
+            Ok(self
+                .users
+                .iter()
+                .skip(offset as usize)
+                .take(limit as usize)
+                .cloned()
+                .collect())
+        }
+
+        // Counts the rows read, so a test can tell the backfill doesn't re-read earlier batches
+        async fn list_after(
+            &self,
+            after: Option<&str>,
+            limit: i64,
+        ) -> Result<Vec<User>, ApplicationError> {
+            let page: Vec<User> = self
+                .users
+                .iter()
+                .filter(|user| after.is_none_or(|after| user.email_address().as_str() > after))
+                .take(limit as usize)
+                .cloned()
+                .collect();
+            *self.rows_read.lock().unwrap() += page.len();
+
+            Ok(page)
+        }
+
+        async fn store(&self, _user: User) -> Result<(), ApplicationError> {
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingPublisher {
+        published: Mutex<Vec<(String, String, String)>>,
+    }
+
+    #[async_trait::async_trait]
+    impl EventPublisher for RecordingPublisher {
+        async fn publish(&self, topic: &str, key: &str, payload: &str) -> Result<(), ApplicationError> {
+            self.published
+                .lock()
+                .unwrap()
+                .push((topic.to_string(), key.to_string(), payload.to_string()));
+            Ok(())
+        }
+    }
+
+    fn users(count: usize) -> InMemoryUsers {
+        let mut users: Vec<User> = (0..count)
+            .map(|i| User::from(&format!("user{}@test.com", i), "James", "hashed"))
+            .collect();
+        users.sort_by_key(|user| user.email_address());
+
+        InMemoryUsers {
+            users,
+            rows_read: Mutex::new(0),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_publish_an_event_for_every_user_in_batches() {
+        let publisher = RecordingPublisher::default();
+        let settings = BackfillSettings {
+            batch_size: 4,
+            max_events_per_second: 0,
+        };
+
+        let data_access = users(10);
+
+        let report = backfill_user_registered_events(&data_access, &publisher, &settings)
+            .await
+            .unwrap();
+
+        assert_eq!(report, BackfillReport { published: 10, batches: 3 });
+        assert_eq!(*data_access.rows_read.lock().unwrap(), 10);
+
+        let published = publisher.published.lock().unwrap();
+        let (topic, key, payload) = &published[0];
+        assert_eq!(topic, USER_REGISTERED_TOPIC);
+        assert_eq!(*key, data_access.users[0].id().to_string());
+        assert!(payload.contains(&format!("\"partitionKey\":\"{}\"", key)));
+        assert!(payload.contains("\"synthetic\":true"));
+        assert!(!payload.contains("hashed"));
+    }
+
+    #[tokio::test]
+    async fn users_copied_by_a_rebalance_should_only_be_published_once() {
+        // Every user on both shards, as if a rebalance had copied them all and left the originals
+        let shards = [
+            crate::data_access::InMemoryUsers::default(),
+            crate::data_access::InMemoryUsers::default(),
+        ];
+        for user in users(10).users {
+            for shard in &shards {
+                shard.store(user.clone()).await.unwrap();
+            }
+        }
+        let sharded = crate::data_access::ShardedDataAccess::new(shards.into()).unwrap();
+        let publisher = RecordingPublisher::default();
+        let settings = BackfillSettings {
+            batch_size: 3,
+            max_events_per_second: 0,
+        };
+
+        let report = backfill_user_registered_events(&sharded, &publisher, &settings)
+            .await
+            .unwrap();
+
+        assert_eq!(report.published, 10);
+    }
+
+    #[test]
+    fn batches_should_be_spaced_to_respect_the_rate_limit() {
+        let limited = BackfillSettings {
+            batch_size: 50,
+            max_events_per_second: 100,
+        };
+        let unlimited = BackfillSettings {
+            batch_size: 50,
+            max_events_per_second: 0,
+        };
+
+        assert_eq!(limited.batch_interval(), Some(Duration::from_millis(500)));
+        assert_eq!(unlimited.batch_interval(), None);
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::body::Bytes;
+use hmac::{Hmac, Mac};
+use reqwest::{Method, StatusCode};
+use sha2::{Digest, Sha256};
+
+use crate::core::{ApplicationError, BlobStoreKind, Config};
+
+// Opaque files such as profile pictures. Keys are chosen by the API, e.g. `avatars/<user id>`,
+// and only contain characters that are safe in a path and a URL.
+#[async_trait::async_trait]
+pub trait BlobStore: Send + Sync {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<(), ApplicationError>;
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, ApplicationError>;
+}
+
+pub async fn blob_store_from_config(config: &Config) -> Result<Arc<dyn BlobStore>, ApplicationError> {
+    match config.avatar_store() {
+        BlobStoreKind::Memory => Ok(Arc::new(InMemoryBlobStore::default())),
+        BlobStoreKind::Filesystem => Ok(Arc::new(FilesystemBlobStore::new(
+            config.avatar_directory(),
+        ))),
+        BlobStoreKind::S3 => {
+            let (Some(endpoint), Some(bucket), Some(access_key_id), Some(secret_access_key)) = (
+                config.avatar_s3_endpoint(),
+                config.avatar_s3_bucket(),
+                config.avatar_s3_access_key_id(),
+                config.avatar_s3_secret_access_key(),
+            ) else {
+                return Err(ApplicationError::ApplicationError(
+                    "avatars.s3 needs an endpoint, bucket, access_key_id and secret_access_key"
+                        .to_string(),
+                ));
+            };
+
+            Ok(Arc::new(S3BlobStore::new(
+                &endpoint,
+                &bucket,
+                &config.avatar_s3_region(),
+                &access_key_id,
+                &secret_access_key,
+            )?))
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryBlobStore {
+    blobs: Mutex<HashMap<String, Bytes>>,
+}
+
+#[async_trait::async_trait]
+impl BlobStore for InMemoryBlobStore {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<(), ApplicationError> {
+        self.blobs.lock().unwrap().insert(key.to_string(), bytes);
+
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, ApplicationError> {
+        Ok(self.blobs.lock().unwrap().get(key).cloned())
+    }
+}
+
+// Each blob is a file under `root`, at the path given by its key
+pub struct FilesystemBlobStore {
+    root: PathBuf,
+}
+
+impl FilesystemBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+#[async_trait::async_trait]
+impl BlobStore for FilesystemBlobStore {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<(), ApplicationError> {
+        let path = self.root.join(key);
+        let io_error = |e: std::io::Error| ApplicationError::ApplicationError(e.to_string());
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await.map_err(io_error)?;
+        }
+        // Written next to the blob and renamed over it, so a reader never sees half a file
+        let partial = path.with_extension(format!("{}.partial", uuid::Uuid::new_v4()));
+        tokio::fs::write(&partial, &bytes).await.map_err(io_error)?;
+        tokio::fs::rename(&partial, &path).await.map_err(io_error)?;
+
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, ApplicationError> {
+        match tokio::fs::read(self.root.join(key)).await {
+            Ok(bytes) => Ok(Some(Bytes::from(bytes))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(ApplicationError::ApplicationError(e.to_string())),
+        }
+    }
+}
+
+// Any S3 compatible store, addressed path style (`<endpoint>/<bucket>/<key>`) so it works with
+// MinIO and other self-hosted stores as well as AWS. Requests are signed with Signature Version 4.
+pub struct S3BlobStore {
+    client: reqwest::Client,
+    endpoint: reqwest::Url,
+    bucket: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+impl S3BlobStore {
+    pub fn new(
+        endpoint: &str,
+        bucket: &str,
+        region: &str,
+        access_key_id: &str,
+        secret_access_key: &str,
+    ) -> Result<Self, ApplicationError> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+        let endpoint = reqwest::Url::parse(endpoint)
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+
+        Ok(Self {
+            client,
+            endpoint,
+            bucket: bucket.to_string(),
+            region: region.to_string(),
+            access_key_id: access_key_id.to_string(),
+            secret_access_key: secret_access_key.to_string(),
+        })
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        body: Bytes,
+    ) -> Result<reqwest::Response, ApplicationError> {
+        let path = format!("/{}/{}", self.bucket, key);
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let now = time::OffsetDateTime::now_utc();
+        let amz_date = format!(
+            "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+            now.year(),
+            now.month() as u8,
+            now.day(),
+            now.hour(),
+            now.minute(),
+            now.second()
+        );
+        let payload_hash = hex::encode(Sha256::digest(&body));
+        let authorization = sign_v4(&SigningRequest {
+            method: method.as_str(),
+            host: &host,
+            path: &path,
+            payload_hash: &payload_hash,
+            amz_date: &amz_date,
+            region: &self.region,
+            access_key_id: &self.access_key_id,
+            secret_access_key: &self.secret_access_key,
+        });
+
+        self.client
+            .request(method, url)
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header(reqwest::header::AUTHORIZATION, authorization)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))
+    }
+}
+
+#[async_trait::async_trait]
+impl BlobStore for S3BlobStore {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<(), ApplicationError> {
+        let response = self.send(Method::PUT, key, bytes).await?;
+
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(ApplicationError::ApplicationError(format!(
+                "S3 rejected the upload of {} with {}",
+                key,
+                response.status()
+            ))),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, ApplicationError> {
+        let response = self.send(Method::GET, key, Bytes::new()).await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => response
+                .bytes()
+                .await
+                .map(Some)
+                .map_err(|e| ApplicationError::ApplicationError(e.to_string())),
+            status => Err(ApplicationError::ApplicationError(format!(
+                "S3 rejected the download of {} with {}",
+                key, status
+            ))),
+        }
+    }
+}
+
+struct SigningRequest<'a> {
+    method: &'a str,
+    host: &'a str,
+    // Already URI encoded, keys never need escaping
+    path: &'a str,
+    payload_hash: &'a str,
+    // e.g. `20130524T000000Z`
+    amz_date: &'a str,
+    region: &'a str,
+    access_key_id: &'a str,
+    secret_access_key: &'a str,
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let date_key = hmac_sha256(format!("AWS4{}", secret_access_key).as_bytes(), date);
+    let region_key = hmac_sha256(&date_key, region);
+    let service_key = hmac_sha256(&region_key, service);
+
+    hmac_sha256(&service_key, "aws4_request")
+}
+
+// The `Authorization` header for a request without a query string, signing the host, date and
+// payload hash headers
+fn sign_v4(request: &SigningRequest) -> String {
+    let date = &request.amz_date[..8];
+    let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+    let canonical_request = format!(
+        "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+        request.method,
+        request.path,
+        request.host,
+        request.payload_hash,
+        request.amz_date,
+        signed_headers,
+        request.payload_hash
+    );
+    let scope = format!("{}/{}/s3/aws4_request", date, request.region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        request.amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let signature = hex::encode(hmac_sha256(
+        &signing_key(request.secret_access_key, date, request.region, "s3"),
+        &string_to_sign,
+    ));
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        request.access_key_id, scope, signed_headers, signature
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_blob_should_be_read_back_from_the_filesystem() {
+        let root = std::env::temp_dir().join(format!("blobs-{}", uuid::Uuid::new_v4()));
+        let store = FilesystemBlobStore::new(&root);
+
+        store
+            .put("avatars/test", Bytes::from_static(b"first"))
+            .await
+            .unwrap();
+        store
+            .put("avatars/test", Bytes::from_static(b"second"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store.get("avatars/test").await.unwrap(),
+            Some(Bytes::from_static(b"second"))
+        );
+        assert_eq!(store.get("avatars/missing").await.unwrap(), None);
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+
+    // The example from the AWS Signature Version 4 documentation
+    #[test]
+    fn the_signing_key_should_match_the_aws_example() {
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+
+        assert_eq!(
+            hex::encode(key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+}
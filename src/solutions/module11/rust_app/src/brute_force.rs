@@ -0,0 +1,218 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::core::{ApplicationError, ChallengeProvider, Config, LockoutResponse};
+
+#[derive(Clone, Debug)]
+pub struct BruteForceSettings {
+    pub window: Duration,
+    pub max_failures: u64,
+    pub cooldown: Duration,
+    pub response: LockoutResponse,
+}
+
+impl BruteForceSettings {
+    pub fn from_config(config: &Config) -> Result<Self, ApplicationError> {
+        let response = config.brute_force_response();
+
+        if response == LockoutResponse::Challenge
+            && config.challenge_provider() == ChallengeProvider::None
+        {
+            return Err(ApplicationError::ApplicationError(
+                "brute_force.response challenge requires registration.challenge_provider to be set"
+                    .to_string(),
+            ));
+        }
+
+        Ok(Self {
+            window: Duration::from_secs(config.brute_force_window_seconds()),
+            max_failures: config.brute_force_max_failures(),
+            cooldown: Duration::from_secs(config.brute_force_cooldown_seconds()),
+            response,
+        })
+    }
+}
+
+// Where the detector keeps the failures it has seen and the lockout they caused, both as unix
+// timestamps in seconds so a shared store could serve several API instances.
+#[async_trait::async_trait]
+pub trait LoginFailureStore: Send + Sync {
+    // Records a failure and returns how many have happened since `window_start`
+    async fn record_failure(&self, at: u64, window_start: u64) -> Result<u64, ApplicationError>;
+    async fn lockout_until(&self) -> Result<Option<u64>, ApplicationError>;
+    async fn lock_out_until(&self, until: u64) -> Result<(), ApplicationError>;
+}
+
+#[derive(Default)]
+pub struct InMemoryLoginFailures {
+    failures: Mutex<VecDeque<u64>>,
+    lockout_until: Mutex<Option<u64>>,
+}
+
+#[async_trait::async_trait]
+impl LoginFailureStore for InMemoryLoginFailures {
+    async fn record_failure(&self, at: u64, window_start: u64) -> Result<u64, ApplicationError> {
+        let mut failures = self.failures.lock().unwrap();
+
+        while failures.front().is_some_and(|failure| *failure < window_start) {
+            failures.pop_front();
+        }
+        failures.push_back(at);
+
+        Ok(failures.len() as u64)
+    }
+
+    async fn lockout_until(&self) -> Result<Option<u64>, ApplicationError> {
+        Ok(*self.lockout_until.lock().unwrap())
+    }
+
+    async fn lock_out_until(&self, until: u64) -> Result<(), ApplicationError> {
+        *self.lockout_until.lock().unwrap() = Some(until);
+
+        Ok(())
+    }
+}
+
+// Why a login failed, the `reason` label of `user_login_failed_total`. Credential stuffing shows
+// as unknown users and bad passwords rising together across many addresses, where someone
+// guessing one user's password only raises bad passwords.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoginFailure {
+    UnknownUser,
+    BadPassword,
+    // Refused by the gate before the credentials were checked. The lockout is of every account at
+    // once, see `BruteForceDetector`.
+    LockedAccount,
+    // The magic link was invalid, expired, already used or for an account deleted since
+    InvalidLink,
+    // The user couldn't be read, so nothing was checked
+    Error,
+}
+
+impl LoginFailure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginFailure::UnknownUser => "unknown_user",
+            LoginFailure::BadPassword => "bad_password",
+            LoginFailure::LockedAccount => "locked_account",
+            LoginFailure::InvalidLink => "invalid_link",
+            LoginFailure::Error => "error",
+        }
+    }
+
+    // Only guesses count towards a lockout, attempts refused by one would otherwise extend it
+    pub fn counts_towards_lockout(&self) -> bool {
+        matches!(self, LoginFailure::UnknownUser | LoginFailure::BadPassword)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LoginGate {
+    Open,
+    CoolingDown { retry_after: Duration },
+    ChallengeRequired,
+}
+
+// Counts failed logins across every account in a sliding window, an attacker spraying one
+// password over many accounts never trips a per-account limit. Once the window holds more than
+// `max_failures` the whole login endpoint is guarded for `cooldown`.
+pub struct BruteForceDetector {
+    store: Arc<dyn LoginFailureStore>,
+    settings: BruteForceSettings,
+}
+
+impl BruteForceDetector {
+    pub fn new(store: Arc<dyn LoginFailureStore>, settings: BruteForceSettings) -> Self {
+        Self { store, settings }
+    }
+
+    pub async fn gate(&self, now: u64) -> Result<LoginGate, ApplicationError> {
+        match self.store.lockout_until().await? {
+            Some(until) if until > now => Ok(match self.settings.response {
+                LockoutResponse::Cooldown => LoginGate::CoolingDown {
+                    retry_after: Duration::from_secs(until - now),
+                },
+                LockoutResponse::Challenge => LoginGate::ChallengeRequired,
+            }),
+            _ => Ok(LoginGate::Open),
+        }
+    }
+
+    // Returns true when this failure started a lockout
+    pub async fn record_failure(&self, now: u64) -> Result<bool, ApplicationError> {
+        let window_start = now.saturating_sub(self.settings.window.as_secs());
+        let failures = self.store.record_failure(now, window_start).await?;
+
+        if failures <= self.settings.max_failures || self.gate(now).await? != LoginGate::Open {
+            return Ok(false);
+        }
+
+        self.store
+            .lock_out_until(now + self.settings.cooldown.as_secs())
+            .await?;
+
+        Ok(true)
+    }
+}
+
+pub fn brute_force_detector_from_config(
+    config: &Config,
+) -> Result<Option<BruteForceDetector>, ApplicationError> {
+    if !config.brute_force_enabled() {
+        return Ok(None);
+    }
+
+    Ok(Some(BruteForceDetector::new(
+        Arc::new(InMemoryLoginFailures::default()),
+        BruteForceSettings::from_config(config)?,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector(response: LockoutResponse) -> BruteForceDetector {
+        BruteForceDetector::new(
+            Arc::new(InMemoryLoginFailures::default()),
+            BruteForceSettings {
+                window: Duration::from_secs(60),
+                max_failures: 3,
+                cooldown: Duration::from_secs(30),
+                response,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn when_failures_exceed_the_threshold_should_cool_down_until_the_cooldown_passes() {
+        let detector = detector(LockoutResponse::Cooldown);
+
+        for second in 0..3 {
+            assert!(!detector.record_failure(1_000 + second).await.unwrap());
+        }
+        assert_eq!(detector.gate(1_003).await.unwrap(), LoginGate::Open);
+
+        assert!(detector.record_failure(1_003).await.unwrap());
+        assert_eq!(
+            detector.gate(1_013).await.unwrap(),
+            LoginGate::CoolingDown {
+                retry_after: Duration::from_secs(20)
+            }
+        );
+        assert_eq!(detector.gate(1_033).await.unwrap(), LoginGate::Open);
+    }
+
+    #[tokio::test]
+    async fn failures_outside_the_window_should_not_count() {
+        let detector = detector(LockoutResponse::Challenge);
+
+        for second in [0, 45, 61, 90] {
+            assert!(!detector.record_failure(1_000 + second).await.unwrap());
+        }
+        assert!(detector.record_failure(1_100).await.unwrap());
+
+        assert_eq!(detector.gate(1_100).await.unwrap(), LoginGate::ChallengeRequired);
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use axum::body::{Body, Bytes};
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::auth::SessionClaims;
+use crate::core::Config;
+use crate::metrics::{HTTP_CACHE_HITS_TOTAL, HTTP_CACHE_MISSES_TOTAL};
+use crate::AppState;
+
+const MAX_ENTRIES: usize = 10_000;
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+// Responses are cached per principal, one user must never be served another user's response
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CacheKey {
+    path: String,
+    query: String,
+    principal: String,
+}
+
+#[derive(Clone)]
+struct CachedResponse {
+    headers: HeaderMap,
+    body: Bytes,
+    etag: HeaderValue,
+    expires_at: Instant,
+}
+
+// Caches successful GET responses for the routes given a TTL in config. The ETag is the handler's
+// own if it set one, otherwise a hash of the body, so a client revalidating with `If-None-Match` gets a 304 for as long as the content is
+// unchanged. Write handlers call `invalidate_path` for anything they change.
+pub struct ResponseCache {
+    ttls: HashMap<String, Duration>,
+    entries: RwLock<HashMap<CacheKey, CachedResponse>>,
+}
+
+impl ResponseCache {
+    pub fn new(ttls: HashMap<String, Duration>) -> Self {
+        Self {
+            ttls,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Option<Self> {
+        if !config.response_cache_enabled() {
+            return None;
+        }
+
+        Some(Self::new(
+            config
+                .response_cache_routes()
+                .into_iter()
+                .map(|(route, seconds)| (route, Duration::from_secs(seconds)))
+                .collect(),
+        ))
+    }
+
+    fn get(&self, key: &CacheKey, now: Instant) -> Option<CachedResponse> {
+        self.entries
+            .read()
+            .unwrap()
+            .get(key)
+            .filter(|cached| cached.expires_at > now)
+            .cloned()
+    }
+
+    fn insert(&self, key: CacheKey, cached: CachedResponse) {
+        let mut entries = self.entries.write().unwrap();
+
+        if entries.len() >= MAX_ENTRIES {
+            let now = Instant::now();
+            entries.retain(|_, cached| cached.expires_at > now);
+        }
+        if entries.len() < MAX_ENTRIES {
+            entries.insert(key, cached);
+        }
+    }
+
+    // Drops every cached response for the path, for all principals and query strings
+    pub fn invalidate_path(&self, path: &str) {
+        self.entries
+            .write()
+            .unwrap()
+            .retain(|key, _| key.path != path);
+    }
+
+    // Drops every cached response, when it can no longer be told which are out of date
+    pub fn clear(&self) {
+        self.entries.write().unwrap().clear();
+    }
+}
+
+pub(crate) fn etag_for(body: &[u8]) -> HeaderValue {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+
+    HeaderValue::from_str(&format!("\"{:016x}\"", hasher.finish()))
+        .expect("a hex digest is a valid header value")
+}
+
+fn respond(cached: &CachedResponse, request_headers: &HeaderMap, cache_status: &'static str) -> Response {
+    let not_modified = request_headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|if_none_match| if_none_match == cached.etag);
+
+    let mut response = if not_modified {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        let mut response = Response::new(Body::from(cached.body.clone()));
+        *response.headers_mut() = cached.headers.clone();
+        response
+    };
+
+    let headers = response.headers_mut();
+    headers.insert(header::ETAG, cached.etag.clone());
+    headers.insert("x-cache", HeaderValue::from_static(cache_status));
+
+    response
+}
+
+// Layered inside `require_session`, so the session's claims are already in the request extensions
+pub async fn cache_responses(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(cache) = &state.response_cache else {
+        return next.run(request).await;
+    };
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|matched| matched.as_str().to_string());
+    let ttl = route.as_deref().and_then(|route| cache.ttls.get(route).copied());
+    let (Some(route), Some(ttl)) = (route, ttl) else {
+        return next.run(request).await;
+    };
+    if request.method() != Method::GET {
+        return next.run(request).await;
+    }
+
+    let key = CacheKey {
+        path: request.uri().path().to_string(),
+        query: request.uri().query().unwrap_or_default().to_string(),
+        principal: request
+            .extensions()
+            .get::<SessionClaims>()
+            .map(|claims| claims.sub.clone())
+            .unwrap_or_default(),
+    };
+    let request_headers = request.headers().clone();
+
+    if let Some(cached) = cache.get(&key, Instant::now()) {
+        state
+            .metrics
+            .increment_with_labels(HTTP_CACHE_HITS_TOTAL, &[("route", &route)]);
+        return respond(&cached, &request_headers, "HIT");
+    }
+    state
+        .metrics
+        .increment_with_labels(HTTP_CACHE_MISSES_TOTAL, &[("route", &route)]);
+
+    let response = next.run(request).await;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, MAX_BODY_BYTES).await {
+        Ok(body) => body,
+        Err(e) => {
+            log::error!("{:?}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    // Handlers that version what they return set their own ETag, it must survive caching so it can
+    // be sent back in `If-Match`
+    let etag = parts
+        .headers
+        .get(header::ETAG)
+        .cloned()
+        .unwrap_or_else(|| etag_for(&body));
+    let cached = CachedResponse {
+        headers: parts.headers,
+        etag,
+        body,
+        expires_at: Instant::now() + ttl,
+    };
+    cache.insert(key, cached.clone());
+
+    respond(&cached, &request_headers, "MISS")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached(body: &'static str, ttl: Duration) -> CachedResponse {
+        CachedResponse {
+            headers: HeaderMap::new(),
+            body: Bytes::from_static(body.as_bytes()),
+            etag: etag_for(body.as_bytes()),
+            expires_at: Instant::now() + ttl,
+        }
+    }
+
+    fn key(path: &str, principal: &str) -> CacheKey {
+        CacheKey {
+            path: path.to_string(),
+            query: String::new(),
+            principal: principal.to_string(),
+        }
+    }
+
+    #[test]
+    fn cached_responses_should_be_kept_per_principal_and_invalidated_per_path() {
+        let cache = ResponseCache::new(HashMap::new());
+
+        cache.insert(key("/users/a@test.com", "a@test.com"), cached("a", Duration::from_secs(60)));
+        cache.insert(key("/users/b@test.com", "a@test.com"), cached("b", Duration::from_secs(60)));
+
+        assert!(cache.get(&key("/users/a@test.com", "a@test.com"), Instant::now()).is_some());
+        assert!(cache.get(&key("/users/a@test.com", "b@test.com"), Instant::now()).is_none());
+
+        cache.invalidate_path("/users/a@test.com");
+
+        assert!(cache.get(&key("/users/a@test.com", "a@test.com"), Instant::now()).is_none());
+        assert!(cache.get(&key("/users/b@test.com", "a@test.com"), Instant::now()).is_some());
+    }
+
+    #[test]
+    fn expired_responses_should_not_be_served() {
+        let cache = ResponseCache::new(HashMap::new());
+
+        cache.insert(key("/users/a@test.com", ""), cached("a", Duration::from_secs(1)));
+
+        assert!(cache
+            .get(&key("/users/a@test.com", ""), Instant::now() + Duration::from_secs(2))
+            .is_none());
+    }
+
+    #[test]
+    fn a_matching_if_none_match_should_be_answered_with_not_modified() {
+        let cached = cached("a", Duration::from_secs(60));
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(header::IF_NONE_MATCH, cached.etag.clone());
+
+        assert_eq!(respond(&cached, &request_headers, "HIT").status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(respond(&cached, &HeaderMap::new(), "HIT").status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,122 @@
+use clap::{Parser, Subcommand};
+use rust_users_lib::parsing;
+use rust_users_lib::{ApplicationError, BackfillSettings, DemoSettings, SeedSettings};
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(name = "rust_users_cli", about = "Operational tooling for the users service")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Copy users onto the shard their email address hashes to, e.g. after adding a shard
+    RebalanceShards {
+        /// Only report misplaced users without copying them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Compare every user in the database being migrated from with the one being migrated to
+    VerifyMigration {
+        /// Copy users the new database is missing, and the primary's copy of users that differ
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Publish a synthetic user-registered event for every existing user, e.g. to seed a read model
+    BackfillEvents {
+        /// Number of users read from the database per batch
+        #[arg(long, default_value_t = 100)]
+        batch_size: i64,
+        /// Upper bound on events published per second, 0 disables the limit
+        #[arg(long, default_value_t = 500)]
+        max_events_per_second: u32,
+    },
+    /// Store the users of a JSON or CSV fixture, hashing their passwords, with the local profile only
+    Seed {
+        /// A JSON array of users as `POST /users` takes them, or CSV with a header row of the same
+        /// field names
+        path: PathBuf,
+        /// Number of users stored at once
+        #[arg(long, default_value_t = 100)]
+        batch_size: usize,
+    },
+    /// Run the API in memory with seeded users and replay a scripted tour of it, for presentations
+    Demo {
+        /// Port the demo API listens on
+        #[arg(long, default_value = "3000", value_parser = parsing::port)]
+        port: u16,
+        /// How long to wait between scripted requests, e.g. 1500ms or 2s
+        #[arg(long, default_value = "1500ms", value_parser = parsing::duration)]
+        pause: Duration,
+        /// Stop once the script has finished instead of serving until Ctrl+C
+        #[arg(long)]
+        exit: bool,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<(), ApplicationError> {
+    let cli = Cli::parse();
+
+    if let Command::Demo { .. } = cli.command {
+        rust_users_lib::init_demo_logging();
+    } else {
+        rust_users_lib::init_cli_logger();
+    }
+
+    match cli.command {
+        Command::RebalanceShards { dry_run } => {
+            let report = rust_users_lib::rebalance_shards(dry_run).await?;
+
+            println!(
+                "Scanned {} users, {} misplaced, {} copied",
+                report.scanned, report.misplaced, report.copied
+            );
+        }
+        Command::VerifyMigration { repair } => {
+            let report = rust_users_lib::verify_migration(repair).await?;
+
+            println!(
+                "Scanned {} users, {} missing, {} mismatched, {} repaired",
+                report.scanned, report.missing, report.mismatched, report.repaired
+            );
+        }
+        Command::BackfillEvents {
+            batch_size,
+            max_events_per_second,
+        } => {
+            let report = rust_users_lib::backfill_events(BackfillSettings {
+                batch_size: batch_size.max(1),
+                max_events_per_second,
+            })
+            .await?;
+
+            println!(
+                "Published {} events in {} batches",
+                report.published, report.batches
+            );
+        }
+        Command::Seed { path, batch_size } => {
+            let report = rust_users_lib::seed_users(SeedSettings { path, batch_size }).await?;
+
+            println!("Stored {} users, {} were already taken", report.stored, report.skipped);
+        }
+        Command::Demo {
+            port,
+            pause,
+            exit,
+        } => {
+            rust_users_lib::run_demo(DemoSettings {
+                port,
+                pause,
+                keep_running: !exit,
+            })
+            .await?;
+        }
+    }
+
+    Ok(())
+}
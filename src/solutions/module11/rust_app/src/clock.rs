@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::Config;
+
+// Seconds since the epoch, as seen by anything that expires: sessions, magic links and login
+// lockouts. In the local profile it can be moved forward with `POST /dev/clock/advance` to see
+// those expire without waiting. It never runs backwards, so nothing that has expired comes back.
+#[derive(Debug, Default)]
+pub struct Clock {
+    adjustable: bool,
+    offset_seconds: AtomicU64,
+}
+
+impl Clock {
+    pub fn adjustable() -> Self {
+        Self {
+            adjustable: true,
+            offset_seconds: AtomicU64::new(0),
+        }
+    }
+
+    // Adjustable when `dev.time_travel` is set, which only the local profile allows
+    pub fn from_config(config: &Config) -> Self {
+        match config.time_travel_enabled() {
+            true => Clock::adjustable(),
+            false => Clock::default(),
+        }
+    }
+
+    pub fn is_adjustable(&self) -> bool {
+        self.adjustable
+    }
+
+    pub fn now(&self) -> u64 {
+        let system = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        system + self.offset_seconds.load(Ordering::Relaxed)
+    }
+
+    pub fn offset_seconds(&self) -> u64 {
+        self.offset_seconds.load(Ordering::Relaxed)
+    }
+
+    // Gives `false` without moving the clock when it isn't adjustable
+    pub fn advance(&self, seconds: u64) -> bool {
+        if !self.adjustable {
+            return false;
+        }
+
+        self.offset_seconds.fetch_add(seconds, Ordering::Relaxed);
+        true
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdvanceClockRequest {
+    pub seconds: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClockResponse {
+    pub now: u64,
+    pub offset_seconds: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_an_adjustable_clock_should_move_forward() {
+        let system = Clock::default();
+        let adjustable = Clock::adjustable();
+
+        assert!(!system.advance(3600));
+        assert!(adjustable.advance(3600));
+
+        assert_eq!(system.offset_seconds(), 0);
+        assert_eq!(adjustable.offset_seconds(), 3600);
+        assert!(adjustable.now() >= system.now() + 3600);
+    }
+}
@@ -0,0 +1,195 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::metrics::{Metrics, HTTP_OPEN_CONNECTIONS, HTTP_REQUESTS_IN_FLIGHT, HTTP_REQUESTS_TOTAL};
+use crate::AppState;
+
+// Open HTTP connections and requests that haven't been answered yet. While the API shuts down
+// these should drain to 0, anything left when it exits was cut off.
+#[derive(Default)]
+pub struct ConnectionStats {
+    open_connections: AtomicI64,
+    in_flight_requests: AtomicI64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionStatsResponse {
+    pub open_connections: i64,
+    pub in_flight_requests: i64,
+}
+
+impl ConnectionStats {
+    pub fn current(&self) -> ConnectionStatsResponse {
+        ConnectionStatsResponse {
+            open_connections: self.open_connections.load(Ordering::Relaxed),
+            in_flight_requests: self.in_flight_requests.load(Ordering::Relaxed),
+        }
+    }
+
+    // The gauges are only written when they are read, so concurrent connections can't leave
+    // them at a value that was already out of date
+    pub fn record(&self, metrics: &Metrics) {
+        let current = self.current();
+        metrics.set_gauge(HTTP_OPEN_CONNECTIONS, &[], current.open_connections as f64);
+        metrics.set_gauge(HTTP_REQUESTS_IN_FLIGHT, &[], current.in_flight_requests as f64);
+    }
+}
+
+// Decrements its count when dropped, so a connection that errors or a request whose future is
+// cancelled is still taken off
+struct Tracked {
+    count: Arc<ConnectionStats>,
+    field: fn(&ConnectionStats) -> &AtomicI64,
+}
+
+impl Tracked {
+    fn start(count: Arc<ConnectionStats>, field: fn(&ConnectionStats) -> &AtomicI64) -> Self {
+        field(&count).fetch_add(1, Ordering::Relaxed);
+        Self { count, field }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        (self.field)(&self.count).fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Counts the connections accepted by `axum::serve` for as long as they stay open
+pub struct TrackedListener {
+    inner: TcpListener,
+    stats: Arc<ConnectionStats>,
+}
+
+impl TrackedListener {
+    pub fn new(inner: TcpListener, stats: Arc<ConnectionStats>) -> Self {
+        Self { inner, stats }
+    }
+}
+
+pub struct TrackedStream {
+    inner: TcpStream,
+    _tracked: Tracked,
+}
+
+impl axum::serve::Listener for TrackedListener {
+    type Io = TrackedStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        // axum's own accept, which retries on errors such as running out of file descriptors
+        let (stream, address) = axum::serve::Listener::accept(&mut self.inner).await;
+
+        let stream = TrackedStream {
+            inner: stream,
+            _tracked: Tracked::start(self.stats.clone(), |stats| &stats.open_connections),
+        };
+
+        (stream, address)
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        axum::serve::Listener::local_addr(&self.inner)
+    }
+}
+
+impl AsyncRead for TrackedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TrackedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+pub async fn track_in_flight(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let _tracked = Tracked::start(state.connections.clone(), |stats| &stats.in_flight_requests);
+    state.metrics.increment(HTTP_REQUESTS_TOTAL);
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::serve::Listener;
+
+    #[tokio::test]
+    async fn a_connection_should_be_counted_until_it_is_closed() {
+        let stats = Arc::new(ConnectionStats::default());
+        let mut listener = TrackedListener::new(
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            stats.clone(),
+        );
+        let address = listener.local_addr().unwrap();
+
+        let client = TcpStream::connect(address).await.unwrap();
+        let (stream, _) = listener.accept().await;
+        assert_eq!(stats.current().open_connections, 1);
+
+        drop(stream);
+        drop(client);
+        assert_eq!(stats.current().open_connections, 0);
+    }
+
+    #[test]
+    fn recording_should_set_the_gauges_to_the_current_counts() {
+        let stats = Arc::new(ConnectionStats::default());
+        let metrics = Metrics::default();
+
+        let request = Tracked::start(stats.clone(), |stats| &stats.in_flight_requests);
+        stats.record(&metrics);
+        drop(request);
+
+        assert_eq!(metrics.gauge(HTTP_REQUESTS_IN_FLIGHT, &[]), Some(1.0));
+        assert_eq!(metrics.gauge(HTTP_OPEN_CONNECTIONS, &[]), Some(0.0));
+        assert_eq!(stats.current().in_flight_requests, 0);
+    }
+}
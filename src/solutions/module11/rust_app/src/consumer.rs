@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rdkafka::client::ClientContext;
+use rdkafka::consumer::stream_consumer::StreamConsumer;
+use rdkafka::consumer::{Consumer, ConsumerContext};
+use rdkafka::Message;
+
+use crate::core::{ApplicationError, Config};
+use crate::events;
+use crate::metrics::WORKER_MESSAGES_CONSUMED_TOTAL;
+use crate::retry::RetryPolicy;
+use crate::AppState;
+
+pub const ORDER_COMPLETED_TOPIC: &str = "order-completed";
+
+pub struct CustomContext;
+
+impl ClientContext for CustomContext {}
+
+impl ConsumerContext for CustomContext {}
+
+type LoggingConsumer = StreamConsumer<CustomContext>;
+
+// A message as handlers see it, copied out of the consumer's buffer so a handler can hold on to it
+// across awaits
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsumedMessage {
+    pub topic: String,
+    pub key: Option<String>,
+    pub payload: Option<String>,
+}
+
+// Does something with each message on the topics it is registered for. A failure is logged and
+// the consumer moves on, the message isn't redelivered.
+#[async_trait::async_trait]
+pub trait MessageHandler: Send + Sync {
+    async fn handle(
+        &self,
+        state: &AppState,
+        message: &ConsumedMessage,
+    ) -> Result<(), ApplicationError>;
+}
+
+// Logs the payload, what the worker has always done with order events
+pub struct LogMessage;
+
+#[async_trait::async_trait]
+impl MessageHandler for LogMessage {
+    async fn handle(
+        &self,
+        _: &AppState,
+        message: &ConsumedMessage,
+    ) -> Result<(), ApplicationError> {
+        log::info!("Received message");
+        log::info!("Message: {:?}", message.payload);
+        Ok(())
+    }
+}
+
+// The handlers for each topic, the consumer subscribes to every topic with one. Exercises reuse
+// the consumer loop by registering their own, tests by registering fakes.
+pub struct HandlerRegistry {
+    handlers: HashMap<String, Vec<Arc<dyn MessageHandler>>>,
+}
+
+impl HandlerRegistry {
+    pub fn new() -> Self {
+        Self {
+            handlers: HashMap::new(),
+        }
+    }
+
+    // What `rust_users_worker` runs with
+    pub fn with_defaults() -> Self {
+        Self::new().on(ORDER_COMPLETED_TOPIC, LogMessage)
+    }
+
+    // Handlers for the same topic run in the order they were registered
+    pub fn on(mut self, topic: &str, handler: impl MessageHandler + 'static) -> Self {
+        self.handlers
+            .entry(topic.to_string())
+            .or_default()
+            .push(Arc::new(handler));
+        self
+    }
+
+    pub fn topics(&self) -> Vec<&str> {
+        let mut topics: Vec<&str> = self.handlers.keys().map(String::as_str).collect();
+        topics.sort();
+        topics
+    }
+
+    // Every handler for the message's topic runs even when an earlier one fails, the first
+    // failure is returned
+    pub async fn dispatch(
+        &self,
+        state: &AppState,
+        message: &ConsumedMessage,
+    ) -> Result<(), ApplicationError> {
+        let mut result = Ok(());
+        for handler in self.handlers.get(&message.topic).into_iter().flatten() {
+            if let Err(e) = handler.handle(state, message).await {
+                log::warn!("Handling a message from {} failed: {:?}", message.topic, e);
+                result = result.and(Err(e));
+            }
+        }
+
+        result
+    }
+}
+
+impl Default for HandlerRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Consumes the registry's topics until the process stops, backing off while Kafka is unreachable
+pub async fn run_consumer(
+    config: &Config,
+    state: Arc<AppState>,
+    handlers: HandlerRegistry,
+) -> Result<(), ApplicationError> {
+    let topics = handlers.topics();
+    if topics.is_empty() {
+        return Err(ApplicationError::ApplicationError(
+            "the worker has no message handlers registered".to_string(),
+        ));
+    }
+
+    let consumer: LoggingConsumer = events::kafka_client_config(config)
+        .set("group.id", config.kafka_group_id())
+        .create_with_context(CustomContext)
+        .map_err(|e| {
+            ApplicationError::ApplicationError(format!("Consumer creation failed: {}", e))
+        })?;
+    consumer.subscribe(&topics).map_err(|e| {
+        ApplicationError::ApplicationError(format!("Can't subscribe to specified topics: {}", e))
+    })?;
+
+    let backoff = RetryPolicy::builder()
+        .base_delay(Duration::from_millis(500))
+        .max_delay(Duration::from_secs(30))
+        .build();
+    let mut consecutive_errors = 0;
+
+    loop {
+        log::info!("Background worker is running...");
+        match consumer.recv().await {
+            Err(e) => {
+                consecutive_errors += 1;
+                let delay = backoff.delay_for_attempt(consecutive_errors);
+                tracing::warn!("Kafka error: {}, backing off for {:?}", e, delay);
+                tokio::time::sleep(delay).await;
+            }
+            Ok(m) => {
+                consecutive_errors = 0;
+                state
+                    .metrics
+                    .increment_with_labels(WORKER_MESSAGES_CONSUMED_TOTAL, &[("topic", m.topic())]);
+                let message = ConsumedMessage {
+                    topic: m.topic().to_string(),
+                    key: m.key_view::<str>().and_then(Result::ok).map(str::to_string),
+                    payload: m.payload_view::<str>().and_then(Result::ok).map(str::to_string),
+                };
+                // Already logged, one bad message doesn't stop the worker
+                let _ = handlers.dispatch(&state, &message).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap, Method};
+use axum::middleware::Next;
+use axum::response::Response;
+
+use crate::core::ApplicationError;
+use crate::errors::error_response;
+use crate::AppState;
+
+// Whether the request carries a body. Bodiless writes such as `POST /logout` have nothing to parse.
+fn has_body(headers: &HeaderMap) -> bool {
+    let length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.trim().parse::<u64>().ok());
+
+    match length {
+        Some(length) => length > 0,
+        None => headers.contains_key(header::TRANSFER_ENCODING),
+    }
+}
+
+// Accepts `application/json` and `+json` types such as `application/merge-patch+json`, as axum's
+// `Json` does, with no charset or UTF-8, the only encoding JSON may be sent in.
+fn check_json(content_type: Option<&str>) -> Result<(), ApplicationError> {
+    let content_type = content_type.ok_or_else(|| {
+        ApplicationError::UnsupportedMediaType("the body must be sent as application/json".to_string())
+    })?;
+    let mut parameters = content_type.split(';').map(str::trim);
+    let media_type = parameters.next().unwrap_or_default().to_ascii_lowercase();
+
+    let is_json = media_type == "application/json"
+        || media_type
+            .strip_prefix("application/")
+            .is_some_and(|subtype| subtype.ends_with("+json"));
+    if !is_json {
+        return Err(ApplicationError::UnsupportedMediaType(format!(
+            "{} isn't accepted, the body must be sent as application/json",
+            media_type
+        )));
+    }
+
+    for parameter in parameters {
+        let Some((name, value)) = parameter.split_once('=') else {
+            continue;
+        };
+        let charset = value.trim().trim_matches('"');
+        if name.trim().eq_ignore_ascii_case("charset")
+            && !charset.eq_ignore_ascii_case("utf-8")
+            && !charset.eq_ignore_ascii_case("utf8")
+        {
+            return Err(ApplicationError::UnsupportedMediaType(format!(
+                "charset {} isn't accepted, JSON must be sent as utf-8",
+                charset
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// Turns away writes to JSON endpoints whose body isn't JSON with a 415 and the error envelope,
+// before the extractor gets to answer with its own plain text rejection. Layered on the routes
+// that take JSON, uploads such as the avatar have their own content types.
+pub async fn require_json(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let writes = matches!(*request.method(), Method::POST | Method::PUT | Method::PATCH);
+    if !writes || !has_body(request.headers()) {
+        return next.run(request).await;
+    }
+
+    let content_type = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map(|content_type| content_type.to_str().unwrap_or_default());
+    match check_json(content_type) {
+        Ok(()) => next.run(request).await,
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_with_or_without_a_utf8_charset_should_be_accepted() {
+        for content_type in [
+            "application/json",
+            "Application/JSON",
+            "application/json; charset=utf-8",
+            "application/json;charset=\"UTF-8\"",
+            "application/merge-patch+json",
+        ] {
+            assert!(check_json(Some(content_type)).is_ok(), "{} should be accepted", content_type);
+        }
+    }
+
+    #[test]
+    fn other_media_types_and_charsets_should_be_unsupported() {
+        for content_type in [
+            None,
+            Some("text/plain"),
+            Some("application/x-www-form-urlencoded"),
+            Some("text/json+xml"),
+            Some("application/json; charset=iso-8859-1"),
+        ] {
+            assert!(
+                matches!(check_json(content_type), Err(ApplicationError::UnsupportedMediaType(_))),
+                "{:?} should be rejected",
+                content_type
+            );
+        }
+    }
+}
@@ -1,19 +1,68 @@
+use std::collections::HashMap;
+
 use figment::providers::{Env, Format};
 use figment::Figment;
 use serde::Deserialize;
 
-use super::core::ApplicationError;
+use workshop_core::ApplicationError;
 
 #[derive(Deserialize)]
 pub struct Config {
     database: DatabaseConfiguration,
     messaging: Option<KafkaConfiguration>,
+    auth: Option<AuthConfiguration>,
+    anomaly_detection: Option<AnomalyDetectionConfiguration>,
+    maintenance: Option<MaintenanceConfiguration>,
+    registration: Option<RegistrationConfiguration>,
+    brute_force: Option<BruteForceConfiguration>,
+    response_cache: Option<ResponseCacheConfiguration>,
+    user_cache: Option<UserCacheConfiguration>,
+    cors: Option<CorsConfiguration>,
+    outbox: Option<OutboxConfiguration>,
+    request_timeout_ms: Option<u64>,
+    coalesce_reads: Option<bool>,
+    profile: Option<Profile>,
+    dev: Option<DevConfiguration>,
+    warmup: Option<WarmupConfiguration>,
+    login_alerts: Option<LoginAlertsConfiguration>,
+    audit: Option<AuditConfiguration>,
+    buffer: Option<BufferConfiguration>,
+    routes: Option<RoutesConfiguration>,
+    avatars: Option<AvatarConfiguration>,
+    telemetry: Option<TelemetryConfiguration>,
+    migration: Option<MigrationConfiguration>,
+    dynamodb: Option<DynamoConfiguration>,
+    mongodb: Option<MongoConfiguration>,
+    in_memory: Option<InMemoryConfiguration>,
+    #[serde(default, deserialize_with = "super::parsing::deserialize_port")]
     app_port: Option<u16>,
 }
 
 #[derive(Deserialize)]
 pub struct DatabaseConfiguration {
     connection_string: String,
+    // A replica of `connection_string` lookups and listings are read from, see
+    // `PostgresUsers::with_reads`. Not used with shards.
+    read_connection_string: Option<String>,
+    shards: Option<Vec<String>>,
+    // Whether a user missing from their shard is looked for on the one the modulo placement put
+    // them on, turn it off once `rebalance-shards` has run after upgrading
+    legacy_shard_placement: Option<bool>,
+    statement_cache_capacity: Option<usize>,
+    // Each pool's, every shard and the migration target have their own, see `PoolSettings`
+    min_connections: Option<u32>,
+    max_connections: Option<u32>,
+    acquire_timeout_ms: Option<u64>,
+    // 0 keeps idle connections open
+    idle_timeout_seconds: Option<u64>,
+    // How long Postgres lets a statement run before cancelling it, 0 lets them run for as long as
+    // they take
+    statement_timeout_ms: Option<u64>,
+    // Whether the API and worker apply the embedded migrations at startup, off for databases whose
+    // tables were created by hand
+    run_migrations: Option<bool>,
+    // How often the API records each pool's connections and longest acquire, 0 doesn't
+    pool_statistics_interval_seconds: Option<u64>,
 }
 
 #[derive(Deserialize)]
@@ -24,6 +73,296 @@ pub struct KafkaConfiguration {
     group_id: String,
 }
 
+#[derive(Deserialize)]
+pub struct AuthConfiguration {
+    mode: Option<AuthMode>,
+    signing_key: Option<String>,
+    session_ttl_seconds: Option<u64>,
+    secure_cookie: Option<bool>,
+    revocation_store: Option<StoreKind>,
+    session_store: Option<StoreKind>,
+    magic_link: Option<MagicLinkConfiguration>,
+    admins: Option<Vec<String>>,
+    impersonation_ttl_seconds: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkConfiguration {
+    enabled: Option<bool>,
+    base_url: Option<String>,
+    ttl_seconds: Option<u64>,
+    store: Option<StoreKind>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    // Login only verifies the password, no session is issued
+    None,
+    // Login sets a signed, HttpOnly session cookie that authenticates subsequent requests
+    Cookie,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    // Running on a developer's machine, enables conveniences such as the `/dev` endpoints
+    Local,
+    Production,
+}
+
+// Conveniences for the local profile only, ignored otherwise
+#[derive(Deserialize)]
+pub struct DevConfiguration {
+    time_travel: Option<bool>,
+}
+
+// Work done before the API starts listening, so the first requests aren't slower than the rest
+#[derive(Deserialize)]
+pub struct WarmupConfiguration {
+    enabled: Option<bool>,
+    connections: Option<u32>,
+    // Email addresses whose `GET /users/{email_address}` is put in the response cache up front
+    hot_users: Option<Vec<String>>,
+}
+
+// Tells users when they log in from a device they haven't used before
+#[derive(Deserialize)]
+pub struct LoginAlertsConfiguration {
+    enabled: Option<bool>,
+    store: Option<StoreKind>,
+}
+
+#[derive(Deserialize)]
+pub struct AuditConfiguration {
+    enabled: Option<bool>,
+    store: Option<StoreKind>,
+}
+
+// Users held in memory in front of the database, see `BufferedUsers`
+#[derive(Deserialize)]
+pub struct BufferConfiguration {
+    mode: Option<BufferMode>,
+    flush_interval_ms: Option<u64>,
+    max_pending: Option<usize>,
+}
+
+// `write_through` writes to the database before answering, `write_behind` answers once the write is
+// in memory and flushes it to the database later, losing it if the process stops first
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BufferMode {
+    WriteThrough,
+    WriteBehind,
+}
+
+// Which routes answer, see `RouteToggles`. Unset, every route does.
+#[derive(Deserialize)]
+pub struct RoutesConfiguration {
+    enabled: Option<Vec<String>>,
+    hint: Option<String>,
+}
+
+// Profile pictures uploaded with `PUT /users/{email_address}/avatar`
+#[derive(Deserialize)]
+pub struct AvatarConfiguration {
+    store: Option<BlobStoreKind>,
+    // Where the filesystem store keeps its files, avatars are under `avatars/` in it
+    directory: Option<String>,
+    max_bytes: Option<usize>,
+    s3: Option<S3Configuration>,
+}
+
+#[derive(Deserialize)]
+pub struct S3Configuration {
+    // e.g. "https://s3.eu-west-1.amazonaws.com", or a MinIO server
+    endpoint: Option<String>,
+    bucket: Option<String>,
+    region: Option<String>,
+    access_key_id: Option<String>,
+    secret_access_key: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BlobStoreKind {
+    Memory,
+    Filesystem,
+    S3,
+}
+
+// Moving users from `database.connection_string` to another database, every write goes to both
+#[derive(Deserialize)]
+pub struct MigrationConfiguration {
+    connection_string: String,
+    primary: Option<MigrationPrimary>,
+}
+
+// Users kept in a DynamoDB table instead of `database.connection_string`, see `DynamoUsers`.
+// Sessions, the audit log and the other stores are still kept as their own `store` says, with
+// none of them in Postgres `database.run_migrations` can be switched off.
+#[derive(Deserialize)]
+pub struct DynamoConfiguration {
+    enabled: Option<bool>,
+    table_name: Option<String>,
+    // Otherwise the SDK's, from `AWS_REGION` or the AWS profile
+    region: Option<String>,
+    // e.g. `http://localhost:8000` for DynamoDB Local
+    endpoint_url: Option<String>,
+}
+
+// Users kept in a MongoDB collection instead, see `MongoUsers`. The other stores are kept as for
+// `dynamodb`, which can't be enabled alongside it.
+#[derive(Deserialize)]
+pub struct MongoConfiguration {
+    enabled: Option<bool>,
+    connection_string: Option<String>,
+    database: Option<String>,
+    collection: Option<String>,
+}
+
+// Users held in the process instead, see `InMemoryUsers`, so the API runs without any
+// infrastructure. They are lost on restart and not shared between instances, the other stores are
+// kept as for `dynamodb`.
+#[derive(Deserialize)]
+pub struct InMemoryConfiguration {
+    enabled: Option<bool>,
+}
+
+// Which database reads are served from, the other is only read for users the primary doesn't have
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MigrationPrimary {
+    #[default]
+    Old,
+    New,
+}
+
+#[derive(Deserialize)]
+pub struct TelemetryConfiguration {
+    id_generator: Option<IdGeneratorKind>,
+    propagation: Option<PropagationFormat>,
+    // Reports where each request's time went in a `Server-Timing` header
+    server_timing: Option<bool>,
+}
+
+// X-Ray rejects trace ids that don't start with the time they were created at
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IdGeneratorKind {
+    #[default]
+    Random,
+    Xray,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PropagationFormat {
+    // W3C `traceparent`
+    #[default]
+    TraceContext,
+    // `X-Amzn-Trace-Id` as well as `traceparent`
+    Xray,
+}
+
+#[derive(Deserialize)]
+pub struct AnomalyDetectionConfiguration {
+    enabled: Option<bool>,
+    interval_seconds: Option<u64>,
+    baseline_windows: Option<usize>,
+    threshold_multiplier: Option<f64>,
+    min_events: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct MaintenanceConfiguration {
+    enabled: Option<bool>,
+    interval_seconds: Option<u64>,
+    run_analyze: Option<bool>,
+    off_peak_start_hour: Option<u8>,
+    off_peak_end_hour: Option<u8>,
+}
+
+// Where state that isn't a user is kept, e.g. revoked sessions or login history. The in-memory
+// stores are lost on restart and not shared between instances.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    Memory,
+    Postgres,
+}
+
+#[derive(Deserialize)]
+pub struct OutboxConfiguration {
+    enabled: Option<bool>,
+    poll_interval_ms: Option<u64>,
+    batch_size: Option<i64>,
+    // How often the API records how far behind the relay is, 0 doesn't
+    statistics_interval_seconds: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct ResponseCacheConfiguration {
+    enabled: Option<bool>,
+    // Route templates as they are registered on the router, e.g. "/users/{email_address}"
+    routes: Option<HashMap<String, u64>>,
+    // Drops the responses cached for users as soon as anyone changes them in the main database,
+    // so replicas don't serve them until their TTL runs out
+    listen_for_changes: Option<bool>,
+}
+
+// Users read by email address cached in Redis in front of the store, see `CachedDataAccess`
+#[derive(Deserialize)]
+pub struct UserCacheConfiguration {
+    enabled: Option<bool>,
+    redis_url: Option<String>,
+    ttl_seconds: Option<u64>,
+    // Put before each user's email address in their key, so instances sharing a Redis can keep
+    // apart
+    key_prefix: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CorsConfiguration {
+    // e.g. "https://app.example.com", or "https://*.example.com" for any of its subdomains
+    allowed_origins: Option<Vec<String>>,
+    max_age_seconds: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct BruteForceConfiguration {
+    enabled: Option<bool>,
+    window_seconds: Option<u64>,
+    max_failures: Option<u64>,
+    cooldown_seconds: Option<u64>,
+    response: Option<LockoutResponse>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LockoutResponse {
+    // Every login is refused with a 429 until the cooldown has passed
+    Cooldown,
+    // Logins must pass the registration challenge until the cooldown has passed
+    Challenge,
+}
+
+#[derive(Deserialize)]
+pub struct RegistrationConfiguration {
+    challenge_provider: Option<ChallengeProvider>,
+    challenge_secret: Option<String>,
+    challenge_verify_url: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChallengeProvider {
+    // Registrations are accepted without a challenge
+    None,
+    HCaptcha,
+    Turnstile,
+}
+
 impl Config {
     pub fn get_configuration() -> Result<Self, ApplicationError> {
         let config: Config = Figment::new()
@@ -39,6 +378,46 @@ impl Config {
         self.database.connection_string.clone()
     }
 
+    pub fn read_connection_string(&self) -> Option<String> {
+        self.database.read_connection_string.clone()
+    }
+
+    pub fn shard_connection_strings(&self) -> Vec<String> {
+        self.database.shards.clone().unwrap_or_default()
+    }
+
+    pub fn legacy_shard_placement(&self) -> bool {
+        self.database.legacy_shard_placement.unwrap_or(true)
+    }
+
+    pub fn statement_cache_capacity(&self) -> Option<usize> {
+        self.database.statement_cache_capacity
+    }
+    pub fn min_connections(&self) -> Option<u32> {
+        self.database.min_connections
+    }
+    pub fn max_connections(&self) -> Option<u32> {
+        self.database.max_connections
+    }
+    pub fn acquire_timeout_ms(&self) -> Option<u64> {
+        self.database.acquire_timeout_ms
+    }
+    pub fn idle_timeout_seconds(&self) -> Option<u64> {
+        self.database.idle_timeout_seconds
+    }
+    pub fn statement_timeout_ms(&self) -> Option<u64> {
+        self.database.statement_timeout_ms
+    }
+
+    // Off by default with users in memory, Postgres is then only there for stores that ask for it
+    pub fn run_migrations(&self) -> bool {
+        self.database.run_migrations.unwrap_or(!self.in_memory_enabled())
+    }
+
+    pub fn pool_statistics_interval_seconds(&self) -> u64 {
+        self.database.pool_statistics_interval_seconds.unwrap_or(15)
+    }
+
     pub fn kafka_broker(&self) -> String {
         self.messaging
             .as_ref()
@@ -49,11 +428,13 @@ impl Config {
         self.messaging
             .as_ref()
             .and_then(|kafka| kafka.username.clone())
+            .filter(|username| !username.is_empty())
     }
     pub fn kafka_password(&self) -> Option<String> {
         self.messaging
             .as_ref()
             .and_then(|kafka| kafka.password.clone())
+            .filter(|password| !password.is_empty())
     }
     pub fn kafka_group_id(&self) -> String {
         self.messaging
@@ -62,6 +443,517 @@ impl Config {
             .unwrap_or_else(|| "default_group".to_string())
     }
 
+    pub fn auth_mode(&self) -> AuthMode {
+        self.auth
+            .as_ref()
+            .and_then(|auth| auth.mode)
+            .unwrap_or(AuthMode::None)
+    }
+    pub fn auth_signing_key(&self) -> Option<String> {
+        self.auth
+            .as_ref()
+            .and_then(|auth| auth.signing_key.clone())
+    }
+    pub fn session_ttl_seconds(&self) -> u64 {
+        self.auth
+            .as_ref()
+            .and_then(|auth| auth.session_ttl_seconds)
+            .unwrap_or(3600)
+    }
+    pub fn secure_cookie(&self) -> bool {
+        self.auth
+            .as_ref()
+            .and_then(|auth| auth.secure_cookie)
+            .unwrap_or(false)
+    }
+
+    pub fn revocation_store(&self) -> StoreKind {
+        self.auth
+            .as_ref()
+            .and_then(|auth| auth.revocation_store)
+            .unwrap_or(StoreKind::Memory)
+    }
+
+    // Where the metadata listed by `GET /users/{email_address}/sessions` is kept
+    pub fn session_store(&self) -> StoreKind {
+        self.auth
+            .as_ref()
+            .and_then(|auth| auth.session_store)
+            .unwrap_or(StoreKind::Memory)
+    }
+
+    // Email addresses given the admin role when the API starts, see `auth::promote_admins`
+    pub fn auth_admins(&self) -> Vec<String> {
+        self.auth
+            .as_ref()
+            .and_then(|auth| auth.admins.clone())
+            .unwrap_or_default()
+    }
+    pub fn impersonation_ttl_seconds(&self) -> u64 {
+        self.auth
+            .as_ref()
+            .and_then(|auth| auth.impersonation_ttl_seconds)
+            .unwrap_or(900)
+    }
+
+    fn magic_link(&self) -> Option<&MagicLinkConfiguration> {
+        self.auth.as_ref().and_then(|auth| auth.magic_link.as_ref())
+    }
+    pub fn magic_link_enabled(&self) -> bool {
+        self.magic_link()
+            .and_then(|magic_link| magic_link.enabled)
+            .unwrap_or(false)
+    }
+    // Links point back at this API unless a public URL is configured
+    pub fn magic_link_base_url(&self) -> String {
+        self.magic_link()
+            .and_then(|magic_link| magic_link.base_url.clone())
+            .unwrap_or_else(|| format!("http://localhost:{}", self.app_port()))
+    }
+    pub fn magic_link_ttl_seconds(&self) -> u64 {
+        self.magic_link()
+            .and_then(|magic_link| magic_link.ttl_seconds)
+            .unwrap_or(900)
+    }
+    pub fn magic_link_store(&self) -> StoreKind {
+        self.magic_link()
+            .and_then(|magic_link| magic_link.store)
+            .unwrap_or(StoreKind::Memory)
+    }
+
+    pub fn anomaly_detection_enabled(&self) -> bool {
+        self.anomaly_detection
+            .as_ref()
+            .and_then(|anomaly| anomaly.enabled)
+            .unwrap_or(false)
+    }
+    pub fn anomaly_detection_interval_seconds(&self) -> u64 {
+        self.anomaly_detection
+            .as_ref()
+            .and_then(|anomaly| anomaly.interval_seconds)
+            .unwrap_or(60)
+    }
+    pub fn anomaly_detection_baseline_windows(&self) -> usize {
+        self.anomaly_detection
+            .as_ref()
+            .and_then(|anomaly| anomaly.baseline_windows)
+            .unwrap_or(10)
+    }
+    pub fn anomaly_detection_threshold_multiplier(&self) -> f64 {
+        self.anomaly_detection
+            .as_ref()
+            .and_then(|anomaly| anomaly.threshold_multiplier)
+            .unwrap_or(3.0)
+    }
+    pub fn anomaly_detection_min_events(&self) -> u64 {
+        self.anomaly_detection
+            .as_ref()
+            .and_then(|anomaly| anomaly.min_events)
+            .unwrap_or(10)
+    }
+
+    pub fn maintenance_enabled(&self) -> bool {
+        self.maintenance
+            .as_ref()
+            .and_then(|maintenance| maintenance.enabled)
+            .unwrap_or(false)
+    }
+    pub fn maintenance_interval_seconds(&self) -> u64 {
+        self.maintenance
+            .as_ref()
+            .and_then(|maintenance| maintenance.interval_seconds)
+            .unwrap_or(3600)
+    }
+    pub fn maintenance_run_analyze(&self) -> bool {
+        self.maintenance
+            .as_ref()
+            .and_then(|maintenance| maintenance.run_analyze)
+            .unwrap_or(false)
+    }
+    pub fn maintenance_off_peak_start_hour(&self) -> u8 {
+        self.maintenance
+            .as_ref()
+            .and_then(|maintenance| maintenance.off_peak_start_hour)
+            .unwrap_or(1)
+    }
+    pub fn maintenance_off_peak_end_hour(&self) -> u8 {
+        self.maintenance
+            .as_ref()
+            .and_then(|maintenance| maintenance.off_peak_end_hour)
+            .unwrap_or(5)
+    }
+
+    pub fn challenge_provider(&self) -> ChallengeProvider {
+        self.registration
+            .as_ref()
+            .and_then(|registration| registration.challenge_provider)
+            .unwrap_or(ChallengeProvider::None)
+    }
+    pub fn challenge_secret(&self) -> Option<String> {
+        self.registration
+            .as_ref()
+            .and_then(|registration| registration.challenge_secret.clone())
+    }
+    // Overrides the provider's siteverify endpoint, e.g. to point at a stub in tests
+    pub fn challenge_verify_url(&self) -> Option<String> {
+        self.registration
+            .as_ref()
+            .and_then(|registration| registration.challenge_verify_url.clone())
+    }
+
+    pub fn brute_force_enabled(&self) -> bool {
+        self.brute_force
+            .as_ref()
+            .and_then(|brute_force| brute_force.enabled)
+            .unwrap_or(false)
+    }
+    pub fn brute_force_window_seconds(&self) -> u64 {
+        self.brute_force
+            .as_ref()
+            .and_then(|brute_force| brute_force.window_seconds)
+            .unwrap_or(60)
+    }
+    // Failed logins across all accounts within the window before the service locks down
+    pub fn brute_force_max_failures(&self) -> u64 {
+        self.brute_force
+            .as_ref()
+            .and_then(|brute_force| brute_force.max_failures)
+            .unwrap_or(100)
+    }
+    pub fn brute_force_cooldown_seconds(&self) -> u64 {
+        self.brute_force
+            .as_ref()
+            .and_then(|brute_force| brute_force.cooldown_seconds)
+            .unwrap_or(300)
+    }
+    pub fn brute_force_response(&self) -> LockoutResponse {
+        self.brute_force
+            .as_ref()
+            .and_then(|brute_force| brute_force.response)
+            .unwrap_or(LockoutResponse::Cooldown)
+    }
+
+    pub fn response_cache_enabled(&self) -> bool {
+        self.response_cache
+            .as_ref()
+            .and_then(|cache| cache.enabled)
+            .unwrap_or(false)
+    }
+    // Seconds each cached GET route is kept for, routes that aren't listed are never cached
+    pub fn response_cache_routes(&self) -> HashMap<String, u64> {
+        self.response_cache
+            .as_ref()
+            .and_then(|cache| cache.routes.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn response_cache_listen_for_changes(&self) -> bool {
+        self.response_cache
+            .as_ref()
+            .and_then(|cache| cache.listen_for_changes)
+            .unwrap_or(false)
+    }
+
+    pub fn user_cache_enabled(&self) -> bool {
+        self.user_cache
+            .as_ref()
+            .and_then(|cache| cache.enabled)
+            .unwrap_or(false)
+    }
+    pub fn user_cache_redis_url(&self) -> String {
+        self.user_cache
+            .as_ref()
+            .and_then(|cache| cache.redis_url.clone())
+            .unwrap_or_else(|| "redis://localhost:6379".to_string())
+    }
+    pub fn user_cache_ttl_seconds(&self) -> u64 {
+        self.user_cache
+            .as_ref()
+            .and_then(|cache| cache.ttl_seconds)
+            .unwrap_or(60)
+    }
+    pub fn user_cache_key_prefix(&self) -> String {
+        self.user_cache
+            .as_ref()
+            .and_then(|cache| cache.key_prefix.clone())
+            .unwrap_or_else(|| "user:".to_string())
+    }
+
+    // Origins browser frontends may call the API from, none means only same-origin calls work
+    pub fn cors_allowed_origins(&self) -> Vec<String> {
+        self.cors
+            .as_ref()
+            .and_then(|cors| cors.allowed_origins.clone())
+            .unwrap_or_default()
+    }
+    // How long browsers may cache a preflight's answer, they cap it themselves, e.g. Chrome at two
+    // hours
+    pub fn cors_max_age_seconds(&self) -> u64 {
+        self.cors
+            .as_ref()
+            .and_then(|cors| cors.max_age_seconds)
+            .unwrap_or(600)
+    }
+
+    // Registrations write a user-registered event to the outbox, which the background worker relays
+    pub fn outbox_enabled(&self) -> bool {
+        self.outbox
+            .as_ref()
+            .and_then(|outbox| outbox.enabled)
+            .unwrap_or(false)
+    }
+    pub fn outbox_poll_interval_ms(&self) -> u64 {
+        self.outbox
+            .as_ref()
+            .and_then(|outbox| outbox.poll_interval_ms)
+            .unwrap_or(1000)
+    }
+    pub fn outbox_batch_size(&self) -> i64 {
+        self.outbox
+            .as_ref()
+            .and_then(|outbox| outbox.batch_size)
+            .unwrap_or(100)
+    }
+    pub fn outbox_statistics_interval_seconds(&self) -> u64 {
+        self.outbox
+            .as_ref()
+            .and_then(|outbox| outbox.statistics_interval_seconds)
+            .unwrap_or(15)
+    }
+
+    // How long a request, and any work it spawns, may take. Unset means no deadline.
+    pub fn request_timeout_ms(&self) -> Option<u64> {
+        self.request_timeout_ms
+    }
+
+    // Concurrent `GET /users/{email_address}` for the same user share one query
+    pub fn coalesce_reads(&self) -> bool {
+        self.coalesce_reads.unwrap_or(false)
+    }
+
+    // Anything that isn't explicitly local is treated as production
+    pub fn profile(&self) -> Profile {
+        self.profile.unwrap_or(Profile::Production)
+    }
+
+    // Lets `POST /dev/clock/advance` move the clock that sessions, magic links and lockouts expire by
+    pub fn time_travel_enabled(&self) -> bool {
+        self.profile() == Profile::Local
+            && self
+                .dev
+                .as_ref()
+                .and_then(|dev| dev.time_travel)
+                .unwrap_or(false)
+    }
+
+    pub fn warmup_enabled(&self) -> bool {
+        self.warmup
+            .as_ref()
+            .and_then(|warmup| warmup.enabled)
+            .unwrap_or(false)
+    }
+    // Connections each database pool opens before the API listens and keeps open afterwards
+    pub fn warmup_connections(&self) -> u32 {
+        match self.warmup_enabled() {
+            true => self
+                .warmup
+                .as_ref()
+                .and_then(|warmup| warmup.connections)
+                .unwrap_or(5),
+            false => 0,
+        }
+    }
+    pub fn warmup_hot_users(&self) -> Vec<String> {
+        self.warmup
+            .as_ref()
+            .and_then(|warmup| warmup.hot_users.clone())
+            .unwrap_or_default()
+    }
+
+    // Unset means users are read from and written to the database directly
+    pub fn buffer_mode(&self) -> Option<BufferMode> {
+        self.buffer.as_ref().and_then(|buffer| buffer.mode)
+    }
+    pub fn buffer_flush_interval_ms(&self) -> u64 {
+        self.buffer
+            .as_ref()
+            .and_then(|buffer| buffer.flush_interval_ms)
+            .unwrap_or(1000)
+    }
+    // Writes waiting to be flushed, a write that finds this many waiting flushes them itself
+    pub fn buffer_max_pending(&self) -> usize {
+        self.buffer
+            .as_ref()
+            .and_then(|buffer| buffer.max_pending)
+            .unwrap_or(1000)
+    }
+
+    // Routes such as `POST /users` or `/admin/*`, any other route answers with a 501. `None`
+    // enables every route.
+    pub fn enabled_routes(&self) -> Option<Vec<String>> {
+        self.routes.as_ref().and_then(|routes| routes.enabled.clone())
+    }
+    // Sent with the 501, e.g. which module switches the route on
+    pub fn disabled_route_hint(&self) -> Option<String> {
+        self.routes.as_ref().and_then(|routes| routes.hint.clone())
+    }
+
+    // Every write to a user is recorded, see `GET /users/{email_address}/audit`
+    pub fn audit_enabled(&self) -> bool {
+        self.audit
+            .as_ref()
+            .and_then(|audit| audit.enabled)
+            .unwrap_or(false)
+    }
+    // The in-memory store loses the trail on restart, it is only for trying the endpoint out
+    pub fn audit_store(&self) -> StoreKind {
+        self.audit
+            .as_ref()
+            .and_then(|audit| audit.store)
+            .unwrap_or(StoreKind::Postgres)
+    }
+
+    pub fn login_alerts_enabled(&self) -> bool {
+        self.login_alerts
+            .as_ref()
+            .and_then(|login_alerts| login_alerts.enabled)
+            .unwrap_or(false)
+    }
+    // Where the devices each user has logged in from are kept. With the in-memory store every
+    // user's next login after a restart counts as their first.
+    pub fn login_alerts_store(&self) -> StoreKind {
+        self.login_alerts
+            .as_ref()
+            .and_then(|login_alerts| login_alerts.store)
+            .unwrap_or(StoreKind::Memory)
+    }
+
+    pub fn avatar_store(&self) -> BlobStoreKind {
+        self.avatars
+            .as_ref()
+            .and_then(|avatars| avatars.store)
+            .unwrap_or(BlobStoreKind::Filesystem)
+    }
+    pub fn avatar_directory(&self) -> String {
+        self.avatars
+            .as_ref()
+            .and_then(|avatars| avatars.directory.clone())
+            .unwrap_or("blobs".to_string())
+    }
+    pub fn avatar_max_bytes(&self) -> usize {
+        self.avatars
+            .as_ref()
+            .and_then(|avatars| avatars.max_bytes)
+            .unwrap_or(1024 * 1024)
+    }
+    fn avatar_s3(&self) -> Option<&S3Configuration> {
+        self.avatars.as_ref().and_then(|avatars| avatars.s3.as_ref())
+    }
+    pub fn avatar_s3_endpoint(&self) -> Option<String> {
+        self.avatar_s3().and_then(|s3| s3.endpoint.clone())
+    }
+    pub fn avatar_s3_bucket(&self) -> Option<String> {
+        self.avatar_s3().and_then(|s3| s3.bucket.clone())
+    }
+    pub fn avatar_s3_region(&self) -> String {
+        self.avatar_s3()
+            .and_then(|s3| s3.region.clone())
+            .unwrap_or("us-east-1".to_string())
+    }
+    pub fn avatar_s3_access_key_id(&self) -> Option<String> {
+        self.avatar_s3().and_then(|s3| s3.access_key_id.clone())
+    }
+    pub fn avatar_s3_secret_access_key(&self) -> Option<String> {
+        self.avatar_s3().and_then(|s3| s3.secret_access_key.clone())
+    }
+
+    pub fn telemetry_id_generator(&self) -> IdGeneratorKind {
+        self.telemetry
+            .as_ref()
+            .and_then(|telemetry| telemetry.id_generator)
+            .unwrap_or_default()
+    }
+    pub fn telemetry_propagation(&self) -> PropagationFormat {
+        self.telemetry
+            .as_ref()
+            .and_then(|telemetry| telemetry.propagation)
+            .unwrap_or_default()
+    }
+    pub fn telemetry_server_timing(&self) -> bool {
+        self.telemetry
+            .as_ref()
+            .and_then(|telemetry| telemetry.server_timing)
+            .unwrap_or(false)
+    }
+
+    // The database users are being moved to, unset when no migration is under way
+    pub fn migration_connection_string(&self) -> Option<String> {
+        self.migration
+            .as_ref()
+            .map(|migration| migration.connection_string.clone())
+    }
+    pub fn migration_primary(&self) -> MigrationPrimary {
+        self.migration
+            .as_ref()
+            .and_then(|migration| migration.primary)
+            .unwrap_or_default()
+    }
+
+    pub fn dynamodb_enabled(&self) -> bool {
+        self.dynamodb
+            .as_ref()
+            .and_then(|dynamodb| dynamodb.enabled)
+            .unwrap_or(false)
+    }
+    pub fn dynamodb_table_name(&self) -> String {
+        self.dynamodb
+            .as_ref()
+            .and_then(|dynamodb| dynamodb.table_name.clone())
+            .unwrap_or_else(|| "users".to_string())
+    }
+    pub fn dynamodb_region(&self) -> Option<String> {
+        self.dynamodb
+            .as_ref()
+            .and_then(|dynamodb| dynamodb.region.clone())
+    }
+    pub fn dynamodb_endpoint_url(&self) -> Option<String> {
+        self.dynamodb
+            .as_ref()
+            .and_then(|dynamodb| dynamodb.endpoint_url.clone())
+    }
+
+    pub fn mongodb_enabled(&self) -> bool {
+        self.mongodb
+            .as_ref()
+            .and_then(|mongodb| mongodb.enabled)
+            .unwrap_or(false)
+    }
+    pub fn mongodb_connection_string(&self) -> String {
+        self.mongodb
+            .as_ref()
+            .and_then(|mongodb| mongodb.connection_string.clone())
+            .unwrap_or_else(|| "mongodb://localhost:27017".to_string())
+    }
+    pub fn mongodb_database(&self) -> String {
+        self.mongodb
+            .as_ref()
+            .and_then(|mongodb| mongodb.database.clone())
+            .unwrap_or_else(|| "users".to_string())
+    }
+    pub fn mongodb_collection(&self) -> String {
+        self.mongodb
+            .as_ref()
+            .and_then(|mongodb| mongodb.collection.clone())
+            .unwrap_or_else(|| "users".to_string())
+    }
+
+    pub fn in_memory_enabled(&self) -> bool {
+        self.in_memory
+            .as_ref()
+            .and_then(|in_memory| in_memory.enabled)
+            .unwrap_or(false)
+    }
+
     pub fn app_port(&self) -> u16 {
         self.app_port.unwrap_or(3000)
     }
@@ -1,324 +1,582 @@
-use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
-use argon2::password_hash::rand_core::OsRng;
-use argon2::password_hash::SaltString;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use thiserror::Error;
-use regex::Regex;
-use tracing::{span, Level};
-
-#[derive(Error, Debug)]
-pub enum ApplicationError {
-    #[error("user already exists")]
-    UserAlreadyExists,
-    #[error("user does not exist")]
-    UserDoesNotExist,
-    #[error("the provider password is incorrect")]
-    IncorrectPassword,
-    #[error("error interacting with database {0}")]
-    DatabaseError(String),
-    #[error("unexpected application error {0}")]
-    ApplicationError(String),
+use time::Date;
+use uuid::Uuid;
+use workshop_core::{ApplicationError, Capabilities, Metadata, Tier, User, UserDetails};
+
+use crate::connections::ConnectionStatsResponse;
+use crate::data_access::UnitOfWork;
+
+// Users read per page by the default `DataAccess::stream_all`
+const STREAM_PAGE_SIZE: i64 = 500;
+
+// A stored user alongside its version, which every update increments. Handlers expose the
+// version as the ETag so writes can be made conditional on it with `If-Match`.
+#[derive(Clone, Debug)]
+pub struct Versioned<T> {
+    pub value: T,
+    pub version: i64,
 }
 
 #[async_trait::async_trait]
 pub trait DataAccess: Send + Sync {
     async fn with_email_address(&self, email_address: &str) -> Result<User, ApplicationError>;
+    async fn list(&self, offset: i64, limit: i64) -> Result<Vec<User>, ApplicationError>;
     async fn store(&self, user: User) -> Result<(), ApplicationError>;
-}
-
-#[derive(Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct RegisterUserRequest {
-    pub email_address: String,
-    pub password: String,
-    pub name: String,
-}
-
-#[derive(Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct LoginRequest {
-    pub email_address: String,
-    pub password: String,
-}
 
-#[derive(Serialize, Clone)]
-#[serde(rename_all = "camelCase")]
-pub struct UserDetails {
-    email_address: String,
-    password: String,
-    age: Option<i32>,
-    name: String,
-}
+    // Up to `limit` users in email address order, starting after `after`, so a walk over every
+    // user reads each row once however far in it is. Stores that can't seek page through `list`.
+    async fn list_after(
+        &self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<User>, ApplicationError> {
+        let mut found = Vec::new();
+        let mut offset = 0;
+
+        while (found.len() as i64) < limit {
+            let users = self.list(offset, limit).await?;
+            let page_size = users.len() as i64;
+
+            found.extend(
+                users
+                    .into_iter()
+                    .filter(|user| after.is_none_or(|after| user.email_address().as_str() > after)),
+            );
+
+            if page_size < limit {
+                break;
+            }
+            offset += page_size;
+        }
+        found.truncate(limit.max(0) as usize);
 
-#[derive(Clone)]
-pub enum User {
-    Standard {
-        user_details: UserDetails,
-    },
-    Premium {
-        user_details: UserDetails,
-        is_premium: bool,
-    },
-}
+        Ok(found)
+    }
 
-impl User {
-    // no 'self' at all defines a static method. Called using User::new()
-    pub fn new(email_address: &str, name: &str, password: &str) -> Result<User, ApplicationError> {
-        let span = span!(Level::INFO, "user.new", "user.type" = "standard");
-        let _entered = span.enter();
-        
-        User::email_is_valid(email_address)?;
-        User::password_is_valid(password)?;
-        
-        Ok(User::Standard {
-            user_details: UserDetails {
-                email_address: email_address.to_string(),
-                name: name.to_string(),
-                age: None,
-                password: User::hash(password)?,
-            },
+    // Every live user in email address order, read as the stream is polled so exports and reports
+    // never hold more than a page of them. Stores that can't stream walk `list_after` a page at a
+    // time, the stream ends after the first error.
+    fn stream_all(&self) -> BoxStream<'_, Result<User, ApplicationError>> {
+        futures::stream::try_unfold(Some(None::<String>), move |cursor| async move {
+            let Some(after) = cursor else {
+                return Ok::<_, ApplicationError>(None);
+            };
+            let users = self.list_after(after.as_deref(), STREAM_PAGE_SIZE).await?;
+            let next = match (users.len() as i64) < STREAM_PAGE_SIZE {
+                true => None,
+                false => users.last().map(|user| Some(user.email_address())),
+            };
+
+            Ok(Some((futures::stream::iter(users.into_iter().map(Ok)), next)))
         })
+        .try_flatten()
+        .boxed()
     }
 
-    pub fn from(email_address: &str, name: &str, hashed_password: &str) -> User {
-        User::Standard {
-            user_details: UserDetails {
-                email_address: email_address.to_string(),
-                name: name.to_string(),
-                age: None,
-                password: hashed_password.to_string(),
-            },
-        }
+    // As `list`, with the users who were soft deleted among them, each alongside when they were
+    // and `None` for live users. For admins finding an account to remove for good.
+    async fn list_including_deleted(
+        &self,
+        _offset: i64,
+        _limit: i64,
+    ) -> Result<Vec<(User, Option<u64>)>, ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "listing deleted users is not supported".to_string(),
+        ))
     }
 
-    fn hash(password: &str) -> Result<String, ApplicationError> {
-        let argon2 = Argon2::default();
-        let salt = SaltString::generate(&mut OsRng);
-        let hash = argon2.hash_password(password.as_bytes(), &salt)
-            .map_err(|_| ApplicationError::ApplicationError("Failed to hash password".to_string()))?;
-
-        Ok(hash.to_string())
-    }
-    
-    pub fn details(&self) -> &UserDetails {
-        match self {
-            User::Standard { user_details } => user_details,
-            User::Premium {
-                user_details,
-                is_premium: _,
-            } => user_details,
-        }
+    // Up to `limit` live users whose name or email address match every word of `query`, best
+    // match first, each alongside a rank that is only comparable to the others of the same search
+    async fn search_ranked(
+        &self,
+        _query: &str,
+        _offset: i64,
+        _limit: i64,
+    ) -> Result<Vec<(User, f32)>, ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "searching users is not supported".to_string(),
+        ))
     }
-    
-    pub fn email_address(&self) -> String {
-        match self {
-            User::Standard { user_details } => user_details.email_address.clone(),
-            User::Premium {
-                user_details,
-                is_premium: _,
-            } => user_details.email_address.clone(),
-        }
+
+    // How many live users there are, without reading them
+    async fn count(&self) -> Result<i64, ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "counting users is not supported".to_string(),
+        ))
     }
-    
-    pub fn name(&self) -> String {
-        match self {
-            User::Standard { user_details } => user_details.name.clone(),
-            User::Premium {
-                user_details,
-                is_premium: _,
-            } => user_details.name.clone(),
+
+    // Whether a live user has the address, without reading them where the store can. An address
+    // that is only taken by a soft deleted user or as an alias reads as free, `store` still
+    // rejects it.
+    async fn exists(&self, email_address: &str) -> Result<bool, ApplicationError> {
+        match self.with_email_address(email_address).await {
+            Ok(_) => Ok(true),
+            Err(ApplicationError::UserDoesNotExist) => Ok(false),
+            Err(e) => Err(e),
         }
     }
-    
-    pub fn password(&self) -> String {
-        match self {
-            User::Standard { user_details } => user_details.password.clone(),
-            User::Premium {
-                user_details,
-                is_premium: _,
-            } => user_details.password.clone(),
-        }
+
+    // Stores every user or none of them, failing with `UserAlreadyExists` when any of the addresses
+    // is taken or given twice. One round trip where the store can, for seeding and registering
+    // users in batches.
+    async fn store_many(&self, _users: Vec<User>) -> Result<(), ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "bulk stores are not supported".to_string(),
+        ))
     }
 
-    // &mut self is used because you want to mutate the data in this instance of the struct
-    #[allow(dead_code)]
-    fn update_name(&mut self, new_name: &str) {
-        let user_details = match self {
-            // The '*' is used to dereference the value of the variable, so you can change it.
-            // De-referncing refers to accessing the underlying value the reference points to
-            User::Standard { user_details } => user_details,
-            User::Premium {
-                user_details,
-                is_premium: _,
-            } => user_details,
-        };
+    // Opens `connections` database connections up front, stores without a pool have nothing to do
+    async fn warm_up(&self, _connections: u32) -> Result<(), ApplicationError> {
+        Ok(())
+    }
 
-        user_details.name = new_name.to_string();
+    // Fails when the store can't be reached, `GET /ready` answers 503 until it can. Stores that
+    // live in the process are always reachable.
+    async fn health_check(&self) -> Result<(), ApplicationError> {
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    fn update_age(&mut self, new_age: i32) {
-        let user_details = match self {
-            // The '*' is used to dereference the value of the variable, so you can change it.
-            // De-referncing refers to accessing the underlying value the reference points to
-            User::Standard { user_details } => user_details,
-            User::Premium {
-                user_details,
-                is_premium: _,
-            } => user_details,
-        };
+    // Groups writes so they commit or roll back together. `None` where the store can't, e.g. a
+    // sharded store whose writes land in different databases, callers then make the writes one by
+    // one. Wrappers that keep something alongside the store, such as the buffer, leave it `None`
+    // too, writes through the unit of work would go around them.
+    async fn begin(&self) -> Result<Option<Box<dyn UnitOfWork>>, ApplicationError> {
+        Ok(None)
+    }
 
-        user_details.age = Some(new_age);
+    // Versioned reads and writes are only needed by the update and delete endpoints, test doubles
+    // that don't exercise them can leave the defaults.
+    async fn with_id(&self, _id: Uuid) -> Result<User, ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "lookups by id are not supported".to_string(),
+        ))
+    }
+    async fn with_email_address_versioned(
+        &self,
+        _email_address: &str,
+    ) -> Result<Versioned<User>, ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "versioned reads are not supported".to_string(),
+        ))
+    }
+    // Returns the new version, or `VersionMismatch` if the stored version isn't `expected_version`.
+    // `None` writes whatever the current version is.
+    async fn update(
+        &self,
+        _user: User,
+        _expected_version: Option<i64>,
+    ) -> Result<i64, ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "updates are not supported".to_string(),
+        ))
+    }
+    // Stores the user, or overwrites the live user with their email address, and returns the
+    // version written. Writing the same details again changes nothing, not even the version, so a
+    // snapshot of a user can be applied as often as it is delivered. The stored user keeps their
+    // id, everything else including the username is the snapshot's. A soft deleted user's address
+    // is taken, as it is for `store`, and no event is written to the outbox. Nothing applies
+    // snapshots yet, the worker's events don't carry the password a user is stored with.
+    async fn upsert(&self, _user: User) -> Result<i64, ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "upserts are not supported".to_string(),
+        ))
+    }
+    // Marks the user as deleted, reads skip them afterwards but the email address stays taken
+    // until the row is removed with `delete`
+    async fn soft_delete(
+        &self,
+        _email_address: &str,
+        _expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "deletes are not supported".to_string(),
+        ))
+    }
+    async fn delete(
+        &self,
+        _email_address: &str,
+        _expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "deletes are not supported".to_string(),
+        ))
+    }
+    // Replaces everything that identifies the user, live or soft deleted, with placeholders and
+    // records the erasure. The row and its id are kept so whatever refers to the user stays valid,
+    // and it reads as deleted. `requested_by` is whoever erased someone else's account, `None`
+    // when users erase their own. Returns the erased user's id.
+    async fn erase(
+        &self,
+        _email_address: &str,
+        _requested_by: Option<&str>,
+    ) -> Result<Uuid, ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "erasure is not supported".to_string(),
+        ))
+    }
+    // Folds the live user at `merged` into the live user at `kept` as a whole or not at all. The
+    // kept user gains the other's verified addresses, their primary one among them, whatever
+    // details they have and the kept user doesn't, and their preferences if the kept user never
+    // changed theirs. The merged user is soft deleted. `requested_by` is the admin who merged them.
+    async fn merge(
+        &self,
+        _kept: &str,
+        _merged: &str,
+        _requested_by: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "merging users is not supported".to_string(),
+        ))
+    }
+    // Kept apart from the user, changing them doesn't change the user's version
+    async fn preferences(&self, _email_address: &str) -> Result<UserPreferences, ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "preferences are not supported".to_string(),
+        ))
+    }
+    async fn update_preferences(
+        &self,
+        _email_address: &str,
+        _preferences: &UserPreferences,
+    ) -> Result<(), ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "preferences are not supported".to_string(),
+        ))
+    }
+    // The last successful login, also kept apart from the user. A login isn't an edit, so it
+    // doesn't change the user's version either.
+    async fn record_login(
+        &self,
+        _email_address: &str,
+        _logged_in_at: u64,
+        _ip: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "last logins are not supported".to_string(),
+        ))
+    }
+    async fn last_login(
+        &self,
+        _email_address: &str,
+    ) -> Result<Option<LastLogin>, ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "last logins are not supported".to_string(),
+        ))
     }
 
-    // Using just 'self' is a rare case where you want to take ownership of the original instance and use something new
-    // calling this function will prevent the original instance from being used, as this function
-    // takes ownership and then drop the original instance
-    #[allow(dead_code)]
-    fn update_to_premium(self) -> User {
-        match self {
-            User::Standard { user_details } => User::Premium {
-                user_details,
-                is_premium: true,
-            },
-            User::Premium { .. } => self,
-        }
+    // Secondary addresses of a user. Anyone may add an address, only one user can have verified
+    // it and only then does it find them. `token_hash` is the SHA-256 of the token in the link sent
+    // to the address, the token itself is never stored.
+    async fn email_aliases(&self, _email_address: &str) -> Result<Vec<EmailAlias>, ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "email aliases are not supported".to_string(),
+        ))
+    }
+    // Adding an alias the user hasn't verified yet replaces its token, so the link can be resent
+    async fn add_email_alias(
+        &self,
+        _email_address: &str,
+        _alias: &str,
+        _token_hash: &str,
+    ) -> Result<(), ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "email aliases are not supported".to_string(),
+        ))
+    }
+    // Verifies the unverified alias with `token_hash` if it was added at or after `added_after`,
+    // the token can't be used again. Returns the alias, `None` when there is no such alias.
+    async fn verify_email_alias(
+        &self,
+        _token_hash: &str,
+        _added_after: u64,
+    ) -> Result<Option<String>, ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "email aliases are not supported".to_string(),
+        ))
+    }
+    async fn remove_email_alias(
+        &self,
+        _email_address: &str,
+        _alias: &str,
+    ) -> Result<(), ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "email aliases are not supported".to_string(),
+        ))
+    }
+    // Swaps a verified alias with the primary address, which stays on as a verified alias. Bumps
+    // the user's version, as its email address changes.
+    async fn set_primary_email_address(
+        &self,
+        _email_address: &str,
+        _alias: &str,
+    ) -> Result<(), ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "email aliases are not supported".to_string(),
+        ))
+    }
+    // The primary address of the user `alias` is a verified alias of, stores without aliases
+    // have none to resolve
+    async fn resolve_email_alias(&self, _alias: &str) -> Result<Option<String>, ApplicationError> {
+        Ok(None)
     }
 
-    pub fn verify_password(&self, password: &str) -> Result<(), ApplicationError> {
-        let users_password = &self.password().clone();
-        
-        let parsed_hash = PasswordHash::new(users_password).map_err(|_| ApplicationError::ApplicationError("Failed to parse password hash".to_string()))?;
-        
-        let verified_password = Argon2::default()
-            .verify_password(password.as_bytes(), &parsed_hash);
-        
-        match verified_password {
-            Ok(_) => Ok(()),
-            Err(_) => Err(ApplicationError::IncorrectPassword)
-        } 
-    }
-
-    fn password_is_valid(password: &str) -> Result<(), ApplicationError> {
-        if password.len() < 8 {
-            tracing::Span::current().record("user.password_is_valid", "false");
-            return Err(ApplicationError::ApplicationError("Password must be at least 8 characters long".to_string()));
-        }
-        if !password.chars().any(|c| c.is_uppercase()) {
-            tracing::Span::current().record("user.password_is_valid", "false");
-            return Err(ApplicationError::ApplicationError("Password must contain at least one uppercase letter".to_string()));
-        }
-        if !password.chars().any(|c| c.is_lowercase()) {
-            tracing::Span::current().record("user.password_is_valid", "false");
-            return Err(ApplicationError::ApplicationError("Password must contain at least one lowercase letter".to_string()));
-        }
-        if !password.chars().any(|c| c.is_ascii_digit()) {
-            tracing::Span::current().record("user.password_is_valid", "false");
-            return Err(ApplicationError::ApplicationError("Password must contain at least one digit".to_string()));
-        }
-        
-        tracing::Span::current().record("user.password_is_valid", "true");
-        
-        Ok(())
+    // Usernames are written on their own rather than by `update`, so a write from a stale read
+    // can't drop one. `None` frees the user's username. Fails with `UsernameTaken` when another
+    // user, live or soft deleted, has it, and bumps the user's version.
+    async fn set_username(
+        &self,
+        _email_address: &str,
+        _username: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        Err(ApplicationError::ApplicationError(
+            "usernames are not supported".to_string(),
+        ))
     }
-    
-    fn email_is_valid(input: &str) -> Result<(), ApplicationError> {
-        let re = Regex::new(r"^[a-zA-Z0-9._%+-]+@[a-zA-Z0-9.-]+\.[a-zA-Z]{2,}$").unwrap();
-        if re.is_match(input) {
-            tracing::Span::current().record("user.email_is_valid", "true");
-            Ok(())
-        } else {
-            tracing::Span::current().record("user.email_is_valid", "false");
-            Err(ApplicationError::ApplicationError("Invalid email address".to_string()))
+    // The email address of the live user with `username`, which is given in lower case
+    async fn resolve_username(&self, _username: &str) -> Result<Option<String>, ApplicationError> {
+        Ok(None)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    // Follow the operating system
+    #[default]
+    System,
+    Light,
+    Dark,
+}
+
+// Stored as a JSON document alongside the user. Fields missing from it, e.g. in a document written
+// before they were added, take their defaults.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct UserPreferences {
+    pub locale: String,
+    pub marketing_opt_in: bool,
+    pub theme: Theme,
+}
+
+impl Default for UserPreferences {
+    fn default() -> Self {
+        Self {
+            locale: "en".to_string(),
+            marketing_opt_in: false,
+            theme: Theme::default(),
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn when_new_user_is_created_should_be_standard() {
-        let user = User::new("test@test.com", "James", "James!23").unwrap();
-        
-        if let User::Standard { user_details } = user {
-            assert_eq!(user_details.email_address, "test@test.com");
-            assert_eq!(user_details.name, "James");
-        } else {
-            panic!("Expected User::Standard variant");
+impl UserPreferences {
+    // When two users are merged, the kept user's, unless they never changed them from the defaults
+    pub fn merged_with(self, merged: UserPreferences) -> Self {
+        match self == UserPreferences::default() {
+            true => merged,
+            false => self,
         }
     }
 
-    #[test]
-    fn when_user_is_updated_to_premium_should_be_premium_user() {
-        let user = User::new("test@test.com", "James", "James!23").unwrap();
-        
-        let premium_user = user.update_to_premium();
+    pub fn is_valid(&self) -> bool {
+        UserPreferences::is_language_tag(&self.locale)
+    }
 
-        if let User::Premium { user_details, is_premium } = premium_user {
-            assert_eq!(user_details.email_address, "test@test.com");
-            assert_eq!(user_details.name, "James");
-        } else {
-            panic!("Expected User::Standard variant");
-        }
+    // Shaped like a BCP 47 language tag, e.g. `en` or `pt-BR`
+    pub fn is_language_tag(tag: &str) -> bool {
+        tag.len() <= 35
+            && tag.split('-').all(|subtag| {
+                (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+            })
     }
+}
 
-    #[test]
-    fn when_a_user_is_created_should_be_able_to_update_age() {
-        let mut user = User::new("test@test.com", "James", "James!23").unwrap();
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateUserRequest {
+    pub name: String,
+}
+
+// Only the fields that are present are changed, an empty phone number removes it and metadata
+// is merged key by key. The age comes from the date of birth, which is set with
+// `PUT /users/{email_address}/birthday`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PatchUserRequest {
+    pub name: Option<String>,
+    pub phone_number: Option<String>,
+    pub metadata: Option<Metadata>,
+}
 
-        assert_eq!(user.details().age, None);
-        
-        user.update_age(10);
+impl PatchUserRequest {
+    pub fn is_valid(&self) -> bool {
+        let name_is_valid = self.name.as_ref().is_none_or(|name| !name.trim().is_empty());
 
-        assert_eq!(user.details().age.unwrap(), 10);
+        name_is_valid
+            && (self.name.is_some() || self.phone_number.is_some() || self.metadata.is_some())
     }
+}
 
-    #[test]
-    fn when_a_user_is_created_should_be_able_to_update_name() {
-        let mut user = User::new("test@test.com", "James", "James!23").unwrap();
+// `YYYY-MM-DD`, or `null` to forget it
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateBirthdayRequest {
+    pub date_of_birth: Option<Date>,
+}
 
-        assert_eq!(user.details().name, "James");
-        
-        user.update_name("John");
+// `null` frees the user's username for someone else to take
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateUsernameRequest {
+    pub username: Option<String>,
+}
 
-        assert_eq!(user.details().name, "John");
-    }
+// The tier to move the user to. A downgrade without one makes them Standard.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeTierRequest {
+    #[serde(default)]
+    pub tier: Option<Tier>,
+}
 
-    #[test]
-    fn when_user_is_created_with_an_invalid_email_should_return_error() {
-        let user = User::new("thisisaninvalidemail", "James", "James!23");
+// The user's tier as `GET /users/{email_address}/tier` and the upgrade and downgrade answer with
+// it, `null` for a Standard user
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TierDetails {
+    pub tier: Option<Tier>,
+    pub capabilities: Capabilities,
+}
 
-        assert!(user.is_err());
+impl From<&User> for TierDetails {
+    fn from(user: &User) -> Self {
+        Self {
+            tier: user.tier(),
+            capabilities: user.capabilities(),
+        }
     }
+}
+
+// When and from where the user last logged in, `None` until they first do
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LastLogin {
+    pub last_login_at: u64,
+    pub last_login_ip: Option<String>,
+}
 
-    #[test]
-    fn when_user_is_created_with_an_invalid_password_should_return_error() {
-        let user = User::new("test@test.com", "James", "james");
+// `GET /users/{email_address}` as the user or an admin reads it, with when and where they last
+// logged in, both null until they first do
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManagedUserDetails {
+    #[serde(flatten)]
+    pub details: UserDetails,
+    pub last_login_at: Option<u64>,
+    pub last_login_ip: Option<String>,
+}
 
-        assert!(user.is_err());
-    }
+impl ManagedUserDetails {
+    pub fn new(details: UserDetails, last_login: Option<LastLogin>) -> Self {
+        let (last_login_at, last_login_ip) = match last_login {
+            Some(last_login) => (Some(last_login.last_login_at), last_login.last_login_ip),
+            None => (None, None),
+        };
 
-    #[test]
-    fn when_user_is_created_should_verify_a_matching_password() {
-        let user = User::new("test@test.com", "James", "James!23").unwrap();
-        
-        assert_ne!(user.password(), "Test!23");
-        
-        let is_password_valid = user.verify_password("James!23");
-        
-        assert!(is_password_valid.is_ok());
+        Self {
+            details,
+            last_login_at,
+            last_login_ip,
+        }
     }
+}
 
-    #[test]
-    fn when_user_is_created_should_fail_if_password_does_not_match() {
-        let user = User::new("test@test.com", "James", "James!23").unwrap();
+// A user as `GET /users?includeDeleted=true` lists them, `deletedAt` is null for live users
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListedUserDetails {
+    #[serde(flatten)]
+    pub details: UserDetails,
+    pub deleted_at: Option<u64>,
+}
 
-        assert_ne!(user.password(), "Test!23");
+// The query string of `GET /users/search` besides its paging, a missing `q` is rejected by the
+// handler with the error envelope rather than by the extractor
+#[derive(Deserialize)]
+pub struct SearchUsersQuery {
+    pub q: Option<String>,
+}
 
-        let is_password_valid = user.verify_password("This is the wrong password");
+// A user as `GET /users/search` finds them, `rank` orders them within the one search
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchedUserDetails {
+    #[serde(flatten)]
+    pub details: UserDetails,
+    pub rank: f32,
+}
 
-        assert!(is_password_valid.is_err());
-    }
+// A session as listed by `GET /users/{email_address}/sessions`, the id is what's passed to
+// `DELETE /users/{email_address}/sessions/{session_id}` to revoke it
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionDetails {
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub last_seen_ip: Option<String>,
+    pub created_at: u64,
+    pub last_seen_at: u64,
+    pub expires_at: u64,
+    pub impersonated_by: Option<String>,
+    // Whether this is the session the listing was requested with
+    pub current: bool,
+}
+
+// An address listed by `GET /users/{email_address}/emails`, besides the primary one
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailAlias {
+    pub email_address: String,
+    pub created_at: u64,
+    pub verified_at: Option<u64>,
+}
+
+// `POST /admin/users/merge`, the user at `mergeEmailAddress` is folded into the one at
+// `keepEmailAddress`, whose address stays the primary one
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MergeUsersRequest {
+    pub keep_email_address: String,
+    pub merge_email_address: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AddEmailAliasRequest {
+    pub email_address: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MagicLinkRequest {
+    pub email_address: String,
+}
+
+// `GET /admin/stats`, the API's connections alongside how many live users there are
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminStatsResponse {
+    #[serde(flatten)]
+    pub connections: ConnectionStatsResponse,
+    pub users: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WeakPasswordResponse {
+    pub message: String,
+    pub score: u8,
+    pub minimum_score: u8,
+    pub warning: Option<String>,
+    pub suggestions: Vec<String>,
 }
@@ -1,5 +1,20 @@
+#[allow(clippy::module_inception)]
 mod core;
 mod configuration;
+pub mod parsing;
 
-pub use configuration::Config;
-pub use core::{ApplicationError, DataAccess, LoginRequest, RegisterUserRequest, User, UserDetails,};
\ No newline at end of file
+pub use configuration::{
+    AuthMode, BlobStoreKind, BufferMode, ChallengeProvider, Config, IdGeneratorKind, LockoutResponse,
+    MigrationPrimary, Profile, PropagationFormat, StoreKind,
+};
+pub use core::{
+    AddEmailAliasRequest, AdminStatsResponse, ChangeTierRequest, DataAccess, EmailAlias, LastLogin,
+    ListedUserDetails, MagicLinkRequest, ManagedUserDetails, MergeUsersRequest, PatchUserRequest,
+    SearchUsersQuery, SearchedUserDetails, SessionDetails, Theme,
+    TierDetails, UpdateBirthdayRequest, UpdateUserRequest, UpdateUsernameRequest, UserPreferences,
+    Versioned, WeakPasswordResponse,
+};
+pub use workshop_core::{
+    ApplicationError, FieldViolation, LoginRequest, Metadata, RegisterUserRequest, Role, Tier, User,
+    UserBuilder, UserDetails, MINIMUM_PASSWORD_SCORE,
+};
@@ -0,0 +1,141 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer};
+use workshop_core::ApplicationError;
+
+// What went wrong reading a value from config, the environment or the command line, naming the
+// value so a startup failure says which setting to fix
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    NotANumber { value: String },
+    OutOfRange { value: String, min: u64, max: u64 },
+    UnknownUnit { value: String },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::NotANumber { value } => write!(f, "{:?} isn't a number", value),
+            ParseError::OutOfRange { value, min, max } => {
+                write!(f, "{:?} must be between {} and {}", value, min, max)
+            }
+            ParseError::UnknownUnit { value } => write!(
+                f,
+                "{:?} needs a unit of ms, s, m or h, e.g. 500ms or 30s",
+                value
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<ParseError> for ApplicationError {
+    fn from(e: ParseError) -> Self {
+        ApplicationError::ApplicationError(e.to_string())
+    }
+}
+
+fn number(value: &str) -> Result<u64, ParseError> {
+    value.trim().parse().map_err(|_| ParseError::NotANumber {
+        value: value.to_string(),
+    })
+}
+
+fn in_range(value: &str, min: u64, max: u64) -> Result<u64, ParseError> {
+    let number = number(value)?;
+    match (min..=max).contains(&number) {
+        true => Ok(number),
+        false => Err(ParseError::OutOfRange {
+            value: value.to_string(),
+            min,
+            max,
+        }),
+    }
+}
+
+// A port to listen on. 0 would have the OS pick one, which nothing could then be pointed at.
+pub fn port(value: &str) -> Result<u16, ParseError> {
+    in_range(value, 1, u16::MAX as u64).map(|port| port as u16)
+}
+
+// A whole number with its unit, e.g. `500ms`, `30s`, `5m` or `2h`. A bare number is refused rather
+// than guessed at, milliseconds and seconds are both used across the config.
+pub fn duration(value: &str) -> Result<Duration, ParseError> {
+    let trimmed = value.trim();
+    let split = trimmed
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| ParseError::UnknownUnit {
+            value: value.to_string(),
+        })?;
+    let (amount, unit) = trimmed.split_at(split);
+    let amount = number(amount).map_err(|_| ParseError::NotANumber {
+        value: value.to_string(),
+    })?;
+
+    match unit.trim() {
+        "ms" => Ok(Duration::from_millis(amount)),
+        "s" => Ok(Duration::from_secs(amount)),
+        "m" => Ok(Duration::from_secs(amount.saturating_mul(60))),
+        "h" => Ok(Duration::from_secs(amount.saturating_mul(60 * 60))),
+        _ => Err(ParseError::UnknownUnit {
+            value: value.to_string(),
+        }),
+    }
+}
+
+// For a port in config, which the environment provides as a string and a JSON file as a number
+pub fn deserialize_port<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u16>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Number(u64),
+        Text(String),
+    }
+
+    let value = match Option::<Raw>::deserialize(deserializer)? {
+        Some(Raw::Number(number)) => number.to_string(),
+        Some(Raw::Text(text)) => text,
+        None => return Ok(None),
+    };
+    port(&value).map(Some).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ports_and_durations_should_parse_or_say_what_is_wrong() {
+        assert_eq!(port("3000"), Ok(3000));
+        assert!(matches!(port("0"), Err(ParseError::OutOfRange { .. })));
+        assert!(matches!(port("70000"), Err(ParseError::OutOfRange { .. })));
+        assert!(matches!(port("http"), Err(ParseError::NotANumber { .. })));
+        assert!(matches!(port("-1"), Err(ParseError::NotANumber { .. })));
+
+        assert_eq!(duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(duration(" 30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(duration("2h"), Ok(Duration::from_secs(7200)));
+        assert!(matches!(duration("1500"), Err(ParseError::UnknownUnit { .. })));
+        assert!(matches!(duration("5 days"), Err(ParseError::UnknownUnit { .. })));
+        assert!(matches!(duration("ms"), Err(ParseError::NotANumber { .. })));
+    }
+
+    #[test]
+    fn a_port_in_config_should_be_read_from_a_string_or_a_number() {
+        #[derive(Deserialize)]
+        struct Config {
+            #[serde(default, deserialize_with = "deserialize_port")]
+            app_port: Option<u16>,
+        }
+        let app_port = |json: &str| serde_json::from_str::<Config>(json).map(|config| config.app_port);
+
+        assert_eq!(app_port(r#"{"app_port": "8080"}"#).unwrap(), Some(8080));
+        assert_eq!(app_port(r#"{"app_port": 8080}"#).unwrap(), Some(8080));
+        assert_eq!(app_port("{}").unwrap(), None);
+        assert!(app_port(r#"{"app_port": 0}"#).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::core::{ApplicationError, Config};
+use crate::metrics::{Metrics, HTTP_CORS_REJECTED_TOTAL};
+use crate::AppState;
+
+const ALLOWED_METHODS: &str = "GET, POST, PUT, PATCH, DELETE";
+// Headers handlers set that a frontend needs to read, e.g. the ETag to send back in `If-Match`
+const EXPOSED_HEADERS: &str = "etag, location, server-timing";
+// Rejected origins beyond this many are counted as `other`, so a client making up origins can't
+// grow the counter without bound
+const MAX_REJECTED_ORIGINS: usize = 100;
+const OTHER_ORIGIN: &str = "other";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum OriginRule {
+    Exact(String),
+    // `https://*.example.com` keeps the scheme and port, and the `.example.com` any subdomain of
+    // it has to end with. The bare domain isn't matched, list it as well if it is a frontend too.
+    Subdomains { scheme: String, suffix: String, port: Option<String> },
+}
+
+// Splits `https://app.example.com:8443` into its scheme, host and port, lowercased. Anything with a
+// path, or that isn't `scheme://host`, isn't an origin.
+fn parse_origin(origin: &str) -> Option<(String, String, Option<String>)> {
+    let (scheme, rest) = origin.split_once("://")?;
+    if scheme.is_empty() || rest.is_empty() || rest.contains('/') {
+        return None;
+    }
+    let (host, port) = match rest.rsplit_once(':') {
+        Some((host, port)) if !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()) => {
+            (host, Some(port.to_string()))
+        }
+        Some(_) => return None,
+        None => (rest, None),
+    };
+    if host.is_empty() {
+        return None;
+    }
+
+    Some((scheme.to_ascii_lowercase(), host.to_ascii_lowercase(), port))
+}
+
+impl OriginRule {
+    fn parse(rule: &str) -> Result<Self, ApplicationError> {
+        let invalid = || ApplicationError::ApplicationError(format!("invalid CORS origin {}", rule));
+        let (scheme, host, port) = parse_origin(rule).ok_or_else(invalid)?;
+
+        match host.strip_prefix("*.") {
+            Some(domain) if !domain.is_empty() && !domain.contains('*') => Ok(OriginRule::Subdomains {
+                scheme,
+                suffix: format!(".{}", domain),
+                port,
+            }),
+            Some(_) => Err(invalid()),
+            None if host.contains('*') => Err(invalid()),
+            None => Ok(OriginRule::Exact(match &port {
+                Some(port) => format!("{}://{}:{}", scheme, host, port),
+                None => format!("{}://{}", scheme, host),
+            })),
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        let Some((origin_scheme, origin_host, origin_port)) = parse_origin(origin) else {
+            return false;
+        };
+
+        match self {
+            OriginRule::Exact(allowed) => {
+                let normalised = match &origin_port {
+                    Some(port) => format!("{}://{}:{}", origin_scheme, origin_host, port),
+                    None => format!("{}://{}", origin_scheme, origin_host),
+                };
+                *allowed == normalised
+            }
+            OriginRule::Subdomains { scheme, suffix, port } => {
+                *scheme == origin_scheme
+                    && *port == origin_port
+                    && origin_host.len() > suffix.len()
+                    && origin_host.ends_with(suffix.as_str())
+            }
+        }
+    }
+}
+
+// The origins browsers may call the API from. Sessions are cookies, so allowed origins are sent
+// credentials and are always echoed back, never answered with `*`.
+pub struct CorsPolicy {
+    rules: Vec<OriginRule>,
+    max_age: HeaderValue,
+    rejected_origins: Mutex<HashSet<String>>,
+}
+
+impl CorsPolicy {
+    pub fn new(origins: &[String], max_age_seconds: u64) -> Result<Self, ApplicationError> {
+        Ok(Self {
+            rules: origins
+                .iter()
+                .map(|origin| OriginRule::parse(origin))
+                .collect::<Result<_, _>>()?,
+            max_age: HeaderValue::from(max_age_seconds),
+            rejected_origins: Mutex::new(HashSet::new()),
+        })
+    }
+
+    // Without allowed origins there is no CORS handling, browsers only call the API same-origin
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ApplicationError> {
+        let origins = config.cors_allowed_origins();
+        if origins.is_empty() {
+            return Ok(None);
+        }
+
+        Self::new(&origins, config.cors_max_age_seconds()).map(Some)
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.rules.iter().any(|rule| rule.matches(origin))
+    }
+
+    fn reject(&self, metrics: &Metrics, origin: &str, kind: &str) {
+        let label = {
+            let mut rejected = self.rejected_origins.lock().unwrap();
+            if rejected.contains(origin) || rejected.len() < MAX_REJECTED_ORIGINS {
+                rejected.insert(origin.to_string());
+                origin.to_string()
+            } else {
+                OTHER_ORIGIN.to_string()
+            }
+        };
+        log::warn!("Rejected CORS {} from origin {}", kind, origin);
+        metrics.increment_with_labels(HTTP_CORS_REJECTED_TOTAL, &[("origin", &label), ("kind", kind)]);
+    }
+}
+
+fn is_preflight(request: &Request) -> bool {
+    request.method() == Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+fn allow_origin(headers: &mut HeaderMap, origin: HeaderValue) {
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+        HeaderValue::from_static("true"),
+    );
+    // The response depends on the origin, a shared cache mustn't serve it to another one
+    headers.append(header::VARY, HeaderValue::from_static("origin"));
+}
+
+// Answers preflights for allowed origins, letting the browser cache the answer for the configured
+// max age, and adds the CORS headers to their other requests. Requests from other origins get no
+// CORS headers, so the browser doesn't let the frontend read the response, and are counted in
+// `http_cors_rejected_total` by origin. Requests without an `Origin` aren't from a browser frontend
+// on another origin and pass through.
+pub async fn handle_cors(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(policy) = &state.cors else {
+        return next.run(request).await;
+    };
+    let Some(origin) = request.headers().get(header::ORIGIN).cloned() else {
+        return next.run(request).await;
+    };
+    let preflight = is_preflight(&request);
+    let allowed = origin.to_str().is_ok_and(|origin| policy.allows(origin));
+
+    if !allowed {
+        policy.reject(
+            &state.metrics,
+            &String::from_utf8_lossy(origin.as_bytes()),
+            if preflight { "preflight" } else { "request" },
+        );
+        return match preflight {
+            true => StatusCode::FORBIDDEN.into_response(),
+            false => next.run(request).await,
+        };
+    }
+
+    if preflight {
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        let headers = response.headers_mut();
+        allow_origin(headers, origin);
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static(ALLOWED_METHODS),
+        );
+        // Whatever the frontend wants to send, e.g. `content-type` or `if-match`, the handlers
+        // decide what they accept
+        if let Some(requested) = request.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+        }
+        headers.insert(header::ACCESS_CONTROL_MAX_AGE, policy.max_age.clone());
+        headers.append(
+            header::VARY,
+            HeaderValue::from_static("access-control-request-method, access-control-request-headers"),
+        );
+        return response;
+    }
+
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    allow_origin(headers, origin);
+    headers.insert(
+        header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static(EXPOSED_HEADERS),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(origins: &[&str]) -> CorsPolicy {
+        let origins: Vec<String> = origins.iter().map(|origin| origin.to_string()).collect();
+        CorsPolicy::new(&origins, 600).unwrap()
+    }
+
+    #[test]
+    fn exact_origins_should_only_match_the_same_scheme_host_and_port() {
+        let policy = policy(&["https://app.example.com", "http://localhost:5173"]);
+
+        assert!(policy.allows("https://app.example.com"));
+        assert!(policy.allows("https://APP.example.com"));
+        assert!(policy.allows("http://localhost:5173"));
+
+        assert!(!policy.allows("http://app.example.com"));
+        assert!(!policy.allows("https://app.example.com:8443"));
+        assert!(!policy.allows("http://localhost:3000"));
+        assert!(!policy.allows("https://app.example.com.evil.test"));
+        assert!(!policy.allows("null"));
+    }
+
+    #[test]
+    fn a_wildcard_should_match_any_subdomain_but_not_the_domain_itself() {
+        let policy = policy(&["https://*.example.com"]);
+
+        assert!(policy.allows("https://app.example.com"));
+        assert!(policy.allows("https://preview.app.example.com"));
+
+        assert!(!policy.allows("https://example.com"));
+        assert!(!policy.allows("https://evilexample.com"));
+        assert!(!policy.allows("http://app.example.com"));
+        assert!(!policy.allows("https://app.example.com:8443"));
+    }
+
+    #[test]
+    fn malformed_rules_should_be_rejected() {
+        for rule in ["example.com", "https://*", "https://app.*.com", "https://example.com/app", "*"] {
+            assert!(
+                CorsPolicy::new(&[rule.to_string()], 600).is_err(),
+                "{} should be rejected",
+                rule
+            );
+        }
+    }
+
+    #[test]
+    fn rejected_origins_past_the_limit_should_be_counted_as_other() {
+        let policy = policy(&["https://app.example.com"]);
+        let metrics = Metrics::default();
+
+        for i in 0..MAX_REJECTED_ORIGINS + 5 {
+            policy.reject(&metrics, &format!("https://{}.evil.test", i), "request");
+        }
+        policy.reject(&metrics, "https://0.evil.test", "request");
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(
+            snapshot.counter_with_labels(
+                HTTP_CORS_REJECTED_TOTAL,
+                &[("origin", "https://0.evil.test"), ("kind", "request")]
+            ),
+            2
+        );
+        assert_eq!(
+            snapshot.counter_with_labels(
+                HTTP_CORS_REJECTED_TOTAL,
+                &[("origin", OTHER_ORIGIN), ("kind", "request")]
+            ),
+            5
+        );
+    }
+}
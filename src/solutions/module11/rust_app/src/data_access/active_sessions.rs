@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sqlx::PgPool;
+
+use crate::core::ApplicationError;
+
+// How stale `last_seen_at` may get before a request updates it, so a busy session isn't written
+// on every request
+pub const TOUCH_INTERVAL_SECONDS: u64 = 60;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ActiveSession {
+    pub token_id: String,
+    pub email_address: String,
+    pub user_agent: Option<String>,
+    pub last_seen_ip: Option<String>,
+    pub created_at: u64,
+    pub last_seen_at: u64,
+    pub expires_at: u64,
+    pub impersonated_by: Option<String>,
+}
+
+// What is known about each session that has been issued, so users can see where they are signed
+// in. Sessions are still verified by their signature and the revocation list, an entry missing
+// from here (e.g. after a restart with the in-memory store) doesn't invalidate the session.
+#[async_trait::async_trait]
+pub trait ActiveSessions: Send + Sync {
+    async fn record(&self, session: ActiveSession) -> Result<(), ApplicationError>;
+    async fn touch(
+        &self,
+        token_id: &str,
+        seen_at: u64,
+        ip_address: Option<&str>,
+    ) -> Result<(), ApplicationError>;
+    // The user's unexpired sessions, most recently used first
+    async fn for_user(&self, email_address: &str) -> Result<Vec<ActiveSession>, ApplicationError>;
+    // Only removes the session if it belongs to `email_address`
+    async fn remove(
+        &self,
+        email_address: &str,
+        token_id: &str,
+    ) -> Result<Option<ActiveSession>, ApplicationError>;
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Default)]
+pub struct InMemoryActiveSessions {
+    sessions: Mutex<HashMap<String, ActiveSession>>,
+}
+
+#[async_trait::async_trait]
+impl ActiveSessions for InMemoryActiveSessions {
+    async fn record(&self, session: ActiveSession) -> Result<(), ApplicationError> {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        let now = now();
+        sessions.retain(|_, session| session.expires_at > now);
+        sessions.insert(session.token_id.clone(), session);
+
+        Ok(())
+    }
+
+    async fn touch(
+        &self,
+        token_id: &str,
+        seen_at: u64,
+        ip_address: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(token_id) else {
+            return Ok(());
+        };
+
+        if seen_at >= session.last_seen_at + TOUCH_INTERVAL_SECONDS
+            || session.last_seen_ip.as_deref() != ip_address
+        {
+            session.last_seen_at = seen_at;
+            session.last_seen_ip = ip_address.map(str::to_string);
+        }
+
+        Ok(())
+    }
+
+    async fn for_user(&self, email_address: &str) -> Result<Vec<ActiveSession>, ApplicationError> {
+        let now = now();
+        let mut sessions: Vec<ActiveSession> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| session.email_address == email_address && session.expires_at > now)
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen_at));
+
+        Ok(sessions)
+    }
+
+    async fn remove(
+        &self,
+        email_address: &str,
+        token_id: &str,
+    ) -> Result<Option<ActiveSession>, ApplicationError> {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        match sessions.get(token_id) {
+            Some(session) if session.email_address == email_address => {
+                Ok(sessions.remove(token_id))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+struct ActiveSessionRow {
+    token_id: String,
+    email_address: String,
+    user_agent: Option<String>,
+    last_seen_ip: Option<String>,
+    created_at: i64,
+    last_seen_at: i64,
+    expires_at: i64,
+    impersonated_by: Option<String>,
+}
+
+impl From<ActiveSessionRow> for ActiveSession {
+    fn from(row: ActiveSessionRow) -> Self {
+        ActiveSession {
+            token_id: row.token_id,
+            email_address: row.email_address,
+            user_agent: row.user_agent,
+            last_seen_ip: row.last_seen_ip,
+            created_at: row.created_at as u64,
+            last_seen_at: row.last_seen_at as u64,
+            expires_at: row.expires_at as u64,
+            impersonated_by: row.impersonated_by,
+        }
+    }
+}
+
+pub struct PostgresActiveSessions {
+    db: PgPool,
+}
+
+impl PostgresActiveSessions {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait::async_trait]
+impl ActiveSessions for PostgresActiveSessions {
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "record"))]
+    async fn record(&self, session: ActiveSession) -> Result<(), ApplicationError> {
+        log::info!("Recording active session");
+
+        sqlx::query!(
+            r#"
+            DELETE FROM active_sessions
+            WHERE expires_at < $1
+            "#,
+            now() as i64,
+        )
+            .execute(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO active_sessions ( token_id, email_address, user_agent, last_seen_ip, created_at, last_seen_at, expires_at, impersonated_by )
+            VALUES ( $1, $2, $3, $4, $5, $6, $7, $8 )
+            "#,
+            session.token_id,
+            session.email_address,
+            session.user_agent,
+            session.last_seen_ip,
+            session.created_at as i64,
+            session.last_seen_at as i64,
+            session.expires_at as i64,
+            session.impersonated_by,
+        )
+            .execute(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "touch"))]
+    async fn touch(
+        &self,
+        token_id: &str,
+        seen_at: u64,
+        ip_address: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        sqlx::query!(
+            r#"
+            UPDATE active_sessions
+            SET last_seen_at = $2, last_seen_ip = $3
+            WHERE token_id = $1 AND (last_seen_at <= $4 OR last_seen_ip IS DISTINCT FROM $3)
+            "#,
+            token_id,
+            seen_at as i64,
+            ip_address,
+            seen_at.saturating_sub(TOUCH_INTERVAL_SECONDS) as i64,
+        )
+            .execute(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "for_user"))]
+    async fn for_user(&self, email_address: &str) -> Result<Vec<ActiveSession>, ApplicationError> {
+        let rows = sqlx::query_as!(
+            ActiveSessionRow,
+            r#"
+            SELECT token_id, email_address, user_agent, last_seen_ip, created_at, last_seen_at, expires_at, impersonated_by
+            FROM active_sessions
+            WHERE email_address = $1 AND expires_at > $2
+            ORDER BY last_seen_at DESC
+            "#,
+            email_address,
+            now() as i64,
+        )
+            .fetch_all(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        Ok(rows.into_iter().map(ActiveSession::from).collect())
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "remove"))]
+    async fn remove(
+        &self,
+        email_address: &str,
+        token_id: &str,
+    ) -> Result<Option<ActiveSession>, ApplicationError> {
+        let row = sqlx::query_as!(
+            ActiveSessionRow,
+            r#"
+            DELETE FROM active_sessions
+            WHERE token_id = $1 AND email_address = $2
+            RETURNING token_id, email_address, user_agent, last_seen_ip, created_at, last_seen_at, expires_at, impersonated_by
+            "#,
+            token_id,
+            email_address,
+        )
+            .fetch_optional(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        Ok(row.map(ActiveSession::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(token_id: &str, email_address: &str, last_seen_at: u64) -> ActiveSession {
+        ActiveSession {
+            token_id: token_id.to_string(),
+            email_address: email_address.to_string(),
+            user_agent: Some("curl/8.0".to_string()),
+            last_seen_ip: None,
+            created_at: last_seen_at,
+            last_seen_at,
+            expires_at: now() + 60,
+            impersonated_by: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_should_only_be_listed_and_removed_by_the_user_they_belong_to() {
+        let sessions = InMemoryActiveSessions::default();
+        sessions.record(session("laptop", "test@test.com", 10)).await.unwrap();
+        sessions.record(session("phone", "test@test.com", 20)).await.unwrap();
+        sessions.record(session("other", "other@test.com", 30)).await.unwrap();
+
+        let listed: Vec<String> = sessions
+            .for_user("test@test.com")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|session| session.token_id)
+            .collect();
+        assert_eq!(listed, vec!["phone", "laptop"]);
+
+        assert_eq!(sessions.remove("test@test.com", "other").await.unwrap(), None);
+        assert!(sessions.remove("test@test.com", "laptop").await.unwrap().is_some());
+        assert_eq!(sessions.for_user("test@test.com").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn touching_a_session_should_only_write_once_per_interval_or_on_a_new_ip() {
+        let sessions = InMemoryActiveSessions::default();
+        sessions.record(session("laptop", "test@test.com", 100)).await.unwrap();
+
+        sessions.touch("laptop", 110, None).await.unwrap();
+        assert_eq!(sessions.for_user("test@test.com").await.unwrap()[0].last_seen_at, 100);
+
+        sessions.touch("laptop", 120, Some("10.0.0.1")).await.unwrap();
+        let touched = &sessions.for_user("test@test.com").await.unwrap()[0];
+        assert_eq!(touched.last_seen_at, 120);
+        assert_eq!(touched.last_seen_ip.as_deref(), Some("10.0.0.1"));
+    }
+}
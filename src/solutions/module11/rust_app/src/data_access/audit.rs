@@ -0,0 +1,299 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use sqlx::types::Json;
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::core::{ApplicationError, Config, StoreKind};
+
+// Who a request acts as, set from its session by `auth::require_session`. An impersonated session
+// acts as the user, `impersonated_by` is the admin behind it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Actor {
+    pub email_address: Option<String>,
+    pub impersonated_by: Option<String>,
+}
+
+tokio::task_local! {
+    static ACTOR: Actor;
+}
+
+// Writes made outside a request with a session, e.g. registering or promoting the configured
+// admins at startup, have no actor
+pub fn current_actor() -> Actor {
+    ACTOR.try_with(Actor::clone).unwrap_or_default()
+}
+
+pub async fn with_actor<F: Future>(actor: Actor, future: F) -> F::Output {
+    ACTOR.scope(actor, future).await
+}
+
+// One state changing operation on a user. `before` and `after` are the user's details, or their
+// preferences for `update_preferences`, either side is `None` where there was nothing, e.g. before
+// `store`. Passwords are never recorded.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub user_id: Uuid,
+    pub operation: String,
+    pub actor: Option<String>,
+    pub impersonated_by: Option<String>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub recorded_at: u64,
+}
+
+impl AuditEntry {
+    pub fn new(
+        user_id: Uuid,
+        operation: &str,
+        before: Option<serde_json::Value>,
+        after: Option<serde_json::Value>,
+    ) -> Self {
+        let actor = current_actor();
+
+        Self {
+            user_id,
+            operation: operation.to_string(),
+            actor: actor.email_address,
+            impersonated_by: actor.impersonated_by,
+            before,
+            after,
+            recorded_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0),
+        }
+    }
+}
+
+// Kept by user id, which outlives the email address
+#[async_trait::async_trait]
+pub trait AuditLog: Send + Sync {
+    async fn record(&self, entry: AuditEntry) -> Result<(), ApplicationError>;
+    // Oldest first, at most `limit` of the user's most recent entries
+    async fn for_user(&self, user_id: Uuid, limit: i64) -> Result<Vec<AuditEntry>, ApplicationError>;
+    // Drops the before and after of every entry, for when the user is erased. Who did what and
+    // when is kept.
+    async fn redact(&self, user_id: Uuid) -> Result<(), ApplicationError>;
+    // Whether the log is kept in the same database as the users, so an entry can be written in the
+    // transaction of the write it records, see `UnitOfWork::record`
+    fn in_users_database(&self) -> bool {
+        false
+    }
+}
+
+pub async fn audit_log_from_config(
+    config: &Config,
+) -> Result<Option<Arc<dyn AuditLog>>, ApplicationError> {
+    if !config.audit_enabled() {
+        return Ok(None);
+    }
+
+    match config.audit_store() {
+        StoreKind::Memory => Ok(Some(Arc::new(InMemoryAuditLog::default()))),
+        StoreKind::Postgres => {
+            let db = super::connect(
+                &config.connection_string(),
+                &super::PoolSettings::from_config(config),
+            )
+            .await?;
+
+            Ok(Some(Arc::new(PostgresAuditLog::new(db))))
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryAuditLog {
+    entries: Mutex<Vec<AuditEntry>>,
+}
+
+#[async_trait::async_trait]
+impl AuditLog for InMemoryAuditLog {
+    async fn record(&self, entry: AuditEntry) -> Result<(), ApplicationError> {
+        self.entries.lock().unwrap().push(entry);
+
+        Ok(())
+    }
+
+    async fn for_user(&self, user_id: Uuid, limit: i64) -> Result<Vec<AuditEntry>, ApplicationError> {
+        let entries = self.entries.lock().unwrap();
+        let for_user: Vec<&AuditEntry> = entries.iter().filter(|entry| entry.user_id == user_id).collect();
+        let skip = for_user.len().saturating_sub(limit.max(0) as usize);
+
+        Ok(for_user.into_iter().skip(skip).cloned().collect())
+    }
+
+    async fn redact(&self, user_id: Uuid) -> Result<(), ApplicationError> {
+        for entry in self.entries.lock().unwrap().iter_mut() {
+            if entry.user_id == user_id {
+                entry.before = None;
+                entry.after = None;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct AuditEntryRow {
+    user_id: Uuid,
+    operation: String,
+    actor: Option<String>,
+    impersonated_by: Option<String>,
+    before: Option<Json<serde_json::Value>>,
+    after: Option<Json<serde_json::Value>>,
+    recorded_at: i64,
+}
+
+impl From<AuditEntryRow> for AuditEntry {
+    fn from(row: AuditEntryRow) -> Self {
+        AuditEntry {
+            user_id: row.user_id,
+            operation: row.operation,
+            actor: row.actor,
+            impersonated_by: row.impersonated_by,
+            before: row.before.map(|Json(before)| before),
+            after: row.after.map(|Json(after)| after),
+            recorded_at: row.recorded_at as u64,
+        }
+    }
+}
+
+// Also what `UnitOfWork::record` writes with, in the transaction of the write being recorded
+pub(crate) async fn insert_entry(
+    connection: &mut PgConnection,
+    entry: &AuditEntry,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO audit_log ( user_id, operation, actor, impersonated_by, before, after, recorded_at )
+        VALUES ( $1, $2, $3, $4, $5, $6, $7 )
+        "#,
+        entry.user_id,
+        entry.operation,
+        entry.actor,
+        entry.impersonated_by,
+        entry.before.clone().map(Json) as _,
+        entry.after.clone().map(Json) as _,
+        entry.recorded_at as i64,
+    )
+        .execute(connection)
+        .await?;
+
+    Ok(())
+}
+
+pub struct PostgresAuditLog {
+    db: PgPool,
+}
+
+impl PostgresAuditLog {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait::async_trait]
+impl AuditLog for PostgresAuditLog {
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "record"))]
+    async fn record(&self, entry: AuditEntry) -> Result<(), ApplicationError> {
+        let mut connection = self
+            .db
+            .acquire()
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        insert_entry(&mut connection, &entry)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "for_user"))]
+    async fn for_user(&self, user_id: Uuid, limit: i64) -> Result<Vec<AuditEntry>, ApplicationError> {
+        let rows = sqlx::query_as!(
+            AuditEntryRow,
+            r#"
+            SELECT user_id, operation, actor, impersonated_by,
+                before AS "before: Json<serde_json::Value>",
+                after AS "after: Json<serde_json::Value>",
+                recorded_at
+            FROM (
+                SELECT * FROM audit_log WHERE user_id = $1 ORDER BY id DESC LIMIT $2
+            ) AS recent
+            ORDER BY id
+            "#,
+            user_id,
+            limit,
+        )
+            .fetch_all(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "redact"))]
+    async fn redact(&self, user_id: Uuid) -> Result<(), ApplicationError> {
+        sqlx::query!(
+            r#"
+            UPDATE audit_log SET before = NULL, after = NULL WHERE user_id = $1
+            "#,
+            user_id,
+        )
+            .execute(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    // `audit_log_from_config` connects it to the main database, where unsharded users are kept
+    fn in_users_database(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn entries_should_record_the_actor_of_the_request_they_were_made_in() {
+        let user_id = Uuid::new_v4();
+        let actor = Actor {
+            email_address: Some("admin@test.com".to_string()),
+            impersonated_by: None,
+        };
+
+        let in_request = with_actor(actor, async { AuditEntry::new(user_id, "update", None, None) }).await;
+        let outside = AuditEntry::new(user_id, "store", None, None);
+
+        assert_eq!(in_request.actor.as_deref(), Some("admin@test.com"));
+        assert_eq!(outside.actor, None);
+    }
+
+    #[tokio::test]
+    async fn for_user_should_return_the_most_recent_entries_oldest_first() {
+        let log = InMemoryAuditLog::default();
+        let user_id = Uuid::new_v4();
+        for operation in ["store", "update", "soft_delete"] {
+            log.record(AuditEntry::new(user_id, operation, None, Some(serde_json::json!({}))))
+                .await
+                .unwrap();
+        }
+        log.record(AuditEntry::new(Uuid::new_v4(), "store", None, None)).await.unwrap();
+
+        let entries = log.for_user(user_id, 2).await.unwrap();
+        log.redact(user_id).await.unwrap();
+
+        let operations: Vec<&str> = entries.iter().map(|entry| entry.operation.as_str()).collect();
+        assert_eq!(operations, vec!["update", "soft_delete"]);
+        assert!(log.for_user(user_id, 10).await.unwrap().iter().all(|entry| entry.after.is_none()));
+    }
+}
@@ -0,0 +1,585 @@
+use std::sync::Arc;
+
+use futures::stream::BoxStream;
+use uuid::Uuid;
+
+use crate::core::{
+    ApplicationError, DataAccess, EmailAlias, LastLogin, User, UserPreferences, Versioned,
+};
+use super::audit::{AuditEntry, AuditLog};
+use super::UnitOfWork;
+
+fn snapshot<T: serde::Serialize>(value: &T) -> Option<serde_json::Value> {
+    serde_json::to_value(value).ok()
+}
+
+fn alias_snapshot(alias: &str) -> Option<serde_json::Value> {
+    Some(serde_json::json!({ "emailAddress": alias }))
+}
+
+// Records every successful write to the audit log, with the user as it was read just before and as
+// it was written. Reads pass straight through. With the log in the users' database a store or an
+// update and its entry are one unit of work, an entry that fails to record rolls the write back.
+// Otherwise the write has already happened when it is recorded, so an entry that fails to record
+// is logged rather than failing the write. A user that was soft
+// deleted can't be read any more, their hard delete isn't recorded, the soft delete is their last
+// entry.
+pub struct AuditedDataAccess<TDataAccess: DataAccess> {
+    inner: TDataAccess,
+    log: Arc<dyn AuditLog>,
+}
+
+impl<TDataAccess: DataAccess> AuditedDataAccess<TDataAccess> {
+    pub fn new(inner: TDataAccess, log: Arc<dyn AuditLog>) -> Self {
+        Self { inner, log }
+    }
+
+    async fn record(&self, entry: AuditEntry) {
+        if let Err(e) = self.log.record(entry).await {
+            log::error!("Failed to record audit entry: {:?}", e);
+        }
+    }
+
+    async fn live(&self, email_address: &str) -> Option<User> {
+        self.inner.with_email_address(email_address).await.ok()
+    }
+
+    // A unit of work can only record entries when the log is kept alongside the users
+    async fn begin_recorded(&self) -> Result<Option<Box<dyn UnitOfWork>>, ApplicationError> {
+        match self.log.in_users_database() {
+            true => self.inner.begin().await,
+            false => Ok(None),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<TDataAccess: DataAccess> DataAccess for AuditedDataAccess<TDataAccess> {
+    async fn with_email_address(&self, email_address: &str) -> Result<User, ApplicationError> {
+        self.inner.with_email_address(email_address).await
+    }
+
+    async fn list(&self, offset: i64, limit: i64) -> Result<Vec<User>, ApplicationError> {
+        self.inner.list(offset, limit).await
+    }
+
+    async fn list_after(
+        &self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<User>, ApplicationError> {
+        self.inner.list_after(after, limit).await
+    }
+
+    fn stream_all(&self) -> BoxStream<'_, Result<User, ApplicationError>> {
+        self.inner.stream_all()
+    }
+
+    async fn search_ranked(
+        &self,
+        query: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<(User, f32)>, ApplicationError> {
+        self.inner.search_ranked(query, offset, limit).await
+    }
+
+    async fn count(&self) -> Result<i64, ApplicationError> {
+        self.inner.count().await
+    }
+
+    async fn exists(&self, email_address: &str) -> Result<bool, ApplicationError> {
+        self.inner.exists(email_address).await
+    }
+
+    async fn list_including_deleted(
+        &self,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<(User, Option<u64>)>, ApplicationError> {
+        self.inner.list_including_deleted(offset, limit).await
+    }
+
+    async fn store(&self, user: User) -> Result<(), ApplicationError> {
+        let entry = AuditEntry::new(user.id(), "store", None, snapshot(user.details()));
+        if let Some(mut work) = self.begin_recorded().await? {
+            work.store(&user).await?;
+            work.record(&entry).await?;
+            return work.commit().await;
+        }
+        self.inner.store(user).await?;
+
+        self.record(entry).await;
+        Ok(())
+    }
+
+    // With the log alongside the users every store and its entry is one unit of work, a statement
+    // at a time, otherwise the batch is stored in one and each user recorded after it
+    async fn store_many(&self, users: Vec<User>) -> Result<(), ApplicationError> {
+        let entries: Vec<AuditEntry> = users
+            .iter()
+            .map(|user| AuditEntry::new(user.id(), "store", None, snapshot(user.details())))
+            .collect();
+        if let Some(mut work) = self.begin_recorded().await? {
+            for (user, entry) in users.iter().zip(&entries) {
+                work.store(user).await?;
+                work.record(entry).await?;
+            }
+            return work.commit().await;
+        }
+        self.inner.store_many(users).await?;
+
+        for entry in entries {
+            self.record(entry).await;
+        }
+        Ok(())
+    }
+
+    async fn warm_up(&self, connections: u32) -> Result<(), ApplicationError> {
+        self.inner.warm_up(connections).await
+    }
+
+    async fn health_check(&self) -> Result<(), ApplicationError> {
+        self.inner.health_check().await
+    }
+
+    async fn with_id(&self, id: Uuid) -> Result<User, ApplicationError> {
+        self.inner.with_id(id).await
+    }
+
+    async fn with_email_address_versioned(
+        &self,
+        email_address: &str,
+    ) -> Result<Versioned<User>, ApplicationError> {
+        self.inner.with_email_address_versioned(email_address).await
+    }
+
+    async fn update(
+        &self,
+        user: User,
+        expected_version: Option<i64>,
+    ) -> Result<i64, ApplicationError> {
+        let before = self.live(&user.email_address()).await;
+        let after = snapshot(user.details());
+        let id = before.as_ref().map(User::id).unwrap_or(user.id());
+        let before = before.and_then(|before| snapshot(before.details()));
+        let entry = AuditEntry::new(id, "update", before, after);
+        if let Some(mut work) = self.begin_recorded().await? {
+            let version = work.update(&user, expected_version).await?;
+            work.record(&entry).await?;
+            work.commit().await?;
+            return Ok(version);
+        }
+        let version = self.inner.update(user, expected_version).await?;
+
+        self.record(entry).await;
+        Ok(version)
+    }
+
+    // Recorded as a store or an update, and not at all when the version didn't move. The unit of
+    // work has no upsert, so the entry is recorded after the write even with the log alongside the
+    // users.
+    async fn upsert(&self, user: User) -> Result<i64, ApplicationError> {
+        let before = self
+            .inner
+            .with_email_address_versioned(&user.email_address())
+            .await
+            .ok();
+        let id = user.id();
+        let after = snapshot(user.details());
+        let version = self.inner.upsert(user).await?;
+
+        let entry = match before {
+            Some(before) if before.version == version => return Ok(version),
+            Some(before) => AuditEntry::new(
+                before.value.id(),
+                "update",
+                snapshot(before.value.details()),
+                after,
+            ),
+            None => AuditEntry::new(id, "store", None, after),
+        };
+        self.record(entry).await;
+        Ok(version)
+    }
+
+    async fn soft_delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        let before = self.live(email_address).await;
+        self.inner.soft_delete(email_address, expected_version).await?;
+
+        if let Some(before) = before {
+            self.record(AuditEntry::new(before.id(), "soft_delete", snapshot(before.details()), None))
+                .await;
+        }
+        Ok(())
+    }
+
+    async fn delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        let before = self.live(email_address).await;
+        self.inner.delete(email_address, expected_version).await?;
+
+        if let Some(before) = before {
+            self.record(AuditEntry::new(before.id(), "delete", snapshot(before.details()), None))
+                .await;
+        }
+        Ok(())
+    }
+
+    // The user's earlier entries are redacted along with them, and this one holds nothing about
+    // them either
+    async fn erase(
+        &self,
+        email_address: &str,
+        requested_by: Option<&str>,
+    ) -> Result<Uuid, ApplicationError> {
+        let id = self.inner.erase(email_address, requested_by).await?;
+
+        if let Err(e) = self.log.redact(id).await {
+            log::error!("Failed to redact the audit entries of erased user {}: {:?}", id, e);
+        }
+        self.record(AuditEntry::new(id, "erase", None, None)).await;
+        Ok(id)
+    }
+
+    // An entry for each user, the merged user's is their last
+    async fn merge(
+        &self,
+        kept: &str,
+        merged: &str,
+        requested_by: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        let before = self.live(kept).await;
+        let before_merged = self.live(merged).await;
+        self.inner.merge(kept, merged, requested_by).await?;
+
+        if let Some(before) = before {
+            let after = self.live(kept).await;
+            self.record(AuditEntry::new(
+                before.id(),
+                "merge",
+                snapshot(before.details()),
+                after.and_then(|after| snapshot(after.details())),
+            ))
+            .await;
+        }
+        if let Some(before) = before_merged {
+            self.record(AuditEntry::new(before.id(), "merge", snapshot(before.details()), None))
+                .await;
+        }
+        Ok(())
+    }
+
+    async fn preferences(&self, email_address: &str) -> Result<UserPreferences, ApplicationError> {
+        self.inner.preferences(email_address).await
+    }
+
+    async fn update_preferences(
+        &self,
+        email_address: &str,
+        preferences: &UserPreferences,
+    ) -> Result<(), ApplicationError> {
+        let user = self.live(email_address).await;
+        let before = self.inner.preferences(email_address).await.ok();
+        self.inner.update_preferences(email_address, preferences).await?;
+
+        if let Some(user) = user {
+            self.record(AuditEntry::new(
+                user.id(),
+                "update_preferences",
+                before.as_ref().and_then(snapshot),
+                snapshot(preferences),
+            ))
+            .await;
+        }
+        Ok(())
+    }
+
+    // A login doesn't change the account, so it isn't audited
+    async fn record_login(
+        &self,
+        email_address: &str,
+        logged_in_at: u64,
+        ip: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        self.inner.record_login(email_address, logged_in_at, ip).await
+    }
+
+    async fn last_login(&self, email_address: &str) -> Result<Option<LastLogin>, ApplicationError> {
+        self.inner.last_login(email_address).await
+    }
+
+    async fn email_aliases(&self, email_address: &str) -> Result<Vec<EmailAlias>, ApplicationError> {
+        self.inner.email_aliases(email_address).await
+    }
+
+    async fn add_email_alias(
+        &self,
+        email_address: &str,
+        alias: &str,
+        token_hash: &str,
+    ) -> Result<(), ApplicationError> {
+        self.inner.add_email_alias(email_address, alias, token_hash).await?;
+
+        if let Some(user) = self.live(email_address).await {
+            self.record(AuditEntry::new(user.id(), "add_email_alias", None, alias_snapshot(alias)))
+                .await;
+        }
+        Ok(())
+    }
+
+    // Verified through the link, by someone who may not be signed in, so the entry has no actor
+    // unless they were
+    async fn verify_email_alias(
+        &self,
+        token_hash: &str,
+        added_after: u64,
+    ) -> Result<Option<String>, ApplicationError> {
+        let verified = self.inner.verify_email_alias(token_hash, added_after).await?;
+
+        if let Some(alias) = &verified
+            && let Ok(Some(email_address)) = self.inner.resolve_email_alias(alias).await
+            && let Some(user) = self.live(&email_address).await
+        {
+            self.record(AuditEntry::new(user.id(), "verify_email_alias", None, alias_snapshot(alias)))
+                .await;
+        }
+        Ok(verified)
+    }
+
+    async fn remove_email_alias(
+        &self,
+        email_address: &str,
+        alias: &str,
+    ) -> Result<(), ApplicationError> {
+        self.inner.remove_email_alias(email_address, alias).await?;
+
+        if let Some(user) = self.live(email_address).await {
+            self.record(AuditEntry::new(user.id(), "remove_email_alias", alias_snapshot(alias), None))
+                .await;
+        }
+        Ok(())
+    }
+
+    async fn set_primary_email_address(
+        &self,
+        email_address: &str,
+        alias: &str,
+    ) -> Result<(), ApplicationError> {
+        let before = self.live(email_address).await;
+        self.inner.set_primary_email_address(email_address, alias).await?;
+
+        if let Some(before) = before {
+            let after = self.live(alias).await;
+            self.record(AuditEntry::new(
+                before.id(),
+                "set_primary_email_address",
+                snapshot(before.details()),
+                after.and_then(|after| snapshot(after.details())),
+            ))
+            .await;
+        }
+        Ok(())
+    }
+
+    async fn resolve_email_alias(&self, alias: &str) -> Result<Option<String>, ApplicationError> {
+        self.inner.resolve_email_alias(alias).await
+    }
+
+    async fn set_username(
+        &self,
+        email_address: &str,
+        username: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        let before = self.live(email_address).await;
+        self.inner.set_username(email_address, username).await?;
+
+        if let Some(before) = before {
+            let after = self.live(email_address).await;
+            self.record(AuditEntry::new(
+                before.id(),
+                "set_username",
+                snapshot(before.details()),
+                after.and_then(|after| snapshot(after.details())),
+            ))
+            .await;
+        }
+        Ok(())
+    }
+
+    async fn resolve_username(&self, username: &str) -> Result<Option<String>, ApplicationError> {
+        self.inner.resolve_username(username).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::data_access::{with_actor, Actor, InMemoryAuditLog, InMemoryUsers, OutboxEntry};
+
+    // The users and their audit log in one database, as with Postgres. A unit of work only applies
+    // its writes when it commits.
+    #[derive(Default)]
+    struct Database {
+        users: InMemoryUsers,
+        log: InMemoryAuditLog,
+        fail_recording: AtomicBool,
+    }
+
+    struct DatabaseUsers(Arc<Database>);
+    struct DatabaseLog(Arc<Database>);
+
+    struct StagedWork {
+        database: Arc<Database>,
+        users: Vec<User>,
+        entries: Vec<AuditEntry>,
+    }
+
+    #[async_trait::async_trait]
+    impl DataAccess for DatabaseUsers {
+        async fn with_email_address(&self, email_address: &str) -> Result<User, ApplicationError> {
+            self.0.users.with_email_address(email_address).await
+        }
+
+        async fn list(&self, offset: i64, limit: i64) -> Result<Vec<User>, ApplicationError> {
+            self.0.users.list(offset, limit).await
+        }
+
+        async fn store(&self, user: User) -> Result<(), ApplicationError> {
+            self.0.users.store(user).await
+        }
+
+        async fn begin(&self) -> Result<Option<Box<dyn UnitOfWork>>, ApplicationError> {
+            Ok(Some(Box::new(StagedWork {
+                database: self.0.clone(),
+                users: Vec::new(),
+                entries: Vec::new(),
+            })))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl AuditLog for DatabaseLog {
+        async fn record(&self, entry: AuditEntry) -> Result<(), ApplicationError> {
+            self.0.log.record(entry).await
+        }
+
+        async fn for_user(
+            &self,
+            user_id: Uuid,
+            limit: i64,
+        ) -> Result<Vec<AuditEntry>, ApplicationError> {
+            self.0.log.for_user(user_id, limit).await
+        }
+
+        async fn redact(&self, user_id: Uuid) -> Result<(), ApplicationError> {
+            self.0.log.redact(user_id).await
+        }
+
+        fn in_users_database(&self) -> bool {
+            true
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl UnitOfWork for StagedWork {
+        async fn store(&mut self, user: &User) -> Result<(), ApplicationError> {
+            self.users.push(user.clone());
+            Ok(())
+        }
+
+        async fn update(&mut self, _: &User, _: Option<i64>) -> Result<i64, ApplicationError> {
+            Err(ApplicationError::ApplicationError("updates are not staged".to_string()))
+        }
+
+        async fn enqueue(&mut self, _: &OutboxEntry) -> Result<(), ApplicationError> {
+            Ok(())
+        }
+
+        async fn record(&mut self, entry: &AuditEntry) -> Result<(), ApplicationError> {
+            if self.database.fail_recording.load(Ordering::Relaxed) {
+                return Err(ApplicationError::DatabaseError("audit_log is full".to_string()));
+            }
+            self.entries.push(entry.clone());
+            Ok(())
+        }
+
+        async fn commit(self: Box<Self>) -> Result<(), ApplicationError> {
+            for user in self.users {
+                self.database.users.store(user).await?;
+            }
+            for entry in self.entries {
+                self.database.log.record(entry).await?;
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_should_be_recorded_with_the_user_before_and_after() {
+        let log = Arc::new(InMemoryAuditLog::default());
+        let users = AuditedDataAccess::new(InMemoryUsers::default(), log.clone());
+        let user = User::from("james@test.com", "James", "hashed");
+        let admin = Actor {
+            email_address: Some("admin@test.com".to_string()),
+            impersonated_by: None,
+        };
+
+        users.store(user.clone()).await.unwrap();
+        let mut renamed = user.clone();
+        renamed.update_name("Renamed");
+        with_actor(admin, users.update(renamed, None)).await.unwrap();
+        assert!(users.update(user.clone(), Some(7)).await.is_err());
+
+        let entries = log.for_user(user.id(), 10).await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].operation, "store");
+        assert_eq!(entries[0].after.as_ref().unwrap()["name"], "James");
+        assert!(entries[0].after.as_ref().unwrap().get("password").is_none());
+        assert_eq!(entries[1].actor.as_deref(), Some("admin@test.com"));
+        assert_eq!(entries[1].before.as_ref().unwrap()["name"], "James");
+        assert_eq!(entries[1].after.as_ref().unwrap()["name"], "Renamed");
+    }
+
+    #[tokio::test]
+    async fn erasing_a_user_should_redact_their_entries() {
+        let log = Arc::new(InMemoryAuditLog::default());
+        let users = AuditedDataAccess::new(InMemoryUsers::default(), log.clone());
+        let user = User::from("james@test.com", "James", "hashed");
+        users.store(user.clone()).await.unwrap();
+
+        users.erase("james@test.com", None).await.unwrap();
+
+        let entries = log.for_user(user.id(), 10).await.unwrap();
+        let operations: Vec<&str> = entries.iter().map(|entry| entry.operation.as_str()).collect();
+        assert_eq!(operations, vec!["store", "erase"]);
+        assert!(entries.iter().all(|entry| entry.before.is_none() && entry.after.is_none()));
+    }
+
+    #[tokio::test]
+    async fn a_store_should_roll_back_when_its_entry_fails_to_record() {
+        let database = Arc::new(Database::default());
+        let log = Arc::new(DatabaseLog(database.clone()));
+        let users = AuditedDataAccess::new(DatabaseUsers(database.clone()), log);
+        let user = User::from("james@test.com", "James", "hashed");
+
+        database.fail_recording.store(true, Ordering::Relaxed);
+        assert!(users.store(user.clone()).await.is_err());
+        assert!(users.with_email_address("james@test.com").await.is_err());
+
+        database.fail_recording.store(false, Ordering::Relaxed);
+        users.store(user.clone()).await.unwrap();
+        assert!(users.with_email_address("james@test.com").await.is_ok());
+        let entries = database.log.for_user(user.id(), 10).await.unwrap();
+        assert_eq!(entries.len(), 1);
+    }
+}
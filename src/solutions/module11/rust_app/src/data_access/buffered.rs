@@ -0,0 +1,583 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use futures::stream::{BoxStream, StreamExt};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::core::{
+    ApplicationError, BufferMode, Config, DataAccess, EmailAlias, LastLogin, User, UserPreferences,
+    Versioned,
+};
+use crate::metrics::{Metrics, USER_WRITE_BEHIND_LOST_TOTAL, USER_WRITE_BEHIND_PENDING};
+use super::InMemoryUsers;
+
+#[derive(Clone, Debug)]
+pub struct BufferSettings {
+    pub mode: BufferMode,
+    pub flush_interval: Duration,
+    pub max_pending: usize,
+}
+
+impl BufferSettings {
+    pub fn from_config(config: &Config) -> Option<Self> {
+        Some(Self {
+            mode: config.buffer_mode()?,
+            flush_interval: Duration::from_millis(config.buffer_flush_interval_ms()),
+            max_pending: config.buffer_max_pending().max(1),
+        })
+    }
+}
+
+// A write-behind write that was applied in memory and is waiting to be flushed. The version was
+// checked in memory, so it is flushed regardless of the stored one.
+enum PendingWrite {
+    Store(User),
+    Update(User),
+    SoftDelete(String),
+    Preferences(String, UserPreferences),
+}
+
+impl PendingWrite {
+    fn operation(&self) -> &'static str {
+        match self {
+            PendingWrite::Store(_) => "store",
+            PendingWrite::Update(_) => "update",
+            PendingWrite::SoftDelete(_) => "soft_delete",
+            PendingWrite::Preferences(_, _) => "update_preferences",
+        }
+    }
+
+    async fn apply<TDataAccess: DataAccess>(self, store: &TDataAccess) -> Result<(), ApplicationError> {
+        match self {
+            PendingWrite::Store(user) => store.store(user).await,
+            PendingWrite::Update(user) => store.update(user, None).await.map(|_| ()),
+            PendingWrite::SoftDelete(email_address) => store.soft_delete(&email_address, None).await,
+            PendingWrite::Preferences(email_address, preferences) => {
+                store.update_preferences(&email_address, &preferences).await
+            }
+        }
+    }
+}
+
+// The database behind the buffer, and the writes waiting for it, shared with the flushing task
+struct Backing<TDataAccess: DataAccess> {
+    store: TDataAccess,
+    pending: Mutex<VecDeque<PendingWrite>>,
+    // One flush at a time, so writes reach the database in the order they were made
+    flushing: tokio::sync::Mutex<()>,
+    metrics: Arc<Metrics>,
+}
+
+impl<TDataAccess: DataAccess> Backing<TDataAccess> {
+    fn record_pending(&self, pending: usize) {
+        self.metrics
+            .set_gauge(USER_WRITE_BEHIND_PENDING, &[], pending as f64);
+    }
+
+    // A write the database turns away, e.g. a registration for an email address a soft deleted user
+    // still holds, has already been answered as a success. It is logged and counted as lost.
+    async fn flush(&self) {
+        let _flushing = self.flushing.lock().await;
+        let writes: Vec<PendingWrite> = self.pending.lock().unwrap().drain(..).collect();
+        self.record_pending(0);
+
+        for write in writes {
+            let operation = write.operation();
+            if let Err(e) = write.apply(&self.store).await {
+                log::error!("Lost a buffered {} that failed to flush: {:?}", operation, e);
+                self.metrics
+                    .increment_with_labels(USER_WRITE_BEHIND_LOST_TOTAL, &[("operation", operation)]);
+            }
+        }
+    }
+}
+
+async fn flush_periodically<TDataAccess: DataAccess>(
+    backing: Weak<Backing<TDataAccess>>,
+    interval: Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(backing) = backing.upgrade() else {
+            return;
+        };
+        backing.flush().await;
+    }
+}
+
+// Holds the users it has read in memory in front of another store, for the durability module.
+// Reads are served from memory once a user has been read. In `WriteThrough` mode writes go to the
+// store before they are answered and the copy in memory is dropped. In `WriteBehind` mode writes
+// are made in memory and answered straight away, then flushed every `flush_interval`, or by the
+// write that finds `max_pending` waiting. Whatever hasn't been flushed is lost if the process
+// stops. Listings, hard deletes, erasures, email aliases and usernames flush first and go to the
+// store, as do reads of users that aren't held.
+pub struct BufferedUsers<TDataAccess: DataAccess> {
+    cache: InMemoryUsers,
+    backing: Arc<Backing<TDataAccess>>,
+    settings: BufferSettings,
+    // Writes to the store hold it exclusively, so a user being read into memory can't be read
+    // before a write and kept after it
+    loading: RwLock<()>,
+}
+
+impl<TDataAccess: DataAccess + 'static> BufferedUsers<TDataAccess> {
+    pub fn new(store: TDataAccess, settings: BufferSettings, metrics: Arc<Metrics>) -> Self {
+        let backing = Arc::new(Backing {
+            store,
+            pending: Mutex::new(VecDeque::new()),
+            flushing: tokio::sync::Mutex::new(()),
+            metrics,
+        });
+
+        if settings.mode == BufferMode::WriteBehind {
+            log::warn!(
+                "Writes are buffered in memory and flushed every {} ms, up to that much of them is lost if the process stops",
+                settings.flush_interval.as_millis()
+            );
+            tokio::spawn(flush_periodically(
+                Arc::downgrade(&backing),
+                settings.flush_interval,
+            ));
+        }
+
+        Self {
+            cache: InMemoryUsers::default(),
+            backing,
+            settings,
+            loading: RwLock::new(()),
+        }
+    }
+}
+
+impl<TDataAccess: DataAccess> BufferedUsers<TDataAccess> {
+    // Writes everything that is waiting to the store
+    pub async fn flush(&self) {
+        self.backing.flush().await;
+    }
+
+    fn write_behind(&self) -> bool {
+        self.settings.mode == BufferMode::WriteBehind
+    }
+
+    // Reads the user from the store into memory unless they are held already
+    async fn load(&self, email_address: &str) -> Result<(), ApplicationError> {
+        if self.cache.holds(email_address) {
+            return Ok(());
+        }
+
+        let _loading = self.loading.read().await;
+        let user = match self.backing.store.with_email_address_versioned(email_address).await {
+            Ok(user) => user,
+            Err(ApplicationError::UserDoesNotExist) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let preferences = self.backing.store.preferences(email_address).await?;
+        self.cache.load(user, preferences);
+
+        Ok(())
+    }
+
+    async fn enqueue(&self, write: PendingWrite) {
+        let pending = {
+            let mut pending = self.backing.pending.lock().unwrap();
+            pending.push_back(write);
+            pending.len()
+        };
+        self.backing.record_pending(pending);
+
+        if pending >= self.settings.max_pending {
+            self.flush().await;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<TDataAccess: DataAccess> DataAccess for BufferedUsers<TDataAccess> {
+    async fn with_email_address(&self, email_address: &str) -> Result<User, ApplicationError> {
+        self.load(email_address).await?;
+        self.cache.with_email_address(email_address).await
+    }
+
+    async fn list(&self, offset: i64, limit: i64) -> Result<Vec<User>, ApplicationError> {
+        self.flush().await;
+        self.backing.store.list(offset, limit).await
+    }
+
+    async fn list_after(
+        &self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<User>, ApplicationError> {
+        self.flush().await;
+        self.backing.store.list_after(after, limit).await
+    }
+
+    async fn list_including_deleted(
+        &self,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<(User, Option<u64>)>, ApplicationError> {
+        self.flush().await;
+        self.backing.store.list_including_deleted(offset, limit).await
+    }
+
+    async fn search_ranked(
+        &self,
+        query: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<(User, f32)>, ApplicationError> {
+        self.flush().await;
+        self.backing.store.search_ranked(query, offset, limit).await
+    }
+
+    async fn count(&self) -> Result<i64, ApplicationError> {
+        self.flush().await;
+        self.backing.store.count().await
+    }
+
+    // A user held in memory may have writes the store hasn't seen yet
+    async fn exists(&self, email_address: &str) -> Result<bool, ApplicationError> {
+        if self.cache.holds(email_address) {
+            return self.cache.exists(email_address).await;
+        }
+
+        self.backing.store.exists(email_address).await
+    }
+
+    // Pending writes are flushed before the first user is read, as they are for a page
+    fn stream_all(&self) -> BoxStream<'_, Result<User, ApplicationError>> {
+        futures::stream::once(self.flush())
+            .flat_map(move |_| self.backing.store.stream_all())
+            .boxed()
+    }
+
+    async fn store(&self, user: User) -> Result<(), ApplicationError> {
+        if !self.write_behind() {
+            let _writing = self.loading.write().await;
+            return self.backing.store.store(user).await;
+        }
+
+        self.load(&user.email_address()).await?;
+        self.cache.store(user.clone()).await?;
+        self.enqueue(PendingWrite::Store(user)).await;
+        Ok(())
+    }
+
+    // Straight to the store in either mode, the batch is stored as a whole or not at all
+    async fn store_many(&self, users: Vec<User>) -> Result<(), ApplicationError> {
+        self.flush().await;
+        let _writing = self.loading.write().await;
+        let email_addresses: Vec<String> = users.iter().map(User::email_address).collect();
+        self.backing.store.store_many(users).await?;
+        for email_address in email_addresses {
+            self.cache.evict(&email_address);
+        }
+        Ok(())
+    }
+
+    async fn warm_up(&self, connections: u32) -> Result<(), ApplicationError> {
+        self.backing.store.warm_up(connections).await
+    }
+
+    async fn health_check(&self) -> Result<(), ApplicationError> {
+        self.backing.store.health_check().await
+    }
+
+    async fn with_id(&self, id: Uuid) -> Result<User, ApplicationError> {
+        match self.cache.with_id(id).await {
+            Err(ApplicationError::UserDoesNotExist) => {
+                let user = self.backing.store.with_id(id).await?;
+                self.with_email_address(&user.email_address()).await
+            }
+            found => found,
+        }
+    }
+
+    async fn with_email_address_versioned(
+        &self,
+        email_address: &str,
+    ) -> Result<Versioned<User>, ApplicationError> {
+        self.load(email_address).await?;
+        self.cache.with_email_address_versioned(email_address).await
+    }
+
+    async fn update(
+        &self,
+        user: User,
+        expected_version: Option<i64>,
+    ) -> Result<i64, ApplicationError> {
+        let email_address = user.email_address();
+        if !self.write_behind() {
+            let _writing = self.loading.write().await;
+            let version = self.backing.store.update(user, expected_version).await?;
+            self.cache.evict(&email_address);
+            return Ok(version);
+        }
+
+        self.load(&email_address).await?;
+        let version = self.cache.update(user.clone(), expected_version).await?;
+        self.enqueue(PendingWrite::Update(user)).await;
+        Ok(version)
+    }
+
+    // Straight to the store in either mode, it decides whether anything changed
+    async fn upsert(&self, user: User) -> Result<i64, ApplicationError> {
+        self.flush().await;
+        let _writing = self.loading.write().await;
+        let email_address = user.email_address();
+        let version = self.backing.store.upsert(user).await?;
+        self.cache.evict(&email_address);
+        Ok(version)
+    }
+
+    async fn soft_delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        if !self.write_behind() {
+            let _writing = self.loading.write().await;
+            self.backing.store.soft_delete(email_address, expected_version).await?;
+            self.cache.evict(email_address);
+            return Ok(());
+        }
+
+        self.load(email_address).await?;
+        self.cache.soft_delete(email_address, expected_version).await?;
+        self.enqueue(PendingWrite::SoftDelete(email_address.to_string())).await;
+        Ok(())
+    }
+
+    // Usually of a soft deleted user, who isn't read into memory, so the version is checked by the
+    // store once everything before it has been flushed
+    async fn delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        self.flush().await;
+        let _writing = self.loading.write().await;
+        self.backing.store.delete(email_address, expected_version).await?;
+        self.cache.evict(email_address);
+        Ok(())
+    }
+
+    // Never left waiting in memory, the user's details must actually be gone once it returns
+    async fn erase(
+        &self,
+        email_address: &str,
+        requested_by: Option<&str>,
+    ) -> Result<Uuid, ApplicationError> {
+        self.flush().await;
+        let _writing = self.loading.write().await;
+        let id = self.backing.store.erase(email_address, requested_by).await?;
+        self.cache.evict(email_address);
+        Ok(id)
+    }
+
+    async fn merge(
+        &self,
+        kept: &str,
+        merged: &str,
+        requested_by: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        self.flush().await;
+        let _writing = self.loading.write().await;
+        self.backing.store.merge(kept, merged, requested_by).await?;
+        self.cache.evict(kept);
+        self.cache.evict(merged);
+        Ok(())
+    }
+
+    async fn preferences(&self, email_address: &str) -> Result<UserPreferences, ApplicationError> {
+        self.load(email_address).await?;
+        self.cache.preferences(email_address).await
+    }
+
+    async fn update_preferences(
+        &self,
+        email_address: &str,
+        preferences: &UserPreferences,
+    ) -> Result<(), ApplicationError> {
+        if !self.write_behind() {
+            let _writing = self.loading.write().await;
+            self.backing.store.update_preferences(email_address, preferences).await?;
+            self.cache.evict(email_address);
+            return Ok(());
+        }
+
+        self.load(email_address).await?;
+        self.cache.update_preferences(email_address, preferences).await?;
+        self.enqueue(PendingWrite::Preferences(
+            email_address.to_string(),
+            preferences.clone(),
+        ))
+        .await;
+        Ok(())
+    }
+
+    // Not held in memory, a login is written straight to the store and read from it
+    async fn record_login(
+        &self,
+        email_address: &str,
+        logged_in_at: u64,
+        ip: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        self.flush().await;
+        self.backing.store.record_login(email_address, logged_in_at, ip).await
+    }
+
+    async fn last_login(&self, email_address: &str) -> Result<Option<LastLogin>, ApplicationError> {
+        self.flush().await;
+        self.backing.store.last_login(email_address).await
+    }
+
+    async fn email_aliases(&self, email_address: &str) -> Result<Vec<EmailAlias>, ApplicationError> {
+        self.flush().await;
+        self.backing.store.email_aliases(email_address).await
+    }
+
+    async fn add_email_alias(
+        &self,
+        email_address: &str,
+        alias: &str,
+        token_hash: &str,
+    ) -> Result<(), ApplicationError> {
+        self.flush().await;
+        self.backing.store.add_email_alias(email_address, alias, token_hash).await
+    }
+
+    async fn verify_email_alias(
+        &self,
+        token_hash: &str,
+        added_after: u64,
+    ) -> Result<Option<String>, ApplicationError> {
+        self.flush().await;
+        self.backing.store.verify_email_alias(token_hash, added_after).await
+    }
+
+    async fn remove_email_alias(
+        &self,
+        email_address: &str,
+        alias: &str,
+    ) -> Result<(), ApplicationError> {
+        self.flush().await;
+        self.backing.store.remove_email_alias(email_address, alias).await
+    }
+
+    // Changes the address the user is held under, so their copy in memory is dropped
+    async fn set_primary_email_address(
+        &self,
+        email_address: &str,
+        alias: &str,
+    ) -> Result<(), ApplicationError> {
+        self.flush().await;
+        let _writing = self.loading.write().await;
+        self.backing.store.set_primary_email_address(email_address, alias).await?;
+        self.cache.evict(email_address);
+        self.cache.evict(alias);
+        Ok(())
+    }
+
+    async fn resolve_email_alias(&self, alias: &str) -> Result<Option<String>, ApplicationError> {
+        self.backing.store.resolve_email_alias(alias).await
+    }
+
+    // Written straight to the store, where uniqueness is checked, so the copy in memory is dropped
+    async fn set_username(
+        &self,
+        email_address: &str,
+        username: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        self.flush().await;
+        let _writing = self.loading.write().await;
+        self.backing.store.set_username(email_address, username).await?;
+        self.cache.evict(email_address);
+        Ok(())
+    }
+
+    async fn resolve_username(&self, username: &str) -> Result<Option<String>, ApplicationError> {
+        self.backing.store.resolve_username(username).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(mode: BufferMode, max_pending: usize) -> BufferSettings {
+        BufferSettings {
+            mode,
+            // Long enough that only the tests flush
+            flush_interval: Duration::from_secs(3600),
+            max_pending,
+        }
+    }
+
+    #[tokio::test]
+    async fn write_behind_writes_should_only_reach_the_store_when_flushed() {
+        let users = BufferedUsers::new(
+            InMemoryUsers::default(),
+            settings(BufferMode::WriteBehind, 100),
+            Arc::new(Metrics::default()),
+        );
+        let user = User::from("james@test.com", "James", "hashed");
+
+        users.store(user.clone()).await.unwrap();
+        let mut renamed = user.clone();
+        renamed.update_name("Renamed");
+        let version = users.update(renamed, Some(1)).await.unwrap();
+
+        assert_eq!(version, 2);
+        assert_eq!(users.with_email_address("james@test.com").await.unwrap().name(), "Renamed");
+        assert!(users.backing.store.with_email_address("james@test.com").await.is_err());
+
+        users.flush().await;
+
+        let stored = users.backing.store.with_email_address_versioned("james@test.com").await.unwrap();
+        assert_eq!(stored.value.name(), "Renamed");
+        assert_eq!(stored.version, 2);
+    }
+
+    #[tokio::test]
+    async fn a_full_queue_should_be_flushed_by_the_write_that_fills_it() {
+        let metrics = Arc::new(Metrics::default());
+        let users = BufferedUsers::new(
+            InMemoryUsers::default(),
+            settings(BufferMode::WriteBehind, 2),
+            metrics.clone(),
+        );
+
+        users.store(User::from("a@test.com", "A", "hashed")).await.unwrap();
+        assert_eq!(metrics.gauge(USER_WRITE_BEHIND_PENDING, &[]), Some(1.0));
+        users.store(User::from("b@test.com", "B", "hashed")).await.unwrap();
+
+        assert_eq!(metrics.gauge(USER_WRITE_BEHIND_PENDING, &[]), Some(0.0));
+        assert_eq!(users.backing.store.list(0, 10).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn write_through_writes_should_reach_the_store_and_not_be_read_stale() {
+        let store = InMemoryUsers::default();
+        store.store(User::from("james@test.com", "James", "hashed")).await.unwrap();
+        let users = BufferedUsers::new(
+            store,
+            settings(BufferMode::WriteThrough, 100),
+            Arc::new(Metrics::default()),
+        );
+
+        let read = users.with_email_address_versioned("james@test.com").await.unwrap();
+        let mut renamed = read.value.clone();
+        renamed.update_name("Renamed");
+        users.update(renamed, Some(read.version)).await.unwrap();
+
+        assert_eq!(users.backing.store.with_email_address("james@test.com").await.unwrap().name(), "Renamed");
+        let reread = users.with_email_address_versioned("james@test.com").await.unwrap();
+        assert_eq!(reread.value.name(), "Renamed");
+        assert_eq!(reread.version, 2);
+    }
+}
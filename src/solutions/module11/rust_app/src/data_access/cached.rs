@@ -0,0 +1,516 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use time::format_description::well_known::Iso8601;
+use time::Date;
+use uuid::Uuid;
+
+use crate::core::{
+    ApplicationError, Config, DataAccess, EmailAlias, LastLogin, Metadata, User, UserPreferences,
+    Versioned,
+};
+use crate::metrics::{Metrics, USER_CACHE_HITS_TOTAL, USER_CACHE_MISSES_TOTAL};
+use super::audit::AuditEntry;
+use super::outbox::OutboxEntry;
+use super::rows::UserRow;
+use super::user_cache::UserCache;
+use super::UnitOfWork;
+
+// A user as they are cached, the row's columns with the id and the date of birth as strings
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedUser {
+    id: String,
+    email_address: String,
+    name: String,
+    password: String,
+    date_of_birth: Option<String>,
+    username: Option<String>,
+    phone_number: Option<String>,
+    metadata: Metadata,
+    role: String,
+    tier: Option<String>,
+}
+
+fn cached_json(user: &User) -> Result<String, ApplicationError> {
+    let row = UserRow::from(user);
+    let date_of_birth = row
+        .date_of_birth
+        .map(|date| date.format(&Iso8601::DATE))
+        .transpose()
+        .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+    let cached = CachedUser {
+        id: row.id.to_string(),
+        email_address: row.email_address,
+        name: row.name,
+        password: row.password,
+        date_of_birth,
+        username: row.username,
+        phone_number: row.phone_number,
+        metadata: row.metadata.0,
+        role: row.role,
+        tier: row.tier,
+    };
+
+    serde_json::to_string(&cached).map_err(|e| ApplicationError::ApplicationError(e.to_string()))
+}
+
+// `None` for anything this build can't read, e.g. written by an older one, which is then read
+// from the store again
+fn cached_user(json: &str) -> Option<User> {
+    let cached: CachedUser = serde_json::from_str(json).ok()?;
+    let date_of_birth = cached
+        .date_of_birth
+        .map(|date| Date::parse(&date, &Iso8601::DATE))
+        .transpose()
+        .ok()?;
+
+    Some(
+        UserRow {
+            id: Uuid::parse_str(&cached.id).ok()?,
+            email_address: cached.email_address,
+            name: cached.name,
+            password: cached.password,
+            date_of_birth,
+            username: cached.username,
+            phone_number: cached.phone_number,
+            metadata: Json(cached.metadata),
+            role: cached.role,
+            tier: cached.tier,
+            version: 0,
+        }
+        .into(),
+    )
+}
+
+#[derive(Clone, Debug)]
+pub struct CacheSettings {
+    pub ttl: Duration,
+}
+
+impl CacheSettings {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.user_cache_ttl_seconds()),
+        }
+    }
+}
+
+// Serves `with_email_address` from a cache in front of another store, cache aside. A user that
+// isn't cached is read from the store and cached for `ttl`, every write removes the users it
+// changes once it has been made. A read that raced a write can still cache what it read before
+// it, so `ttl` bounds how stale a user can be, as it does for writes made without going through
+// the cache, e.g. by maintenance commands. The cache failing doesn't fail the read or the write,
+// they go to the store and the error is logged.
+pub struct CachedDataAccess<TDataAccess: DataAccess> {
+    inner: TDataAccess,
+    cache: Arc<dyn UserCache>,
+    settings: CacheSettings,
+    metrics: Arc<Metrics>,
+}
+
+impl<TDataAccess: DataAccess> CachedDataAccess<TDataAccess> {
+    pub fn new(
+        inner: TDataAccess,
+        cache: Arc<dyn UserCache>,
+        settings: CacheSettings,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            inner,
+            cache,
+            settings,
+            metrics,
+        }
+    }
+
+    async fn invalidate(&self, email_addresses: &[&str]) {
+        invalidate(self.cache.as_ref(), email_addresses).await;
+    }
+}
+
+async fn invalidate(cache: &dyn UserCache, email_addresses: &[&str]) {
+    if let Err(e) = cache.remove(email_addresses).await {
+        log::error!("Failed to remove written users from the cache: {:?}", e);
+    }
+}
+
+#[async_trait::async_trait]
+impl<TDataAccess: DataAccess> DataAccess for CachedDataAccess<TDataAccess> {
+    async fn with_email_address(&self, email_address: &str) -> Result<User, ApplicationError> {
+        match self.cache.get(email_address).await {
+            Ok(Some(json)) => {
+                if let Some(user) = cached_user(&json) {
+                    self.metrics.increment(USER_CACHE_HITS_TOTAL);
+                    return Ok(user);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to read a user from the cache: {:?}", e),
+        }
+        self.metrics.increment(USER_CACHE_MISSES_TOTAL);
+
+        let user = self.inner.with_email_address(email_address).await?;
+        match cached_json(&user) {
+            Ok(json) => {
+                if let Err(e) = self.cache.set(email_address, &json, self.settings.ttl).await {
+                    log::warn!("Failed to cache a user: {:?}", e);
+                }
+            }
+            Err(e) => log::warn!("Failed to cache a user: {:?}", e),
+        }
+        Ok(user)
+    }
+
+    async fn list(&self, offset: i64, limit: i64) -> Result<Vec<User>, ApplicationError> {
+        self.inner.list(offset, limit).await
+    }
+
+    async fn list_after(
+        &self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<User>, ApplicationError> {
+        self.inner.list_after(after, limit).await
+    }
+
+    fn stream_all(&self) -> BoxStream<'_, Result<User, ApplicationError>> {
+        self.inner.stream_all()
+    }
+
+    async fn search_ranked(
+        &self,
+        query: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<(User, f32)>, ApplicationError> {
+        self.inner.search_ranked(query, offset, limit).await
+    }
+
+    async fn count(&self) -> Result<i64, ApplicationError> {
+        self.inner.count().await
+    }
+
+    async fn exists(&self, email_address: &str) -> Result<bool, ApplicationError> {
+        self.inner.exists(email_address).await
+    }
+
+    async fn list_including_deleted(
+        &self,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<(User, Option<u64>)>, ApplicationError> {
+        self.inner.list_including_deleted(offset, limit).await
+    }
+
+    async fn store(&self, user: User) -> Result<(), ApplicationError> {
+        let email_address = user.email_address();
+        self.inner.store(user).await?;
+
+        self.invalidate(&[&email_address]).await;
+        Ok(())
+    }
+
+    async fn store_many(&self, users: Vec<User>) -> Result<(), ApplicationError> {
+        let email_addresses: Vec<String> = users.iter().map(User::email_address).collect();
+        self.inner.store_many(users).await?;
+
+        let written: Vec<&str> = email_addresses.iter().map(String::as_str).collect();
+        self.invalidate(&written).await;
+        Ok(())
+    }
+
+    async fn warm_up(&self, connections: u32) -> Result<(), ApplicationError> {
+        self.inner.warm_up(connections).await
+    }
+
+    // Only the store, reads go to it whenever the cache can't be reached
+    async fn health_check(&self) -> Result<(), ApplicationError> {
+        self.inner.health_check().await
+    }
+
+    // The users a unit of work writes are removed once it commits
+    async fn begin(&self) -> Result<Option<Box<dyn UnitOfWork>>, ApplicationError> {
+        let Some(inner) = self.inner.begin().await? else {
+            return Ok(None);
+        };
+
+        Ok(Some(Box::new(CachedUnitOfWork {
+            inner,
+            cache: self.cache.clone(),
+            written: Vec::new(),
+        })))
+    }
+
+    async fn with_id(&self, id: Uuid) -> Result<User, ApplicationError> {
+        self.inner.with_id(id).await
+    }
+
+    async fn with_email_address_versioned(
+        &self,
+        email_address: &str,
+    ) -> Result<Versioned<User>, ApplicationError> {
+        self.inner.with_email_address_versioned(email_address).await
+    }
+
+    async fn update(
+        &self,
+        user: User,
+        expected_version: Option<i64>,
+    ) -> Result<i64, ApplicationError> {
+        let email_address = user.email_address();
+        let version = self.inner.update(user, expected_version).await?;
+
+        self.invalidate(&[&email_address]).await;
+        Ok(version)
+    }
+
+    async fn upsert(&self, user: User) -> Result<i64, ApplicationError> {
+        let email_address = user.email_address();
+        let version = self.inner.upsert(user).await?;
+
+        self.invalidate(&[&email_address]).await;
+        Ok(version)
+    }
+
+    async fn soft_delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        self.inner.soft_delete(email_address, expected_version).await?;
+
+        self.invalidate(&[email_address]).await;
+        Ok(())
+    }
+
+    async fn delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        self.inner.delete(email_address, expected_version).await?;
+
+        self.invalidate(&[email_address]).await;
+        Ok(())
+    }
+
+    async fn erase(
+        &self,
+        email_address: &str,
+        requested_by: Option<&str>,
+    ) -> Result<Uuid, ApplicationError> {
+        let id = self.inner.erase(email_address, requested_by).await?;
+
+        self.invalidate(&[email_address]).await;
+        Ok(id)
+    }
+
+    async fn merge(
+        &self,
+        kept: &str,
+        merged: &str,
+        requested_by: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        self.inner.merge(kept, merged, requested_by).await?;
+
+        self.invalidate(&[kept, merged]).await;
+        Ok(())
+    }
+
+    // Preferences and logins are kept apart from the user, so they aren't cached with them
+    async fn preferences(&self, email_address: &str) -> Result<UserPreferences, ApplicationError> {
+        self.inner.preferences(email_address).await
+    }
+
+    async fn update_preferences(
+        &self,
+        email_address: &str,
+        preferences: &UserPreferences,
+    ) -> Result<(), ApplicationError> {
+        self.inner.update_preferences(email_address, preferences).await
+    }
+
+    async fn record_login(
+        &self,
+        email_address: &str,
+        logged_in_at: u64,
+        ip: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        self.inner.record_login(email_address, logged_in_at, ip).await
+    }
+
+    async fn last_login(&self, email_address: &str) -> Result<Option<LastLogin>, ApplicationError> {
+        self.inner.last_login(email_address).await
+    }
+
+    async fn email_aliases(&self, email_address: &str) -> Result<Vec<EmailAlias>, ApplicationError> {
+        self.inner.email_aliases(email_address).await
+    }
+
+    async fn add_email_alias(
+        &self,
+        email_address: &str,
+        alias: &str,
+        token_hash: &str,
+    ) -> Result<(), ApplicationError> {
+        self.inner.add_email_alias(email_address, alias, token_hash).await
+    }
+
+    async fn verify_email_alias(
+        &self,
+        token_hash: &str,
+        added_after: u64,
+    ) -> Result<Option<String>, ApplicationError> {
+        self.inner.verify_email_alias(token_hash, added_after).await
+    }
+
+    async fn remove_email_alias(
+        &self,
+        email_address: &str,
+        alias: &str,
+    ) -> Result<(), ApplicationError> {
+        self.inner.remove_email_alias(email_address, alias).await
+    }
+
+    // The user moves from one address to the other
+    async fn set_primary_email_address(
+        &self,
+        email_address: &str,
+        alias: &str,
+    ) -> Result<(), ApplicationError> {
+        self.inner.set_primary_email_address(email_address, alias).await?;
+
+        self.invalidate(&[email_address, alias]).await;
+        Ok(())
+    }
+
+    async fn resolve_email_alias(&self, alias: &str) -> Result<Option<String>, ApplicationError> {
+        self.inner.resolve_email_alias(alias).await
+    }
+
+    async fn set_username(
+        &self,
+        email_address: &str,
+        username: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        self.inner.set_username(email_address, username).await?;
+
+        self.invalidate(&[email_address]).await;
+        Ok(())
+    }
+
+    async fn resolve_username(&self, username: &str) -> Result<Option<String>, ApplicationError> {
+        self.inner.resolve_username(username).await
+    }
+}
+
+// The store's unit of work, remembering whom it wrote so they can be removed from the cache after
+// it commits. Removed any earlier, a read between the two could cache the user as they were.
+struct CachedUnitOfWork {
+    inner: Box<dyn UnitOfWork>,
+    cache: Arc<dyn UserCache>,
+    written: Vec<String>,
+}
+
+#[async_trait::async_trait]
+impl UnitOfWork for CachedUnitOfWork {
+    async fn store(&mut self, user: &User) -> Result<(), ApplicationError> {
+        self.inner.store(user).await?;
+        self.written.push(user.email_address());
+        Ok(())
+    }
+
+    async fn update(
+        &mut self,
+        user: &User,
+        expected_version: Option<i64>,
+    ) -> Result<i64, ApplicationError> {
+        let version = self.inner.update(user, expected_version).await?;
+        self.written.push(user.email_address());
+        Ok(version)
+    }
+
+    async fn enqueue(&mut self, entry: &OutboxEntry) -> Result<(), ApplicationError> {
+        self.inner.enqueue(entry).await
+    }
+
+    async fn record(&mut self, entry: &AuditEntry) -> Result<(), ApplicationError> {
+        self.inner.record(entry).await
+    }
+
+    async fn commit(self: Box<Self>) -> Result<(), ApplicationError> {
+        self.inner.commit().await?;
+
+        let written: Vec<&str> = self.written.iter().map(String::as_str).collect();
+        invalidate(self.cache.as_ref(), &written).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Tier;
+    use crate::data_access::{InMemoryUserCache, InMemoryUsers};
+
+    fn cached(inner: InMemoryUsers) -> (CachedDataAccess<InMemoryUsers>, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::default());
+        let settings = CacheSettings {
+            ttl: Duration::from_secs(60),
+        };
+        let cache = CachedDataAccess::new(
+            inner,
+            Arc::new(InMemoryUserCache::default()),
+            settings,
+            metrics.clone(),
+        );
+        (cache, metrics)
+    }
+
+    #[test]
+    fn a_user_should_read_back_from_the_cache_as_they_were_written() {
+        let user = User::builder("james@test.com", "James")
+            .hashed_password("hashed")
+            .date_of_birth(Some(Date::from_calendar_date(1990, time::Month::June, 15).unwrap()))
+            .tier(Some(Tier::Gold))
+            .build()
+            .unwrap();
+
+        let read = cached_user(&cached_json(&user).unwrap()).unwrap();
+
+        assert_eq!(UserRow::from(&read), UserRow::from(&user));
+    }
+
+    #[tokio::test]
+    async fn a_second_read_should_be_served_from_the_cache() {
+        let (users, metrics) = cached(InMemoryUsers::default());
+        users.store(User::from("james@test.com", "James", "hashed")).await.unwrap();
+
+        users.with_email_address("james@test.com").await.unwrap();
+        users.with_email_address("james@test.com").await.unwrap();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.counter(USER_CACHE_MISSES_TOTAL), 1);
+        assert_eq!(snapshot.counter(USER_CACHE_HITS_TOTAL), 1);
+    }
+
+    #[tokio::test]
+    async fn an_update_should_remove_the_user_from_the_cache() {
+        let (users, _) = cached(InMemoryUsers::default());
+        users.store(User::from("james@test.com", "James", "hashed")).await.unwrap();
+        let read = users.with_email_address("james@test.com").await.unwrap();
+        let renamed = User::builder("james@test.com", "Jim")
+            .id(read.id())
+            .hashed_password("hashed")
+            .build()
+            .unwrap();
+
+        users.update(renamed, None).await.unwrap();
+
+        assert_eq!(users.with_email_address("james@test.com").await.unwrap().name(), "Jim");
+    }
+}
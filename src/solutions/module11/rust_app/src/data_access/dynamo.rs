@@ -0,0 +1,703 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aws_config::BehaviorVersion;
+use aws_sdk_dynamodb::client::Waiters;
+use aws_sdk_dynamodb::config::Region;
+use aws_sdk_dynamodb::error::{DisplayErrorContext, SdkError};
+use aws_sdk_dynamodb::types::{
+    AttributeDefinition, AttributeValue, BillingMode, KeySchemaElement, KeyType, ReturnValue,
+    ScalarAttributeType,
+};
+use aws_sdk_dynamodb::Client;
+use sqlx::types::Json;
+use time::format_description::well_known::Iso8601;
+use time::Date;
+use uuid::Uuid;
+
+use crate::core::{
+    ApplicationError, Config, DataAccess, LastLogin, User, UserPreferences, Versioned,
+};
+use super::rows::UserRow;
+
+type Item = HashMap<String, AttributeValue>;
+
+// The table's key, each user is one item
+const EMAIL_ADDRESS: &str = "email_address";
+const DELETED_AT: &str = "deleted_at";
+
+// How long a table created at startup may take to become active
+const TABLE_CREATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn dynamo_error<E, R>(e: SdkError<E, R>) -> ApplicationError
+where
+    E: std::error::Error + 'static,
+    R: std::fmt::Debug,
+{
+    ApplicationError::DatabaseError(DisplayErrorContext(&e).to_string())
+}
+
+fn malformed(attribute: &str) -> ApplicationError {
+    ApplicationError::DatabaseError(format!("a user item has a missing or malformed {}", attribute))
+}
+
+fn optional_string(item: &Item, attribute: &str) -> Option<String> {
+    item.get(attribute).and_then(|value| value.as_s().ok()).cloned()
+}
+
+fn string(item: &Item, attribute: &str) -> Result<String, ApplicationError> {
+    optional_string(item, attribute).ok_or_else(|| malformed(attribute))
+}
+
+fn number(item: &Item, attribute: &str) -> Option<i64> {
+    item.get(attribute)
+        .and_then(|value| value.as_n().ok())
+        .and_then(|number| number.parse().ok())
+}
+
+fn is_live(item: &Item) -> bool {
+    !item.contains_key(DELETED_AT)
+}
+
+// Everything `update` writes, `None` where the user has nothing and the attribute is removed. The
+// username is only written by `set_username`, as in Postgres.
+fn updated_attributes(
+    row: &UserRow,
+) -> Result<Vec<(&'static str, Option<AttributeValue>)>, ApplicationError> {
+    let metadata = serde_json::to_string(&row.metadata.0)
+        .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+    let date_of_birth = row
+        .date_of_birth
+        .map(|date| date.format(&Iso8601::DATE))
+        .transpose()
+        .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+
+    Ok(vec![
+        ("name", Some(AttributeValue::S(row.name.clone()))),
+        ("password", Some(AttributeValue::S(row.password.clone()))),
+        ("date_of_birth", date_of_birth.map(AttributeValue::S)),
+        ("phone_number", row.phone_number.clone().map(AttributeValue::S)),
+        ("metadata", Some(AttributeValue::S(metadata))),
+        ("role", Some(AttributeValue::S(row.role.clone()))),
+        ("tier", row.tier.clone().map(AttributeValue::S)),
+    ])
+}
+
+fn user_item(row: &UserRow) -> Result<Item, ApplicationError> {
+    let mut item = Item::from([
+        (EMAIL_ADDRESS.to_string(), AttributeValue::S(row.email_address.clone())),
+        ("id".to_string(), AttributeValue::S(row.id.to_string())),
+        ("version".to_string(), AttributeValue::N(row.version.to_string())),
+    ]);
+    if let Some(username) = &row.username {
+        item.insert("username".to_string(), AttributeValue::S(username.clone()));
+    }
+    for (attribute, value) in updated_attributes(row)? {
+        if let Some(value) = value {
+            item.insert(attribute.to_string(), value);
+        }
+    }
+
+    Ok(item)
+}
+
+fn user_row(item: &Item) -> Result<UserRow, ApplicationError> {
+    let date_of_birth = optional_string(item, "date_of_birth")
+        .map(|date| Date::parse(&date, &Iso8601::DATE))
+        .transpose()
+        .map_err(|_| malformed("date_of_birth"))?;
+    let metadata = optional_string(item, "metadata")
+        .map(|metadata| serde_json::from_str(&metadata))
+        .transpose()
+        .map_err(|_| malformed("metadata"))?
+        .unwrap_or_default();
+
+    Ok(UserRow {
+        id: Uuid::parse_str(&string(item, "id")?).map_err(|_| malformed("id"))?,
+        email_address: string(item, EMAIL_ADDRESS)?,
+        name: string(item, "name")?,
+        password: string(item, "password")?,
+        date_of_birth,
+        username: optional_string(item, "username"),
+        phone_number: optional_string(item, "phone_number"),
+        metadata: Json(metadata),
+        role: string(item, "role")?,
+        tier: optional_string(item, "tier"),
+        version: number(item, "version").ok_or_else(|| malformed("version"))?,
+    })
+}
+
+// Every attribute an expression mentions goes in as `#attribute`, so none of them clash with
+// DynamoDB's reserved words, e.g. `name`. DynamoDB rejects names and values that aren't used, so
+// only the ones asked for are sent.
+#[derive(Default)]
+struct Expression {
+    names: HashMap<String, String>,
+    values: HashMap<String, AttributeValue>,
+}
+
+impl Expression {
+    fn name(&mut self, attribute: &str) -> String {
+        let name = format!("#{}", attribute);
+        self.names.insert(name.clone(), attribute.to_string());
+        name
+    }
+
+    fn value(&mut self, placeholder: &str, value: AttributeValue) -> String {
+        let placeholder = format!(":{}", placeholder);
+        self.values.insert(placeholder.clone(), value);
+        placeholder
+    }
+
+    // The user exists, soft deleted or not, at `expected_version` when there is one
+    fn exists(&mut self, expected_version: Option<i64>) -> String {
+        let mut condition = format!("attribute_exists({})", self.name(EMAIL_ADDRESS));
+        if let Some(expected_version) = expected_version {
+            let version = self.name("version");
+            let expected =
+                self.value("expected_version", AttributeValue::N(expected_version.to_string()));
+            condition.push_str(&format!(" AND {} = {}", version, expected));
+        }
+        condition
+    }
+
+    // The user exists and isn't soft deleted, at `expected_version` when there is one
+    fn live(&mut self, expected_version: Option<i64>) -> String {
+        let exists = self.exists(expected_version);
+        format!("{} AND attribute_not_exists({})", exists, self.name(DELETED_AT))
+    }
+
+    fn values(&mut self) -> Option<HashMap<String, AttributeValue>> {
+        let values = std::mem::take(&mut self.values);
+        (!values.is_empty()).then_some(values)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+// Users kept in a DynamoDB table keyed by email address, to show the API running on another store
+// behind the same trait. Reads are strongly consistent, so a user is found straight after they
+// register, and versioned writes are conditional on the version like Postgres' `UPDATE ... WHERE`.
+// Lookups by id and listing scan the table, which is fine for a workshop's users but would want
+// an index on `id` and a paged query beyond that. Aliases, usernames, merges and erasure aren't
+// supported.
+pub struct DynamoUsers {
+    client: Client,
+    table_name: String,
+}
+
+impl DynamoUsers {
+    pub fn new(client: Client, table_name: String) -> Self {
+        Self { client, table_name }
+    }
+
+    // The table is created when it doesn't exist yet, as the Postgres tables are by the
+    // migrations
+    pub async fn from_config(config: &Config) -> Result<Self, ApplicationError> {
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(region) = config.dynamodb_region() {
+            loader = loader.region(Region::new(region));
+        }
+        if let Some(endpoint_url) = config.dynamodb_endpoint_url() {
+            loader = loader.endpoint_url(endpoint_url);
+        }
+        let users = Self::new(Client::new(&loader.load().await), config.dynamodb_table_name());
+        users.create_table_if_missing().await?;
+
+        Ok(users)
+    }
+
+    async fn create_table_if_missing(&self) -> Result<(), ApplicationError> {
+        match self.client.describe_table().table_name(&self.table_name).send().await {
+            Ok(_) => return Ok(()),
+            Err(e) if e
+                .as_service_error()
+                .is_some_and(|e| e.is_resource_not_found_exception()) => {}
+            Err(e) => return Err(dynamo_error(e)),
+        }
+        log::info!("Creating the DynamoDB table {}", self.table_name);
+
+        let key = KeySchemaElement::builder()
+            .attribute_name(EMAIL_ADDRESS)
+            .key_type(KeyType::Hash)
+            .build()
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+        let definition = AttributeDefinition::builder()
+            .attribute_name(EMAIL_ADDRESS)
+            .attribute_type(ScalarAttributeType::S)
+            .build()
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+        self.client
+            .create_table()
+            .table_name(&self.table_name)
+            .key_schema(key)
+            .attribute_definitions(definition)
+            .billing_mode(BillingMode::PayPerRequest)
+            .send()
+            .await
+            .map_err(dynamo_error)?;
+
+        self.client
+            .wait_until_table_exists()
+            .table_name(&self.table_name)
+            .wait(TABLE_CREATION_TIMEOUT)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(DisplayErrorContext(&e).to_string()))?;
+
+        Ok(())
+    }
+
+    fn key(email_address: &str) -> (String, AttributeValue) {
+        (EMAIL_ADDRESS.to_string(), AttributeValue::S(email_address.to_string()))
+    }
+
+    async fn live_item(&self, email_address: &str) -> Result<Item, ApplicationError> {
+        let (key, value) = Self::key(email_address);
+        let output = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key(key, value)
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(dynamo_error)?;
+
+        output
+            .item
+            .filter(is_live)
+            .ok_or(ApplicationError::UserDoesNotExist)
+    }
+
+    async fn live_rows(&self) -> Result<Vec<UserRow>, ApplicationError> {
+        let items: Vec<Item> = self
+            .client
+            .scan()
+            .table_name(&self.table_name)
+            .consistent_read(true)
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<Result<_, _>>()
+            .await
+            .map_err(dynamo_error)?;
+
+        items.iter().filter(|item| is_live(item)).map(user_row).collect()
+    }
+
+    // A conditional write that failed either found no user or a different version
+    async fn missing_or_changed(&self, email_address: &str) -> ApplicationError {
+        match self.live_item(email_address).await {
+            Ok(_) => ApplicationError::VersionMismatch,
+            Err(e) => e,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl DataAccess for DynamoUsers {
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "with_email_address"))]
+    async fn with_email_address(&self, email_address: &str) -> Result<User, ApplicationError> {
+        let item = self.live_item(email_address).await?;
+
+        Ok(user_row(&item)?.into())
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "with_id"))]
+    async fn with_id(&self, id: Uuid) -> Result<User, ApplicationError> {
+        self.live_rows()
+            .await?
+            .into_iter()
+            .find(|row| row.id == id)
+            .map(Into::into)
+            .ok_or(ApplicationError::UserDoesNotExist)
+    }
+
+    // By email address like the Postgres store, a scan comes back in no particular order
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "list"))]
+    async fn list(&self, offset: i64, limit: i64) -> Result<Vec<User>, ApplicationError> {
+        let mut rows = self.live_rows().await?;
+        rows.sort_by(|a, b| a.email_address.cmp(&b.email_address));
+
+        Ok(rows
+            .into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .map(Into::into)
+            .collect())
+    }
+
+    // A soft deleted user still holds their address, as in Postgres
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "store"))]
+    async fn store(&self, user: User) -> Result<(), ApplicationError> {
+        let row = UserRow {
+            version: 1,
+            ..UserRow::from(&user)
+        };
+        let mut expression = Expression::default();
+        let condition = format!("attribute_not_exists({})", expression.name(EMAIL_ADDRESS));
+
+        let stored = self
+            .client
+            .put_item()
+            .table_name(&self.table_name)
+            .set_item(Some(user_item(&row)?))
+            .condition_expression(condition)
+            .set_expression_attribute_names(Some(expression.names))
+            .send()
+            .await;
+
+        match stored {
+            Ok(_) => Ok(()),
+            Err(e) if e
+                .as_service_error()
+                .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Err(ApplicationError::UserAlreadyExists)
+            }
+            Err(e) => Err(dynamo_error(e)),
+        }
+    }
+
+    // A scan like `list`, the table keeps no count of its live users
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "count"))]
+    async fn count(&self) -> Result<i64, ApplicationError> {
+        Ok(self.live_rows().await?.len() as i64)
+    }
+
+    // Describing the table is the cheapest call that needs both the credentials and the table
+    async fn health_check(&self) -> Result<(), ApplicationError> {
+        self.client
+            .describe_table()
+            .table_name(&self.table_name)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(dynamo_error)
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "with_email_address_versioned"))]
+    async fn with_email_address_versioned(
+        &self,
+        email_address: &str,
+    ) -> Result<Versioned<User>, ApplicationError> {
+        let item = self.live_item(email_address).await?;
+
+        Ok(user_row(&item)?.into())
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "update"))]
+    async fn update(
+        &self,
+        user: User,
+        expected_version: Option<i64>,
+    ) -> Result<i64, ApplicationError> {
+        let row = UserRow::from(&user);
+        let mut expression = Expression::default();
+        let version = expression.name("version");
+        let mut set = vec![format!(
+            "{} = {} + {}",
+            version,
+            version,
+            expression.value("one", AttributeValue::N("1".to_string()))
+        )];
+        let mut remove = Vec::new();
+        for (attribute, value) in updated_attributes(&row)? {
+            let name = expression.name(attribute);
+            match value {
+                Some(value) => {
+                    set.push(format!("{} = {}", name, expression.value(attribute, value)))
+                }
+                None => remove.push(name),
+            }
+        }
+        let mut update = format!("SET {}", set.join(", "));
+        if !remove.is_empty() {
+            update.push_str(&format!(" REMOVE {}", remove.join(", ")));
+        }
+        let condition = format!(
+            "{} AND {} = {}",
+            expression.live(expected_version),
+            expression.name("id"),
+            expression.value("id", AttributeValue::S(row.id.to_string()))
+        );
+
+        let (key, value) = Self::key(&row.email_address);
+        let updated = self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .key(key, value)
+            .update_expression(update)
+            .condition_expression(condition)
+            .set_expression_attribute_names(Some(expression.names.clone()))
+            .set_expression_attribute_values(expression.values())
+            .return_values(ReturnValue::UpdatedNew)
+            .send()
+            .await;
+
+        match updated {
+            Ok(output) => output
+                .attributes
+                .as_ref()
+                .and_then(|attributes| number(attributes, "version"))
+                .ok_or_else(|| malformed("version")),
+            Err(e) if e
+                .as_service_error()
+                .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Err(self.missing_or_changed(&row.email_address).await)
+            }
+            Err(e) => Err(dynamo_error(e)),
+        }
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "soft_delete"))]
+    async fn soft_delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        let mut expression = Expression::default();
+        let condition = expression.live(expected_version);
+        let version = expression.name("version");
+        let update = format!(
+            "SET {} = {}, {} = {} + {}",
+            expression.name(DELETED_AT),
+            expression.value("deleted_at", AttributeValue::N(now().to_string())),
+            version,
+            version,
+            expression.value("one", AttributeValue::N("1".to_string()))
+        );
+
+        let (key, value) = Self::key(email_address);
+        let deleted = self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .key(key, value)
+            .update_expression(update)
+            .condition_expression(condition)
+            .set_expression_attribute_names(Some(expression.names.clone()))
+            .set_expression_attribute_values(expression.values())
+            .send()
+            .await;
+
+        match deleted {
+            Ok(_) => Ok(()),
+            Err(e) if e
+                .as_service_error()
+                .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Err(self.missing_or_changed(email_address).await)
+            }
+            Err(e) => Err(dynamo_error(e)),
+        }
+    }
+
+    // Also removes a user that was soft deleted
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "delete"))]
+    async fn delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        let mut expression = Expression::default();
+        let condition = expression.exists(expected_version);
+
+        let (key, value) = Self::key(email_address);
+        let deleted = self
+            .client
+            .delete_item()
+            .table_name(&self.table_name)
+            .key(key, value)
+            .condition_expression(condition)
+            .set_expression_attribute_names(Some(expression.names.clone()))
+            .set_expression_attribute_values(expression.values())
+            .send()
+            .await;
+
+        match deleted {
+            Ok(_) => Ok(()),
+            Err(e) if e
+                .as_service_error()
+                .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                // Unlike the other writes a soft deleted user is still found here
+                let (key, value) = Self::key(email_address);
+                let found = self
+                    .client
+                    .get_item()
+                    .table_name(&self.table_name)
+                    .key(key, value)
+                    .consistent_read(true)
+                    .send()
+                    .await
+                    .map_err(dynamo_error)?;
+                match found.item {
+                    Some(_) => Err(ApplicationError::VersionMismatch),
+                    None => Err(ApplicationError::UserDoesNotExist),
+                }
+            }
+            Err(e) => Err(dynamo_error(e)),
+        }
+    }
+
+    // Kept on the user's item, outside the attributes `update` writes, so neither changes the
+    // version
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "preferences"))]
+    async fn preferences(&self, email_address: &str) -> Result<UserPreferences, ApplicationError> {
+        let item = self.live_item(email_address).await?;
+
+        optional_string(&item, "preferences")
+            .map(|preferences| serde_json::from_str(&preferences))
+            .transpose()
+            .map_err(|_| malformed("preferences"))
+            .map(Option::unwrap_or_default)
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "update_preferences"))]
+    async fn update_preferences(
+        &self,
+        email_address: &str,
+        preferences: &UserPreferences,
+    ) -> Result<(), ApplicationError> {
+        let preferences = serde_json::to_string(preferences)
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+        let mut expression = Expression::default();
+        let condition = expression.live(None);
+        let update = format!(
+            "SET {} = {}",
+            expression.name("preferences"),
+            expression.value("preferences", AttributeValue::S(preferences))
+        );
+
+        let (key, value) = Self::key(email_address);
+        let updated = self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .key(key, value)
+            .update_expression(update)
+            .condition_expression(condition)
+            .set_expression_attribute_names(Some(expression.names.clone()))
+            .set_expression_attribute_values(expression.values())
+            .send()
+            .await;
+
+        match updated {
+            Ok(_) => Ok(()),
+            Err(e) if e
+                .as_service_error()
+                .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Err(ApplicationError::UserDoesNotExist)
+            }
+            Err(e) => Err(dynamo_error(e)),
+        }
+    }
+
+    // A login that finishes after a later one doesn't take its place, the condition fails and
+    // the later one is kept
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "record_login"))]
+    async fn record_login(
+        &self,
+        email_address: &str,
+        logged_in_at: u64,
+        ip: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        let mut expression = Expression::default();
+        let last_login_at = expression.name("last_login_at");
+        let at = expression.value("logged_in_at", AttributeValue::N(logged_in_at.to_string()));
+        let condition = format!(
+            "{} AND (attribute_not_exists({}) OR {} <= {})",
+            expression.live(None),
+            last_login_at,
+            last_login_at,
+            at
+        );
+        let last_login_ip = expression.name("last_login_ip");
+        let update = match ip {
+            Some(ip) => format!(
+                "SET {} = {}, {} = {}",
+                last_login_at,
+                at,
+                last_login_ip,
+                expression.value("ip", AttributeValue::S(ip.to_string()))
+            ),
+            None => format!("SET {} = {} REMOVE {}", last_login_at, at, last_login_ip),
+        };
+
+        let (key, value) = Self::key(email_address);
+        let recorded = self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .key(key, value)
+            .update_expression(update)
+            .condition_expression(condition)
+            .set_expression_attribute_names(Some(expression.names.clone()))
+            .set_expression_attribute_values(expression.values())
+            .send()
+            .await;
+
+        match recorded {
+            Ok(_) => Ok(()),
+            Err(e) if e
+                .as_service_error()
+                .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                self.live_item(email_address).await.map(|_| ())
+            }
+            Err(e) => Err(dynamo_error(e)),
+        }
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "last_login"))]
+    async fn last_login(&self, email_address: &str) -> Result<Option<LastLogin>, ApplicationError> {
+        let item = self.live_item(email_address).await?;
+
+        Ok(number(&item, "last_login_at").map(|last_login_at| LastLogin {
+            last_login_at: last_login_at as u64,
+            last_login_ip: optional_string(&item, "last_login_ip"),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Tier;
+
+    #[test]
+    fn a_user_should_read_back_from_their_item_as_they_were_written() {
+        let user = User::builder("james@test.com", "James")
+            .hashed_password("hashed")
+            .date_of_birth(Some(Date::from_calendar_date(1990, time::Month::June, 15).unwrap()))
+            .phone_number(Some("+447700900123".to_string()))
+            .tier(Some(Tier::Gold))
+            .build()
+            .unwrap();
+        let row = UserRow::from(&user);
+
+        let item = user_item(&row).unwrap();
+
+        assert_eq!(item["date_of_birth"], AttributeValue::S("1990-06-15".to_string()));
+        assert!(!item.contains_key("username"));
+        assert_eq!(user_row(&item).unwrap(), row);
+    }
+
+    #[test]
+    fn an_item_without_its_required_attributes_should_be_rejected() {
+        let mut item = user_item(&UserRow::from(&User::from("james@test.com", "James", "hashed")))
+            .unwrap();
+        item.remove("name");
+
+        assert!(matches!(user_row(&item), Err(ApplicationError::DatabaseError(_))));
+    }
+}
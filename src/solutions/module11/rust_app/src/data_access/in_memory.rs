@@ -0,0 +1,1065 @@
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use uuid::Uuid;
+
+use crate::core::{
+    ApplicationError, DataAccess, EmailAlias, LastLogin, User, UserPreferences, Versioned,
+};
+use super::rows::UserRow;
+
+struct StoredUser {
+    user: Versioned<User>,
+    preferences: UserPreferences,
+    last_login: Option<LastLogin>,
+    deleted_at: Option<u64>,
+}
+
+impl StoredUser {
+    fn live(&self) -> Option<&Versioned<User>> {
+        self.deleted_at.is_none().then_some(&self.user)
+    }
+}
+
+// By user id, which stays the same when the primary address is swapped
+struct StoredAlias {
+    email_address: String,
+    user_id: Uuid,
+    token_hash: Option<String>,
+    created_at: u64,
+    verified_at: Option<u64>,
+}
+
+// Lowercase words, split where Postgres' simple search configuration splits them
+fn search_terms(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// How well the user matches every word of the query, `None` if one of them is missing. A word of
+// their name counts for more than one of their email address, as the weights of Postgres' `search`
+// column have it.
+fn search_rank(user: &User, words: &[String]) -> Option<f32> {
+    let name = search_terms(&user.name());
+    let email_address = search_terms(&user.email_address());
+
+    words.iter().try_fold(0.0, |rank, word| {
+        if name.contains(word) {
+            Some(rank + 1.0)
+        } else if email_address.contains(word) {
+            Some(rank + 0.4)
+        } else {
+            None
+        }
+    })
+}
+
+// The id of the live user with `email_address`
+fn live_id(users: &BTreeMap<String, StoredUser>, email_address: &str) -> Result<Uuid, ApplicationError> {
+    users
+        .get(email_address)
+        .and_then(StoredUser::live)
+        .map(|stored| stored.value.id())
+        .ok_or(ApplicationError::UserDoesNotExist)
+}
+
+// Users held in the process, for running the API without a database, e.g. the CLI `demo` or with
+// `in_memory.enabled`.
+// Ordered by email address so `list` pages consistently.
+#[derive(Default)]
+pub struct InMemoryUsers {
+    users: RwLock<BTreeMap<String, StoredUser>>,
+    // Always locked after `users`
+    aliases: RwLock<Vec<StoredAlias>>,
+}
+
+// For `BufferedUsers`, which keeps users it read from the database here with their stored version
+impl InMemoryUsers {
+    // Whether the user is held, live or soft deleted
+    pub(crate) fn holds(&self, email_address: &str) -> bool {
+        self.users.read().unwrap().contains_key(email_address)
+    }
+
+    // Unless the user is already held, whose copy may have writes the one read doesn't
+    pub(crate) fn load(&self, user: Versioned<User>, preferences: UserPreferences) {
+        self.users
+            .write()
+            .unwrap()
+            .entry(user.value.email_address())
+            .or_insert(StoredUser {
+                user,
+                preferences,
+                last_login: None,
+                deleted_at: None,
+            });
+    }
+
+    pub(crate) fn evict(&self, email_address: &str) {
+        self.users.write().unwrap().remove(email_address);
+    }
+}
+
+fn check_version(
+    stored: Option<&Versioned<User>>,
+    expected_version: Option<i64>,
+) -> Result<i64, ApplicationError> {
+    let stored = stored.ok_or(ApplicationError::UserDoesNotExist)?;
+
+    match expected_version {
+        Some(expected) if expected != stored.version => Err(ApplicationError::VersionMismatch),
+        _ => Ok(stored.version),
+    }
+}
+
+#[async_trait::async_trait]
+impl DataAccess for InMemoryUsers {
+    async fn with_email_address(&self, email_address: &str) -> Result<User, ApplicationError> {
+        self.users
+            .read()
+            .unwrap()
+            .get(email_address)
+            .and_then(StoredUser::live)
+            .map(|stored| stored.value.clone())
+            .ok_or(ApplicationError::UserDoesNotExist)
+    }
+
+    async fn with_id(&self, id: Uuid) -> Result<User, ApplicationError> {
+        self.users
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(StoredUser::live)
+            .find(|stored| stored.value.id() == id)
+            .map(|stored| stored.value.clone())
+            .ok_or(ApplicationError::UserDoesNotExist)
+    }
+
+    // By email address like the Postgres store, so pages don't overlap
+    async fn list(&self, offset: i64, limit: i64) -> Result<Vec<User>, ApplicationError> {
+        let users = self.users.read().unwrap();
+        let mut live: Vec<&Versioned<User>> = users.values().filter_map(StoredUser::live).collect();
+        live.sort_by_key(|stored| stored.value.email_address());
+
+        Ok(live
+            .into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .map(|stored| stored.value.clone())
+            .collect())
+    }
+
+    async fn list_including_deleted(
+        &self,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<(User, Option<u64>)>, ApplicationError> {
+        Ok(self
+            .users
+            .read()
+            .unwrap()
+            .values()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .map(|stored| (stored.user.value.clone(), stored.deleted_at))
+            .collect())
+    }
+
+    // Every word has to be one of the user's, as with Postgres, without its quoting or operators.
+    // Ties stay in email address order.
+    async fn search_ranked(
+        &self,
+        query: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<(User, f32)>, ApplicationError> {
+        let words = search_terms(query);
+        if words.is_empty() {
+            return Ok(Vec::new());
+        }
+        let users = self.users.read().unwrap();
+        let mut found: Vec<(User, f32)> = users
+            .values()
+            .filter_map(StoredUser::live)
+            .filter_map(|stored| {
+                search_rank(&stored.value, &words).map(|rank| (stored.value.clone(), rank))
+            })
+            .collect();
+        found.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        Ok(found
+            .into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .collect())
+    }
+
+    async fn count(&self) -> Result<i64, ApplicationError> {
+        let users = self.users.read().unwrap();
+
+        Ok(users.values().filter_map(StoredUser::live).count() as i64)
+    }
+
+    async fn exists(&self, email_address: &str) -> Result<bool, ApplicationError> {
+        let users = self.users.read().unwrap();
+
+        Ok(users.get(email_address).and_then(StoredUser::live).is_some())
+    }
+
+    async fn list_after(
+        &self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<User>, ApplicationError> {
+        let users = self.users.read().unwrap();
+        let mut live: Vec<&Versioned<User>> = users
+            .values()
+            .filter_map(StoredUser::live)
+            .filter(|stored| after.is_none_or(|after| stored.value.email_address().as_str() > after))
+            .collect();
+        live.sort_by_key(|stored| stored.value.email_address());
+
+        Ok(live
+            .into_iter()
+            .take(limit.max(0) as usize)
+            .map(|stored| stored.value.clone())
+            .collect())
+    }
+
+    async fn store(&self, user: User) -> Result<(), ApplicationError> {
+        let mut users = self.users.write().unwrap();
+        let verified_alias = self.aliases.read().unwrap().iter().any(|alias| {
+            alias.verified_at.is_some() && alias.email_address == user.email_address()
+        });
+
+        if verified_alias || users.contains_key(&user.email_address()) {
+            return Err(ApplicationError::UserAlreadyExists);
+        }
+
+        users.insert(
+            user.email_address(),
+            StoredUser {
+                user: Versioned {
+                    value: user,
+                    version: 1,
+                },
+                preferences: UserPreferences::default(),
+                last_login: None,
+                deleted_at: None,
+            },
+        );
+
+        Ok(())
+    }
+
+    // Checked as a whole under the lock before any of them is stored
+    async fn store_many(&self, users: Vec<User>) -> Result<(), ApplicationError> {
+        let mut stored = self.users.write().unwrap();
+        let aliases = self.aliases.read().unwrap();
+        let mut email_addresses = std::collections::BTreeSet::new();
+        for user in &users {
+            let email_address = user.email_address();
+            let verified_alias = aliases.iter().any(|alias| {
+                alias.verified_at.is_some() && alias.email_address == email_address
+            });
+            if verified_alias
+                || stored.contains_key(&email_address)
+                || !email_addresses.insert(email_address)
+            {
+                return Err(ApplicationError::UserAlreadyExists);
+            }
+        }
+
+        for user in users {
+            stored.insert(
+                user.email_address(),
+                StoredUser {
+                    user: Versioned {
+                        value: user,
+                        version: 1,
+                    },
+                    preferences: UserPreferences::default(),
+                    last_login: None,
+                    deleted_at: None,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn with_email_address_versioned(
+        &self,
+        email_address: &str,
+    ) -> Result<Versioned<User>, ApplicationError> {
+        self.users
+            .read()
+            .unwrap()
+            .get(email_address)
+            .and_then(StoredUser::live)
+            .cloned()
+            .ok_or(ApplicationError::UserDoesNotExist)
+    }
+
+    async fn update(
+        &self,
+        user: User,
+        expected_version: Option<i64>,
+    ) -> Result<i64, ApplicationError> {
+        let mut users = self.users.write().unwrap();
+        let stored = users.get(&user.email_address()).and_then(StoredUser::live);
+        let version = check_version(stored, expected_version)? + 1;
+
+        // The username is only written by `set_username`, as in Postgres
+        if let Some(stored) = users.get_mut(&user.email_address()) {
+            let mut user = user;
+            user.update_username(stored.user.value.username().as_deref());
+            stored.user = Versioned {
+                value: user,
+                version,
+            };
+        }
+
+        Ok(version)
+    }
+
+    async fn upsert(&self, user: User) -> Result<i64, ApplicationError> {
+        let mut users = self.users.write().unwrap();
+        let Some(stored) = users.get_mut(&user.email_address()) else {
+            let verified_alias = self.aliases.read().unwrap().iter().any(|alias| {
+                alias.verified_at.is_some() && alias.email_address == user.email_address()
+            });
+            if verified_alias {
+                return Err(ApplicationError::UserAlreadyExists);
+            }
+
+            users.insert(
+                user.email_address(),
+                StoredUser {
+                    user: Versioned {
+                        value: user,
+                        version: 1,
+                    },
+                    preferences: UserPreferences::default(),
+                    last_login: None,
+                    deleted_at: None,
+                },
+            );
+            return Ok(1);
+        };
+        if stored.deleted_at.is_some() {
+            return Err(ApplicationError::UserAlreadyExists);
+        }
+
+        // Compared as rows, with the id the upsert never writes taken from the stored user
+        let current = UserRow::from(&stored.user.value);
+        let user = user.with_id(current.id);
+        if UserRow::from(&user) == current {
+            return Ok(stored.user.version);
+        }
+
+        stored.user = Versioned {
+            value: user,
+            version: stored.user.version + 1,
+        };
+        Ok(stored.user.version)
+    }
+
+    async fn soft_delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        let mut users = self.users.write().unwrap();
+        let stored = users.get(email_address).and_then(StoredUser::live);
+        check_version(stored, expected_version)?;
+
+        let deleted_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        if let Some(stored) = users.get_mut(email_address) {
+            stored.user.version += 1;
+            stored.deleted_at = Some(deleted_at);
+        }
+
+        Ok(())
+    }
+
+    async fn delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        let mut users = self.users.write().unwrap();
+        check_version(users.get(email_address).map(|stored| &stored.user), expected_version)?;
+
+        if let Some(stored) = users.remove(email_address) {
+            let id = stored.user.value.id();
+            self.aliases.write().unwrap().retain(|alias| alias.user_id != id);
+        }
+
+        Ok(())
+    }
+
+    // Kept under the placeholder address, which frees the original one to register again
+    async fn erase(
+        &self,
+        email_address: &str,
+        _requested_by: Option<&str>,
+    ) -> Result<Uuid, ApplicationError> {
+        let mut users = self.users.write().unwrap();
+        let stored = users
+            .remove(email_address)
+            .ok_or(ApplicationError::UserDoesNotExist)?;
+
+        let erased: User = UserRow::from(&stored.user.value).erased().into();
+        let id = erased.id();
+        self.aliases.write().unwrap().retain(|alias| alias.user_id != id);
+        let deleted_at = stored.deleted_at.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0)
+        });
+
+        users.insert(
+            erased.email_address(),
+            StoredUser {
+                user: Versioned {
+                    value: erased,
+                    version: stored.user.version + 1,
+                },
+                preferences: UserPreferences::default(),
+                last_login: None,
+                deleted_at: Some(deleted_at),
+            },
+        );
+
+        Ok(id)
+    }
+
+    // The same fold as the database's, nothing records it
+    async fn merge(
+        &self,
+        kept: &str,
+        merged: &str,
+        _requested_by: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        let mut users = self.users.write().unwrap();
+        let kept_id = live_id(&users, kept)?;
+        let merged_id = live_id(&users, merged)?;
+        let merged_user = users.get(merged).ok_or(ApplicationError::UserDoesNotExist)?;
+        let merged_row = UserRow::from(&merged_user.user.value);
+        let merged_preferences = merged_user.preferences.clone();
+        let merged_login = merged_user.last_login.clone();
+        let now = crate::outbox::now();
+
+        let stored = users.get_mut(kept).ok_or(ApplicationError::UserDoesNotExist)?;
+        let row = UserRow::from(&stored.user.value).merged_with(&merged_row)?;
+        stored.user = Versioned {
+            value: row.into(),
+            version: stored.user.version + 1,
+        };
+        stored.preferences = stored.preferences.clone().merged_with(merged_preferences);
+        stored.last_login = [stored.last_login.take(), merged_login]
+            .into_iter()
+            .flatten()
+            .max_by_key(|login| login.last_login_at);
+
+        if let Some(stored) = users.get_mut(merged) {
+            stored.user.version += 1;
+            stored.deleted_at = Some(now);
+        }
+
+        // Unverified claims are dropped, so are the kept user's claims on addresses that are about
+        // to be theirs
+        let mut aliases = self.aliases.write().unwrap();
+        aliases.retain(|alias| alias.user_id != merged_id || alias.verified_at.is_some());
+        let moving: Vec<String> = aliases
+            .iter()
+            .filter(|alias| alias.user_id == merged_id)
+            .map(|alias| alias.email_address.clone())
+            .chain([merged.to_string()])
+            .collect();
+        aliases.retain(|alias| alias.user_id != kept_id || !moving.contains(&alias.email_address));
+        for alias in aliases.iter_mut().filter(|alias| alias.user_id == merged_id) {
+            alias.user_id = kept_id;
+        }
+        aliases.push(StoredAlias {
+            email_address: merged.to_string(),
+            user_id: kept_id,
+            token_hash: None,
+            created_at: now,
+            verified_at: Some(now),
+        });
+
+        Ok(())
+    }
+
+    async fn preferences(&self, email_address: &str) -> Result<UserPreferences, ApplicationError> {
+        self.users
+            .read()
+            .unwrap()
+            .get(email_address)
+            .filter(|stored| stored.live().is_some())
+            .map(|stored| stored.preferences.clone())
+            .ok_or(ApplicationError::UserDoesNotExist)
+    }
+
+    async fn update_preferences(
+        &self,
+        email_address: &str,
+        preferences: &UserPreferences,
+    ) -> Result<(), ApplicationError> {
+        let mut users = self.users.write().unwrap();
+        let stored = users
+            .get_mut(email_address)
+            .filter(|stored| stored.live().is_some())
+            .ok_or(ApplicationError::UserDoesNotExist)?;
+
+        stored.preferences = preferences.clone();
+
+        Ok(())
+    }
+
+    async fn record_login(
+        &self,
+        email_address: &str,
+        logged_in_at: u64,
+        ip: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        let mut users = self.users.write().unwrap();
+        let stored = users
+            .get_mut(email_address)
+            .filter(|stored| stored.live().is_some())
+            .ok_or(ApplicationError::UserDoesNotExist)?;
+
+        // A login that finishes after a later one doesn't take its place
+        if stored.last_login.as_ref().is_none_or(|last| last.last_login_at <= logged_in_at) {
+            stored.last_login = Some(LastLogin {
+                last_login_at: logged_in_at,
+                last_login_ip: ip.map(str::to_string),
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn last_login(&self, email_address: &str) -> Result<Option<LastLogin>, ApplicationError> {
+        self.users
+            .read()
+            .unwrap()
+            .get(email_address)
+            .filter(|stored| stored.live().is_some())
+            .map(|stored| stored.last_login.clone())
+            .ok_or(ApplicationError::UserDoesNotExist)
+    }
+
+    async fn email_aliases(&self, email_address: &str) -> Result<Vec<EmailAlias>, ApplicationError> {
+        let users = self.users.read().unwrap();
+        let id = live_id(&users, email_address)?;
+
+        let mut aliases: Vec<EmailAlias> = self
+            .aliases
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|alias| alias.user_id == id)
+            .map(|alias| EmailAlias {
+                email_address: alias.email_address.clone(),
+                created_at: alias.created_at,
+                verified_at: alias.verified_at,
+            })
+            .collect();
+        aliases.sort_by(|a, b| a.email_address.cmp(&b.email_address));
+
+        Ok(aliases)
+    }
+
+    async fn add_email_alias(
+        &self,
+        email_address: &str,
+        alias: &str,
+        token_hash: &str,
+    ) -> Result<(), ApplicationError> {
+        let users = self.users.read().unwrap();
+        let id = live_id(&users, email_address)?;
+        if users.contains_key(alias) {
+            return Err(ApplicationError::UserAlreadyExists);
+        }
+
+        let mut aliases = self.aliases.write().unwrap();
+        if aliases.iter().any(|stored| {
+            stored.email_address == alias && stored.verified_at.is_some()
+        }) {
+            return Err(ApplicationError::UserAlreadyExists);
+        }
+
+        match aliases
+            .iter_mut()
+            .find(|stored| stored.email_address == alias && stored.user_id == id)
+        {
+            Some(stored) => {
+                stored.token_hash = Some(token_hash.to_string());
+                stored.created_at = crate::outbox::now();
+            }
+            None => aliases.push(StoredAlias {
+                email_address: alias.to_string(),
+                user_id: id,
+                token_hash: Some(token_hash.to_string()),
+                created_at: crate::outbox::now(),
+                verified_at: None,
+            }),
+        }
+
+        Ok(())
+    }
+
+    async fn verify_email_alias(
+        &self,
+        token_hash: &str,
+        added_after: u64,
+    ) -> Result<Option<String>, ApplicationError> {
+        let users = self.users.read().unwrap();
+        let mut aliases = self.aliases.write().unwrap();
+
+        let Some(position) = aliases.iter().position(|stored| {
+            stored.token_hash.as_deref() == Some(token_hash)
+                && stored.verified_at.is_none()
+                && stored.created_at >= added_after
+        }) else {
+            return Ok(None);
+        };
+
+        // Someone else may have registered or verified the address since it was added
+        let alias = aliases[position].email_address.clone();
+        let taken = users.contains_key(&alias)
+            || aliases
+                .iter()
+                .any(|stored| stored.email_address == alias && stored.verified_at.is_some());
+        if taken {
+            return Err(ApplicationError::UserAlreadyExists);
+        }
+
+        let stored = &mut aliases[position];
+        stored.token_hash = None;
+        stored.verified_at = Some(crate::outbox::now());
+
+        Ok(Some(alias))
+    }
+
+    async fn remove_email_alias(
+        &self,
+        email_address: &str,
+        alias: &str,
+    ) -> Result<(), ApplicationError> {
+        let users = self.users.read().unwrap();
+        let id = live_id(&users, email_address)?;
+
+        let mut aliases = self.aliases.write().unwrap();
+        let before = aliases.len();
+        aliases.retain(|stored| !(stored.email_address == alias && stored.user_id == id));
+
+        match aliases.len() < before {
+            true => Ok(()),
+            false => Err(ApplicationError::InvalidRequest(format!(
+                "{} is not one of the user's email addresses",
+                alias
+            ))),
+        }
+    }
+
+    async fn set_primary_email_address(
+        &self,
+        email_address: &str,
+        alias: &str,
+    ) -> Result<(), ApplicationError> {
+        let mut users = self.users.write().unwrap();
+        let id = live_id(&users, email_address)?;
+
+        let mut aliases = self.aliases.write().unwrap();
+        let stored_alias = aliases
+            .iter_mut()
+            .find(|stored| {
+                stored.email_address == alias && stored.user_id == id && stored.verified_at.is_some()
+            })
+            .ok_or_else(|| {
+                ApplicationError::InvalidRequest(format!(
+                    "{} is not a verified email address of the user",
+                    alias
+                ))
+            })?;
+
+        let stored = users
+            .remove(email_address)
+            .ok_or(ApplicationError::UserDoesNotExist)?;
+        let mut row = UserRow::from(&stored.user.value);
+        row.email_address = alias.to_string();
+        users.insert(
+            alias.to_string(),
+            StoredUser {
+                user: Versioned {
+                    value: row.into(),
+                    version: stored.user.version + 1,
+                },
+                ..stored
+            },
+        );
+
+        stored_alias.email_address = email_address.to_string();
+        stored_alias.created_at = crate::outbox::now();
+        stored_alias.verified_at = Some(crate::outbox::now());
+
+        Ok(())
+    }
+
+    async fn resolve_email_alias(&self, alias: &str) -> Result<Option<String>, ApplicationError> {
+        let users = self.users.read().unwrap();
+        let aliases = self.aliases.read().unwrap();
+
+        let Some(user_id) = aliases
+            .iter()
+            .find(|stored| stored.email_address == alias && stored.verified_at.is_some())
+            .map(|stored| stored.user_id)
+        else {
+            return Ok(None);
+        };
+
+        Ok(users
+            .values()
+            .filter_map(StoredUser::live)
+            .find(|stored| stored.value.id() == user_id)
+            .map(|stored| stored.value.email_address()))
+    }
+
+    async fn set_username(
+        &self,
+        email_address: &str,
+        username: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        let mut users = self.users.write().unwrap();
+        live_id(&users, email_address)?;
+        if let Some(username) = username {
+            let taken = users.iter().any(|(other, stored)| {
+                other != email_address && stored.user.value.username().as_deref() == Some(username)
+            });
+            if taken {
+                return Err(ApplicationError::UsernameTaken(username.to_string()));
+            }
+        }
+
+        if let Some(stored) = users.get_mut(email_address) {
+            stored.user.value.update_username(username);
+            stored.user.version += 1;
+        }
+
+        Ok(())
+    }
+
+    async fn resolve_username(&self, username: &str) -> Result<Option<String>, ApplicationError> {
+        Ok(self
+            .users
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(StoredUser::live)
+            .find(|stored| stored.value.username().as_deref() == Some(username))
+            .map(|stored| stored.value.email_address()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn when_a_user_is_stored_twice_should_return_already_exists() {
+        let users = InMemoryUsers::default();
+
+        users
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        let result = users
+            .store(User::from("test@test.com", "Someone Else", "hashed"))
+            .await;
+
+        assert!(matches!(result, Err(ApplicationError::UserAlreadyExists)));
+        assert_eq!(
+            users.with_email_address("test@test.com").await.unwrap().name(),
+            "Test User"
+        );
+    }
+
+    #[tokio::test]
+    async fn upserting_the_same_user_again_should_change_nothing() {
+        let users = InMemoryUsers::default();
+        let user = User::from("test@test.com", "Test User", "hashed");
+        let id = user.id();
+
+        let stored = users.upsert(user).await.unwrap();
+        let repeated = users
+            .upsert(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        let renamed = users
+            .upsert(User::from("test@test.com", "Renamed User", "hashed"))
+            .await
+            .unwrap();
+
+        assert_eq!((stored, repeated, renamed), (1, 1, 2));
+        let found = users.with_email_address("test@test.com").await.unwrap();
+        assert_eq!((found.id(), found.name()), (id, "Renamed User".to_string()));
+
+        users.soft_delete("test@test.com", None).await.unwrap();
+        assert!(matches!(
+            users.upsert(User::from("test@test.com", "Test User", "hashed")).await,
+            Err(ApplicationError::UserAlreadyExists)
+        ));
+    }
+
+    #[tokio::test]
+    async fn upserting_a_stored_user_should_keep_their_id_and_replace_their_details() {
+        let users = InMemoryUsers::default();
+        let stored = User::from("test@test.com", "Test User", "hashed");
+        users.store(stored.clone()).await.unwrap();
+        users.set_username("test@test.com", Some("tester")).await.unwrap();
+
+        let mut snapshot = User::from("test@test.com", "Renamed User", "rehashed");
+        snapshot.update_username(Some("renamed"));
+        let version = users.upsert(snapshot).await.unwrap();
+
+        let found = users.with_email_address_versioned("test@test.com").await.unwrap();
+        assert_eq!(version, found.version);
+        assert_eq!(found.value.id(), stored.id());
+        assert_eq!(found.value.name(), "Renamed User");
+        assert_eq!(found.value.username(), Some("renamed".to_string()));
+    }
+
+    #[tokio::test]
+    async fn count_and_exists_should_only_see_live_users() {
+        let users = InMemoryUsers::default();
+        for email_address in ["live@test.com", "deleted@test.com"] {
+            users
+                .store(User::from(email_address, "Test User", "hashed"))
+                .await
+                .unwrap();
+        }
+        users.soft_delete("deleted@test.com", None).await.unwrap();
+
+        assert_eq!(users.count().await.unwrap(), 1);
+        assert!(users.exists("live@test.com").await.unwrap());
+        assert!(!users.exists("deleted@test.com").await.unwrap());
+        assert!(!users.exists("unknown@test.com").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn stream_all_should_return_every_live_user_in_email_address_order_across_pages() {
+        use futures::stream::TryStreamExt;
+
+        let users = InMemoryUsers::default();
+        for i in (0..1200).rev() {
+            users
+                .store(User::from(&format!("user-{:04}@test.com", i), "Test User", "hashed"))
+                .await
+                .unwrap();
+        }
+        users.soft_delete("user-0600@test.com", None).await.unwrap();
+
+        let streamed: Vec<String> = users
+            .stream_all()
+            .map_ok(|user| user.email_address())
+            .try_collect()
+            .await
+            .unwrap();
+
+        assert_eq!(streamed.len(), 1199);
+        assert!(streamed.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(!streamed.contains(&"user-0600@test.com".to_string()));
+    }
+
+    #[tokio::test]
+    async fn when_any_address_in_a_batch_is_taken_store_many_should_store_none_of_them() {
+        let users = InMemoryUsers::default();
+        users
+            .store(User::from("taken@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+
+        let taken = users
+            .store_many(vec![
+                User::from("new@test.com", "New User", "hashed"),
+                User::from("taken@test.com", "Someone Else", "hashed"),
+            ])
+            .await;
+        let twice = users
+            .store_many(vec![
+                User::from("new@test.com", "New User", "hashed"),
+                User::from("new@test.com", "Someone Else", "hashed"),
+            ])
+            .await;
+
+        assert!(matches!(taken, Err(ApplicationError::UserAlreadyExists)));
+        assert!(matches!(twice, Err(ApplicationError::UserAlreadyExists)));
+        assert!(users.with_email_address("new@test.com").await.is_err());
+
+        users
+            .store_many(vec![
+                User::from("new@test.com", "New User", "hashed"),
+                User::from("other@test.com", "Other User", "hashed"),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(users.list(0, 10).await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn when_the_expected_version_is_stale_update_and_delete_should_return_version_mismatch() {
+        let users = InMemoryUsers::default();
+        users
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+
+        let version = users
+            .update(User::from("test@test.com", "Renamed", "hashed"), Some(1))
+            .await
+            .unwrap();
+        let stale_update = users
+            .update(User::from("test@test.com", "Again", "hashed"), Some(1))
+            .await;
+        let stale_delete = users.delete("test@test.com", Some(1)).await;
+
+        assert_eq!(version, 2);
+        assert!(matches!(stale_update, Err(ApplicationError::VersionMismatch)));
+        assert!(matches!(stale_delete, Err(ApplicationError::VersionMismatch)));
+
+        users.delete("test@test.com", Some(2)).await.unwrap();
+        assert!(matches!(
+            users.with_email_address("test@test.com").await,
+            Err(ApplicationError::UserDoesNotExist)
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_soft_deleted_user_should_be_hidden_but_keep_their_email_address_until_hard_deleted() {
+        let users = InMemoryUsers::default();
+        users
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+
+        users.soft_delete("test@test.com", Some(1)).await.unwrap();
+
+        assert!(matches!(
+            users.with_email_address("test@test.com").await,
+            Err(ApplicationError::UserDoesNotExist)
+        ));
+        assert!(users.list(0, 10).await.unwrap().is_empty());
+        assert!(matches!(
+            users.soft_delete("test@test.com", None).await,
+            Err(ApplicationError::UserDoesNotExist)
+        ));
+        assert!(matches!(
+            users
+                .store(User::from("test@test.com", "Someone Else", "hashed"))
+                .await,
+            Err(ApplicationError::UserAlreadyExists)
+        ));
+
+        users.delete("test@test.com", None).await.unwrap();
+        users
+            .store(User::from("test@test.com", "Someone Else", "hashed"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn an_erased_user_should_keep_their_id_under_a_placeholder_address() {
+        let users = InMemoryUsers::default();
+        let user = User::from("test@test.com", "Test User", "hashed");
+        users.store(user.clone()).await.unwrap();
+        users.soft_delete("test@test.com", None).await.unwrap();
+
+        let id = users.erase("test@test.com", None).await.unwrap();
+
+        assert_eq!(id, user.id());
+        assert!(matches!(
+            users.erase("test@test.com", None).await,
+            Err(ApplicationError::UserDoesNotExist)
+        ));
+        {
+            let stored = users.users.read().unwrap();
+            let erased = &stored[&format!("erased-{}@erased.invalid", id)];
+            assert_eq!(erased.user.value.name(), "Erased user");
+            assert_eq!(erased.user.version, 3);
+            assert!(erased.live().is_none());
+        }
+        users.store(User::from("test@test.com", "Someone Else", "hashed")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn an_alias_should_only_be_claimed_by_the_first_user_to_verify_it() {
+        let users = InMemoryUsers::default();
+        users.store(User::from("james@test.com", "James", "hashed")).await.unwrap();
+        users.store(User::from("jane@test.com", "Jane", "hashed")).await.unwrap();
+
+        users.add_email_alias("james@test.com", "shared@test.com", "james-token").await.unwrap();
+        users.add_email_alias("jane@test.com", "shared@test.com", "jane-token").await.unwrap();
+        assert_eq!(users.resolve_email_alias("shared@test.com").await.unwrap(), None);
+
+        let stale = users.verify_email_alias("james-token", u64::MAX).await.unwrap();
+        let verified = users.verify_email_alias("james-token", 0).await.unwrap();
+
+        assert_eq!(stale, None);
+        assert_eq!(verified.as_deref(), Some("shared@test.com"));
+        assert!(matches!(
+            users.verify_email_alias("jane-token", 0).await,
+            Err(ApplicationError::UserAlreadyExists)
+        ));
+        assert!(matches!(
+            users.add_email_alias("jane@test.com", "james@test.com", "token").await,
+            Err(ApplicationError::UserAlreadyExists)
+        ));
+        assert_eq!(
+            users.resolve_email_alias("shared@test.com").await.unwrap().as_deref(),
+            Some("james@test.com")
+        );
+
+        users.erase("james@test.com", None).await.unwrap();
+        assert_eq!(users.resolve_email_alias("shared@test.com").await.unwrap(), None);
+        users.verify_email_alias("jane-token", 0).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_username_should_belong_to_one_user_until_it_is_freed() {
+        let users = InMemoryUsers::default();
+        users.store(User::from("james@test.com", "James", "hashed")).await.unwrap();
+        users.store(User::from("jane@test.com", "Jane", "hashed")).await.unwrap();
+
+        users.set_username("james@test.com", Some("james")).await.unwrap();
+        let taken = users.set_username("jane@test.com", Some("james")).await;
+        let stale = users.with_email_address_versioned("james@test.com").await.unwrap();
+        let mut renamed = stale.value.clone();
+        renamed.update_name("Jim");
+        renamed.update_username(None);
+        users.update(renamed, None).await.unwrap();
+
+        assert!(matches!(taken, Err(ApplicationError::UsernameTaken(_))));
+        assert_eq!(stale.version, 2);
+        assert_eq!(
+            users.resolve_username("james").await.unwrap().as_deref(),
+            Some("james@test.com")
+        );
+
+        users.soft_delete("james@test.com", None).await.unwrap();
+        assert_eq!(users.resolve_username("james").await.unwrap(), None);
+        assert!(users.set_username("jane@test.com", Some("james")).await.is_err());
+        users.erase("james@test.com", None).await.unwrap();
+        users.set_username("jane@test.com", Some("james")).await.unwrap();
+    }
+}
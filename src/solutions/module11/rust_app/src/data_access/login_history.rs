@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use sqlx::PgPool;
+
+use super::outbox::{self, OutboxEntry};
+use crate::core::ApplicationError;
+
+// A successful login and the device it came from. A device is the IP address and user agent
+// together, either of which may be missing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoginRecord {
+    pub email_address: String,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub logged_in_at: u64,
+}
+
+// The IP address and user agent, missing values are kept as empty strings so they can be part
+// of the key
+type Device = (String, String);
+
+impl LoginRecord {
+    fn device(&self) -> Device {
+        (
+            self.ip_address.clone().unwrap_or_default(),
+            self.user_agent.clone().unwrap_or_default(),
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceSighting {
+    // The user had never logged in before, so there is nothing to compare the device against
+    FirstLogin,
+    KnownDevice,
+    NewDevice,
+}
+
+// The devices each user has logged in from, so a login from one they haven't used before can be
+// flagged to them
+#[async_trait::async_trait]
+pub trait LoginHistory: Send + Sync {
+    // Records the login. `alert` is only written to the outbox if it came from a new device, in
+    // the same transaction as the login.
+    async fn record(
+        &self,
+        login: &LoginRecord,
+        alert: Vec<OutboxEntry>,
+    ) -> Result<DeviceSighting, ApplicationError>;
+    // Removes every device the user logged in from, e.g. when they are erased
+    async fn forget(&self, email_address: &str) -> Result<(), ApplicationError>;
+    // Moves the devices of the user at `from` to the user at `into`, e.g. when they are merged,
+    // so their logins from those devices aren't flagged as new
+    async fn merge(&self, from: &str, into: &str) -> Result<(), ApplicationError>;
+}
+
+fn sighting(known: Option<bool>) -> DeviceSighting {
+    match known {
+        None => DeviceSighting::FirstLogin,
+        Some(true) => DeviceSighting::KnownDevice,
+        Some(false) => DeviceSighting::NewDevice,
+    }
+}
+
+// Only for the local profile. There is no outbox to write alerts to, they are dropped and the
+// user is only told through the notification sandbox.
+#[derive(Default)]
+pub struct InMemoryLoginHistory {
+    // The last time each user logged in from each device
+    devices: Mutex<HashMap<String, HashMap<Device, u64>>>,
+}
+
+#[async_trait::async_trait]
+impl LoginHistory for InMemoryLoginHistory {
+    async fn record(
+        &self,
+        login: &LoginRecord,
+        _alert: Vec<OutboxEntry>,
+    ) -> Result<DeviceSighting, ApplicationError> {
+        let mut devices = self.devices.lock().unwrap();
+        let known = devices.entry(login.email_address.clone()).or_default();
+
+        let device = login.device();
+        let sighting = match known.is_empty() {
+            true => sighting(None),
+            false => sighting(Some(known.contains_key(&device))),
+        };
+        known.insert(device, login.logged_in_at);
+
+        Ok(sighting)
+    }
+
+    async fn forget(&self, email_address: &str) -> Result<(), ApplicationError> {
+        self.devices.lock().unwrap().remove(email_address);
+
+        Ok(())
+    }
+
+    async fn merge(&self, from: &str, into: &str) -> Result<(), ApplicationError> {
+        let mut devices = self.devices.lock().unwrap();
+        let Some(moved) = devices.remove(from) else {
+            return Ok(());
+        };
+        let known = devices.entry(into.to_string()).or_default();
+        for (device, logged_in_at) in moved {
+            let last = known.entry(device).or_insert(logged_in_at);
+            *last = (*last).max(logged_in_at);
+        }
+
+        Ok(())
+    }
+}
+
+// Alerts go to the outbox, `login_history_from_config` only uses this store when the outbox is
+// enabled so they are relayed
+pub struct PostgresLoginHistory {
+    db: PgPool,
+}
+
+impl PostgresLoginHistory {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait::async_trait]
+impl LoginHistory for PostgresLoginHistory {
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "record"))]
+    async fn record(
+        &self,
+        login: &LoginRecord,
+        alert: Vec<OutboxEntry>,
+    ) -> Result<DeviceSighting, ApplicationError> {
+        let (ip_address, user_agent) = login.device();
+
+        let result: Result<DeviceSighting, sqlx::Error> = async {
+            let mut transaction = self.db.begin().await?;
+
+            // `NULL` when the user has no history at all
+            let known = sqlx::query_scalar!(
+                r#"
+                SELECT bool_or(ip_address = $2 AND user_agent = $3)
+                FROM login_history
+                WHERE email_address = $1
+                "#,
+                login.email_address,
+                ip_address,
+                user_agent,
+            )
+                .fetch_one(&mut *transaction)
+                .await?;
+
+            sqlx::query!(
+                r#"
+                INSERT INTO login_history ( email_address, ip_address, user_agent, first_seen_at, last_seen_at )
+                VALUES ( $1, $2, $3, $4, $4 )
+                ON CONFLICT ( email_address, ip_address, user_agent )
+                DO UPDATE SET last_seen_at = EXCLUDED.last_seen_at
+                "#,
+                login.email_address,
+                ip_address,
+                user_agent,
+                login.logged_in_at as i64,
+            )
+                .execute(&mut *transaction)
+                .await?;
+
+            let sighting = sighting(known);
+            if sighting == DeviceSighting::NewDevice {
+                for entry in &alert {
+                    outbox::enqueue(&mut transaction, entry).await?;
+                }
+            }
+
+            transaction.commit().await?;
+
+            Ok(sighting)
+        }
+        .await;
+
+        result.map_err(|e| ApplicationError::DatabaseError(e.to_string()))
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "forget"))]
+    async fn forget(&self, email_address: &str) -> Result<(), ApplicationError> {
+        sqlx::query!(
+            r#"
+            DELETE FROM login_history WHERE email_address = $1
+            "#,
+            email_address,
+        )
+            .execute(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    // A device both users logged in from is kept once, seen first and last when either was
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "merge"))]
+    async fn merge(&self, from: &str, into: &str) -> Result<(), ApplicationError> {
+        let result: Result<(), sqlx::Error> = async {
+            let mut transaction = self.db.begin().await?;
+
+            sqlx::query!(
+                r#"
+                INSERT INTO login_history ( email_address, ip_address, user_agent, first_seen_at, last_seen_at )
+                SELECT $2, ip_address, user_agent, first_seen_at, last_seen_at
+                FROM login_history
+                WHERE email_address = $1
+                ON CONFLICT ( email_address, ip_address, user_agent )
+                DO UPDATE SET
+                    first_seen_at = LEAST(login_history.first_seen_at, EXCLUDED.first_seen_at),
+                    last_seen_at = GREATEST(login_history.last_seen_at, EXCLUDED.last_seen_at)
+                "#,
+                from,
+                into,
+            )
+                .execute(&mut *transaction)
+                .await?;
+
+            sqlx::query!(
+                r#"
+                DELETE FROM login_history WHERE email_address = $1
+                "#,
+                from,
+            )
+                .execute(&mut *transaction)
+                .await?;
+
+            transaction.commit().await
+        }
+        .await;
+
+        result.map_err(|e| ApplicationError::DatabaseError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login(ip_address: &str, user_agent: &str) -> LoginRecord {
+        LoginRecord {
+            email_address: "test@test.com".to_string(),
+            ip_address: Some(ip_address.to_string()),
+            user_agent: Some(user_agent.to_string()),
+            logged_in_at: 1_700_000_000,
+        }
+    }
+
+    fn alert() -> Vec<OutboxEntry> {
+        vec![OutboxEntry {
+            id: "alert".to_string(),
+            topic: "new-device-login".to_string(),
+            key: "test@test.com".to_string(),
+            payload: "{}".to_string(),
+            trace_context: None,
+            created_at: 1_700_000_000,
+        }]
+    }
+
+    #[tokio::test]
+    async fn the_first_login_and_known_devices_should_not_be_new() {
+        let history = InMemoryLoginHistory::default();
+
+        let first = history.record(&login("10.0.0.1", "Firefox"), alert()).await.unwrap();
+        let again = history.record(&login("10.0.0.1", "Firefox"), alert()).await.unwrap();
+
+        assert_eq!(first, DeviceSighting::FirstLogin);
+        assert_eq!(again, DeviceSighting::KnownDevice);
+    }
+
+    #[tokio::test]
+    async fn a_new_ip_or_user_agent_should_be_a_new_device_once() {
+        let history = InMemoryLoginHistory::default();
+        history.record(&login("10.0.0.1", "Firefox"), alert()).await.unwrap();
+
+        let new_ip = history.record(&login("10.0.0.2", "Firefox"), alert()).await.unwrap();
+        let new_user_agent = history.record(&login("10.0.0.1", "curl"), alert()).await.unwrap();
+        let seen_since = history.record(&login("10.0.0.2", "Firefox"), alert()).await.unwrap();
+
+        assert_eq!(new_ip, DeviceSighting::NewDevice);
+        assert_eq!(new_user_agent, DeviceSighting::NewDevice);
+        assert_eq!(seen_since, DeviceSighting::KnownDevice);
+    }
+
+    #[tokio::test]
+    async fn a_forgotten_user_should_log_in_for_the_first_time_again() {
+        let history = InMemoryLoginHistory::default();
+        history.record(&login("10.0.0.1", "Firefox"), alert()).await.unwrap();
+
+        history.forget("test@test.com").await.unwrap();
+
+        let sighting = history.record(&login("10.0.0.1", "Firefox"), alert()).await.unwrap();
+        assert_eq!(sighting, DeviceSighting::FirstLogin);
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sqlx::PgPool;
+
+use crate::core::ApplicationError;
+
+// Outstanding magic links, keyed by the token id embedded in the signed link. Consuming a token
+// removes it, so each link can complete a login once and only before it expires.
+#[async_trait::async_trait]
+pub trait MagicLinkTokens: Send + Sync {
+    async fn save(
+        &self,
+        token_id: &str,
+        email_address: &str,
+        expires_at: u64,
+    ) -> Result<(), ApplicationError>;
+    async fn consume(&self, token_id: &str) -> Result<Option<String>, ApplicationError>;
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Default)]
+pub struct InMemoryMagicLinkTokens {
+    tokens: Mutex<HashMap<String, (String, u64)>>,
+}
+
+#[async_trait::async_trait]
+impl MagicLinkTokens for InMemoryMagicLinkTokens {
+    async fn save(
+        &self,
+        token_id: &str,
+        email_address: &str,
+        expires_at: u64,
+    ) -> Result<(), ApplicationError> {
+        let mut tokens = self.tokens.lock().unwrap();
+
+        let now = now();
+        tokens.retain(|_, (_, expiry)| *expiry > now);
+        tokens.insert(token_id.to_string(), (email_address.to_string(), expires_at));
+
+        Ok(())
+    }
+
+    async fn consume(&self, token_id: &str) -> Result<Option<String>, ApplicationError> {
+        Ok(self
+            .tokens
+            .lock()
+            .unwrap()
+            .remove(token_id)
+            .filter(|(_, expiry)| *expiry > now())
+            .map(|(email_address, _)| email_address))
+    }
+}
+
+pub struct PostgresMagicLinkTokens {
+    db: PgPool,
+}
+
+impl PostgresMagicLinkTokens {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait::async_trait]
+impl MagicLinkTokens for PostgresMagicLinkTokens {
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "save"))]
+    async fn save(
+        &self,
+        token_id: &str,
+        email_address: &str,
+        expires_at: u64,
+    ) -> Result<(), ApplicationError> {
+        log::info!("Storing magic link token");
+
+        sqlx::query!(
+            r#"
+            DELETE FROM magic_link_tokens
+            WHERE expires_at < $1
+            "#,
+            now() as i64,
+        )
+            .execute(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO magic_link_tokens ( token_id, email_address, expires_at )
+            VALUES ( $1, $2, $3 )
+            "#,
+            token_id,
+            email_address,
+            expires_at as i64,
+        )
+            .execute(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "consume"))]
+    async fn consume(&self, token_id: &str) -> Result<Option<String>, ApplicationError> {
+        // Deleting and returning in one statement stops two concurrent requests using the same link
+        let record = sqlx::query!(
+            r#"
+            DELETE FROM magic_link_tokens
+            WHERE token_id = $1
+            RETURNING email_address, expires_at
+            "#,
+            token_id,
+        )
+            .fetch_optional(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        Ok(record
+            .filter(|record| record.expires_at as u64 > now())
+            .map(|record| record.email_address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_token_should_only_be_consumed_once() {
+        let tokens = InMemoryMagicLinkTokens::default();
+
+        tokens.save("token-id", "test@test.com", now() + 60).await.unwrap();
+
+        assert_eq!(
+            tokens.consume("token-id").await.unwrap(),
+            Some("test@test.com".to_string())
+        );
+        assert_eq!(tokens.consume("token-id").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn an_expired_token_should_not_be_consumed() {
+        let tokens = InMemoryMagicLinkTokens::default();
+
+        tokens.save("token-id", "test@test.com", now() - 60).await.unwrap();
+
+        assert_eq!(tokens.consume("token-id").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,281 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sqlx::PgPool;
+
+use crate::core::{ApplicationError, Config};
+use crate::metrics::{DB_MAINTENANCE_RECOMMENDATIONS_TOTAL, DB_TABLE_DEAD_TUPLES};
+use crate::AppState;
+
+// Tables with fewer rows than this are cheap to scan and not worth advising on
+const MIN_TUPLES: i64 = 1000;
+const DEAD_TUPLE_RATIO: f64 = 0.2;
+const MODIFIED_SINCE_ANALYZE_RATIO: f64 = 0.1;
+
+#[derive(Clone, Debug)]
+pub struct MaintenanceSettings {
+    pub interval: Duration,
+    pub run_analyze: bool,
+    pub off_peak_start_hour: u8,
+    pub off_peak_end_hour: u8,
+}
+
+impl MaintenanceSettings {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            interval: Duration::from_secs(config.maintenance_interval_seconds()),
+            run_analyze: config.maintenance_run_analyze(),
+            off_peak_start_hour: config.maintenance_off_peak_start_hour(),
+            off_peak_end_hour: config.maintenance_off_peak_end_hour(),
+        }
+    }
+
+    // The window is in UTC and may wrap midnight, e.g. 22 -> 5
+    pub fn is_off_peak(&self, hour: u8) -> bool {
+        if self.off_peak_start_hour <= self.off_peak_end_hour {
+            hour >= self.off_peak_start_hour && hour < self.off_peak_end_hour
+        } else {
+            hour >= self.off_peak_start_hour || hour < self.off_peak_end_hour
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TableStatistics {
+    pub table_name: String,
+    pub live_tuples: i64,
+    pub dead_tuples: i64,
+    pub modified_since_analyze: i64,
+    pub seconds_since_analyze: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaintenanceAction {
+    Vacuum,
+    Analyze,
+}
+
+impl MaintenanceAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MaintenanceAction::Vacuum => "vacuum",
+            MaintenanceAction::Analyze => "analyze",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaintenanceRecommendation {
+    pub table_name: String,
+    pub action: MaintenanceAction,
+    pub reason: String,
+}
+
+pub fn recommend(statistics: &[TableStatistics]) -> Vec<MaintenanceRecommendation> {
+    let mut recommendations = Vec::new();
+
+    for table in statistics {
+        let total_tuples = table.live_tuples + table.dead_tuples;
+        if total_tuples < MIN_TUPLES {
+            continue;
+        }
+
+        let dead_ratio = table.dead_tuples as f64 / total_tuples as f64;
+        if dead_ratio > DEAD_TUPLE_RATIO {
+            recommendations.push(MaintenanceRecommendation {
+                table_name: table.table_name.clone(),
+                action: MaintenanceAction::Vacuum,
+                reason: format!("{:.0}% of tuples are dead", dead_ratio * 100.0),
+            });
+        }
+
+        let modified_ratio = table.modified_since_analyze as f64 / total_tuples as f64;
+        if table.seconds_since_analyze.is_none() {
+            recommendations.push(MaintenanceRecommendation {
+                table_name: table.table_name.clone(),
+                action: MaintenanceAction::Analyze,
+                reason: "table has never been analyzed".to_string(),
+            });
+        } else if modified_ratio > MODIFIED_SINCE_ANALYZE_RATIO {
+            recommendations.push(MaintenanceRecommendation {
+                table_name: table.table_name.clone(),
+                action: MaintenanceAction::Analyze,
+                reason: format!(
+                    "{:.0}% of rows changed since the last analyze",
+                    modified_ratio * 100.0
+                ),
+            });
+        }
+    }
+
+    recommendations
+}
+
+pub struct PostgresMaintenance {
+    db: PgPool,
+}
+
+impl PostgresMaintenance {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+
+    pub async fn table_statistics(&self) -> Result<Vec<TableStatistics>, ApplicationError> {
+        let records = sqlx::query!(
+            r#"
+            SELECT relname AS "table_name!",
+                   n_live_tup AS "live_tuples!",
+                   n_dead_tup AS "dead_tuples!",
+                   n_mod_since_analyze AS "modified_since_analyze!",
+                   EXTRACT(EPOCH FROM now() - GREATEST(last_analyze, last_autoanalyze))::BIGINT AS seconds_since_analyze
+            FROM pg_stat_user_tables
+            "#,
+        )
+            .fetch_all(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        Ok(records
+            .into_iter()
+            .map(|record| TableStatistics {
+                table_name: record.table_name,
+                live_tuples: record.live_tuples,
+                dead_tuples: record.dead_tuples,
+                modified_since_analyze: record.modified_since_analyze,
+                seconds_since_analyze: record.seconds_since_analyze,
+            })
+            .collect())
+    }
+
+    pub async fn analyze(&self, table_name: &str) -> Result<(), ApplicationError> {
+        // Identifiers can't be bound as parameters, the name comes from the catalog and is quoted
+        let statement = format!("ANALYZE \"{}\"", table_name.replace('"', "\"\""));
+
+        sqlx::query(&statement)
+            .execute(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+fn current_utc_hour() -> u8 {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+
+    ((seconds / 3600) % 24) as u8
+}
+
+pub async fn run_maintenance_advisor(
+    maintenance: PostgresMaintenance,
+    state: Arc<AppState>,
+    settings: MaintenanceSettings,
+) {
+    log::info!("Starting database maintenance advisor every {:?}", settings.interval);
+
+    let mut interval = tokio::time::interval(settings.interval);
+
+    loop {
+        interval.tick().await;
+
+        let statistics = match maintenance.table_statistics().await {
+            Ok(statistics) => statistics,
+            Err(e) => {
+                log::warn!("Unable to read table statistics: {:?}", e);
+                continue;
+            }
+        };
+
+        for table in &statistics {
+            state.metrics.set_gauge(
+                DB_TABLE_DEAD_TUPLES,
+                &[("table", &table.table_name)],
+                table.dead_tuples as f64,
+            );
+        }
+
+        for recommendation in recommend(&statistics) {
+            log::warn!(
+                "Maintenance recommended: {} {} because {}",
+                recommendation.action.as_str(),
+                recommendation.table_name,
+                recommendation.reason
+            );
+            state.metrics.increment_with_labels(
+                DB_MAINTENANCE_RECOMMENDATIONS_TOTAL,
+                &[
+                    ("table", &recommendation.table_name),
+                    ("action", recommendation.action.as_str()),
+                ],
+            );
+
+            if recommendation.action == MaintenanceAction::Analyze
+                && settings.run_analyze
+                && settings.is_off_peak(current_utc_hour())
+            {
+                log::info!("Running ANALYZE on {} during off-peak hours", recommendation.table_name);
+                if let Err(e) = maintenance.analyze(&recommendation.table_name).await {
+                    log::warn!("ANALYZE failed: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(live_tuples: i64, dead_tuples: i64, modified_since_analyze: i64) -> TableStatistics {
+        TableStatistics {
+            table_name: "users".to_string(),
+            live_tuples,
+            dead_tuples,
+            modified_since_analyze,
+            seconds_since_analyze: Some(60),
+        }
+    }
+
+    #[test]
+    fn when_a_table_is_bloated_should_recommend_vacuum() {
+        let recommendations = recommend(&[table(5000, 5000, 0)]);
+
+        assert_eq!(recommendations.len(), 1);
+        assert_eq!(recommendations[0].action, MaintenanceAction::Vacuum);
+    }
+
+    #[test]
+    fn when_statistics_are_stale_should_recommend_analyze() {
+        let mut never_analyzed = table(5000, 0, 0);
+        never_analyzed.seconds_since_analyze = None;
+
+        let recommendations = recommend(&[table(5000, 0, 2000), never_analyzed]);
+
+        assert_eq!(recommendations.len(), 2);
+        assert!(recommendations
+            .iter()
+            .all(|recommendation| recommendation.action == MaintenanceAction::Analyze));
+    }
+
+    #[test]
+    fn when_a_table_is_small_or_healthy_should_not_recommend_anything() {
+        assert!(recommend(&[table(10, 90, 90), table(5000, 10, 10)]).is_empty());
+    }
+
+    #[test]
+    fn off_peak_window_should_support_wrapping_midnight() {
+        let settings = MaintenanceSettings {
+            interval: Duration::from_secs(60),
+            run_analyze: true,
+            off_peak_start_hour: 22,
+            off_peak_end_hour: 5,
+        };
+
+        assert!(settings.is_off_peak(23));
+        assert!(settings.is_off_peak(2));
+        assert!(!settings.is_off_peak(12));
+    }
+}
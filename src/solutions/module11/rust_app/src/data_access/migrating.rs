@@ -0,0 +1,602 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use uuid::Uuid;
+
+use crate::core::{
+    ApplicationError, DataAccess, EmailAlias, LastLogin, MigrationPrimary, User, UserPreferences,
+    Versioned,
+};
+use crate::metrics::{Metrics, USER_MIGRATION_DIVERGENCE_TOTAL};
+
+const VERIFY_PAGE_SIZE: i64 = 100;
+
+// Moves users from one store to another while the API keeps running. Every write goes to both,
+// reads are served by `primary` and fall back to the other store for users it doesn't have yet.
+// `verify` walks the old store to find, and optionally copy, users the new one is missing or has
+// a different copy of.
+pub struct MigratingDataAccess<TOld: DataAccess, TNew: DataAccess> {
+    old: TOld,
+    new: TNew,
+    primary: MigrationPrimary,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub scanned: usize,
+    pub missing: usize,
+    pub mismatched: usize,
+    pub repaired: usize,
+}
+
+impl<TOld: DataAccess, TNew: DataAccess> MigratingDataAccess<TOld, TNew> {
+    pub fn new(old: TOld, new: TNew, primary: MigrationPrimary) -> Self {
+        Self {
+            old,
+            new,
+            primary,
+            metrics: Arc::new(Metrics::default()),
+        }
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    fn primary(&self) -> &dyn DataAccess {
+        match self.primary {
+            MigrationPrimary::Old => &self.old,
+            MigrationPrimary::New => &self.new,
+        }
+    }
+
+    fn secondary(&self) -> &dyn DataAccess {
+        match self.primary {
+            MigrationPrimary::Old => &self.new,
+            MigrationPrimary::New => &self.old,
+        }
+    }
+
+    fn store_for(&self, primary: bool) -> &dyn DataAccess {
+        match primary {
+            true => self.primary(),
+            false => self.secondary(),
+        }
+    }
+
+    fn diverged(&self, operation: &str, reason: &str) {
+        self.metrics.increment_with_labels(
+            USER_MIGRATION_DIVERGENCE_TOTAL,
+            &[("operation", operation), ("reason", reason)],
+        );
+    }
+
+    // A user the primary doesn't have yet is read from the other store. `read` is given whether
+    // to read from the primary.
+    async fn read<T, F, Fut>(&self, operation: &str, read: F) -> Result<T, ApplicationError>
+    where
+        F: Fn(bool) -> Fut,
+        Fut: Future<Output = Result<T, ApplicationError>>,
+    {
+        match read(true).await {
+            Err(ApplicationError::UserDoesNotExist) => {
+                let found = read(false).await;
+                if found.is_ok() {
+                    self.diverged(operation, "missing_in_primary");
+                }
+                found
+            }
+            result => result,
+        }
+    }
+
+    // Writes to the store that served the user's reads, so `expected_version` is checked against
+    // the version the client saw, then copies the write to the other store regardless of its
+    // version. Only the first write decides the result, the copy failing is counted and logged.
+    // `write` is given whether to write to the primary and whether the write is the first.
+    async fn write<T, F, Fut>(&self, operation: &str, write: F) -> Result<T, ApplicationError>
+    where
+        F: Fn(bool, bool) -> Fut,
+        Fut: Future<Output = Result<T, ApplicationError>>,
+    {
+        let written = match write(true, true).await {
+            Err(ApplicationError::UserDoesNotExist) => return write(false, true).await,
+            Err(e) => return Err(e),
+            Ok(value) => value,
+        };
+
+        match write(false, false).await {
+            Ok(_) => {}
+            Err(ApplicationError::UserDoesNotExist) => self.diverged(operation, "missing_in_secondary"),
+            Err(e) => {
+                log::warn!("{} was not copied to the secondary store: {:?}", operation, e);
+                self.diverged(operation, "write_failed");
+            }
+        }
+
+        Ok(written)
+    }
+
+    // Both stores' users after `after`, the primary's copy winning when both have one
+    async fn merged(&self, after: Option<&str>, limit: i64) -> Result<Vec<User>, ApplicationError> {
+        let mut users = self.primary().list_after(after, limit).await?;
+        for user in self.secondary().list_after(after, limit).await? {
+            if !users.iter().any(|found| found.email_address() == user.email_address()) {
+                users.push(user);
+            }
+        }
+        users.sort_by_key(|user| user.email_address());
+        users.truncate(limit.max(0) as usize);
+
+        Ok(users)
+    }
+
+    // The old store has every user, writes and the backfill only ever add to the new one. A user
+    // the new store is missing is copied from the old one, one that differs gets the primary's copy.
+    pub async fn verify(&self, repair: bool) -> Result<MigrationReport, ApplicationError> {
+        let mut report = MigrationReport::default();
+        let mut cursor: Option<String> = None;
+
+        loop {
+            let users = self.old.list_after(cursor.as_deref(), VERIFY_PAGE_SIZE).await?;
+            let page_size = users.len() as i64;
+            cursor = users.last().map(|user| user.email_address());
+
+            for user in users {
+                report.scanned += 1;
+                let email_address = user.email_address();
+
+                match self.new.with_email_address(&email_address).await {
+                    Err(ApplicationError::UserDoesNotExist) => {
+                        report.missing += 1;
+                        if repair {
+                            self.new.store(user).await?;
+                            report.repaired += 1;
+                        }
+                    }
+                    Err(e) => return Err(e),
+                    Ok(copy) if differs(&user, &copy) => {
+                        report.mismatched += 1;
+                        log::info!("{} differs between the old and new stores", email_address);
+                        if repair {
+                            let (from, to) = match self.primary {
+                                MigrationPrimary::Old => (user, &self.new as &dyn DataAccess),
+                                MigrationPrimary::New => (copy, &self.old as &dyn DataAccess),
+                            };
+                            to.update(from, None).await?;
+                            report.repaired += 1;
+                        }
+                    }
+                    Ok(_) => {}
+                }
+            }
+
+            if page_size < VERIFY_PAGE_SIZE {
+                break;
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+fn differs(old: &User, new: &User) -> bool {
+    old.id() != new.id()
+        || old.role() != new.role()
+        || old.name() != new.name()
+        || old.date_of_birth() != new.date_of_birth()
+        || old.username() != new.username()
+        || old.phone_number() != new.phone_number()
+        || old.metadata() != new.metadata()
+        || old.password() != new.password()
+        || old.tier() != new.tier()
+}
+
+#[async_trait::async_trait]
+impl<TOld: DataAccess, TNew: DataAccess> DataAccess for MigratingDataAccess<TOld, TNew> {
+    async fn with_email_address(&self, email_address: &str) -> Result<User, ApplicationError> {
+        self.read("with_email_address", |primary| {
+            self.store_for(primary).with_email_address(email_address)
+        })
+        .await
+    }
+
+    async fn with_id(&self, id: Uuid) -> Result<User, ApplicationError> {
+        self.read("with_id", |primary| self.store_for(primary).with_id(id))
+            .await
+    }
+
+    async fn list(&self, offset: i64, limit: i64) -> Result<Vec<User>, ApplicationError> {
+        Ok(self
+            .merged(None, offset + limit)
+            .await?
+            .into_iter()
+            .skip(offset.max(0) as usize)
+            .collect())
+    }
+
+    async fn list_after(
+        &self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<User>, ApplicationError> {
+        self.merged(after, limit).await
+    }
+
+    // From the old store, which has every user, soft deleted or not
+    async fn list_including_deleted(
+        &self,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<(User, Option<u64>)>, ApplicationError> {
+        self.old.list_including_deleted(offset, limit).await
+    }
+
+    async fn search_ranked(
+        &self,
+        query: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<(User, f32)>, ApplicationError> {
+        self.old.search_ranked(query, offset, limit).await
+    }
+
+    async fn count(&self) -> Result<i64, ApplicationError> {
+        self.old.count().await
+    }
+
+    async fn exists(&self, email_address: &str) -> Result<bool, ApplicationError> {
+        self.old.exists(email_address).await
+    }
+
+    // The old store has every user, so it decides whether the email address is taken
+    async fn store(&self, user: User) -> Result<(), ApplicationError> {
+        self.old.store(user.clone()).await?;
+
+        if let Err(e) = self.new.store(user).await {
+            log::warn!("store was not copied to the new store: {:?}", e);
+            self.diverged("store", "write_failed");
+        }
+
+        Ok(())
+    }
+
+    async fn store_many(&self, users: Vec<User>) -> Result<(), ApplicationError> {
+        self.old.store_many(users.clone()).await?;
+
+        if let Err(e) = self.new.store_many(users).await {
+            log::warn!("store_many was not copied to the new store: {:?}", e);
+            self.diverged("store_many", "write_failed");
+        }
+
+        Ok(())
+    }
+
+    async fn warm_up(&self, connections: u32) -> Result<(), ApplicationError> {
+        self.old.warm_up(connections).await?;
+        self.new.warm_up(connections).await
+    }
+
+    // Both, every write goes to the old store and reads to whichever is the primary
+    async fn health_check(&self) -> Result<(), ApplicationError> {
+        self.old.health_check().await?;
+        self.new.health_check().await
+    }
+
+    async fn with_email_address_versioned(
+        &self,
+        email_address: &str,
+    ) -> Result<Versioned<User>, ApplicationError> {
+        self.read("with_email_address_versioned", |primary| {
+            self.store_for(primary).with_email_address_versioned(email_address)
+        })
+        .await
+    }
+
+    async fn update(
+        &self,
+        user: User,
+        expected_version: Option<i64>,
+    ) -> Result<i64, ApplicationError> {
+        self.write("update", |primary, first| {
+            self.store_for(primary)
+                .update(user.clone(), expected_version.filter(|_| first))
+        })
+        .await
+    }
+
+    // Like `store`, the old store has every user, so it decides what is written
+    async fn upsert(&self, user: User) -> Result<i64, ApplicationError> {
+        let version = self.old.upsert(user.clone()).await?;
+
+        if let Err(e) = self.new.upsert(user).await {
+            log::warn!("upsert was not copied to the new store: {:?}", e);
+            self.diverged("upsert", "write_failed");
+        }
+
+        Ok(version)
+    }
+
+    async fn soft_delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        self.write("soft_delete", |primary, first| {
+            self.store_for(primary)
+                .soft_delete(email_address, expected_version.filter(|_| first))
+        })
+        .await
+    }
+
+    async fn delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        self.write("delete", |primary, first| {
+            self.store_for(primary)
+                .delete(email_address, expected_version.filter(|_| first))
+        })
+        .await
+    }
+
+    // Both stores record the erasure, only the old one announces it
+    async fn erase(
+        &self,
+        email_address: &str,
+        requested_by: Option<&str>,
+    ) -> Result<Uuid, ApplicationError> {
+        self.write("erase", |primary, _| {
+            self.store_for(primary).erase(email_address, requested_by)
+        })
+        .await
+    }
+
+    async fn merge(
+        &self,
+        kept: &str,
+        merged: &str,
+        requested_by: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        self.write("merge", |primary, _| {
+            self.store_for(primary).merge(kept, merged, requested_by)
+        })
+        .await
+    }
+
+    async fn preferences(&self, email_address: &str) -> Result<UserPreferences, ApplicationError> {
+        self.read("preferences", |primary| {
+            self.store_for(primary).preferences(email_address)
+        })
+        .await
+    }
+
+    async fn update_preferences(
+        &self,
+        email_address: &str,
+        preferences: &UserPreferences,
+    ) -> Result<(), ApplicationError> {
+        self.write("update_preferences", |primary, _| {
+            self.store_for(primary)
+                .update_preferences(email_address, preferences)
+        })
+        .await
+    }
+
+    async fn record_login(
+        &self,
+        email_address: &str,
+        logged_in_at: u64,
+        ip: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        self.write("record_login", |primary, _| {
+            self.store_for(primary)
+                .record_login(email_address, logged_in_at, ip)
+        })
+        .await
+    }
+
+    async fn last_login(&self, email_address: &str) -> Result<Option<LastLogin>, ApplicationError> {
+        self.read("last_login", |primary| {
+            self.store_for(primary).last_login(email_address)
+        })
+        .await
+    }
+
+    async fn email_aliases(&self, email_address: &str) -> Result<Vec<EmailAlias>, ApplicationError> {
+        self.read("email_aliases", |primary| {
+            self.store_for(primary).email_aliases(email_address)
+        })
+        .await
+    }
+
+    // Both stores are given the same token, so the link verifies the alias in each
+    async fn add_email_alias(
+        &self,
+        email_address: &str,
+        alias: &str,
+        token_hash: &str,
+    ) -> Result<(), ApplicationError> {
+        self.write("add_email_alias", |primary, _| {
+            self.store_for(primary)
+                .add_email_alias(email_address, alias, token_hash)
+        })
+        .await
+    }
+
+    // An unknown token isn't an error, so the other store is asked and copied to by hand
+    async fn verify_email_alias(
+        &self,
+        token_hash: &str,
+        added_after: u64,
+    ) -> Result<Option<String>, ApplicationError> {
+        let verified = self.primary().verify_email_alias(token_hash, added_after).await?;
+
+        match self.secondary().verify_email_alias(token_hash, added_after).await {
+            Ok(Some(alias)) if verified.is_none() => {
+                self.diverged("verify_email_alias", "missing_in_primary");
+                Ok(Some(alias))
+            }
+            Ok(None) if verified.is_some() => {
+                self.diverged("verify_email_alias", "missing_in_secondary");
+                Ok(verified)
+            }
+            Ok(_) => Ok(verified),
+            Err(e) => {
+                log::warn!("verify_email_alias was not copied to the secondary store: {:?}", e);
+                self.diverged("verify_email_alias", "write_failed");
+                Ok(verified)
+            }
+        }
+    }
+
+    async fn remove_email_alias(
+        &self,
+        email_address: &str,
+        alias: &str,
+    ) -> Result<(), ApplicationError> {
+        self.write("remove_email_alias", |primary, _| {
+            self.store_for(primary).remove_email_alias(email_address, alias)
+        })
+        .await
+    }
+
+    async fn set_primary_email_address(
+        &self,
+        email_address: &str,
+        alias: &str,
+    ) -> Result<(), ApplicationError> {
+        self.write("set_primary_email_address", |primary, _| {
+            self.store_for(primary)
+                .set_primary_email_address(email_address, alias)
+        })
+        .await
+    }
+
+    async fn resolve_email_alias(&self, alias: &str) -> Result<Option<String>, ApplicationError> {
+        match self.primary().resolve_email_alias(alias).await? {
+            Some(email_address) => Ok(Some(email_address)),
+            None => self.secondary().resolve_email_alias(alias).await,
+        }
+    }
+
+    async fn set_username(
+        &self,
+        email_address: &str,
+        username: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        self.write("set_username", |primary, _| {
+            self.store_for(primary).set_username(email_address, username)
+        })
+        .await
+    }
+
+    async fn resolve_username(&self, username: &str) -> Result<Option<String>, ApplicationError> {
+        match self.primary().resolve_username(username).await? {
+            Some(email_address) => Ok(Some(email_address)),
+            None => self.secondary().resolve_username(username).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_access::InMemoryUsers;
+
+    fn user(email_address: &str) -> User {
+        User::from(email_address, "James", "hashed")
+    }
+
+    fn migrating(primary: MigrationPrimary) -> MigratingDataAccess<InMemoryUsers, InMemoryUsers> {
+        MigratingDataAccess::new(InMemoryUsers::default(), InMemoryUsers::default(), primary)
+    }
+
+    #[tokio::test]
+    async fn writes_should_go_to_both_stores() {
+        let migrating = migrating(MigrationPrimary::Old);
+
+        migrating.store(user("james@test.com")).await.unwrap();
+        let mut renamed = user("james@test.com");
+        renamed.update_name("Renamed");
+        migrating.update(renamed, Some(1)).await.unwrap();
+
+        for store in [&migrating.old, &migrating.new] {
+            assert_eq!(store.with_email_address("james@test.com").await.unwrap().name(), "Renamed");
+        }
+        assert!(matches!(
+            migrating.store(user("james@test.com")).await,
+            Err(ApplicationError::UserAlreadyExists)
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_user_the_primary_is_missing_should_be_read_from_the_other_store() {
+        let migrating = migrating(MigrationPrimary::New);
+        migrating.old.store(user("james@test.com")).await.unwrap();
+
+        let found = migrating.with_email_address("james@test.com").await.unwrap();
+
+        assert_eq!(found.email_address(), "james@test.com");
+        assert_eq!(
+            migrating.metrics.snapshot().counter_with_labels(
+                USER_MIGRATION_DIVERGENCE_TOTAL,
+                &[("operation", "with_email_address"), ("reason", "missing_in_primary")]
+            ),
+            1
+        );
+        assert_eq!(migrating.list(0, 10).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_rejected_write_should_not_be_copied() {
+        let migrating = migrating(MigrationPrimary::Old);
+        migrating.store(user("james@test.com")).await.unwrap();
+        let mut renamed = user("james@test.com");
+        renamed.update_name("Renamed");
+
+        assert!(matches!(
+            migrating.update(renamed, Some(7)).await,
+            Err(ApplicationError::VersionMismatch)
+        ));
+        assert_eq!(migrating.new.with_email_address("james@test.com").await.unwrap().name(), "James");
+    }
+
+    #[tokio::test]
+    async fn verify_should_report_and_repair_users_the_new_store_is_missing_or_differs_on() {
+        let migrating = migrating(MigrationPrimary::Old);
+        for index in 0..5 {
+            migrating.old.store(user(&format!("user{}@test.com", index))).await.unwrap();
+        }
+        let copied = migrating.old.with_email_address("user0@test.com").await.unwrap();
+        migrating.new.store(copied.clone()).await.unwrap();
+        let mut stale = migrating.old.with_email_address("user1@test.com").await.unwrap();
+        stale.update_name("Stale");
+        migrating.new.store(stale).await.unwrap();
+
+        let report = migrating.verify(false).await.unwrap();
+        assert_eq!(
+            report,
+            MigrationReport {
+                scanned: 5,
+                missing: 3,
+                mismatched: 1,
+                repaired: 0,
+            }
+        );
+
+        let repaired = migrating.verify(true).await.unwrap();
+        assert_eq!(repaired.repaired, 4);
+        assert_eq!(
+            migrating.verify(false).await.unwrap(),
+            MigrationReport {
+                scanned: 5,
+                ..MigrationReport::default()
+            }
+        );
+        assert_eq!(migrating.new.with_email_address("user1@test.com").await.unwrap().name(), "James");
+    }
+}
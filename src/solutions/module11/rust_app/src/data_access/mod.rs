@@ -0,0 +1,44 @@
+mod active_sessions;
+mod audit;
+mod audited;
+mod buffered;
+mod cached;
+mod dynamo;
+mod in_memory;
+mod login_history;
+mod magic_links;
+mod maintenance;
+mod migrating;
+mod mongo;
+mod outbox;
+mod postgres;
+mod rows;
+mod schema;
+mod sharded;
+mod unit_of_work;
+mod user_cache;
+mod user_changes;
+
+pub use active_sessions::{
+    ActiveSession, ActiveSessions, InMemoryActiveSessions, PostgresActiveSessions,
+};
+pub use audit::{audit_log_from_config, with_actor, Actor, AuditEntry, AuditLog, InMemoryAuditLog};
+pub use audited::AuditedDataAccess;
+pub use buffered::{BufferSettings, BufferedUsers};
+pub use cached::{CacheSettings, CachedDataAccess};
+pub use dynamo::DynamoUsers;
+pub use in_memory::InMemoryUsers;
+pub use login_history::{
+    DeviceSighting, InMemoryLoginHistory, LoginHistory, LoginRecord, PostgresLoginHistory,
+};
+pub use magic_links::{InMemoryMagicLinkTokens, MagicLinkTokens, PostgresMagicLinkTokens};
+pub use maintenance::{run_maintenance_advisor, MaintenanceSettings, PostgresMaintenance};
+pub use migrating::{MigratingDataAccess, MigrationReport};
+pub use mongo::MongoUsers;
+pub use outbox::{OutboxBacklog, OutboxEntry, PostgresOutbox};
+pub use postgres::{connect, run_pool_statistics, PoolSettings, PoolStatistics, PostgresUsers};
+pub use schema::run_migrations;
+pub use sharded::{RebalanceReport, ShardedDataAccess};
+pub use unit_of_work::UnitOfWork;
+pub use user_cache::{user_cache_from_config, InMemoryUserCache, RedisUserCache, UserCache};
+pub use user_changes::{listen_for_user_changes, UserChangeNotification};
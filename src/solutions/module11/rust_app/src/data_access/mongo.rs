@@ -0,0 +1,529 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use mongodb::bson::{self, doc, Bson, Document};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::{IndexOptions, ReturnDocument};
+use mongodb::{Client, Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use time::format_description::well_known::Iso8601;
+use time::Date;
+use uuid::Uuid;
+
+use crate::core::{
+    ApplicationError, Config, DataAccess, LastLogin, Metadata, User, UserPreferences, Versioned,
+};
+use super::rows::UserRow;
+
+// The server's code for a write that breaks a unique index
+const DUPLICATE_KEY: i32 = 11000;
+
+fn mongo_error(e: mongodb::error::Error) -> ApplicationError {
+    ApplicationError::DatabaseError(e.to_string())
+}
+
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == DUPLICATE_KEY
+    )
+}
+
+fn malformed(field: &str) -> ApplicationError {
+    ApplicationError::DatabaseError(format!("a user document has a missing or malformed {}", field))
+}
+
+// A user as they are kept in the collection, `_id` is their id. The id and the date of birth are
+// strings, as the BSON serializer would otherwise write a uuid as binary and a date as a tuple.
+// Preferences, the last login and `deleted_at` share the document but are written on their own,
+// so reading a user ignores them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct UserDocument {
+    #[serde(rename = "_id")]
+    id: String,
+    email_address: String,
+    name: String,
+    password: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    date_of_birth: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    phone_number: Option<String>,
+    #[serde(default)]
+    metadata: Metadata,
+    role: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tier: Option<String>,
+    version: i64,
+}
+
+impl TryFrom<&UserRow> for UserDocument {
+    type Error = ApplicationError;
+
+    fn try_from(row: &UserRow) -> Result<Self, Self::Error> {
+        let date_of_birth = row
+            .date_of_birth
+            .map(|date| date.format(&Iso8601::DATE))
+            .transpose()
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+
+        Ok(Self {
+            id: row.id.to_string(),
+            email_address: row.email_address.clone(),
+            name: row.name.clone(),
+            password: row.password.clone(),
+            date_of_birth,
+            username: row.username.clone(),
+            phone_number: row.phone_number.clone(),
+            metadata: row.metadata.0.clone(),
+            role: row.role.clone(),
+            tier: row.tier.clone(),
+            version: row.version,
+        })
+    }
+}
+
+impl TryFrom<UserDocument> for UserRow {
+    type Error = ApplicationError;
+
+    fn try_from(document: UserDocument) -> Result<Self, Self::Error> {
+        let date_of_birth = document
+            .date_of_birth
+            .map(|date| Date::parse(&date, &Iso8601::DATE))
+            .transpose()
+            .map_err(|_| malformed("date_of_birth"))?;
+
+        Ok(Self {
+            id: Uuid::parse_str(&document.id).map_err(|_| malformed("_id"))?,
+            email_address: document.email_address,
+            name: document.name,
+            password: document.password,
+            date_of_birth,
+            username: document.username,
+            phone_number: document.phone_number,
+            metadata: Json(document.metadata),
+            role: document.role,
+            tier: document.tier,
+            version: document.version,
+        })
+    }
+}
+
+// What `update` sets and unsets, a detail the user no longer has is removed. The username is only
+// written by `set_username`, as in Postgres.
+fn updated_fields(document: &UserDocument) -> Result<(Document, Document), ApplicationError> {
+    let metadata = bson::to_bson(&document.metadata)
+        .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+    let fields = [
+        ("name", Some(Bson::String(document.name.clone()))),
+        ("password", Some(Bson::String(document.password.clone()))),
+        ("date_of_birth", document.date_of_birth.clone().map(Bson::String)),
+        ("phone_number", document.phone_number.clone().map(Bson::String)),
+        ("metadata", Some(metadata)),
+        ("role", Some(Bson::String(document.role.clone()))),
+        ("tier", document.tier.clone().map(Bson::String)),
+    ];
+
+    let mut set = Document::new();
+    let mut unset = Document::new();
+    for (field, value) in fields {
+        match value {
+            Some(value) => set.insert(field, value),
+            None => unset.insert(field, ""),
+        };
+    }
+    Ok((set, unset))
+}
+
+// The user with this address, if they aren't soft deleted
+fn live(email_address: &str) -> Document {
+    doc! { "email_address": email_address, "deleted_at": { "$exists": false } }
+}
+
+fn at_version(mut filter: Document, expected_version: Option<i64>) -> Document {
+    if let Some(expected_version) = expected_version {
+        filter.insert("version", expected_version);
+    }
+    filter
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// Users kept as documents in a MongoDB collection, to show the same trait over a document store.
+// The collection has a unique index on `email_address`, so a second registration with an address
+// fails in the server as it does on the Postgres constraint, and versioned writes filter on the
+// version like Postgres' `UPDATE ... WHERE`. Aliases, usernames, merges and erasure aren't
+// supported.
+pub struct MongoUsers {
+    users: Collection<UserDocument>,
+}
+
+impl MongoUsers {
+    pub async fn new(
+        connection_string: &str,
+        database: &str,
+        collection: &str,
+    ) -> Result<Self, ApplicationError> {
+        let client = Client::with_uri_str(connection_string).await.map_err(mongo_error)?;
+        let users = Self {
+            users: client.database(database).collection(collection),
+        };
+        users.create_indexes().await?;
+
+        Ok(users)
+    }
+
+    pub async fn from_config(config: &Config) -> Result<Self, ApplicationError> {
+        Self::new(
+            &config.mongodb_connection_string(),
+            &config.mongodb_database(),
+            &config.mongodb_collection(),
+        )
+        .await
+    }
+
+    // Creating an index that already exists does nothing, so this runs on every start as the
+    // migrations do
+    async fn create_indexes(&self) -> Result<(), ApplicationError> {
+        let email_address = IndexModel::builder()
+            .keys(doc! { "email_address": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        self.users.create_index(email_address).await.map_err(mongo_error)?;
+
+        Ok(())
+    }
+
+    // The fields written outside `UserDocument`
+    fn documents(&self) -> Collection<Document> {
+        self.users.clone_with_type()
+    }
+
+    async fn live_document(&self, email_address: &str) -> Result<UserDocument, ApplicationError> {
+        self.users
+            .find_one(live(email_address))
+            .await
+            .map_err(mongo_error)?
+            .ok_or(ApplicationError::UserDoesNotExist)
+    }
+
+    async fn live_fields(
+        &self,
+        email_address: &str,
+        projection: Document,
+    ) -> Result<Document, ApplicationError> {
+        self.documents()
+            .find_one(live(email_address))
+            .projection(projection)
+            .await
+            .map_err(mongo_error)?
+            .ok_or(ApplicationError::UserDoesNotExist)
+    }
+
+    // A filtered write that matched nothing either found no user or a different version
+    async fn missing_or_changed(&self, email_address: &str) -> ApplicationError {
+        match self.live_document(email_address).await {
+            Ok(_) => ApplicationError::VersionMismatch,
+            Err(e) => e,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl DataAccess for MongoUsers {
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "with_email_address"))]
+    async fn with_email_address(&self, email_address: &str) -> Result<User, ApplicationError> {
+        let document = self.live_document(email_address).await?;
+
+        Ok(UserRow::try_from(document)?.into())
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "with_id"))]
+    async fn with_id(&self, id: Uuid) -> Result<User, ApplicationError> {
+        let document = self
+            .users
+            .find_one(doc! { "_id": id.to_string(), "deleted_at": { "$exists": false } })
+            .await
+            .map_err(mongo_error)?
+            .ok_or(ApplicationError::UserDoesNotExist)?;
+
+        Ok(UserRow::try_from(document)?.into())
+    }
+
+    // By email address like the Postgres store, the unique index serves the sort
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "list"))]
+    async fn list(&self, offset: i64, limit: i64) -> Result<Vec<User>, ApplicationError> {
+        // A limit of 0 is no limit to the server
+        if limit <= 0 {
+            return Ok(Vec::new());
+        }
+        let mut cursor = self
+            .users
+            .find(doc! { "deleted_at": { "$exists": false } })
+            .sort(doc! { "email_address": 1 })
+            .skip(offset.max(0) as u64)
+            .limit(limit)
+            .await
+            .map_err(mongo_error)?;
+
+        let mut users = Vec::new();
+        while cursor.advance().await.map_err(mongo_error)? {
+            let document = cursor.deserialize_current().map_err(mongo_error)?;
+            users.push(UserRow::try_from(document)?.into());
+        }
+        Ok(users)
+    }
+
+    // A soft deleted user still holds their address, as in Postgres
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "store"))]
+    async fn store(&self, user: User) -> Result<(), ApplicationError> {
+        let row = UserRow {
+            version: 1,
+            ..UserRow::from(&user)
+        };
+
+        match self.users.insert_one(UserDocument::try_from(&row)?).await {
+            Ok(_) => Ok(()),
+            Err(e) if is_duplicate_key(&e) => Err(ApplicationError::UserAlreadyExists),
+            Err(e) => Err(mongo_error(e)),
+        }
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "count"))]
+    async fn count(&self) -> Result<i64, ApplicationError> {
+        let count = self
+            .users
+            .count_documents(doc! { "deleted_at": { "$exists": false } })
+            .await
+            .map_err(mongo_error)?;
+
+        Ok(count as i64)
+    }
+
+    async fn health_check(&self) -> Result<(), ApplicationError> {
+        self.users
+            .client()
+            .database("admin")
+            .run_command(doc! { "ping": 1 })
+            .await
+            .map(|_| ())
+            .map_err(mongo_error)
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "with_email_address_versioned"))]
+    async fn with_email_address_versioned(
+        &self,
+        email_address: &str,
+    ) -> Result<Versioned<User>, ApplicationError> {
+        let document = self.live_document(email_address).await?;
+
+        Ok(UserRow::try_from(document)?.into())
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "update"))]
+    async fn update(
+        &self,
+        user: User,
+        expected_version: Option<i64>,
+    ) -> Result<i64, ApplicationError> {
+        let document = UserDocument::try_from(&UserRow::from(&user))?;
+        let (set, unset) = updated_fields(&document)?;
+        let mut update = doc! { "$set": set, "$inc": { "version": 1 } };
+        // An empty `$unset` is rejected by servers before 5.0
+        if !unset.is_empty() {
+            update.insert("$unset", unset);
+        }
+        let mut filter = at_version(live(&document.email_address), expected_version);
+        filter.insert("_id", &document.id);
+
+        let updated = self
+            .users
+            .find_one_and_update(filter, update)
+            .return_document(ReturnDocument::After)
+            .await
+            .map_err(mongo_error)?;
+
+        match updated {
+            Some(updated) => Ok(updated.version),
+            None => Err(self.missing_or_changed(&document.email_address).await),
+        }
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "soft_delete"))]
+    async fn soft_delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        let deleted = self
+            .users
+            .update_one(
+                at_version(live(email_address), expected_version),
+                doc! { "$set": { "deleted_at": now() }, "$inc": { "version": 1 } },
+            )
+            .await
+            .map_err(mongo_error)?;
+
+        if deleted.matched_count == 0 {
+            return Err(self.missing_or_changed(email_address).await);
+        }
+        Ok(())
+    }
+
+    // Also removes a user that was soft deleted
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "delete"))]
+    async fn delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        let deleted = self
+            .users
+            .delete_one(at_version(doc! { "email_address": email_address }, expected_version))
+            .await
+            .map_err(mongo_error)?;
+        if deleted.deleted_count > 0 {
+            return Ok(());
+        }
+
+        // Unlike the other writes a soft deleted user is still found here
+        let found = self
+            .users
+            .find_one(doc! { "email_address": email_address })
+            .await
+            .map_err(mongo_error)?;
+        match found {
+            Some(_) => Err(ApplicationError::VersionMismatch),
+            None => Err(ApplicationError::UserDoesNotExist),
+        }
+    }
+
+    // An embedded document on the user's, outside the fields `update` writes, so neither changes
+    // the version
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "preferences"))]
+    async fn preferences(&self, email_address: &str) -> Result<UserPreferences, ApplicationError> {
+        let fields = self.live_fields(email_address, doc! { "preferences": 1 }).await?;
+
+        match fields.get_document("preferences") {
+            Ok(preferences) => {
+                bson::from_document(preferences.clone()).map_err(|_| malformed("preferences"))
+            }
+            Err(_) => Ok(UserPreferences::default()),
+        }
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "update_preferences"))]
+    async fn update_preferences(
+        &self,
+        email_address: &str,
+        preferences: &UserPreferences,
+    ) -> Result<(), ApplicationError> {
+        let preferences = bson::to_document(preferences)
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+
+        let updated = self
+            .users
+            .update_one(live(email_address), doc! { "$set": { "preferences": preferences } })
+            .await
+            .map_err(mongo_error)?;
+
+        if updated.matched_count == 0 {
+            return Err(ApplicationError::UserDoesNotExist);
+        }
+        Ok(())
+    }
+
+    // A login that finishes after a later one doesn't take its place, the filter doesn't match and
+    // the later one is kept
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "record_login"))]
+    async fn record_login(
+        &self,
+        email_address: &str,
+        logged_in_at: u64,
+        ip: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        let logged_in_at = logged_in_at as i64;
+        let mut filter = live(email_address);
+        filter.insert(
+            "$or",
+            vec![
+                doc! { "last_login_at": { "$exists": false } },
+                doc! { "last_login_at": { "$lte": logged_in_at } },
+            ],
+        );
+        let update = match ip {
+            Some(ip) => doc! { "$set": { "last_login_at": logged_in_at, "last_login_ip": ip } },
+            None => doc! {
+                "$set": { "last_login_at": logged_in_at },
+                "$unset": { "last_login_ip": "" },
+            },
+        };
+
+        let recorded = self.users.update_one(filter, update).await.map_err(mongo_error)?;
+
+        if recorded.matched_count == 0 {
+            self.live_document(email_address).await?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "last_login"))]
+    async fn last_login(&self, email_address: &str) -> Result<Option<LastLogin>, ApplicationError> {
+        let fields = self
+            .live_fields(email_address, doc! { "last_login_at": 1, "last_login_ip": 1 })
+            .await?;
+
+        Ok(fields.get_i64("last_login_at").ok().map(|last_login_at| LastLogin {
+            last_login_at: last_login_at as u64,
+            last_login_ip: fields.get_str("last_login_ip").ok().map(str::to_string),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Tier;
+
+    #[test]
+    fn a_user_should_read_back_from_their_document_as_they_were_written() {
+        let mut metadata = Metadata::new();
+        metadata.insert("referrer".to_string(), serde_json::json!({ "campaign": 7 }));
+        let user = User::builder("james@test.com", "James")
+            .hashed_password("hashed")
+            .date_of_birth(Some(Date::from_calendar_date(1990, time::Month::June, 15).unwrap()))
+            .metadata(metadata)
+            .tier(Some(Tier::Gold))
+            .build()
+            .unwrap();
+        let row = UserRow::from(&user);
+
+        let document = bson::to_document(&UserDocument::try_from(&row).unwrap()).unwrap();
+
+        assert_eq!(document.get_str("_id").unwrap(), row.id.to_string());
+        assert_eq!(document.get_str("date_of_birth").unwrap(), "1990-06-15");
+        assert!(!document.contains_key("phone_number"));
+        let read: UserDocument = bson::from_document(document).unwrap();
+        assert_eq!(UserRow::try_from(read).unwrap(), row);
+    }
+
+    #[test]
+    fn an_update_should_unset_the_details_a_user_no_longer_has() {
+        let row = UserRow::from(&User::from("james@test.com", "James", "hashed"));
+
+        let (set, unset) = updated_fields(&UserDocument::try_from(&row).unwrap()).unwrap();
+
+        assert_eq!(set.get_str("name").unwrap(), "James");
+        assert!(!set.contains_key("username"));
+        assert_eq!(
+            unset.keys().collect::<Vec<_>>(),
+            vec!["date_of_birth", "phone_number", "tier"]
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use sqlx::{PgConnection, PgPool};
+
+use crate::core::ApplicationError;
+use super::postgres::database_label;
+
+// An event written in the same transaction as the change it describes, so it is published if and
+// only if the change was committed. `trace_context` is the W3C `traceparent` of the span that
+// wrote it, which the relay links its publish span back to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutboxEntry {
+    pub id: String,
+    pub topic: String,
+    pub key: String,
+    pub payload: String,
+    pub trace_context: Option<String>,
+    pub created_at: u64,
+}
+
+#[tracing::instrument(name = "events.enqueue", skip_all, fields(messaging.destination.name = %entry.topic))]
+pub(crate) async fn enqueue(
+    connection: &mut PgConnection,
+    entry: &OutboxEntry,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO outbox ( id, topic, key, payload, trace_context, created_at )
+        VALUES ( $1, $2, $3, $4, $5, $6 )
+        "#,
+        entry.id,
+        entry.topic,
+        entry.key,
+        entry.payload,
+        entry.trace_context,
+        entry.created_at as i64,
+    )
+        .execute(connection)
+        .await?;
+
+    Ok(())
+}
+
+// What the relay hasn't published yet
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OutboxBacklog {
+    pub pending: i64,
+    // Seconds since the epoch the oldest of them was written, `None` when nothing is pending
+    pub oldest_created_at: Option<u64>,
+}
+
+pub struct PostgresOutbox {
+    db: PgPool,
+}
+
+impl PostgresOutbox {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+
+    // Host, port and database name, as the pool statistics label them
+    pub fn database(&self) -> String {
+        database_label(&self.db)
+    }
+
+    // Counted with the same partial index `pending` reads
+    pub async fn backlog(&self) -> Result<OutboxBacklog, ApplicationError> {
+        let record = sqlx::query!(
+            r#"
+            SELECT COUNT(*) AS "pending!", MIN(created_at) AS oldest_created_at
+            FROM outbox
+            WHERE published_at IS NULL
+            "#,
+        )
+            .fetch_one(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        Ok(OutboxBacklog {
+            pending: record.pending,
+            oldest_created_at: record.oldest_created_at.map(|created_at| created_at as u64),
+        })
+    }
+
+    // Oldest first, so events for the same key are published in the order they were written
+    pub async fn pending(&self, limit: i64) -> Result<Vec<OutboxEntry>, ApplicationError> {
+        let records = sqlx::query!(
+            r#"
+            SELECT id, topic, key, payload, trace_context, created_at
+            FROM outbox
+            WHERE published_at IS NULL
+            ORDER BY created_at, id
+            LIMIT $1
+            "#,
+            limit,
+        )
+            .fetch_all(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        Ok(records
+            .into_iter()
+            .map(|record| OutboxEntry {
+                id: record.id,
+                topic: record.topic,
+                key: record.key,
+                payload: record.payload,
+                trace_context: record.trace_context,
+                created_at: record.created_at as u64,
+            })
+            .collect())
+    }
+
+    pub async fn mark_published(&self, id: &str, published_at: u64) -> Result<(), ApplicationError> {
+        sqlx::query!(
+            r#"
+            UPDATE outbox
+            SET published_at = $2
+            WHERE id = $1
+            "#,
+            id,
+            published_at as i64,
+        )
+            .execute(&self.db)
+            .await
+            .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1940 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::types::Json;
+use sqlx::{Connection, PgConnection, PgPool, Postgres, Transaction};
+use time::Date;
+use uuid::Uuid;
+use crate::clock::Clock;
+use crate::core::{
+    ApplicationError, Config, DataAccess, EmailAlias, LastLogin, Metadata, User, UserPreferences,
+    Versioned,
+};
+use crate::metrics::{
+    Metrics, DB_POOL_ACQUIRE_ERRORS_TOTAL, DB_POOL_ACQUIRE_WAIT_SECONDS, DB_POOL_CONNECTIONS,
+    DB_POOL_IDLE_CONNECTIONS, DB_STATEMENT_EXECUTIONS_TOTAL, DB_STATEMENT_PREPARES_TOTAL,
+};
+use crate::events::{UserErasedEvent, UserMergedEvent, UserRegisteredEvent};
+use crate::retry::RetryPolicy;
+use super::UnitOfWork;
+use super::audit::{self, AuditEntry};
+use super::outbox::{self, OutboxEntry, PostgresOutbox};
+use super::rows::{erased_email_address, ListedUserRow, SearchedUserRow, UserRow, ERASED_NAME};
+
+#[derive(Clone, Debug)]
+pub struct PoolSettings {
+    // Number of prepared statements each connection keeps, 0 re-prepares every statement
+    pub statement_cache_capacity: usize,
+    // Connections the pool keeps open even when idle, 0 only opens them on demand
+    pub min_connections: u32,
+    pub max_connections: u32,
+    // How long a query waits for a free connection before it fails
+    pub acquire_timeout: Duration,
+    // How long a connection above `min_connections` may sit idle before it is closed, `None` keeps
+    // them open
+    pub idle_timeout: Option<Duration>,
+    // How long a statement may run before Postgres cancels it with `DatabaseTimeout`, `None` lets
+    // it run for as long as it takes
+    pub statement_timeout: Option<Duration>,
+}
+
+// sqlx's own defaults
+impl Default for PoolSettings {
+    fn default() -> Self {
+        Self {
+            statement_cache_capacity: 100,
+            min_connections: 0,
+            max_connections: 10,
+            acquire_timeout: Duration::from_secs(30),
+            idle_timeout: Some(Duration::from_secs(600)),
+            statement_timeout: None,
+        }
+    }
+}
+
+impl PoolSettings {
+    // Warming up keeps at least its connections open. The pool never holds fewer connections than
+    // it keeps open, nor none at all.
+    pub fn from_config(config: &Config) -> Self {
+        let defaults = PoolSettings::default();
+        let min_connections = config
+            .min_connections()
+            .unwrap_or(defaults.min_connections)
+            .max(config.warmup_connections());
+        let max_connections = config
+            .max_connections()
+            .unwrap_or(defaults.max_connections)
+            .max(min_connections)
+            .max(1);
+
+        Self {
+            statement_cache_capacity: config
+                .statement_cache_capacity()
+                .unwrap_or(defaults.statement_cache_capacity),
+            min_connections,
+            max_connections,
+            acquire_timeout: config
+                .acquire_timeout_ms()
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.acquire_timeout),
+            idle_timeout: match config.idle_timeout_seconds() {
+                Some(0) => None,
+                Some(seconds) => Some(Duration::from_secs(seconds)),
+                None => defaults.idle_timeout,
+            },
+            statement_timeout: config
+                .statement_timeout_ms()
+                .filter(|timeout_ms| *timeout_ms > 0)
+                .map(Duration::from_millis),
+        }
+    }
+}
+
+pub struct PostgresUsers {
+    db: PgPool,
+    // Where lookups and listings are read from, see `with_reads`
+    reads: Option<PgPool>,
+    statement_cache_capacity: usize,
+    metrics: Arc<Metrics>,
+    // Whether `store` also writes a user-registered event to the outbox
+    outbox: bool,
+    // Dates the events written to the outbox, the API's so they agree with its sessions
+    clock: Arc<Clock>,
+    // The longest a query waited for a connection from `db` and `reads` since the pool statistics
+    // were last recorded, in microseconds
+    primary_wait_us: Arc<AtomicU64>,
+    replica_wait_us: Arc<AtomicU64>,
+}
+
+pub async fn connect(
+    connection_string: &str,
+    settings: &PoolSettings,
+) -> Result<PgPool, ApplicationError> {
+    let idle_timeout = match settings.idle_timeout {
+        Some(idle_timeout) => format!("an idle timeout of {:?}", idle_timeout),
+        None => "no idle timeout".to_string(),
+    };
+    let statement_timeout = match settings.statement_timeout {
+        Some(statement_timeout) => format!("a statement timeout of {:?}", statement_timeout),
+        None => "no statement timeout".to_string(),
+    };
+    log::info!(
+        "Attempting to connect to the database with a statement cache of {}, {} to {} connections, \
+         an acquire timeout of {:?}, {} and {}",
+        settings.statement_cache_capacity,
+        settings.min_connections,
+        settings.max_connections,
+        settings.acquire_timeout,
+        idle_timeout,
+        statement_timeout
+    );
+
+    let mut options = PgConnectOptions::from_str(connection_string)
+        .map_err(database_error)?
+        .statement_cache_capacity(settings.statement_cache_capacity);
+    // Set on every connection as it is opened, so it covers each statement whoever runs it
+    if let Some(statement_timeout) = settings.statement_timeout {
+        options = options.options([(
+            "statement_timeout",
+            statement_timeout.as_millis().to_string(),
+        )]);
+    }
+
+    RetryPolicy::builder()
+        .max_attempts(5)
+        .base_delay(Duration::from_millis(500))
+        .build()
+        .retry("database connect", || {
+            PgPoolOptions::new()
+                .min_connections(settings.min_connections)
+                .max_connections(settings.max_connections)
+                .acquire_timeout(settings.acquire_timeout)
+                .idle_timeout(settings.idle_timeout)
+                .connect_with(options.clone())
+        })
+        .await
+        .map_err(database_error)
+}
+
+// Host, port and database name, which tells apart the pools of shards on one server too
+pub(crate) fn database_label(pool: &PgPool) -> String {
+    let options = pool.connect_options();
+
+    format!(
+        "{}:{}/{}",
+        options.get_host(),
+        options.get_port(),
+        options.get_database().unwrap_or_default()
+    )
+}
+
+// Every query's connection comes from here, so how long it waited for one is kept for the next
+// pool statistics and each acquire that fails, e.g. because the pool was exhausted for the whole
+// acquire timeout, is counted
+async fn acquire(
+    pool: &PgPool,
+    name: &'static str,
+    longest_wait_us: &AtomicU64,
+    metrics: &Metrics,
+) -> Result<PoolConnection<Postgres>, ApplicationError> {
+    let started = Instant::now();
+    let acquired = pool.acquire().await;
+    longest_wait_us.fetch_max(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+
+    acquired.map_err(|e| {
+        metrics.increment_with_labels(
+            DB_POOL_ACQUIRE_ERRORS_TOTAL,
+            &[("database", &database_label(pool)), ("pool", name)],
+        );
+        database_error(e)
+    })
+}
+
+// The pools of one `PostgresUsers`, see `PostgresUsers::pool_statistics`
+#[derive(Clone)]
+pub struct PoolStatistics {
+    pools: Vec<(&'static str, PgPool, Arc<AtomicU64>)>,
+    metrics: Arc<Metrics>,
+}
+
+impl PoolStatistics {
+    // The wait is the longest since the previous call, a quiet pool reports 0 again
+    pub fn record(&self) {
+        for (name, pool, longest_wait_us) in &self.pools {
+            let database = database_label(pool);
+            let labels = [("database", database.as_str()), ("pool", *name)];
+            let longest_wait = Duration::from_micros(longest_wait_us.swap(0, Ordering::Relaxed));
+
+            self.metrics.set_gauge(DB_POOL_CONNECTIONS, &labels, pool.size() as f64);
+            self.metrics.set_gauge(DB_POOL_IDLE_CONNECTIONS, &labels, pool.num_idle() as f64);
+            self.metrics
+                .set_gauge(DB_POOL_ACQUIRE_WAIT_SECONDS, &labels, longest_wait.as_secs_f64());
+        }
+    }
+}
+
+pub async fn run_pool_statistics(statistics: PoolStatistics, interval: Duration) {
+    log::info!("Recording database pool statistics every {:?}", interval);
+
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+        statistics.record();
+    }
+}
+
+impl PostgresUsers {
+    pub async fn new(
+        connection_string: String,
+        settings: &PoolSettings,
+    ) -> Result<Self, ApplicationError> {
+        let database_pool = connect(&connection_string, settings).await?;
+
+        Ok(Self {
+            db: database_pool,
+            reads: None,
+            statement_cache_capacity: settings.statement_cache_capacity,
+            metrics: Arc::new(Metrics::default()),
+            outbox: false,
+            clock: Arc::new(Clock::default()),
+            primary_wait_us: Arc::default(),
+            replica_wait_us: Arc::default(),
+        })
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn with_outbox(mut self, enabled: bool) -> Self {
+        self.outbox = enabled;
+        self
+    }
+
+    // The outbox `with_outbox` writes to, read through the same pool
+    pub fn outbox(&self) -> PostgresOutbox {
+        PostgresOutbox::new(self.db.clone())
+    }
+
+    pub fn with_clock(mut self, clock: Arc<Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Sends `with_email_address`, `with_id` and listings to `reads`, e.g. a replica of the
+    // database, everything else stays on the primary. A replica lags behind, so a user can be
+    // missing from it or out of date for a moment after they are written. Versioned reads stay on
+    // the primary, their version is what conditional writes are checked against.
+    pub fn with_reads(mut self, reads: PgPool) -> Self {
+        self.reads = Some(reads);
+        self
+    }
+
+    fn reads(&self) -> &PgPool {
+        self.reads.as_ref().unwrap_or(&self.db)
+    }
+
+    async fn acquire_primary(&self) -> Result<PoolConnection<Postgres>, ApplicationError> {
+        acquire(&self.db, "primary", &self.primary_wait_us, &self.metrics).await
+    }
+
+    async fn acquire_reads(&self) -> Result<PoolConnection<Postgres>, ApplicationError> {
+        match &self.reads {
+            Some(reads) => acquire(reads, "replica", &self.replica_wait_us, &self.metrics).await,
+            None => self.acquire_primary().await,
+        }
+    }
+
+    // What `run_pool_statistics` samples, taken before the store is wrapped or moved into the API.
+    // Record to the same registry as `with_metrics`, which should be set first.
+    pub fn pool_statistics(&self) -> PoolStatistics {
+        let mut pools = vec![("primary", self.db.clone(), self.primary_wait_us.clone())];
+        if let Some(reads) = &self.reads {
+            pools.push(("replica", reads.clone(), self.replica_wait_us.clone()));
+        }
+
+        PoolStatistics {
+            pools,
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    // sqlx doesn't report cache misses, so a prepare is inferred when the connection's statement
+    // cache grew while running the query. With the cache disabled every execution is prepared.
+    // Evictions from a full cache are not visible and are counted as reuse.
+    fn record_statement(&self, query: &'static str, cached_before: usize, cached_after: usize) {
+        self.metrics
+            .increment_with_labels(DB_STATEMENT_EXECUTIONS_TOTAL, &[("query", query)]);
+
+        if self.statement_cache_capacity == 0 || cached_after > cached_before {
+            self.metrics
+                .increment_with_labels(DB_STATEMENT_PREPARES_TOTAL, &[("query", query)]);
+        }
+    }
+}
+
+// `false` when the address is someone's verified alias, which is taken as much as a primary one
+async fn insert_user(connection: &mut PgConnection, row: &UserRow) -> Result<bool, sqlx::Error> {
+    let inserted = sqlx::query!(
+        r#"
+    INSERT INTO users (
+        id, email_address, name, password, date_of_birth, username, phone_number, metadata, role,
+        tier
+    )
+    SELECT $1, $2::VARCHAR, $3, $4, $5, $6, $7, $8, $9, $10
+    WHERE NOT EXISTS (
+        SELECT 1 FROM user_emails WHERE email_address = $2::VARCHAR AND verified_at IS NOT NULL
+    )
+        "#,
+        row.id,
+        row.email_address,
+        row.name,
+        row.password,
+        row.date_of_birth,
+        row.username,
+        row.phone_number,
+        &row.metadata as _,
+        row.role,
+        row.tier,
+    )
+        .execute(connection)
+        .await?;
+
+    Ok(inserted.rows_affected() > 0)
+}
+
+// Every row in one statement, as arrays unnested into rows. Returns how many were inserted, none
+// when any of the addresses is someone's verified alias.
+async fn insert_users(connection: &mut PgConnection, rows: &[UserRow]) -> Result<u64, sqlx::Error> {
+    let ids: Vec<Uuid> = rows.iter().map(|row| row.id).collect();
+    let email_addresses: Vec<String> = rows.iter().map(|row| row.email_address.clone()).collect();
+    let names: Vec<String> = rows.iter().map(|row| row.name.clone()).collect();
+    let passwords: Vec<String> = rows.iter().map(|row| row.password.clone()).collect();
+    let dates_of_birth: Vec<Option<Date>> = rows.iter().map(|row| row.date_of_birth).collect();
+    let usernames: Vec<Option<String>> = rows.iter().map(|row| row.username.clone()).collect();
+    let phone_numbers: Vec<Option<String>> =
+        rows.iter().map(|row| row.phone_number.clone()).collect();
+    let metadata: Vec<Json<Metadata>> = rows.iter().map(|row| row.metadata.clone()).collect();
+    let roles: Vec<String> = rows.iter().map(|row| row.role.clone()).collect();
+    let tiers: Vec<Option<String>> = rows.iter().map(|row| row.tier.clone()).collect();
+
+    let inserted = sqlx::query!(
+        r#"
+    INSERT INTO users (
+        id, email_address, name, password, date_of_birth, username, phone_number, metadata, role,
+        tier
+    )
+    SELECT * FROM UNNEST(
+        $1::UUID[], $2::VARCHAR[], $3::VARCHAR[], $4::VARCHAR[], $5::DATE[], $6::VARCHAR[],
+        $7::VARCHAR[], $8::JSONB[], $9::VARCHAR[], $10::VARCHAR[]
+    )
+    WHERE NOT EXISTS (
+        SELECT 1 FROM user_emails
+        WHERE email_address = ANY($2::VARCHAR[]) AND verified_at IS NOT NULL
+    )
+        "#,
+        &ids,
+        &email_addresses,
+        &names,
+        &passwords,
+        &dates_of_birth as &[Option<Date>],
+        &usernames as &[Option<String>],
+        &phone_numbers as &[Option<String>],
+        &metadata as _,
+        &roles,
+        &tiers as &[Option<String>],
+    )
+        .execute(connection)
+        .await?;
+
+    Ok(inserted.rows_affected())
+}
+
+// The new version, `None` when there is no live user with the row's id or `expected_version`
+// isn't theirs. The version check and the write are one statement, so a concurrent update can't
+// slip in between them.
+async fn update_user(
+    connection: &mut PgConnection,
+    row: &UserRow,
+    expected_version: Option<i64>,
+) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        UPDATE users
+        SET name = $2, password = $3, date_of_birth = $4, phone_number = $5, metadata = $6,
+            role = $7, tier = $8, version = version + 1
+        WHERE id = $1 AND deleted_at IS NULL AND ($9::BIGINT IS NULL OR version = $9)
+        RETURNING version
+        "#,
+        row.id,
+        row.name,
+        row.password,
+        row.date_of_birth,
+        row.phone_number,
+        &row.metadata as _,
+        row.role,
+        row.tier,
+        expected_version,
+    )
+        .fetch_optional(connection)
+        .await
+}
+
+// Postgres cancelled the statement for running past `statement_timeout`
+const QUERY_CANCELED: &str = "57014";
+
+// A statement that ran out of time, or a query that waited too long for a connection, is told
+// apart from other failures as it's worth retrying later
+fn database_error(e: sqlx::Error) -> ApplicationError {
+    let cancelled = e
+        .as_database_error()
+        .and_then(|database_error| database_error.code())
+        .is_some_and(|code| code == QUERY_CANCELED);
+
+    match cancelled || matches!(e, sqlx::Error::PoolTimedOut) {
+        true => ApplicationError::DatabaseTimeout(e.to_string()),
+        false => ApplicationError::DatabaseError(e.to_string()),
+    }
+}
+
+// A verified alias already holding the address is reported the same as a user with it
+fn store_error(e: sqlx::Error) -> ApplicationError {
+    match e
+        .as_database_error()
+        .is_some_and(|database_error| database_error.is_unique_violation())
+    {
+        true => ApplicationError::UserAlreadyExists,
+        false => database_error(e),
+    }
+}
+
+// The user-registered event a store writes along with the user, when it writes to the outbox
+fn registered_entry(
+    user: &User,
+    outbox: bool,
+    clock: &Clock,
+) -> Result<Option<OutboxEntry>, ApplicationError> {
+    match outbox {
+        true => {
+            let event = UserRegisteredEvent::registered(
+                user,
+                clock.now(),
+                crate::outbox::current_trace_context(),
+            );
+            event.into_outbox_entry().map(Some)
+        }
+        false => Ok(None),
+    }
+}
+
+impl PostgresUsers {
+    // A conditional write that touched no rows either found no user or a different version
+    async fn missing_or_changed(&self, email_address: &str) -> ApplicationError {
+        match self.with_email_address(email_address).await {
+            Ok(_) => ApplicationError::VersionMismatch,
+            Err(e) => e,
+        }
+    }
+}
+
+// The id of the live user with `email_address`, aliases are kept by it
+async fn live_id(connection: &mut PgConnection, email_address: &str) -> Result<Uuid, ApplicationError> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT id FROM users WHERE email_address = $1 AND deleted_at IS NULL
+        "#,
+        email_address,
+    )
+        .fetch_optional(connection)
+        .await
+        .map_err(database_error)?
+        .ok_or(ApplicationError::UserDoesNotExist)
+}
+
+// The writes of a `begin`, in one transaction that `commit` commits. sqlx rolls it back when the
+// transaction is dropped without committing.
+struct PostgresUnitOfWork {
+    transaction: Transaction<'static, Postgres>,
+    outbox: bool,
+    clock: Arc<Clock>,
+}
+
+#[async_trait::async_trait]
+impl UnitOfWork for PostgresUnitOfWork {
+    async fn store(&mut self, user: &User) -> Result<(), ApplicationError> {
+        let entry = registered_entry(user, self.outbox, &self.clock)?;
+        if !insert_user(&mut self.transaction, &UserRow::from(user))
+            .await
+            .map_err(store_error)?
+        {
+            return Err(ApplicationError::UserAlreadyExists);
+        }
+
+        match entry {
+            Some(entry) => self.enqueue(&entry).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn update(
+        &mut self,
+        user: &User,
+        expected_version: Option<i64>,
+    ) -> Result<i64, ApplicationError> {
+        let version = update_user(&mut self.transaction, &UserRow::from(user), expected_version)
+            .await
+            .map_err(database_error)?;
+
+        match version {
+            Some(version) => Ok(version),
+            None => match live_id(&mut self.transaction, &user.email_address()).await {
+                Ok(_) => Err(ApplicationError::VersionMismatch),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    async fn enqueue(&mut self, entry: &OutboxEntry) -> Result<(), ApplicationError> {
+        outbox::enqueue(&mut self.transaction, entry)
+            .await
+            .map_err(database_error)
+    }
+
+    async fn record(&mut self, entry: &AuditEntry) -> Result<(), ApplicationError> {
+        audit::insert_entry(&mut self.transaction, entry)
+            .await
+            .map_err(database_error)
+    }
+
+    async fn commit(self: Box<Self>) -> Result<(), ApplicationError> {
+        self.transaction
+            .commit()
+            .await
+            .map_err(database_error)
+    }
+}
+
+#[async_trait::async_trait]
+impl DataAccess for PostgresUsers {
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "with_email_address"))]
+    async fn with_email_address(&self, email_address: &str) -> Result<User, ApplicationError> {
+        log::info!("Attempting to retrieve user from email address");
+
+        let mut connection = self.acquire_reads().await?;
+        let cached_before = connection.cached_statements_size();
+        
+        let email = sqlx::query_as!(
+            UserRow,
+            r#"
+            SELECT id, email_address, name, password, date_of_birth, username, phone_number,
+                metadata AS "metadata: Json<Metadata>", role, tier, version
+            FROM users
+            WHERE email_address = $1 AND deleted_at IS NULL
+            "#,
+            email_address,
+        )
+            .fetch_optional(&mut *connection)
+            .await;
+
+        self.record_statement(
+            "with_email_address",
+            cached_before,
+            connection.cached_statements_size(),
+        );
+        
+        // Only a missing row is a user that doesn't exist, a store that can't answer is an outage
+        match email {
+            Ok(Some(row)) => Ok(row.into()),
+            Ok(None) => Err(ApplicationError::UserDoesNotExist),
+            Err(e) => Err(database_error(e)),
+        }
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "with_id"))]
+    async fn with_id(&self, id: Uuid) -> Result<User, ApplicationError> {
+        log::info!("Attempting to retrieve user from id");
+
+        let mut connection = self.acquire_reads().await?;
+        let cached_before = connection.cached_statements_size();
+
+        let record = sqlx::query_as!(
+            UserRow,
+            r#"
+            SELECT id, email_address, name, password, date_of_birth, username, phone_number,
+                metadata AS "metadata: Json<Metadata>", role, tier, version
+            FROM users
+            WHERE id = $1 AND deleted_at IS NULL
+            "#,
+            id,
+        )
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(database_error)?;
+
+        self.record_statement("with_id", cached_before, connection.cached_statements_size());
+
+        record
+            .map(|row| row.into())
+            .ok_or(ApplicationError::UserDoesNotExist)
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "list"))]
+    async fn list(&self, offset: i64, limit: i64) -> Result<Vec<User>, ApplicationError> {
+        log::info!("Attempting to list users");
+
+        let mut connection = self.acquire_reads().await?;
+        let cached_before = connection.cached_statements_size();
+
+        let records = sqlx::query_as!(
+            UserRow,
+            r#"
+            SELECT id, email_address, name, password, date_of_birth, username, phone_number,
+                metadata AS "metadata: Json<Metadata>", role, tier, version
+            FROM users
+            WHERE deleted_at IS NULL
+            ORDER BY email_address
+            LIMIT $1 OFFSET $2
+            "#,
+            limit,
+            offset,
+        )
+            .fetch_all(&mut *connection)
+            .await;
+
+        self.record_statement("list", cached_before, connection.cached_statements_size());
+
+        let records = records.map_err(database_error)?;
+
+        Ok(records.into_iter().map(Into::into).collect())
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "list_after"))]
+    async fn list_after(
+        &self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<User>, ApplicationError> {
+        let mut connection = self.acquire_reads().await?;
+        let cached_before = connection.cached_statements_size();
+
+        // Seeks on the email address index rather than counting through an offset
+        let records = sqlx::query_as!(
+            UserRow,
+            r#"
+            SELECT id, email_address, name, password, date_of_birth, username, phone_number,
+                metadata AS "metadata: Json<Metadata>", role, tier, version
+            FROM users
+            WHERE deleted_at IS NULL AND ($1::text IS NULL OR email_address > $1)
+            ORDER BY email_address
+            LIMIT $2
+            "#,
+            after,
+            limit,
+        )
+            .fetch_all(&mut *connection)
+            .await;
+
+        self.record_statement("list_after", cached_before, connection.cached_statements_size());
+
+        let records = records.map_err(database_error)?;
+
+        Ok(records.into_iter().map(Into::into).collect())
+    }
+
+    #[tracing::instrument(
+        name = "db.query",
+        skip_all,
+        fields(db.operation = "list_including_deleted")
+    )]
+    async fn list_including_deleted(
+        &self,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<(User, Option<u64>)>, ApplicationError> {
+        let mut connection = self.acquire_reads().await?;
+        let cached_before = connection.cached_statements_size();
+
+        let records = sqlx::query_as::<_, ListedUserRow>(
+            r#"
+            SELECT id, email_address, name, password, date_of_birth, username, phone_number,
+                metadata, role, tier, version, deleted_at
+            FROM users
+            ORDER BY email_address
+            LIMIT $1 OFFSET $2
+            "#,
+        )
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&mut *connection)
+            .await;
+
+        self.record_statement(
+            "list_including_deleted",
+            cached_before,
+            connection.cached_statements_size(),
+        );
+
+        let records = records.map_err(database_error)?;
+
+        Ok(records
+            .into_iter()
+            .map(|row| (row.user.into(), row.deleted_at.map(|deleted_at| deleted_at as u64)))
+            .collect())
+    }
+
+    // Served by the GIN index on the generated `search` column, ties go in email address order so
+    // pages don't overlap
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "search_ranked"))]
+    async fn search_ranked(
+        &self,
+        query: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<(User, f32)>, ApplicationError> {
+        let mut connection = self.acquire_reads().await?;
+        let cached_before = connection.cached_statements_size();
+
+        let records = sqlx::query_as::<_, SearchedUserRow>(
+            r#"
+            SELECT id, email_address, name, password, date_of_birth, username, phone_number,
+                metadata, role, tier, version, ts_rank(search, query) AS rank
+            FROM users, websearch_to_tsquery('simple', $1) AS query
+            WHERE search @@ query AND deleted_at IS NULL
+            ORDER BY rank DESC, email_address
+            LIMIT $2 OFFSET $3
+            "#,
+        )
+            .bind(query)
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&mut *connection)
+            .await;
+
+        self.record_statement("search_ranked", cached_before, connection.cached_statements_size());
+
+        let records = records.map_err(database_error)?;
+
+        Ok(records.into_iter().map(|row| (row.user.into(), row.rank)).collect())
+    }
+
+    // One query whose rows are decoded as they arrive, on a connection the stream holds until it
+    // ends. The pool picks the connection, so only the execution is counted, not whether the
+    // statement had to be prepared.
+    fn stream_all(&self) -> BoxStream<'_, Result<User, ApplicationError>> {
+        log::info!("Attempting to stream users");
+        self.metrics
+            .increment_with_labels(DB_STATEMENT_EXECUTIONS_TOTAL, &[("query", "stream_all")]);
+
+        sqlx::query_as!(
+            UserRow,
+            r#"
+            SELECT id, email_address, name, password, date_of_birth, username, phone_number,
+                metadata AS "metadata: Json<Metadata>", role, tier, version
+            FROM users
+            WHERE deleted_at IS NULL
+            ORDER BY email_address
+            "#,
+        )
+            .fetch(self.reads())
+            .map_ok(<User as From<UserRow>>::from)
+            .map_err(database_error)
+            .boxed()
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "store"))]
+    async fn store(&self, user: User) -> Result<(), ApplicationError> {
+        log::info!("Attempting to create user in the database");
+
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+        let row = UserRow::from(&user);
+        let entry = registered_entry(&user, self.outbox, &self.clock)?;
+
+        let result = match &entry {
+            None => insert_user(&mut connection, &row).await,
+            // The event commits or rolls back with the user, so it can't be lost or sent for a
+            // registration that failed
+            Some(entry) => async {
+                let mut transaction = connection.begin().await?;
+                if !insert_user(&mut transaction, &row).await? {
+                    return Ok(false);
+                }
+                outbox::enqueue(&mut transaction, entry).await?;
+                transaction.commit().await.map(|_| true)
+            }
+            .await,
+        };
+
+        self.record_statement("store", cached_before, connection.cached_statements_size());
+
+        match result {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(ApplicationError::UserAlreadyExists),
+            Err(e) => Err(store_error(e)),
+        }
+    }
+
+    // A repeated or taken address fails the statement as a whole, so nothing is stored
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "store_many"))]
+    async fn store_many(&self, users: Vec<User>) -> Result<(), ApplicationError> {
+        if users.is_empty() {
+            return Ok(());
+        }
+        log::info!("Attempting to create {} users in the database", users.len());
+
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+        let rows: Vec<UserRow> = users.iter().map(UserRow::from).collect();
+        let entries = users
+            .iter()
+            .map(|user| registered_entry(user, self.outbox, &self.clock))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+
+        let result = match entries.is_empty() {
+            true => insert_users(&mut connection, &rows).await,
+            false => async {
+                let mut transaction = connection.begin().await?;
+                let inserted = insert_users(&mut transaction, &rows).await?;
+                if inserted < rows.len() as u64 {
+                    return Ok(inserted);
+                }
+                for entry in &entries {
+                    outbox::enqueue(&mut transaction, entry).await?;
+                }
+                transaction.commit().await.map(|_| inserted)
+            }
+            .await,
+        };
+
+        self.record_statement("store_many", cached_before, connection.cached_statements_size());
+
+        match result {
+            Ok(inserted) if inserted == rows.len() as u64 => Ok(()),
+            Ok(_) => Err(ApplicationError::UserAlreadyExists),
+            Err(e) => Err(store_error(e)),
+        }
+    }
+
+    async fn begin(&self) -> Result<Option<Box<dyn UnitOfWork>>, ApplicationError> {
+        let connection = self.acquire_primary().await?;
+        let transaction = Transaction::begin(connection, None)
+            .await
+            .map_err(database_error)?;
+
+        Ok(Some(Box::new(PostgresUnitOfWork {
+            transaction,
+            outbox: self.outbox,
+            clock: self.clock.clone(),
+        })))
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "count"))]
+    async fn count(&self) -> Result<i64, ApplicationError> {
+        let mut connection = self.acquire_reads().await?;
+        let cached_before = connection.cached_statements_size();
+
+        let count = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) AS "count!" FROM users WHERE deleted_at IS NULL
+            "#,
+        )
+            .fetch_one(&mut *connection)
+            .await
+            .map_err(database_error)?;
+
+        self.record_statement("count", cached_before, connection.cached_statements_size());
+
+        Ok(count)
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "exists"))]
+    async fn exists(&self, email_address: &str) -> Result<bool, ApplicationError> {
+        let mut connection = self.acquire_reads().await?;
+        let cached_before = connection.cached_statements_size();
+
+        let exists = sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS(
+                SELECT 1 FROM users WHERE email_address = $1 AND deleted_at IS NULL
+            ) AS "exists!"
+            "#,
+            email_address,
+        )
+            .fetch_one(&mut *connection)
+            .await
+            .map_err(database_error)?;
+
+        self.record_statement("exists", cached_before, connection.cached_statements_size());
+
+        Ok(exists)
+    }
+
+    // The replica too when there is one, reads fail without it
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "health_check"))]
+    async fn health_check(&self) -> Result<(), ApplicationError> {
+        for pool in std::iter::once(&self.db).chain(&self.reads) {
+            sqlx::query("SELECT 1")
+                .execute(pool)
+                .await
+                .map_err(database_error)?;
+        }
+
+        Ok(())
+    }
+
+    // The pool opens its minimum connections in the background, holding them all at once here
+    // makes sure they are open before the first request needs one
+    async fn warm_up(&self, connections: u32) -> Result<(), ApplicationError> {
+        let mut held = Vec::new();
+        for pool in std::iter::once(&self.db).chain(&self.reads) {
+            for _ in 0..connections {
+                held.push(
+                    pool.acquire()
+                        .await
+                        .map_err(database_error)?,
+                );
+            }
+        }
+
+        log::info!("Opened {} database connections", held.len());
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "with_email_address_versioned"))]
+    async fn with_email_address_versioned(
+        &self,
+        email_address: &str,
+    ) -> Result<Versioned<User>, ApplicationError> {
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+
+        let record = sqlx::query_as!(
+            UserRow,
+            r#"
+            SELECT id, email_address, name, password, date_of_birth, username, phone_number,
+                metadata AS "metadata: Json<Metadata>", role, tier, version
+            FROM users
+            WHERE email_address = $1 AND deleted_at IS NULL
+            "#,
+            email_address,
+        )
+            .fetch_optional(&mut *connection)
+            .await;
+
+        self.record_statement(
+            "with_email_address_versioned",
+            cached_before,
+            connection.cached_statements_size(),
+        );
+
+        match record {
+            Ok(Some(row)) => Ok(row.into()),
+            Ok(None) => Err(ApplicationError::UserDoesNotExist),
+            Err(e) => Err(database_error(e)),
+        }
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "update"))]
+    async fn update(
+        &self,
+        user: User,
+        expected_version: Option<i64>,
+    ) -> Result<i64, ApplicationError> {
+        log::info!("Attempting to update user in the database");
+
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+        let row = UserRow::from(&user);
+
+        let version = update_user(&mut connection, &row, expected_version)
+            .await
+            .map_err(database_error)?;
+
+        self.record_statement("update", cached_before, connection.cached_statements_size());
+
+        match version {
+            Some(version) => Ok(version),
+            None => Err(self.missing_or_changed(&row.email_address).await),
+        }
+    }
+
+    // Details that are the same as the stored ones are left alone, so the version only moves when
+    // something changed
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "upsert"))]
+    async fn upsert(&self, user: User) -> Result<i64, ApplicationError> {
+        log::info!("Attempting to upsert user in the database");
+
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+        let row = UserRow::from(&user);
+
+        let written = sqlx::query_scalar!(
+            r#"
+            INSERT INTO users (
+                id, email_address, name, password, date_of_birth, username, phone_number, metadata,
+                role, tier
+            )
+            SELECT $1, $2::VARCHAR, $3, $4, $5, $6, $7, $8, $9, $10
+            WHERE NOT EXISTS (
+                SELECT 1 FROM user_emails WHERE email_address = $2::VARCHAR AND verified_at IS NOT NULL
+            )
+            ON CONFLICT (email_address) DO UPDATE
+            SET name = EXCLUDED.name, password = EXCLUDED.password,
+                date_of_birth = EXCLUDED.date_of_birth, username = EXCLUDED.username,
+                phone_number = EXCLUDED.phone_number, metadata = EXCLUDED.metadata,
+                role = EXCLUDED.role, tier = EXCLUDED.tier, version = users.version + 1
+            WHERE users.deleted_at IS NULL
+                AND (
+                    users.name, users.password, users.date_of_birth, users.username,
+                    users.phone_number, users.metadata, users.role, users.tier
+                ) IS DISTINCT FROM (
+                    EXCLUDED.name, EXCLUDED.password, EXCLUDED.date_of_birth, EXCLUDED.username,
+                    EXCLUDED.phone_number, EXCLUDED.metadata, EXCLUDED.role, EXCLUDED.tier
+                )
+            RETURNING version
+            "#,
+            row.id,
+            row.email_address,
+            row.name,
+            row.password,
+            row.date_of_birth,
+            row.username,
+            row.phone_number,
+            &row.metadata as _,
+            row.role,
+            row.tier,
+        )
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(store_error)?;
+
+        self.record_statement("upsert", cached_before, connection.cached_statements_size());
+
+        if let Some(version) = written {
+            return Ok(version);
+        }
+        // Nothing written, the user was already up to date, soft deleted or the address is someone
+        // else's verified alias
+        sqlx::query_scalar!(
+            r#"
+            SELECT version FROM users WHERE email_address = $1 AND deleted_at IS NULL
+            "#,
+            row.email_address,
+        )
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(database_error)?
+            .ok_or(ApplicationError::UserAlreadyExists)
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "soft_delete"))]
+    async fn soft_delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        log::info!("Attempting to mark user as deleted in the database");
+
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+
+        let deleted_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        // Bumping the version means an ETag read before the delete can't match anything afterwards
+        let deleted = sqlx::query_scalar!(
+            r#"
+            UPDATE users
+            SET deleted_at = $2, version = version + 1
+            WHERE email_address = $1 AND deleted_at IS NULL AND ($3::BIGINT IS NULL OR version = $3)
+            RETURNING version
+            "#,
+            email_address,
+            deleted_at as i64,
+            expected_version,
+        )
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(database_error)?;
+
+        self.record_statement("soft_delete", cached_before, connection.cached_statements_size());
+
+        match deleted {
+            Some(_) => Ok(()),
+            None => Err(self.missing_or_changed(email_address).await),
+        }
+    }
+
+    // Removes the row whether or not it was soft deleted first
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "delete"))]
+    async fn delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        log::info!("Attempting to delete user from the database");
+
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+
+        let deleted = sqlx::query_scalar!(
+            r#"
+            DELETE FROM users
+            WHERE email_address = $1 AND ($2::BIGINT IS NULL OR version = $2)
+            RETURNING email_address
+            "#,
+            email_address,
+            expected_version,
+        )
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(database_error)?;
+
+        self.record_statement("delete", cached_before, connection.cached_statements_size());
+
+        match deleted {
+            Some(_) => Ok(()),
+            None => Err(self.missing_or_changed(email_address).await),
+        }
+    }
+
+    // The placeholders, the record and the event commit together, an erasure is never recorded or
+    // announced without having happened
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "erase"))]
+    async fn erase(
+        &self,
+        email_address: &str,
+        requested_by: Option<&str>,
+    ) -> Result<Uuid, ApplicationError> {
+        log::info!("Attempting to erase user in the database");
+
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+        let erased_at = crate::outbox::now();
+
+        let result: Result<Uuid, ApplicationError> = async {
+            let mut transaction = connection.begin().await.map_err(database_error)?;
+
+            let Some(id) = sqlx::query_scalar!(
+                r#"
+                SELECT id FROM users WHERE email_address = $1 FOR UPDATE
+                "#,
+                email_address,
+            )
+                .fetch_optional(&mut *transaction)
+                .await
+                .map_err(database_error)?
+            else {
+                return Err(ApplicationError::UserDoesNotExist);
+            };
+
+            sqlx::query!(
+                r#"
+                UPDATE users
+                SET email_address = $2, name = $3, password = '', date_of_birth = NULL,
+                    username = NULL, phone_number = NULL, metadata = '{}', preferences = '{}',
+                    tier = NULL, last_login_at = NULL, last_login_ip = NULL,
+                    deleted_at = COALESCE(deleted_at, $4), version = version + 1
+                WHERE id = $1
+                "#,
+                id,
+                erased_email_address(id),
+                ERASED_NAME,
+                erased_at as i64,
+            )
+                .execute(&mut *transaction)
+                .await
+                .map_err(database_error)?;
+
+            sqlx::query!(
+                r#"
+                DELETE FROM user_emails WHERE user_id = $1
+                "#,
+                id,
+            )
+                .execute(&mut *transaction)
+                .await
+                .map_err(database_error)?;
+
+            sqlx::query!(
+                r#"
+                INSERT INTO user_erasures ( user_id, erased_at, requested_by )
+                VALUES ( $1, $2, $3 )
+                "#,
+                id,
+                erased_at as i64,
+                requested_by,
+            )
+                .execute(&mut *transaction)
+                .await
+                .map_err(database_error)?;
+
+            if self.outbox {
+                let entry = UserErasedEvent {
+                    event_id: Uuid::new_v4().to_string(),
+                    partition_key: crate::partitioning::user_key(id),
+                    user_id: id.to_string(),
+                    email_address: email_address.to_string(),
+                    erased_at,
+                }
+                .into_outbox_entry(crate::outbox::current_trace_context(), erased_at)?;
+                outbox::enqueue(&mut transaction, &entry)
+                    .await
+                    .map_err(database_error)?;
+            }
+
+            transaction.commit().await.map_err(database_error)?;
+
+            Ok(id)
+        }
+        .await;
+
+        self.record_statement("erase", cached_before, connection.cached_statements_size());
+
+        result
+    }
+
+    // Both rows are locked in id order, so two merges of the same pair the other way round can't
+    // deadlock. The fold, the record and the event commit together.
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "merge"))]
+    async fn merge(
+        &self,
+        kept: &str,
+        merged: &str,
+        requested_by: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        log::info!("Attempting to merge users in the database");
+
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+        let merged_at = self.clock.now();
+
+        let result: Result<(), ApplicationError> = async {
+            let mut transaction = connection.begin().await.map_err(database_error)?;
+
+            let rows = sqlx::query_as!(
+                UserRow,
+                r#"
+                SELECT id, email_address, name, password, date_of_birth, username, phone_number,
+                    metadata AS "metadata: Json<Metadata>", role, tier, version
+                FROM users
+                WHERE email_address = ANY($1) AND deleted_at IS NULL
+                ORDER BY id
+                FOR UPDATE
+                "#,
+                &[kept.to_string(), merged.to_string()] as &[String],
+            )
+                .fetch_all(&mut *transaction)
+                .await
+                .map_err(database_error)?;
+            let find = |email_address: &str| {
+                rows.iter()
+                    .find(|row| row.email_address == email_address)
+                    .ok_or(ApplicationError::UserDoesNotExist)
+            };
+            let (kept_row, merged_row) = (find(kept)?, find(merged)?);
+            let row = kept_row.clone().merged_with(merged_row)?;
+
+            let preferences = sqlx::query!(
+                r#"
+                SELECT id, preferences AS "preferences: Json<UserPreferences>"
+                FROM users
+                WHERE id = $1 OR id = $2
+                "#,
+                kept_row.id,
+                merged_row.id,
+            )
+                .fetch_all(&mut *transaction)
+                .await
+                .map_err(database_error)?;
+            let preferences_of = |id: Uuid| {
+                preferences
+                    .iter()
+                    .find(|record| record.id == id)
+                    .map(|record| record.preferences.0.clone())
+                    .unwrap_or_default()
+            };
+            let preferences =
+                preferences_of(kept_row.id).merged_with(preferences_of(merged_row.id));
+
+            // The later of the two logins is the user's last one
+            sqlx::query!(
+                r#"
+                UPDATE users AS kept
+                SET date_of_birth = $3, phone_number = $4, metadata = $5, tier = $6,
+                    preferences = $7,
+                    last_login_at = GREATEST(kept.last_login_at, merged.last_login_at),
+                    last_login_ip = CASE
+                        WHEN merged.last_login_at > COALESCE(kept.last_login_at, 0)
+                        THEN merged.last_login_ip ELSE kept.last_login_ip END,
+                    version = kept.version + 1
+                FROM users AS merged
+                WHERE kept.id = $1 AND merged.id = $2
+                "#,
+                row.id,
+                merged_row.id,
+                row.date_of_birth,
+                row.phone_number,
+                row.metadata as _,
+                row.tier,
+                Json(&preferences) as _,
+            )
+                .execute(&mut *transaction)
+                .await
+                .map_err(database_error)?;
+
+            // Unverified claims are dropped, so are the kept user's claims on addresses that are
+            // about to be theirs
+            sqlx::query!(
+                r#"
+                DELETE FROM user_emails
+                WHERE (user_id = $2 AND verified_at IS NULL)
+                    OR (user_id = $1 AND (email_address = $3 OR email_address IN (
+                        SELECT email_address FROM user_emails WHERE user_id = $2
+                    )))
+                "#,
+                row.id,
+                merged_row.id,
+                merged,
+            )
+                .execute(&mut *transaction)
+                .await
+                .map_err(database_error)?;
+
+            sqlx::query!(
+                r#"
+                UPDATE user_emails SET user_id = $1 WHERE user_id = $2
+                "#,
+                row.id,
+                merged_row.id,
+            )
+                .execute(&mut *transaction)
+                .await
+                .map_err(database_error)?;
+
+            sqlx::query!(
+                r#"
+                INSERT INTO user_emails ( email_address, user_id, created_at, verified_at )
+                VALUES ( $1, $2, $3, $3 )
+                "#,
+                merged,
+                row.id,
+                merged_at as i64,
+            )
+                .execute(&mut *transaction)
+                .await
+                .map_err(database_error)?;
+
+            sqlx::query!(
+                r#"
+                UPDATE users SET deleted_at = $2, version = version + 1 WHERE id = $1
+                "#,
+                merged_row.id,
+                merged_at as i64,
+            )
+                .execute(&mut *transaction)
+                .await
+                .map_err(database_error)?;
+
+            sqlx::query!(
+                r#"
+                INSERT INTO user_merges ( merged_user_id, kept_user_id, merged_at, requested_by )
+                VALUES ( $1, $2, $3, $4 )
+                "#,
+                merged_row.id,
+                row.id,
+                merged_at as i64,
+                requested_by,
+            )
+                .execute(&mut *transaction)
+                .await
+                .map_err(database_error)?;
+
+            if self.outbox {
+                let entry = UserMergedEvent {
+                    event_id: Uuid::new_v4().to_string(),
+                    partition_key: crate::partitioning::user_key(row.id),
+                    kept_user_id: row.id.to_string(),
+                    email_address: kept.to_string(),
+                    merged_user_id: merged_row.id.to_string(),
+                    merged_email_address: merged.to_string(),
+                    merged_at,
+                }
+                .into_outbox_entry(crate::outbox::current_trace_context(), merged_at)?;
+                outbox::enqueue(&mut transaction, &entry)
+                    .await
+                    .map_err(database_error)?;
+            }
+
+            transaction.commit().await.map_err(database_error)
+        }
+        .await;
+
+        self.record_statement("merge", cached_before, connection.cached_statements_size());
+
+        result
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "preferences"))]
+    async fn preferences(&self, email_address: &str) -> Result<UserPreferences, ApplicationError> {
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+
+        let preferences = sqlx::query_scalar!(
+            r#"
+            SELECT preferences AS "preferences: Json<UserPreferences>"
+            FROM users
+            WHERE email_address = $1 AND deleted_at IS NULL
+            "#,
+            email_address,
+        )
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(database_error)?;
+
+        self.record_statement("preferences", cached_before, connection.cached_statements_size());
+
+        preferences
+            .map(|Json(preferences)| preferences)
+            .ok_or(ApplicationError::UserDoesNotExist)
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "update_preferences"))]
+    async fn update_preferences(
+        &self,
+        email_address: &str,
+        preferences: &UserPreferences,
+    ) -> Result<(), ApplicationError> {
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+
+        let updated = sqlx::query!(
+            r#"
+            UPDATE users
+            SET preferences = $2
+            WHERE email_address = $1 AND deleted_at IS NULL
+            "#,
+            email_address,
+            Json(preferences) as _,
+        )
+            .execute(&mut *connection)
+            .await
+            .map_err(database_error)?;
+
+        self.record_statement(
+            "update_preferences",
+            cached_before,
+            connection.cached_statements_size(),
+        );
+
+        match updated.rows_affected() {
+            0 => Err(ApplicationError::UserDoesNotExist),
+            _ => Ok(()),
+        }
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "record_login"))]
+    async fn record_login(
+        &self,
+        email_address: &str,
+        logged_in_at: u64,
+        ip: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+
+        // A login that finishes after a later one doesn't take its place
+        let updated = sqlx::query!(
+            r#"
+            UPDATE users
+            SET last_login_at = GREATEST(COALESCE(last_login_at, 0), $2),
+                last_login_ip = CASE WHEN COALESCE(last_login_at, 0) <= $2
+                    THEN $3 ELSE last_login_ip END
+            WHERE email_address = $1 AND deleted_at IS NULL
+            "#,
+            email_address,
+            logged_in_at as i64,
+            ip,
+        )
+            .execute(&mut *connection)
+            .await
+            .map_err(database_error)?;
+
+        self.record_statement("record_login", cached_before, connection.cached_statements_size());
+
+        match updated.rows_affected() {
+            0 => Err(ApplicationError::UserDoesNotExist),
+            _ => Ok(()),
+        }
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "last_login"))]
+    async fn last_login(&self, email_address: &str) -> Result<Option<LastLogin>, ApplicationError> {
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+
+        let row = sqlx::query!(
+            r#"
+            SELECT last_login_at, last_login_ip
+            FROM users
+            WHERE email_address = $1 AND deleted_at IS NULL
+            "#,
+            email_address,
+        )
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(database_error)?;
+
+        self.record_statement("last_login", cached_before, connection.cached_statements_size());
+
+        let row = row.ok_or(ApplicationError::UserDoesNotExist)?;
+        Ok(row.last_login_at.map(|last_login_at| LastLogin {
+            last_login_at: last_login_at as u64,
+            last_login_ip: row.last_login_ip,
+        }))
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "email_aliases"))]
+    async fn email_aliases(&self, email_address: &str) -> Result<Vec<EmailAlias>, ApplicationError> {
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+        let id = live_id(&mut connection, email_address).await?;
+
+        let records = sqlx::query!(
+            r#"
+            SELECT email_address, created_at, verified_at
+            FROM user_emails
+            WHERE user_id = $1
+            ORDER BY email_address
+            "#,
+            id,
+        )
+            .fetch_all(&mut *connection)
+            .await
+            .map_err(database_error)?;
+
+        self.record_statement("email_aliases", cached_before, connection.cached_statements_size());
+
+        Ok(records
+            .into_iter()
+            .map(|record| EmailAlias {
+                email_address: record.email_address,
+                created_at: record.created_at as u64,
+                verified_at: record.verified_at.map(|verified_at| verified_at as u64),
+            })
+            .collect())
+    }
+
+    // Nobody's primary address and nobody's verified alias, which covers one of the user's own
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "add_email_alias"))]
+    async fn add_email_alias(
+        &self,
+        email_address: &str,
+        alias: &str,
+        token_hash: &str,
+    ) -> Result<(), ApplicationError> {
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+        let id = live_id(&mut connection, email_address).await?;
+
+        let added = sqlx::query!(
+            r#"
+            INSERT INTO user_emails ( email_address, user_id, verification_token_hash, created_at )
+            SELECT $1::VARCHAR, $2, $3, $4
+            WHERE NOT EXISTS ( SELECT 1 FROM users WHERE email_address = $1::VARCHAR )
+                AND NOT EXISTS (
+                    SELECT 1 FROM user_emails WHERE email_address = $1::VARCHAR AND verified_at IS NOT NULL
+                )
+            ON CONFLICT ( email_address, user_id ) DO UPDATE
+            SET verification_token_hash = EXCLUDED.verification_token_hash,
+                created_at = EXCLUDED.created_at
+            "#,
+            alias,
+            id,
+            token_hash,
+            crate::outbox::now() as i64,
+        )
+            .execute(&mut *connection)
+            .await
+            .map_err(database_error)?;
+
+        self.record_statement("add_email_alias", cached_before, connection.cached_statements_size());
+
+        match added.rows_affected() {
+            0 => Err(ApplicationError::UserAlreadyExists),
+            _ => Ok(()),
+        }
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "verify_email_alias"))]
+    async fn verify_email_alias(
+        &self,
+        token_hash: &str,
+        added_after: u64,
+    ) -> Result<Option<String>, ApplicationError> {
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+
+        let pending = sqlx::query_scalar!(
+            r#"
+            SELECT email_address
+            FROM user_emails
+            WHERE verification_token_hash = $1 AND verified_at IS NULL AND created_at >= $2
+            "#,
+            token_hash,
+            added_after as i64,
+        )
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(database_error)?;
+        if pending.is_none() {
+            self.record_statement("verify_email_alias", cached_before, connection.cached_statements_size());
+            return Ok(None);
+        }
+
+        // Someone else may have registered or verified the address since it was added, the
+        // partial unique index turns away a second verification
+        let verified = sqlx::query_scalar!(
+            r#"
+            UPDATE user_emails
+            SET verified_at = $2, verification_token_hash = NULL
+            WHERE verification_token_hash = $1 AND verified_at IS NULL
+                AND NOT EXISTS ( SELECT 1 FROM users WHERE users.email_address = user_emails.email_address )
+            RETURNING email_address
+            "#,
+            token_hash,
+            crate::outbox::now() as i64,
+        )
+            .fetch_optional(&mut *connection)
+            .await;
+
+        self.record_statement("verify_email_alias", cached_before, connection.cached_statements_size());
+
+        match verified {
+            Ok(Some(alias)) => Ok(Some(alias)),
+            Ok(None) => Err(ApplicationError::UserAlreadyExists),
+            Err(e) if e
+                .as_database_error()
+                .is_some_and(|database_error| database_error.is_unique_violation()) =>
+            {
+                Err(ApplicationError::UserAlreadyExists)
+            }
+            Err(e) => Err(database_error(e)),
+        }
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "remove_email_alias"))]
+    async fn remove_email_alias(
+        &self,
+        email_address: &str,
+        alias: &str,
+    ) -> Result<(), ApplicationError> {
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+        let id = live_id(&mut connection, email_address).await?;
+
+        let removed = sqlx::query!(
+            r#"
+            DELETE FROM user_emails WHERE user_id = $1 AND email_address = $2
+            "#,
+            id,
+            alias,
+        )
+            .execute(&mut *connection)
+            .await
+            .map_err(database_error)?;
+
+        self.record_statement("remove_email_alias", cached_before, connection.cached_statements_size());
+
+        match removed.rows_affected() {
+            0 => Err(ApplicationError::InvalidRequest(format!(
+                "{} is not one of the user's email addresses",
+                alias
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    // The swap commits as a whole, the user is never without an address or found by both
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "set_primary_email_address"))]
+    async fn set_primary_email_address(
+        &self,
+        email_address: &str,
+        alias: &str,
+    ) -> Result<(), ApplicationError> {
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+        let now = crate::outbox::now();
+
+        let result: Result<(), ApplicationError> = async {
+            let mut transaction = connection.begin().await.map_err(database_error)?;
+
+            let Some(id) = sqlx::query_scalar!(
+                r#"
+                SELECT id FROM users WHERE email_address = $1 AND deleted_at IS NULL FOR UPDATE
+                "#,
+                email_address,
+            )
+                .fetch_optional(&mut *transaction)
+                .await
+                .map_err(database_error)?
+            else {
+                return Err(ApplicationError::UserDoesNotExist);
+            };
+
+            let swapped = sqlx::query!(
+                r#"
+                DELETE FROM user_emails
+                WHERE user_id = $1 AND email_address = $2 AND verified_at IS NOT NULL
+                "#,
+                id,
+                alias,
+            )
+                .execute(&mut *transaction)
+                .await
+                .map_err(database_error)?;
+            if swapped.rows_affected() == 0 {
+                return Err(ApplicationError::InvalidRequest(format!(
+                    "{} is not a verified email address of the user",
+                    alias
+                )));
+            }
+
+            sqlx::query!(
+                r#"
+                UPDATE users SET email_address = $2, version = version + 1 WHERE id = $1
+                "#,
+                id,
+                alias,
+            )
+                .execute(&mut *transaction)
+                .await
+                .map_err(database_error)?;
+
+            sqlx::query!(
+                r#"
+                INSERT INTO user_emails ( email_address, user_id, created_at, verified_at )
+                VALUES ( $1, $2, $3, $3 )
+                "#,
+                email_address,
+                id,
+                now as i64,
+            )
+                .execute(&mut *transaction)
+                .await
+                .map_err(database_error)?;
+
+            transaction.commit().await.map_err(database_error)
+        }
+        .await;
+
+        self.record_statement(
+            "set_primary_email_address",
+            cached_before,
+            connection.cached_statements_size(),
+        );
+
+        result
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "resolve_email_alias"))]
+    async fn resolve_email_alias(&self, alias: &str) -> Result<Option<String>, ApplicationError> {
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+
+        let email_address = sqlx::query_scalar!(
+            r#"
+            SELECT users.email_address
+            FROM user_emails JOIN users ON users.id = user_emails.user_id
+            WHERE user_emails.email_address = $1 AND user_emails.verified_at IS NOT NULL
+                AND users.deleted_at IS NULL
+            "#,
+            alias,
+        )
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(database_error)?;
+
+        self.record_statement("resolve_email_alias", cached_before, connection.cached_statements_size());
+
+        Ok(email_address)
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "set_username"))]
+    async fn set_username(
+        &self,
+        email_address: &str,
+        username: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+
+        // The unique constraint turns away a username someone else has, however close together
+        // the two writes are
+        let updated = sqlx::query!(
+            r#"
+            UPDATE users SET username = $2, version = version + 1
+            WHERE email_address = $1 AND deleted_at IS NULL
+            "#,
+            email_address,
+            username,
+        )
+            .execute(&mut *connection)
+            .await;
+
+        self.record_statement("set_username", cached_before, connection.cached_statements_size());
+
+        match updated {
+            Ok(updated) if updated.rows_affected() == 0 => Err(ApplicationError::UserDoesNotExist),
+            Ok(_) => Ok(()),
+            Err(e) if e
+                .as_database_error()
+                .is_some_and(|database_error| database_error.is_unique_violation()) =>
+            {
+                Err(ApplicationError::UsernameTaken(username.unwrap_or_default().to_string()))
+            }
+            Err(e) => Err(database_error(e)),
+        }
+    }
+
+    #[tracing::instrument(name = "db.query", skip_all, fields(db.operation = "resolve_username"))]
+    async fn resolve_username(&self, username: &str) -> Result<Option<String>, ApplicationError> {
+        let mut connection = self.acquire_primary().await?;
+        let cached_before = connection.cached_statements_size();
+
+        let email_address = sqlx::query_scalar!(
+            r#"
+            SELECT email_address FROM users WHERE username = $1 AND deleted_at IS NULL
+            "#,
+            username,
+        )
+            .fetch_optional(&mut *connection)
+            .await
+            .map_err(database_error)?;
+
+        self.record_statement("resolve_username", cached_before, connection.cached_statements_size());
+
+        Ok(email_address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Nothing listens on port 1, so connecting fails as soon as a connection is needed
+    const UNREACHABLE: &str = "postgres://postgres@127.0.0.1:1/users";
+    const LABELS: [(&str, &str); 2] = [("database", "127.0.0.1:1/users"), ("pool", "primary")];
+
+    fn unreachable_users() -> PostgresUsers {
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(200))
+            .connect_lazy(UNREACHABLE)
+            .unwrap();
+
+        PostgresUsers {
+            db: pool,
+            reads: None,
+            statement_cache_capacity: 0,
+            metrics: Arc::new(Metrics::default()),
+            outbox: false,
+            clock: Arc::new(Clock::default()),
+            primary_wait_us: Arc::default(),
+            replica_wait_us: Arc::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn pool_statistics_should_record_each_pools_connections() {
+        let users = unreachable_users();
+
+        users.pool_statistics().record();
+
+        let metrics = users.metrics.snapshot();
+        assert_eq!(metrics.gauge(DB_POOL_CONNECTIONS, &LABELS), Some(0.0));
+        assert_eq!(metrics.gauge(DB_POOL_IDLE_CONNECTIONS, &LABELS), Some(0.0));
+        assert_eq!(metrics.gauge(DB_POOL_ACQUIRE_WAIT_SECONDS, &LABELS), Some(0.0));
+    }
+
+    #[tokio::test]
+    async fn a_failed_acquire_should_be_counted_and_its_wait_recorded() {
+        let users = unreachable_users();
+
+        assert!(users.acquire_primary().await.is_err());
+        users.pool_statistics().record();
+
+        let metrics = users.metrics.snapshot();
+        assert_eq!(metrics.counter_with_labels(DB_POOL_ACQUIRE_ERRORS_TOTAL, &LABELS), 1);
+        assert!(metrics.gauge(DB_POOL_ACQUIRE_WAIT_SECONDS, &LABELS).unwrap() > 0.0);
+    }
+
+    // Read as a missing user, an outage would answer every lookup with a 404
+    #[tokio::test]
+    async fn a_lookup_the_database_cant_answer_should_not_be_a_missing_user() {
+        let users = unreachable_users();
+
+        let error = users.with_email_address("james@test.com").await.err();
+
+        assert!(
+            matches!(
+                error,
+                Some(ApplicationError::DatabaseError(_) | ApplicationError::DatabaseTimeout(_))
+            ),
+            "{:?}",
+            error
+        );
+    }
+}
@@ -0,0 +1,242 @@
+use sqlx::types::Json;
+use time::Date;
+use uuid::Uuid;
+
+use crate::core::{ApplicationError, Metadata, Role, Tier, User, Versioned};
+
+// The shape of a row in the `users` table. Macro queries map into it with `query_as!`, which checks
+// it against the schema at compile time, and runtime queries with `query_as::<_, UserRow>` through
+// `FromRow`, so a new column is added here once rather than to every anonymous record.
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow)]
+pub(crate) struct UserRow {
+    pub id: Uuid,
+    pub email_address: String,
+    pub name: String,
+    pub password: String,
+    pub date_of_birth: Option<Date>,
+    pub username: Option<String>,
+    pub phone_number: Option<String>,
+    pub metadata: Json<Metadata>,
+    pub role: String,
+    pub tier: Option<String>,
+    pub version: i64,
+}
+
+// A user as listed along with the soft deleted ones, `deleted_at` is `None` for live users
+#[derive(Debug, sqlx::FromRow)]
+pub(crate) struct ListedUserRow {
+    #[sqlx(flatten)]
+    pub user: UserRow,
+    pub deleted_at: Option<i64>,
+}
+
+// A user found by `search_ranked`, with how well they matched
+#[derive(Debug, sqlx::FromRow)]
+pub(crate) struct SearchedUserRow {
+    #[sqlx(flatten)]
+    pub user: UserRow,
+    pub rank: f32,
+}
+
+// What an erased user's email address and name are replaced with. The address comes from the id so
+// it stays unique, and nothing can be delivered to `.invalid`.
+pub(crate) const ERASED_NAME: &str = "Erased user";
+
+pub(crate) fn erased_email_address(id: Uuid) -> String {
+    format!("erased-{}@erased.invalid", id)
+}
+
+impl UserRow {
+    // Everything that identifies the user replaced, only the id and the role are kept
+    pub(crate) fn erased(self) -> Self {
+        Self {
+            email_address: erased_email_address(self.id),
+            name: ERASED_NAME.to_string(),
+            password: String::new(),
+            date_of_birth: None,
+            username: None,
+            phone_number: None,
+            metadata: Json(Metadata::new()),
+            tier: None,
+            ..self
+        }
+    }
+
+    // The kept user's row when `merged` is folded into it. Details the kept user has win, the
+    // merged user's fill in what they don't have, metadata key by key. Either may have paid for
+    // their tier, so the higher one is kept. The username stays with the merged user's row, it
+    // can't be held by two.
+    pub(crate) fn merged_with(self, merged: &UserRow) -> Result<Self, ApplicationError> {
+        let mut metadata = merged.metadata.0.clone();
+        metadata.extend(self.metadata.0.clone());
+        User::metadata_is_valid(&metadata)?;
+        let tier = [&self.tier, &merged.tier]
+            .into_iter()
+            .flatten()
+            .max_by_key(|tier| tier.parse::<Tier>().ok())
+            .cloned();
+
+        Ok(Self {
+            date_of_birth: self.date_of_birth.or(merged.date_of_birth),
+            phone_number: self.phone_number.clone().or_else(|| merged.phone_number.clone()),
+            metadata: Json(metadata),
+            tier,
+            ..self
+        })
+    }
+}
+
+impl From<UserRow> for User {
+    fn from(row: UserRow) -> Self {
+        // A role this build doesn't know grants nothing rather than failing every read of the user
+        let role = row.role.parse().unwrap_or_else(|_| {
+            log::warn!("Unknown role {}, treating the user as a member", row.role);
+            Role::Member
+        });
+        let tier = row.tier.as_deref().and_then(|tier| match tier.parse() {
+            Ok(tier) => Some(tier),
+            Err(_) => {
+                log::warn!("Unknown tier {}, treating the user as standard", tier);
+                None
+            }
+        });
+        User::builder(&row.email_address, &row.name)
+            .hashed_password(&row.password)
+            .id(row.id)
+            .date_of_birth(row.date_of_birth)
+            .username(row.username)
+            .phone_number(row.phone_number)
+            .metadata(row.metadata.0)
+            .role(role)
+            .tier(tier)
+            .build()
+            .expect("a hashed password is always set")
+    }
+}
+
+impl From<UserRow> for Versioned<User> {
+    fn from(row: UserRow) -> Self {
+        Versioned {
+            version: row.version,
+            value: row.into(),
+        }
+    }
+}
+
+impl From<&User> for UserRow {
+    fn from(user: &User) -> Self {
+        Self {
+            id: user.id(),
+            email_address: user.email_address(),
+            name: user.name(),
+            password: user.password(),
+            date_of_birth: user.date_of_birth(),
+            username: user.username(),
+            phone_number: user.phone_number(),
+            metadata: Json(user.metadata()),
+            role: user.role().as_str().to_string(),
+            tier: user.tier().map(|tier| tier.as_str().to_string()),
+            // The version a newly stored user starts at
+            version: 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_user_should_round_trip_through_its_row() {
+        let row = UserRow {
+            id: Uuid::new_v4(),
+            email_address: "james@test.com".to_string(),
+            name: "James".to_string(),
+            password: "hashed-password".to_string(),
+            date_of_birth: Some(Date::from_calendar_date(1990, time::Month::June, 15).unwrap()),
+            username: Some("james".to_string()),
+            phone_number: Some("+442079460958".to_string()),
+            metadata: Json(Metadata::from_iter([("team".to_string(), "otters".into())])),
+            role: "admin".to_string(),
+            tier: Some("gold".to_string()),
+            version: 1,
+        };
+
+        let user: User = row.clone().into();
+
+        assert_eq!(user.email_address(), "james@test.com");
+        assert_eq!(user.role(), Role::Admin);
+        assert_eq!(user.tier(), Some(Tier::Gold));
+        assert_eq!(UserRow::from(&user), row);
+    }
+
+    #[test]
+    fn an_erased_row_should_only_keep_the_id_and_role() {
+        let id = Uuid::new_v4();
+        let row = UserRow {
+            id,
+            email_address: "james@test.com".to_string(),
+            name: "James".to_string(),
+            password: "hashed-password".to_string(),
+            date_of_birth: Some(Date::from_calendar_date(1990, time::Month::June, 15).unwrap()),
+            username: Some("james".to_string()),
+            phone_number: Some("+442079460958".to_string()),
+            metadata: Json(Metadata::from_iter([("team".to_string(), "otters".into())])),
+            role: "admin".to_string(),
+            tier: Some("gold".to_string()),
+            version: 3,
+        };
+
+        let erased = row.erased();
+
+        assert_eq!(erased.email_address, format!("erased-{}@erased.invalid", id));
+        assert_eq!(erased.name, ERASED_NAME);
+        assert!(erased.password.is_empty());
+        assert_eq!(
+            (erased.date_of_birth, erased.username, erased.phone_number),
+            (None, None, None)
+        );
+        assert!(erased.metadata.is_empty() && erased.tier.is_none());
+        assert_eq!((erased.id, erased.role.as_str(), erased.version), (id, "admin", 3));
+    }
+
+    #[test]
+    fn a_merged_row_should_keep_its_own_details_and_fill_in_the_rest() {
+        let kept = UserRow {
+            id: Uuid::new_v4(),
+            email_address: "james@test.com".to_string(),
+            name: "James".to_string(),
+            password: "hashed-password".to_string(),
+            date_of_birth: None,
+            username: Some("james".to_string()),
+            phone_number: Some("+442079460958".to_string()),
+            metadata: Json(Metadata::from_iter([("team".to_string(), "otters".into())])),
+            role: "member".to_string(),
+            tier: Some("silver".to_string()),
+            version: 3,
+        };
+        let merged = UserRow {
+            id: Uuid::new_v4(),
+            email_address: "jim@test.com".to_string(),
+            date_of_birth: Some(Date::from_calendar_date(1990, time::Month::June, 15).unwrap()),
+            username: Some("jim".to_string()),
+            phone_number: Some("+442079460000".to_string()),
+            metadata: Json(Metadata::from_iter([
+                ("team".to_string(), "badgers".into()),
+                ("desk".to_string(), "4b".into()),
+            ])),
+            tier: Some("gold".to_string()),
+            ..kept.clone()
+        };
+
+        let row = kept.clone().merged_with(&merged).unwrap();
+
+        assert_eq!((row.id, row.email_address.as_str()), (kept.id, "james@test.com"));
+        assert_eq!(row.date_of_birth, merged.date_of_birth);
+        assert_eq!(row.phone_number, kept.phone_number);
+        assert_eq!(row.username, kept.username);
+        assert_eq!(row.metadata["team"], "otters");
+        assert_eq!(row.metadata["desk"], "4b");
+        assert_eq!(row.tier.as_deref(), Some("gold"));
+    }
+}
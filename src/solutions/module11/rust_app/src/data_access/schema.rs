@@ -0,0 +1,52 @@
+use sqlx::migrate::Migrator;
+
+use crate::core::{ApplicationError, Config};
+use super::postgres::{connect, PoolSettings};
+
+// The files in `migrations/` are built into the binary, `build.rs` rebuilds it when one is added
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+// Brings every database the API and worker use up to date before either of them reads from it, the
+// main one or each shard, and the database a migration moves users to. sqlx holds an advisory lock
+// on each database while it applies migrations, so instances starting together wait for the first
+// of them rather than applying anything twice.
+pub async fn run_migrations(config: &Config) -> Result<(), ApplicationError> {
+    if !config.run_migrations() {
+        log::info!("database.run_migrations is off, the schema is expected to be up to date");
+        return Ok(());
+    }
+
+    let mut connection_strings = config.shard_connection_strings();
+    if connection_strings.is_empty() {
+        connection_strings.push(config.connection_string());
+    }
+    connection_strings.extend(config.migration_connection_string());
+
+    for connection_string in connection_strings {
+        let db = connect(&connection_string, &PoolSettings::default()).await?;
+        let applied = MIGRATOR.run(&db).await;
+        db.close().await;
+
+        // A database whose tables were created by running the files with psql has no record of
+        // them, so the first migration fails on a table that already exists
+        applied.map_err(|e| {
+            ApplicationError::DatabaseError(format!(
+                "Migrating the database failed: {}. If its tables were created by hand set \
+                 database.run_migrations to false",
+                e
+            ))
+        })?;
+    }
+    log::info!("The database is at migration {}", latest_migration());
+
+    Ok(())
+}
+
+fn latest_migration() -> i64 {
+    MIGRATOR
+        .iter()
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or_default()
+}
+
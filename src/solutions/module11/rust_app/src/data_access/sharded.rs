@@ -0,0 +1,752 @@
+use uuid::Uuid;
+
+use crate::core::{
+    ApplicationError, DataAccess, EmailAlias, LastLogin, User, UserPreferences, Versioned,
+};
+use crate::partitioning::{fnv1a, partition_for};
+
+const REBALANCE_PAGE_SIZE: i64 = 100;
+
+// Routes every operation to one of N underlying data stores based on a consistent hash of the
+// email address. Each shard owns its own connection pool, so the same trait can be scaled
+// horizontally, and adding a shard only moves the users it takes over.
+pub struct ShardedDataAccess<TDataAccess: DataAccess> {
+    shards: Vec<TDataAccess>,
+    // Whether users are also looked for where `legacy_shard_index` put them, see `shard_holding`
+    legacy_placement: bool,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RebalanceReport {
+    pub scanned: usize,
+    pub misplaced: usize,
+    pub copied: usize,
+}
+
+impl<TDataAccess: DataAccess> ShardedDataAccess<TDataAccess> {
+    pub fn new(shards: Vec<TDataAccess>) -> Result<Self, ApplicationError> {
+        if shards.is_empty() {
+            return Err(ApplicationError::ApplicationError(
+                "At least one shard must be configured".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            shards,
+            legacy_placement: true,
+        })
+    }
+
+    pub fn with_legacy_placement(mut self, legacy_placement: bool) -> Self {
+        self.legacy_placement = legacy_placement;
+        self
+    }
+
+    pub fn shards(&self) -> &[TDataAccess] {
+        &self.shards
+    }
+
+    // The same jump hash as Kafka partitions, a modulo would move nearly every user when a shard
+    // is added. Users placed by the modulo this replaced are moved by `rebalance`.
+    pub fn shard_index(&self, email_address: &str) -> usize {
+        partition_for(email_address, self.shards.len() as u32) as usize
+    }
+
+    // Where the modulo placed the user, when that isn't where they belong and they may not have
+    // been moved yet
+    fn legacy_shard_index(&self, email_address: &str) -> Option<usize> {
+        let legacy = (fnv1a(email_address.as_bytes()) % self.shards.len() as u64) as usize;
+        (self.legacy_placement && legacy != self.shard_index(email_address)).then_some(legacy)
+    }
+
+    // The shard the user belongs on, unless only the one the modulo placed them on has them.
+    // Until `rebalance` has copied everyone, which is part of upgrading from the modulo, this
+    // costs an extra lookup for users whose shard changed. Soft deleted users read as missing, so
+    // one left on their old shard can't be read or deleted again until they are copied.
+    async fn holding_index(&self, email_address: &str) -> Result<usize, ApplicationError> {
+        let index = self.shard_index(email_address);
+        let Some(legacy) = self.legacy_shard_index(email_address) else {
+            return Ok(index);
+        };
+
+        if !self.shards[index].exists(email_address).await?
+            && self.shards[legacy].exists(email_address).await?
+        {
+            return Ok(legacy);
+        }
+
+        Ok(index)
+    }
+
+    async fn shard_holding(&self, email_address: &str) -> Result<&TDataAccess, ApplicationError> {
+        Ok(&self.shards[self.holding_index(email_address).await?])
+    }
+
+    // Whether a user found on shard `index` is read from it, either because they belong there or
+    // because the modulo put them there. A copy on both is dropped by `merged`.
+    fn reads_from(&self, index: usize, email_address: &str) -> bool {
+        self.shard_index(email_address) == index
+            || self.legacy_shard_index(email_address) == Some(index)
+    }
+
+    // Up to `limit` of the users shard `index` owns, in email address order after `after`. Copies
+    // `rebalance` left on the shard are skipped, so pages are read until `limit` owned users have
+    // been found or the shard runs out.
+    async fn owned_users(
+        &self,
+        index: usize,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<User>, ApplicationError> {
+        let mut owned = Vec::new();
+        let mut cursor = after.map(str::to_string);
+
+        while (owned.len() as i64) < limit {
+            let users = self.shards[index]
+                .list_after(cursor.as_deref(), limit)
+                .await?;
+            let page_size = users.len() as i64;
+            cursor = users.last().map(|user| user.email_address()).or(cursor);
+
+            owned.extend(
+                users
+                    .into_iter()
+                    .filter(|user| self.reads_from(index, &user.email_address())),
+            );
+
+            if page_size < limit {
+                break;
+            }
+        }
+        owned.truncate(limit.max(0) as usize);
+
+        Ok(owned)
+    }
+
+    // The first `limit` users across every shard, each shard contributing the users it owns. A
+    // user on both the shard they belong on and their old one is listed from the former.
+    async fn merged(&self, after: Option<&str>, limit: i64) -> Result<Vec<User>, ApplicationError> {
+        let mut users = Vec::new();
+        for index in 0..self.shards.len() {
+            let owned = self.owned_users(index, after, limit).await?;
+            users.extend(owned.into_iter().map(|user| (index, user)));
+        }
+        users.sort_by_key(|(index, user)| {
+            let email_address = user.email_address();
+            let moved = self.shard_index(&email_address) != *index;
+            (email_address, moved)
+        });
+        users.dedup_by_key(|(_, user)| user.email_address());
+        users.truncate(limit.max(0) as usize);
+
+        Ok(users.into_iter().map(|(_, user)| user).collect())
+    }
+
+    // Walks every shard and copies users that no longer hash to the shard they live on, e.g. after
+    // a shard has been added or when upgrading from the modulo placement. The original rows are
+    // left in place, once the owning shard has the user they are never read again.
+    pub async fn rebalance(&self, dry_run: bool) -> Result<RebalanceReport, ApplicationError> {
+        let mut report = RebalanceReport::default();
+
+        for (current_index, shard) in self.shards.iter().enumerate() {
+            let mut cursor: Option<String> = None;
+
+            loop {
+                let users = shard.list_after(cursor.as_deref(), REBALANCE_PAGE_SIZE).await?;
+                let page_size = users.len() as i64;
+                cursor = users.last().map(|user| user.email_address());
+
+                for user in users {
+                    report.scanned += 1;
+
+                    let target_index = self.shard_index(&user.email_address());
+                    if target_index == current_index {
+                        continue;
+                    }
+
+                    report.misplaced += 1;
+                    log::info!(
+                        "User belongs on shard {} but was found on shard {}",
+                        target_index,
+                        current_index
+                    );
+
+                    let target = &self.shards[target_index];
+                    if dry_run || target.with_email_address(&user.email_address()).await.is_ok() {
+                        continue;
+                    }
+
+                    target.store(user).await?;
+                    report.copied += 1;
+                }
+
+                if page_size < REBALANCE_PAGE_SIZE {
+                    break;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+#[async_trait::async_trait]
+impl<TDataAccess: DataAccess> DataAccess for ShardedDataAccess<TDataAccess> {
+    async fn with_email_address(&self, email_address: &str) -> Result<User, ApplicationError> {
+        self.shard_holding(email_address)
+            .await?
+            .with_email_address(email_address)
+            .await
+    }
+
+    // The id says nothing about the shard, so every shard is asked. A copy left behind by
+    // `rebalance` is skipped, only the shard the email address hashes to owns the user, or the one
+    // the modulo placed them on when the owning shard doesn't have them yet.
+    async fn with_id(&self, id: Uuid) -> Result<User, ApplicationError> {
+        let mut not_moved = None;
+        for (index, shard) in self.shards.iter().enumerate() {
+            match shard.with_id(id).await {
+                Ok(user) if self.shard_index(&user.email_address()) == index => return Ok(user),
+                Ok(user) if self.reads_from(index, &user.email_address()) => not_moved = Some(user),
+                Ok(_) | Err(ApplicationError::UserDoesNotExist) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        not_moved.ok_or(ApplicationError::UserDoesNotExist)
+    }
+
+    async fn list(&self, offset: i64, limit: i64) -> Result<Vec<User>, ApplicationError> {
+        // Shards are ordered independently, so merge the first offset + limit users of each shard
+        // and page over the combined, sorted result. `list_after` doesn't need to re-read earlier
+        // pages.
+        Ok(self
+            .merged(None, offset + limit)
+            .await?
+            .into_iter()
+            .skip(offset as usize)
+            .collect())
+    }
+
+    async fn list_after(
+        &self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<User>, ApplicationError> {
+        self.merged(after, limit).await
+    }
+
+    async fn store(&self, user: User) -> Result<(), ApplicationError> {
+        self.shard_holding(&user.email_address()).await?.store(user).await
+    }
+
+    async fn warm_up(&self, connections: u32) -> Result<(), ApplicationError> {
+        for shard in &self.shards {
+            shard.warm_up(connections).await?;
+        }
+
+        Ok(())
+    }
+
+    // Each shard's best matches up to the end of the page, ranks are computed the same way on
+    // every shard so they can be merged
+    async fn search_ranked(
+        &self,
+        query: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<(User, f32)>, ApplicationError> {
+        let mut found = Vec::new();
+        for shard in &self.shards {
+            found.extend(shard.search_ranked(query, 0, offset + limit).await?);
+        }
+        found.sort_by(|a, b| {
+            b.1.total_cmp(&a.1)
+                .then_with(|| a.0.email_address().cmp(&b.0.email_address()))
+        });
+
+        Ok(found
+            .into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .collect())
+    }
+
+    async fn count(&self) -> Result<i64, ApplicationError> {
+        let mut count = 0;
+        for shard in &self.shards {
+            count += shard.count().await?;
+        }
+
+        Ok(count)
+    }
+
+    async fn exists(&self, email_address: &str) -> Result<bool, ApplicationError> {
+        self.shard_holding(email_address).await?.exists(email_address).await
+    }
+
+    // Every shard, a user on one that is down can't be read or written
+    async fn health_check(&self) -> Result<(), ApplicationError> {
+        for shard in &self.shards {
+            shard.health_check().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn with_email_address_versioned(
+        &self,
+        email_address: &str,
+    ) -> Result<Versioned<User>, ApplicationError> {
+        self.shard_holding(email_address)
+            .await?
+            .with_email_address_versioned(email_address)
+            .await
+    }
+
+    async fn update(
+        &self,
+        user: User,
+        expected_version: Option<i64>,
+    ) -> Result<i64, ApplicationError> {
+        self.shard_holding(&user.email_address())
+            .await?
+            .update(user, expected_version)
+            .await
+    }
+
+    async fn upsert(&self, user: User) -> Result<i64, ApplicationError> {
+        self.shard_holding(&user.email_address()).await?.upsert(user).await
+    }
+
+    async fn soft_delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        self.shard_holding(email_address)
+            .await?
+            .soft_delete(email_address, expected_version)
+            .await
+    }
+
+    // The row stays on the shard it was on, which isn't the one its placeholder address hashes to.
+    // Erased users read as deleted, so nothing looks them up by it.
+    async fn erase(
+        &self,
+        email_address: &str,
+        requested_by: Option<&str>,
+    ) -> Result<Uuid, ApplicationError> {
+        self.shard_holding(email_address)
+            .await?
+            .erase(email_address, requested_by)
+            .await
+    }
+
+    // One shard's transaction can't hold the other's user
+    async fn merge(
+        &self,
+        kept: &str,
+        merged: &str,
+        requested_by: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        let index = self.holding_index(kept).await?;
+        if index != self.holding_index(merged).await? {
+            return Err(ApplicationError::InvalidRequest(format!(
+                "{} is on a different shard, it can't be merged into {}",
+                merged, kept
+            )));
+        }
+
+        self.shards[index].merge(kept, merged, requested_by).await
+    }
+
+    async fn preferences(&self, email_address: &str) -> Result<UserPreferences, ApplicationError> {
+        self.shard_holding(email_address).await?.preferences(email_address).await
+    }
+
+    async fn update_preferences(
+        &self,
+        email_address: &str,
+        preferences: &UserPreferences,
+    ) -> Result<(), ApplicationError> {
+        self.shard_holding(email_address)
+            .await?
+            .update_preferences(email_address, preferences)
+            .await
+    }
+
+    async fn record_login(
+        &self,
+        email_address: &str,
+        logged_in_at: u64,
+        ip: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        self.shard_holding(email_address)
+            .await?
+            .record_login(email_address, logged_in_at, ip)
+            .await
+    }
+
+    async fn last_login(&self, email_address: &str) -> Result<Option<LastLogin>, ApplicationError> {
+        self.shard_holding(email_address).await?.last_login(email_address).await
+    }
+
+    async fn delete(
+        &self,
+        email_address: &str,
+        expected_version: Option<i64>,
+    ) -> Result<(), ApplicationError> {
+        self.shard_holding(email_address)
+            .await?
+            .delete(email_address, expected_version)
+            .await
+    }
+
+    // Aliases are kept on the shard of the user they belong to. Each shard only knows its own, so
+    // two users on different shards can both verify the same address.
+    async fn email_aliases(&self, email_address: &str) -> Result<Vec<EmailAlias>, ApplicationError> {
+        self.shard_holding(email_address).await?.email_aliases(email_address).await
+    }
+
+    async fn add_email_alias(
+        &self,
+        email_address: &str,
+        alias: &str,
+        token_hash: &str,
+    ) -> Result<(), ApplicationError> {
+        self.shard_holding(email_address)
+            .await?
+            .add_email_alias(email_address, alias, token_hash)
+            .await
+    }
+
+    // The token says nothing about the shard, so every shard is asked
+    async fn verify_email_alias(
+        &self,
+        token_hash: &str,
+        added_after: u64,
+    ) -> Result<Option<String>, ApplicationError> {
+        for shard in &self.shards {
+            if let Some(alias) = shard.verify_email_alias(token_hash, added_after).await? {
+                return Ok(Some(alias));
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn remove_email_alias(
+        &self,
+        email_address: &str,
+        alias: &str,
+    ) -> Result<(), ApplicationError> {
+        self.shard_holding(email_address)
+            .await?
+            .remove_email_alias(email_address, alias)
+            .await
+    }
+
+    // The user would have to move to the shard the alias hashes to, which only `rebalance` does
+    async fn set_primary_email_address(
+        &self,
+        email_address: &str,
+        alias: &str,
+    ) -> Result<(), ApplicationError> {
+        let index = self.holding_index(email_address).await?;
+        if index != self.shard_index(alias) {
+            return Err(ApplicationError::InvalidRequest(format!(
+                "{} is on a different shard, it can't become the primary address",
+                alias
+            )));
+        }
+
+        self.shards[index]
+            .set_primary_email_address(email_address, alias)
+            .await
+    }
+
+    async fn resolve_email_alias(&self, alias: &str) -> Result<Option<String>, ApplicationError> {
+        for shard in &self.shards {
+            if let Some(email_address) = shard.resolve_email_alias(alias).await? {
+                return Ok(Some(email_address));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Only the user's shard enforces the username atomically, the others are checked first. Two
+    // users on different shards taking the same username at the same moment can both get it.
+    async fn set_username(
+        &self,
+        email_address: &str,
+        username: Option<&str>,
+    ) -> Result<(), ApplicationError> {
+        let index = self.holding_index(email_address).await?;
+        if let Some(username) = username {
+            for (other, shard) in self.shards.iter().enumerate() {
+                if other != index && shard.resolve_username(username).await?.is_some() {
+                    return Err(ApplicationError::UsernameTaken(username.to_string()));
+                }
+            }
+        }
+
+        self.shards[index].set_username(email_address, username).await
+    }
+
+    async fn resolve_username(&self, username: &str) -> Result<Option<String>, ApplicationError> {
+        for shard in &self.shards {
+            if let Some(email_address) = shard.resolve_username(username).await? {
+                return Ok(Some(email_address));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct InMemoryShard {
+        users: Mutex<BTreeMap<String, User>>,
+    }
+
+    #[async_trait::async_trait]
+    impl DataAccess for InMemoryShard {
+        async fn with_email_address(&self, email_address: &str) -> Result<User, ApplicationError> {
+            self.users
+                .lock()
+                .unwrap()
+                .get(email_address)
+                .cloned()
+                .ok_or(ApplicationError::UserDoesNotExist)
+        }
+
+        async fn list(&self, offset: i64, limit: i64) -> Result<Vec<User>, ApplicationError> {
+            Ok(self
+                .users
+                .lock()
+                .unwrap()
+                .values()
+                .skip(offset as usize)
+                .take(limit as usize)
+                .cloned()
+                .collect())
+        }
+
+        async fn store(&self, user: User) -> Result<(), ApplicationError> {
+            self.users
+                .lock()
+                .unwrap()
+                .insert(user.email_address(), user);
+            Ok(())
+        }
+
+        async fn with_id(&self, id: Uuid) -> Result<User, ApplicationError> {
+            self.users
+                .lock()
+                .unwrap()
+                .values()
+                .find(|user| user.id() == id)
+                .cloned()
+                .ok_or(ApplicationError::UserDoesNotExist)
+        }
+
+        async fn update(&self, user: User, _: Option<i64>) -> Result<i64, ApplicationError> {
+            let mut users = self.users.lock().unwrap();
+            let stored = users
+                .get_mut(&user.email_address())
+                .ok_or(ApplicationError::UserDoesNotExist)?;
+            *stored = user;
+            Ok(1)
+        }
+    }
+
+    impl InMemoryShard {
+        fn update_name_for_test(&self, email_address: &str, name: &str) {
+            if let Some(user) = self.users.lock().unwrap().get_mut(email_address) {
+                user.update_name(name);
+            }
+        }
+    }
+
+    fn user(email_address: &str) -> User {
+        User::from(email_address, "James", "hashed")
+    }
+
+    #[tokio::test]
+    async fn when_a_user_is_stored_should_be_retrievable_from_the_owning_shard() {
+        let sharded = ShardedDataAccess::new(vec![
+            InMemoryShard::default(),
+            InMemoryShard::default(),
+            InMemoryShard::default(),
+        ])
+        .unwrap();
+
+        for index in 0..20 {
+            sharded.store(user(&format!("user{}@test.com", index))).await.unwrap();
+        }
+
+        for index in 0..20 {
+            let email_address = format!("user{}@test.com", index);
+            let owner = &sharded.shards[sharded.shard_index(&email_address)];
+
+            assert!(owner.with_email_address(&email_address).await.is_ok());
+        }
+        assert_eq!(sharded.list(0, 100).await.unwrap().len(), 20);
+    }
+
+    #[tokio::test]
+    async fn when_users_are_on_the_wrong_shard_rebalance_should_copy_them() {
+        let sharded =
+            ShardedDataAccess::new(vec![InMemoryShard::default(), InMemoryShard::default()])
+                .unwrap();
+
+        // Simulate data written before the second shard was added
+        for index in 0..10 {
+            sharded.shards[0]
+                .store(user(&format!("user{}@test.com", index)))
+                .await
+                .unwrap();
+        }
+
+        let report = sharded.rebalance(false).await.unwrap();
+
+        assert!(report.misplaced > 0);
+        assert_eq!(report.misplaced, report.copied);
+        // Copied users are scanned again on their new shard, where they are correctly placed
+        assert_eq!(report.scanned, 10 + report.copied);
+        for index in 0..10 {
+            let email_address = format!("user{}@test.com", index);
+            assert!(sharded.with_email_address(&email_address).await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn a_lookup_by_id_should_only_find_the_user_on_the_shard_that_owns_them() {
+        let sharded =
+            ShardedDataAccess::new(vec![InMemoryShard::default(), InMemoryShard::default()])
+                .unwrap();
+        // Somewhere neither placement puts them
+        let misplaced = (0..10)
+            .map(|index| user(&format!("user{}@test.com", index)))
+            .find(|user| !sharded.reads_from(0, &user.email_address()))
+            .unwrap();
+        sharded.shards[0].store(misplaced.clone()).await.unwrap();
+
+        assert!(sharded.with_id(misplaced.id()).await.is_err());
+
+        sharded.rebalance(false).await.unwrap();
+        sharded.shards[1].update_name_for_test(&misplaced.email_address(), "Renamed");
+
+        let found = sharded.with_id(misplaced.id()).await.unwrap();
+        assert_eq!(found.name(), "Renamed");
+    }
+
+    #[tokio::test]
+    async fn copies_left_by_rebalance_should_not_be_listed_twice() {
+        let sharded =
+            ShardedDataAccess::new(vec![InMemoryShard::default(), InMemoryShard::default()])
+                .unwrap();
+        for index in 0..10 {
+            sharded.shards[0]
+                .store(user(&format!("user{}@test.com", index)))
+                .await
+                .unwrap();
+        }
+        sharded.rebalance(false).await.unwrap();
+
+        let listed = sharded.list(0, 100).await.unwrap();
+        let mut walked = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = sharded.list_after(cursor.as_deref(), 3).await.unwrap();
+            if page.is_empty() {
+                break;
+            }
+            cursor = page.last().map(|user| user.email_address());
+            walked.extend(page.into_iter().map(|user| user.email_address()));
+        }
+
+        assert_eq!(listed.len(), 10);
+        assert_eq!(sharded.list(8, 5).await.unwrap().len(), 2);
+        assert_eq!(
+            walked,
+            listed.iter().map(|user| user.email_address()).collect::<Vec<_>>()
+        );
+    }
+
+    // Each address's shard for 1 to 8 shards, changing the hash would move users to shards that
+    // don't have them
+    #[test]
+    fn shard_index_should_be_stable() {
+        let placements = [
+            ("james@test.com", [0, 0, 2, 2, 2, 5, 5, 5]),
+            ("jane@test.com", [0, 1, 1, 3, 3, 3, 3, 7]),
+            ("user0@test.com", [0, 1, 2, 2, 4, 4, 4, 7]),
+            ("user1@test.com", [0, 1, 1, 1, 1, 5, 5, 7]),
+        ];
+
+        for (email_address, expected) in placements {
+            let placed: Vec<usize> = (1..=8)
+                .map(|count| {
+                    ShardedDataAccess::new((0..count).map(|_| InMemoryShard::default()).collect())
+                        .unwrap()
+                        .shard_index(email_address)
+                })
+                .collect();
+
+            assert_eq!(placed, expected, "{}", email_address);
+        }
+    }
+
+    #[tokio::test]
+    async fn users_the_modulo_placed_should_be_found_until_they_are_rebalanced() {
+        let sharded = ShardedDataAccess::new((0..4).map(|_| InMemoryShard::default()).collect())
+            .unwrap();
+        // jane@test.com belongs on shard 3, the modulo put her on shard 0
+        let jane = user("jane@test.com");
+        sharded.shards[0].store(jane.clone()).await.unwrap();
+
+        let mut renamed = sharded.with_email_address("jane@test.com").await.unwrap();
+        renamed.update_name("Renamed");
+        sharded.update(renamed, None).await.unwrap();
+
+        let stored = sharded.shards[0].with_email_address("jane@test.com").await.unwrap();
+        assert_eq!(stored.name(), "Renamed");
+        assert!(sharded.exists("jane@test.com").await.unwrap());
+        assert_eq!(sharded.with_id(jane.id()).await.unwrap().name(), "Renamed");
+        assert_eq!(sharded.list(0, 10).await.unwrap().len(), 1);
+
+        sharded.rebalance(false).await.unwrap();
+        sharded.shards[3].update_name_for_test("jane@test.com", "Moved");
+
+        assert_eq!(sharded.with_email_address("jane@test.com").await.unwrap().name(), "Moved");
+        assert_eq!(sharded.list(0, 10).await.unwrap().len(), 1);
+        let sharded = sharded.with_legacy_placement(false);
+        assert!(sharded.with_email_address("jane@test.com").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn adding_a_shard_should_only_move_the_users_it_takes_over() {
+        let sharded = ShardedDataAccess::new((0..4).map(|_| InMemoryShard::default()).collect())
+            .unwrap();
+        for index in 0..100 {
+            sharded.store(user(&format!("user{}@test.com", index))).await.unwrap();
+        }
+
+        let mut shards = sharded.shards;
+        shards.push(InMemoryShard::default());
+        let grown = ShardedDataAccess::new(shards).unwrap();
+        let report = grown.rebalance(true).await.unwrap();
+
+        assert_eq!(report.scanned, 100);
+        assert!(report.misplaced < 40, "{} of 100 users moved", report.misplaced);
+    }
+}
@@ -0,0 +1,24 @@
+use crate::core::{ApplicationError, User};
+use super::audit::AuditEntry;
+use super::outbox::OutboxEntry;
+
+// Writes that commit or roll back together, from `DataAccess::begin`. Nothing is visible to other
+// reads until `commit`, dropping it without committing rolls every write back. A write that fails
+// leaves it unusable, the caller drops it and returns the error.
+#[async_trait::async_trait]
+pub trait UnitOfWork: Send {
+    // As `DataAccess::store`, along with the user-registered event when the store writes to the
+    // outbox
+    async fn store(&mut self, user: &User) -> Result<(), ApplicationError>;
+    // As `DataAccess::update`, the new version is only seen by others once committed
+    async fn update(
+        &mut self,
+        user: &User,
+        expected_version: Option<i64>,
+    ) -> Result<i64, ApplicationError>;
+    async fn enqueue(&mut self, entry: &OutboxEntry) -> Result<(), ApplicationError>;
+    // Only stores that keep the audit log in their own database can record, see
+    // `AuditLog::in_users_database`
+    async fn record(&mut self, entry: &AuditEntry) -> Result<(), ApplicationError>;
+    async fn commit(self: Box<Self>) -> Result<(), ApplicationError>;
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+
+use crate::core::{ApplicationError, Config};
+
+// Users read by email address, as the JSON `CachedDataAccess` writes, kept for `ttl` or until they
+// are removed
+#[async_trait::async_trait]
+pub trait UserCache: Send + Sync {
+    async fn get(&self, email_address: &str) -> Result<Option<String>, ApplicationError>;
+    async fn set(
+        &self,
+        email_address: &str,
+        user: &str,
+        ttl: Duration,
+    ) -> Result<(), ApplicationError>;
+    async fn remove(&self, email_addresses: &[&str]) -> Result<(), ApplicationError>;
+}
+
+pub async fn user_cache_from_config(
+    config: &Config,
+) -> Result<Option<Arc<dyn UserCache>>, ApplicationError> {
+    if !config.user_cache_enabled() {
+        return Ok(None);
+    }
+
+    let cache = RedisUserCache::new(&config.user_cache_redis_url(), config.user_cache_key_prefix())
+        .await?;
+    Ok(Some(Arc::new(cache)))
+}
+
+#[derive(Default)]
+pub struct InMemoryUserCache {
+    users: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+#[async_trait::async_trait]
+impl UserCache for InMemoryUserCache {
+    async fn get(&self, email_address: &str) -> Result<Option<String>, ApplicationError> {
+        Ok(self
+            .users
+            .lock()
+            .unwrap()
+            .get(email_address)
+            .filter(|(_, expires_at)| *expires_at > Instant::now())
+            .map(|(user, _)| user.clone()))
+    }
+
+    async fn set(
+        &self,
+        email_address: &str,
+        user: &str,
+        ttl: Duration,
+    ) -> Result<(), ApplicationError> {
+        self.users
+            .lock()
+            .unwrap()
+            .insert(email_address.to_string(), (user.to_string(), Instant::now() + ttl));
+
+        Ok(())
+    }
+
+    async fn remove(&self, email_addresses: &[&str]) -> Result<(), ApplicationError> {
+        let mut users = self.users.lock().unwrap();
+        for email_address in email_addresses {
+            users.remove(*email_address);
+        }
+
+        Ok(())
+    }
+}
+
+fn redis_error(e: redis::RedisError) -> ApplicationError {
+    ApplicationError::DatabaseError(e.to_string())
+}
+
+// One key per user under `key_prefix`, which Redis expires itself. The connection manager
+// reconnects after Redis goes away, until then each command fails on its own.
+pub struct RedisUserCache {
+    connection: ConnectionManager,
+    key_prefix: String,
+}
+
+impl RedisUserCache {
+    pub async fn new(redis_url: &str, key_prefix: String) -> Result<Self, ApplicationError> {
+        let client = redis::Client::open(redis_url).map_err(redis_error)?;
+        let connection = ConnectionManager::new(client).await.map_err(redis_error)?;
+
+        Ok(Self {
+            connection,
+            key_prefix,
+        })
+    }
+
+    fn key(&self, email_address: &str) -> String {
+        format!("{}{}", self.key_prefix, email_address)
+    }
+}
+
+#[async_trait::async_trait]
+impl UserCache for RedisUserCache {
+    async fn get(&self, email_address: &str) -> Result<Option<String>, ApplicationError> {
+        let mut connection = self.connection.clone();
+
+        connection.get(self.key(email_address)).await.map_err(redis_error)
+    }
+
+    async fn set(
+        &self,
+        email_address: &str,
+        user: &str,
+        ttl: Duration,
+    ) -> Result<(), ApplicationError> {
+        let mut connection = self.connection.clone();
+
+        connection
+            .set_ex(self.key(email_address), user, ttl.as_secs().max(1))
+            .await
+            .map_err(redis_error)
+    }
+
+    async fn remove(&self, email_addresses: &[&str]) -> Result<(), ApplicationError> {
+        let keys: Vec<String> = email_addresses.iter().map(|email| self.key(email)).collect();
+        let mut connection = self.connection.clone();
+
+        let _: usize = connection.del(keys).await.map_err(redis_error)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use sqlx::postgres::PgListener;
+use uuid::Uuid;
+
+use crate::core::ApplicationError;
+
+const USERS_CHANGED_CHANNEL: &str = "users_changed";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// A user as they were before they were updated or deleted, by whichever API replica, worker or
+// command did it
+#[derive(Debug, Deserialize)]
+pub struct UserChange {
+    pub id: Uuid,
+    pub email_address: String,
+    pub username: Option<String>,
+}
+
+// What a listener is told about, in the order the changes were committed
+pub enum UserChangeNotification {
+    Changed(UserChange),
+    // The connection was lost, changes committed until it is reopened are missed
+    Missed,
+}
+
+// Calls `on_change` for every user whose row changes in the database at `connection_string`. Only
+// fails when the first connection can't be opened, after that it reconnects for as long as it
+// takes.
+pub async fn listen_for_user_changes(
+    connection_string: &str,
+    on_change: impl Fn(UserChangeNotification),
+) -> Result<(), ApplicationError> {
+    let mut listener = PgListener::connect(connection_string)
+        .await
+        .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+    listener
+        .listen(USERS_CHANGED_CHANNEL)
+        .await
+        .map_err(|e| ApplicationError::DatabaseError(e.to_string()))?;
+
+    log::info!("Listening for user changes on {}", USERS_CHANGED_CHANNEL);
+
+    loop {
+        // `None` when the connection was lost, the next call reconnects and listens again
+        match listener.try_recv().await {
+            Ok(Some(notification)) => match serde_json::from_str(notification.payload()) {
+                Ok(change) => on_change(UserChangeNotification::Changed(change)),
+                Err(e) => log::warn!("Ignoring a malformed user change: {:?}", e),
+            },
+            Ok(None) => {
+                log::warn!("Lost the connection listening for user changes, reconnecting");
+                on_change(UserChangeNotification::Missed);
+            }
+            Err(e) => {
+                log::warn!("Unable to reconnect to listen for user changes: {:?}", e);
+                on_change(UserChangeNotification::Missed);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
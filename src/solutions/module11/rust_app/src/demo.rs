@@ -0,0 +1,352 @@
+use std::collections::BTreeMap;
+use std::io::Write as _;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::kv::{Key, Value};
+use reqwest::header::{COOKIE, SET_COOKIE};
+use reqwest::Method;
+use serde_json::json;
+use structured_logger::{Builder, Writer};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::auth::{promote_admins, InMemoryRevocations, MagicLinkSender, MagicLinks, SessionManager};
+use crate::avatars::Avatars;
+use crate::blobs::InMemoryBlobStore;
+use crate::connections::ConnectionStats;
+use crate::core::{ApplicationError, AuthMode, User};
+use crate::data_access::{InMemoryActiveSessions, InMemoryMagicLinkTokens, InMemoryUsers};
+use crate::metrics::Metrics;
+use crate::registration::AllowAllRegistrations;
+use crate::AppState;
+
+const DEMO_PASSWORD: &str = "Correct-Horse-Battery-42";
+const DEMO_ADMIN: &str = "ada@demo.local";
+const SEED_USERS: [(&str, &str); 3] = [
+    (DEMO_ADMIN, "Ada Lovelace"),
+    ("grace@demo.local", "Grace Hopper"),
+    ("linus@demo.local", "Linus Torvalds"),
+];
+
+pub struct DemoSettings {
+    pub port: u16,
+    // Time between scripted requests, so the audience can follow along
+    pub pause: Duration,
+    // Keep serving once the script has finished, until Ctrl+C
+    pub keep_running: bool,
+}
+
+// Writes log records as single readable lines instead of JSON, indented under the request that
+// caused them.
+struct PrettyWriter;
+
+impl Writer for PrettyWriter {
+    fn write_log(&self, value: &BTreeMap<Key, Value>) -> Result<(), std::io::Error> {
+        let field = |name: &str| {
+            value
+                .get(&Key::from(name))
+                .map(|value| value.to_string())
+                .unwrap_or_default()
+        };
+
+        writeln!(
+            std::io::stdout().lock(),
+            "      {:<5} {}: {}",
+            field("level"),
+            field("target"),
+            field("message")
+        )
+    }
+}
+
+// Human readable logs, plus a line per closed span with its fields and timings
+pub fn init_demo_logging() {
+    Builder::with_level("INFO")
+        .with_target_writer("*", Box::new(PrettyWriter))
+        .init();
+
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::filter::LevelFilter::INFO)
+        .with(
+            tracing_subscriber::fmt::layer()
+                .compact()
+                .without_time()
+                .with_target(false)
+                .with_span_events(FmtSpan::CLOSE),
+        )
+        .init();
+}
+
+// Keeps the links that would have been emailed so the script can follow them
+#[derive(Default)]
+struct DemoMagicLinkSender {
+    last_link: Mutex<Option<String>>,
+}
+
+#[async_trait::async_trait]
+impl MagicLinkSender for DemoMagicLinkSender {
+    async fn send(&self, email_address: &str, link: &str) -> Result<(), ApplicationError> {
+        log::info!("Magic link for {}: {}", email_address, link);
+        *self.last_link.lock().unwrap() = Some(link.to_string());
+
+        Ok(())
+    }
+}
+
+pub async fn run_demo(settings: DemoSettings) -> Result<(), ApplicationError> {
+    let base_url = format!("http://localhost:{}", settings.port);
+    let sender = Arc::new(DemoMagicLinkSender::default());
+
+    let state = AppState {
+        data_access: Arc::new(InMemoryUsers::default()),
+        sessions: SessionManager::new(
+            AuthMode::Cookie,
+            &uuid::Uuid::new_v4().to_string(),
+            3600,
+            false,
+        )
+        .with_impersonation_ttl(300),
+        revocations: Arc::new(InMemoryRevocations::default()),
+        active_sessions: Arc::new(InMemoryActiveSessions::default()),
+        magic_links: MagicLinks::new(
+            Arc::new(InMemoryMagicLinkTokens::default()),
+            sender.clone(),
+            &base_url,
+            300,
+        ),
+        registration_guard: Arc::new(AllowAllRegistrations),
+        brute_force: None,
+        response_cache: None,
+        cors: None,
+        user_reads: None,
+        route_toggles: None,
+        sandbox: None,
+        login_history: None,
+        audit_log: None,
+        avatars: Avatars::new(Arc::new(InMemoryBlobStore::default()), 1024 * 1024),
+        connections: Arc::new(ConnectionStats::default()),
+        metrics: Arc::new(Metrics::default()),
+    };
+
+    let users = SEED_USERS
+        .iter()
+        .map(|(email_address, name)| User::new(email_address, name, DEMO_PASSWORD))
+        .collect::<Result<Vec<User>, ApplicationError>>()?;
+    state.data_access.store_many(users).await?;
+    promote_admins(state.data_access.as_ref(), &[DEMO_ADMIN.to_string()]).await?;
+    println!(
+        "Seeded {} users, all with the password \"{}\", {} is an admin",
+        SEED_USERS.len(),
+        DEMO_PASSWORD,
+        DEMO_ADMIN
+    );
+
+    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", settings.port))
+        .await
+        .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+    let app = crate::router(Arc::new(state), true);
+    let server = tokio::spawn(async move { axum::serve(listener, app.into_make_service()).await });
+    println!("Listening on {}\n", base_url);
+
+    let result = run_script(&DemoClient::new(base_url, settings.pause), &sender).await;
+
+    if result.is_ok() && settings.keep_running {
+        println!("\nThe demo API is still running on port {}, press Ctrl+C to stop", settings.port);
+        let _ = tokio::signal::ctrl_c().await;
+    }
+
+    server.abort();
+    result
+}
+
+async fn run_script(
+    client: &DemoClient,
+    sender: &DemoMagicLinkSender,
+) -> Result<(), ApplicationError> {
+    let new_user = json!({
+        "emailAddress": "margaret@demo.local",
+        "name": "Margaret Hamilton",
+        "password": DEMO_PASSWORD,
+    });
+    let margaret_login = json!({ "emailAddress": "margaret@demo.local", "password": DEMO_PASSWORD });
+
+    client.step("Register a new user").await;
+    client.send(Method::POST, "/users", Some(&new_user), None).await?;
+
+    client.step("Registering the same email address again conflicts").await;
+    client.send(Method::POST, "/users", Some(&new_user), None).await?;
+
+    client.step("Passwords that are easy to guess are rejected with suggestions").await;
+    client
+        .send(
+            Method::POST,
+            "/users",
+            Some(&json!({ "emailAddress": "weak@demo.local", "name": "Weak", "password": "Password1" })),
+            None,
+        )
+        .await?;
+
+    client.step("The wrong password is refused").await;
+    client
+        .send(
+            Method::POST,
+            "/login",
+            Some(&json!({ "emailAddress": "margaret@demo.local", "password": "not-my-password" })),
+            None,
+        )
+        .await?;
+
+    client.step("Logging in sets a session cookie").await;
+    let session = client
+        .send(Method::POST, "/login", Some(&margaret_login), None)
+        .await?
+        .session;
+
+    client.step("The session cookie authenticates further requests").await;
+    client
+        .send(Method::GET, "/users/margaret@demo.local", None, session.as_deref())
+        .await?;
+
+    client.step("Without a session the same request is unauthorized").await;
+    client.send(Method::GET, "/users/margaret@demo.local", None, None).await?;
+
+    client.step("Request a magic link instead of using a password").await;
+    client
+        .send(
+            Method::POST,
+            "/login/magic-link",
+            Some(&json!({ "emailAddress": "grace@demo.local" })),
+            None,
+        )
+        .await?;
+
+    let link = sender.last_link.lock().unwrap().take();
+    if let Some(link) = link {
+        let path = link.trim_start_matches(&client.base_url).to_string();
+
+        client.step("Following the link logs the user in").await;
+        client.send(Method::GET, &path, None, None).await?;
+
+        client.step("Each link only works once").await;
+        client.send(Method::GET, &path, None, None).await?;
+    }
+
+    client.step("An admin can impersonate a user for a support request").await;
+    let admin = client
+        .send(
+            Method::POST,
+            "/login",
+            Some(&json!({ "emailAddress": DEMO_ADMIN, "password": DEMO_PASSWORD })),
+            None,
+        )
+        .await?
+        .session;
+    client
+        .send(
+            Method::POST,
+            "/admin/impersonate/linus@demo.local",
+            None,
+            admin.as_deref(),
+        )
+        .await?;
+
+    client.step("Logging out revokes the session").await;
+    client.send(Method::POST, "/logout", None, session.as_deref()).await?;
+    client
+        .send(Method::GET, "/users/margaret@demo.local", None, session.as_deref())
+        .await?;
+
+    client.step("Everything above was counted").await;
+    client.send(Method::GET, "/metrics", None, None).await?;
+
+    Ok(())
+}
+
+struct DemoResponse {
+    // The session cookie set by the response, if any
+    session: Option<String>,
+}
+
+struct DemoClient {
+    http: reqwest::Client,
+    base_url: String,
+    pause: Duration,
+    steps: AtomicUsize,
+}
+
+impl DemoClient {
+    fn new(base_url: String, pause: Duration) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url,
+            pause,
+            steps: AtomicUsize::new(0),
+        }
+    }
+
+    async fn step(&self, description: &str) {
+        tokio::time::sleep(self.pause).await;
+
+        let step = self.steps.fetch_add(1, Ordering::Relaxed) + 1;
+        println!("\n{}. {}", step, description);
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&serde_json::Value>,
+        session: Option<&str>,
+    ) -> Result<DemoResponse, ApplicationError> {
+        println!("  > {} {}", method, path);
+        if let Some(session) = session {
+            println!("    cookie: {}...", &session[..session.len().min(40)]);
+        }
+
+        let mut request = self
+            .http
+            .request(method, format!("{}{}", self.base_url, path));
+        if let Some(body) = body {
+            print_indented(&serde_json::to_string_pretty(body).unwrap_or_default());
+            request = request.json(body);
+        }
+        if let Some(session) = session {
+            request = request.header(COOKIE, session);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+
+        let status = response.status();
+        let session = response
+            .headers()
+            .get(SET_COOKIE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(str::to_string);
+        let text = response
+            .text()
+            .await
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+
+        println!("  < {}", status);
+        let pretty = serde_json::from_str::<serde_json::Value>(&text)
+            .and_then(|json| serde_json::to_string_pretty(&json))
+            .unwrap_or(text);
+        if !pretty.is_empty() && pretty != "null" {
+            print_indented(&pretty);
+        }
+
+        Ok(DemoResponse { session })
+    }
+}
+
+fn print_indented(text: &str) {
+    for line in text.lines() {
+        println!("    {}", line);
+    }
+}
@@ -0,0 +1,35 @@
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+// How long the link sent to a newly added address can verify it. Adding the address again sends
+// a new one.
+pub const VERIFICATION_TTL_SECONDS: u64 = 24 * 60 * 60;
+
+// The token goes in the link, only its hash is stored, so reading the table doesn't let anyone
+// verify an address they don't receive mail at
+pub fn verification_token() -> (String, String) {
+    let token = hex::encode(rand::rng().random::<[u8; 32]>());
+    let hash = token_hash(&token);
+
+    (token, hash)
+}
+
+pub fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_token_should_be_new_and_stored_as_its_hash() {
+        let (token, hash) = verification_token();
+        let (other, _) = verification_token();
+
+        assert_ne!(token, other);
+        assert_eq!(token.len(), 64);
+        assert_eq!(hash, token_hash(&token));
+        assert_ne!(hash, token);
+    }
+}
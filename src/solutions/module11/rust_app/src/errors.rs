@@ -0,0 +1,365 @@
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use opentelemetry::trace::TraceContextExt;
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::core::{ApplicationError, FieldViolation};
+use crate::metrics::{Metrics, APPLICATION_ERRORS_TOTAL};
+
+// The body of an error response. `code` is `ApplicationError::code`, stable across releases so
+// clients can branch on it, `message` is for people and may change. `retryAfterSeconds` repeats
+// the `Retry-After` header for clients that only look at the body. `fields` lists each rule a field
+// broke when the request failed validation. `hint` says how to switch on a disabled route.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorResponse {
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_seconds: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldViolation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl From<&ApplicationError> for ErrorResponse {
+    fn from(error: &ApplicationError) -> Self {
+        // Server side failures carry driver and connection details that clients shouldn't see
+        let message = match error {
+            ApplicationError::DatabaseError(_) | ApplicationError::ApplicationError(_) => {
+                "an unexpected error occurred".to_string()
+            }
+            ApplicationError::DatabaseTimeout(_) => {
+                "the database did not answer in time".to_string()
+            }
+            _ => error.to_string(),
+        };
+
+        ErrorResponse {
+            code: error.code(),
+            message,
+            retry_after_seconds: retry_after_seconds(error),
+            fields: match error {
+                ApplicationError::ValidationFailed(violations) => violations.clone(),
+                _ => Vec::new(),
+            },
+            hint: match error {
+                ApplicationError::RouteDisabled { hint, .. } => Some(hint.clone()),
+                _ => None,
+            },
+        }
+    }
+}
+
+pub fn status_for(error: &ApplicationError) -> StatusCode {
+    match error {
+        ApplicationError::UserAlreadyExists | ApplicationError::UsernameTaken(_) => {
+            StatusCode::CONFLICT
+        }
+        ApplicationError::UserDoesNotExist => StatusCode::NOT_FOUND,
+        ApplicationError::VersionMismatch => StatusCode::PRECONDITION_FAILED,
+        ApplicationError::IncorrectPassword | ApplicationError::InvalidSession => {
+            StatusCode::UNAUTHORIZED
+        }
+        ApplicationError::WeakPassword { .. }
+        | ApplicationError::InvalidName(_)
+        | ApplicationError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+        ApplicationError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        ApplicationError::ValidationFailed(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        ApplicationError::Throttled { .. } => StatusCode::TOO_MANY_REQUESTS,
+        ApplicationError::RegistrationRejected(_) | ApplicationError::Forbidden => {
+            StatusCode::FORBIDDEN
+        }
+        ApplicationError::TimedOut { .. } | ApplicationError::DatabaseTimeout(_) => {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        ApplicationError::RouteDisabled { .. } => StatusCode::NOT_IMPLEMENTED,
+        ApplicationError::DatabaseError(_) | ApplicationError::ApplicationError(_) => {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+// When a throttled, locked or unavailable request is worth retrying, taken from the state of
+// whatever turned it away. At least a second, `Retry-After: 0` reads as "retry immediately".
+pub fn retry_after_seconds(error: &ApplicationError) -> Option<u64> {
+    match error {
+        ApplicationError::Throttled {
+            retry_after_seconds,
+        } => Some((*retry_after_seconds).max(1)),
+        _ => None,
+    }
+}
+
+// Logs the error, counts it by code and answers with the error envelope
+pub fn error_response(metrics: &Metrics, error: ApplicationError) -> Response {
+    match status_for(&error).is_server_error() {
+        true => log::error!("{:?}", error),
+        false => log::warn!("{:?}", error),
+    }
+    metrics.increment_with_labels(APPLICATION_ERRORS_TOTAL, &[("code", error.code())]);
+
+    let status = status_for(&error);
+    let body = Json(ErrorResponse::from(&error));
+    let retryable = matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::LOCKED | StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    match body.retry_after_seconds.filter(|_| retryable) {
+        Some(seconds) => (status, [(header::RETRY_AFTER, seconds.to_string())], body).into_response(),
+        None => (status, body).into_response(),
+    }
+}
+
+// Error bodies are small, anything larger is passed through rather than buffered
+const MAX_ERROR_BODY_BYTES: usize = 64 * 1024;
+
+// Just enough of `ErrorResponse` to render it again
+#[derive(Deserialize)]
+struct ErrorEnvelope {
+    code: String,
+    message: String,
+}
+
+// Whether `text/html` is preferred over JSON. Browsers list it explicitly, API clients such as
+// curl send `*/*` or ask for JSON, which keeps the envelope.
+pub fn prefers_html(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|accept| accept.to_str().ok()) else {
+        return false;
+    };
+
+    let mut html = 0.0;
+    let mut json = 0.0;
+    for media_range in accept.split(',') {
+        let mut parameters = media_range.split(';').map(str::trim);
+        let media_type = parameters.next().unwrap_or_default().to_ascii_lowercase();
+        let quality = parameters
+            .find_map(|parameter| parameter.strip_prefix("q="))
+            .and_then(|quality| quality.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        match media_type.as_str() {
+            "text/html" => html = f32::max(html, quality),
+            "application/json" | "application/*" | "*/*" => json = f32::max(json, quality),
+            _ => {}
+        }
+    }
+
+    html > json
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn error_page(status: StatusCode, code: &str, message: &str, trace_id: Option<&str>) -> String {
+    let title = format!(
+        "{} {}",
+        status.as_str(),
+        status.canonical_reason().unwrap_or_default()
+    );
+    let trace_id = trace_id
+        .map(|trace_id| format!("<dt>Trace id</dt><dd><code>{}</code></dd>", escape_html(trace_id)))
+        .unwrap_or_default();
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>{title}</title></head>\n<body>\n<h1>{title}</h1>\n<p>{}</p>\n<dl><dt>Code</dt><dd><code>{}</code></dd>{trace_id}</dl>\n</body>\n</html>\n",
+        escape_html(message),
+        escape_html(code),
+    )
+}
+
+// Every request runs in an `http.request` span, continuing the caller's trace if it sent one, so
+// the trace id on an error page is the one the handler's spans were recorded under, and where the
+// time under it went is reported in `Server-Timing` when that is enabled. Error
+// responses to a browser are rendered as a page, the code and message are taken from the
+// envelope, or from the status for handlers that answer with an empty body.
+pub async fn html_error_pages(request: Request, next: Next) -> Response {
+    let html = prefers_html(request.headers());
+    let span = tracing::info_span!(
+        "http.request",
+        http.request.method = %request.method(),
+        url.path = %request.uri().path(),
+        user.locale = tracing::field::Empty,
+    );
+    span.set_parent(crate::telemetry::incoming_context(request.headers()));
+    let span_context = span.context().span().span_context().clone();
+
+    let mut response = next.run(request).instrument(span.clone()).await;
+    if let Some(server_timing) = crate::server_timing::server_timing_header(&span) {
+        response
+            .headers_mut()
+            .insert(crate::server_timing::SERVER_TIMING, server_timing);
+    }
+
+    let status = response.status();
+    if !html || !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, MAX_ERROR_BODY_BYTES).await else {
+        return (status, "").into_response();
+    };
+    let (code, message) = match serde_json::from_slice::<ErrorEnvelope>(&body) {
+        Ok(envelope) => (envelope.code, envelope.message),
+        Err(_) => (
+            status
+                .canonical_reason()
+                .unwrap_or("ERROR")
+                .to_ascii_uppercase()
+                .replace(' ', "_"),
+            status.canonical_reason().unwrap_or_default().to_string(),
+        ),
+    };
+    let trace_id = span_context
+        .is_valid()
+        .then(|| format!("{:032x}", span_context.trace_id()));
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+
+    Response::from_parts(
+        parts,
+        Body::from(error_page(status, &code, &message, trace_id.as_deref())),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    const BROWSER_ACCEPT: &str =
+        "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn only_clients_preferring_html_over_json_should_get_a_page() {
+        assert!(prefers_html(&accept(BROWSER_ACCEPT)));
+        assert!(!prefers_html(&accept("*/*")));
+        assert!(!prefers_html(&accept("application/json, text/html;q=0.5")));
+        assert!(!prefers_html(&HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn an_error_for_a_browser_should_be_rendered_as_a_page() {
+        let app = Router::new()
+            .route(
+                "/envelope",
+                get(|| async {
+                    error_response(&Metrics::default(), ApplicationError::UserDoesNotExist)
+                }),
+            )
+            .route(
+                "/empty",
+                get(|| async { (StatusCode::FORBIDDEN, Json(None::<String>)) }),
+            )
+            .layer(axum::middleware::from_fn(html_error_pages));
+        let request = |path: &str, accept: &'static str| {
+            Request::get(path)
+                .header(header::ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let body = |response: Response| async {
+            String::from_utf8(
+                axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap()
+                    .to_vec(),
+            )
+            .unwrap()
+        };
+
+        let envelope = app.clone().oneshot(request("/envelope", BROWSER_ACCEPT)).await.unwrap();
+        let empty = app.clone().oneshot(request("/empty", BROWSER_ACCEPT)).await.unwrap();
+        let json = app.oneshot(request("/envelope", "*/*")).await.unwrap();
+
+        assert_eq!(envelope.status(), StatusCode::NOT_FOUND);
+        assert_eq!(envelope.headers()[header::CONTENT_TYPE], "text/html; charset=utf-8");
+        let envelope = body(envelope).await;
+        assert!(envelope.contains("<h1>404 Not Found</h1>"));
+        assert!(envelope.contains("<code>USER_DOES_NOT_EXIST</code>"));
+        assert!(body(empty).await.contains("<code>FORBIDDEN</code>"));
+        assert_eq!(json.headers()[header::CONTENT_TYPE], "application/json");
+    }
+
+    #[test]
+    fn server_errors_should_not_expose_their_details() {
+        let conflict = ErrorResponse::from(&ApplicationError::UserAlreadyExists);
+        let database =
+            ErrorResponse::from(&ApplicationError::DatabaseError("password=secret".to_string()));
+
+        assert_eq!(conflict.code, "USER_ALREADY_EXISTS");
+        assert_eq!(conflict.message, "user already exists");
+        assert_eq!(database.code, "DATABASE_ERROR");
+        assert!(!database.message.contains("secret"));
+
+        let timeout = ErrorResponse::from(&ApplicationError::DatabaseTimeout(
+            "canceling statement due to statement timeout on users".to_string(),
+        ));
+        assert_eq!(timeout.code, "DATABASE_TIMEOUT");
+        assert!(!timeout.message.contains("users"));
+        assert_eq!(
+            status_for(&ApplicationError::DatabaseTimeout(String::new())),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[test]
+    fn a_throttled_response_should_say_when_to_retry_in_the_header_and_body() {
+        let throttled = ErrorResponse::from(&ApplicationError::Throttled {
+            retry_after_seconds: 0,
+        });
+        let response = error_response(
+            &Metrics::default(),
+            ApplicationError::Throttled {
+                retry_after_seconds: 42,
+            },
+        );
+
+        assert_eq!(throttled.retry_after_seconds, Some(1));
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "42");
+        assert!(ErrorResponse::from(&ApplicationError::UserAlreadyExists)
+            .retry_after_seconds
+            .is_none());
+    }
+
+    #[test]
+    fn every_error_response_should_be_counted_by_code() {
+        let metrics = Metrics::default();
+
+        let response = error_response(&metrics, ApplicationError::VersionMismatch);
+
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(
+            metrics
+                .snapshot()
+                .counter_with_labels(APPLICATION_ERRORS_TOTAL, &[("code", "VERSION_MISMATCH")]),
+            1
+        );
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+use serde::Serialize;
+
+use crate::core::{ApplicationError, Config, User};
+use crate::data_access::OutboxEntry;
+use crate::partitioning;
+use crate::retry::{RetryBudget, RetryPolicy};
+
+pub const USER_REGISTERED_TOPIC: &str = "user-registered";
+pub const NEW_DEVICE_LOGIN_TOPIC: &str = "new-device-login";
+pub const USER_ERASED_TOPIC: &str = "user-erased";
+pub const USER_MERGED_TOPIC: &str = "user-merged";
+// Emails for a mailer to send, rather than events about users
+pub const NOTIFICATION_EMAIL_TOPIC: &str = "notification-email";
+
+// Bumped when a field of `UserRegisteredEvent` changes meaning or goes away, adding one doesn't.
+// Version 2 added `schemaVersion`, `occurredAt` and `traceContext`.
+pub const USER_REGISTERED_SCHEMA_VERSION: u32 = 2;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UserRegisteredEvent {
+    pub event_id: String,
+    pub schema_version: u32,
+    // Seconds since the epoch by the API's clock, when the user was stored
+    pub occurred_at: u64,
+    // The W3C `traceparent` of the registration, for consumers that don't read the outbox's
+    pub trace_context: Option<String>,
+    // The Kafka key it was produced with, see `partitioning::user_key`
+    pub partition_key: String,
+    pub email_address: String,
+    pub name: String,
+    // Set on events replayed from existing data rather than emitted when the user registered
+    pub synthetic: bool,
+}
+
+impl UserRegisteredEvent {
+    pub fn registered(user: &User, occurred_at: u64, trace_context: Option<String>) -> Self {
+        Self {
+            event_id: uuid::Uuid::new_v4().to_string(),
+            schema_version: USER_REGISTERED_SCHEMA_VERSION,
+            occurred_at,
+            trace_context,
+            partition_key: partitioning::user_key(user.id()),
+            email_address: user.email_address(),
+            name: user.name(),
+            synthetic: false,
+        }
+    }
+
+    // Occurs when it is replayed, which nothing traces back to a registration
+    pub fn synthetic(user: &User, occurred_at: u64) -> Self {
+        Self {
+            event_id: uuid::Uuid::new_v4().to_string(),
+            schema_version: USER_REGISTERED_SCHEMA_VERSION,
+            occurred_at,
+            trace_context: None,
+            partition_key: partitioning::user_key(user.id()),
+            email_address: user.email_address(),
+            name: user.name(),
+            synthetic: true,
+        }
+    }
+
+    // The event's id is the entry's, so the relay republishing it after a crash is a duplicate
+    // consumers can recognise. The entry is traced and timed like the event.
+    pub fn into_outbox_entry(self) -> Result<OutboxEntry, ApplicationError> {
+        Ok(OutboxEntry {
+            id: self.event_id.clone(),
+            topic: USER_REGISTERED_TOPIC.to_string(),
+            key: self.partition_key.clone(),
+            payload: serde_json::to_string(&self)
+                .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?,
+            trace_context: self.trace_context.clone(),
+            created_at: self.occurred_at,
+        })
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NewDeviceLoginEvent {
+    pub event_id: String,
+    pub partition_key: String,
+    pub email_address: String,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub logged_in_at: u64,
+}
+
+impl NewDeviceLoginEvent {
+    pub fn into_outbox_entry(
+        self,
+        trace_context: Option<String>,
+        created_at: u64,
+    ) -> Result<OutboxEntry, ApplicationError> {
+        Ok(OutboxEntry {
+            id: self.event_id.clone(),
+            topic: NEW_DEVICE_LOGIN_TOPIC.to_string(),
+            key: self.partition_key.clone(),
+            payload: serde_json::to_string(&self)
+                .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?,
+            trace_context,
+            created_at,
+        })
+    }
+}
+
+// Tells consumers to erase their copies of the user. It has to carry the address they know the
+// user by, and is keyed by their id like the user's earlier events.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UserErasedEvent {
+    pub event_id: String,
+    pub partition_key: String,
+    pub user_id: String,
+    pub email_address: String,
+    pub erased_at: u64,
+}
+
+impl UserErasedEvent {
+    pub fn into_outbox_entry(
+        self,
+        trace_context: Option<String>,
+        created_at: u64,
+    ) -> Result<OutboxEntry, ApplicationError> {
+        Ok(OutboxEntry {
+            id: self.event_id.clone(),
+            topic: USER_ERASED_TOPIC.to_string(),
+            key: self.partition_key.clone(),
+            payload: serde_json::to_string(&self)
+                .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?,
+            trace_context,
+            created_at,
+        })
+    }
+}
+
+// Tells consumers to fold what they hold about the merged user into the kept one, who is found by
+// the merged address from then on. It is keyed by the kept user's id, so it can arrive before the
+// merged user's last events, which are on their own key.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UserMergedEvent {
+    pub event_id: String,
+    pub partition_key: String,
+    pub kept_user_id: String,
+    pub email_address: String,
+    pub merged_user_id: String,
+    pub merged_email_address: String,
+    pub merged_at: u64,
+}
+
+impl UserMergedEvent {
+    pub fn into_outbox_entry(
+        self,
+        trace_context: Option<String>,
+        created_at: u64,
+    ) -> Result<OutboxEntry, ApplicationError> {
+        Ok(OutboxEntry {
+            id: self.event_id.clone(),
+            topic: USER_MERGED_TOPIC.to_string(),
+            key: self.partition_key.clone(),
+            payload: serde_json::to_string(&self)
+                .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?,
+            trace_context,
+            created_at,
+        })
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationEmail {
+    pub email_id: String,
+    // The recipient's id, so their emails are sent in the order they were queued
+    pub partition_key: String,
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl NotificationEmail {
+    pub fn into_outbox_entry(
+        self,
+        trace_context: Option<String>,
+        created_at: u64,
+    ) -> Result<OutboxEntry, ApplicationError> {
+        Ok(OutboxEntry {
+            id: self.email_id.clone(),
+            topic: NOTIFICATION_EMAIL_TOPIC.to_string(),
+            key: self.partition_key.clone(),
+            payload: serde_json::to_string(&self)
+                .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?,
+            trace_context,
+            created_at,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+pub trait EventPublisher: Send + Sync {
+    async fn publish(&self, topic: &str, key: &str, payload: &str) -> Result<(), ApplicationError>;
+}
+
+// Shared by the consumer in the background worker and the producer, so both pick up SASL settings
+pub fn kafka_client_config(config: &Config) -> ClientConfig {
+    let mut client_config = ClientConfig::new();
+    client_config
+        .set("bootstrap.servers", config.kafka_broker())
+        .set_log_level(RDKafkaLogLevel::Debug);
+
+    if let (Some(username), Some(password)) = (config.kafka_username(), config.kafka_password()) {
+        client_config
+            .set("security.protocol", "SASL_SSL")
+            .set("sasl.mechanisms", "PLAIN")
+            .set("sasl.username", username)
+            .set("sasl.password", password);
+    }
+
+    client_config
+}
+
+pub struct KafkaPublisher {
+    producer: FutureProducer,
+    retry: RetryPolicy,
+    // Fetched on the first publish to each topic. Partitions added later are picked up on restart.
+    partition_counts: Mutex<HashMap<String, u32>>,
+}
+
+impl KafkaPublisher {
+    pub fn from_config(config: &Config) -> Result<Self, ApplicationError> {
+        let producer = kafka_client_config(config)
+            .set("message.timeout.ms", "5000")
+            .create()
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+
+        Ok(Self {
+            producer,
+            // Shared by every publish, so a broker outage can't multiply the load by the attempts
+            retry: RetryPolicy::builder()
+                .max_attempts(3)
+                .base_delay(Duration::from_millis(200))
+                .max_delay(Duration::from_secs(2))
+                .budget(Arc::new(RetryBudget::new(0.1, 10)))
+                .build(),
+            partition_counts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // `None` for a topic the broker doesn't know yet, whose first message is left to librdkafka's
+    // partitioner so that it can be auto-created
+    async fn partition_count(&self, topic: &str) -> Result<Option<u32>, ApplicationError> {
+        if let Some(count) = self.partition_counts.lock().unwrap().get(topic) {
+            return Ok(Some(*count));
+        }
+
+        let producer = self.producer.clone();
+        let name = topic.to_string();
+        let metadata = tokio::task::spawn_blocking(move || {
+            producer.client().fetch_metadata(Some(&name), Duration::from_secs(5))
+        })
+        .await
+        .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?
+        .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+
+        let count = metadata
+            .topics()
+            .iter()
+            .find(|metadata| metadata.name() == topic && metadata.error().is_none())
+            .map(|metadata| metadata.partitions().len() as u32)
+            .filter(|count| *count > 0);
+        if let Some(count) = count {
+            self.partition_counts.lock().unwrap().insert(topic.to_string(), count);
+        }
+
+        Ok(count)
+    }
+}
+
+// The broker being unreachable, busy or electing a leader. A message that is too large or a topic
+// that doesn't exist fails the same way every time.
+fn is_transient(error: &KafkaError) -> bool {
+    matches!(
+        error.rdkafka_error_code(),
+        Some(
+            RDKafkaErrorCode::QueueFull
+                | RDKafkaErrorCode::MessageTimedOut
+                | RDKafkaErrorCode::RequestTimedOut
+                | RDKafkaErrorCode::BrokerTransportFailure
+                | RDKafkaErrorCode::AllBrokersDown
+                | RDKafkaErrorCode::NetworkException
+                | RDKafkaErrorCode::LeaderNotAvailable
+                | RDKafkaErrorCode::NotLeaderForPartition
+                | RDKafkaErrorCode::NotEnoughReplicas
+        )
+    )
+}
+
+#[async_trait::async_trait]
+impl EventPublisher for KafkaPublisher {
+    async fn publish(&self, topic: &str, key: &str, payload: &str) -> Result<(), ApplicationError> {
+        // Chosen here rather than by librdkafka, whose partitioner moves most keys when partitions
+        // are added
+        let partition = self
+            .partition_count(topic)
+            .await?
+            .map(|count| partitioning::partition_for(key, count) as i32);
+
+        self.retry
+            .retry_if("kafka publish", is_transient, || async {
+                let record = FutureRecord::to(topic).payload(payload).key(key);
+                let record = match partition {
+                    Some(partition) => record.partition(partition),
+                    None => record,
+                };
+                self.producer
+                    .send(record, Duration::from_secs(5))
+                    .await
+                    .map_err(|(e, _)| e)
+            })
+            .await
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Consumers parse this shape, a change that fails here needs a new schema version
+    #[test]
+    fn the_user_registered_event_should_keep_its_serialized_shape() {
+        let user = User::from("james@test.com", "James", "hashed");
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let event =
+            UserRegisteredEvent::registered(&user, 1_760_000_000, Some(traceparent.to_string()));
+
+        let entry = event.clone().into_outbox_entry().unwrap();
+        let payload: serde_json::Value = serde_json::from_str(&entry.payload).unwrap();
+
+        assert_eq!(
+            payload,
+            serde_json::json!({
+                "eventId": event.event_id,
+                "schemaVersion": 2,
+                "occurredAt": 1_760_000_000,
+                "traceContext": traceparent,
+                "partitionKey": user.id().to_string(),
+                "emailAddress": "james@test.com",
+                "name": "James",
+                "synthetic": false,
+            })
+        );
+        assert_eq!((entry.id, entry.key), (event.event_id, user.id().to_string()));
+        assert_eq!(entry.trace_context.as_deref(), Some(traceparent));
+        assert_eq!(entry.created_at, 1_760_000_000);
+    }
+
+    #[test]
+    fn only_broker_outages_should_be_retried() {
+        assert!(is_transient(&KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull)));
+        assert!(is_transient(&KafkaError::MessageProduction(RDKafkaErrorCode::AllBrokersDown)));
+
+        assert!(!is_transient(&KafkaError::MessageProduction(RDKafkaErrorCode::MessageSizeTooLarge)));
+        assert!(!is_transient(&KafkaError::MessageProduction(RDKafkaErrorCode::UnknownTopicOrPartition)));
+    }
+}
@@ -1,46 +1,148 @@
+mod anomaly;
+mod auth;
+mod avatars;
+mod backfill;
+mod blobs;
+mod brute_force;
+mod cache;
+mod clock;
+mod connections;
+pub mod consumer;
+mod content_type;
 mod core;
+mod cors;
 mod data_access;
+mod demo;
+mod email_aliases;
+mod errors;
+mod events;
+mod listing;
+mod locale;
+mod login_alerts;
+pub mod metrics;
+mod outbox;
+mod partitioning;
+pub mod prelude;
+mod preconditions;
+mod registration;
+pub mod retry;
+mod route_toggles;
+mod runtime;
+mod sandbox;
+mod seed;
+mod server_timing;
+mod single_flight;
+mod static_resources;
+pub mod tasks;
+mod telemetry;
+#[cfg(feature = "test-support")]
+pub mod testing;
+mod warmup;
 
+pub use crate::backfill::{BackfillReport, BackfillSettings};
+pub use crate::core::parsing;
 pub use crate::core::ApplicationError;
+pub use crate::data_access::{MigrationReport, RebalanceReport};
+pub use crate::demo::{init_demo_logging, run_demo, DemoSettings};
+pub use crate::runtime::{shutdown_signal, RuntimeGuard};
+pub use crate::seed::{SeedReport, SeedSettings};
 
-use crate::core::{DataAccess, LoginRequest, RegisterUserRequest, User, UserDetails};
-use crate::data_access::PostgresUsers;
+use crate::anomaly::AnomalyDetectionSettings;
+use crate::avatars::Avatars;
+use crate::brute_force::{BruteForceDetector, LoginFailure, LoginGate};
+use crate::cache::ResponseCache;
+use crate::cors::CorsPolicy;
+use crate::single_flight::SingleFlight;
+use crate::clock::{AdvanceClockRequest, Clock, ClockResponse};
+use crate::connections::{ConnectionStats, TrackedListener};
+use crate::consumer::HandlerRegistry;
+use crate::auth::{
+    CurrentUser, ImpersonationResponse, MagicLinks, RequireScope, RevocationStore, SessionClaims,
+    SessionCookie, SessionManager, UsersAdmin, UsersRead, UsersWrite, USERS_ADMIN,
+};
+use crate::core::{
+    AddEmailAliasRequest, AdminStatsResponse, AuthMode, ChangeTierRequest, DataAccess,
+    FieldViolation, ListedUserDetails,
+    LoginRequest, MagicLinkRequest, ManagedUserDetails, MergeUsersRequest, PatchUserRequest, Profile,
+    RegisterUserRequest, SearchUsersQuery, SearchedUserDetails,
+    SessionDetails, Tier, TierDetails, UpdateBirthdayRequest, UpdateUserRequest,
+    UpdateUsernameRequest, User, UserDetails, UserPreferences, Versioned, WeakPasswordResponse,
+    MINIMUM_PASSWORD_SCORE,
+};
+use crate::data_access::{
+    ActiveSessions, AuditLog, AuditedDataAccess, BufferSettings, BufferedUsers, CacheSettings,
+    CachedDataAccess, DynamoUsers, InMemoryUsers, LoginHistory, MaintenanceSettings,
+    MigratingDataAccess, MongoUsers, PoolSettings, PoolStatistics, PostgresMaintenance,
+    PostgresOutbox, PostgresUsers, ShardedDataAccess, UserChangeNotification,
+};
+use crate::errors::error_response;
+use crate::events::{EventPublisher, KafkaPublisher};
+use crate::listing::{ListQuery, SessionListing, UserListing, UserSearchListing};
+use crate::locale::AcceptLanguage;
+use crate::outbox::OutboxSettings;
+use crate::registration::{RegistrationAttempt, RegistrationGuard, CHALLENGE_TOKEN_HEADER};
+use crate::route_toggles::RouteToggles;
+use crate::warmup::WarmupSettings;
+use crate::sandbox::{CapturedNotification, NotificationSandbox, SandboxMagicLinkSender};
+use crate::server_timing::ServerTimingLayer;
+use crate::metrics::{
+    Metrics, BRUTE_FORCE_DETECTED_TOTAL, HTTP_COALESCED_REQUESTS_TOTAL, USER_LOGIN_FAILED_TOTAL, USER_LOGIN_THROTTLED_TOTAL,
+    USER_LOGIN_TOTAL, USER_REGISTERED_TOTAL, USER_REGISTRATION_FAILED_TOTAL,
+};
 use anyhow::Result;
-use axum::extract::{Path, State};
-use axum::routing::get;
+use axum::extract::{DefaultBodyLimit, Multipart, Path, Query, State};
+use axum::Extension;
+use axum::http::{header, HeaderMap};
+use axum::middleware;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, put};
 use axum::{http::StatusCode, routing::post, Json, Router};
+use axum_extra::extract::CookieJar;
 use core::Config;
-use log::info;
 use opentelemetry::{trace::TracerProvider as _, KeyValue};
 use opentelemetry_sdk::{
-    trace::{RandomIdGenerator, Sampler, SdkTracerProvider},
+    trace::{Sampler, SdkTracerProvider},
     Resource,
 };
 use opentelemetry_semantic_conventions::{
     attribute::{DEPLOYMENT_ENVIRONMENT_NAME, SERVICE_NAME, SERVICE_VERSION},
     SCHEMA_URL,
 };
-use rdkafka::client::ClientContext;
-use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
-use rdkafka::consumer::stream_consumer::StreamConsumer;
-use rdkafka::consumer::{Consumer, ConsumerContext};
-use rdkafka::Message;
 use std::sync::Arc;
+use std::time::Duration;
 use structured_logger::{async_json::new_writer, Builder};
 use tracing::Level;
 use tracing_opentelemetry::OpenTelemetryLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use uuid::Uuid;
 
-pub struct CustomContext;
-
-impl ClientContext for CustomContext {}
-
-impl ConsumerContext for CustomContext {}
-
-type LoggingConsumer = StreamConsumer<CustomContext>;
-
-pub struct AppState<TDataAccess: DataAccess> {
-    pub data_access: TDataAccess,
+// Not generic over the users store, so the router and its handlers are built once whichever store
+// config picks
+pub struct AppState {
+    pub data_access: Arc<dyn DataAccess>,
+    pub sessions: SessionManager,
+    pub revocations: Arc<dyn RevocationStore>,
+    pub active_sessions: Arc<dyn ActiveSessions>,
+    pub magic_links: MagicLinks,
+    pub registration_guard: Arc<dyn RegistrationGuard>,
+    pub brute_force: Option<BruteForceDetector>,
+    pub response_cache: Option<ResponseCache>,
+    // Only when allowed origins are configured
+    pub cors: Option<CorsPolicy>,
+    // Only when reads are coalesced, the reads of `GET /users/{email_address}` in flight by email
+    // address
+    pub user_reads: Option<SingleFlight<Result<Versioned<User>, ApplicationError>>>,
+    // Only when `routes.enabled` is configured, every other route answers with a 501
+    pub route_toggles: Option<RouteToggles>,
+    // Only in the local profile, see `GET /dev/outbox`
+    pub sandbox: Option<Arc<NotificationSandbox>>,
+    // Only when login alerts are enabled
+    pub login_history: Option<Arc<dyn LoginHistory>>,
+    // Only when auditing is enabled, the log `data_access` writes to through `AuditedDataAccess`
+    pub audit_log: Option<Arc<dyn AuditLog>>,
+    pub avatars: Avatars,
+    pub connections: Arc<ConnectionStats>,
+    pub metrics: Arc<Metrics>,
 }
 
 pub fn init_logger() {
@@ -52,59 +154,545 @@ pub fn init_logger() {
         .init()
 }
 
-pub async fn start_background_worker() -> Result<(), ApplicationError> {
+// librdkafka logs from its own threads, outside the tokio runtime the async writer spawns onto, so
+// the CLI producing to Kafka writes logs synchronously instead.
+pub fn init_cli_logger() {
+    let log_level = std::env::var("LOG_LEVEL").unwrap_or("INFO".to_string());
+
+    Builder::with_level(&log_level)
+        .with_target_writer("*", structured_logger::json::new_writer(std::io::stdout()))
+        .init()
+}
+
+// Relays the outbox and hands every message on the registry's topics to its handlers, with the
+// state they act on. For consumers that built their own state, as with `serve_api`.
+pub async fn start_background_worker(
+    config: &Config,
+    state: Arc<AppState>,
+    handlers: HandlerRegistry,
+) -> Result<(), ApplicationError> {
+    data_access::run_migrations(config).await?;
+    runtime::report_on(&state.metrics);
+
+    if config.outbox_enabled() {
+        let publisher: Arc<dyn EventPublisher> = Arc::new(KafkaPublisher::from_config(config)?);
+        // In sharded mode every shard has its own outbox, written in the same transaction as its
+        // users
+        let mut connection_strings = config.shard_connection_strings();
+        if connection_strings.is_empty() {
+            connection_strings.push(config.connection_string());
+        }
+
+        for connection_string in connection_strings {
+            let outbox = PostgresOutbox::new(
+                data_access::connect(&connection_string, &PoolSettings::from_config(config))
+                    .await?,
+            );
+            tasks::spawn_instrumented(outbox::run_outbox_relay(
+                outbox,
+                publisher.clone(),
+                OutboxSettings::from_config(config),
+            ));
+        }
+    }
+
+    consumer::run_consumer(config, state, handlers).await
+}
+
+// What `rust_users_worker` runs, the default handlers with state built from config. The worker
+// registers no one, so its stores never write to the outbox.
+pub async fn run_background_worker() -> Result<(), ApplicationError> {
     let config = Config::get_configuration()?;
 
-    let postgres_data_access = PostgresUsers::new(config.connection_string()).await?;
+    let metrics = Arc::new(Metrics::default());
+    let clock = Arc::new(Clock::from_config(&config));
 
-    let shared_state = Arc::new(AppState {
-        data_access: postgres_data_access,
-    });
+    if config.dynamodb_enabled() {
+        let dynamo_data_access = connect_dynamo(&config).await?;
 
-    let context = CustomContext;
+        run_worker_with(&config, dynamo_data_access, metrics, clock).await
+    } else if config.mongodb_enabled() {
+        let mongo_data_access = connect_mongo(&config).await?;
 
-    let consumer: LoggingConsumer = ClientConfig::new()
-        .set("group.id", config.kafka_group_id())
-        .set("bootstrap.servers", config.kafka_broker())
-        .set_log_level(RDKafkaLogLevel::Debug)
-        .create_with_context(context)
-        .expect("Consumer creation failed");
+        run_worker_with(&config, mongo_data_access, metrics, clock).await
+    } else if config.shard_connection_strings().is_empty() {
+        let postgres_data_access = connect_postgres(&config).await?.with_metrics(metrics.clone());
 
-    let channels = vec!["order-completed"];
-    consumer
-        .subscribe(&channels)
-        .expect("Can't subscribe to specified topics");
+        run_worker_with(&config, postgres_data_access, metrics, clock).await
+    } else {
+        let sharded_data_access =
+            connect_shards(&config, metrics.clone(), clock.clone(), false).await?;
 
-    loop {
-        // Perform some background task
-        log::info!("Background worker is running...");
-        match consumer.recv().await {
-            Err(e) => tracing::warn!("Kafka error: {}", e),
-            Ok(m) => {
-                info!("Received message");
-                info!("Message: {:?}", m.payload_view::<str>());
-            }
+        run_worker_with(&config, sharded_data_access, metrics, clock).await
+    }
+}
+
+// The worker writes to users too, e.g. when merging them, so with a cache configured it goes
+// through it to remove whom it changed
+async fn run_worker_with<TDataAccess: DataAccess + 'static>(
+    config: &Config,
+    data_access: TDataAccess,
+    metrics: Arc<Metrics>,
+    clock: Arc<Clock>,
+) -> Result<(), ApplicationError> {
+    match data_access::user_cache_from_config(config).await? {
+        Some(cache) => {
+            let settings = CacheSettings::from_config(config);
+            let data_access = CachedDataAccess::new(data_access, cache, settings, metrics.clone());
+            let state = AppState::from_config(config, data_access, metrics, clock).await?;
+            start_background_worker(config, Arc::new(state), HandlerRegistry::with_defaults()).await
+        }
+        None => {
+            let state = AppState::from_config(config, data_access, metrics, clock).await?;
+            start_background_worker(config, Arc::new(state), HandlerRegistry::with_defaults()).await
+        }
+    }
+}
+
+// Users in DynamoDB, MongoDB or memory instead of Postgres, which is then one store, neither
+// sharded nor migrating. Registrations write no events, the outbox is a Postgres table written in
+// the users' transaction.
+fn check_users_store(config: &Config, store: &str) -> Result<(), ApplicationError> {
+    let sharded = !config.shard_connection_strings().is_empty();
+    let stores = [config.dynamodb_enabled(), config.mongodb_enabled(), config.in_memory_enabled()];
+    let both = stores.into_iter().filter(|enabled| *enabled).count() > 1;
+    if sharded || both || config.migration_connection_string().is_some() {
+        return Err(ApplicationError::ApplicationError(format!(
+            "{} is not supported together with database.shards, migration or another users store",
+            store
+        )));
+    }
+    if config.outbox_enabled() {
+        log::warn!("Users are kept in {}, registrations won't write user-registered events", store);
+    }
+
+    Ok(())
+}
+
+async fn connect_dynamo(config: &Config) -> Result<DynamoUsers, ApplicationError> {
+    check_users_store(config, "DynamoDB")?;
+    log::info!("Users are kept in the DynamoDB table {}", config.dynamodb_table_name());
+
+    DynamoUsers::from_config(config).await
+}
+
+async fn connect_mongo(config: &Config) -> Result<MongoUsers, ApplicationError> {
+    check_users_store(config, "MongoDB")?;
+    log::info!(
+        "Users are kept in the MongoDB collection {}.{}",
+        config.mongodb_database(),
+        config.mongodb_collection()
+    );
+
+    MongoUsers::from_config(config).await
+}
+
+fn in_memory_users(config: &Config) -> Result<InMemoryUsers, ApplicationError> {
+    check_users_store(config, "memory")?;
+    log::warn!("Users are held in memory, they are lost when the API stops");
+
+    Ok(InMemoryUsers::default())
+}
+
+// The main database, reading from its replica when one is configured
+async fn connect_postgres(config: &Config) -> Result<PostgresUsers, ApplicationError> {
+    let settings = PoolSettings::from_config(config);
+    let users = PostgresUsers::new(config.connection_string(), &settings).await?;
+
+    match config.read_connection_string() {
+        Some(read_connection_string) => {
+            log::info!("Users are looked up and listed from a replica of the database");
+            let reads = data_access::connect(&read_connection_string, &settings).await?;
+            Ok(users.with_reads(reads))
+        }
+        None => Ok(users),
+    }
+}
+
+// Only the API writes to the outbox, users copied by maintenance commands aren't new registrations
+async fn connect_shards(
+    config: &Config,
+    metrics: Arc<Metrics>,
+    clock: Arc<Clock>,
+    outbox: bool,
+) -> Result<ShardedDataAccess<PostgresUsers>, ApplicationError> {
+    let settings = PoolSettings::from_config(config);
+
+    let mut shards = Vec::new();
+    for connection_string in config.shard_connection_strings() {
+        shards.push(
+            PostgresUsers::new(connection_string, &settings)
+                .await?
+                .with_metrics(metrics.clone())
+                .with_clock(clock.clone())
+                .with_outbox(outbox),
+        );
+    }
+
+    log::info!("Connected to {} database shards", shards.len());
+
+    Ok(ShardedDataAccess::new(shards)?.with_legacy_placement(config.legacy_shard_placement()))
+}
+
+// Only the old database writes the outbox, it is the one every registration is stored in first
+async fn connect_migration(
+    config: &Config,
+    metrics: Arc<Metrics>,
+    clock: Arc<Clock>,
+    outbox: bool,
+) -> Result<MigratingDataAccess<PostgresUsers, PostgresUsers>, ApplicationError> {
+    let Some(new_connection_string) = config.migration_connection_string() else {
+        return Err(ApplicationError::ApplicationError(
+            "migration.connection_string must be set to migrate users".to_string(),
+        ));
+    };
+    if !config.shard_connection_strings().is_empty() {
+        return Err(ApplicationError::ApplicationError(
+            "migrating users is not supported together with database.shards".to_string(),
+        ));
+    }
+    let settings = PoolSettings::from_config(config);
+
+    let old = PostgresUsers::new(config.connection_string(), &settings)
+        .await?
+        .with_metrics(metrics.clone())
+        .with_clock(clock)
+        .with_outbox(outbox);
+    let new = PostgresUsers::new(new_connection_string, &settings)
+        .await?
+        .with_metrics(metrics.clone());
+
+    log::info!(
+        "Migrating users, reads are served by the {:?} database",
+        config.migration_primary()
+    );
+
+    Ok(MigratingDataAccess::new(old, new, config.migration_primary()).with_metrics(metrics))
+}
+
+pub async fn verify_migration(repair: bool) -> Result<MigrationReport, ApplicationError> {
+    let config = Config::get_configuration()?;
+
+    let migrating_data_access =
+        connect_migration(&config, Arc::new(Metrics::default()), Arc::default(), false).await?;
+
+    migrating_data_access.verify(repair).await
+}
+
+pub async fn rebalance_shards(dry_run: bool) -> Result<RebalanceReport, ApplicationError> {
+    let config = Config::get_configuration()?;
+
+    let sharded_data_access =
+        connect_shards(&config, Arc::new(Metrics::default()), Arc::default(), false).await?;
+
+    sharded_data_access.rebalance(dry_run).await
+}
+
+pub async fn backfill_events(settings: BackfillSettings) -> Result<BackfillReport, ApplicationError> {
+    let config = Config::get_configuration()?;
+
+    let publisher = KafkaPublisher::from_config(&config)?;
+
+    if config.shard_connection_strings().is_empty() {
+        let postgres_data_access =
+            PostgresUsers::new(config.connection_string(), &PoolSettings::from_config(&config))
+                .await?;
+
+        backfill::backfill_user_registered_events(&postgres_data_access, &publisher, &settings).await
+    } else {
+        let sharded_data_access =
+            connect_shards(&config, Arc::new(Metrics::default()), Arc::default(), false).await?;
+
+        backfill::backfill_user_registered_events(&sharded_data_access, &publisher, &settings).await
+    }
+}
+
+// Into whichever store `start_api` would use, only with the local profile as the fixture's
+// passwords are known to everyone who has it
+pub async fn seed_users(settings: SeedSettings) -> Result<SeedReport, ApplicationError> {
+    let config = Config::get_configuration()?;
+    if config.profile() != Profile::Local {
+        return Err(ApplicationError::ApplicationError(
+            "seeding users is only allowed with the local profile".to_string(),
+        ));
+    }
+
+    let users = seed::read_fixture(&settings.path)?;
+
+    if config.dynamodb_enabled() {
+        seed::seed_users(&connect_dynamo(&config).await?, users, &settings).await
+    } else if config.mongodb_enabled() {
+        seed::seed_users(&connect_mongo(&config).await?, users, &settings).await
+    } else if config.in_memory_enabled() {
+        Err(ApplicationError::ApplicationError(
+            "users held in memory can't be seeded from outside the API".to_string(),
+        ))
+    } else if config.shard_connection_strings().is_empty() {
+        seed::seed_users(&connect_postgres(&config).await?, users, &settings).await
+    } else {
+        let sharded_data_access =
+            connect_shards(&config, Arc::new(Metrics::default()), Arc::default(), false).await?;
+
+        seed::seed_users(&sharded_data_access, users, &settings).await
+    }
+}
+
+impl AppState {
+    // Everything but the users store is built from config, as `start_api` does. `metrics` should be
+    // the registry the store records to, so its series are served on `/metrics` too, and `clock`
+    // the one it dates events by, usually `Clock::from_config`.
+    pub async fn from_config(
+        config: &Config,
+        data_access: impl DataAccess + 'static,
+        metrics: Arc<Metrics>,
+        clock: Arc<Clock>,
+    ) -> Result<Self, ApplicationError> {
+        let mut magic_links = MagicLinks::from_config(config).await?;
+        let sandbox = (config.profile() == Profile::Local)
+            .then(|| Arc::new(NotificationSandbox::new(sandbox::SANDBOX_CAPACITY)));
+        if let Some(sandbox) = &sandbox {
+            magic_links.sender = Arc::new(SandboxMagicLinkSender {
+                sandbox: sandbox.clone(),
+                inner: magic_links.sender.clone(),
+            });
         }
-        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+
+        Ok(AppState {
+            data_access: Arc::new(data_access),
+            sessions: SessionManager::from_config(config)?.with_clock(clock),
+            revocations: auth::revocation_store_from_config(config).await?,
+            active_sessions: auth::active_sessions_from_config(config).await?,
+            magic_links,
+            registration_guard: registration::registration_guard_from_config(config)?,
+            brute_force: brute_force::brute_force_detector_from_config(config)?,
+            response_cache: ResponseCache::from_config(config),
+            cors: CorsPolicy::from_config(config)?,
+            user_reads: SingleFlight::from_config(config),
+            route_toggles: RouteToggles::from_config(config)?,
+            sandbox,
+            login_history: login_alerts::login_history_from_config(config).await?,
+            // `serve_users` sets it, only it can wrap the store to write to it
+            audit_log: None,
+            avatars: Avatars::from_config(config).await?,
+            connections: Arc::new(ConnectionStats::default()),
+            metrics,
+        })
     }
 }
 
 pub async fn start_api() -> Result<(), ApplicationError> {
     let config = Config::get_configuration()?;
 
-    let postgres_data_access = PostgresUsers::new(config.connection_string()).await?;
+    let metrics = Arc::new(Metrics::default());
+    // Shared by the store and the sessions, so a registration and the session that follows it are
+    // dated alike, also when the clock has been moved forward
+    let clock = Arc::new(Clock::from_config(&config));
 
-    let shared_state = Arc::new(AppState {
-        data_access: postgres_data_access,
-    });
+    data_access::run_migrations(&config).await?;
 
-    // build our application with a route
-    let app = Router::new()
-        // `POST /users` goes to `register_user`
-        .route("/users", post(register_user))
-        .route("/login", post(login))
-        .route("/users/{email_address}", get(get_user_details))
-        .with_state(shared_state);
+    if config.dynamodb_enabled() {
+        let dynamo_data_access = connect_dynamo(&config).await?;
+
+        serve_users(&config, dynamo_data_access, metrics, clock).await
+    } else if config.mongodb_enabled() {
+        let mongo_data_access = connect_mongo(&config).await?;
+
+        serve_users(&config, mongo_data_access, metrics, clock).await
+    } else if config.in_memory_enabled() {
+        let in_memory_data_access = in_memory_users(&config)?;
+
+        serve_users(&config, in_memory_data_access, metrics, clock).await
+    } else if config.migration_connection_string().is_some() {
+        let migrating_data_access =
+            connect_migration(&config, metrics.clone(), clock.clone(), config.outbox_enabled())
+                .await?;
+
+        serve_users(&config, migrating_data_access, metrics, clock).await
+    } else if config.shard_connection_strings().is_empty() {
+        let postgres_data_access = connect_postgres(&config)
+            .await?
+            .with_metrics(metrics.clone())
+            .with_clock(clock.clone())
+            .with_outbox(config.outbox_enabled());
+        record_pool_statistics(&config, &[postgres_data_access.pool_statistics()]);
+        record_outbox_statistics(&config, &metrics, &clock, vec![postgres_data_access.outbox()]);
+
+        serve_users(&config, postgres_data_access, metrics, clock).await
+    } else {
+        let sharded_data_access =
+            connect_shards(&config, metrics.clone(), clock.clone(), config.outbox_enabled())
+                .await?;
+        let statistics: Vec<PoolStatistics> =
+            sharded_data_access.shards().iter().map(PostgresUsers::pool_statistics).collect();
+        record_pool_statistics(&config, &statistics);
+        let outboxes: Vec<PostgresOutbox> =
+            sharded_data_access.shards().iter().map(PostgresUsers::outbox).collect();
+        record_outbox_statistics(&config, &metrics, &clock, outboxes);
+
+        serve_users(&config, sharded_data_access, metrics, clock).await
+    }
+}
+
+// Read off the stores before they are moved into the API, which can't reach their pools anymore
+fn record_pool_statistics(config: &Config, statistics: &[PoolStatistics]) {
+    let interval_seconds = config.pool_statistics_interval_seconds();
+    if interval_seconds == 0 {
+        return;
+    }
+
+    for statistics in statistics {
+        tasks::spawn_instrumented(data_access::run_pool_statistics(
+            statistics.clone(),
+            Duration::from_secs(interval_seconds),
+        ));
+    }
+}
+
+// Every outbox the API writes to, each relayed by the worker on its own
+fn record_outbox_statistics(
+    config: &Config,
+    metrics: &Arc<Metrics>,
+    clock: &Arc<Clock>,
+    outboxes: Vec<PostgresOutbox>,
+) {
+    let interval_seconds = config.outbox_statistics_interval_seconds();
+    if !config.outbox_enabled() || interval_seconds == 0 {
+        return;
+    }
+
+    for outbox in outboxes {
+        tasks::spawn_instrumented(outbox::run_outbox_statistics(
+            outbox,
+            metrics.clone(),
+            clock.clone(),
+            Duration::from_secs(interval_seconds),
+        ));
+    }
+}
+
+// With a cache configured users read by email address are served from it, in front of the store
+// and behind anything else, so the buffer and the audit log read through it too
+async fn serve_users<TDataAccess: DataAccess + 'static>(
+    config: &Config,
+    data_access: TDataAccess,
+    metrics: Arc<Metrics>,
+    clock: Arc<Clock>,
+) -> Result<(), ApplicationError> {
+    match data_access::user_cache_from_config(config).await? {
+        Some(cache) => {
+            let settings = CacheSettings::from_config(config);
+            let data_access = CachedDataAccess::new(data_access, cache, settings, metrics.clone());
+            serve_buffered_users(config, data_access, metrics, clock).await
+        }
+        None => serve_buffered_users(config, data_access, metrics, clock).await,
+    }
+}
+
+// With a buffer configured users are held in memory in front of the store
+async fn serve_buffered_users<TDataAccess: DataAccess + 'static>(
+    config: &Config,
+    data_access: TDataAccess,
+    metrics: Arc<Metrics>,
+    clock: Arc<Clock>,
+) -> Result<(), ApplicationError> {
+    match BufferSettings::from_config(config) {
+        Some(settings) => {
+            let data_access = BufferedUsers::new(data_access, settings, metrics.clone());
+            serve_audited_users(config, data_access, metrics, clock).await
+        }
+        None => serve_audited_users(config, data_access, metrics, clock).await,
+    }
+}
+
+// With auditing enabled every write to the store is recorded, to the log the audit endpoint reads
+async fn serve_audited_users<TDataAccess: DataAccess + 'static>(
+    config: &Config,
+    data_access: TDataAccess,
+    metrics: Arc<Metrics>,
+    clock: Arc<Clock>,
+) -> Result<(), ApplicationError> {
+    match data_access::audit_log_from_config(config).await? {
+        Some(audit_log) => {
+            let data_access = AuditedDataAccess::new(data_access, audit_log.clone());
+            let state = AppState {
+                audit_log: Some(audit_log),
+                ..AppState::from_config(config, data_access, metrics, clock).await?
+            };
+            serve_api(config, state).await
+        }
+        None => {
+            let state = AppState::from_config(config, data_access, metrics, clock).await?;
+            serve_api(config, state).await
+        }
+    }
+}
+
+// Starts the background tasks config enables and serves the API until it fails, for consumers
+// that built their own state
+pub async fn serve_api(config: &Config, state: AppState) -> Result<(), ApplicationError> {
+    let shared_state = Arc::new(state);
+
+    auth::promote_admins(shared_state.data_access.as_ref(), &config.auth_admins()).await?;
+
+    if config.anomaly_detection_enabled() {
+        tasks::spawn_instrumented(anomaly::run_anomaly_detection(
+            shared_state.clone(),
+            AnomalyDetectionSettings::from_config(config),
+        ));
+    }
+
+    if config.maintenance_enabled() {
+        let maintenance = PostgresMaintenance::new(
+            data_access::connect(&config.connection_string(), &PoolSettings::from_config(config))
+                .await?,
+        );
+
+        tasks::spawn_instrumented(data_access::run_maintenance_advisor(
+            maintenance,
+            shared_state.clone(),
+            MaintenanceSettings::from_config(config),
+        ));
+    }
+
+    if config.response_cache_listen_for_changes() && shared_state.response_cache.is_some() {
+        let state = shared_state.clone();
+        let connection_string = config.connection_string();
+        tasks::spawn_instrumented(async move {
+            let listened = data_access::listen_for_user_changes(&connection_string, |notification| {
+                if let Some(cache) = &state.response_cache {
+                    invalidate_changed_user(cache, notification);
+                }
+            })
+            .await;
+            if let Err(e) = listened {
+                log::error!("Unable to listen for user changes: {:?}", e);
+            }
+        });
+    }
+
+    let mut app = router(shared_state.clone(), config.magic_link_enabled());
+
+    // Requests that run out of time are answered with a 503, the deadline is also picked up by
+    // anything the handler spawns with `tasks::spawn_instrumented`
+    if let Some(timeout_ms) = config.request_timeout_ms() {
+        let timeout = Duration::from_millis(timeout_ms);
+        let metrics = shared_state.metrics.clone();
+        app = app.layer(middleware::from_fn(
+            move |request: axum::extract::Request, next: middleware::Next| {
+                let metrics = metrics.clone();
+                async move {
+                    match tasks::with_timeout(timeout, next.run(request)).await {
+                        Ok(response) => response,
+                        Err(e) => error_response(&metrics, e),
+                    }
+                }
+            },
+        ));
+    }
+
+    if config.warmup_enabled() {
+        warmup::warm_up(&shared_state, &app, &WarmupSettings::from_config(config)).await;
+    }
 
     // run our app with hyper, listening globally on port 3000
     println!("Listening on port {}", config.app_port());
@@ -113,56 +701,296 @@ pub async fn start_api() -> Result<(), ApplicationError> {
         .await
         .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
 
-    log::info!("listening on {}", listener.local_addr().unwrap());
+    let address = listener
+        .local_addr()
+        .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+    log::info!("listening on {}", address);
+    let listener = TrackedListener::new(listener, shared_state.connections.clone());
+
+    runtime::report_on(&shared_state.metrics);
 
+    // Requests already being answered are finished before it returns
     axum::serve(listener, app.into_make_service())
+        .with_graceful_shutdown(runtime::shutdown_signal())
         .await
         .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
 
     Ok(())
 }
 
-#[tracing::instrument(skip(state, payload), fields(user.email_is_valid, user.password_is_valid))]
-async fn register_user<TDataAccess: DataAccess + Send + Sync>(
-    State(state): State<Arc<AppState<TDataAccess>>>,
+// Room for the multipart boundaries and headers around the avatar itself
+const AVATAR_FORM_OVERHEAD_BYTES: usize = 16 * 1024;
+
+// Entries `GET /users/{email_address}/audit` returns, the most recent ones
+const AUDIT_TRAIL_LIMIT: i64 = 100;
+
+// The API's routes, without the background tasks or the listener `serve_api` adds
+pub fn router(shared_state: Arc<AppState>, magic_link_enabled: bool) -> Router {
+    // On the routes that take a JSON body, so a body in any other format is a 415
+    let require_json =
+        middleware::from_fn_with_state(shared_state.clone(), content_type::require_json);
+
+    let mut user_routes = Router::new().route(
+        "/users/{email_address}",
+        get(get_user_details)
+            .put(update_user)
+            .patch(patch_user)
+            .delete(delete_user)
+            .layer(require_json.clone()),
+    )
+    .route("/users/{email_address}/erase", post(erase_user))
+    .route(
+        "/users/{email_address}/birthday",
+        put(update_birthday).layer(require_json.clone()),
+    )
+    .route(
+        "/users/{email_address}/username",
+        put(update_username).layer(require_json.clone()),
+    )
+    .route("/usernames/{username}", get(get_user_by_username))
+    .route("/users/{email_address}/tier", get(get_tier))
+    .route(
+        "/users/{email_address}/upgrade",
+        post(upgrade_tier).layer(require_json.clone()),
+    )
+    .route(
+        "/users/{email_address}/downgrade",
+        post(downgrade_tier).layer(require_json.clone()),
+    )
+    .route(
+        "/users/{email_address}/preferences",
+        get(get_preferences)
+            .put(update_preferences)
+            .layer(require_json.clone()),
+    )
+    .route(
+        "/users/{email_address}/emails",
+        get(list_email_aliases)
+            .post(add_email_alias)
+            .layer(require_json.clone()),
+    )
+    .route(
+        "/users/{email_address}/emails/{alias}",
+        delete(remove_email_alias),
+    )
+    .route(
+        "/users/{email_address}/emails/{alias}/primary",
+        post(set_primary_email_address),
+    )
+    .route(
+        "/users/{email_address}/avatar",
+        get(get_avatar)
+            .put(upload_avatar)
+            .layer(DefaultBodyLimit::max(
+                AVATAR_FORM_OVERHEAD_BYTES + shared_state.avatars.max_bytes(),
+            )),
+    );
+
+    if shared_state.sessions.mode() == AuthMode::Cookie {
+        user_routes = user_routes
+            .route("/users/me", get(get_current_user))
+            .route("/users/search", get(search_users))
+            .route("/users/{email_address}/sessions", get(list_sessions))
+            .route(
+                "/users/{email_address}/sessions/{session_id}",
+                delete(revoke_session),
+            );
+        if shared_state.audit_log.is_some() {
+            user_routes = user_routes.route("/users/{email_address}/audit", get(get_audit_trail));
+        }
+    }
+
+    // Added before the session check so it runs inside it and can key responses by principal
+    user_routes = user_routes.route_layer(middleware::from_fn_with_state(
+        shared_state.clone(),
+        cache::cache_responses,
+    ));
+
+    // In cookie mode the session set by `login` must be presented on every subsequent request
+    if shared_state.sessions.mode() == AuthMode::Cookie {
+        user_routes = user_routes.route_layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            auth::require_session,
+        ));
+    }
+
+    // Admin scopes come from sessions, so these only exist when sessions do
+    let mut admin_routes = Router::new();
+    if shared_state.sessions.mode() == AuthMode::Cookie {
+        admin_routes = admin_routes
+            .route("/admin/impersonate/{email_address}", post(impersonate))
+            .route("/admin/users/merge", post(merge_users))
+            .route("/admin/users/{email_address}", delete(hard_delete_user))
+            .route("/admin/stats", get(admin_stats));
+    }
+
+
+    // Never exposed outside the local profile, the outbox shows the links that sign users in and
+    // the clock can expire anyone's session
+    let mut dev_routes = Router::new();
+    if shared_state.sandbox.is_some() {
+        dev_routes = dev_routes.route("/dev/outbox", get(dev_outbox));
+    }
+    if shared_state.sessions.clock().is_adjustable() {
+        dev_routes = dev_routes.route(
+            "/dev/clock/advance",
+            post(advance_clock).layer(require_json.clone()),
+        );
+    }
+
+    let mut login_routes = Router::new().route("/login", post(login).layer(require_json.clone()));
+
+    if magic_link_enabled {
+        login_routes = login_routes
+            .route(
+                "/login/magic-link",
+                post(request_magic_link).layer(require_json.clone()),
+            )
+            .route("/login/magic/{token}", get(complete_magic_link));
+    }
+
+    // Listing needs an admin's session, without sessions anyone could list every user
+    let users_route = match shared_state.sessions.mode() {
+        AuthMode::Cookie => post(register_user).get(list_users),
+        AuthMode::None => post(register_user),
+    };
+
+    // Built once, so every response carries the same ETag for as long as the process runs
+    let version = static_resources::version();
+
+    // build our application with a route
+    Router::new()
+        // `POST /users` goes to `register_user`
+        .route("/users", users_route.layer(require_json))
+        .merge(login_routes)
+        .route("/logout", post(logout))
+        // Followed from the email, by whoever receives mail at the address, signed in or not
+        .route("/emails/verify/{token}", get(verify_email_alias))
+        .route("/metrics", get(metrics))
+        .route(
+            "/version",
+            get(move |headers: HeaderMap| async move { version.respond(&headers) }),
+        )
+        .merge(user_routes)
+        .merge(admin_routes)
+        .merge(dev_routes)
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            route_toggles::require_enabled,
+        ))
+        // Past the toggles, deployments check it whichever routes a workshop has switched on
+        .route("/ready", get(ready))
+        .layer(middleware::from_fn(errors::html_error_pages))
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            connections::track_in_flight,
+        ))
+        // Outermost, so preflights are answered before anything else and error responses carry
+        // the CORS headers too
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            cors::handle_cors,
+        ))
+        .with_state(shared_state)
+}
+
+#[tracing::instrument(
+    skip(state, headers, payload),
+    fields(user.email_is_valid, user.password_is_valid, user.password_score)
+)]
+async fn register_user(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     // this argument tells axum to parse the request body
     // as JSON into a `RegisterUserRequest` type
     Json(payload): Json<RegisterUserRequest>,
-) -> (StatusCode, Json<Option<UserDetails>>) {
+) -> Response {
+    let attempt = RegistrationAttempt {
+        email_address: &payload.email_address,
+        challenge_token: headers
+            .get(CHALLENGE_TOKEN_HEADER)
+            .and_then(|value| value.to_str().ok()),
+        remote_ip: auth::forwarded_ip(&headers),
+    };
+
+    if let Err(e) = state.registration_guard.check(&attempt).await {
+        state.metrics.increment(USER_REGISTRATION_FAILED_TOTAL);
+        return error_response(&state.metrics, e);
+    }
+
+    // Answered before the password is hashed, the most expensive part of a registration. `store`
+    // rejects whatever this lets through, e.g. an address a soft deleted user still holds.
+    match state.data_access.exists(&payload.email_address).await {
+        Ok(false) => {}
+        Ok(true) => {
+            state.metrics.increment(USER_REGISTRATION_FAILED_TOTAL);
+            return error_response(&state.metrics, ApplicationError::UserAlreadyExists);
+        }
+        Err(e) => {
+            state.metrics.increment(USER_REGISTRATION_FAILED_TOTAL);
+            return error_response(&state.metrics, e);
+        }
+    }
+
     // insert your application logic here
-    let user = User::new(&payload.email_address, &payload.name, &payload.password);
+    let user = User::register(&payload);
     match user {
         Ok(user) => {
             let data_access = state.data_access.store(user.clone()).await;
 
             match data_access {
-                Ok(_) => (StatusCode::CREATED, Json(Some(user.details().clone()))),
+                Ok(_) => {
+                    state.metrics.increment(USER_REGISTERED_TOTAL);
+                    // Anything cached for the user is stale once they have been written
+                    invalidate_user(&state, &user);
+                    (StatusCode::CREATED, Json(Some(user.details().clone()))).into_response()
+                }
                 Err(e) => {
-                    log::error!("{:?}", e);
-                    match e {
-                        ApplicationError::UserDoesNotExist => (StatusCode::NOT_FOUND, Json(None)),
-                        _ => (StatusCode::INTERNAL_SERVER_ERROR, Json(None)),
-                    }
+                    state.metrics.increment(USER_REGISTRATION_FAILED_TOTAL);
+                    error_response(&state.metrics, e)
                 }
             }
         }
         Err(e) => {
-            log::error!("{:?}", e);
+            state.metrics.increment(USER_REGISTRATION_FAILED_TOTAL);
             match e {
-                ApplicationError::UserDoesNotExist => (StatusCode::NOT_FOUND, Json(None)),
-                _ => (StatusCode::INTERNAL_SERVER_ERROR, Json(None)),
+                // Keeps its own body, which tells the user how to pick a stronger password
+                ApplicationError::WeakPassword {
+                    score,
+                    warning,
+                    suggestions,
+                } => (
+                    StatusCode::BAD_REQUEST,
+                    Json(WeakPasswordResponse {
+                        message: "The password is too easy to guess".to_string(),
+                        score,
+                        minimum_score: MINIMUM_PASSWORD_SCORE,
+                        warning,
+                        suggestions,
+                    }),
+                )
+                    .into_response(),
+                e => error_response(&state.metrics, e),
             }
         }
     }
 }
 
-#[tracing::instrument(skip(state, payload))]
-async fn login<TDataAccess: DataAccess + Send + Sync>(
-    State(state): State<Arc<AppState<TDataAccess>>>,
+#[tracing::instrument(skip(state, headers, jar, payload))]
+async fn login(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    jar: CookieJar,
     // this argument tells axum to parse the request body
     // as JSON into a `RegisterUserRequest` type
     Json(payload): Json<LoginRequest>,
-) -> (StatusCode, Json<Option<UserDetails>>) {
+) -> Response {
+    state.metrics.increment(USER_LOGIN_TOTAL);
+
+    if let Some(response) = guard_login(&state, &headers, &payload).await {
+        return response;
+    }
+
     let user = state
         .data_access
         .with_email_address(&payload.email_address)
@@ -170,192 +998,3744 @@ async fn login<TDataAccess: DataAccess + Send + Sync>(
 
     match user {
         Ok(user) => match user.verify_password(&payload.password) {
-            Ok(_) => (StatusCode::OK, Json(Some(user.details().clone()))),
-            Err(_) => (StatusCode::UNAUTHORIZED, Json(None)),
+            Ok(_) => start_session(&state, &headers, jar, &user).await,
+            Err(_) => {
+                record_login_failure(&state, LoginFailure::BadPassword).await;
+                error_response(&state.metrics, ApplicationError::IncorrectPassword)
+            }
         },
         Err(e) => {
-            log::error!("{:?}", e);
-            match e {
-                ApplicationError::UserDoesNotExist => (StatusCode::NOT_FOUND, Json(None)),
-                _ => (StatusCode::INTERNAL_SERVER_ERROR, Json(None)),
-            }
+            let failure = match e {
+                ApplicationError::UserDoesNotExist => LoginFailure::UnknownUser,
+                _ => LoginFailure::Error,
+            };
+            record_login_failure(&state, failure).await;
+            error_response(&state.metrics, e)
         }
     }
 }
 
-#[tracing::instrument(skip(state, email_address))]
-async fn get_user_details<TDataAccess: DataAccess + Send + Sync>(
-    State(state): State<Arc<AppState<TDataAccess>>>,
-    // this argument tells axum to parse the request body
-    // as JSON into a `RegisterUserRequest` type
-    Path(email_address): Path<String>,
-) -> (StatusCode, Json<Option<UserDetails>>) {
-    let user = state.data_access.with_email_address(&email_address).await;
+// While the service is locked down after a burst of failures, logins either wait out the
+// cooldown or have to pass the same challenge as registrations.
+async fn guard_login(
+    state: &AppState,
+    headers: &HeaderMap,
+    payload: &LoginRequest,
+) -> Option<Response> {
+    let brute_force = state.brute_force.as_ref()?;
 
-    match user {
-        Ok(user) => (StatusCode::OK, Json(Some(user.details().clone()))),
-        Err(e) => {
-            log::error!("{:?}", e);
-            match e {
-                ApplicationError::UserDoesNotExist => (StatusCode::NOT_FOUND, Json(None)),
-                _ => (StatusCode::INTERNAL_SERVER_ERROR, Json(None)),
+    let gate = match brute_force.gate(state.sessions.clock().now()).await {
+        Ok(gate) => gate,
+        Err(e) => return Some(error_response(&state.metrics, e)),
+    };
+
+    match gate {
+        LoginGate::Open => None,
+        LoginGate::CoolingDown { retry_after } => {
+            state
+                .metrics
+                .increment_with_labels(USER_LOGIN_THROTTLED_TOTAL, &[("reason", "cooldown")]);
+            record_login_failure(state, LoginFailure::LockedAccount).await;
+            Some(error_response(
+                &state.metrics,
+                ApplicationError::Throttled {
+                    retry_after_seconds: retry_after.as_secs(),
+                },
+            ))
+        }
+        LoginGate::ChallengeRequired => {
+            let attempt = RegistrationAttempt {
+                email_address: &payload.email_address,
+                challenge_token: headers
+                    .get(CHALLENGE_TOKEN_HEADER)
+                    .and_then(|value| value.to_str().ok()),
+                remote_ip: None,
+            };
+
+            match state.registration_guard.check(&attempt).await {
+                Ok(_) => None,
+                Err(e) => {
+                    state
+                        .metrics
+                        .increment_with_labels(USER_LOGIN_THROTTLED_TOTAL, &[("reason", "challenge")]);
+                    record_login_failure(state, LoginFailure::LockedAccount).await;
+                    Some(error_response(&state.metrics, e))
+                }
             }
         }
     }
 }
 
-pub struct OtelGuard {
-    tracer_provider: SdkTracerProvider,
-}
+async fn record_login_failure(state: &AppState, failure: LoginFailure) {
+    state
+        .metrics
+        .increment_with_labels(USER_LOGIN_FAILED_TOTAL, &[("reason", failure.as_str())]);
+
+    let Some(brute_force) = &state.brute_force else {
+        return;
+    };
+    if !failure.counts_towards_lockout() {
+        return;
+    }
 
-impl Drop for OtelGuard {
-    fn drop(&mut self) {
-        if let Err(err) = self.tracer_provider.shutdown() {
-            eprintln!("{err:?}");
+    match brute_force.record_failure(state.sessions.clock().now()).await {
+        Ok(true) => {
+            log::warn!("brute-force-detected: too many failed logins, locking down logins");
+            state.metrics.increment(BRUTE_FORCE_DETECTED_TOTAL);
         }
+        Ok(false) => {}
+        Err(e) => log::error!("{:?}", e),
     }
 }
 
-// Create a Resource that captures information about the entity for which telemetry is recorded.
-fn resource() -> Resource {
-    Resource::builder()
-        .with_schema_url(
-            [
-                KeyValue::new(SERVICE_NAME, "users-service"),
-                KeyValue::new(SERVICE_VERSION, "1.0.0"),
-                KeyValue::new(DEPLOYMENT_ENVIRONMENT_NAME, "develop"),
-            ],
-            SCHEMA_URL,
-        )
-        .build()
-}
+// Shared by the password and magic link logins once the user has been authenticated
+async fn start_session(
+    state: &Arc<AppState>,
+    headers: &HeaderMap,
+    jar: CookieJar,
+    user: &User,
+) -> Response {
+    login_alerts::check_login(state, headers, user).await;
+    record_last_login(state, headers, user);
 
-// Construct TracerProvider for OpenTelemetryLayer
-fn init_tracer_provider() -> SdkTracerProvider {
-    let exporter = opentelemetry_otlp::SpanExporter::builder()
-        .with_tonic()
-        .build()
-        .unwrap();
+    if state.sessions.mode() != AuthMode::Cookie {
+        return (StatusCode::OK, jar, Json(user.details().clone())).into_response();
+    }
 
-    SdkTracerProvider::builder()
-        // Customize sampling strategy
-        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
-            1.0,
-        ))))
-        // If export trace to AWS X-Ray, you can use XrayIdGenerator
-        .with_id_generator(RandomIdGenerator::default())
-        .with_resource(resource())
-        .with_batch_exporter(exporter)
-        .build()
+    match state.sessions.issue(user) {
+        Ok((token, claims)) => {
+            auth::record_session(state, &claims, headers).await;
+            (
+                StatusCode::OK,
+                jar.add(state.sessions.session_cookie(token)),
+                Json(user.details().clone()),
+            )
+                .into_response()
+        }
+        Err(e) => error_response(&state.metrics, e),
+    }
 }
 
-// Initialize tracing-subscriber and return OtelGuard for opentelemetry-related termination processing
-pub fn init_tracing_subscriber() -> OtelGuard {
-    let tracer_provider = init_tracer_provider();
-
-    let tracer = tracer_provider.tracer("users-service");
-
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::filter::LevelFilter::from_level(
-            Level::INFO,
-        ))
-        .with(OpenTelemetryLayer::new(tracer))
-        .init();
+// In the background, so the login doesn't wait on the write. Failing to record it is logged
+// rather than failing the login, which has already succeeded.
+fn record_last_login(state: &Arc<AppState>, headers: &HeaderMap, user: &User) {
+    let state = state.clone();
+    let user = user.clone();
+    let ip = auth::forwarded_ip(headers).map(str::to_string);
+    let logged_in_at = state.sessions.clock().now();
 
-    OtelGuard { tracer_provider }
+    tasks::spawn_instrumented(async move {
+        let recorded = state
+            .data_access
+            .record_login(&user.email_address(), logged_in_at, ip.as_deref())
+            .await;
+        match recorded {
+            Ok(()) => invalidate_user(&state, &user),
+            Err(e) => log::error!("{:?}", e),
+        }
+    });
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::core::{ApplicationError, User};
-    use mockall::mock;
-    use std::collections::HashMap;
-    use std::sync::Arc;
-
+#[tracing::instrument(skip(state, payload))]
+async fn request_magic_link(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<MagicLinkRequest>,
+) -> StatusCode {
+    // Unknown addresses get the same response so the endpoint can't be used to discover accounts
+    let user = match state
+        .data_access
+        .with_email_address(&payload.email_address)
+        .await
+    {
+        Ok(user) => user,
+        Err(ApplicationError::UserDoesNotExist) => return StatusCode::ACCEPTED,
+        Err(e) => {
+            log::error!("{:?}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    };
+
+    let magic_links = &state.magic_links;
+    let result = async {
+        let (token, claims) = state
+            .sessions
+            .issue_magic_link(&user.email_address(), magic_links.ttl_seconds())?;
+        magic_links
+            .tokens
+            .save(&claims.jti, &claims.sub, claims.exp)
+            .await?;
+        magic_links
+            .sender
+            .send(&claims.sub, &magic_links.link(&token))
+            .await
+    }
+    .await;
+
+    match result {
+        Ok(_) => StatusCode::ACCEPTED,
+        Err(e) => {
+            log::error!("{:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[tracing::instrument(skip(state, headers, jar, token))]
+async fn complete_magic_link(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    jar: CookieJar,
+    Path(token): Path<String>,
+) -> Response {
+    state.metrics.increment(USER_LOGIN_TOTAL);
+
+    let claims = match state.sessions.verify_magic_link(&token) {
+        Ok(claims) => claims,
+        Err(e) => {
+            log::warn!("{:?}", e);
+            record_login_failure(&state, LoginFailure::InvalidLink).await;
+            return error_response(&state.metrics, ApplicationError::InvalidSession);
+        }
+    };
+
+    match state.magic_links.tokens.consume(&claims.jti).await {
+        Ok(Some(email_address)) if email_address == claims.sub => {}
+        Ok(_) => {
+            log::warn!("Magic link has already been used or has expired");
+            record_login_failure(&state, LoginFailure::InvalidLink).await;
+            return error_response(&state.metrics, ApplicationError::InvalidSession);
+        }
+        Err(e) => return error_response(&state.metrics, e),
+    }
+
+    match state.data_access.with_email_address(&claims.sub).await {
+        Ok(user) => start_session(&state, &headers, jar, &user).await,
+        Err(e) => {
+            let failure = match e {
+                ApplicationError::UserDoesNotExist => LoginFailure::InvalidLink,
+                _ => LoginFailure::Error,
+            };
+            record_login_failure(&state, failure).await;
+            // The link doesn't say whether the account has since been deleted
+            match e {
+                ApplicationError::UserDoesNotExist => {
+                    error_response(&state.metrics, ApplicationError::InvalidSession)
+                }
+                _ => error_response(&state.metrics, e),
+            }
+        }
+    }
+}
+
+#[tracing::instrument(skip(state, jar, claims))]
+async fn logout(
+    State(state): State<Arc<AppState>>,
+    jar: CookieJar,
+    SessionCookie(claims): SessionCookie,
+) -> Response {
+    match state.revocations.revoke(&claims.jti, claims.exp).await {
+        Ok(_) => {
+            if let Err(e) = state.active_sessions.remove(&claims.sub, &claims.jti).await {
+                log::error!("{:?}", e);
+            }
+            (
+                StatusCode::NO_CONTENT,
+                jar.remove(state.sessions.removal_cookie()),
+            )
+                .into_response()
+        }
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+#[tracing::instrument(skip(state, admin, email_address, headers))]
+async fn impersonate(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersAdmin>,
+    CurrentUser { claims: admin, .. }: CurrentUser,
+    Path(email_address): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let user = match state.data_access.with_email_address(&email_address).await {
+        Ok(user) => user,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+
+    match state.sessions.issue_impersonation(&user, &admin) {
+        Ok((token, claims)) => {
+            // Recorded so the user can see that an admin is acting as them
+            auth::record_session(&state, &claims, &headers).await;
+            (
+                StatusCode::OK,
+                Json(ImpersonationResponse {
+                    token,
+                    email_address: claims.sub,
+                    impersonated_by: admin.sub,
+                    expires_at: claims.exp,
+                }),
+            )
+                .into_response()
+        }
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+#[tracing::instrument(skip(current_user))]
+async fn get_current_user(
+    current_user: CurrentUser,
+    locale: AcceptLanguage,
+) -> (StatusCode, AcceptLanguage, Json<Option<UserDetails>>) {
+    (StatusCode::OK, locale, Json(Some(current_user.user.details().clone())))
+}
+
+// Every user's details, so only admins may list them and only in cookie mode
+#[tracing::instrument(skip(state, params))]
+async fn list_users(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersAdmin>,
+    ListQuery { params, .. }: ListQuery<UserListing>,
+) -> Response {
+    if params.include_deleted {
+        return match state
+            .data_access
+            .list_including_deleted(params.offset, params.limit)
+            .await
+        {
+            Ok(users) => Json(
+                users
+                    .into_iter()
+                    .map(|(user, deleted_at)| ListedUserDetails {
+                        details: user.details().clone(),
+                        deleted_at,
+                    })
+                    .collect::<Vec<ListedUserDetails>>(),
+            )
+            .into_response(),
+            Err(e) => error_response(&state.metrics, e),
+        };
+    }
+
+    // A cursor seeks straight to the page, an offset counts through every user before it
+    let users = match &params.after {
+        Some(after) => state.data_access.list_after(Some(after), params.limit).await,
+        None => state.data_access.list(params.offset, params.limit).await,
+    };
+    let users = match users {
+        Ok(users) => users,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+
+    // A full page may not be the last, the next one starts after its last user
+    let next = users
+        .last()
+        .filter(|_| users.len() as i64 == params.limit)
+        .map(|last| {
+            format!(
+                "</users?cursor={}&limit={}>; rel=\"next\"",
+                listing::cursor(&last.email_address()),
+                params.limit
+            )
+        });
+    let body = Json(
+        users
+            .iter()
+            .map(|user| user.details().clone())
+            .collect::<Vec<UserDetails>>(),
+    );
+
+    match next {
+        Some(next) => ([(header::LINK, next)], body).into_response(),
+        None => body.into_response(),
+    }
+}
+
+#[tracing::instrument(skip(state, key))]
+async fn get_user_details(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersRead>,
+    claims: Option<Extension<SessionClaims>>,
+    // this argument tells axum to parse the request body
+    // as JSON into a `RegisterUserRequest` type
+    Path(key): Path<String>,
+) -> Response {
+    let email_address = match resolve_email_address(&state, &key).await {
+        Ok(email_address) => email_address,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    let read = || state.data_access.with_email_address_versioned(&email_address);
+    let user = match &state.user_reads {
+        Some(user_reads) => {
+            let (user, coalesced) = user_reads.run(&email_address, read).await;
+            if coalesced {
+                state.metrics.increment_with_labels(
+                    HTTP_COALESCED_REQUESTS_TOTAL,
+                    &[("route", "/users/{email_address}")],
+                );
+            }
+            user
+        }
+        None => read().await,
+    };
+
+    let user = match user {
+        Ok(user) => user,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    // The version is the ETag, so it can be sent back in `If-Match` to update or delete
+    let etag = [(header::ETAG, preconditions::version_etag(user.version))];
+    let details = user.value.details().clone();
+    if !may_manage(claims.as_deref(), &email_address) {
+        return (StatusCode::OK, etag, Json(Some(details))).into_response();
+    }
+
+    // A login isn't an edit, so it leaves the version and the ETag as they were. Without it the
+    // details are still sent, only without the last login.
+    let last_login = match state.data_access.last_login(&email_address).await {
+        Ok(last_login) => last_login,
+        Err(e) => {
+            log::error!("{:?}", e);
+            None
+        }
+    };
+
+    (StatusCode::OK, etag, Json(Some(ManagedUserDetails::new(details, last_login))))
+        .into_response()
+}
+
+// Usernames are matched whatever their case, as they are stored in lower case
+#[tracing::instrument(skip(state))]
+async fn get_user_by_username(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersRead>,
+    Path(username): Path<String>,
+) -> Response {
+    let email_address = match state.data_access.resolve_username(&username.to_lowercase()).await {
+        Ok(Some(email_address)) => email_address,
+        Ok(None) => return error_response(&state.metrics, ApplicationError::UserDoesNotExist),
+        Err(e) => return error_response(&state.metrics, e),
+    };
+
+    match state.data_access.with_email_address_versioned(&email_address).await {
+        Ok(user) => (
+            StatusCode::OK,
+            [(header::ETAG, preconditions::version_etag(user.version))],
+            Json(Some(user.value.details().clone())),
+        )
+            .into_response(),
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+// In cookie mode users may only manage their own account, unless the session is an admin's.
+// Without authentication there are no sessions and anyone may.
+fn may_manage(claims: Option<&SessionClaims>, email_address: &str) -> bool {
+    claims.is_none_or(|claims| claims.sub == email_address || claims.has_scope(USERS_ADMIN))
+}
+
+// `/users/{email_address}` also takes the user's id, or any address they have verified. Anything
+// that parses as a UUID is looked up as an id, an email address never does. Everything past this
+// works with the primary address.
+async fn resolve_email_address(state: &AppState, key: &str) -> Result<String, ApplicationError> {
+    match Uuid::parse_str(key) {
+        Ok(id) => Ok(state.data_access.with_id(id).await?.email_address()),
+        Err(_) => Ok(state
+            .data_access
+            .resolve_email_alias(key)
+            .await?
+            .unwrap_or_else(|| key.to_string())),
+    }
+}
+
+// The user at `/users/{email_address}`, by their id or any of their email addresses
+async fn find_user(state: &AppState, key: &str) -> Result<User, ApplicationError> {
+    match Uuid::parse_str(key) {
+        Ok(id) => state.data_access.with_id(id).await,
+        Err(_) => {
+            let email_address = resolve_email_address(state, key).await?;
+            state.data_access.with_email_address(&email_address).await
+        }
+    }
+}
+
+// A user is cached under both of the paths they can be read from, and as `/users/me` for their
+// own session. That path is shared, so it is dropped for every principal. Responses read through
+// one of the user's aliases aren't dropped, they expire with their TTL.
+fn invalidate_user(state: &AppState, user: &User) {
+    if let Some(cache) = &state.response_cache {
+        invalidate_user_paths(cache, user.id(), &user.email_address(), user.username());
+    }
+}
+
+fn invalidate_user_paths(
+    cache: &ResponseCache,
+    id: Uuid,
+    email_address: &str,
+    username: Option<String>,
+) {
+    cache.invalidate_path(&format!("/users/{}", email_address));
+    cache.invalidate_path(&format!("/users/{}", id));
+    cache.invalidate_path("/users/me");
+    if let Some(username) = username {
+        cache.invalidate_path(&format!("/usernames/{}", username));
+    }
+}
+
+// Users changed by another replica or the worker, which this one's cache doesn't hear about
+// otherwise. What it cached while it couldn't listen may be out of date, so all of it is dropped.
+fn invalidate_changed_user(cache: &ResponseCache, notification: UserChangeNotification) {
+    match notification {
+        UserChangeNotification::Changed(change) => {
+            // Their row also holds what these are read from
+            for suffix in ["/tier", "/preferences"] {
+                cache.invalidate_path(&format!("/users/{}{}", change.email_address, suffix));
+                cache.invalidate_path(&format!("/users/{}{}", change.id, suffix));
+            }
+            invalidate_user_paths(cache, change.id, &change.email_address, change.username);
+        }
+        UserChangeNotification::Missed => cache.clear(),
+    }
+}
+
+// Something read under the user's paths, e.g. `/avatar`, by email address and by id
+fn invalidate_user_resource(state: &AppState, user: &User, suffix: &str) {
+    if let Some(cache) = &state.response_cache {
+        cache.invalidate_path(&format!("/users/{}{}", user.email_address(), suffix));
+        cache.invalidate_path(&format!("/users/{}{}", user.id(), suffix));
+    }
+}
+
+#[tracing::instrument(skip(state, claims, key, headers, payload))]
+async fn update_user(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersWrite>,
+    claims: Option<Extension<SessionClaims>>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<UpdateUserRequest>,
+) -> Response {
+    let email_address = match resolve_email_address(&state, &key).await {
+        Ok(email_address) => email_address,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    if !may_manage(claims.as_deref(), &email_address) {
+        return error_response(&state.metrics, ApplicationError::Forbidden);
+    }
+    let expected_version = match preconditions::expected_version(&headers) {
+        Ok(expected_version) => expected_version,
+        Err(status) => return (status, Json(None::<UserDetails>)).into_response(),
+    };
+    if let Err(e) = User::name_is_valid(&payload.name) {
+        return error_response(&state.metrics, e);
+    }
+
+    let mut user = match state.data_access.with_email_address(&email_address).await {
+        Ok(user) => user,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    user.update_name(&payload.name);
+
+    // The version is checked again by the write itself, a change made after the read above is
+    // still answered with a 412
+    match state.data_access.update(user.clone(), expected_version).await {
+        Ok(version) => {
+            invalidate_user(&state, &user);
+            (
+                StatusCode::OK,
+                [(header::ETAG, preconditions::version_etag(version))],
+                Json(Some(user.details().clone())),
+            )
+                .into_response()
+        }
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+// How many times a patch is re-applied when another write lands between its read and its write
+const PATCH_ATTEMPTS: usize = 3;
+
+#[tracing::instrument(skip(state, claims, key, headers, payload))]
+async fn patch_user(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersWrite>,
+    claims: Option<Extension<SessionClaims>>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<PatchUserRequest>,
+) -> Response {
+    let email_address = match resolve_email_address(&state, &key).await {
+        Ok(email_address) => email_address,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    if !may_manage(claims.as_deref(), &email_address) {
+        return error_response(&state.metrics, ApplicationError::Forbidden);
+    }
+    if !payload.is_valid() {
+        return (StatusCode::BAD_REQUEST, Json(None::<UserDetails>)).into_response();
+    }
+    if let Some(Err(e)) = payload.name.as_deref().map(|name| User::name_is_valid(name.trim())) {
+        return error_response(&state.metrics, e);
+    }
+    // An empty phone number removes it, anything else has to normalize
+    let phone_number = match payload.phone_number.as_deref().map(str::trim) {
+        Some("") => Some(None),
+        Some(phone_number) => match User::normalize_phone_number(phone_number) {
+            Ok(phone_number) => Some(Some(phone_number)),
+            Err(e) => return error_response(&state.metrics, e),
+        },
+        None => None,
+    };
+    // Unlike PUT, `If-Match` is optional. Without it the patch is applied to whatever is stored.
+    let expected_version = match headers.contains_key(header::IF_MATCH) {
+        true => match preconditions::expected_version(&headers) {
+            Ok(expected_version) => expected_version,
+            Err(status) => return (status, Json(None::<UserDetails>)).into_response(),
+        },
+        false => None,
+    };
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+
+        let stored = match state
+            .data_access
+            .with_email_address_versioned(&email_address)
+            .await
+        {
+            Ok(stored) => stored,
+            Err(e) => return error_response(&state.metrics, e),
+        };
+        if expected_version.is_some_and(|expected| expected != stored.version) {
+            return (StatusCode::PRECONDITION_FAILED, Json(None::<UserDetails>)).into_response();
+        }
+
+        let mut user = stored.value;
+        if let Some(name) = &payload.name {
+            user.update_name(name.trim());
+        }
+        if let Some(phone_number) = &phone_number
+            && let Err(e) = user.update_phone_number(phone_number.as_deref())
+        {
+            return error_response(&state.metrics, e);
+        }
+        // Merged into what was just read, so keys set by a concurrent patch are kept
+        if let Some(metadata) = &payload.metadata
+            && let Err(e) = user.merge_metadata(metadata)
+        {
+            return error_response(&state.metrics, e);
+        }
+
+        // Conditional on the version just read, so fields changed by a concurrent write aren't
+        // overwritten with stale values
+        match state.data_access.update(user.clone(), Some(stored.version)).await {
+            Ok(version) => {
+                invalidate_user(&state, &user);
+                return (
+                    StatusCode::OK,
+                    [(header::ETAG, preconditions::version_etag(version))],
+                    Json(Some(user.details().clone())),
+                )
+                    .into_response();
+            }
+            Err(ApplicationError::VersionMismatch)
+                if expected_version.is_none() && attempt < PATCH_ATTEMPTS =>
+            {
+                continue
+            }
+            Err(e) => return error_response(&state.metrics, e),
+        }
+    }
+}
+
+// The age in the response is worked out from the new date of birth. Retried like a patch when
+// another write lands in between, so a name changed meanwhile isn't overwritten.
+#[tracing::instrument(skip(state, claims, key, payload))]
+async fn update_birthday(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersWrite>,
+    claims: Option<Extension<SessionClaims>>,
+    Path(key): Path<String>,
+    Json(payload): Json<UpdateBirthdayRequest>,
+) -> Response {
+    let email_address = match resolve_email_address(&state, &key).await {
+        Ok(email_address) => email_address,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    if !may_manage(claims.as_deref(), &email_address) {
+        return error_response(&state.metrics, ApplicationError::Forbidden);
+    }
+    if let Some(date_of_birth) = payload.date_of_birth {
+        // By the same clock as sessions, so a test that moves it sees birthdays come round
+        let today = time::OffsetDateTime::from_unix_timestamp(state.sessions.clock().now() as i64)
+            .map(|now| now.date())
+            .unwrap_or_else(|_| time::OffsetDateTime::now_utc().date());
+        if let Err(e) = User::date_of_birth_is_valid(date_of_birth, today) {
+            return error_response(&state.metrics, e);
+        }
+    }
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+
+        let stored = match state
+            .data_access
+            .with_email_address_versioned(&email_address)
+            .await
+        {
+            Ok(stored) => stored,
+            Err(e) => return error_response(&state.metrics, e),
+        };
+        let mut user = stored.value;
+        user.update_date_of_birth(payload.date_of_birth);
+
+        match state.data_access.update(user.clone(), Some(stored.version)).await {
+            Ok(version) => {
+                invalidate_user(&state, &user);
+                return (
+                    StatusCode::OK,
+                    [(header::ETAG, preconditions::version_etag(version))],
+                    Json(Some(user.details().clone())),
+                )
+                    .into_response();
+            }
+            Err(ApplicationError::VersionMismatch) if attempt < PATCH_ATTEMPTS => continue,
+            Err(e) => return error_response(&state.metrics, e),
+        }
+    }
+}
+
+// Answers with the user as they are now, their version bumped by the change
+#[tracing::instrument(skip(state, claims, key, payload))]
+async fn update_username(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersWrite>,
+    claims: Option<Extension<SessionClaims>>,
+    Path(key): Path<String>,
+    Json(payload): Json<UpdateUsernameRequest>,
+) -> Response {
+    let user = match find_user(&state, &key).await {
+        Ok(user) => user,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    let email_address = user.email_address();
+    if !may_manage(claims.as_deref(), &email_address) {
+        return error_response(&state.metrics, ApplicationError::Forbidden);
+    }
+    if let Some(Err(e)) = payload.username.as_deref().map(User::username_is_valid) {
+        return error_response(&state.metrics, e);
+    }
+    let username = payload.username.as_deref().map(str::to_lowercase);
+
+    if let Err(e) = state
+        .data_access
+        .set_username(&email_address, username.as_deref())
+        .await
+    {
+        return error_response(&state.metrics, e);
+    }
+    // Under the old username too, which no longer finds them
+    invalidate_user(&state, &user);
+
+    match state.data_access.with_email_address_versioned(&email_address).await {
+        Ok(updated) => {
+            invalidate_user(&state, &updated.value);
+            (
+                StatusCode::OK,
+                [(header::ETAG, preconditions::version_etag(updated.version))],
+                Json(Some(updated.value.details().clone())),
+            )
+                .into_response()
+        }
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+#[tracing::instrument(skip(state, claims, key))]
+async fn get_tier(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersRead>,
+    claims: Option<Extension<SessionClaims>>,
+    Path(key): Path<String>,
+) -> Response {
+    let user = match find_user(&state, &key).await {
+        Ok(user) => user,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    if !may_manage(claims.as_deref(), &user.email_address()) {
+        return error_response(&state.metrics, ApplicationError::Forbidden);
+    }
+
+    (StatusCode::OK, Json(TierDetails::from(&user))).into_response()
+}
+
+// Tiers are paid for, so only an admin moves a user between them. Sessions the user already has
+// keep the scopes of their old tier until they sign in again.
+#[tracing::instrument(skip(state, key, payload))]
+async fn upgrade_tier(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersAdmin>,
+    Path(key): Path<String>,
+    Json(payload): Json<ChangeTierRequest>,
+) -> Response {
+    change_tier(&state, &key, payload.tier, std::cmp::Ordering::Greater).await
+}
+
+#[tracing::instrument(skip(state, key, payload))]
+async fn downgrade_tier(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersAdmin>,
+    Path(key): Path<String>,
+    Json(payload): Json<ChangeTierRequest>,
+) -> Response {
+    change_tier(&state, &key, payload.tier, std::cmp::Ordering::Less).await
+}
+
+// An upgrade has to end above the tier the user has and a downgrade below it, Standard being
+// below every tier. Retried like a patch when another write lands in between.
+async fn change_tier(
+    state: &AppState,
+    key: &str,
+    tier: Option<Tier>,
+    direction: std::cmp::Ordering,
+) -> Response {
+    let email_address = match resolve_email_address(state, key).await {
+        Ok(email_address) => email_address,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+
+        let stored = match state
+            .data_access
+            .with_email_address_versioned(&email_address)
+            .await
+        {
+            Ok(stored) => stored,
+            Err(e) => return error_response(&state.metrics, e),
+        };
+        let current = stored.value.tier();
+        if tier.cmp(&current) != direction {
+            let (constraint, relation) = match direction {
+                std::cmp::Ordering::Greater => ("above_current", "above"),
+                _ => ("below_current", "below"),
+            };
+            return error_response(
+                &state.metrics,
+                ApplicationError::ValidationFailed(vec![FieldViolation {
+                    field: "tier".to_string(),
+                    constraint: constraint.to_string(),
+                    message: format!(
+                        "must be {} the user's current tier, {}",
+                        relation,
+                        current.map_or("standard", |current| current.as_str())
+                    ),
+                }]),
+            );
+        }
+        let user = stored.value.with_tier(tier);
+
+        match state.data_access.update(user.clone(), Some(stored.version)).await {
+            Ok(version) => {
+                invalidate_user(state, &user);
+                invalidate_user_resource(state, &user, "/tier");
+                return (
+                    StatusCode::OK,
+                    [(header::ETAG, preconditions::version_etag(version))],
+                    Json(TierDetails::from(&user)),
+                )
+                    .into_response();
+            }
+            Err(ApplicationError::VersionMismatch) if attempt < PATCH_ATTEMPTS => continue,
+            Err(e) => return error_response(&state.metrics, e),
+        }
+    }
+}
+
+#[tracing::instrument(skip(state, claims, key, multipart))]
+async fn upload_avatar(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersWrite>,
+    claims: Option<Extension<SessionClaims>>,
+    Path(key): Path<String>,
+    mut multipart: Multipart,
+) -> Response {
+    let user = match find_user(&state, &key).await {
+        Ok(user) => user,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    if !may_manage(claims.as_deref(), &user.email_address()) {
+        return error_response(&state.metrics, ApplicationError::Forbidden);
+    }
+
+    // The picture is the `avatar` field, anything else in the form is ignored
+    let mut avatar = None;
+    loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) if field.name() == Some("avatar") => match field.bytes().await {
+                Ok(bytes) => avatar = Some(bytes),
+                Err(e) => return (e.status(), e.body_text()).into_response(),
+            },
+            Ok(Some(_)) => continue,
+            Ok(None) => break,
+            Err(e) => return (e.status(), e.body_text()).into_response(),
+        }
+    }
+    let Some(avatar) = avatar else {
+        return error_response(
+            &state.metrics,
+            ApplicationError::InvalidRequest("the form needs an avatar field".to_string()),
+        );
+    };
+    if avatar.len() > state.avatars.max_bytes() {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    }
+    if avatars::image_content_type(&avatar).is_none() {
+        return error_response(
+            &state.metrics,
+            ApplicationError::InvalidRequest(
+                "the avatar must be a PNG, JPEG, GIF or WebP image".to_string(),
+            ),
+        );
+    }
+
+    match state.avatars.store.put(&Avatars::key(user.id()), avatar).await {
+        Ok(()) => {
+            invalidate_user_resource(&state, &user, "/avatar");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+#[tracing::instrument(skip(state, key, headers))]
+async fn get_avatar(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersRead>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let user = match find_user(&state, &key).await {
+        Ok(user) => user,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+
+    let avatar = match state.avatars.store.get(&Avatars::key(user.id())).await {
+        Ok(Some(avatar)) => avatar,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => return error_response(&state.metrics, e),
+    };
+
+    // Private, in cookie mode avatars are only served to signed in users
+    let etag = cache::etag_for(&avatar);
+    let caching = [
+        (
+            header::CACHE_CONTROL,
+            format!("private, max-age={}", avatars::AVATAR_MAX_AGE_SECONDS),
+        ),
+        (header::ETAG, etag.to_str().unwrap_or_default().to_string()),
+    ];
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|if_none_match| *if_none_match == etag)
+    {
+        return (StatusCode::NOT_MODIFIED, caching).into_response();
+    }
+
+    let content_type = avatars::image_content_type(&avatar).unwrap_or("application/octet-stream");
+    (
+        StatusCode::OK,
+        caching,
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        ],
+        avatar,
+    )
+        .into_response()
+}
+
+// Only the user themselves, or an admin, may see or change their preferences
+#[tracing::instrument(skip(state, claims, key))]
+async fn get_preferences(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersRead>,
+    claims: Option<Extension<SessionClaims>>,
+    Path(key): Path<String>,
+) -> Response {
+    let email_address = match resolve_email_address(&state, &key).await {
+        Ok(email_address) => email_address,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    if !may_manage(claims.as_deref(), &email_address) {
+        return error_response(&state.metrics, ApplicationError::Forbidden);
+    }
+
+    match state.data_access.preferences(&email_address).await {
+        Ok(preferences) => (StatusCode::OK, Json(preferences)).into_response(),
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+#[tracing::instrument(skip(state, claims, key, payload))]
+async fn update_preferences(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersWrite>,
+    claims: Option<Extension<SessionClaims>>,
+    Path(key): Path<String>,
+    Json(payload): Json<UserPreferences>,
+) -> Response {
+    let user = match find_user(&state, &key).await {
+        Ok(user) => user,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    let email_address = user.email_address();
+    if !may_manage(claims.as_deref(), &email_address) {
+        return error_response(&state.metrics, ApplicationError::Forbidden);
+    }
+    if !payload.is_valid() {
+        return error_response(
+            &state.metrics,
+            ApplicationError::InvalidRequest(
+                "locale must be a language tag such as en or pt-BR".to_string(),
+            ),
+        );
+    }
+
+    match state
+        .data_access
+        .update_preferences(&email_address, &payload)
+        .await
+    {
+        Ok(()) => {
+            invalidate_user_resource(&state, &user, "/preferences");
+            (StatusCode::OK, Json(payload)).into_response()
+        }
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+#[tracing::instrument(skip(state, claims, key, headers))]
+async fn delete_user(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersWrite>,
+    claims: Option<Extension<SessionClaims>>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    // Read first, both the address and the id are needed to clear the cache afterwards
+    let user = match find_user(&state, &key).await {
+        Ok(user) => user,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    if !may_manage(claims.as_deref(), &user.email_address()) {
+        return error_response(&state.metrics, ApplicationError::Forbidden);
+    }
+    let expected_version = match preconditions::expected_version(&headers) {
+        Ok(expected_version) => expected_version,
+        Err(status) => return status.into_response(),
+    };
+
+    // Their sessions are ended first, as an erased user's are, a deleted user's cookie must not
+    // keep authenticating them
+    if let Err(e) = end_sessions(&state, &user.email_address()).await {
+        return error_response(&state.metrics, e);
+    }
+    // Only marks the user as deleted, the row is kept until an admin removes it for good
+    match state.data_access.soft_delete(&user.email_address(), expected_version).await {
+        Ok(()) => {
+            invalidate_user(&state, &user);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+// Removes the row, soft deleted or not, which also frees the email address to register again. Any
+// session still issued to the address is ended first, or it would authenticate whoever registers it
+// next.
+#[tracing::instrument(skip(state, admin, email_address))]
+async fn hard_delete_user(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersAdmin>,
+    CurrentUser { claims: admin, .. }: CurrentUser,
+    Path(email_address): Path<String>,
+) -> Response {
+    // A soft deleted user can only be found by their address, their cache entries were already
+    // cleared when they were soft deleted
+    let live_user = state.data_access.with_email_address(&email_address).await.ok();
+    if let Err(e) = end_sessions(&state, &email_address).await {
+        return error_response(&state.metrics, e);
+    }
+    match state.data_access.delete(&email_address, None).await {
+        Ok(()) => {
+            log::info!(target: "audit", "{} permanently deleted {}", admin.sub, email_address);
+            if let Some(user) = &live_user {
+                invalidate_user(&state, user);
+            }
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+// Folds one account into another, e.g. for someone who registered twice. The merged user's
+// sessions are ended first, as an erased user's are. Their login history is moved once the merge
+// has committed, failing to move it is logged, the devices are only flagged as new again.
+#[tracing::instrument(skip(state, admin, payload))]
+async fn merge_users(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersAdmin>,
+    CurrentUser { claims: admin, .. }: CurrentUser,
+    Json(payload): Json<MergeUsersRequest>,
+) -> Response {
+    let (kept, merged) = (&payload.keep_email_address, &payload.merge_email_address);
+    if kept == merged {
+        return error_response(
+            &state.metrics,
+            ApplicationError::ValidationFailed(vec![FieldViolation {
+                field: "mergeEmailAddress".to_string(),
+                constraint: "same_user".to_string(),
+                message: "a user can't be merged into themselves".to_string(),
+            }]),
+        );
+    }
+    let users = async {
+        let kept_user = state.data_access.with_email_address(kept).await?;
+        let merged_user = state.data_access.with_email_address(merged).await?;
+        Ok::<_, ApplicationError>((kept_user, merged_user))
+    };
+    let (kept_user, merged_user) = match users.await {
+        Ok(users) => users,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    let requested_by = admin.impersonated_by.clone().unwrap_or_else(|| admin.sub.clone());
+
+    let result = async {
+        end_sessions(&state, merged).await?;
+        state.data_access.merge(kept, merged, Some(&requested_by)).await
+    }
+    .await;
+    if let Err(e) = result {
+        return error_response(&state.metrics, e);
+    }
+
+    log::info!(
+        target: "audit",
+        "{} merged user {} into {}",
+        requested_by,
+        merged_user.id(),
+        kept_user.id()
+    );
+    if let Some(login_history) = &state.login_history
+        && let Err(e) = login_history.merge(merged, kept).await
+    {
+        log::error!("Failed to move the login history of merged user {}: {:?}", merged, e);
+    }
+    invalidate_user(&state, &kept_user);
+    invalidate_user(&state, &merged_user);
+
+    match state.data_access.with_email_address(kept).await {
+        Ok(user) => (StatusCode::OK, Json(user.details().clone())).into_response(),
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+// Right to erasure, for users who want everything identifying them gone rather than their account
+// closed. Soft deleted users can be erased too. Their sessions and login history are removed first,
+// once the address is replaced there is nothing left to find them by.
+#[tracing::instrument(skip(state, claims, email_address))]
+async fn erase_user(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersWrite>,
+    claims: Option<Extension<SessionClaims>>,
+    Path(email_address): Path<String>,
+) -> Response {
+    if !may_manage(claims.as_deref(), &email_address) {
+        return error_response(&state.metrics, ApplicationError::Forbidden);
+    }
+    // Whoever acted on someone else's account, the audit record holds nothing about users who
+    // erased their own
+    let requested_by = claims.as_deref().and_then(|claims| {
+        claims
+            .impersonated_by
+            .clone()
+            .or_else(|| (claims.sub != email_address).then(|| claims.sub.clone()))
+    });
+
+    let live_user = state.data_access.with_email_address(&email_address).await.ok();
+    let result = async {
+        end_sessions(&state, &email_address).await?;
+        if let Some(login_history) = &state.login_history {
+            login_history.forget(&email_address).await?;
+        }
+
+        state
+            .data_access
+            .erase(&email_address, requested_by.as_deref())
+            .await
+    }
+    .await;
+
+    match result {
+        Ok(id) => {
+            log::info!(
+                target: "audit",
+                "{} erased user {}",
+                requested_by.as_deref().unwrap_or("the user"),
+                id
+            );
+            if let Some(user) = &live_user {
+                invalidate_user(&state, user);
+            }
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+// Revokes every session issued to the address
+async fn end_sessions(state: &AppState, email_address: &str) -> Result<(), ApplicationError> {
+    for session in state.active_sessions.for_user(email_address).await? {
+        state
+            .revocations
+            .revoke(&session.token_id, session.expires_at)
+            .await?;
+        state
+            .active_sessions
+            .remove(email_address, &session.token_id)
+            .await?;
+    }
+
+    Ok(())
+}
+
+// The user's secondary addresses, verified or not. Only the user themselves, or an admin, may see
+// or change them.
+#[tracing::instrument(skip(state, claims, key))]
+async fn list_email_aliases(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersRead>,
+    claims: Option<Extension<SessionClaims>>,
+    Path(key): Path<String>,
+) -> Response {
+    let email_address = match resolve_email_address(&state, &key).await {
+        Ok(email_address) => email_address,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    if !may_manage(claims.as_deref(), &email_address) {
+        return error_response(&state.metrics, ApplicationError::Forbidden);
+    }
+
+    match state.data_access.email_aliases(&email_address).await {
+        Ok(aliases) => (StatusCode::OK, Json(aliases)).into_response(),
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+// Sends a link to the address that verifies it, until it is followed the address doesn't find the
+// user. Adding an address that is waiting to be verified sends a new link.
+#[tracing::instrument(skip(state, claims, key, payload))]
+async fn add_email_alias(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersWrite>,
+    claims: Option<Extension<SessionClaims>>,
+    Path(key): Path<String>,
+    Json(payload): Json<AddEmailAliasRequest>,
+) -> Response {
+    let user = match find_user(&state, &key).await {
+        Ok(user) => user,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    let email_address = user.email_address();
+    if !may_manage(claims.as_deref(), &email_address) {
+        return error_response(&state.metrics, ApplicationError::Forbidden);
+    }
+    if let Err(e) = User::email_address_is_valid(&payload.email_address) {
+        return error_response(&state.metrics, e);
+    }
+    // Adding one the user already has again only resends its link
+    let aliases = match state.data_access.email_aliases(&email_address).await {
+        Ok(aliases) => aliases,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    let max_email_aliases = user.capabilities().max_email_aliases;
+    if aliases.len() >= max_email_aliases
+        && !aliases.iter().any(|alias| alias.email_address == payload.email_address)
+    {
+        return error_response(
+            &state.metrics,
+            ApplicationError::ValidationFailed(vec![FieldViolation {
+                field: "emailAddress".to_string(),
+                constraint: "max_aliases".to_string(),
+                message: format!("the user's tier allows {} addresses", max_email_aliases),
+            }]),
+        );
+    }
+
+    let (token, token_hash) = email_aliases::verification_token();
+    let result = async {
+        state
+            .data_access
+            .add_email_alias(&email_address, &payload.email_address, &token_hash)
+            .await?;
+        state
+            .magic_links
+            .sender
+            .send(
+                &payload.email_address,
+                &state.magic_links.verification_link(&token),
+            )
+            .await
+    }
+    .await;
+
+    match result {
+        Ok(()) => {
+            invalidate_user_resource(&state, &user, "/emails");
+            StatusCode::ACCEPTED.into_response()
+        }
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+#[tracing::instrument(skip(state, token))]
+async fn verify_email_alias(
+    State(state): State<Arc<AppState>>,
+    Path(token): Path<String>,
+) -> Response {
+    let added_after = state
+        .sessions
+        .clock()
+        .now()
+        .saturating_sub(email_aliases::VERIFICATION_TTL_SECONDS);
+
+    let alias = match state
+        .data_access
+        .verify_email_alias(&email_aliases::token_hash(&token), added_after)
+        .await
+    {
+        Ok(Some(alias)) => alias,
+        Ok(None) => {
+            return error_response(
+                &state.metrics,
+                ApplicationError::InvalidRequest(
+                    "the link is invalid, has expired or has already been used".to_string(),
+                ),
+            )
+        }
+        Err(e) => return error_response(&state.metrics, e),
+    };
+
+    // A lookup by the address may have been cached as not found
+    if let Some(cache) = &state.response_cache {
+        cache.invalidate_path(&format!("/users/{}", alias));
+    }
+    if let Ok(user) = find_user(&state, &alias).await {
+        invalidate_user_resource(&state, &user, "/emails");
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+#[tracing::instrument(skip(state, claims, key, alias))]
+async fn remove_email_alias(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersWrite>,
+    claims: Option<Extension<SessionClaims>>,
+    Path((key, alias)): Path<(String, String)>,
+) -> Response {
+    let user = match find_user(&state, &key).await {
+        Ok(user) => user,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    let email_address = user.email_address();
+    if !may_manage(claims.as_deref(), &email_address) {
+        return error_response(&state.metrics, ApplicationError::Forbidden);
+    }
+
+    match state.data_access.remove_email_alias(&email_address, &alias).await {
+        Ok(()) => {
+            invalidate_user_resource(&state, &user, "/emails");
+            if let Some(cache) = &state.response_cache {
+                cache.invalidate_path(&format!("/users/{}", alias));
+            }
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+// Sessions, login history and sign-in links are kept by the address they were issued to, so the
+// user's sessions end and they sign in again with the new primary address. The old one still
+// finds them as an alias.
+#[tracing::instrument(skip(state, claims, key, alias))]
+async fn set_primary_email_address(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersWrite>,
+    claims: Option<Extension<SessionClaims>>,
+    Path((key, alias)): Path<(String, String)>,
+) -> Response {
+    let user = match find_user(&state, &key).await {
+        Ok(user) => user,
+        Err(e) => return error_response(&state.metrics, e),
+    };
+    let email_address = user.email_address();
+    if !may_manage(claims.as_deref(), &email_address) {
+        return error_response(&state.metrics, ApplicationError::Forbidden);
+    }
+
+    if let Err(e) = state
+        .data_access
+        .set_primary_email_address(&email_address, &alias)
+        .await
+    {
+        return error_response(&state.metrics, e);
+    }
+    invalidate_user(&state, &user);
+    invalidate_user_resource(&state, &user, "/emails");
+    if let Some(cache) = &state.response_cache {
+        cache.invalidate_path(&format!("/users/{}", alias));
+    }
+    if let Err(e) = end_sessions(&state, &email_address).await {
+        log::error!("Failed to end the sessions of {}: {:?}", email_address, e);
+    }
+
+    match state.data_access.with_email_address(&alias).await {
+        Ok(user) => (StatusCode::OK, Json(user.details().clone())).into_response(),
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+// The user's most recent writes, oldest first. Only admins may read it, users can't see their own.
+// Takes the user's id as well, which finds the trail of a user who was deleted or erased.
+#[tracing::instrument(skip(state, key))]
+async fn get_audit_trail(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersAdmin>,
+    Path(key): Path<String>,
+) -> Response {
+    let Some(audit_log) = &state.audit_log else {
+        return error_response(&state.metrics, ApplicationError::UserDoesNotExist);
+    };
+    let user_id = match Uuid::parse_str(&key) {
+        Ok(id) => id,
+        Err(_) => match state.data_access.with_email_address(&key).await {
+            Ok(user) => user.id(),
+            Err(e) => return error_response(&state.metrics, e),
+        },
+    };
+
+    match audit_log.for_user(user_id, AUDIT_TRAIL_LIMIT).await {
+        Ok(entries) => (StatusCode::OK, Json(entries)).into_response(),
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+// Users whose name or email address match every word of `q`, best match first, for admins
+// looking someone up by what they remember of them. Pages by offset, ranks aren't stable enough
+// between writes to seek past.
+#[tracing::instrument(skip(state, query, params))]
+async fn search_users(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersAdmin>,
+    Query(query): Query<SearchUsersQuery>,
+    ListQuery { params, .. }: ListQuery<UserSearchListing>,
+) -> Response {
+    let Some(query) = query.q.filter(|q| !q.trim().is_empty()) else {
+        let missing = ApplicationError::InvalidRequest("q must not be empty".to_string());
+        return error_response(&state.metrics, missing);
+    };
+
+    match state
+        .data_access
+        .search_ranked(&query, params.offset, params.limit)
+        .await
+    {
+        Ok(users) => Json(
+            users
+                .into_iter()
+                .map(|(user, rank)| SearchedUserDetails {
+                    details: user.details().clone(),
+                    rank,
+                })
+                .collect::<Vec<SearchedUserDetails>>(),
+        )
+        .into_response(),
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+// The same counts as the `http_open_connections` and `http_requests_in_flight` gauges, this
+// request included, with the number of live users
+async fn admin_stats(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersAdmin>,
+) -> Response {
+    match state.data_access.count().await {
+        Ok(users) => Json(AdminStatsResponse {
+            connections: state.connections.current(),
+            users,
+        })
+        .into_response(),
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+#[tracing::instrument(skip(state, claims, email_address))]
+async fn list_sessions(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersRead>,
+    Extension(claims): Extension<SessionClaims>,
+    Path(email_address): Path<String>,
+    ListQuery { params, .. }: ListQuery<SessionListing>,
+) -> Response {
+    if !may_manage(Some(&claims), &email_address) {
+        return error_response(&state.metrics, ApplicationError::Forbidden);
+    }
+
+    match state.active_sessions.for_user(&email_address).await {
+        Ok(mut sessions) => {
+            sessions.retain(|session| params.matches(session.user_agent.as_deref()));
+            match params.sort {
+                "lastSeenAt" => sessions.sort_by_key(|session| session.last_seen_at),
+                "-createdAt" => sessions.sort_by_key(|session| std::cmp::Reverse(session.created_at)),
+                "createdAt" => sessions.sort_by_key(|session| session.created_at),
+                _ => sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen_at)),
+            }
+
+            let sessions: Vec<SessionDetails> = params
+                .page(sessions)
+                .into_iter()
+                .map(|session| SessionDetails {
+                    current: session.token_id == claims.jti,
+                    id: session.token_id,
+                    user_agent: session.user_agent,
+                    last_seen_ip: session.last_seen_ip,
+                    created_at: session.created_at,
+                    last_seen_at: session.last_seen_at,
+                    expires_at: session.expires_at,
+                    impersonated_by: session.impersonated_by,
+                })
+                .collect();
+
+            (StatusCode::OK, Json(sessions)).into_response()
+        }
+        Err(e) => error_response(&state.metrics, e),
+    }
+}
+
+#[tracing::instrument(skip(state, claims, email_address, session_id))]
+async fn revoke_session(
+    State(state): State<Arc<AppState>>,
+    _: RequireScope<UsersWrite>,
+    Extension(claims): Extension<SessionClaims>,
+    Path((email_address, session_id)): Path<(String, String)>,
+) -> StatusCode {
+    if !may_manage(Some(&claims), &email_address) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    let result = async {
+        let Some(session) = state
+            .active_sessions
+            .remove(&email_address, &session_id)
+            .await?
+        else {
+            return Ok(StatusCode::NOT_FOUND);
+        };
+
+        // Removing it only hides it from the listing, the revocation is what the session check sees
+        state
+            .revocations
+            .revoke(&session.token_id, session.expires_at)
+            .await?;
+        log::info!(
+            target: "audit",
+            "{} revoked session {} of {}",
+            claims.sub,
+            session.token_id,
+            email_address
+        );
+
+        Ok::<_, ApplicationError>(StatusCode::NO_CONTENT)
+    }
+    .await;
+
+    result.unwrap_or_else(|e| {
+        log::error!("{:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+async fn dev_outbox(State(state): State<Arc<AppState>>) -> Json<Vec<CapturedNotification>> {
+    Json(
+        state
+            .sandbox
+            .as_ref()
+            .map(|sandbox| sandbox.list())
+            .unwrap_or_default(),
+    )
+}
+
+async fn advance_clock(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<AdvanceClockRequest>,
+) -> (StatusCode, Json<Option<ClockResponse>>) {
+    let clock = state.sessions.clock();
+    if !clock.advance(payload.seconds) {
+        return (StatusCode::NOT_FOUND, Json(None));
+    }
+    log::warn!("Clock advanced by {} seconds", payload.seconds);
+
+    (
+        StatusCode::OK,
+        Json(Some(ClockResponse {
+            now: clock.now(),
+            offset_seconds: clock.offset_seconds(),
+        })),
+    )
+}
+
+// Ready once the store can be reached, so a load balancer only sends traffic to instances that can
+// answer it. A 503 with the error envelope otherwise, whatever the store failed with.
+async fn ready(State(state): State<Arc<AppState>>) -> Response {
+    match state.data_access.health_check().await {
+        Ok(()) => (StatusCode::OK, "ready").into_response(),
+        Err(e) => {
+            let mut response = error_response(&state.metrics, e);
+            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            response
+        }
+    }
+}
+
+async fn metrics(
+    State(state): State<Arc<AppState>>,
+) -> ([(header::HeaderName, &'static str); 1], String) {
+    state.connections.record(&state.metrics);
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render_prometheus(),
+    )
+}
+
+// Create a Resource that captures information about the entity for which telemetry is recorded.
+fn resource() -> Resource {
+    Resource::builder()
+        .with_schema_url(
+            [
+                KeyValue::new(SERVICE_NAME, "users-service"),
+                KeyValue::new(SERVICE_VERSION, "1.0.0"),
+                KeyValue::new(DEPLOYMENT_ENVIRONMENT_NAME, "develop"),
+            ],
+            SCHEMA_URL,
+        )
+        .build()
+}
+
+// Construct TracerProvider for OpenTelemetryLayer
+fn init_tracer_provider(config: Option<&Config>) -> Result<SdkTracerProvider, ApplicationError> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .build()
+        .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+
+    Ok(SdkTracerProvider::builder()
+        // Customize sampling strategy
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            1.0,
+        ))))
+        // `telemetry.id_generator = "xray"` when the traces are exported to AWS X-Ray
+        .with_id_generator(telemetry::id_generator_from_config(config))
+        .with_resource(resource())
+        .with_batch_exporter(exporter)
+        .build())
+}
+
+// Initialize tracing-subscriber and return the RuntimeGuard that reports and flushes on shutdown.
+// The telemetry settings are read here, before `start_api` loads the configuration, and the
+// defaults are used if it can't be loaded so the error is still traced.
+pub fn init_tracing_subscriber() -> Result<RuntimeGuard, ApplicationError> {
+    let config = Config::get_configuration().ok();
+    let tracer_provider = init_tracer_provider(config.as_ref())?;
+    opentelemetry::global::set_text_map_propagator(telemetry::propagator_from_config(
+        config.as_ref(),
+    ));
+
+    let tracer = tracer_provider.tracer("users-service");
+
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::filter::LevelFilter::from_level(
+            Level::INFO,
+        ))
+        .with(OpenTelemetryLayer::new(tracer))
+        .with(
+            config
+                .as_ref()
+                .is_some_and(Config::telemetry_server_timing)
+                .then(ServerTimingLayer::default),
+        )
+        .init();
+
+    Ok(RuntimeGuard::new(tracer_provider))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{InMemoryRevocations, LogMagicLinkSender, MagicLinkSender, UsersPremium};
+    use crate::brute_force::{BruteForceSettings, InMemoryLoginFailures};
+    use crate::core::{LockoutResponse, Role};
+    use axum::extract::FromRequestParts;
+    use crate::core::{ApplicationError, User};
+    use crate::blobs::InMemoryBlobStore;
+    use crate::data_access::{
+        DeviceSighting, InMemoryActiveSessions, InMemoryLoginHistory, InMemoryMagicLinkTokens,
+        LoginRecord,
+    };
+    use crate::registration::AllowAllRegistrations;
+    use mockall::mock;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    fn test_state(data_access: impl DataAccess + 'static) -> AppState {
+        AppState {
+            data_access: Arc::new(data_access),
+            sessions: SessionManager::new(AuthMode::None, "test-signing-key", 3600, false),
+            revocations: Arc::new(InMemoryRevocations::default()),
+            active_sessions: Arc::new(InMemoryActiveSessions::default()),
+            magic_links: test_magic_links(Arc::new(LogMagicLinkSender)),
+            registration_guard: Arc::new(AllowAllRegistrations),
+            brute_force: None,
+            response_cache: None,
+            cors: None,
+            user_reads: None,
+            route_toggles: None,
+            sandbox: None,
+            login_history: None,
+            audit_log: None,
+            avatars: Avatars::new(Arc::new(InMemoryBlobStore::default()), 1024),
+            connections: Arc::new(ConnectionStats::default()),
+            metrics: Arc::new(Metrics::default()),
+        }
+    }
+
+    fn test_magic_links(sender: Arc<dyn MagicLinkSender>) -> MagicLinks {
+        MagicLinks::new(
+            Arc::new(InMemoryMagicLinkTokens::default()),
+            sender,
+            "http://localhost:3000",
+            900,
+        )
+    }
+
+    // Captures the links that would have been emailed
+    #[derive(Default)]
+    struct RecordingMagicLinkSender {
+        links: Mutex<Vec<String>>,
+    }
+
+    #[async_trait::async_trait]
+    impl MagicLinkSender for RecordingMagicLinkSender {
+        async fn send(&self, _email_address: &str, link: &str) -> std::result::Result<(), ApplicationError> {
+            self.links.lock().unwrap().push(link.to_string());
+            Ok(())
+        }
+    }
+
     // Create a mock implementation for testing
     struct ManualMockDataAccess {
         // You can store expected results or track calls
         users: HashMap<String, User>,
     }
 
-    impl ManualMockDataAccess {
-        pub fn new() -> Self {
-            Self {
-                users: HashMap::new(),
+    impl ManualMockDataAccess {
+        pub fn new() -> Self {
+            Self {
+                users: HashMap::new(),
+            }
+        }
+    }
+
+    mock! {
+        DataAccess{}
+        #[async_trait::async_trait]
+        impl DataAccess for DataAccess {
+            async fn with_email_address(&self, email_address: &str) -> std::result::Result<User, ApplicationError>;
+            async fn list(&self, offset: i64, limit: i64) -> std::result::Result<Vec<User>, ApplicationError>;
+            async fn store(&self, user: User) -> std::result::Result<(), ApplicationError>;
+            async fn exists(&self, email_address: &str) -> std::result::Result<bool, ApplicationError>;
+            async fn health_check(&self) -> std::result::Result<(), ApplicationError>;
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl DataAccess for ManualMockDataAccess {
+        async fn with_email_address(
+            &self,
+            email_address: &str,
+        ) -> std::result::Result<User, ApplicationError> {
+            if let Some(user) = self.users.get(email_address) {
+                Ok(user.clone())
+            } else {
+                Err(ApplicationError::UserDoesNotExist)
+            }
+        }
+
+        async fn list(
+            &self,
+            offset: i64,
+            limit: i64,
+        ) -> std::result::Result<Vec<User>, ApplicationError> {
+            Ok(self
+                .users
+                .values()
+                .skip(offset as usize)
+                .take(limit as usize)
+                .cloned()
+                .collect())
+        }
+
+        async fn store(&self, _user: User) -> std::result::Result<(), ApplicationError> {
+            // Simulate storing the user
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_user_with_manual_mock() {
+        let mock_data_access = ManualMockDataAccess::new();
+        let shared_state = Arc::new(test_state(mock_data_access));
+
+        let response = register_user(
+            State(shared_state),
+            HeaderMap::new(),
+            Json(RegisterUserRequest {
+                email_address: "test@test.com".to_string(),
+                name: "Test User".to_string(),
+                password: "Purple-Otter-Canoe-42".to_string(),
+                phone_number: None,
+            }),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_register_user_with_a_weak_password_should_be_rejected() {
+        let mut mock_data_access = MockDataAccess::new();
+        mock_data_access.expect_exists().returning(|_| Ok(false));
+        mock_data_access.expect_store().never();
+        let shared_state = Arc::new(test_state(mock_data_access));
+
+        let response = register_user(
+            State(shared_state),
+            HeaderMap::new(),
+            Json(RegisterUserRequest {
+                email_address: "test@test.com".to_string(),
+                name: "Test User".to_string(),
+                password: "Testing!23".to_string(),
+                phone_number: None,
+            }),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_register_user_with_invalid_fields_should_list_each_of_them() {
+        let mut mock_data_access = MockDataAccess::new();
+        mock_data_access.expect_exists().returning(|_| Ok(false));
+        mock_data_access.expect_store().never();
+        let shared_state = Arc::new(test_state(mock_data_access));
+
+        let response = register_user(
+            State(shared_state),
+            HeaderMap::new(),
+            Json(RegisterUserRequest {
+                email_address: "not-an-email".to_string(),
+                name: " ".to_string(),
+                password: "Correct-Horse-42-battery".to_string(),
+                phone_number: None,
+            }),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["code"], "VALIDATION_FAILED");
+        assert_eq!(
+            error["fields"],
+            serde_json::json!([
+                {"field": "emailAddress", "constraint": "email", "message": "must be a valid email address"},
+                {"field": "name", "constraint": "required", "message": "name must not be empty"},
+            ])
+        );
+    }
+
+    struct RejectAllRegistrations;
+
+    #[async_trait::async_trait]
+    impl RegistrationGuard for RejectAllRegistrations {
+        async fn check(
+            &self,
+            _attempt: &RegistrationAttempt<'_>,
+        ) -> std::result::Result<(), ApplicationError> {
+            Err(ApplicationError::RegistrationRejected("challenge failed".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_user_rejected_by_the_registration_guard_should_not_be_stored() {
+        let mut mock_data_access = MockDataAccess::new();
+        mock_data_access.expect_store().never();
+        let shared_state = Arc::new(AppState {
+            registration_guard: Arc::new(RejectAllRegistrations),
+            ..test_state(mock_data_access)
+        });
+
+        let response = register_user(
+            State(shared_state),
+            HeaderMap::new(),
+            Json(RegisterUserRequest {
+                email_address: "test@test.com".to_string(),
+                name: "Test User".to_string(),
+                password: "Purple-Otter-Canoe-42".to_string(),
+                phone_number: None,
+            }),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_register_user_that_already_exists_should_conflict() {
+        let mut mock_data_access = MockDataAccess::new();
+        mock_data_access.expect_exists().returning(|_| Ok(false));
+        mock_data_access
+            .expect_store()
+            .return_once(move |_| Err(ApplicationError::UserAlreadyExists));
+        let shared_state = Arc::new(test_state(mock_data_access));
+
+        let response = register_user(
+            State(shared_state),
+            HeaderMap::new(),
+            Json(RegisterUserRequest {
+                email_address: "test@test.com".to_string(),
+                name: "Test User".to_string(),
+                password: "Purple-Otter-Canoe-42".to_string(),
+                phone_number: None,
+            }),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "USER_ALREADY_EXISTS");
+    }
+
+    #[tokio::test]
+    async fn test_register_user_whose_address_is_taken_should_conflict_without_storing() {
+        let mut mock_data_access = MockDataAccess::new();
+        mock_data_access
+            .expect_exists()
+            .withf(|email_address| email_address == "test@test.com")
+            .returning(|_| Ok(true));
+        mock_data_access.expect_store().never();
+        let shared_state = Arc::new(test_state(mock_data_access));
+
+        let response = register_user(
+            State(shared_state.clone()),
+            HeaderMap::new(),
+            Json(RegisterUserRequest {
+                email_address: "test@test.com".to_string(),
+                name: "Test User".to_string(),
+                password: "Purple-Otter-Canoe-42".to_string(),
+                phone_number: None,
+            }),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(shared_state.metrics.counter(USER_REGISTRATION_FAILED_TOTAL), 1);
+    }
+
+    #[tokio::test]
+    async fn test_register_user_with_mock_all() {
+        let mut mock_data_access = MockDataAccess::new();
+        mock_data_access.expect_exists().returning(|_| Ok(false));
+        mock_data_access
+            .expect_store()
+            .withf(|user| {
+                user.email_address() == "test@test.com"
+                    && user.phone_number().as_deref() == Some("+442079460958")
+            })
+            .return_once(move |_| Ok(()));
+        let shared_state = Arc::new(test_state(mock_data_access));
+
+        let response = register_user(
+            State(shared_state),
+            HeaderMap::new(),
+            Json(RegisterUserRequest {
+                email_address: "test@test.com".to_string(),
+                name: "Test User".to_string(),
+                password: "Purple-Otter-Canoe-42".to_string(),
+                phone_number: Some("+44 20 7946 0958".to_string()),
+            }),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_magic_link_should_log_the_user_in_once() {
+        let mut manual_mock_data_access = ManualMockDataAccess::new();
+        manual_mock_data_access.users.insert(
+            "test@test.com".to_string(),
+            User::from("test@test.com", "Test User", "hashed"),
+        );
+        let sender = Arc::new(RecordingMagicLinkSender::default());
+        let shared_state = Arc::new(AppState {
+            magic_links: test_magic_links(sender.clone()),
+            ..test_state(manual_mock_data_access)
+        });
+
+        let status = request_magic_link(
+            State(shared_state.clone()),
+            Json(MagicLinkRequest {
+                email_address: "test@test.com".to_string(),
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let link = sender.links.lock().unwrap().pop().unwrap();
+        let token = link
+            .strip_prefix("http://localhost:3000/login/magic/")
+            .unwrap()
+            .to_string();
+
+        let response = complete_magic_link(
+            State(shared_state.clone()),
+            HeaderMap::new(),
+            CookieJar::new(),
+            Path(token.clone()),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response =
+            complete_magic_link(State(shared_state), HeaderMap::new(), CookieJar::new(), Path(token))
+                .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "INVALID_SESSION");
+    }
+
+    #[tokio::test]
+    async fn test_magic_link_for_an_unknown_user_should_not_be_sent() {
+        let sender = Arc::new(RecordingMagicLinkSender::default());
+        let shared_state = Arc::new(AppState {
+            magic_links: test_magic_links(sender.clone()),
+            ..test_state(ManualMockDataAccess::new())
+        });
+
+        let status = request_magic_link(
+            State(shared_state),
+            Json(MagicLinkRequest {
+                email_address: "unknown@test.com".to_string(),
+            }),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(sender.links.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_require_scope_should_forbid_scopes_the_session_was_not_granted() {
+        let shared_state = Arc::new(AppState {
+            sessions: SessionManager::new(AuthMode::Cookie, "test-signing-key", 3600, false),
+            ..test_state(ManualMockDataAccess::new())
+        });
+        let (token, _) = shared_state
+            .sessions
+            .issue(&User::from("test@test.com", "Test User", "hashed"))
+            .unwrap();
+        let (mut parts, _) = axum::http::Request::builder()
+            .header(header::COOKIE, format!("{}={}", auth::SESSION_COOKIE_NAME, token))
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        let read = RequireScope::<UsersRead>::from_request_parts(&mut parts, &shared_state).await;
+        let premium =
+            RequireScope::<UsersPremium>::from_request_parts(&mut parts, &shared_state).await;
+
+        assert!(read.is_ok());
+        assert_eq!(premium.err(), Some(StatusCode::FORBIDDEN));
+    }
+
+    #[tokio::test]
+    async fn test_login_after_a_burst_of_failures_should_cool_down() {
+        let mut manual_mock_data_access = ManualMockDataAccess::new();
+        manual_mock_data_access.users.insert(
+            "test@test.com".to_string(),
+            User::new("test@test.com", "Test User", "Correct-Horse-Battery-42").unwrap(),
+        );
+        let shared_state = Arc::new(AppState {
+            brute_force: Some(BruteForceDetector::new(
+                Arc::new(InMemoryLoginFailures::default()),
+                BruteForceSettings {
+                    window: Duration::from_secs(60),
+                    max_failures: 1,
+                    cooldown: Duration::from_secs(60),
+                    response: LockoutResponse::Cooldown,
+                },
+            )),
+            ..test_state(manual_mock_data_access)
+        });
+        let attempt = |email_address: &str, password: &str| {
+            login(
+                State(shared_state.clone()),
+                HeaderMap::new(),
+                CookieJar::new(),
+                Json(LoginRequest {
+                    email_address: email_address.to_string(),
+                    password: password.to_string(),
+                }),
+            )
+        };
+
+        assert_eq!(attempt("test@test.com", "wrong").await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(attempt("other@test.com", "wrong").await.status(), StatusCode::NOT_FOUND);
+
+        let response = attempt("test@test.com", "Correct-Horse-Battery-42").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+        assert_eq!(shared_state.metrics.counter(BRUTE_FORCE_DETECTED_TOTAL), 1);
+
+        let metrics = shared_state.metrics.snapshot();
+        for reason in ["bad_password", "unknown_user", "locked_account"] {
+            let failed =
+                metrics.counter_with_labels(USER_LOGIN_FAILED_TOTAL, &[("reason", reason)]);
+            assert_eq!(failed, 1, "{}", reason);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_current_user_should_load_the_user_the_session_belongs_to() {
+        let mut manual_mock_data_access = ManualMockDataAccess::new();
+        manual_mock_data_access.users.insert(
+            "test@test.com".to_string(),
+            User::from("test@test.com", "Test User", "hashed"),
+        );
+        let shared_state = Arc::new(AppState {
+            sessions: SessionManager::new(AuthMode::Cookie, "test-signing-key", 3600, false),
+            ..test_state(manual_mock_data_access)
+        });
+        let request_for = |user: User| {
+            let (token, _) = shared_state.sessions.issue(&user).unwrap();
+            axum::http::Request::builder()
+                .header(header::COOKIE, format!("{}={}", auth::SESSION_COOKIE_NAME, token))
+                .body(())
+                .unwrap()
+                .into_parts()
+                .0
+        };
+
+        let mut parts = request_for(User::from("test@test.com", "Test User", "hashed"));
+        let current_user = CurrentUser::from_request_parts(&mut parts, &shared_state)
+            .await
+            .unwrap();
+        assert_eq!(current_user.user.name(), "Test User");
+
+        let mut parts = request_for(User::from("deleted@test.com", "Deleted", "hashed"));
+        let rejection = CurrentUser::from_request_parts(&mut parts, &shared_state).await;
+        assert_eq!(rejection.err(), Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[tokio::test]
+    async fn test_update_user_should_require_the_current_version_in_if_match() {
+        let data_access = InMemoryUsers::default();
+        data_access
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        let shared_state = Arc::new(test_state(data_access));
+        let update = |if_match: Option<&'static str>| {
+            let shared_state = shared_state.clone();
+            async move {
+                let mut headers = HeaderMap::new();
+                if let Some(if_match) = if_match {
+                    headers.insert(header::IF_MATCH, header::HeaderValue::from_static(if_match));
+                }
+                let mut parts = axum::http::Request::new(()).into_parts().0;
+                let scope = RequireScope::<UsersWrite>::from_request_parts(&mut parts, &shared_state)
+                    .await
+                    .unwrap();
+
+                update_user(
+                    State(shared_state),
+                    scope,
+                    None,
+                    Path("test@test.com".to_string()),
+                    headers,
+                    Json(UpdateUserRequest {
+                        name: "Renamed".to_string(),
+                    }),
+                )
+                .await
+            }
+        };
+
+        assert_eq!(update(None).await.status(), StatusCode::PRECONDITION_REQUIRED);
+
+        let updated = update(Some(r#""v1""#)).await;
+        assert_eq!(updated.status(), StatusCode::OK);
+        assert_eq!(updated.headers()[header::ETAG], r#""v2""#);
+
+        assert_eq!(update(Some(r#""v1""#)).await.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(
+            shared_state
+                .data_access
+                .with_email_address("test@test.com")
+                .await
+                .unwrap()
+                .name(),
+            "Renamed"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_revoking_a_listed_session_should_reject_it_and_only_its_owner_may() {
+        let shared_state = Arc::new(AppState {
+            sessions: SessionManager::new(AuthMode::Cookie, "test-signing-key", 3600, false),
+            ..test_state(ManualMockDataAccess::new())
+        });
+        let sign_in = |email_address: &str| {
+            let shared_state = shared_state.clone();
+            let user = User::from(email_address, "Test User", "hashed");
+            async move {
+                let (token, claims) = shared_state.sessions.issue(&user).unwrap();
+                auth::record_session(&shared_state, &claims, &HeaderMap::new()).await;
+                let (mut parts, _) = axum::http::Request::builder()
+                    .header(header::COOKIE, format!("{}={}", auth::SESSION_COOKIE_NAME, token))
+                    .body(())
+                    .unwrap()
+                    .into_parts();
+                let scope = RequireScope::<UsersWrite>::from_request_parts(&mut parts, &shared_state)
+                    .await
+                    .unwrap();
+                (parts, claims, scope)
+            }
+        };
+        let (_, laptop, scope) = sign_in("test@test.com").await;
+        let (mut phone_parts, phone, _) = sign_in("test@test.com").await;
+        let (_, other, other_scope) = sign_in("other@test.com").await;
+
+        let forbidden = revoke_session(
+            State(shared_state.clone()),
+            other_scope,
+            Extension(other),
+            Path(("test@test.com".to_string(), phone.jti.clone())),
+        )
+        .await;
+        let revoked = revoke_session(
+            State(shared_state.clone()),
+            scope,
+            Extension(laptop),
+            Path(("test@test.com".to_string(), phone.jti.clone())),
+        )
+        .await;
+
+        assert_eq!(forbidden, StatusCode::FORBIDDEN);
+        assert_eq!(revoked, StatusCode::NO_CONTENT);
+        assert_eq!(
+            SessionCookie::from_request_parts(&mut phone_parts, &shared_state)
+                .await
+                .err(),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            shared_state
+                .active_sessions
+                .for_user("test@test.com")
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_erasing_a_user_should_end_their_sessions_and_forget_their_devices() {
+        let data_access = InMemoryUsers::default();
+        data_access
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        let login = LoginRecord {
+            email_address: "test@test.com".to_string(),
+            ip_address: Some("10.0.0.1".to_string()),
+            user_agent: Some("Firefox".to_string()),
+            logged_in_at: 1_700_000_000,
+        };
+        let login_history = Arc::new(InMemoryLoginHistory::default());
+        login_history.record(&login, Vec::new()).await.unwrap();
+        let shared_state = Arc::new(AppState {
+            sessions: SessionManager::new(AuthMode::Cookie, "test-signing-key", 3600, false),
+            login_history: Some(login_history.clone()),
+            ..test_state(data_access)
+        });
+        let sign_in = |email_address: &str| {
+            let shared_state = shared_state.clone();
+            let user = User::from(email_address, "Test User", "hashed");
+            async move {
+                let (token, claims) = shared_state.sessions.issue(&user).unwrap();
+                auth::record_session(&shared_state, &claims, &HeaderMap::new()).await;
+                let (mut parts, _) = axum::http::Request::builder()
+                    .header(header::COOKIE, format!("{}={}", auth::SESSION_COOKIE_NAME, token))
+                    .body(())
+                    .unwrap()
+                    .into_parts();
+                let scope = RequireScope::<UsersWrite>::from_request_parts(&mut parts, &shared_state)
+                    .await
+                    .unwrap();
+                (parts, claims, scope)
+            }
+        };
+        let (mut parts, claims, scope) = sign_in("test@test.com").await;
+        let (_, other, other_scope) = sign_in("other@test.com").await;
+
+        let forbidden = erase_user(
+            State(shared_state.clone()),
+            other_scope,
+            Some(Extension(other)),
+            Path("test@test.com".to_string()),
+        )
+        .await;
+        let erased = erase_user(
+            State(shared_state.clone()),
+            scope,
+            Some(Extension(claims)),
+            Path("test@test.com".to_string()),
+        )
+        .await;
+
+        assert_eq!(forbidden.status(), StatusCode::FORBIDDEN);
+        assert_eq!(erased.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            SessionCookie::from_request_parts(&mut parts, &shared_state)
+                .await
+                .err(),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        assert!(shared_state.active_sessions.for_user("test@test.com").await.unwrap().is_empty());
+        assert!(shared_state.data_access.list(0, 10).await.unwrap().is_empty());
+        assert_eq!(
+            login_history.record(&login, Vec::new()).await.unwrap(),
+            DeviceSighting::FirstLogin
+        );
+    }
+
+    #[tokio::test]
+    async fn test_patch_user_should_only_change_the_fields_that_are_present() {
+        let data_access = InMemoryUsers::default();
+        data_access
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        let shared_state = Arc::new(test_state(data_access));
+        let patch = |payload: PatchUserRequest| {
+            let shared_state = shared_state.clone();
+            async move {
+                let mut parts = axum::http::Request::new(()).into_parts().0;
+                let scope = RequireScope::<UsersWrite>::from_request_parts(&mut parts, &shared_state)
+                    .await
+                    .unwrap();
+
+                patch_user(
+                    State(shared_state),
+                    scope,
+                    None,
+                    Path("test@test.com".to_string()),
+                    HeaderMap::new(),
+                    Json(payload),
+                )
+                .await
+                .status()
+            }
+        };
+
+        let empty = patch(PatchUserRequest {
+            name: None,
+            phone_number: None,
+            metadata: None,
+        }).await;
+        let renamed = patch(PatchUserRequest {
+            name: Some("Renamed".to_string()),
+            phone_number: None,
+            metadata: None,
+        })
+        .await;
+        let invalid = patch(PatchUserRequest {
+            name: Some(" ".to_string()),
+            phone_number: None,
+            metadata: None,
+        })
+        .await;
+        let control_characters = patch(PatchUserRequest {
+            name: Some("Renamed\u{0007}".to_string()),
+            phone_number: None,
+            metadata: None,
+        })
+        .await;
+
+        assert_eq!(empty, StatusCode::BAD_REQUEST);
+        assert_eq!(renamed, StatusCode::OK);
+        assert_eq!(invalid, StatusCode::BAD_REQUEST);
+        assert_eq!(control_characters, StatusCode::BAD_REQUEST);
+        let stored = shared_state
+            .data_access
+            .with_email_address_versioned("test@test.com")
+            .await
+            .unwrap();
+        assert_eq!(stored.value.name(), "Renamed");
+        assert_eq!(stored.version, 2);
+
+        let phoned = patch(PatchUserRequest {
+            name: None,
+            phone_number: Some("0044 20 7946 0958".to_string()),
+            metadata: None,
+        })
+        .await;
+        let invalid_phone = patch(PatchUserRequest {
+            name: None,
+            phone_number: Some("020 7946 0958".to_string()),
+            metadata: None,
+        })
+        .await;
+
+        assert_eq!(phoned, StatusCode::OK);
+        assert_eq!(invalid_phone, StatusCode::UNPROCESSABLE_ENTITY);
+        let stored = shared_state
+            .data_access
+            .with_email_address("test@test.com")
+            .await
+            .unwrap();
+        assert_eq!(stored.name(), "Renamed");
+        assert_eq!(stored.phone_number().as_deref(), Some("+442079460958"));
+
+        let unphoned = patch(PatchUserRequest {
+            name: None,
+            phone_number: Some(String::new()),
+            metadata: None,
+        })
+        .await;
+
+        assert_eq!(unphoned, StatusCode::OK);
+        let stored = shared_state
+            .data_access
+            .with_email_address("test@test.com")
+            .await
+            .unwrap();
+        assert_eq!(stored.phone_number(), None);
+    }
+
+    #[tokio::test]
+    async fn test_metadata_should_be_merged_by_patch_and_kept_to_its_size() {
+        use tower::ServiceExt;
+        use workshop_core::MAX_METADATA_BYTES;
+
+        let data_access = InMemoryUsers::default();
+        data_access
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        let app = router(Arc::new(test_state(data_access)), false);
+        let patch = |body: serde_json::Value| {
+            let app = app.clone();
+            async move {
+                let request = axum::http::Request::patch("/users/test@test.com")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(axum::body::Body::from(body.to_string()))
+                    .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
             }
-        }
+        };
+
+        patch(serde_json::json!({"metadata": {"team": "otters", "seat": 4}})).await;
+        let (status, merged) = patch(serde_json::json!({"metadata": {"seat": null}})).await;
+        let (oversized, error) =
+            patch(serde_json::json!({"metadata": {"notes": "n".repeat(MAX_METADATA_BYTES)}})).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(merged["metadata"], serde_json::json!({"team": "otters"}));
+        assert_eq!(merged["name"], "Test User");
+        assert_eq!(oversized, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error["fields"][0]["constraint"], "max_size");
     }
 
-    mock! {
-        DataAccess{}
-        #[async_trait::async_trait]
-        impl DataAccess for DataAccess {
-            async fn with_email_address(&self, email_address: &str) -> std::result::Result<User, ApplicationError>;
-            async fn store(&self, user: User) -> std::result::Result<(), ApplicationError>;
+    #[tokio::test]
+    async fn test_tiers_should_only_move_up_on_upgrade_and_down_on_downgrade() {
+        use tower::ServiceExt;
+
+        let data_access = InMemoryUsers::default();
+        data_access
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        let app = router(Arc::new(test_state(data_access)), false);
+        let change = |direction: &'static str, body: serde_json::Value| {
+            let app = app.clone();
+            async move {
+                let request =
+                    axum::http::Request::post(format!("/users/test@test.com/{}", direction))
+                        .header(header::CONTENT_TYPE, "application/json")
+                        .body(axum::body::Body::from(body.to_string()))
+                        .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+            }
+        };
+
+        let (status, gold) = change("upgrade", serde_json::json!({"tier": "gold"})).await;
+        let (sideways, error) = change("upgrade", serde_json::json!({"tier": "silver"})).await;
+        let (_, silver) = change("downgrade", serde_json::json!({"tier": "silver"})).await;
+        let (_, standard) = change("downgrade", serde_json::json!({})).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(gold["tier"], "gold");
+        assert_eq!(gold["capabilities"]["prioritySupport"], true);
+        assert_eq!(sideways, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error["fields"][0]["constraint"], "above_current");
+        assert_eq!(silver["capabilities"]["maxEmailAliases"], 3);
+        assert_eq!(standard["tier"], serde_json::Value::Null);
+
+        let request = axum::http::Request::get("/users/test@test.com/tier")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let tier: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(tier["capabilities"]["maxEmailAliases"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_a_login_should_be_recorded_without_changing_the_version() {
+        use tower::ServiceExt;
+
+        let data_access = InMemoryUsers::default();
+        data_access
+            .store(User::new("test@test.com", "Test User", "Correct-Horse-Battery-42").unwrap())
+            .await
+            .unwrap();
+        let shared_state = Arc::new(test_state(data_access));
+        let app = router(shared_state.clone(), false);
+        let get = || async {
+            let request = axum::http::Request::get("/users/test@test.com")
+                .body(axum::body::Body::empty())
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            let etag = response.headers()[header::ETAG].clone();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            (etag, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+        };
+
+        let (etag_before, before) = get().await;
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", header::HeaderValue::from_static("10.0.0.1"));
+        let response = login(
+            State(shared_state.clone()),
+            headers,
+            CookieJar::new(),
+            Json(LoginRequest {
+                email_address: "test@test.com".to_string(),
+                password: "Correct-Horse-Battery-42".to_string(),
+            }),
+        )
+        .await;
+        // Recorded in the background, after the login has answered
+        for _ in 0..100 {
+            match shared_state.data_access.last_login("test@test.com").await.unwrap() {
+                Some(_) => break,
+                None => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
         }
+        let (etag_after, after) = get().await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(before["lastLoginAt"], serde_json::Value::Null);
+        assert!(after["lastLoginAt"].is_u64());
+        assert_eq!(after["lastLoginIp"], "10.0.0.1");
+        assert_eq!(after["emailAddress"], "test@test.com");
+        assert_eq!(etag_before, etag_after);
     }
 
-    #[async_trait::async_trait]
-    impl DataAccess for ManualMockDataAccess {
-        async fn with_email_address(
-            &self,
-            email_address: &str,
-        ) -> std::result::Result<User, ApplicationError> {
-            if let Some(user) = self.users.get(email_address) {
-                Ok(user.clone())
-            } else {
-                Err(ApplicationError::UserDoesNotExist)
+    #[tokio::test]
+    async fn test_get_user_details_should_find_the_user_by_their_id_as_well() {
+        let user = User::from("test@test.com", "Test User", "hashed");
+        let id = user.id();
+        let data_access = InMemoryUsers::default();
+        data_access.store(user).await.unwrap();
+        let shared_state = Arc::new(test_state(data_access));
+        let get = |key: String| {
+            let shared_state = shared_state.clone();
+            async move {
+                let mut parts = axum::http::Request::new(()).into_parts().0;
+                let scope = RequireScope::<UsersRead>::from_request_parts(&mut parts, &shared_state)
+                    .await
+                    .unwrap();
+
+                get_user_details(State(shared_state), scope, None, Path(key)).await
             }
+        };
+
+        let by_id = get(id.to_string()).await;
+        let unknown_id = get(Uuid::new_v4().to_string()).await;
+        let by_email_address = get("test@test.com".to_string()).await;
+
+        assert_eq!(by_id.status(), StatusCode::OK);
+        assert_eq!(unknown_id.status(), StatusCode::NOT_FOUND);
+        assert_eq!(by_email_address.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(by_id.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["id"], id.to_string());
+        assert_eq!(body["emailAddress"], "test@test.com");
+    }
+
+    #[tokio::test]
+    async fn test_deleted_users_should_be_hidden_until_an_admin_deletes_them_for_good() {
+        let data_access = InMemoryUsers::default();
+        let admin_user = User::from("admin@test.com", "Test User", "hashed").with_role(Role::Admin);
+        data_access
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        data_access.store(admin_user.clone()).await.unwrap();
+        let shared_state = Arc::new(AppState {
+            sessions: SessionManager::new(AuthMode::Cookie, "test-signing-key", 3600, false),
+            ..test_state(data_access)
+        });
+        let (token, admin) = shared_state.sessions.issue(&admin_user).unwrap();
+        let (mut parts, _) = axum::http::Request::builder()
+            .header(header::COOKIE, format!("{}={}", auth::SESSION_COOKIE_NAME, token))
+            .body(())
+            .unwrap()
+            .into_parts();
+        let write_scope = RequireScope::<UsersWrite>::from_request_parts(&mut parts, &shared_state)
+            .await
+            .unwrap();
+        let admin_scope = RequireScope::<UsersAdmin>::from_request_parts(&mut parts, &shared_state)
+            .await
+            .unwrap();
+        let current_user = CurrentUser::from_request_parts(&mut parts, &shared_state)
+            .await
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_MATCH, preconditions::version_etag(1));
+
+        let soft_deleted = delete_user(
+            State(shared_state.clone()),
+            write_scope,
+            Some(Extension(admin)),
+            Path("test@test.com".to_string()),
+            headers,
+        )
+        .await;
+
+        assert_eq!(soft_deleted.status(), StatusCode::NO_CONTENT);
+        assert!(matches!(
+            shared_state.data_access.with_email_address("test@test.com").await,
+            Err(ApplicationError::UserDoesNotExist)
+        ));
+        assert!(matches!(
+            shared_state
+                .data_access
+                .store(User::from("test@test.com", "Someone Else", "hashed"))
+                .await,
+            Err(ApplicationError::UserAlreadyExists)
+        ));
+
+        let (mut listing, _) = axum::http::Request::builder()
+            .uri("/users?includeDeleted=true")
+            .header(header::COOKIE, format!("{}={}", auth::SESSION_COOKIE_NAME, token))
+            .body(())
+            .unwrap()
+            .into_parts();
+        let listed = list_users(
+            State(shared_state.clone()),
+            RequireScope::<UsersAdmin>::from_request_parts(&mut listing, &shared_state)
+                .await
+                .unwrap(),
+            ListQuery::from_request_parts(&mut listing, &shared_state)
+                .await
+                .unwrap(),
+        )
+        .await;
+
+        assert_eq!(listed.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(listed.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body[0]["emailAddress"], "admin@test.com");
+        assert!(body[0]["deletedAt"].is_null());
+        assert_eq!(body[1]["emailAddress"], "test@test.com");
+        assert!(body[1]["deletedAt"].is_u64());
+
+        let hard_deleted = hard_delete_user(
+            State(shared_state.clone()),
+            admin_scope,
+            current_user,
+            Path("test@test.com".to_string()),
+        )
+        .await;
+
+        assert_eq!(hard_deleted.status(), StatusCode::NO_CONTENT);
+        shared_state
+            .data_access
+            .store(User::from("test@test.com", "Someone Else", "hashed"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_a_deleted_users_cookie_should_no_longer_authenticate() {
+        use tower::ServiceExt;
+
+        let data_access = InMemoryUsers::default();
+        for user in [
+            User::from("admin@test.com", "Test User", "hashed").with_role(Role::Admin),
+            User::from("soft@test.com", "Test User", "hashed"),
+            User::from("hard@test.com", "Test User", "hashed"),
+        ] {
+            data_access.store(user).await.unwrap();
+        }
+        let shared_state = Arc::new(AppState {
+            sessions: SessionManager::new(AuthMode::Cookie, "test-signing-key", 3600, false),
+            ..test_state(data_access)
+        });
+        let mut cookies = HashMap::new();
+        for email_address in ["admin@test.com", "soft@test.com", "hard@test.com"] {
+            let user = shared_state.data_access.with_email_address(email_address).await.unwrap();
+            let (token, claims) = shared_state.sessions.issue(&user).unwrap();
+            auth::record_session(&shared_state, &claims, &HeaderMap::new()).await;
+            cookies.insert(email_address, format!("{}={}", auth::SESSION_COOKIE_NAME, token));
         }
+        let app = router(shared_state.clone(), false);
+        let send = |method: &str, uri: &str, email_address: &str| {
+            let request = axum::http::Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::COOKIE, &cookies[email_address])
+                .header(header::IF_MATCH, "*")
+                .body(axum::body::Body::empty())
+                .unwrap();
+            app.clone().oneshot(request)
+        };
 
-        async fn store(&self, user: User) -> std::result::Result<(), ApplicationError> {
-            // Simulate storing the user
-            Ok(())
+        let soft_deleted = send("DELETE", "/users/soft@test.com", "soft@test.com").await.unwrap();
+        let hard_deleted = send("DELETE", "/admin/users/hard@test.com", "admin@test.com")
+            .await
+            .unwrap();
+
+        assert_eq!(soft_deleted.status(), StatusCode::NO_CONTENT);
+        assert_eq!(hard_deleted.status(), StatusCode::NO_CONTENT);
+        for email_address in ["soft@test.com", "hard@test.com"] {
+            let (mut parts, _) = axum::http::Request::builder()
+                .header(header::COOKIE, &cookies[email_address])
+                .body(())
+                .unwrap()
+                .into_parts();
+            assert_eq!(
+                SessionCookie::from_request_parts(&mut parts, &shared_state).await.err(),
+                Some(StatusCode::UNAUTHORIZED),
+                "{}",
+                email_address
+            );
         }
+        // Whoever registers the freed address next isn't who the old cookie was issued to
+        shared_state
+            .data_access
+            .store(User::from("hard@test.com", "Someone Else", "hashed"))
+            .await
+            .unwrap();
+        let response = send("GET", "/users/me", "hard@test.com").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = send("GET", "/users/me", "admin@test.com").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
     }
 
     #[tokio::test]
-    async fn test_register_user_with_manual_mock() {
-        let mock_data_access = ManualMockDataAccess::new();
+    async fn test_merging_users_should_fold_the_duplicate_into_the_kept_user() {
+        use crate::core::Theme;
+        use crate::data_access::{DeviceSighting, InMemoryLoginHistory};
+
+        let data_access = InMemoryUsers::default();
+        let admin_user = User::from("admin@test.com", "Test User", "hashed").with_role(Role::Admin);
+        data_access.store(admin_user.clone()).await.unwrap();
+        data_access
+            .store(User::from("keep@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        data_access
+            .store(User::from("duplicate@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        data_access
+            .add_email_alias("duplicate@test.com", "work@test.com", "token-hash")
+            .await
+            .unwrap();
+        data_access.verify_email_alias("token-hash", 0).await.unwrap();
+        let preferences = UserPreferences {
+            theme: Theme::Dark,
+            ..UserPreferences::default()
+        };
+        data_access
+            .update_preferences("duplicate@test.com", &preferences)
+            .await
+            .unwrap();
+        let device_of = |email_address: &str| LoginRecord {
+            email_address: email_address.to_string(),
+            ip_address: Some("10.0.0.1".to_string()),
+            user_agent: Some("Firefox".to_string()),
+            logged_in_at: 1_700_000_000,
+        };
+        let login_history = Arc::new(InMemoryLoginHistory::default());
+        login_history.record(&device_of("duplicate@test.com"), Vec::new()).await.unwrap();
         let shared_state = Arc::new(AppState {
-            data_access: mock_data_access,
+            sessions: SessionManager::new(AuthMode::Cookie, "test-signing-key", 3600, false),
+            login_history: Some(login_history.clone()),
+            ..test_state(data_access)
         });
+        let (token, _) = shared_state.sessions.issue(&admin_user).unwrap();
+        let merge = |keep: &str, merge: &str| {
+            let shared_state = shared_state.clone();
+            let cookie = format!("{}={}", auth::SESSION_COOKIE_NAME, token);
+            let payload = MergeUsersRequest {
+                keep_email_address: keep.to_string(),
+                merge_email_address: merge.to_string(),
+            };
+            async move {
+                let (mut parts, _) = axum::http::Request::builder()
+                    .header(header::COOKIE, cookie)
+                    .body(())
+                    .unwrap()
+                    .into_parts();
+                let scope =
+                    RequireScope::<UsersAdmin>::from_request_parts(&mut parts, &shared_state)
+                        .await
+                        .unwrap();
+                let admin = CurrentUser::from_request_parts(&mut parts, &shared_state)
+                    .await
+                    .unwrap();
+                merge_users(State(shared_state), scope, admin, Json(payload)).await
+            }
+        };
 
-        let (status, response) = register_user(
-            State(shared_state),
-            Json(RegisterUserRequest {
+        let into_themselves = merge("keep@test.com", "keep@test.com").await;
+        let merged = merge("keep@test.com", "duplicate@test.com").await;
+        let again = merge("keep@test.com", "duplicate@test.com").await;
+
+        assert_eq!(into_themselves.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(merged.status(), StatusCode::OK);
+        assert_eq!(again.status(), StatusCode::NOT_FOUND);
+        let data_access = &shared_state.data_access;
+        assert!(matches!(
+            data_access.with_email_address("duplicate@test.com").await,
+            Err(ApplicationError::UserDoesNotExist)
+        ));
+        for alias in ["duplicate@test.com", "work@test.com"] {
+            assert_eq!(
+                data_access.resolve_email_alias(alias).await.unwrap().as_deref(),
+                Some("keep@test.com")
+            );
+        }
+        assert_eq!(data_access.preferences("keep@test.com").await.unwrap(), preferences);
+        assert_eq!(
+            login_history.record(&device_of("keep@test.com"), Vec::new()).await.unwrap(),
+            DeviceSighting::KnownDevice
+        );
+    }
+
+    #[tokio::test]
+    async fn test_magic_links_sent_in_the_local_profile_should_be_listed_in_the_dev_outbox() {
+        let mut manual_mock_data_access = ManualMockDataAccess::new();
+        manual_mock_data_access.users.insert(
+            "test@test.com".to_string(),
+            User::from("test@test.com", "Test User", "hashed"),
+        );
+        let sandbox = Arc::new(NotificationSandbox::new(sandbox::SANDBOX_CAPACITY));
+        let shared_state = Arc::new(AppState {
+            magic_links: test_magic_links(Arc::new(SandboxMagicLinkSender {
+                sandbox: sandbox.clone(),
+                inner: Arc::new(LogMagicLinkSender),
+            })),
+            sandbox: Some(sandbox),
+            ..test_state(manual_mock_data_access)
+        });
+
+        let status = request_magic_link(
+            State(shared_state.clone()),
+            Json(MagicLinkRequest {
                 email_address: "test@test.com".to_string(),
-                name: "Test User".to_string(),
-                password: "Testing!23".to_string(),
             }),
         )
         .await;
+        let Json(captured) = dev_outbox(State(shared_state)).await;
 
-        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(captured.len(), 1);
+        assert_eq!(captured[0].recipient, "test@test.com");
+        assert!(captured[0].body.starts_with("http://localhost:3000/login/magic/"));
     }
 
     #[tokio::test]
-    async fn test_register_user_with_mock_all() {
-        let mut mock_data_access = MockDataAccess::new();
-        mock_data_access
-            .expect_store()
-            .withf(|user| user.email_address() == "test@test.com".to_string())
-            .return_once(move |_| Ok(()));
+    async fn test_only_a_login_from_a_new_device_should_alert_the_user() {
+        use crate::data_access::InMemoryLoginHistory;
+        use crate::metrics::USER_NEW_DEVICE_LOGIN_TOTAL;
+
+        let mut manual_mock_data_access = ManualMockDataAccess::new();
+        manual_mock_data_access.users.insert(
+            "test@test.com".to_string(),
+            User::new("test@test.com", "Test User", "Correct-Horse-Battery-42").unwrap(),
+        );
+        let sandbox = Arc::new(NotificationSandbox::new(sandbox::SANDBOX_CAPACITY));
         let shared_state = Arc::new(AppState {
-            data_access: mock_data_access,
+            login_history: Some(Arc::new(InMemoryLoginHistory::default())),
+            sandbox: Some(sandbox.clone()),
+            ..test_state(manual_mock_data_access)
         });
+        let login_from = |user_agent: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::USER_AGENT, header::HeaderValue::from_static(user_agent));
+            headers.insert("x-forwarded-for", header::HeaderValue::from_static("10.0.0.1"));
+            login(
+                State(shared_state.clone()),
+                headers,
+                CookieJar::new(),
+                Json(LoginRequest {
+                    email_address: "test@test.com".to_string(),
+                    password: "Correct-Horse-Battery-42".to_string(),
+                }),
+            )
+        };
 
-        let (status, response) = register_user(
-            State(shared_state),
-            Json(RegisterUserRequest {
-                email_address: "test@test.com".to_string(),
-                name: "Test User".to_string(),
-                password: "Testing!23".to_string(),
+        assert_eq!(login_from("Firefox").await.status(), StatusCode::OK);
+        assert_eq!(login_from("Firefox").await.status(), StatusCode::OK);
+        assert!(sandbox.list().is_empty());
+        assert_eq!(shared_state.metrics.counter(USER_NEW_DEVICE_LOGIN_TOTAL), 0);
+
+        assert_eq!(login_from("curl").await.status(), StatusCode::OK);
+        assert_eq!(sandbox.list().len(), 1);
+        assert_eq!(sandbox.list()[0].subject, login_alerts::NEW_DEVICE_SUBJECT);
+        assert!(sandbox.list()[0].body.contains("curl"));
+        assert_eq!(shared_state.metrics.counter(USER_NEW_DEVICE_LOGIN_TOTAL), 1);
+    }
+
+    #[tokio::test]
+    async fn test_warm_up_should_cache_the_hot_users_before_the_first_request() {
+        use tower::ServiceExt;
+
+        let data_access = InMemoryUsers::default();
+        data_access
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        let mut state = test_state(data_access);
+        state.response_cache = Some(ResponseCache::new(HashMap::from([(
+            "/users/{email_address}".to_string(),
+            Duration::from_secs(30),
+        )])));
+        let shared_state = Arc::new(state);
+        let app = router(shared_state.clone(), false);
+        let settings = WarmupSettings {
+            connections: 0,
+            hot_users: vec!["test@test.com".to_string(), "unknown@test.com".to_string()],
+        };
+
+        let cached = warmup::warm_up(&shared_state, &app, &settings).await;
+
+        let request = axum::http::Request::get("/users/test@test.com")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(cached, 1);
+        assert_eq!(response.headers()["x-cache"], "HIT");
+    }
+
+    #[tokio::test]
+    async fn test_a_user_changed_elsewhere_should_not_be_served_from_the_cache() {
+        use tower::ServiceExt;
+
+        let user = User::from("test@test.com", "Test User", "hashed");
+        let data_access = InMemoryUsers::default();
+        data_access.store(user.clone()).await.unwrap();
+        let mut state = test_state(data_access);
+        state.response_cache = Some(ResponseCache::new(HashMap::from([(
+            "/users/{email_address}".to_string(),
+            Duration::from_secs(30),
+        )])));
+        let shared_state = Arc::new(state);
+        let app = router(shared_state.clone(), false);
+        let get = || {
+            let app = app.clone();
+            async move {
+                let request = axum::http::Request::get("/users/test@test.com")
+                    .body(axum::body::Body::empty())
+                    .unwrap();
+                app.oneshot(request).await.unwrap()
+            }
+        };
+        get().await;
+        assert_eq!(get().await.headers()["x-cache"], "HIT");
+
+        // As the trigger announces it
+        let payload = format!(
+            r#"{{"id": "{}", "email_address": "test@test.com", "username": null}}"#,
+            user.id()
+        );
+        let cache = shared_state.response_cache.as_ref().unwrap();
+        invalidate_changed_user(
+            cache,
+            UserChangeNotification::Changed(serde_json::from_str(&payload).unwrap()),
+        );
+
+        assert_eq!(get().await.headers()["x-cache"], "MISS");
+    }
+
+    #[tokio::test]
+    async fn test_list_users_should_page_in_email_order_and_reject_oversized_pages() {
+        use tower::ServiceExt;
+
+        let data_access = InMemoryUsers::default();
+        for email_address in ["c@test.com", "a@test.com", "b@test.com"] {
+            data_access
+                .store(User::from(email_address, "Test User", "hashed"))
+                .await
+                .unwrap();
+        }
+        let shared_state = Arc::new(AppState {
+            sessions: SessionManager::new(AuthMode::Cookie, "test-signing-key", 3600, false),
+            ..test_state(data_access)
+        });
+        let (token, _) = shared_state
+            .sessions
+            .issue(&User::from("a@test.com", "Test User", "hashed").with_role(Role::Admin))
+            .unwrap();
+        let app = router(shared_state, false);
+        let get = |uri: &'static str| {
+            let app = app.clone();
+            let cookie = format!("{}={}", auth::SESSION_COOKIE_NAME, token);
+            async move {
+                let request = axum::http::Request::get(uri)
+                    .header(header::COOKIE, cookie)
+                    .body(axum::body::Body::empty())
+                    .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+            }
+        };
+
+        let (status, page) = get("/users?offset=1&limit=1").await;
+        let (oversized, error) = get("/users?limit=1000").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(page.as_array().unwrap().len(), 1);
+        assert_eq!(page[0]["emailAddress"], "b@test.com");
+        assert!(page[0].get("password").is_none());
+        assert_eq!(oversized, StatusCode::BAD_REQUEST);
+        assert_eq!(error["code"], "INVALID_REQUEST");
+    }
+
+    #[tokio::test]
+    async fn test_list_users_should_link_a_full_page_to_the_next_by_cursor() {
+        use tower::ServiceExt;
+
+        let data_access = InMemoryUsers::default();
+        for email_address in ["c@test.com", "a@test.com", "b@test.com"] {
+            data_access
+                .store(User::from(email_address, "Test User", "hashed"))
+                .await
+                .unwrap();
+        }
+        let shared_state = Arc::new(AppState {
+            sessions: SessionManager::new(AuthMode::Cookie, "test-signing-key", 3600, false),
+            ..test_state(data_access)
+        });
+        let (token, _) = shared_state
+            .sessions
+            .issue(&User::from("a@test.com", "Test User", "hashed").with_role(Role::Admin))
+            .unwrap();
+        let app = router(shared_state, false);
+        let get = |uri: String| {
+            let app = app.clone();
+            let cookie = format!("{}={}", auth::SESSION_COOKIE_NAME, token);
+            async move {
+                let request = axum::http::Request::get(uri)
+                    .header(header::COOKIE, cookie)
+                    .body(axum::body::Body::empty())
+                    .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let next = response
+                    .headers()
+                    .get(header::LINK)
+                    .map(|link| link.to_str().unwrap().to_string());
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (next, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+            }
+        };
+
+        let (next, first) = get("/users?limit=2".to_string()).await;
+        let next = next.unwrap();
+        let uri = next.strip_prefix('<').and_then(|next| next.split_once('>')).unwrap().0;
+        let (last, second) = get(uri.to_string()).await;
+
+        assert_eq!(first[1]["emailAddress"], "b@test.com");
+        assert!(next.ends_with("; rel=\"next\""));
+        assert_eq!(second.as_array().unwrap().len(), 1);
+        assert_eq!(second[0]["emailAddress"], "c@test.com");
+        assert_eq!(last, None);
+    }
+
+    #[tokio::test]
+    async fn test_search_users_should_rank_a_match_on_the_name_above_one_on_the_email_address() {
+        use tower::ServiceExt;
+
+        let data_access = InMemoryUsers::default();
+        for (email_address, name) in [
+            ("ada@test.com", "Grace Hopper"),
+            ("lovelace@test.com", "Ada Lovelace"),
+            ("linus@test.com", "Linus Torvalds"),
+        ] {
+            data_access
+                .store(User::from(email_address, name, "hashed"))
+                .await
+                .unwrap();
+        }
+        let shared_state = Arc::new(AppState {
+            sessions: SessionManager::new(AuthMode::Cookie, "test-signing-key", 3600, false),
+            ..test_state(data_access)
+        });
+        let (token, _) = shared_state
+            .sessions
+            .issue(&User::from("admin@test.com", "Test User", "hashed").with_role(Role::Admin))
+            .unwrap();
+        let app = router(shared_state, false);
+        let get = |uri: &'static str| {
+            let app = app.clone();
+            let cookie = format!("{}={}", auth::SESSION_COOKIE_NAME, token);
+            async move {
+                let request = axum::http::Request::get(uri)
+                    .header(header::COOKIE, cookie)
+                    .body(axum::body::Body::empty())
+                    .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+            }
+        };
+
+        let (status, found) = get("/users/search?q=Ada").await;
+        let (_, narrowed) = get("/users/search?q=ada%20hopper").await;
+        let (missing, _) = get("/users/search?q=%20").await;
+
+        assert_eq!(status, StatusCode::OK);
+        let found: Vec<&str> = found
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| user["emailAddress"].as_str().unwrap())
+            .collect();
+        assert_eq!(found, vec!["lovelace@test.com", "ada@test.com"]);
+        assert_eq!(narrowed.as_array().unwrap().len(), 1);
+        assert_eq!(narrowed[0]["emailAddress"], "ada@test.com");
+        assert_eq!(missing, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_users_should_not_be_listed_without_sessions() {
+        use tower::ServiceExt;
+
+        let app = router(Arc::new(test_state(InMemoryUsers::default())), false);
+        let request = axum::http::Request::get("/users")
+            .body(axum::body::Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_ready_should_answer_503_until_the_store_can_be_reached() {
+        use tower::ServiceExt;
+
+        let ready = |reachable: bool| async move {
+            let mut mock_data_access = MockDataAccess::new();
+            mock_data_access.expect_health_check().returning(move || {
+                if reachable {
+                    Ok(())
+                } else {
+                    Err(ApplicationError::DatabaseError("connection refused".to_string()))
+                }
+            });
+            let app = router(Arc::new(test_state(mock_data_access)), false);
+            let request = axum::http::Request::get("/ready")
+                .body(axum::body::Body::empty())
+                .unwrap();
+
+            app.oneshot(request).await.unwrap().status()
+        };
+
+        assert_eq!(ready(true).await, StatusCode::OK);
+        assert_eq!(ready(false).await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_an_uploaded_avatar_should_be_served_with_caching_headers() {
+        use tower::ServiceExt;
+
+        let data_access = InMemoryUsers::default();
+        data_access
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        let app = router(Arc::new(test_state(data_access)), false);
+        let upload = |content: &'static [u8]| {
+            let app = app.clone();
+            async move {
+                let mut body = b"--boundary\r\nContent-Disposition: form-data; name=\"avatar\"; filename=\"me.png\"\r\nContent-Type: image/png\r\n\r\n".to_vec();
+                body.extend_from_slice(content);
+                body.extend_from_slice(b"\r\n--boundary--\r\n");
+                let request = axum::http::Request::put("/users/test@test.com/avatar")
+                    .header(header::CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+                    .body(axum::body::Body::from(body))
+                    .unwrap();
+                app.oneshot(request).await.unwrap().status()
+            }
+        };
+        let get = |if_none_match: Option<String>| {
+            let app = app.clone();
+            async move {
+                let mut request = axum::http::Request::get("/users/test@test.com/avatar");
+                if let Some(if_none_match) = if_none_match {
+                    request = request.header(header::IF_NONE_MATCH, if_none_match);
+                }
+                app.oneshot(request.body(axum::body::Body::empty()).unwrap())
+                    .await
+                    .unwrap()
+            }
+        };
+        let png: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+        assert_eq!(get(None).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(upload(b"<svg></svg>").await, StatusCode::BAD_REQUEST);
+        assert_eq!(upload(png).await, StatusCode::NO_CONTENT);
+
+        let served = get(None).await;
+        assert_eq!(served.status(), StatusCode::OK);
+        assert_eq!(served.headers()[header::CONTENT_TYPE], "image/png");
+        assert_eq!(served.headers()[header::CACHE_CONTROL], "private, max-age=300");
+        let etag = served.headers()[header::ETAG].to_str().unwrap().to_string();
+        let body = axum::body::to_bytes(served.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], png);
+        assert_eq!(get(Some(etag)).await.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn test_preferences_should_default_until_they_are_replaced() {
+        use crate::core::Theme;
+
+        let data_access = InMemoryUsers::default();
+        data_access
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        let shared_state = Arc::new(test_state(data_access));
+        let mut parts = axum::http::Request::new(()).into_parts().0;
+        let read = RequireScope::<UsersRead>::from_request_parts(&mut parts, &shared_state)
+            .await
+            .unwrap();
+        let write = || async {
+            let mut parts = axum::http::Request::new(()).into_parts().0;
+            RequireScope::<UsersWrite>::from_request_parts(&mut parts, &shared_state)
+                .await
+                .unwrap()
+        };
+        let preferences = |locale: &str| UserPreferences {
+            locale: locale.to_string(),
+            marketing_opt_in: true,
+            theme: Theme::Dark,
+        };
+
+        let invalid = update_preferences(
+            State(shared_state.clone()),
+            write().await,
+            None,
+            Path("test@test.com".to_string()),
+            Json(preferences("not a locale")),
+        )
+        .await;
+        assert_eq!(
+            shared_state
+                .data_access
+                .preferences("test@test.com")
+                .await
+                .unwrap(),
+            UserPreferences::default()
+        );
+        let updated = update_preferences(
+            State(shared_state.clone()),
+            write().await,
+            None,
+            Path("test@test.com".to_string()),
+            Json(preferences("pt-BR")),
+        )
+        .await;
+        let read = get_preferences(
+            State(shared_state.clone()),
+            read,
+            None,
+            Path("test@test.com".to_string()),
+        )
+        .await;
+
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(updated.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(read.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({"locale": "pt-BR", "marketingOptIn": true, "theme": "dark"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replaced_preferences_should_not_be_served_from_the_cache() {
+        use tower::ServiceExt;
+
+        let data_access = InMemoryUsers::default();
+        data_access
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        let mut state = test_state(data_access);
+        state.response_cache = Some(ResponseCache::new(HashMap::from([(
+            "/users/{email_address}/preferences".to_string(),
+            Duration::from_secs(30),
+        )])));
+        let app = router(Arc::new(state), false);
+        let send = |request: axum::http::Request<axum::body::Body>| {
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+        let get = || {
+            axum::http::Request::get("/users/test@test.com/preferences")
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+
+        assert_eq!(send(get()).await["locale"], "en");
+        send(
+            axum::http::Request::put("/users/test@test.com/preferences")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(axum::body::Body::from(r#"{"locale":"pt-BR"}"#))
+                .unwrap(),
+        )
+        .await;
+
+        assert_eq!(send(get()).await["locale"], "pt-BR");
+    }
+
+    #[tokio::test]
+    async fn test_bodies_that_are_not_json_should_be_an_unsupported_media_type() {
+        use tower::ServiceExt;
+
+        let data_access = InMemoryUsers::default();
+        data_access
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        let app = router(Arc::new(test_state(data_access)), false);
+        let send = |uri: &'static str, content_type: &'static str, body: &'static str| {
+            let app = app.clone();
+            async move {
+                let request = axum::http::Request::put(uri)
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(header::CONTENT_LENGTH, body.len())
+                    .body(axum::body::Body::from(body))
+                    .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).ok())
+            }
+        };
+
+        let (form, error) = send(
+            "/users/test@test.com/preferences",
+            "application/x-www-form-urlencoded",
+            "locale=pt-BR",
+        )
+        .await;
+        let (latin1, _) = send(
+            "/users/test@test.com/preferences",
+            "application/json; charset=iso-8859-1",
+            r#"{"locale":"pt-BR"}"#,
+        )
+        .await;
+        let (json, _) = send(
+            "/users/test@test.com/preferences",
+            "application/json; charset=utf-8",
+            r#"{"locale":"pt-BR"}"#,
+        )
+        .await;
+
+        assert_eq!(form, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(error.unwrap()["code"], "UNSUPPORTED_MEDIA_TYPE");
+        assert_eq!(latin1, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(json, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_a_verified_alias_should_find_the_user_and_can_become_their_primary_address() {
+        use tower::ServiceExt;
+
+        let data_access = InMemoryUsers::default();
+        data_access
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        let sender = Arc::new(RecordingMagicLinkSender::default());
+        let app = router(
+            Arc::new(AppState {
+                magic_links: test_magic_links(sender.clone()),
+                ..test_state(data_access)
+            }),
+            false,
+        );
+        let send = |method: &'static str, uri: String, body: Option<&'static str>| {
+            let app = app.clone();
+            async move {
+                let request = axum::http::Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(header::CONTENT_TYPE, "application/json");
+                let request = match body {
+                    Some(body) => request.body(axum::body::Body::from(body)).unwrap(),
+                    None => request.body(axum::body::Body::empty()).unwrap(),
+                };
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).ok())
+            }
+        };
+        let alias = Some(r#"{"emailAddress":"work@test.com"}"#);
+
+        let (added, _) = send("POST", "/users/test@test.com/emails".to_string(), alias).await;
+        let (unverified, _) = send("GET", "/users/work@test.com".to_string(), None).await;
+        let link = sender.links.lock().unwrap().pop().unwrap();
+        let token = link.strip_prefix("http://localhost:3000/emails/verify/").unwrap().to_string();
+        let (verified, _) = send("GET", format!("/emails/verify/{}", token), None).await;
+        let (reused, _) = send("GET", format!("/emails/verify/{}", token), None).await;
+        let (taken, _) = send("POST", "/users".to_string(), Some(
+            r#"{"emailAddress":"work@test.com","password":"Purple-Otter-Canoe-42","name":"Someone"}"#,
+        ))
+        .await;
+        let (_, by_alias) = send("GET", "/users/work@test.com".to_string(), None).await;
+
+        assert_eq!(added, StatusCode::ACCEPTED);
+        assert_eq!(unverified, StatusCode::NOT_FOUND);
+        assert_eq!(verified, StatusCode::NO_CONTENT);
+        assert_eq!(reused, StatusCode::BAD_REQUEST);
+        assert_eq!(taken, StatusCode::CONFLICT);
+        assert_eq!(by_alias.unwrap()["emailAddress"], "test@test.com");
+
+        let (swapped, user) =
+            send("POST", "/users/test@test.com/emails/work@test.com/primary".to_string(), None).await;
+        let (_, by_old_address) = send("GET", "/users/test@test.com".to_string(), None).await;
+        let (_, aliases) = send("GET", "/users/work@test.com/emails".to_string(), None).await;
+
+        assert_eq!(swapped, StatusCode::OK);
+        assert_eq!(user.unwrap()["emailAddress"], "work@test.com");
+        assert_eq!(by_old_address.unwrap()["emailAddress"], "work@test.com");
+        let aliases = aliases.unwrap();
+        assert_eq!(aliases.as_array().unwrap().len(), 1);
+        assert_eq!(aliases[0]["emailAddress"], "test@test.com");
+        assert!(aliases[0]["verifiedAt"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn test_routes_that_are_not_enabled_should_answer_with_a_hint() {
+        use tower::ServiceExt;
+
+        let app = router(
+            Arc::new(AppState {
+                route_toggles: Some(
+                    RouteToggles::new(&["POST /users".to_string()], None).unwrap(),
+                ),
+                ..test_state(InMemoryUsers::default())
             }),
+            false,
+        );
+        let send = |method: &'static str, uri: &'static str, body: &'static str| {
+            let app = app.clone();
+            async move {
+                let request = axum::http::Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(axum::body::Body::from(body))
+                    .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).ok())
+            }
+        };
+
+        let (registered, _) = send(
+            "POST",
+            "/users",
+            r#"{"emailAddress":"test@test.com","password":"Purple-Otter-Canoe-42","name":"Test"}"#,
+        )
+        .await;
+        let (login, error) = send(
+            "POST",
+            "/login",
+            r#"{"emailAddress":"test@test.com","password":"Purple-Otter-Canoe-42"}"#,
         )
         .await;
+        let (unknown, _) = send("GET", "/nothing-here", "").await;
+
+        assert_eq!(registered, StatusCode::CREATED);
+        assert_eq!(login, StatusCode::NOT_IMPLEMENTED);
+        let error = error.unwrap();
+        assert_eq!(error["code"], "ROUTE_DISABLED");
+        assert_eq!(error["message"], "POST /login is not enabled");
+        assert_eq!(
+            error["hint"],
+            "add \"POST /login\" to routes.enabled in the config to switch it on"
+        );
+        assert_eq!(unknown, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_a_birthday_should_be_validated_and_give_the_age() {
+        use tower::ServiceExt;
+
+        let data_access = InMemoryUsers::default();
+        data_access
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        let app = router(Arc::new(test_state(data_access)), false);
+        let put = |body: &'static str| {
+            let app = app.clone();
+            async move {
+                let request = axum::http::Request::put("/users/test@test.com/birthday")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(axum::body::Body::from(body))
+                    .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+            }
+        };
+
+        let (set, details) = put(r#"{"dateOfBirth":"1990-06-15"}"#).await;
+        let (future, error) = put(r#"{"dateOfBirth":"2999-01-01"}"#).await;
+        let (cleared, cleared_details) = put(r#"{"dateOfBirth":null}"#).await;
+
+        assert_eq!(set, StatusCode::OK);
+        assert_eq!(details["dateOfBirth"], "1990-06-15");
+        assert!(details["age"].as_i64().is_some_and(|age| age >= 36));
+        assert_eq!(future, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error["fields"][0]["field"], "dateOfBirth");
+        assert_eq!(error["fields"][0]["constraint"], "not_in_future");
+        assert_eq!(cleared, StatusCode::OK);
+        assert!(cleared_details["age"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_a_username_should_find_the_user_and_only_be_taken_once() {
+        use tower::ServiceExt;
+
+        let data_access = InMemoryUsers::default();
+        for email_address in ["test@test.com", "other@test.com"] {
+            data_access
+                .store(User::from(email_address, "Test User", "hashed"))
+                .await
+                .unwrap();
+        }
+        let app = router(Arc::new(test_state(data_access)), false);
+        let send = |method: &'static str, uri: &'static str, body: &'static str| {
+            let app = app.clone();
+            async move {
+                let request = axum::http::Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(axum::body::Body::from(body))
+                    .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<serde_json::Value>(&body).ok())
+            }
+        };
+
+        let (set, details) =
+            send("PUT", "/users/test@test.com/username", r#"{"username":"James_K"}"#).await;
+        let (taken, error) =
+            send("PUT", "/users/other@test.com/username", r#"{"username":"james_k"}"#).await;
+        let (invalid, _) =
+            send("PUT", "/users/other@test.com/username", r#"{"username":"j@k"}"#).await;
+        let (found, by_username) = send("GET", "/usernames/JAMES_K", "").await;
+        let (freed, _) = send("PUT", "/users/test@test.com/username", r#"{"username":null}"#).await;
+        let (gone, _) = send("GET", "/usernames/james_k", "").await;
+
+        assert_eq!(set, StatusCode::OK);
+        assert_eq!(details.unwrap()["username"], "james_k");
+        assert_eq!(taken, StatusCode::CONFLICT);
+        assert_eq!(error.unwrap()["code"], "USERNAME_TAKEN");
+        assert_eq!(invalid, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(found, StatusCode::OK);
+        assert_eq!(by_username.unwrap()["emailAddress"], "test@test.com");
+        assert_eq!(freed, StatusCode::OK);
+        assert_eq!(gone, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_the_current_user_should_be_answered_in_the_header_locale_then_their_preference() {
+        use tower::ServiceExt;
+
+        let data_access = InMemoryUsers::default();
+        data_access
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        let shared_state = Arc::new(AppState {
+            sessions: SessionManager::new(AuthMode::Cookie, "test-signing-key", 3600, false),
+            ..test_state(data_access)
+        });
+        let (token, _) = shared_state
+            .sessions
+            .issue(&User::from("test@test.com", "Test User", "hashed"))
+            .unwrap();
+        let app = router(shared_state.clone(), false);
+        let get = |accept_language: Option<&'static str>| {
+            let app = app.clone();
+            let cookie = format!("{}={}", auth::SESSION_COOKIE_NAME, token);
+            async move {
+                let mut request =
+                    axum::http::Request::get("/users/me").header(header::COOKIE, cookie);
+                if let Some(accept_language) = accept_language {
+                    request = request.header(header::ACCEPT_LANGUAGE, accept_language);
+                }
+                let response = app
+                    .oneshot(request.body(axum::body::Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.headers()[header::VARY], "accept-language");
+                response.headers()[header::CONTENT_LANGUAGE].to_str().unwrap().to_string()
+            }
+        };
+
+        assert_eq!(get(None).await, locale::DEFAULT_LOCALE);
+        shared_state
+            .data_access
+            .update_preferences(
+                "test@test.com",
+                &UserPreferences {
+                    locale: "de".to_string(),
+                    ..UserPreferences::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(get(None).await, "de");
+        assert_eq!(get(Some("fr;q=0.5, pt-BR")).await, "pt-BR");
+    }
+
+    #[tokio::test]
+    async fn test_the_worker_should_hand_each_message_to_the_handlers_for_its_topic() {
+        use crate::consumer::{ConsumedMessage, MessageHandler};
+
+        #[derive(Clone, Default)]
+        struct RecordingHandler {
+            handled: Arc<std::sync::Mutex<Vec<String>>>,
+        }
+
+        #[async_trait::async_trait]
+        impl MessageHandler for RecordingHandler {
+            async fn handle(
+                &self,
+                state: &AppState,
+                message: &ConsumedMessage,
+            ) -> Result<(), ApplicationError> {
+                let email_address = message.payload.clone().unwrap_or_default();
+                let user = state.data_access.with_email_address(&email_address).await?;
+                self.handled.lock().unwrap().push(user.name());
+                Ok(())
+            }
+        }
+
+        let data_access = InMemoryUsers::default();
+        data_access
+            .store(User::from("test@test.com", "Test User", "hashed"))
+            .await
+            .unwrap();
+        let state = test_state(data_access);
+        let recorder = RecordingHandler::default();
+        let handlers = HandlerRegistry::new()
+            .on("order-completed", recorder.clone())
+            .on("order-completed", consumer::LogMessage)
+            .on("order-cancelled", consumer::LogMessage);
+        let message = |topic: &str, payload: &str| ConsumedMessage {
+            topic: topic.to_string(),
+            key: None,
+            payload: Some(payload.to_string()),
+        };
+
+        let handled = handlers
+            .dispatch(&state, &message("order-completed", "test@test.com"))
+            .await;
+        let unknown_user = handlers
+            .dispatch(&state, &message("order-completed", "unknown@test.com"))
+            .await;
+        let other_topic = handlers
+            .dispatch(&state, &message("order-cancelled", "test@test.com"))
+            .await;
 
-        assert_eq!(status, StatusCode::CREATED);
+        assert!(handled.is_ok());
+        assert!(matches!(unknown_user, Err(ApplicationError::UserDoesNotExist)));
+        assert!(other_topic.is_ok());
+        assert_eq!(*recorder.handled.lock().unwrap(), vec!["Test User".to_string()]);
+        assert_eq!(handlers.topics(), vec!["order-cancelled", "order-completed"]);
     }
 }
@@ -0,0 +1,307 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use axum::extract::{FromRequestParts, Query};
+use axum::http::request::Parts;
+use axum::response::Response;
+use serde::Deserialize;
+
+use crate::core::ApplicationError;
+use crate::errors::error_response;
+use crate::AppState;
+
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+// The query string a list endpoint accepts, e.g. `UserListing` for `GET /users`
+pub trait Listing {
+    // The values `sort` accepts, e.g. `-lastSeenAt` for most recent first. The first is the
+    // default.
+    const SORT_FIELDS: &'static [&'static str];
+    // The field `filter` is matched against, `None` if the listing can't be filtered
+    const FILTER_FIELD: Option<&'static str>;
+    // Whether `includeDeleted` may list soft deleted items along with the rest
+    const INCLUDES_DELETED: bool;
+    // Whether pages can be walked with the `cursor` the previous page ended at rather than an
+    // offset, which the store seeks to instead of counting through every item before it
+    const CURSORS: bool;
+}
+
+pub struct UserListing;
+pub struct UserSearchListing;
+pub struct SessionListing;
+
+// Only in the order the stores page users in, sorting on anything else would need every user
+impl Listing for UserListing {
+    const SORT_FIELDS: &'static [&'static str] = &["emailAddress"];
+    const FILTER_FIELD: Option<&'static str> = None;
+    const INCLUDES_DELETED: bool = true;
+    const CURSORS: bool = true;
+}
+
+// Best match first, the query itself is `q`
+impl Listing for UserSearchListing {
+    const SORT_FIELDS: &'static [&'static str] = &["-rank"];
+    const FILTER_FIELD: Option<&'static str> = None;
+    const INCLUDES_DELETED: bool = false;
+    const CURSORS: bool = false;
+}
+
+impl Listing for SessionListing {
+    const SORT_FIELDS: &'static [&'static str] =
+        &["-lastSeenAt", "lastSeenAt", "-createdAt", "createdAt"];
+    const FILTER_FIELD: Option<&'static str> = Some("userAgent");
+    const INCLUDES_DELETED: bool = false;
+    const CURSORS: bool = false;
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RawListParams {
+    offset: Option<i64>,
+    limit: Option<i64>,
+    sort: Option<String>,
+    filter: Option<String>,
+    include_deleted: Option<bool>,
+    cursor: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListParams {
+    pub offset: i64,
+    pub limit: i64,
+    // One of the listing's `SORT_FIELDS`
+    pub sort: &'static str,
+    // Matched case-insensitively anywhere in the listing's `FILTER_FIELD`
+    pub filter: Option<String>,
+    pub include_deleted: bool,
+    // The sort key the page starts after, decoded from the `cursor` the previous page ended at
+    pub after: Option<String>,
+}
+
+// Cursors are only ever handed out by a list endpoint, clients pass them back as they are. The
+// sort key is hex encoded so the cursor doesn't read as something to edit.
+pub fn cursor(sort_key: &str) -> String {
+    hex::encode(sort_key)
+}
+
+fn decode_cursor(cursor: &str) -> Option<String> {
+    String::from_utf8(hex::decode(cursor).ok()?).ok()
+}
+
+impl ListParams {
+    pub fn parse<TListing: Listing>(raw: RawListParams) -> Result<Self, ApplicationError> {
+        let invalid = |message: String| Err(ApplicationError::InvalidRequest(message));
+
+        let offset = raw.offset.unwrap_or(0);
+        if offset < 0 {
+            return invalid("offset must not be negative".to_string());
+        }
+        let limit = raw.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+            return invalid(format!("limit must be between 1 and {}", MAX_PAGE_SIZE));
+        }
+
+        let sort = match raw.sort {
+            None => TListing::SORT_FIELDS[0],
+            Some(sort) => match TListing::SORT_FIELDS.iter().find(|field| **field == sort) {
+                Some(field) => field,
+                None => {
+                    return invalid(format!(
+                        "sort must be one of {}",
+                        TListing::SORT_FIELDS.join(", ")
+                    ))
+                }
+            },
+        };
+
+        let filter = raw
+            .filter
+            .map(|filter| filter.trim().to_lowercase())
+            .filter(|filter| !filter.is_empty());
+        if filter.is_some() && TListing::FILTER_FIELD.is_none() {
+            return invalid("this list can't be filtered".to_string());
+        }
+
+        let include_deleted = raw.include_deleted.unwrap_or(false);
+        if include_deleted && !TListing::INCLUDES_DELETED {
+            return invalid("this list never includes deleted items".to_string());
+        }
+
+        let after = match raw.cursor {
+            None => None,
+            Some(_) if !TListing::CURSORS || include_deleted => {
+                return invalid("this list can't be paged with a cursor".to_string());
+            }
+            Some(_) if raw.offset.is_some() => {
+                return invalid("a cursor can't be combined with an offset".to_string());
+            }
+            Some(cursor) => match decode_cursor(&cursor) {
+                Some(after) => Some(after),
+                None => return invalid("cursor is not one this list returned".to_string()),
+            },
+        };
+
+        Ok(ListParams {
+            offset,
+            limit,
+            sort,
+            filter,
+            include_deleted,
+            after,
+        })
+    }
+
+    pub fn matches(&self, value: Option<&str>) -> bool {
+        match &self.filter {
+            None => true,
+            Some(filter) => value.is_some_and(|value| value.to_lowercase().contains(filter)),
+        }
+    }
+
+    // The page of an already sorted and filtered list
+    pub fn page<T>(&self, items: Vec<T>) -> Vec<T> {
+        items
+            .into_iter()
+            .skip(self.offset as usize)
+            .take(self.limit as usize)
+            .collect()
+    }
+}
+
+// Rejects an invalid query string with a 400 and the error envelope, so every list endpoint
+// answers the same way
+pub struct ListQuery<TListing: Listing> {
+    pub params: ListParams,
+    listing: PhantomData<TListing>,
+}
+
+impl<TListing: Listing> FromRequestParts<Arc<AppState>> for ListQuery<TListing> {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let Query(raw) = Query::<RawListParams>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| {
+                error_response(&state.metrics, ApplicationError::InvalidRequest(e.body_text()))
+            })?;
+
+        match ListParams::parse::<TListing>(raw) {
+            Ok(params) => Ok(ListQuery {
+                params,
+                listing: PhantomData,
+            }),
+            Err(e) => Err(error_response(&state.metrics, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_empty_query_should_give_the_first_page_in_the_default_order() {
+        let params = ListParams::parse::<SessionListing>(RawListParams::default()).unwrap();
+
+        assert_eq!(
+            params,
+            ListParams {
+                offset: 0,
+                limit: DEFAULT_PAGE_SIZE,
+                sort: "-lastSeenAt",
+                filter: None,
+                include_deleted: false,
+                after: None,
+            }
+        );
+    }
+
+    #[test]
+    fn out_of_range_pages_and_unknown_sorts_should_be_rejected() {
+        let parse = |offset, limit, sort: &str| {
+            ListParams::parse::<SessionListing>(RawListParams {
+                offset: Some(offset),
+                limit: Some(limit),
+                sort: Some(sort.to_string()),
+                filter: None,
+                include_deleted: None,
+                cursor: None,
+            })
+        };
+
+        assert!(parse(0, MAX_PAGE_SIZE, "createdAt").is_ok());
+        assert!(matches!(
+            parse(0, MAX_PAGE_SIZE + 1, "createdAt"),
+            Err(ApplicationError::InvalidRequest(_))
+        ));
+        assert!(parse(0, 0, "createdAt").is_err());
+        assert!(parse(-1, 10, "createdAt").is_err());
+        assert!(parse(0, 10, "password").is_err());
+    }
+
+    #[test]
+    fn a_filter_should_only_be_accepted_by_listings_that_support_one() {
+        let raw = || RawListParams {
+            filter: Some(" Firefox ".to_string()),
+            ..RawListParams::default()
+        };
+
+        let sessions = ListParams::parse::<SessionListing>(raw()).unwrap();
+
+        assert!(ListParams::parse::<UserListing>(raw()).is_err());
+        assert!(sessions.matches(Some("Mozilla/5.0 Firefox/131.0")));
+        assert!(!sessions.matches(Some("curl/8.0")));
+        assert!(!sessions.matches(None));
+    }
+
+    #[test]
+    fn deleted_items_should_only_be_included_by_listings_that_keep_them() {
+        let raw = || RawListParams {
+            include_deleted: Some(true),
+            ..RawListParams::default()
+        };
+
+        assert!(ListParams::parse::<UserListing>(raw()).unwrap().include_deleted);
+        assert!(matches!(
+            ListParams::parse::<SessionListing>(raw()),
+            Err(ApplicationError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn a_cursor_should_only_be_accepted_on_its_own_by_listings_that_seek() {
+        let raw = |cursor: &str| RawListParams {
+            cursor: Some(cursor.to_string()),
+            ..RawListParams::default()
+        };
+
+        let params = ListParams::parse::<UserListing>(raw(&cursor("test@test.com"))).unwrap();
+
+        assert_eq!(params.after.as_deref(), Some("test@test.com"));
+        assert!(ListParams::parse::<UserListing>(raw("not a cursor")).is_err());
+        assert!(ListParams::parse::<SessionListing>(raw(&cursor("test@test.com"))).is_err());
+        assert!(ListParams::parse::<UserListing>(RawListParams {
+            offset: Some(20),
+            ..raw(&cursor("test@test.com"))
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn a_page_should_skip_the_offset_and_stop_at_the_limit() {
+        let params = ListParams {
+            offset: 1,
+            limit: 2,
+            sort: "emailAddress",
+            filter: None,
+            include_deleted: false,
+            after: None,
+        };
+
+        assert_eq!(params.page(vec![1, 2, 3, 4]), vec![2, 3]);
+    }
+}
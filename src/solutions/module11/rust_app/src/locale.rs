@@ -0,0 +1,128 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::{header, HeaderValue};
+use axum::response::{IntoResponseParts, ResponseParts};
+
+use crate::auth::SessionClaims;
+use crate::core::UserPreferences;
+use crate::AppState;
+
+// What a response is given in when neither the request nor the user says otherwise, the same as
+// a new user's `locale` preference
+pub const DEFAULT_LOCALE: &str = "en";
+
+// The locale a response is given in. The `Accept-Language` header comes first, as the browser
+// knows best what its user reads right now, then the `locale` preference of the signed in user,
+// then `DEFAULT_LOCALE`. Returned as part of the response it sets `Content-Language`, and `Vary`
+// so a cache doesn't hand one reader's language to another.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AcceptLanguage(pub String);
+
+impl AcceptLanguage {
+    pub fn resolve(header: Option<&str>, preference: Option<&str>) -> Self {
+        let preference = preference.filter(|locale| UserPreferences::is_language_tag(locale));
+        let locale = header
+            .and_then(preferred)
+            .or_else(|| preference.map(str::to_string))
+            .unwrap_or_else(|| DEFAULT_LOCALE.to_string());
+
+        Self(locale)
+    }
+}
+
+// The language tag with the highest weight, the first one among equals. A weight of 0 means "not
+// this one" and `*` names no language in particular, so neither is picked.
+fn preferred(header: &str) -> Option<String> {
+    header
+        .split(',')
+        .filter_map(|entry| {
+            let mut parameters = entry.split(';').map(str::trim);
+            let tag = parameters.next()?;
+            let weight = parameters
+                .find_map(|parameter| parameter.strip_prefix("q="))
+                .map_or(Some(1.0), |weight| weight.parse::<f32>().ok())?;
+
+            (tag != "*" && weight > 0.0 && UserPreferences::is_language_tag(tag))
+                .then(|| (tag.to_string(), weight))
+        })
+        .fold(None::<(String, f32)>, |best, (tag, weight)| match best {
+            Some((_, best_weight)) if best_weight >= weight => best,
+            _ => Some((tag, weight)),
+        })
+        .map(|(tag, _)| tag)
+}
+
+// The preference is only looked up for requests that come with a session, claims are put on the
+// request by `require_session`. An unreadable preference falls back to the default rather than
+// failing the request over its language.
+impl FromRequestParts<Arc<AppState>> for AcceptLanguage {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let header = parts
+            .headers
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok());
+        let claims = parts.extensions.get::<SessionClaims>();
+        let preference = match (header.and_then(preferred), claims) {
+            (None, Some(claims)) => match state.data_access.preferences(&claims.sub).await {
+                Ok(preferences) => Some(preferences.locale),
+                Err(e) => {
+                    log::warn!("Could not read the locale preference, using the default: {:?}", e);
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        let locale = AcceptLanguage::resolve(header, preference.as_deref());
+        tracing::Span::current().record("user.locale", locale.0.as_str());
+        Ok(locale)
+    }
+}
+
+impl IntoResponseParts for AcceptLanguage {
+    type Error = Infallible;
+
+    fn into_response_parts(self, mut parts: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        if let Ok(locale) = HeaderValue::from_str(&self.0) {
+            parts.headers_mut().insert(header::CONTENT_LANGUAGE, locale);
+        }
+        parts
+            .headers_mut()
+            .append(header::VARY, HeaderValue::from_static("accept-language"));
+
+        Ok(parts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_header_should_come_before_the_preference_and_the_preference_before_the_default() {
+        let resolve = |header, preference| AcceptLanguage::resolve(header, preference).0;
+
+        assert_eq!(resolve(Some("pt-BR"), Some("de")), "pt-BR");
+        assert_eq!(resolve(None, Some("de")), "de");
+        assert_eq!(resolve(Some("*"), Some("de")), "de");
+        assert_eq!(resolve(None, Some("not a locale")), DEFAULT_LOCALE);
+        assert_eq!(resolve(None, None), DEFAULT_LOCALE);
+    }
+
+    #[test]
+    fn the_language_with_the_highest_weight_should_be_picked() {
+        assert_eq!(preferred("fr;q=0.5, de, en;q=0.8").as_deref(), Some("de"));
+        assert_eq!(preferred("fr;q=0.5, en-GB;q=0.8").as_deref(), Some("en-GB"));
+        assert_eq!(preferred("fr, de").as_deref(), Some("fr"));
+        assert_eq!(preferred("de;q=0, *;q=0.5"), None);
+        assert_eq!(preferred("fr;q=high, de;q=0.1").as_deref(), Some("de"));
+    }
+}
@@ -0,0 +1,187 @@
+use std::sync::Arc;
+
+use axum::http::{header, HeaderMap};
+
+use crate::auth::forwarded_ip;
+use crate::core::{ApplicationError, Config, Profile, StoreKind, User};
+use crate::data_access::{
+    DeviceSighting, InMemoryLoginHistory, LoginHistory, LoginRecord, OutboxEntry,
+    PostgresLoginHistory,
+};
+use crate::events::{NewDeviceLoginEvent, NotificationEmail};
+use crate::metrics::USER_NEW_DEVICE_LOGIN_TOTAL;
+use crate::partitioning;
+use crate::AppState;
+
+pub const NEW_DEVICE_SUBJECT: &str = "New sign in to your account";
+
+// Alerts are only sent through the outbox, so outside the local profile, where the sandbox shows
+// them, login alerts need the Postgres store with the outbox enabled. Anything else is refused
+// rather than silently never telling anyone.
+fn check_login_alerts_config(config: &Config) -> Result<(), ApplicationError> {
+    match (config.login_alerts_store(), config.outbox_enabled(), config.profile()) {
+        (StoreKind::Postgres, true, _) | (StoreKind::Memory, _, Profile::Local) => Ok(()),
+        _ => Err(ApplicationError::ApplicationError(
+            "login_alerts needs store \"postgres\" and outbox.enabled, the in-memory store is only for the local profile".to_string(),
+        )),
+    }
+}
+
+pub async fn login_history_from_config(
+    config: &Config,
+) -> Result<Option<Arc<dyn LoginHistory>>, ApplicationError> {
+    if !config.login_alerts_enabled() {
+        return Ok(None);
+    }
+    check_login_alerts_config(config)?;
+
+    match config.login_alerts_store() {
+        StoreKind::Memory => Ok(Some(Arc::new(InMemoryLoginHistory::default()))),
+        StoreKind::Postgres => {
+            let db = crate::data_access::connect(
+                &config.connection_string(),
+                &crate::data_access::PoolSettings::from_config(config),
+            )
+            .await?;
+
+            Ok(Some(Arc::new(PostgresLoginHistory::new(db))))
+        }
+    }
+}
+
+fn new_device_email(user: &User, login: &LoginRecord) -> NotificationEmail {
+    NotificationEmail {
+        email_id: uuid::Uuid::new_v4().to_string(),
+        partition_key: partitioning::user_key(user.id()),
+        recipient: login.email_address.clone(),
+        subject: NEW_DEVICE_SUBJECT.to_string(),
+        body: format!(
+            "Your account was signed in to from {} ({}). If this wasn't you, change your password and sign out of your other sessions.",
+            login.ip_address.as_deref().unwrap_or("an unknown address"),
+            login.user_agent.as_deref().unwrap_or("an unknown browser"),
+        ),
+    }
+}
+
+// The event and the email for a login from a new device, enqueued only if the history finds the
+// device is new
+fn new_device_alert(
+    user: &User,
+    login: &LoginRecord,
+) -> Result<Vec<OutboxEntry>, ApplicationError> {
+    let trace_context = crate::outbox::current_trace_context();
+    let event = NewDeviceLoginEvent {
+        event_id: uuid::Uuid::new_v4().to_string(),
+        partition_key: partitioning::user_key(user.id()),
+        email_address: login.email_address.clone(),
+        ip_address: login.ip_address.clone(),
+        user_agent: login.user_agent.clone(),
+        logged_in_at: login.logged_in_at,
+    };
+
+    Ok(vec![
+        event.into_outbox_entry(trace_context.clone(), login.logged_in_at)?,
+        new_device_email(user, login).into_outbox_entry(trace_context, login.logged_in_at)?,
+    ])
+}
+
+// Called once a login has succeeded. Failing to record it is logged rather than failing the login.
+pub async fn check_login(
+    state: &AppState,
+    headers: &HeaderMap,
+    user: &User,
+) -> Option<DeviceSighting> {
+    let login_history = state.login_history.as_ref()?;
+
+    let login = LoginRecord {
+        email_address: user.email_address(),
+        ip_address: forwarded_ip(headers).map(str::to_string),
+        user_agent: headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        logged_in_at: state.sessions.clock().now(),
+    };
+
+    let sighting = match new_device_alert(user, &login) {
+        Ok(alert) => login_history.record(&login, alert).await,
+        Err(e) => Err(e),
+    };
+    let sighting = match sighting {
+        Ok(sighting) => sighting,
+        Err(e) => {
+            log::error!("{:?}", e);
+            return None;
+        }
+    };
+
+    if sighting == DeviceSighting::NewDevice {
+        log::warn!("new-device-login: {} logged in from a new device", login.email_address);
+        tracing::warn!(
+            name: "new-device-login",
+            ip_address = login.ip_address.as_deref(),
+            user_agent = login.user_agent.as_deref()
+        );
+        state.metrics.increment(USER_NEW_DEVICE_LOGIN_TOTAL);
+
+        if let Some(sandbox) = &state.sandbox {
+            let email = new_device_email(user, &login);
+            sandbox.capture("email", &email.recipient, &email.subject, &email.body);
+        }
+    }
+
+    Some(sighting)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{NEW_DEVICE_LOGIN_TOPIC, NOTIFICATION_EMAIL_TOPIC};
+
+    fn config(value: serde_json::Value) -> Config {
+        let mut config = serde_json::json!({
+            "database": { "connection_string": "postgresql://localhost/users" },
+            "login_alerts": { "enabled": true },
+        });
+        config.as_object_mut().unwrap().extend(value.as_object().unwrap().clone());
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[test]
+    fn login_alerts_should_only_be_allowed_where_they_are_sent() {
+        let memory = serde_json::json!({ "enabled": true, "store": "memory" });
+        let postgres = serde_json::json!({ "enabled": true, "store": "postgres" });
+
+        assert!(check_login_alerts_config(&config(serde_json::json!({
+            "login_alerts": postgres, "outbox": { "enabled": true }
+        })))
+        .is_ok());
+        assert!(check_login_alerts_config(&config(serde_json::json!({
+            "login_alerts": memory, "profile": "local"
+        })))
+        .is_ok());
+
+        assert!(check_login_alerts_config(&config(serde_json::json!({ "login_alerts": postgres }))).is_err());
+        assert!(check_login_alerts_config(&config(serde_json::json!({
+            "login_alerts": memory, "outbox": { "enabled": true }
+        })))
+        .is_err());
+    }
+
+    #[test]
+    fn the_alert_should_be_the_event_and_the_email_keyed_by_the_user() {
+        let user = User::from("test@test.com", "James", "hashed");
+        let login = LoginRecord {
+            email_address: "test@test.com".to_string(),
+            ip_address: Some("10.0.0.1".to_string()),
+            user_agent: Some("curl".to_string()),
+            logged_in_at: 0,
+        };
+
+        let alert = new_device_alert(&user, &login).unwrap();
+        let topics: Vec<&str> = alert.iter().map(|entry| entry.topic.as_str()).collect();
+
+        assert_eq!(topics, vec![NEW_DEVICE_LOGIN_TOPIC, NOTIFICATION_EMAIL_TOPIC]);
+        assert!(alert.iter().all(|entry| entry.key == user.id().to_string()));
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::RwLock;
+
+pub const USER_REGISTERED_TOTAL: &str = "user_registered_total";
+pub const USER_REGISTRATION_FAILED_TOTAL: &str = "user_registration_failed_total";
+pub const USER_LOGIN_TOTAL: &str = "user_login_total";
+pub const USER_LOGIN_FAILED_TOTAL: &str = "user_login_failed_total";
+pub const USER_LOGIN_THROTTLED_TOTAL: &str = "user_login_throttled_total";
+pub const BRUTE_FORCE_DETECTED_TOTAL: &str = "brute_force_detected_total";
+pub const RATE_ANOMALY_DETECTED_TOTAL: &str = "rate_anomaly_detected_total";
+pub const USER_NEW_DEVICE_LOGIN_TOTAL: &str = "user_new_device_login_total";
+pub const HTTP_CACHE_HITS_TOTAL: &str = "http_cache_hits_total";
+pub const HTTP_CACHE_MISSES_TOTAL: &str = "http_cache_misses_total";
+pub const HTTP_CORS_REJECTED_TOTAL: &str = "http_cors_rejected_total";
+pub const HTTP_COALESCED_REQUESTS_TOTAL: &str = "http_coalesced_requests_total";
+pub const DB_TABLE_DEAD_TUPLES: &str = "db_table_dead_tuples";
+pub const DB_MAINTENANCE_RECOMMENDATIONS_TOTAL: &str = "db_maintenance_recommendations_total";
+pub const DB_STATEMENT_EXECUTIONS_TOTAL: &str = "db_statement_executions_total";
+pub const DB_STATEMENT_PREPARES_TOTAL: &str = "db_statement_prepares_total";
+pub const DB_POOL_CONNECTIONS: &str = "db_pool_connections";
+pub const DB_POOL_IDLE_CONNECTIONS: &str = "db_pool_idle_connections";
+pub const DB_POOL_ACQUIRE_WAIT_SECONDS: &str = "db_pool_acquire_wait_seconds";
+pub const DB_POOL_ACQUIRE_ERRORS_TOTAL: &str = "db_pool_acquire_errors_total";
+pub const OUTBOX_PENDING_EVENTS: &str = "outbox_pending_events";
+pub const OUTBOX_OLDEST_PENDING_SECONDS: &str = "outbox_oldest_pending_seconds";
+pub const APPLICATION_ERRORS_TOTAL: &str = "application_errors_total";
+pub const HTTP_OPEN_CONNECTIONS: &str = "http_open_connections";
+pub const HTTP_REQUESTS_IN_FLIGHT: &str = "http_requests_in_flight";
+pub const HTTP_REQUESTS_TOTAL: &str = "http_requests_total";
+pub const WORKER_MESSAGES_CONSUMED_TOTAL: &str = "worker_messages_consumed_total";
+pub const USER_MIGRATION_DIVERGENCE_TOTAL: &str = "user_migration_divergence_total";
+pub const USER_WRITE_BEHIND_PENDING: &str = "user_write_behind_pending";
+pub const USER_WRITE_BEHIND_LOST_TOTAL: &str = "user_write_behind_lost_total";
+pub const USER_CACHE_HITS_TOTAL: &str = "user_cache_hits_total";
+pub const USER_CACHE_MISSES_TOTAL: &str = "user_cache_misses_total";
+
+type Labels = Vec<(&'static str, String)>;
+
+// A point in time copy of every series, for tests to assert on values without parsing the
+// Prometheus text. Take one before and one after the code under test and compare.
+#[derive(Clone, Debug, Default)]
+pub struct MetricsSnapshot {
+    counters: BTreeMap<&'static str, BTreeMap<Labels, u64>>,
+    gauges: BTreeMap<&'static str, BTreeMap<Labels, f64>>,
+}
+
+impl MetricsSnapshot {
+    // Summed across all of the counter's label sets
+    pub fn counter(&self, name: &str) -> u64 {
+        self.counters
+            .get(name)
+            .map(|series| series.values().sum())
+            .unwrap_or(0)
+    }
+
+    pub fn counter_with_labels(&self, name: &str, labels: &[(&'static str, &str)]) -> u64 {
+        self.counters
+            .get(name)
+            .and_then(|series| series.get(&to_labels(labels)).copied())
+            .unwrap_or(0)
+    }
+
+    pub fn gauge(&self, name: &str, labels: &[(&'static str, &str)]) -> Option<f64> {
+        self.gauges
+            .get(name)
+            .and_then(|series| series.get(&to_labels(labels)).copied())
+    }
+
+    // How much a counter grew between `earlier` and this snapshot
+    pub fn counter_increase(&self, earlier: &MetricsSnapshot, name: &str) -> u64 {
+        self.counter(name).saturating_sub(earlier.counter(name))
+    }
+}
+
+// A minimal in-process metrics registry rendered in the Prometheus text format on `/metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    counters: RwLock<BTreeMap<&'static str, BTreeMap<Labels, u64>>>,
+    gauges: RwLock<BTreeMap<&'static str, BTreeMap<Labels, f64>>>,
+}
+
+fn to_labels(labels: &[(&'static str, &str)]) -> Labels {
+    labels
+        .iter()
+        .map(|(key, value)| (*key, value.to_string()))
+        .collect()
+}
+
+impl Metrics {
+    pub fn increment(&self, name: &'static str) {
+        self.increment_with_labels(name, &[]);
+    }
+
+    pub fn increment_with_labels(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+        let mut counters = self.counters.write().unwrap();
+        *counters
+            .entry(name)
+            .or_default()
+            .entry(to_labels(labels))
+            .or_insert(0) += 1;
+    }
+
+    pub fn set_gauge(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        let mut gauges = self.gauges.write().unwrap();
+        gauges
+            .entry(name)
+            .or_default()
+            .insert(to_labels(labels), value);
+    }
+
+    pub fn gauge(&self, name: &str, labels: &[(&'static str, &str)]) -> Option<f64> {
+        self.gauges
+            .read()
+            .unwrap()
+            .get(name)
+            .and_then(|series| series.get(&to_labels(labels)).copied())
+    }
+
+    // The current value of a counter, summed across all of its label sets
+    pub fn counter(&self, name: &str) -> u64 {
+        self.counters
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|series| series.values().sum())
+            .unwrap_or(0)
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            counters: self.counters.read().unwrap().clone(),
+            gauges: self.gauges.read().unwrap().clone(),
+        }
+    }
+
+    pub fn render_prometheus(&self) -> String {
+        let counters = self.counters.read().unwrap();
+        let mut output = String::new();
+
+        for (name, series) in counters.iter() {
+            let _ = writeln!(output, "# TYPE {} counter", name);
+            for (labels, value) in series {
+                let _ = writeln!(output, "{}{} {}", name, format_labels(labels), value);
+            }
+        }
+
+        let gauges = self.gauges.read().unwrap();
+        for (name, series) in gauges.iter() {
+            let _ = writeln!(output, "# TYPE {} gauge", name);
+            for (labels, value) in series {
+                let _ = writeln!(output, "{}{} {}", name, format_labels(labels), value);
+            }
+        }
+
+        output
+    }
+}
+
+fn format_labels(labels: &Labels) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, value.replace('"', "\\\"")))
+        .collect();
+
+    format!("{{{}}}", pairs.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn when_a_counter_is_incremented_should_sum_across_labels() {
+        let metrics = Metrics::default();
+
+        metrics.increment(USER_LOGIN_FAILED_TOTAL);
+        metrics.increment_with_labels(USER_LOGIN_FAILED_TOTAL, &[("reason", "bad_password")]);
+
+        assert_eq!(metrics.counter(USER_LOGIN_FAILED_TOTAL), 2);
+        assert_eq!(metrics.counter(USER_REGISTERED_TOTAL), 0);
+    }
+
+    #[test]
+    fn a_snapshot_should_not_change_when_counters_are_incremented_later() {
+        let metrics = Metrics::default();
+        metrics.increment(USER_REGISTERED_TOTAL);
+
+        let before = metrics.snapshot();
+        metrics.increment(USER_REGISTERED_TOTAL);
+        metrics.increment_with_labels(USER_LOGIN_FAILED_TOTAL, &[("reason", "bad_password")]);
+        let after = metrics.snapshot();
+
+        assert_eq!(before.counter(USER_REGISTERED_TOTAL), 1);
+        assert_eq!(after.counter_increase(&before, USER_REGISTERED_TOTAL), 1);
+        assert_eq!(
+            after.counter_with_labels(USER_LOGIN_FAILED_TOTAL, &[("reason", "bad_password")]),
+            1
+        );
+    }
+
+    #[test]
+    fn should_render_counters_in_prometheus_format() {
+        let metrics = Metrics::default();
+
+        metrics.increment(USER_REGISTERED_TOTAL);
+        metrics.increment_with_labels(RATE_ANOMALY_DETECTED_TOTAL, &[("signal", "login_failures")]);
+
+        let output = metrics.render_prometheus();
+
+        assert!(output.contains("# TYPE user_registered_total counter\nuser_registered_total 1\n"));
+        assert!(output.contains("rate_anomaly_detected_total{signal=\"login_failures\"} 1"));
+    }
+
+    #[test]
+    fn when_a_gauge_is_set_should_keep_the_latest_value() {
+        let metrics = Metrics::default();
+
+        metrics.set_gauge(DB_TABLE_DEAD_TUPLES, &[("table", "users")], 10.0);
+        metrics.set_gauge(DB_TABLE_DEAD_TUPLES, &[("table", "users")], 4.0);
+
+        assert_eq!(metrics.gauge(DB_TABLE_DEAD_TUPLES, &[("table", "users")]), Some(4.0));
+        assert!(metrics
+            .render_prometheus()
+            .contains("# TYPE db_table_dead_tuples gauge\ndb_table_dead_tuples{table=\"users\"} 4\n"));
+    }
+}
@@ -0,0 +1,217 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::clock::Clock;
+use crate::core::{ApplicationError, Config};
+use crate::data_access::{OutboxBacklog, PostgresOutbox};
+use crate::events::EventPublisher;
+use crate::metrics::{Metrics, OUTBOX_OLDEST_PENDING_SECONDS, OUTBOX_PENDING_EVENTS};
+
+#[derive(Clone, Debug)]
+pub struct OutboxSettings {
+    pub interval: Duration,
+    pub batch_size: i64,
+}
+
+impl OutboxSettings {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            interval: Duration::from_millis(config.outbox_poll_interval_ms()),
+            batch_size: config.outbox_batch_size(),
+        }
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+// The W3C `traceparent` for a span, e.g. `00-<trace id>-<span id>-01`
+pub fn traceparent(span_context: &SpanContext) -> Option<String> {
+    if !span_context.is_valid() {
+        return None;
+    }
+
+    Some(format!(
+        "00-{:032x}-{:016x}-{:02x}",
+        span_context.trace_id(),
+        span_context.span_id(),
+        span_context.trace_flags().to_u8()
+    ))
+}
+
+pub fn parse_traceparent(traceparent: &str) -> Option<SpanContext> {
+    let mut parts = traceparent.split('-');
+    let (Some("00"), Some(trace_id), Some(span_id), Some(flags), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return None;
+    };
+
+    let span_context = SpanContext::new(
+        TraceId::from_hex(trace_id).ok()?,
+        SpanId::from_hex(span_id).ok()?,
+        TraceFlags::new(u8::from_str_radix(flags, 16).ok()?),
+        true,
+        TraceState::default(),
+    );
+
+    span_context.is_valid().then_some(span_context)
+}
+
+// The trace context to store with an outbox entry written from the current span
+pub fn current_trace_context() -> Option<String> {
+    traceparent(tracing::Span::current().context().span().span_context())
+}
+
+// Publishes what `store` wrote to the outbox. Entries are marked as published after the broker
+// acknowledged them, so a crash in between publishes them again, consumers de-duplicate on the
+// event id.
+pub async fn run_outbox_relay(
+    outbox: PostgresOutbox,
+    publisher: Arc<dyn EventPublisher>,
+    settings: OutboxSettings,
+) {
+    log::info!("Starting the outbox relay every {:?}", settings.interval);
+
+    let mut interval = tokio::time::interval(settings.interval);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = relay_batch(&outbox, publisher.as_ref(), settings.batch_size).await {
+            log::warn!("Unable to relay the outbox: {:?}", e);
+        }
+    }
+}
+
+// The relay runs in the worker, which serves no metrics, so the API records how far behind it is.
+// An oldest pending event that keeps getting older is a relay that isn't running or a broker it
+// can't reach, the count is how many events are waiting for it. Nothing is dropped meanwhile.
+pub async fn run_outbox_statistics(
+    outbox: PostgresOutbox,
+    metrics: Arc<Metrics>,
+    clock: Arc<Clock>,
+    interval: Duration,
+) {
+    log::info!("Recording the outbox backlog every {:?}", interval);
+
+    let database = outbox.database();
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        match outbox.backlog().await {
+            Ok(backlog) => record_backlog(&metrics, &database, &backlog, clock.now()),
+            Err(e) => log::warn!("Unable to read the outbox backlog: {:?}", e),
+        }
+    }
+}
+
+// Entries are dated by the API's clock, so `now` is too
+fn record_backlog(metrics: &Metrics, database: &str, backlog: &OutboxBacklog, now: u64) {
+    let labels = [("database", database)];
+    let oldest = backlog
+        .oldest_created_at
+        .map_or(0, |created_at| now.saturating_sub(created_at));
+
+    metrics.set_gauge(OUTBOX_PENDING_EVENTS, &labels, backlog.pending as f64);
+    metrics.set_gauge(OUTBOX_OLDEST_PENDING_SECONDS, &labels, oldest as f64);
+}
+
+async fn relay_batch(
+    outbox: &PostgresOutbox,
+    publisher: &dyn EventPublisher,
+    batch_size: i64,
+) -> Result<(), ApplicationError> {
+    for entry in outbox.pending(batch_size).await? {
+        // A new trace for the asynchronous leg, linked to the request that wrote the entry rather
+        // than parented by it, as the request finished long before
+        let span = tracing::info_span!(
+            parent: None,
+            "outbox.publish",
+            messaging.destination.name = %entry.topic,
+            messaging.message.id = %entry.id,
+        );
+        if let Some(span_context) = entry.trace_context.as_deref().and_then(parse_traceparent) {
+            span.add_link(span_context);
+        }
+
+        // Stop at the first failure so later events for the same key aren't published first
+        publisher
+            .publish(&entry.topic, &entry.key, &entry.payload)
+            .instrument(span)
+            .await?;
+        outbox.mark_published(&entry.id, now()).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_traceparent_should_round_trip_to_the_same_span_context() {
+        let span_context = SpanContext::new(
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+            SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+            TraceFlags::SAMPLED,
+            false,
+            TraceState::default(),
+        );
+
+        let traceparent = traceparent(&span_context).unwrap();
+        let parsed = parse_traceparent(&traceparent).unwrap();
+
+        assert_eq!(traceparent, "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+        assert_eq!(parsed.trace_id(), span_context.trace_id());
+        assert_eq!(parsed.span_id(), span_context.span_id());
+        assert!(parsed.is_sampled());
+        assert!(parsed.is_remote());
+    }
+
+    #[test]
+    fn an_invalid_traceparent_should_not_produce_a_link() {
+        let zero_trace_id = "00-00000000000000000000000000000000-00f067aa0ba902b7-01";
+        let unknown_version = "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+        assert!(parse_traceparent(zero_trace_id).is_none());
+        assert!(parse_traceparent(unknown_version).is_none());
+        assert!(parse_traceparent("not a traceparent").is_none());
+        assert!(traceparent(&SpanContext::empty_context()).is_none());
+    }
+
+    #[test]
+    fn the_backlog_should_be_recorded_as_its_size_and_oldest_events_age() {
+        let metrics = Metrics::default();
+        let labels = [("database", "localhost:5432/users")];
+        let waiting = OutboxBacklog {
+            pending: 3,
+            oldest_created_at: Some(1_000),
+        };
+
+        record_backlog(&metrics, "localhost:5432/users", &waiting, 1_090);
+        let behind = metrics.snapshot();
+        record_backlog(&metrics, "localhost:5432/users", &OutboxBacklog::default(), 1_100);
+        let caught_up = metrics.snapshot();
+
+        assert_eq!(behind.gauge(OUTBOX_PENDING_EVENTS, &labels), Some(3.0));
+        assert_eq!(behind.gauge(OUTBOX_OLDEST_PENDING_SECONDS, &labels), Some(90.0));
+        assert_eq!(caught_up.gauge(OUTBOX_PENDING_EVENTS, &labels), Some(0.0));
+        assert_eq!(caught_up.gauge(OUTBOX_OLDEST_PENDING_SECONDS, &labels), Some(0.0));
+    }
+}
@@ -0,0 +1,61 @@
+use uuid::Uuid;
+
+// Every event about a user is keyed by their id rather than their address, which changes when an
+// alias becomes their primary one. Events with the same key land on the same partition, so
+// consumers see each user's events in the order they were produced.
+pub fn user_key(user_id: Uuid) -> String {
+    user_id.to_string()
+}
+
+// FNV-1a is used instead of the std hasher because placement must stay stable across processes
+// and compiler versions, for shards as much as for partitions.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Jump consistent hashing (Lamping and Veach), so adding partitions to a topic only moves the keys
+// that the new partitions take, about 1 in `partitions`, where a plain modulo moves nearly all of
+// them and reorders those users' events while consumers catch up.
+pub fn partition_for(key: &str, partitions: u32) -> u32 {
+    let mut hash = fnv1a(key.as_bytes());
+    let mut bucket: i64 = -1;
+    let mut next: i64 = 0;
+    while next < partitions.max(1) as i64 {
+        bucket = next;
+        hash = hash.wrapping_mul(2862933555777941757).wrapping_add(1);
+        next = ((bucket + 1) as f64 * ((1u64 << 31) as f64 / ((hash >> 33) + 1) as f64)) as i64;
+    }
+
+    bucket as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Changing the hash would move every user's events to a different partition
+    #[test]
+    fn partitions_should_be_stable() {
+        assert_eq!(partition_for("james@test.com", 1), 0);
+        assert_eq!(partition_for("james@test.com", 12), 5);
+    }
+
+    #[test]
+    fn adding_a_partition_should_only_move_the_keys_it_takes() {
+        let keys: Vec<String> = (0..1000).map(|_| user_key(Uuid::new_v4())).collect();
+
+        let moved: Vec<&String> = keys
+            .iter()
+            .filter(|key| partition_for(key, 10) != partition_for(key, 11))
+            .collect();
+
+        assert!(moved.iter().all(|key| partition_for(key, 11) == 10));
+        assert!(moved.len() < 150, "{} of 1000 keys moved", moved.len());
+        assert!(keys.iter().all(|key| partition_for(key, 11) < 11));
+    }
+}
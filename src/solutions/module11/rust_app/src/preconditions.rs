@@ -0,0 +1,67 @@
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+
+// The ETag for a stored version of a user, `"v3"` for version 3
+pub fn version_etag(version: i64) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"v{}\"", version)).expect("a version is a valid header value")
+}
+
+// The version a write is conditional on, read from `If-Match`. `*` matches whatever is stored, so
+// gives `None`. A missing header is a 428, writes must say which version they were based on. A tag
+// this service never issued can't match anything that is stored, so is a 412. Only a single tag is
+// understood, clients send back the one ETag they read.
+pub fn expected_version(headers: &HeaderMap) -> Result<Option<i64>, StatusCode> {
+    let if_match = headers
+        .get(header::IF_MATCH)
+        .ok_or(StatusCode::PRECONDITION_REQUIRED)?
+        .to_str()
+        .map_err(|_| StatusCode::PRECONDITION_FAILED)?
+        .trim();
+
+    if if_match == "*" {
+        return Ok(None);
+    }
+
+    if_match
+        .strip_prefix("\"v")
+        .and_then(|tag| tag.strip_suffix('"'))
+        .and_then(|version| version.parse().ok())
+        .map(Some)
+        .ok_or(StatusCode::PRECONDITION_FAILED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn if_match(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_MATCH, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn if_match_should_be_parsed_into_the_expected_version() {
+        assert_eq!(expected_version(&if_match(r#""v3""#)), Ok(Some(3)));
+        assert_eq!(expected_version(&if_match("*")), Ok(None));
+        assert_eq!(
+            expected_version(&if_match(r#"W/"v3""#)),
+            Err(StatusCode::PRECONDITION_FAILED)
+        );
+        assert_eq!(
+            expected_version(&if_match(r#""0123456789abcdef""#)),
+            Err(StatusCode::PRECONDITION_FAILED)
+        );
+        assert_eq!(
+            expected_version(&HeaderMap::new()),
+            Err(StatusCode::PRECONDITION_REQUIRED)
+        );
+    }
+
+    #[test]
+    fn the_etag_for_a_version_should_satisfy_if_match_for_that_version() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_MATCH, version_etag(7));
+
+        assert_eq!(expected_version(&headers), Ok(Some(7)));
+    }
+}
@@ -0,0 +1,27 @@
+// What consumers of the library need to build and serve the API themselves, `use
+// rust_users_lib::prelude::*;`. Names are only added here, the modules behind it can be
+// reorganised without breaking anyone who imports from the prelude.
+pub use crate::auth::{
+    InMemoryRevocations, MagicLinkSender, MagicLinks, RevocationStore, SessionManager,
+};
+pub use crate::avatars::Avatars;
+pub use crate::blobs::{BlobStore, FilesystemBlobStore, InMemoryBlobStore, S3BlobStore};
+pub use crate::clock::Clock;
+pub use crate::connections::ConnectionStats;
+pub use crate::consumer::{ConsumedMessage, HandlerRegistry, MessageHandler};
+pub use crate::core::{
+    AddEmailAliasRequest, ApplicationError, AuthMode, BufferMode, Config, DataAccess, EmailAlias,
+    LoginRequest, MagicLinkRequest, PatchUserRequest, Profile, RegisterUserRequest, Role,
+    SessionDetails, Theme, UpdateBirthdayRequest, UpdateUserRequest, UpdateUsernameRequest, User,
+    UserBuilder, UserDetails, UserPreferences, Versioned, WeakPasswordResponse,
+};
+pub use crate::data_access::{
+    ActiveSessions, AuditEntry, AuditLog, AuditedDataAccess, BufferSettings, BufferedUsers,
+    CacheSettings, CachedDataAccess, InMemoryActiveSessions, InMemoryAuditLog,
+    InMemoryMagicLinkTokens, InMemoryUserCache, InMemoryUsers, MigratingDataAccess, PoolSettings,
+    PostgresUsers, RedisUserCache, ShardedDataAccess, UserCache,
+};
+pub use crate::errors::ErrorResponse;
+pub use crate::metrics::Metrics;
+pub use crate::registration::{AllowAllRegistrations, RegistrationGuard};
+pub use crate::{router, serve_api, start_api, start_background_worker, AppState};
@@ -0,0 +1,258 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::core::{ApplicationError, ChallengeProvider, Config};
+use crate::retry::{RetryBudget, RetryPolicy};
+
+// Clients pass the token produced by the hCaptcha or Turnstile widget in this header
+pub const CHALLENGE_TOKEN_HEADER: &str = "x-challenge-token";
+
+pub struct RegistrationAttempt<'a> {
+    pub email_address: &'a str,
+    pub challenge_token: Option<&'a str>,
+    pub remote_ip: Option<&'a str>,
+}
+
+// Runs before a new user is created, so registrations can be rejected by checks that live outside
+// the user domain, like a CAPTCHA or an allow list of email domains.
+#[async_trait::async_trait]
+pub trait RegistrationGuard: Send + Sync {
+    async fn check(&self, attempt: &RegistrationAttempt<'_>) -> Result<(), ApplicationError>;
+}
+
+pub struct AllowAllRegistrations;
+
+#[async_trait::async_trait]
+impl RegistrationGuard for AllowAllRegistrations {
+    async fn check(&self, _attempt: &RegistrationAttempt<'_>) -> Result<(), ApplicationError> {
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+    #[serde(rename = "error-codes", default)]
+    error_codes: Vec<String>,
+}
+
+// hCaptcha and Cloudflare Turnstile share the same siteverify contract, a form post of the secret
+// and the client's token answered with `{"success": bool, "error-codes": [...]}`.
+pub struct ChallengeVerifier {
+    client: reqwest::Client,
+    verify_url: String,
+    secret: String,
+    retry: RetryPolicy,
+}
+
+impl ChallengeVerifier {
+    pub fn new(verify_url: &str, secret: &str) -> Result<Self, ApplicationError> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+
+        Ok(Self {
+            client,
+            verify_url: verify_url.to_string(),
+            secret: secret.to_string(),
+            // At most one retry for every ten verifications while the provider is struggling
+            retry: RetryPolicy::builder()
+                .max_attempts(3)
+                .base_delay(Duration::from_millis(100))
+                .max_delay(Duration::from_secs(1))
+                .budget(Arc::new(RetryBudget::new(0.1, 10)))
+                .build(),
+        })
+    }
+
+    pub fn default_verify_url(provider: ChallengeProvider) -> Option<&'static str> {
+        match provider {
+            ChallengeProvider::None => None,
+            ChallengeProvider::HCaptcha => Some("https://api.hcaptcha.com/siteverify"),
+            ChallengeProvider::Turnstile => {
+                Some("https://challenges.cloudflare.com/turnstile/v0/siteverify")
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RegistrationGuard for ChallengeVerifier {
+    async fn check(&self, attempt: &RegistrationAttempt<'_>) -> Result<(), ApplicationError> {
+        let token = attempt.challenge_token.ok_or_else(|| {
+            ApplicationError::RegistrationRejected("a challenge token is required".to_string())
+        })?;
+
+        let mut form = vec![("secret", self.secret.as_str()), ("response", token)];
+        if let Some(remote_ip) = attempt.remote_ip {
+            form.push(("remoteip", remote_ip));
+        }
+
+        let response: SiteVerifyResponse = self
+            .retry
+            .retry_if("challenge verification", is_transient, || async {
+                self.client
+                    .post(&self.verify_url)
+                    .form(&form)
+                    .send()
+                    .await?
+                    .error_for_status()
+            })
+            .await
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+
+        if response.success {
+            Ok(())
+        } else {
+            Err(ApplicationError::RegistrationRejected(format!(
+                "challenge verification failed: {}",
+                response.error_codes.join(", ")
+            )))
+        }
+    }
+}
+
+// Tokens can only be verified once, so a request that timed out may already have spent it and is
+// not retried. The provider being unreachable, overloaded or failing is.
+fn is_transient(error: &reqwest::Error) -> bool {
+    if error.is_connect() {
+        return true;
+    }
+
+    error.status().is_some_and(|status| {
+        status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+    })
+}
+
+pub fn registration_guard_from_config(
+    config: &Config,
+) -> Result<Arc<dyn RegistrationGuard>, ApplicationError> {
+    let provider = config.challenge_provider();
+
+    let Some(default_verify_url) = ChallengeVerifier::default_verify_url(provider) else {
+        return Ok(Arc::new(AllowAllRegistrations));
+    };
+
+    let secret = config.challenge_secret().unwrap_or_default();
+    if secret.is_empty() {
+        return Err(ApplicationError::ApplicationError(
+            "registration.challenge_secret must be set when a challenge provider is configured"
+                .to_string(),
+        ));
+    }
+
+    let verify_url = config
+        .challenge_verify_url()
+        .unwrap_or_else(|| default_verify_url.to_string());
+
+    Ok(Arc::new(ChallengeVerifier::new(&verify_url, &secret)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::{Form, Json, Router};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    // A stand-in for the provider's siteverify endpoint that accepts a single known token
+    async fn start_siteverify_stub() -> String {
+        let app = Router::new().route(
+            "/siteverify",
+            post(|Form(form): Form<HashMap<String, String>>| async move {
+                let success = form.get("secret").map(String::as_str) == Some("test-secret")
+                    && form.get("response").map(String::as_str) == Some("valid-token");
+
+                Json(serde_json::json!({
+                    "success": success,
+                    "error-codes": if success { vec![] } else { vec!["invalid-input-response"] },
+                }))
+            }),
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        format!("http://{}/siteverify", address)
+    }
+
+    // Fails every request before `failures` with `status`, then accepts the token
+    async fn start_failing_siteverify_stub(failures: u32, status: StatusCode) -> (String, Arc<AtomicU32>) {
+        let calls = Arc::new(AtomicU32::new(0));
+        let counted = calls.clone();
+        let app = Router::new().route(
+            "/siteverify",
+            post(move || {
+                let calls = counted.clone();
+                async move {
+                    match calls.fetch_add(1, Ordering::SeqCst) < failures {
+                        true => (status, Json(serde_json::json!({}))),
+                        false => (StatusCode::OK, Json(serde_json::json!({ "success": true }))),
+                    }
+                }
+            }),
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        (format!("http://{}/siteverify", address), calls)
+    }
+
+    fn attempt(challenge_token: Option<&str>) -> RegistrationAttempt<'_> {
+        RegistrationAttempt {
+            email_address: "test@test.com",
+            challenge_token,
+            remote_ip: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn when_the_provider_accepts_the_token_should_allow_registration() {
+        let verifier = ChallengeVerifier::new(&start_siteverify_stub().await, "test-secret").unwrap();
+
+        assert!(verifier.check(&attempt(Some("valid-token"))).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn when_the_token_is_missing_or_rejected_should_reject_registration() {
+        let verifier = ChallengeVerifier::new(&start_siteverify_stub().await, "test-secret").unwrap();
+
+        assert!(matches!(
+            verifier.check(&attempt(None)).await,
+            Err(ApplicationError::RegistrationRejected(_))
+        ));
+        assert!(matches!(
+            verifier.check(&attempt(Some("forged-token"))).await,
+            Err(ApplicationError::RegistrationRejected(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn when_the_provider_is_unavailable_should_retry_the_verification() {
+        let (verify_url, calls) = start_failing_siteverify_stub(1, StatusCode::SERVICE_UNAVAILABLE).await;
+        let verifier = ChallengeVerifier::new(&verify_url, "test-secret").unwrap();
+
+        assert!(verifier.check(&attempt(Some("valid-token"))).await.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn when_the_provider_rejects_the_request_should_not_retry() {
+        let (verify_url, calls) = start_failing_siteverify_stub(1, StatusCode::BAD_REQUEST).await;
+        let verifier = ChallengeVerifier::new(&verify_url, "test-secret").unwrap();
+
+        assert!(verifier.check(&attempt(Some("valid-token"))).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,299 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::Rng;
+
+// Budgets are tracked in thousandths of a retry so fractional deposits survive integer atomics
+const BUDGET_SCALE: i64 = 1000;
+
+// Limits retries to a fraction of the calls being made, so a struggling dependency is not hit with
+// a retry storm on top of the normal load. Every call deposits `retry_ratio` tokens, every retry
+// withdraws a whole one, and `min_retries` are always available as a floor.
+pub struct RetryBudget {
+    balance: AtomicI64,
+    deposit: i64,
+    max_balance: i64,
+}
+
+impl RetryBudget {
+    pub fn new(retry_ratio: f64, min_retries: u32) -> Self {
+        let min_balance = min_retries as i64 * BUDGET_SCALE;
+
+        Self {
+            balance: AtomicI64::new(min_balance),
+            deposit: (retry_ratio * BUDGET_SCALE as f64) as i64,
+            max_balance: min_balance.max(100 * BUDGET_SCALE),
+        }
+    }
+
+    pub fn deposit(&self) {
+        let _ = self
+            .balance
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |balance| {
+                Some((balance + self.deposit).min(self.max_balance))
+            });
+    }
+
+    pub fn try_withdraw(&self) -> bool {
+        self.balance
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |balance| {
+                (balance >= BUDGET_SCALE).then_some(balance - BUDGET_SCALE)
+            })
+            .is_ok()
+    }
+
+    pub fn remaining(&self) -> f64 {
+        self.balance.load(Ordering::SeqCst) as f64 / BUDGET_SCALE as f64
+    }
+}
+
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+    budget: Option<Arc<RetryBudget>>,
+}
+
+pub struct RetryPolicyBuilder {
+    policy: RetryPolicy,
+}
+
+impl RetryPolicyBuilder {
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.policy.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn base_delay(mut self, base_delay: Duration) -> Self {
+        self.policy.base_delay = base_delay;
+        self
+    }
+
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.policy.max_delay = max_delay;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.policy.jitter = jitter;
+        self
+    }
+
+    pub fn budget(mut self, budget: Arc<RetryBudget>) -> Self {
+        self.policy.budget = Some(budget);
+        self
+    }
+
+    pub fn build(self) -> RetryPolicy {
+        self.policy
+    }
+}
+
+impl RetryPolicy {
+    pub fn builder() -> RetryPolicyBuilder {
+        RetryPolicyBuilder {
+            policy: RetryPolicy {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(100),
+                max_delay: Duration::from_secs(10),
+                jitter: true,
+                budget: None,
+            },
+        }
+    }
+
+    // Exponential backoff capped at `max_delay`. With jitter enabled the delay is drawn uniformly
+    // from zero up to that value ("full jitter") so clients retrying together spread out.
+    pub fn delay_for_attempt(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let capped = exponential.min(self.max_delay);
+
+        if self.jitter && !capped.is_zero() {
+            rand::rng().random_range(Duration::ZERO..=capped)
+        } else {
+            capped
+        }
+    }
+
+    // Retries every error, for operations where any failure may be transient, e.g. connecting at
+    // startup
+    pub async fn retry<T, E, F, Fut>(&self, operation: &str, f: F) -> Result<T, E>
+    where
+        E: std::fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        self.retry_if(operation, |_| true, f).await
+    }
+
+    // Only retries errors `is_retryable` considers transient. Anything else, e.g. a rejected
+    // request, fails straight away without spending the budget.
+    pub async fn retry_if<T, E, P, F, Fut>(
+        &self,
+        operation: &str,
+        is_retryable: P,
+        mut f: F,
+    ) -> Result<T, E>
+    where
+        E: std::fmt::Display,
+        P: Fn(&E) -> bool,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        if let Some(budget) = &self.budget {
+            budget.deposit();
+        }
+
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    if !is_retryable(&e) {
+                        return Err(e);
+                    }
+                    if attempt >= self.max_attempts {
+                        log::error!("{} failed after {} attempts: {}", operation, attempt, e);
+                        return Err(e);
+                    }
+                    if let Some(budget) = &self.budget
+                        && !budget.try_withdraw()
+                    {
+                        log::warn!("{} retry budget exhausted: {}", operation, e);
+                        return Err(e);
+                    }
+
+                    let delay = self.delay_for_attempt(attempt);
+                    log::warn!(
+                        "{} failed on attempt {}, retrying in {:?}: {}",
+                        operation,
+                        attempt,
+                        delay,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    fn policy(max_attempts: u32) -> RetryPolicyBuilder {
+        RetryPolicy::builder()
+            .max_attempts(max_attempts)
+            .base_delay(Duration::from_millis(1))
+            .jitter(false)
+    }
+
+    #[test]
+    fn delay_should_grow_exponentially_up_to_the_maximum() {
+        let policy = RetryPolicy::builder()
+            .base_delay(Duration::from_millis(100))
+            .max_delay(Duration::from_millis(500))
+            .jitter(false)
+            .build();
+
+        assert_eq!(policy.delay_for_attempt(1), Duration::from_millis(100));
+        assert_eq!(policy.delay_for_attempt(2), Duration::from_millis(200));
+        assert_eq!(policy.delay_for_attempt(3), Duration::from_millis(400));
+        assert_eq!(policy.delay_for_attempt(4), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn jittered_delay_should_never_exceed_the_backoff() {
+        let policy = RetryPolicy::builder()
+            .base_delay(Duration::from_millis(100))
+            .build();
+
+        for _ in 0..100 {
+            assert!(policy.delay_for_attempt(2) <= Duration::from_millis(200));
+        }
+    }
+
+    #[tokio::test]
+    async fn when_an_operation_keeps_failing_should_stop_after_max_attempts() {
+        let calls = AtomicU32::new(0);
+
+        let result: Result<(), String> = policy(3)
+            .build()
+            .retry("test", || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err("failed".to_string())
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn when_an_operation_recovers_should_return_the_value() {
+        let calls = AtomicU32::new(0);
+
+        let result: Result<u32, String> = policy(5)
+            .build()
+            .retry("test", || async {
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err("failed".to_string()),
+                    attempt => Ok(attempt),
+                }
+            })
+            .await;
+
+        assert_eq!(result.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn when_an_error_is_permanent_should_not_retry_or_spend_the_budget() {
+        let calls = AtomicU32::new(0);
+        let budget = Arc::new(RetryBudget::new(0.0, 1));
+
+        let result: Result<(), String> = policy(5)
+            .budget(budget.clone())
+            .build()
+            .retry_if(
+                "test",
+                |e: &String| e != "rejected",
+                || async {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Err("rejected".to_string())
+                },
+            )
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(budget.remaining(), 1.0);
+    }
+
+    #[tokio::test]
+    async fn when_the_budget_is_exhausted_should_not_retry() {
+        let calls = AtomicU32::new(0);
+        let budget = Arc::new(RetryBudget::new(0.0, 1));
+
+        let policy = policy(5).budget(budget.clone()).build();
+        let result: Result<(), String> = policy
+            .retry("test", || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err("failed".to_string())
+            })
+            .await;
+
+        assert!(result.is_err());
+        // one initial attempt plus the single retry the budget allows
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(budget.remaining(), 0.0);
+    }
+}
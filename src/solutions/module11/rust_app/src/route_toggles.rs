@@ -0,0 +1,157 @@
+use std::sync::Arc;
+
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::Method;
+use axum::middleware::Next;
+use axum::response::Response;
+
+use crate::core::{ApplicationError, Config};
+use crate::errors::error_response;
+use crate::AppState;
+
+// One entry of `routes.enabled`, a route as it is registered with an optional method in front,
+// e.g. `POST /users` or `/users/{email_address}`. A trailing `*` takes every route under it, e.g.
+// `/admin/*`.
+#[derive(Clone, Debug, PartialEq)]
+struct EnabledRoute {
+    method: Option<Method>,
+    route: String,
+}
+
+impl EnabledRoute {
+    fn parse(entry: &str) -> Result<Self, ApplicationError> {
+        let entry = entry.trim();
+        let (method, route) = match entry.split_once(char::is_whitespace) {
+            Some((method, route)) => (
+                Some(Method::from_bytes(method.to_ascii_uppercase().as_bytes()).map_err(|_| {
+                    ApplicationError::ApplicationError(format!(
+                        "routes.enabled has an unknown method in {}",
+                        entry
+                    ))
+                })?),
+                route.trim(),
+            ),
+            None => (None, entry),
+        };
+        if !route.starts_with('/') {
+            return Err(ApplicationError::ApplicationError(format!(
+                "routes.enabled entries must be routes starting with /, not {}",
+                entry
+            )));
+        }
+
+        Ok(Self {
+            method,
+            route: route.to_string(),
+        })
+    }
+
+    // A HEAD is answered by the GET handler, so it is enabled along with it
+    fn matches(&self, method: &Method, route: &str) -> bool {
+        let method = match *method {
+            Method::HEAD => &Method::GET,
+            _ => method,
+        };
+        let route_matches = match self.route.strip_suffix('*') {
+            Some(prefix) => route.starts_with(prefix),
+            None => route == self.route,
+        };
+
+        route_matches && self.method.as_ref().is_none_or(|enabled| enabled == method)
+    }
+}
+
+// Switches routes on and off from config, so a workshop can start with only `POST /users` and
+// enable logins, sessions and the admin routes as each module gets to them without rebuilding. A
+// route that isn't enabled answers with a 501 and a hint rather than a 404, so it reads as not
+// there yet rather than mistyped. Routes that don't exist at all, e.g. the cookie-only ones in
+// the `none` auth mode, are still a 404.
+pub struct RouteToggles {
+    enabled: Vec<EnabledRoute>,
+    hint: Option<String>,
+}
+
+impl RouteToggles {
+    pub fn new(enabled: &[String], hint: Option<String>) -> Result<Self, ApplicationError> {
+        Ok(Self {
+            enabled: enabled
+                .iter()
+                .map(|entry| EnabledRoute::parse(entry))
+                .collect::<Result<_, _>>()?,
+            hint,
+        })
+    }
+
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ApplicationError> {
+        let Some(enabled) = config.enabled_routes() else {
+            return Ok(None);
+        };
+        log::info!("Only these routes are enabled: {}", enabled.join(", "));
+
+        Self::new(&enabled, config.disabled_route_hint()).map(Some)
+    }
+
+    pub fn is_enabled(&self, method: &Method, route: &str) -> bool {
+        self.enabled.iter().any(|enabled| enabled.matches(method, route))
+    }
+
+    fn disabled(&self, method: &Method, route: &str) -> ApplicationError {
+        let route = format!("{} {}", method, route);
+        let hint = self.hint.clone().unwrap_or_else(|| {
+            format!("add \"{}\" to routes.enabled in the config to switch it on", route)
+        });
+
+        ApplicationError::RouteDisabled { route, hint }
+    }
+}
+
+// Layered over every route, the matched route is only known once the request has been routed
+pub async fn require_enabled(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(toggles) = &state.route_toggles else {
+        return next.run(request).await;
+    };
+    let Some(route) = request.extensions().get::<MatchedPath>() else {
+        return next.run(request).await;
+    };
+
+    match toggles.is_enabled(request.method(), route.as_str()) {
+        true => next.run(request).await,
+        false => {
+            let error = toggles.disabled(request.method(), route.as_str());
+            error_response(&state.metrics, error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn toggles(enabled: &[&str]) -> RouteToggles {
+        let enabled: Vec<String> = enabled.iter().map(|entry| entry.to_string()).collect();
+        RouteToggles::new(&enabled, None).unwrap()
+    }
+
+    #[test]
+    fn routes_should_be_enabled_by_method_route_or_prefix() {
+        let toggles = toggles(&["POST /users", "/users/{email_address}", "get /admin/*"]);
+
+        assert!(toggles.is_enabled(&Method::POST, "/users"));
+        assert!(!toggles.is_enabled(&Method::GET, "/users"));
+        assert!(toggles.is_enabled(&Method::DELETE, "/users/{email_address}"));
+        assert!(!toggles.is_enabled(&Method::GET, "/users/{email_address}/avatar"));
+        assert!(toggles.is_enabled(&Method::HEAD, "/admin/stats"));
+        assert!(!toggles.is_enabled(&Method::DELETE, "/admin/users/{email_address}"));
+        assert!(!toggles.is_enabled(&Method::POST, "/login"));
+    }
+
+    #[test]
+    fn entries_that_are_not_routes_should_be_rejected() {
+        assert!(EnabledRoute::parse("users").is_err());
+        assert!(EnabledRoute::parse("PO(ST /users").is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use serde::Serialize;
+
+use crate::core::ApplicationError;
+use crate::metrics::{
+    Metrics, APPLICATION_ERRORS_TOTAL, HTTP_REQUESTS_TOTAL, WORKER_MESSAGES_CONSUMED_TOTAL,
+};
+
+// How long the exporters get to send what they still hold, an unreachable collector doesn't keep
+// the process from exiting
+const TELEMETRY_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What the process did while it ran, for the report logged when it exits. It is process wide
+// because the guard is taken before the API or worker has built the metrics it reports on.
+struct Runtime {
+    started_at: Instant,
+    metrics: OnceLock<Arc<Metrics>>,
+    shutdown_reason: Mutex<Option<String>>,
+}
+
+static RUNTIME: LazyLock<Runtime> = LazyLock::new(|| Runtime {
+    started_at: Instant::now(),
+    metrics: OnceLock::new(),
+    shutdown_reason: Mutex::new(None),
+});
+
+// The metrics the shutdown report is taken from, the first ones registered are kept
+pub fn report_on(metrics: &Arc<Metrics>) {
+    let _ = RUNTIME.metrics.set(metrics.clone());
+}
+
+// The first reason is kept, an error returned while draining after a signal isn't why it stopped
+fn shutting_down(reason: impl Into<String>) {
+    let mut shutdown_reason = RUNTIME.shutdown_reason.lock().unwrap();
+    if shutdown_reason.is_none() {
+        *shutdown_reason = Some(reason.into());
+    }
+}
+
+// Resolves on Ctrl+C or, on unix, the SIGTERM an orchestrator stops the process with
+pub async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            log::error!("Unable to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                log::error!("Unable to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    let reason = tokio::select! {
+        _ = interrupt => "interrupted",
+        _ = terminate => "terminated",
+    };
+    log::info!("Shutting down, the process was {}", reason);
+    shutting_down(reason);
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ShutdownReport {
+    reason: String,
+    uptime_seconds: u64,
+    requests_served: u64,
+    errors: u64,
+    messages_consumed: u64,
+}
+
+impl ShutdownReport {
+    fn new(metrics: Option<&Metrics>, uptime: Duration, reason: String) -> Self {
+        let counter = |name| metrics.map(|metrics| metrics.counter(name)).unwrap_or(0);
+
+        Self {
+            reason,
+            uptime_seconds: uptime.as_secs(),
+            requests_served: counter(HTTP_REQUESTS_TOTAL),
+            errors: counter(APPLICATION_ERRORS_TOTAL),
+            messages_consumed: counter(WORKER_MESSAGES_CONSUMED_TOTAL),
+        }
+    }
+}
+
+// Held by the binaries for as long as they run. `shut_down` logs the shutdown report and flushes
+// the telemetry providers, if the process exits some other way, e.g. returning early with an
+// error, dropping the guard still flushes them.
+pub struct RuntimeGuard {
+    tracer_provider: Option<SdkTracerProvider>,
+}
+
+impl RuntimeGuard {
+    pub(crate) fn new(tracer_provider: SdkTracerProvider) -> Self {
+        LazyLock::force(&RUNTIME);
+        Self {
+            tracer_provider: Some(tracer_provider),
+        }
+    }
+
+    pub async fn shut_down(mut self, result: &Result<(), ApplicationError>) {
+        match result {
+            Ok(()) => shutting_down("completed"),
+            Err(e) => shutting_down(format!("failed: {:?}", e)),
+        }
+        let reason = RUNTIME.shutdown_reason.lock().unwrap().clone().unwrap_or_default();
+        let report = ShutdownReport::new(
+            RUNTIME.metrics.get().map(Arc::as_ref),
+            RUNTIME.started_at.elapsed(),
+            reason,
+        );
+        log::info!(report:serde = report; "Shutdown report");
+
+        let Some(tracer_provider) = self.tracer_provider.take() else {
+            return;
+        };
+        let flushed = tokio::time::timeout(
+            TELEMETRY_FLUSH_TIMEOUT,
+            tokio::task::spawn_blocking(move || tracer_provider.shutdown()),
+        )
+        .await;
+        match flushed {
+            Ok(Ok(Ok(()))) => {}
+            Ok(Ok(Err(e))) => eprintln!("{e:?}"),
+            Ok(Err(e)) => eprintln!("{e:?}"),
+            Err(_) => eprintln!(
+                "The traces weren't flushed within {:?}, the rest are dropped",
+                TELEMETRY_FLUSH_TIMEOUT
+            ),
+        }
+    }
+}
+
+impl Drop for RuntimeGuard {
+    fn drop(&mut self) {
+        if let Some(tracer_provider) = self.tracer_provider.take()
+            && let Err(err) = tracer_provider.shutdown()
+        {
+            eprintln!("{err:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_report_should_sum_the_counters_across_their_labels() {
+        let metrics = Metrics::default();
+        metrics.increment(HTTP_REQUESTS_TOTAL);
+        metrics.increment(HTTP_REQUESTS_TOTAL);
+        metrics.increment_with_labels(APPLICATION_ERRORS_TOTAL, &[("code", "USER_DOES_NOT_EXIST")]);
+        metrics.increment_with_labels(WORKER_MESSAGES_CONSUMED_TOTAL, &[("topic", "user-merged")]);
+
+        let report =
+            ShutdownReport::new(Some(&metrics), Duration::from_millis(90_500), "terminated".into());
+
+        assert_eq!(
+            report,
+            ShutdownReport {
+                reason: "terminated".to_string(),
+                uptime_seconds: 90,
+                requests_served: 2,
+                errors: 1,
+                messages_consumed: 1,
+            }
+        );
+    }
+}
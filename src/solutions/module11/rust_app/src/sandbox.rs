@@ -0,0 +1,100 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::auth::MagicLinkSender;
+use crate::core::ApplicationError;
+
+// How many notifications are kept, the oldest are dropped first
+pub const SANDBOX_CAPACITY: usize = 100;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CapturedNotification {
+    pub id: String,
+    pub channel: &'static str,
+    pub recipient: String,
+    pub subject: String,
+    pub body: String,
+    pub captured_at: u64,
+}
+
+// Keeps what would have been sent to users in the local profile, so flows that go through an
+// inbox can be completed from `GET /dev/outbox` without an SMTP server
+pub struct NotificationSandbox {
+    capacity: usize,
+    captured: Mutex<VecDeque<CapturedNotification>>,
+}
+
+impl NotificationSandbox {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            captured: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn capture(&self, channel: &'static str, recipient: &str, subject: &str, body: &str) {
+        let captured_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        let mut captured = self.captured.lock().unwrap();
+        if captured.len() == self.capacity {
+            captured.pop_front();
+        }
+        captured.push_back(CapturedNotification {
+            id: uuid::Uuid::new_v4().to_string(),
+            channel,
+            recipient: recipient.to_string(),
+            subject: subject.to_string(),
+            body: body.to_string(),
+            captured_at,
+        });
+    }
+
+    // Most recent first
+    pub fn list(&self) -> Vec<CapturedNotification> {
+        self.captured.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+// Captures magic links before handing them to the sender that would otherwise deliver them
+pub struct SandboxMagicLinkSender {
+    pub sandbox: Arc<NotificationSandbox>,
+    pub inner: Arc<dyn MagicLinkSender>,
+}
+
+#[async_trait::async_trait]
+impl MagicLinkSender for SandboxMagicLinkSender {
+    async fn send(&self, email_address: &str, link: &str) -> Result<(), ApplicationError> {
+        self.sandbox
+            .capture("email", email_address, "Your sign in link", link);
+
+        self.inner.send(email_address, link).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_sandbox_should_drop_the_oldest_notification_once_full() {
+        let sandbox = NotificationSandbox::new(2);
+
+        for recipient in ["first@test.com", "second@test.com", "third@test.com"] {
+            sandbox.capture("email", recipient, "Subject", "Body");
+        }
+
+        let recipients: Vec<String> = sandbox
+            .list()
+            .into_iter()
+            .map(|notification| notification.recipient)
+            .collect();
+        assert_eq!(recipients, vec!["third@test.com", "second@test.com"]);
+    }
+}
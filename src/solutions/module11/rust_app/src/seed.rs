@@ -0,0 +1,200 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::core::{ApplicationError, DataAccess, User};
+
+#[derive(Clone, Debug)]
+pub struct SeedSettings {
+    // A `.json` or `.csv` fixture, see `read_fixture`
+    pub path: PathBuf,
+    // Users stored per `store_many`, each batch is stored in full or not at all
+    pub batch_size: usize,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SeedReport {
+    pub stored: u64,
+    // Already taken, e.g. by an earlier run of the same fixture, or given earlier in this one
+    pub skipped: u64,
+}
+
+// A user as they would register, with their password in the clear
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SeedUser {
+    email_address: String,
+    name: String,
+    password: String,
+}
+
+fn fixture_error(path: &Path, e: impl std::fmt::Display) -> ApplicationError {
+    ApplicationError::ApplicationError(format!("{}: {}", path.display(), e))
+}
+
+// A JSON array of users named as `POST /users` takes them, or CSV whose header row names the same
+// fields, told apart by the file's extension
+pub fn read_fixture(path: &Path) -> Result<Vec<SeedUser>, ApplicationError> {
+    let contents = std::fs::read_to_string(path).map_err(|e| fixture_error(path, e))?;
+
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => serde_json::from_str(&contents).map_err(|e| fixture_error(path, e)),
+        Some("csv") => parse_csv(&contents).map_err(|e| fixture_error(path, e)),
+        _ => Err(fixture_error(path, "fixtures are either .json or .csv")),
+    }
+}
+
+// Each row is read as the JSON object its header describes, so both formats have the same fields
+fn parse_csv(contents: &str) -> Result<Vec<SeedUser>, String> {
+    let mut lines = contents.lines().filter(|line| !line.trim().is_empty());
+    let header = csv_fields(lines.next().ok_or("the header row is missing")?);
+
+    lines
+        .enumerate()
+        .map(|(index, line)| {
+            let fields = csv_fields(line);
+            if fields.len() != header.len() {
+                let (row, found, expected) = (index + 1, fields.len(), header.len());
+                return Err(format!("row {} has {} fields, not {}", row, found, expected));
+            }
+            let row: serde_json::Map<String, serde_json::Value> = header
+                .iter()
+                .cloned()
+                .zip(fields.into_iter().map(serde_json::Value::String))
+                .collect();
+
+            serde_json::from_value(row.into()).map_err(|e| format!("row {}: {}", index + 1, e))
+        })
+        .collect()
+}
+
+// Fields are separated by commas, a field in double quotes may contain commas and `""` for a quote
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut characters = line.trim_end_matches('\r').chars().peekable();
+
+    while let Some(character) = characters.next() {
+        match character {
+            '"' if quoted && characters.peek() == Some(&'"') => {
+                field.push('"');
+                characters.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(character),
+        }
+    }
+    fields.push(field);
+
+    fields
+}
+
+// Passwords are hashed as registration hashes them, one batch at a time so a large fixture isn't
+// held hashed in memory all at once. Addresses that are already taken are skipped, seeding the same
+// fixture twice stores nobody the second time. An address given twice is stored as its first row.
+pub async fn seed_users<TDataAccess: DataAccess>(
+    data_access: &TDataAccess,
+    users: Vec<SeedUser>,
+    settings: &SeedSettings,
+) -> Result<SeedReport, ApplicationError> {
+    let mut report = SeedReport::default();
+    let mut seen = HashSet::new();
+
+    for batch in users.chunks(settings.batch_size.max(1)) {
+        let mut new_users = Vec::new();
+        for user in batch {
+            if !seen.insert(user.email_address.as_str())
+                || data_access.exists(&user.email_address).await?
+            {
+                report.skipped += 1;
+                continue;
+            }
+            new_users.push(User::new(&user.email_address, &user.name, &user.password)?);
+        }
+
+        let stored = new_users.len() as u64;
+        data_access.store_many(new_users).await?;
+        report.stored += stored;
+        log::info!("Seeded {} users, skipped {}", report.stored, report.skipped);
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_access::InMemoryUsers;
+
+    const PASSWORD: &str = "Correct horse battery staple 91!";
+
+    #[test]
+    fn a_csv_fixture_should_read_as_its_header_names_the_fields() {
+        let csv = format!(
+            "name,emailAddress,password\n\"Smith, James\",james@test.com,{}\r\n\n{}\n",
+            PASSWORD, "Jane,jane@test.com,\"say \"\"hi\"\"\""
+        );
+
+        let users = parse_csv(&csv).unwrap();
+
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0].name, "Smith, James");
+        assert_eq!(users[0].email_address, "james@test.com");
+        assert_eq!(users[0].password, PASSWORD);
+        assert_eq!(users[1].password, "say \"hi\"");
+        assert!(parse_csv("name,emailAddress,password\nJames,james@test.com").is_err());
+    }
+
+    #[tokio::test]
+    async fn seeding_a_fixture_twice_should_only_store_its_users_once() {
+        let data_access = InMemoryUsers::default();
+        let fixture = || {
+            (0..3)
+                .map(|index| SeedUser {
+                    email_address: format!("user{}@test.com", index),
+                    name: "James".to_string(),
+                    password: PASSWORD.to_string(),
+                })
+                .collect::<Vec<_>>()
+        };
+        let settings = SeedSettings {
+            path: PathBuf::from("users.json"),
+            batch_size: 2,
+        };
+
+        let first = seed_users(&data_access, fixture(), &settings).await.unwrap();
+        let second = seed_users(&data_access, fixture(), &settings).await.unwrap();
+
+        assert_eq!(first, SeedReport { stored: 3, skipped: 0 });
+        assert_eq!(second, SeedReport { stored: 0, skipped: 3 });
+        let user = data_access.with_email_address("user0@test.com").await.unwrap();
+        assert!(user.verify_password(PASSWORD).is_ok());
+    }
+
+    #[tokio::test]
+    async fn an_address_given_twice_should_be_stored_from_its_first_row() {
+        let data_access = InMemoryUsers::default();
+        let fixture = ["James", "Jane", "Jim"]
+            .into_iter()
+            .zip(["james@test.com", "jane@test.com", "james@test.com"])
+            .map(|(name, email_address)| SeedUser {
+                email_address: email_address.to_string(),
+                name: name.to_string(),
+                password: PASSWORD.to_string(),
+            })
+            .collect();
+        let settings = SeedSettings {
+            path: PathBuf::from("users.json"),
+            batch_size: 3,
+        };
+
+        let report = seed_users(&data_access, fixture, &settings).await.unwrap();
+
+        assert_eq!(report, SeedReport { stored: 2, skipped: 1 });
+        let user = data_access.with_email_address("james@test.com").await.unwrap();
+        assert_eq!(user.name(), "James");
+    }
+}
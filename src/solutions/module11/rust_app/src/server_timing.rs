@@ -0,0 +1,167 @@
+use std::time::{Duration, Instant};
+
+use axum::http::HeaderValue;
+use tracing::span::{Attributes, Id};
+use tracing::{Span, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Layer, Registry};
+
+pub const SERVER_TIMING: &str = "server-timing";
+
+// The spans whose time is reported and the metric each is reported under, in header order
+const METRICS: [(&str, &str); 4] = [
+    ("user.validate", "validation"),
+    ("user.hash", "hash"),
+    ("db.query", "db"),
+    ("events.enqueue", "events"),
+];
+
+// Time taken by the spans of one request, kept on its `http.request` span
+#[derive(Default)]
+struct RequestTimings {
+    totals: [Duration; METRICS.len()],
+}
+
+struct Timed {
+    metric: usize,
+    started: Instant,
+    // Time taken by timed spans inside this one, which is theirs and not counted again
+    nested: Duration,
+}
+
+// Adds up how long each request spent in validation, hashing, the database and enqueuing events,
+// for `server_timing_header`. Time is counted once, against the innermost timed span, so an event
+// enqueued within a database transaction is counted as events only.
+#[derive(Default)]
+pub struct ServerTimingLayer;
+
+impl<S> Layer<S> for ServerTimingLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let name = attrs.metadata().name();
+
+        if name == "http.request" {
+            span.extensions_mut().insert(RequestTimings::default());
+        } else if let Some(metric) = METRICS.iter().position(|(span_name, _)| *span_name == name) {
+            span.extensions_mut().insert(Timed {
+                metric,
+                started: Instant::now(),
+                nested: Duration::ZERO,
+            });
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some((metric, elapsed, nested)) = span
+            .extensions()
+            .get::<Timed>()
+            .map(|timed| (timed.metric, timed.started.elapsed(), timed.nested))
+        else {
+            return;
+        };
+
+        let mut counted_by_parent = false;
+        for ancestor in span.scope().skip(1) {
+            let mut extensions = ancestor.extensions_mut();
+            if let Some(parent) = extensions.get_mut::<Timed>() {
+                if !counted_by_parent {
+                    parent.nested += elapsed;
+                    counted_by_parent = true;
+                }
+            } else if let Some(timings) = extensions.get_mut::<RequestTimings>() {
+                // Concurrent nested spans can take longer in total than the span they ran in
+                timings.totals[metric] += elapsed.saturating_sub(nested);
+                return;
+            }
+        }
+    }
+}
+
+// e.g. `validation;dur=0.4, hash;dur=41.2, db;dur=3.1`, with the metrics the request spent time
+// in. `None` for a span from outside a request or when the layer isn't installed.
+pub fn server_timing_header(request_span: &Span) -> Option<HeaderValue> {
+    let totals = request_span.with_subscriber(|(id, dispatch)| {
+        let registry = dispatch.downcast_ref::<Registry>()?;
+        let span = registry.span(id)?;
+        let extensions = span.extensions();
+        extensions.get::<RequestTimings>().map(|timings| timings.totals)
+    })??;
+
+    let header = METRICS
+        .iter()
+        .zip(totals)
+        .filter(|(_, total)| !total.is_zero())
+        .map(|((_, metric), total)| format!("{};dur={:.1}", metric, total.as_secs_f64() * 1000.0))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    HeaderValue::from_str(&header).ok().filter(|_| !header.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn metrics(header: &str) -> Vec<&str> {
+        header
+            .split(", ")
+            .map(|metric| metric.split_once(";dur=").unwrap().0)
+            .collect()
+    }
+
+    fn milliseconds(header: &str, metric: &str) -> f64 {
+        header
+            .split(", ")
+            .find_map(|entry| entry.strip_prefix(&format!("{};dur=", metric)))
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn time_in_nested_spans_should_only_be_counted_against_the_innermost() {
+        let subscriber = tracing_subscriber::registry().with(ServerTimingLayer);
+
+        let header = tracing::subscriber::with_default(subscriber, || {
+            let request = tracing::info_span!("http.request");
+            request.in_scope(|| {
+                let _validate = tracing::info_span!("user.validate").entered();
+                std::thread::sleep(Duration::from_millis(5));
+            });
+            request.in_scope(|| {
+                let _db = tracing::info_span!("db.query").entered();
+                std::thread::sleep(Duration::from_millis(5));
+                let _events = tracing::info_span!("events.enqueue").entered();
+                std::thread::sleep(Duration::from_millis(20));
+            });
+            server_timing_header(&request).unwrap()
+        });
+        let header = header.to_str().unwrap();
+
+        assert_eq!(metrics(header), vec!["validation", "db", "events"]);
+        assert!(milliseconds(header, "events") >= 20.0);
+        assert!(milliseconds(header, "db") < 20.0);
+    }
+
+    #[test]
+    fn without_the_layer_or_a_request_there_should_be_no_header() {
+        let request = tracing::info_span!("http.request");
+        assert!(server_timing_header(&request).is_none());
+
+        let subscriber = tracing_subscriber::registry().with(ServerTimingLayer);
+        tracing::subscriber::with_default(subscriber, || {
+            let background = tracing::info_span!("outbox.relay");
+            assert!(server_timing_header(&background).is_none());
+        });
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+
+use tokio::sync::broadcast;
+
+use crate::core::Config;
+
+// Identical reads that arrive while one is already in flight wait for its result rather than
+// making their own query, so a burst of requests for one hot user costs a single query. Nothing is
+// kept once the query finishes, the next read queries again.
+pub struct SingleFlight<V: Clone> {
+    in_flight: Mutex<HashMap<String, broadcast::Sender<V>>>,
+}
+
+impl<V: Clone> Default for SingleFlight<V> {
+    fn default() -> Self {
+        Self {
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+// The request making the query. Its entry is removed when it finishes or is dropped mid-query, e.g.
+// because its client disconnected, which closes the channel and has the waiting requests query
+// for themselves.
+struct Leader<'a, V: Clone> {
+    flights: &'a SingleFlight<V>,
+    key: &'a str,
+    sender: broadcast::Sender<V>,
+}
+
+impl<V: Clone> Drop for Leader<'_, V> {
+    fn drop(&mut self) {
+        let mut in_flight = self.flights.in_flight.lock().unwrap();
+        if in_flight
+            .get(self.key)
+            .is_some_and(|sender| sender.same_channel(&self.sender))
+        {
+            in_flight.remove(self.key);
+        }
+    }
+}
+
+impl<V: Clone> SingleFlight<V> {
+    pub fn from_config(config: &Config) -> Option<Self> {
+        config.coalesce_reads().then(Self::default)
+    }
+
+    // The result of `query` for `key`, shared with whoever else asks for `key` before it finishes.
+    // `true` alongside it when it was another request's.
+    pub async fn run<F, Fut>(&self, key: &str, query: F) -> (V, bool)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let waiting = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(key) {
+                Some(sender) => Ok(sender.subscribe()),
+                None => {
+                    let (sender, _) = broadcast::channel(1);
+                    in_flight.insert(key.to_string(), sender.clone());
+                    Err(sender)
+                }
+            }
+        };
+
+        match waiting {
+            Ok(mut receiver) => match receiver.recv().await {
+                Ok(value) => (value, true),
+                Err(_) => (query().await, false),
+            },
+            Err(sender) => {
+                let leader = Leader {
+                    flights: self,
+                    key,
+                    sender,
+                };
+                let value = query().await;
+                // Removed before sending, anyone arriving from here on makes a fresh query
+                let sender = leader.sender.clone();
+                drop(leader);
+                let _ = sender.send(value.clone());
+                (value, false)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_reads_of_the_same_key_should_share_one_query() {
+        let flights = Arc::new(SingleFlight::<usize>::default());
+        let queries = Arc::new(AtomicUsize::new(0));
+
+        let reads: Vec<_> = (0..10)
+            .map(|_| {
+                let flights = flights.clone();
+                let queries = queries.clone();
+                tokio::spawn(async move {
+                    flights
+                        .run("james@test.com", || async {
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            queries.fetch_add(1, Ordering::SeqCst) + 1
+                        })
+                        .await
+                })
+            })
+            .collect();
+        let mut results = Vec::new();
+        for read in reads {
+            results.push(read.await.unwrap());
+        }
+
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        assert!(results.iter().all(|(value, _)| *value == 1));
+        assert_eq!(results.iter().filter(|(_, coalesced)| *coalesced).count(), 9);
+
+        let (value, coalesced) = flights.run("james@test.com", || async { 2 }).await;
+        assert_eq!((value, coalesced), (2, false));
+    }
+
+    #[tokio::test]
+    async fn waiting_reads_should_query_for_themselves_when_the_first_is_dropped() {
+        let flights = Arc::new(SingleFlight::<&str>::default());
+
+        let leader = {
+            let flights = flights.clone();
+            tokio::spawn(async move {
+                flights
+                    .run("james@test.com", std::future::pending::<&str>)
+                    .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let follower = {
+            let flights = flights.clone();
+            tokio::spawn(async move { flights.run("james@test.com", || async { "own" }).await })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        leader.abort();
+
+        assert_eq!(follower.await.unwrap(), ("own", false));
+    }
+}
@@ -0,0 +1,95 @@
+use axum::body::Bytes;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+
+use crate::cache;
+
+// Nothing served this way is about a user, so any cache in front of the API may keep it
+pub const STATIC_MAX_AGE_SECONDS: u64 = 300;
+
+// A response that can't change while the process runs, built once at startup along with its
+// validators, so a gateway or browser in front of the API can cache it and revalidate it with
+// `If-None-Match` for a 304.
+#[derive(Clone)]
+pub struct StaticResource {
+    body: Bytes,
+    content_type: &'static str,
+    etag: HeaderValue,
+}
+
+impl StaticResource {
+    pub fn json<T: Serialize>(value: &T) -> Self {
+        let body = Bytes::from(serde_json::to_vec(value).expect("a static resource is valid JSON"));
+
+        Self {
+            etag: cache::etag_for(&body),
+            body,
+            content_type: "application/json",
+        }
+    }
+
+    // `Vary` names the header a gateway that compresses responses keys its copies by, the body
+    // itself is the same for every request
+    pub fn respond(&self, request_headers: &HeaderMap) -> Response {
+        let caching = [
+            (
+                header::CACHE_CONTROL,
+                HeaderValue::from_str(&format!("public, max-age={}", STATIC_MAX_AGE_SECONDS))
+                    .expect("a max-age is a valid header value"),
+            ),
+            (header::ETAG, self.etag.clone()),
+            (header::VARY, HeaderValue::from_static("accept-encoding")),
+        ];
+        if request_headers
+            .get(header::IF_NONE_MATCH)
+            .is_some_and(|if_none_match| *if_none_match == self.etag)
+        {
+            return (StatusCode::NOT_MODIFIED, caching).into_response();
+        }
+
+        (
+            StatusCode::OK,
+            caching,
+            [(header::CONTENT_TYPE, self.content_type)],
+            self.body.clone(),
+        )
+            .into_response()
+    }
+}
+
+#[derive(Serialize)]
+struct Version {
+    name: &'static str,
+    version: &'static str,
+}
+
+// What `GET /version` serves, the build that is running
+pub fn version() -> StaticResource {
+    StaticResource::json(&Version {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_static_resource_should_be_revalidated_by_its_etag() {
+        let version = version();
+
+        let served = version.respond(&HeaderMap::new());
+        let etag = served.headers()[header::ETAG].clone();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, etag);
+        let revalidated = version.respond(&headers);
+
+        assert_eq!(served.status(), StatusCode::OK);
+        assert_eq!(served.headers()[header::CACHE_CONTROL], "public, max-age=300");
+        assert_eq!(served.headers()[header::VARY], "accept-encoding");
+        assert_eq!(revalidated.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(revalidated.headers()[header::ETAG], served.headers()[header::ETAG]);
+    }
+}
@@ -0,0 +1,121 @@
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::Instrument;
+
+use crate::core::ApplicationError;
+
+tokio::task_local! {
+    static DEADLINE: Instant;
+}
+
+// The deadline of the request being handled, if one was set with `with_deadline`
+pub fn current_deadline() -> Option<Instant> {
+    DEADLINE.try_with(|deadline| *deadline).ok()
+}
+
+pub async fn with_deadline<F: Future>(deadline: Instant, future: F) -> F::Output {
+    DEADLINE.scope(deadline, future).await
+}
+
+// Runs a request's handler for at most `timeout`, dropping it if it takes longer. Anything the
+// handler spawns with `spawn_instrumented` is held to the same deadline.
+pub async fn with_timeout<F: Future>(
+    timeout: Duration,
+    future: F,
+) -> Result<F::Output, ApplicationError> {
+    let deadline = Instant::now() + timeout;
+
+    tokio::time::timeout_at(deadline, with_deadline(deadline, future))
+        .await
+        .map_err(|_| ApplicationError::TimedOut {
+            timeout_ms: timeout.as_millis() as u64,
+        })
+}
+
+// `tokio::spawn` for work started on behalf of a request. The task runs in the span it was spawned
+// from, so its logs and the spans it opens stay in the request's trace, and under the request's
+// deadline, after which it is dropped. Gives `None` if the deadline passed first.
+pub fn spawn_instrumented<F>(future: F) -> JoinHandle<Option<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let span = tracing::Span::current();
+    let deadline = current_deadline();
+
+    tokio::spawn(
+        async move {
+            let Some(deadline) = deadline else {
+                return Some(future.await);
+            };
+
+            match tokio::time::timeout_at(deadline, with_deadline(deadline, future)).await {
+                Ok(output) => Some(output),
+                Err(_) => {
+                    log::warn!("Spawned task was cancelled as it ran past its deadline");
+                    None
+                }
+            }
+        }
+        .instrument(span),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_spawned_task_should_keep_the_span_and_deadline_it_was_spawned_with() {
+        // Spans only get ids when there is a subscriber, the test runtime is single threaded so
+        // the spawned task sees it too
+        let _subscriber = tracing::subscriber::set_default(tracing_subscriber::registry());
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let span = tracing::info_span!("request");
+
+        let (spawned_span, spawned_deadline) = with_deadline(
+            deadline,
+            async {
+                spawn_instrumented(async { (tracing::Span::current().id(), current_deadline()) })
+                    .await
+                    .unwrap()
+                    .unwrap()
+            }
+            .instrument(span.clone()),
+        )
+        .await;
+
+        assert!(spawned_span.is_some());
+        assert_eq!(spawned_span, span.id());
+        assert_eq!(spawned_deadline, Some(deadline));
+    }
+
+    #[tokio::test]
+    async fn a_spawned_task_should_be_cancelled_once_its_deadline_has_passed() {
+        let deadline = Instant::now() + Duration::from_millis(10);
+
+        let output = with_deadline(deadline, async {
+            spawn_instrumented(tokio::time::sleep(Duration::from_secs(60))).await.unwrap()
+        })
+        .await;
+
+        assert_eq!(output, None);
+        assert_eq!(current_deadline(), None);
+    }
+
+    #[tokio::test]
+    async fn a_handler_should_be_dropped_once_the_timeout_has_passed() {
+        let fast = with_timeout(Duration::from_secs(60), async { current_deadline() }).await;
+        let slow = with_timeout(
+            Duration::from_millis(10),
+            tokio::time::sleep(Duration::from_secs(60)),
+        )
+        .await;
+
+        assert!(fast.unwrap().is_some());
+        assert!(matches!(slow, Err(ApplicationError::TimedOut { timeout_ms: 10 })));
+    }
+}
@@ -0,0 +1,273 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::http::HeaderMap;
+use opentelemetry::propagation::text_map_propagator::FieldIter;
+use opentelemetry::propagation::{
+    Extractor, Injector, TextMapCompositePropagator, TextMapPropagator,
+};
+use opentelemetry::trace::{
+    SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+};
+use opentelemetry::Context;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{IdGenerator, RandomIdGenerator};
+
+use crate::core::{Config, IdGeneratorKind, PropagationFormat};
+
+pub const X_AMZN_TRACE_ID: &str = "x-amzn-trace-id";
+
+// The tracer provider takes the generator by value, so the choice is made in here
+#[derive(Debug)]
+pub enum ConfiguredIdGenerator {
+    Random(RandomIdGenerator),
+    Xray(XrayIdGenerator),
+}
+
+impl IdGenerator for ConfiguredIdGenerator {
+    fn new_trace_id(&self) -> TraceId {
+        match self {
+            ConfiguredIdGenerator::Random(generator) => generator.new_trace_id(),
+            ConfiguredIdGenerator::Xray(generator) => generator.new_trace_id(),
+        }
+    }
+
+    fn new_span_id(&self) -> SpanId {
+        match self {
+            ConfiguredIdGenerator::Random(generator) => generator.new_span_id(),
+            ConfiguredIdGenerator::Xray(generator) => generator.new_span_id(),
+        }
+    }
+}
+
+pub fn id_generator_from_config(config: Option<&Config>) -> ConfiguredIdGenerator {
+    match config.map(Config::telemetry_id_generator).unwrap_or_default() {
+        IdGeneratorKind::Random => ConfiguredIdGenerator::Random(RandomIdGenerator::default()),
+        IdGeneratorKind::Xray => ConfiguredIdGenerator::Xray(XrayIdGenerator::default()),
+    }
+}
+
+// The headers an incoming request's trace is continued from. `traceparent` is always read, with
+// X-Ray propagation an `X-Amzn-Trace-Id`, as added by an AWS load balancer, is read as well and
+// wins if a request has both.
+pub fn propagator_from_config(config: Option<&Config>) -> TextMapCompositePropagator {
+    let mut propagators: Vec<Box<dyn TextMapPropagator + Send + Sync>> =
+        vec![Box::new(TraceContextPropagator::new())];
+    if config.map(Config::telemetry_propagation).unwrap_or_default() == PropagationFormat::Xray {
+        propagators.push(Box::new(XrayPropagator::default()));
+    }
+
+    TextMapCompositePropagator::new(propagators)
+}
+
+// X-Ray only accepts trace ids that start with the time they were created at, in seconds, and
+// rejects those more than 30 days old. The other 96 bits are random.
+#[derive(Debug, Default)]
+pub struct XrayIdGenerator {
+    random: RandomIdGenerator,
+}
+
+impl IdGenerator for XrayIdGenerator {
+    fn new_trace_id(&self) -> TraceId {
+        let epoch_seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0) as u32;
+        let random = u128::from_be_bytes(self.random.new_trace_id().to_bytes());
+
+        TraceId::from(((epoch_seconds as u128) << 96) | (random & ((1u128 << 96) - 1)))
+    }
+
+    fn new_span_id(&self) -> SpanId {
+        self.random.new_span_id()
+    }
+}
+
+// The `X-Amzn-Trace-Id` for a span, e.g.
+// `Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1`
+pub fn x_amzn_trace_id(span_context: &SpanContext) -> Option<String> {
+    if !span_context.is_valid() {
+        return None;
+    }
+
+    let trace_id = format!("{:032x}", span_context.trace_id());
+    Some(format!(
+        "Root=1-{}-{};Parent={:016x};Sampled={}",
+        &trace_id[..8],
+        &trace_id[8..],
+        span_context.span_id(),
+        if span_context.is_sampled() { 1 } else { 0 }
+    ))
+}
+
+// Fields can come in any order and unknown ones, e.g. `Self` or `Lineage`, are ignored. A header
+// with only a `Root`, which a load balancer sends when it starts the trace, has no span to
+// continue from.
+pub fn parse_x_amzn_trace_id(header: &str) -> Option<SpanContext> {
+    let (mut trace_id, mut span_id, mut sampled) = (None, None, true);
+
+    for field in header.split(';') {
+        match field.trim().split_once('=')? {
+            ("Root", root) => {
+                let mut parts = root.split('-');
+                let (Some("1"), Some(epoch), Some(random), None) =
+                    (parts.next(), parts.next(), parts.next(), parts.next())
+                else {
+                    return None;
+                };
+                if epoch.len() != 8 || random.len() != 24 {
+                    return None;
+                }
+                trace_id = Some(TraceId::from_hex(&format!("{}{}", epoch, random)).ok()?);
+            }
+            ("Parent", parent) if parent.len() == 16 => {
+                span_id = Some(SpanId::from_hex(parent).ok()?)
+            }
+            ("Parent", _) => return None,
+            ("Sampled", flag) => sampled = flag != "0",
+            _ => {}
+        }
+    }
+
+    let span_context = SpanContext::new(
+        trace_id?,
+        span_id?,
+        match sampled {
+            true => TraceFlags::SAMPLED,
+            false => TraceFlags::default(),
+        },
+        true,
+        TraceState::default(),
+    );
+
+    span_context.is_valid().then_some(span_context)
+}
+
+#[derive(Debug)]
+pub struct XrayPropagator {
+    fields: [String; 1],
+}
+
+impl Default for XrayPropagator {
+    fn default() -> Self {
+        Self {
+            fields: [X_AMZN_TRACE_ID.to_string()],
+        }
+    }
+}
+
+impl TextMapPropagator for XrayPropagator {
+    fn inject_context(&self, cx: &Context, injector: &mut dyn Injector) {
+        if let Some(header) = x_amzn_trace_id(cx.span().span_context()) {
+            injector.set(X_AMZN_TRACE_ID, header);
+        }
+    }
+
+    fn extract_with_context(&self, cx: &Context, extractor: &dyn Extractor) -> Context {
+        match extractor.get(X_AMZN_TRACE_ID).and_then(parse_x_amzn_trace_id) {
+            Some(span_context) => cx.with_remote_span_context(span_context),
+            None => cx.clone(),
+        }
+    }
+
+    fn fields(&self) -> FieldIter<'_> {
+        FieldIter::new(&self.fields)
+    }
+}
+
+// Reads the trace headers of an incoming request
+pub struct HeaderExtractor<'a>(pub &'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+// The trace an incoming request is part of, using the propagator `init_tracing_subscriber`
+// installed. Without one, e.g. in tests, every request starts a new trace.
+pub fn incoming_context(headers: &HeaderMap) -> Context {
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(headers))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_x_amzn_trace_id_should_round_trip_to_the_same_span_context() {
+        let header = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1";
+
+        let span_context = parse_x_amzn_trace_id(header).unwrap();
+
+        assert_eq!(
+            format!("{:032x}", span_context.trace_id()),
+            "5759e988bd862e3fe1be46a994272793"
+        );
+        assert!(span_context.is_sampled());
+        assert!(span_context.is_remote());
+        assert_eq!(x_amzn_trace_id(&span_context).as_deref(), Some(header));
+    }
+
+    #[test]
+    fn a_root_without_a_parent_or_a_malformed_header_should_be_ignored() {
+        assert!(parse_x_amzn_trace_id("Root=1-5759e988-bd862e3fe1be46a994272793").is_none());
+        assert!(parse_x_amzn_trace_id("Root=2-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8").is_none());
+        assert!(parse_x_amzn_trace_id("Root=1-5759e988;Parent=53995c3f42cd8ad8").is_none());
+        assert!(parse_x_amzn_trace_id("nonsense").is_none());
+
+        let unsampled = parse_x_amzn_trace_id(
+            "Self=1-67891234-12456789abcdef012345678;Parent=53995c3f42cd8ad8;Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=0",
+        )
+        .unwrap();
+        assert!(!unsampled.is_sampled());
+    }
+
+    #[test]
+    fn xray_trace_ids_should_start_with_the_current_time() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+
+        let trace_id = XrayIdGenerator::default().new_trace_id().to_bytes();
+        let epoch_seconds = u32::from_be_bytes(trace_id[..4].try_into().unwrap());
+
+        assert!(epoch_seconds.abs_diff(now) <= 1);
+        assert_ne!(trace_id[4..], [0; 12]);
+    }
+
+    #[test]
+    fn xray_propagation_should_prefer_the_amazon_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".parse().unwrap(),
+        );
+        headers.insert(
+            X_AMZN_TRACE_ID,
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1"
+                .parse()
+                .unwrap(),
+        );
+        let trace_id = |propagator: TextMapCompositePropagator| {
+            let cx = propagator.extract(&HeaderExtractor(&headers));
+            format!("{:032x}", cx.span().span_context().trace_id())
+        };
+
+        assert_eq!(
+            trace_id(propagator_from_config(None)),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+        let xray = TextMapCompositePropagator::new(vec![
+            Box::new(TraceContextPropagator::new()),
+            Box::new(XrayPropagator::default()),
+        ]);
+        assert_eq!(trace_id(xray), "5759e988bd862e3fe1be46a994272793");
+    }
+}
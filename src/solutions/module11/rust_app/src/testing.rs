@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+// Only what the prelude exports, so this builds the state the way a library consumer would
+use crate::metrics::MetricsSnapshot;
+use crate::prelude::*;
+
+struct DiscardMagicLinks;
+
+#[async_trait::async_trait]
+impl MagicLinkSender for DiscardMagicLinks {
+    async fn send(&self, _email_address: &str, _link: &str) -> Result<(), ApplicationError> {
+        Ok(())
+    }
+}
+
+// The API served in the test's own process on a random port, backed by in-memory users, so tests
+// can make real HTTP requests and then read the metrics those requests recorded.
+pub struct TestApp {
+    pub base_url: String,
+    metrics: Arc<Metrics>,
+    server: tokio::task::JoinHandle<()>,
+}
+
+impl TestApp {
+    pub async fn spawn() -> Result<Self, ApplicationError> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?;
+        let base_url = format!(
+            "http://{}",
+            listener
+                .local_addr()
+                .map_err(|e| ApplicationError::ApplicationError(e.to_string()))?
+        );
+        let metrics = Arc::new(Metrics::default());
+
+        let state = AppState {
+            data_access: Arc::new(InMemoryUsers::default()),
+            sessions: SessionManager::new(AuthMode::None, "test-signing-key", 3600, false),
+            revocations: Arc::new(InMemoryRevocations::default()),
+            active_sessions: Arc::new(InMemoryActiveSessions::default()),
+            magic_links: MagicLinks::new(
+                Arc::new(InMemoryMagicLinkTokens::default()),
+                Arc::new(DiscardMagicLinks),
+                &base_url,
+                900,
+            ),
+            registration_guard: Arc::new(AllowAllRegistrations),
+            brute_force: None,
+            response_cache: None,
+            cors: None,
+            user_reads: None,
+            route_toggles: None,
+            sandbox: None,
+            login_history: None,
+            audit_log: None,
+            avatars: Avatars::new(Arc::new(InMemoryBlobStore::default()), 1024 * 1024),
+            connections: Arc::new(ConnectionStats::default()),
+            metrics: metrics.clone(),
+        };
+
+        let app = router(Arc::new(state), false);
+        let server = tokio::spawn(async move {
+            let _ = axum::serve(listener, app.into_make_service()).await;
+        });
+
+        Ok(Self {
+            base_url,
+            metrics,
+            server,
+        })
+    }
+
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
@@ -0,0 +1,68 @@
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use axum::Router;
+use tower::ServiceExt;
+
+use crate::core::{AuthMode, Config, User};
+use crate::AppState;
+
+#[derive(Clone, Debug)]
+pub struct WarmupSettings {
+    pub connections: u32,
+    pub hot_users: Vec<String>,
+}
+
+impl WarmupSettings {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            connections: config.warmup_connections(),
+            hot_users: config.warmup_hot_users(),
+        }
+    }
+}
+
+// Runs before the listener is bound, so nothing reaches the API until it has finished. Warm-up only
+// makes the first requests faster, a step that fails is logged and the API starts regardless.
+// Returns how many of the hot users were put in the response cache.
+pub async fn warm_up(state: &AppState, app: &Router, settings: &WarmupSettings) -> usize {
+    if let Err(e) = state.data_access.warm_up(settings.connections).await {
+        log::warn!("Unable to open the database connections up front: {:?}", e);
+    }
+
+    match tokio::task::spawn_blocking(User::warm_up).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => log::warn!("Unable to warm up password hashing: {:?}", e),
+        Err(e) => log::warn!("Unable to warm up password hashing: {:?}", e),
+    }
+
+    cache_hot_users(state, app, &settings.hot_users).await
+}
+
+// The users are requested through the router, so they are cached exactly as a real request would
+// have cached them. Responses are cached per session, only the anonymous ones can be shared.
+async fn cache_hot_users(state: &AppState, app: &Router, hot_users: &[String]) -> usize {
+    if hot_users.is_empty() {
+        return 0;
+    }
+    if state.response_cache.is_none() || state.sessions.mode() != AuthMode::None {
+        log::warn!("Hot users are only cached when the response cache is enabled without auth");
+        return 0;
+    }
+
+    let mut cached = 0;
+    for email_address in hot_users {
+        let request = Request::get(format!("/users/{}", email_address))
+            .body(Body::empty())
+            .expect("a path is a valid request");
+
+        let Ok(response) = app.clone().oneshot(request).await;
+        match response.status() {
+            StatusCode::OK => cached += 1,
+            status => log::warn!("Unable to cache a hot user: {}", status),
+        }
+    }
+
+    log::info!("Cached {} of {} hot users", cached, hot_users.len());
+
+    cached
+}
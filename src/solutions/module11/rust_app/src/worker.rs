@@ -1,24 +1,19 @@
 use log::info;
-use rust_users_lib::{init_tracing_subscriber, ApplicationError};
-use tokio::signal;
+use rust_users_lib::{init_tracing_subscriber, shutdown_signal, ApplicationError};
 
 #[tokio::main]
 async fn main() -> Result<(), ApplicationError> {
     info!("Starting the application");
 
     rust_users_lib::init_logger();
-    let _otel_guard = init_tracing_subscriber();
+    let runtime_guard = init_tracing_subscriber()?;
 
-    tokio::spawn(async move { rust_users_lib::start_background_worker().await });
+    rust_users_lib::tasks::spawn_instrumented(async move {
+        rust_users_lib::run_background_worker().await
+    });
 
-    match signal::ctrl_c().await {
-        Ok(()) => {
-            info!("Shutting down");
-        }
-        Err(err) => {
-            eprintln!("Unable to listen for shutdown signal: {}", err);
-        }
-    };
+    shutdown_signal().await;
+    runtime_guard.shut_down(&Ok(())).await;
 
     Ok(())
 }
@@ -1,3 +1,3 @@
-mod core;
-
-pub use core::{LoginRequest, RegisterUserRequest, User, UserDetails};
+// The user domain lives in the shared workshop-core crate, this module re-exports the parts the
+// application uses so the rest of the crate keeps importing them from `crate::core`
+pub use workshop_core::{LoginRequest, RegisterUserRequest, User, UserDetails};
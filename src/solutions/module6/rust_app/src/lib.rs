@@ -33,14 +33,18 @@ async fn register_user(
     // this argument tells axum to parse the request body
     // as JSON into a `RegisterUserRequest` type
     Json(payload): Json<RegisterUserRequest>,
-) -> (StatusCode, Json<UserDetails>) {
+) -> (StatusCode, Json<Option<UserDetails>>) {
     // insert your application logic here
-    let user = User::new(&payload.email_address, &payload.name, &payload.password);
+    let user = match User::new(&payload.email_address, &payload.name, &payload.password) {
+        Ok(user) => user,
+        // The password couldn't be hashed
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)),
+    };
     state.write().unwrap().users.push(user.clone());
 
     // this will be converted into a JSON response
     // with a status code of `201 Created`
-    (StatusCode::CREATED, Json(user.details().clone()))
+    (StatusCode::CREATED, Json(Some(user.details().clone())))
 }
 
 async fn login(
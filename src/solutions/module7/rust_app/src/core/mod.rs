@@ -1,3 +1,4 @@
 mod core;
 
-pub use core::{DataAccess, LoginRequest, RegisterUserRequest, User, UserDetails, UserDto};
+pub use core::{DataAccess, UserDto};
+pub use workshop_core::{LoginRequest, RegisterUserRequest, User, UserDetails};
@@ -47,14 +47,18 @@ async fn register_user<TDataAccess: DataAccess + Send + Sync>(
     // Inject the state into the functions
     State(state): State<Arc<AppState<TDataAccess>>>,
     Json(payload): Json<RegisterUserRequest>,
-) -> (StatusCode, Json<UserDetails>) {
-    let user = User::new(&payload.email_address, &payload.name, &payload.password);
+) -> (StatusCode, Json<Option<UserDetails>>) {
+    let user = match User::new(&payload.email_address, &payload.name, &payload.password) {
+        Ok(user) => user,
+        // The password couldn't be hashed
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)),
+    };
 
     let existing_user = state.data_access.with_email_address(&user.email_address());
 
     state.data_access.store(user.clone());
 
-    (StatusCode::CREATED, Json(user.details().clone()))
+    (StatusCode::CREATED, Json(Some(user.details().clone())))
 }
 
 async fn login<TDataAccess: DataAccess + Send + Sync>(
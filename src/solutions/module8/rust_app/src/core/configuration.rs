@@ -2,7 +2,7 @@ use figment::providers::{Env, Format};
 use figment::Figment;
 use serde::Deserialize;
 
-use super::core::ApplicationError;
+use workshop_core::ApplicationError;
 
 #[derive(Deserialize)]
 pub struct Config {
@@ -2,6 +2,7 @@ mod configuration;
 mod core;
 
 pub use configuration::Config;
-pub use core::{
-    ApplicationError, DataAccess, LoginRequest, RegisterUserRequest, User, UserDetails,
-};
+pub use core::DataAccess;
+// Solution: `User::new` checks the email address, name and password with the `validation` feature
+// of `workshop-core`, as it does from module 9 on
+pub use workshop_core::{ApplicationError, LoginRequest, RegisterUserRequest, User, UserDetails};
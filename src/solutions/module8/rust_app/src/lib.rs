@@ -109,3 +109,101 @@ async fn get_user_details<TDataAccess: DataAccess>(
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // Keeps what was stored, so a test can tell a rejected registration never reached the store
+    struct ManualMockDataAccess {
+        stored: Mutex<Vec<User>>,
+    }
+
+    impl ManualMockDataAccess {
+        pub fn new() -> Self {
+            Self {
+                stored: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl DataAccess for ManualMockDataAccess {
+        async fn with_email_address(&self, email_address: &str) -> Result<User, ApplicationError> {
+            self.stored
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|user| user.email_address() == email_address)
+                .cloned()
+                .ok_or(ApplicationError::UserDoesNotExist)
+        }
+
+        async fn store(&self, user: User) -> Result<(), ApplicationError> {
+            self.stored.lock().unwrap().push(user);
+            Ok(())
+        }
+    }
+
+    async fn register(
+        email_address: &str,
+        password: &str,
+    ) -> (StatusCode, Option<UserDetails>, usize) {
+        let shared_state = Arc::new(AppState {
+            data_access: ManualMockDataAccess::new(),
+        });
+
+        let (status, Json(response)) = register_user(
+            State(shared_state.clone()),
+            Json(RegisterUserRequest {
+                email_address: email_address.to_string(),
+                name: "Test User".to_string(),
+                password: password.to_string(),
+                phone_number: None,
+            }),
+        )
+        .await;
+
+        let stored = shared_state.data_access.stored.lock().unwrap().len();
+        (status, response, stored)
+    }
+
+    #[tokio::test]
+    async fn test_register_user_with_manual_mock() {
+        let (status, response, stored) = register("test@test.com", "Purple-Otter-Canoe-42").await;
+
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(response.is_some());
+        assert_eq!(stored, 1);
+    }
+
+    #[tokio::test]
+    async fn test_register_user_should_reject_an_invalid_email_address() {
+        let (status, response, stored) = register("not-an-email-address", "Testing!23").await;
+
+        assert_ne!(status, StatusCode::CREATED);
+        assert!(response.is_none());
+        assert_eq!(stored, 0);
+    }
+
+    #[tokio::test]
+    async fn test_register_user_should_reject_a_short_password() {
+        let (status, response, stored) = register("test@test.com", "short").await;
+
+        assert_ne!(status, StatusCode::CREATED);
+        assert!(response.is_none());
+        assert_eq!(stored, 0);
+    }
+
+    #[test]
+    fn test_user_new_should_report_which_field_failed_validation() {
+        match User::new("not-an-email-address", "Test User", "Testing!23") {
+            Err(ApplicationError::ValidationFailed(violations)) => {
+                assert_eq!(violations.len(), 1);
+                assert_eq!(violations[0].field, "emailAddress");
+            }
+            _ => panic!("Expected ApplicationError::ValidationFailed"),
+        }
+    }
+}
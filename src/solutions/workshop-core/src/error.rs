@@ -0,0 +1,181 @@
+use serde::Serialize;
+use thiserror::Error;
+
+// One rule a field of a request broke, e.g. `password` and `min_length`. `field` is named as it is
+// in the JSON body, `constraint` is stable for clients to key on, `message` is for people.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FieldViolation {
+    pub field: String,
+    pub constraint: String,
+    pub message: String,
+}
+
+fn fields(violations: &[FieldViolation]) -> String {
+    let mut fields: Vec<&str> = violations.iter().map(|violation| violation.field.as_str()).collect();
+    fields.dedup();
+    fields.join(", ")
+}
+
+#[derive(Clone, Error, Debug)]
+pub enum ApplicationError {
+    #[error("user already exists")]
+    UserAlreadyExists,
+    #[cfg(feature = "usernames")]
+    #[error("the username {0} is taken")]
+    UsernameTaken(String),
+    #[error("user does not exist")]
+    UserDoesNotExist,
+    #[error("the user has changed since the version that was read")]
+    VersionMismatch,
+    #[error("the provider password is incorrect")]
+    IncorrectPassword,
+    #[error("the session is missing, invalid or expired")]
+    InvalidSession,
+    // The caller is signed in but may not act on this user or resource
+    #[error("not allowed to access this resource")]
+    Forbidden,
+    #[cfg(feature = "strength")]
+    #[error("the password is too easy to guess")]
+    WeakPassword {
+        score: u8,
+        warning: Option<String>,
+        suggestions: Vec<String>,
+    },
+    #[error("too many attempts, retry in {retry_after_seconds} seconds")]
+    Throttled { retry_after_seconds: u64 },
+    #[error("registration was rejected: {0}")]
+    RegistrationRejected(String),
+    #[error("invalid name: {0}")]
+    InvalidName(String),
+    // The request itself is malformed, e.g. a query string parameter out of range
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    // The request body isn't in a format the endpoint accepts, e.g. form data sent to a JSON one
+    #[error("unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+    // Every field of the request that broke a rule, not just the first
+    #[error("invalid fields: {}", fields(.0))]
+    ValidationFailed(Vec<FieldViolation>),
+    #[error("the request did not finish within {timeout_ms} milliseconds")]
+    TimedOut { timeout_ms: u64 },
+    // The route exists but has been switched off in config, e.g. until a workshop module gets
+    // to it. `hint` says how to switch it on.
+    #[error("{route} is not enabled")]
+    RouteDisabled { route: String, hint: String },
+    #[error("error interacting with database {0}")]
+    DatabaseError(String),
+    // The database didn't answer in time, e.g. a statement ran past its timeout. Likely to pass if
+    // retried once it is less busy.
+    #[error("the database did not answer in time {0}")]
+    DatabaseTimeout(String),
+    #[error("unexpected application error {0}")]
+    ApplicationError(String),
+}
+
+impl ApplicationError {
+    // A stable identifier for the kind of error, for clients and dashboards to key on rather than
+    // the message. Codes are part of the API, renaming one is a breaking change.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApplicationError::UserAlreadyExists => "USER_ALREADY_EXISTS",
+            #[cfg(feature = "usernames")]
+            ApplicationError::UsernameTaken(_) => "USERNAME_TAKEN",
+            ApplicationError::UserDoesNotExist => "USER_DOES_NOT_EXIST",
+            ApplicationError::VersionMismatch => "VERSION_MISMATCH",
+            ApplicationError::IncorrectPassword => "INCORRECT_PASSWORD",
+            ApplicationError::InvalidSession => "INVALID_SESSION",
+            ApplicationError::Forbidden => "FORBIDDEN",
+            #[cfg(feature = "strength")]
+            ApplicationError::WeakPassword { .. } => "WEAK_PASSWORD",
+            ApplicationError::Throttled { .. } => "THROTTLED",
+            ApplicationError::RegistrationRejected(_) => "REGISTRATION_REJECTED",
+            ApplicationError::InvalidName(_) => "INVALID_NAME",
+            ApplicationError::InvalidRequest(_) => "INVALID_REQUEST",
+            ApplicationError::UnsupportedMediaType(_) => "UNSUPPORTED_MEDIA_TYPE",
+            ApplicationError::ValidationFailed(_) => "VALIDATION_FAILED",
+            ApplicationError::TimedOut { .. } => "TIMED_OUT",
+            ApplicationError::RouteDisabled { .. } => "ROUTE_DISABLED",
+            ApplicationError::DatabaseError(_) => "DATABASE_ERROR",
+            ApplicationError::DatabaseTimeout(_) => "DATABASE_TIMEOUT",
+            ApplicationError::ApplicationError(_) => "INTERNAL_ERROR",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    // Deliberately repeats `code` rather than calling it, a new variant doesn't compile here until
+    // its code is pinned, and changing an existing code fails the test below
+    fn pinned_code(error: &ApplicationError) -> &'static str {
+        match error {
+            ApplicationError::UserAlreadyExists => "USER_ALREADY_EXISTS",
+            #[cfg(feature = "usernames")]
+            ApplicationError::UsernameTaken(_) => "USERNAME_TAKEN",
+            ApplicationError::UserDoesNotExist => "USER_DOES_NOT_EXIST",
+            ApplicationError::VersionMismatch => "VERSION_MISMATCH",
+            ApplicationError::IncorrectPassword => "INCORRECT_PASSWORD",
+            ApplicationError::InvalidSession => "INVALID_SESSION",
+            ApplicationError::Forbidden => "FORBIDDEN",
+            #[cfg(feature = "strength")]
+            ApplicationError::WeakPassword { .. } => "WEAK_PASSWORD",
+            ApplicationError::Throttled { .. } => "THROTTLED",
+            ApplicationError::RegistrationRejected(_) => "REGISTRATION_REJECTED",
+            ApplicationError::InvalidName(_) => "INVALID_NAME",
+            ApplicationError::InvalidRequest(_) => "INVALID_REQUEST",
+            ApplicationError::UnsupportedMediaType(_) => "UNSUPPORTED_MEDIA_TYPE",
+            ApplicationError::ValidationFailed(_) => "VALIDATION_FAILED",
+            ApplicationError::TimedOut { .. } => "TIMED_OUT",
+            ApplicationError::RouteDisabled { .. } => "ROUTE_DISABLED",
+            ApplicationError::DatabaseError(_) => "DATABASE_ERROR",
+            ApplicationError::DatabaseTimeout(_) => "DATABASE_TIMEOUT",
+            ApplicationError::ApplicationError(_) => "INTERNAL_ERROR",
+        }
+    }
+
+    #[test]
+    fn error_codes_should_never_change() {
+        let errors = vec![
+            ApplicationError::UserAlreadyExists,
+            #[cfg(feature = "usernames")]
+            ApplicationError::UsernameTaken("james".to_string()),
+            ApplicationError::UserDoesNotExist,
+            ApplicationError::VersionMismatch,
+            ApplicationError::IncorrectPassword,
+            ApplicationError::InvalidSession,
+            ApplicationError::Forbidden,
+            #[cfg(feature = "strength")]
+            ApplicationError::WeakPassword {
+                score: 0,
+                warning: None,
+                suggestions: Vec::new(),
+            },
+            ApplicationError::Throttled {
+                retry_after_seconds: 30,
+            },
+            ApplicationError::RegistrationRejected("challenge failed".to_string()),
+            ApplicationError::InvalidName("name must not be empty".to_string()),
+            ApplicationError::InvalidRequest("limit must be at most 100".to_string()),
+            ApplicationError::UnsupportedMediaType("expected application/json".to_string()),
+            ApplicationError::ValidationFailed(Vec::new()),
+            ApplicationError::TimedOut { timeout_ms: 5000 },
+            ApplicationError::RouteDisabled {
+                route: "POST /login".to_string(),
+                hint: "add it to routes.enabled".to_string(),
+            },
+            ApplicationError::DatabaseError("connection refused".to_string()),
+            ApplicationError::DatabaseTimeout("statement timeout".to_string()),
+            ApplicationError::ApplicationError("unexpected".to_string()),
+        ];
+
+        for error in &errors {
+            assert_eq!(error.code(), pinned_code(error), "{:?}", error);
+        }
+
+        let unique: HashSet<&str> = errors.iter().map(ApplicationError::code).collect();
+        assert_eq!(unique.len(), errors.len());
+    }
+}
@@ -0,0 +1,22 @@
+// The user domain shared by the workshop solutions from module 6 onwards. Modules re-export these
+// types from their own `core` module and opt into behaviour with features as the workshop
+// introduces it, so a fix made here lands in every module at once.
+mod error;
+mod user;
+
+pub use error::{ApplicationError, FieldViolation};
+pub use user::{LoginRequest, RegisterUserRequest, User, UserBuilder, UserDetails};
+#[cfg(feature = "roles")]
+pub use user::Role;
+#[cfg(feature = "strength")]
+pub use user::MINIMUM_PASSWORD_SCORE;
+#[cfg(feature = "validation")]
+pub use user::MAX_NAME_LENGTH;
+#[cfg(feature = "birthdays")]
+pub use user::{MAXIMUM_AGE, MINIMUM_AGE};
+#[cfg(feature = "usernames")]
+pub use user::{MAX_USERNAME_LENGTH, MIN_USERNAME_LENGTH};
+#[cfg(feature = "metadata")]
+pub use user::{Metadata, MAX_METADATA_BYTES};
+#[cfg(feature = "tiers")]
+pub use user::{Capabilities, Tier};
@@ -0,0 +1,1552 @@
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "validation")]
+use regex::Regex;
+#[cfg(feature = "validation")]
+use std::sync::LazyLock;
+#[cfg(feature = "validation")]
+use unicode_segmentation::UnicodeSegmentation;
+#[cfg(feature = "birthdays")]
+use time::Date;
+#[cfg(feature = "ids")]
+use uuid::Uuid;
+
+use crate::error::ApplicationError;
+#[cfg(any(
+    feature = "validation",
+    feature = "birthdays",
+    feature = "usernames",
+    feature = "phones",
+    feature = "metadata"
+))]
+use crate::error::FieldViolation;
+
+// zxcvbn scores range from 0 (too guessable) to 4 (very unguessable)
+#[cfg(feature = "strength")]
+pub const MINIMUM_PASSWORD_SCORE: u8 = 3;
+
+// Counted in graphemes, so a name gets the same allowance whether it's written with accents,
+// in another script or with emoji
+#[cfg(feature = "validation")]
+pub const MAX_NAME_LENGTH: usize = 100;
+
+// The youngest a user may be, the usual minimum for an account holding personal data, and the
+// oldest a date of birth is believable for
+#[cfg(feature = "birthdays")]
+pub const MINIMUM_AGE: i32 = 13;
+#[cfg(feature = "birthdays")]
+pub const MAXIMUM_AGE: i32 = 150;
+
+#[cfg(feature = "usernames")]
+pub const MIN_USERNAME_LENGTH: usize = 3;
+#[cfg(feature = "usernames")]
+pub const MAX_USERNAME_LENGTH: usize = 30;
+
+// A user's metadata is a JSON object, its keys up to whoever extends the model
+#[cfg(feature = "metadata")]
+pub type Metadata = serde_json::Map<String, serde_json::Value>;
+
+// Measured as the object serializes, it is sent back with every read of the user
+#[cfg(feature = "metadata")]
+pub const MAX_METADATA_BYTES: usize = 4096;
+
+// Compiled on first use rather than on every registration
+#[cfg(feature = "validation")]
+static EMAIL_ADDRESS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-zA-Z0-9._%+-]+@[a-zA-Z0-9.-]+\.[a-zA-Z]{2,}$").unwrap());
+
+#[derive(Deserialize)]
+#[cfg_attr(feature = "validation", derive(validator::Validate))]
+#[serde(rename_all = "camelCase")]
+pub struct RegisterUserRequest {
+    #[cfg_attr(feature = "validation", validate(custom(function = "email_address_rule")))]
+    pub email_address: String,
+    #[cfg_attr(
+        feature = "validation",
+        validate(
+            length(min = 8, code = "min_length", message = "must be at least 8 characters long"),
+            custom(function = "password_characters_rule"),
+        )
+    )]
+    pub password: String,
+    #[cfg_attr(feature = "validation", validate(custom(function = "name_rule")))]
+    pub name: String,
+    // Always there, so enabling `phones` doesn't break those building requests without it, but
+    // only checked and kept with the feature
+    #[cfg_attr(
+        all(feature = "validation", feature = "phones"),
+        validate(custom(function = "phone_number_rule"))
+    )]
+    #[serde(default)]
+    pub phone_number: Option<String>,
+}
+
+#[cfg(feature = "validation")]
+fn email_address_rule(email_address: &str) -> Result<(), validator::ValidationError> {
+    match EMAIL_ADDRESS.is_match(email_address) {
+        true => Ok(()),
+        false => Err(validator::ValidationError::new("email")
+            .with_message("must be a valid email address".into())),
+    }
+}
+
+#[cfg(feature = "validation")]
+fn password_characters_rule(password: &str) -> Result<(), validator::ValidationError> {
+    let missing = [
+        (password.chars().any(|c| c.is_uppercase()), "uppercase", "an uppercase letter"),
+        (password.chars().any(|c| c.is_lowercase()), "lowercase", "a lowercase letter"),
+        (password.chars().any(|c| c.is_ascii_digit()), "digit", "a digit"),
+    ];
+
+    match missing.into_iter().find(|(present, _, _)| !present) {
+        Some((_, code, description)) => Err(validator::ValidationError::new(code)
+            .with_message(format!("must contain at least {}", description).into())),
+        None => Ok(()),
+    }
+}
+
+#[cfg(feature = "validation")]
+fn name_rule(name: &str) -> Result<(), validator::ValidationError> {
+    let invalid = |code: &'static str, message: String| {
+        Err(validator::ValidationError::new(code).with_message(message.into()))
+    };
+
+    if name.trim().is_empty() {
+        return invalid("required", "name must not be empty".to_string());
+    }
+    if name.chars().any(char::is_control) {
+        return invalid("control_characters", "name must not contain control characters".to_string());
+    }
+    // Stops counting at the limit, a multi-megabyte name costs no more than a long one
+    if name.graphemes(true).nth(MAX_NAME_LENGTH).is_some() {
+        return invalid(
+            "max_length",
+            format!("name must be at most {} characters", MAX_NAME_LENGTH),
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(all(feature = "validation", feature = "phones"))]
+fn phone_number_rule(phone_number: &str) -> Result<(), validator::ValidationError> {
+    e164(phone_number)
+        .map(|_| ())
+        .map_err(|(code, message)| validator::ValidationError::new(code).with_message(message.into()))
+}
+
+// The number as `+` and 8 to 15 digits, the first of them the country code. Spaces, dashes, dots
+// and brackets are dropped, and a leading `00` is taken as the international prefix.
+#[cfg(feature = "phones")]
+fn e164(phone_number: &str) -> Result<String, (&'static str, &'static str)> {
+    let compact: String = phone_number
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '.' | '(' | ')'))
+        .collect();
+    let digits = match (compact.strip_prefix('+'), compact.strip_prefix("00")) {
+        (Some(digits), _) | (None, Some(digits)) => digits,
+        (None, None) => {
+            return Err(("country_code", "phone number must start with + and the country code"))
+        }
+    };
+
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(("characters", "phone number may only contain digits after the +"));
+    }
+    if digits.starts_with('0') {
+        return Err(("country_code", "a country code never starts with 0"));
+    }
+    if !(8..=15).contains(&digits.len()) {
+        return Err(("length", "phone number must have 8 to 15 digits"));
+    }
+
+    Ok(format!("+{}", digits))
+}
+
+// `email_address` as it is sent, `emailAddress`
+#[cfg(feature = "validation")]
+fn json_field_name(field: &str) -> String {
+    let mut words = field.split('_');
+    let mut name = words.next().unwrap_or_default().to_string();
+    for word in words {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            name.extend(first.to_uppercase());
+            name.push_str(chars.as_str());
+        }
+    }
+    name
+}
+
+#[cfg(feature = "validation")]
+impl RegisterUserRequest {
+    // Checks every field against its rules, failing with each rule that was broken rather than
+    // stopping at the first, so a form can mark all of its invalid fields at once
+    pub fn check(&self) -> Result<(), ApplicationError> {
+        let errors = match validator::Validate::validate(self) {
+            Ok(()) => validator::ValidationErrors::new(),
+            Err(errors) => errors,
+        };
+        let field_errors = errors.field_errors();
+
+        for (field, span_field) in [
+            ("email_address", "user.email_is_valid"),
+            ("name", "user.name_is_valid"),
+            ("password", "user.password_is_valid"),
+        ] {
+            record(span_field, if field_errors.contains_key(field) { "false" } else { "true" });
+        }
+        if field_errors.is_empty() {
+            return Ok(());
+        }
+
+        let mut violations: Vec<FieldViolation> = field_errors
+            .iter()
+            .flat_map(|(field, errors)| {
+                errors.iter().map(|error| FieldViolation {
+                    field: json_field_name(field),
+                    constraint: error.code.to_string(),
+                    message: error
+                        .message
+                        .as_deref()
+                        .unwrap_or(&error.code)
+                        .to_string(),
+                })
+            })
+            .collect();
+        // Stable, each field's rules stay in the order they are declared in
+        violations.sort_by(|a, b| a.field.cmp(&b.field));
+
+        Err(ApplicationError::ValidationFailed(violations))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoginRequest {
+    pub email_address: String,
+    pub password: String,
+}
+
+// Validation results are attached to the span of the request handler when tracing is enabled
+#[cfg(feature = "tracing")]
+fn record<V: tracing::Value>(field: &str, value: V) {
+    tracing::Span::current().record(field, value);
+}
+
+#[cfg(not(feature = "tracing"))]
+#[allow(dead_code)]
+fn record<V>(_field: &str, _value: V) {}
+
+// What a user is allowed to do, which is separate from the tier they have. Stored by its
+// lowercase name, e.g. `"member"`.
+#[cfg(feature = "roles")]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Member,
+    Admin,
+}
+
+#[cfg(feature = "roles")]
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Member => "member",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+#[cfg(feature = "roles")]
+impl std::str::FromStr for Role {
+    type Err = ApplicationError;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "member" => Ok(Role::Member),
+            "admin" => Ok(Role::Admin),
+            _ => Err(ApplicationError::ApplicationError(format!("Unknown role {}", role))),
+        }
+    }
+}
+
+// How much a premium user pays for, from the least to the most, e.g. `"gold"`. Standard users have
+// no tier.
+#[cfg(feature = "tiers")]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Tier {
+    #[default]
+    Silver,
+    Gold,
+    Platinum,
+}
+
+#[cfg(feature = "tiers")]
+impl Tier {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Tier::Silver => "silver",
+            Tier::Gold => "gold",
+            Tier::Platinum => "platinum",
+        }
+    }
+}
+
+#[cfg(feature = "tiers")]
+impl std::str::FromStr for Tier {
+    type Err = ApplicationError;
+
+    fn from_str(tier: &str) -> Result<Self, Self::Err> {
+        match tier {
+            "silver" => Ok(Tier::Silver),
+            "gold" => Ok(Tier::Gold),
+            "platinum" => Ok(Tier::Platinum),
+            _ => Err(ApplicationError::ApplicationError(format!("Unknown tier {}", tier))),
+        }
+    }
+}
+
+// What a tier comes with. Each tier has everything the one below it has.
+#[cfg(feature = "tiers")]
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    // Addresses besides the primary one, verified or not
+    pub max_email_aliases: usize,
+    pub priority_support: bool,
+    pub early_access: bool,
+}
+
+#[cfg(feature = "tiers")]
+impl Capabilities {
+    pub fn of(tier: Option<Tier>) -> Capabilities {
+        match tier {
+            None => Capabilities {
+                max_email_aliases: 1,
+                priority_support: false,
+                early_access: false,
+            },
+            Some(Tier::Silver) => Capabilities {
+                max_email_aliases: 3,
+                priority_support: false,
+                early_access: false,
+            },
+            Some(Tier::Gold) => Capabilities {
+                max_email_aliases: 5,
+                priority_support: true,
+                early_access: false,
+            },
+            Some(Tier::Platinum) => Capabilities {
+                max_email_aliases: 10,
+                priority_support: true,
+                early_access: true,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct UserDetails {
+    #[cfg(feature = "ids")]
+    id: Uuid,
+    #[cfg(feature = "roles")]
+    role: Role,
+    email_address: String,
+    // The hash, never sent to clients
+    #[serde(skip_serializing)]
+    password: String,
+    // With `birthdays`, worked out from the date of birth when the user is built or their date of
+    // birth changes, so a response carries the age as of when the user was read
+    age: Option<i32>,
+    // Sent as `YYYY-MM-DD`
+    #[cfg(feature = "birthdays")]
+    date_of_birth: Option<Date>,
+    // Stored in lower case, unique across users
+    #[cfg(feature = "usernames")]
+    username: Option<String>,
+    // Always in E.164 form, e.g. `+442079460958`
+    #[cfg(feature = "phones")]
+    phone_number: Option<String>,
+    #[cfg(feature = "metadata")]
+    metadata: Metadata,
+    name: String,
+}
+
+#[derive(Clone)]
+pub enum User {
+    Standard {
+        user_details: UserDetails,
+    },
+    Premium {
+        user_details: UserDetails,
+        is_premium: bool,
+        #[cfg(feature = "tiers")]
+        tier: Tier,
+    },
+}
+
+enum Password {
+    // Validated and hashed when the user is built
+    Plain(String),
+    Hashed(String),
+}
+
+// Builds a user from whichever of their fields are known, e.g. a stored user with their id, age
+// and role, without a constructor for every combination
+pub struct UserBuilder {
+    email_address: String,
+    name: String,
+    password: Option<Password>,
+    age: Option<i32>,
+    #[cfg(feature = "birthdays")]
+    date_of_birth: Option<Date>,
+    #[cfg(feature = "usernames")]
+    username: Option<String>,
+    #[cfg(feature = "phones")]
+    phone_number: Option<String>,
+    #[cfg(feature = "metadata")]
+    metadata: Metadata,
+    premium: bool,
+    #[cfg(feature = "tiers")]
+    tier: Tier,
+    #[cfg(feature = "ids")]
+    id: Option<Uuid>,
+    #[cfg(feature = "roles")]
+    role: Role,
+}
+
+impl UserBuilder {
+    pub fn new(email_address: &str, name: &str) -> UserBuilder {
+        UserBuilder {
+            email_address: email_address.to_string(),
+            name: name.to_string(),
+            password: None,
+            age: None,
+            #[cfg(feature = "birthdays")]
+            date_of_birth: None,
+            #[cfg(feature = "usernames")]
+            username: None,
+            #[cfg(feature = "phones")]
+            phone_number: None,
+            #[cfg(feature = "metadata")]
+            metadata: Metadata::new(),
+            premium: false,
+            #[cfg(feature = "tiers")]
+            tier: Tier::default(),
+            #[cfg(feature = "ids")]
+            id: None,
+            #[cfg(feature = "roles")]
+            role: Role::default(),
+        }
+    }
+
+    // A password chosen by the user, which `build` checks and hashes along with the email
+    // address and name
+    pub fn password(mut self, password: &str) -> UserBuilder {
+        self.password = Some(Password::Plain(password.to_string()));
+        self
+    }
+
+    // A password that was already hashed, e.g. read back from a store. Nothing is validated.
+    pub fn hashed_password(mut self, hashed_password: &str) -> UserBuilder {
+        self.password = Some(Password::Hashed(hashed_password.to_string()));
+        self
+    }
+
+    pub fn age(mut self, age: Option<i32>) -> UserBuilder {
+        self.age = age;
+        self
+    }
+
+    // Takes over from `age`, which is worked out from it instead
+    #[cfg(feature = "birthdays")]
+    pub fn date_of_birth(mut self, date_of_birth: Option<Date>) -> UserBuilder {
+        self.date_of_birth = date_of_birth;
+        self
+    }
+
+    #[cfg(feature = "usernames")]
+    pub fn username(mut self, username: Option<String>) -> UserBuilder {
+        self.username = username;
+        self
+    }
+
+    // Checked and normalized along with the password for a new user, taken as it is with a hashed
+    // one
+    #[cfg(feature = "phones")]
+    pub fn phone_number(mut self, phone_number: Option<String>) -> UserBuilder {
+        self.phone_number = phone_number;
+        self
+    }
+
+    // Taken as it is, what is stored was checked when it was set
+    #[cfg(feature = "metadata")]
+    pub fn metadata(mut self, metadata: Metadata) -> UserBuilder {
+        self.metadata = metadata;
+        self
+    }
+
+    // With `tiers`, the lowest tier
+    pub fn premium(mut self) -> UserBuilder {
+        self.premium = true;
+        self
+    }
+
+    // Without a tier the user is Standard
+    #[cfg(feature = "tiers")]
+    pub fn tier(mut self, tier: Option<Tier>) -> UserBuilder {
+        self.premium = tier.is_some();
+        self.tier = tier.unwrap_or_default();
+        self
+    }
+
+    // Without one the user gets a new id
+    #[cfg(feature = "ids")]
+    pub fn id(mut self, id: Uuid) -> UserBuilder {
+        self.id = Some(id);
+        self
+    }
+
+    #[cfg(feature = "roles")]
+    pub fn role(mut self, role: Role) -> UserBuilder {
+        self.role = role;
+        self
+    }
+
+    // Mutable for the phone number, which is stored as it was normalized
+    #[cfg_attr(not(feature = "phones"), allow(unused_mut))]
+    pub fn build(mut self) -> Result<User, ApplicationError> {
+        let password = match &self.password {
+            Some(Password::Plain(password)) => {
+                // Validation and hashing get a span each so their time can be told apart
+                #[cfg(feature = "tracing")]
+                let validating = tracing::info_span!(
+                    "user.validate",
+                    user.email_is_valid = tracing::field::Empty,
+                    user.name_is_valid = tracing::field::Empty,
+                    user.password_is_valid = tracing::field::Empty,
+                    user.password_score = tracing::field::Empty,
+                )
+                .entered();
+                #[cfg(feature = "validation")]
+                RegisterUserRequest {
+                    email_address: self.email_address.clone(),
+                    password: password.clone(),
+                    name: self.name.clone(),
+                    #[cfg(feature = "phones")]
+                    phone_number: self.phone_number.clone(),
+                    #[cfg(not(feature = "phones"))]
+                    phone_number: None,
+                }
+                .check()?;
+                #[cfg(feature = "phones")]
+                if let Some(phone_number) = &self.phone_number {
+                    self.phone_number = Some(User::normalize_phone_number(phone_number)?);
+                }
+                #[cfg(feature = "strength")]
+                User::password_is_strong(password, &[&self.email_address, &self.name])?;
+                #[cfg(feature = "tracing")]
+                drop(validating);
+
+                User::hash(password)?
+            }
+            Some(Password::Hashed(hashed_password)) => hashed_password.clone(),
+            None => {
+                return Err(ApplicationError::ApplicationError(
+                    "A user needs a password or a hashed password".to_string(),
+                ))
+            }
+        };
+
+        Ok(self.with_hashed_password(password))
+    }
+
+    fn with_hashed_password(self, password: String) -> User {
+        #[cfg(feature = "birthdays")]
+        let age = match self.date_of_birth {
+            Some(date_of_birth) => Some(age_on(date_of_birth, today())),
+            None => self.age,
+        };
+        #[cfg(not(feature = "birthdays"))]
+        let age = self.age;
+
+        let user_details = UserDetails {
+            #[cfg(feature = "ids")]
+            id: self.id.unwrap_or_else(Uuid::new_v4),
+            #[cfg(feature = "roles")]
+            role: self.role,
+            email_address: self.email_address,
+            name: self.name,
+            age,
+            #[cfg(feature = "birthdays")]
+            date_of_birth: self.date_of_birth,
+            #[cfg(feature = "usernames")]
+            username: self.username,
+            #[cfg(feature = "phones")]
+            phone_number: self.phone_number,
+            #[cfg(feature = "metadata")]
+            metadata: self.metadata,
+            password,
+        };
+
+        match self.premium {
+            true => User::Premium {
+                user_details,
+                is_premium: true,
+                #[cfg(feature = "tiers")]
+                tier: self.tier,
+            },
+            false => User::Standard { user_details },
+        }
+    }
+}
+
+impl User {
+    pub fn builder(email_address: &str, name: &str) -> UserBuilder {
+        UserBuilder::new(email_address, name)
+    }
+
+    // no 'self' at all defines a static method. Called using User::new()
+    pub fn new(email_address: &str, name: &str, password: &str) -> Result<User, ApplicationError> {
+        #[cfg(feature = "tracing")]
+        let _entered = tracing::span!(tracing::Level::INFO, "user.new", "user.type" = "standard").entered();
+
+        User::builder(email_address, name).password(password).build()
+    }
+
+    // Everything a registration sends, the phone number too when it has one
+    pub fn register(request: &RegisterUserRequest) -> Result<User, ApplicationError> {
+        #[cfg(feature = "tracing")]
+        let _entered = tracing::span!(tracing::Level::INFO, "user.new", "user.type" = "standard").entered();
+
+        let builder = User::builder(&request.email_address, &request.name).password(&request.password);
+        #[cfg(feature = "phones")]
+        let builder = builder.phone_number(request.phone_number.clone());
+
+        builder.build()
+    }
+
+    // With the `ids` feature the user gets a new id, use `with_id` to keep the one that was stored
+    pub fn from(email_address: &str, name: &str, hashed_password: &str) -> User {
+        User::builder(email_address, name).with_hashed_password(hashed_password.to_string())
+    }
+
+    // Does the one-off work the first registration would otherwise pay for: compiling the email
+    // regex, loading the zxcvbn dictionaries and allocating the memory Argon2 hashes in
+    pub fn warm_up() -> Result<(), ApplicationError> {
+        #[cfg(feature = "validation")]
+        LazyLock::force(&EMAIL_ADDRESS);
+        #[cfg(feature = "strength")]
+        zxcvbn::zxcvbn("warm-up password", &[]);
+        User::hash("warm-up password").map(|_| ())
+    }
+
+    fn hash(password: &str) -> Result<String, ApplicationError> {
+        #[cfg(feature = "tracing")]
+        let _entered = tracing::info_span!("user.hash").entered();
+
+        let argon2 = Argon2::default();
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = argon2.hash_password(password.as_bytes(), &salt)
+            .map_err(|_| ApplicationError::ApplicationError("Failed to hash password".to_string()))?;
+
+        Ok(hash.to_string())
+    }
+    
+    pub fn details(&self) -> &UserDetails {
+        match self {
+            User::Standard { user_details } => user_details,
+            User::Premium {
+                user_details,
+                ..
+            } => user_details,
+        }
+    }
+    
+    pub fn email_address(&self) -> String {
+        match self {
+            User::Standard { user_details } => user_details.email_address.clone(),
+            User::Premium {
+                user_details,
+                ..
+            } => user_details.email_address.clone(),
+        }
+    }
+    
+    pub fn name(&self) -> String {
+        match self {
+            User::Standard { user_details } => user_details.name.clone(),
+            User::Premium {
+                user_details,
+                ..
+            } => user_details.name.clone(),
+        }
+    }
+    
+    #[cfg(not(feature = "birthdays"))]
+    pub fn age(&self) -> Option<i32> {
+        self.details().age
+    }
+
+    // As of today rather than when the user was read
+    #[cfg(feature = "birthdays")]
+    pub fn age(&self) -> Option<i32> {
+        match self.details().date_of_birth {
+            Some(date_of_birth) => Some(age_on(date_of_birth, today())),
+            None => self.details().age,
+        }
+    }
+
+    #[cfg(feature = "birthdays")]
+    pub fn date_of_birth(&self) -> Option<Date> {
+        self.details().date_of_birth
+    }
+
+    #[cfg(feature = "usernames")]
+    pub fn username(&self) -> Option<String> {
+        self.details().username.clone()
+    }
+
+    #[cfg(feature = "phones")]
+    pub fn phone_number(&self) -> Option<String> {
+        self.details().phone_number.clone()
+    }
+
+    #[cfg(feature = "metadata")]
+    pub fn metadata(&self) -> Metadata {
+        self.details().metadata.clone()
+    }
+
+    #[cfg(feature = "ids")]
+    pub fn id(&self) -> Uuid {
+        self.details().id
+    }
+
+    #[cfg(feature = "ids")]
+    pub fn with_id(mut self, id: Uuid) -> User {
+        match &mut self {
+            User::Standard { user_details } => user_details.id = id,
+            User::Premium { user_details, .. } => user_details.id = id,
+        }
+
+        self
+    }
+
+    #[cfg(feature = "roles")]
+    pub fn role(&self) -> Role {
+        self.details().role
+    }
+
+    // New users are members, anything else is granted afterwards
+    #[cfg(feature = "roles")]
+    pub fn with_role(mut self, role: Role) -> User {
+        match &mut self {
+            User::Standard { user_details } => user_details.role = role,
+            User::Premium { user_details, .. } => user_details.role = role,
+        }
+
+        self
+    }
+    
+    #[cfg(feature = "tiers")]
+    pub fn tier(&self) -> Option<Tier> {
+        match self {
+            User::Standard { .. } => None,
+            User::Premium { tier, .. } => Some(*tier),
+        }
+    }
+
+    // Upgrades or downgrades the user and keeps everything else about them, without a tier they
+    // become Standard
+    #[cfg(feature = "tiers")]
+    pub fn with_tier(self, tier: Option<Tier>) -> User {
+        let user_details = match self {
+            User::Standard { user_details } | User::Premium { user_details, .. } => user_details,
+        };
+
+        match tier {
+            Some(tier) => User::Premium {
+                user_details,
+                is_premium: true,
+                tier,
+            },
+            None => User::Standard { user_details },
+        }
+    }
+
+    #[cfg(feature = "tiers")]
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::of(self.tier())
+    }
+
+    pub fn password(&self) -> String {
+        match self {
+            User::Standard { user_details } => user_details.password.clone(),
+            User::Premium {
+                user_details,
+                ..
+            } => user_details.password.clone(),
+        }
+    }
+
+    // &mut self is used because you want to mutate the data in this instance of the struct
+    pub fn update_name(&mut self, new_name: &str) {
+        let user_details = match self {
+            // The '*' is used to dereference the value of the variable, so you can change it.
+            // De-referncing refers to accessing the underlying value the reference points to
+            User::Standard { user_details } => user_details,
+            User::Premium {
+                user_details,
+                ..
+            } => user_details,
+        };
+
+        // Callers are expected to check the name with `name_is_valid` first, this only keeps
+        // the limit for those that don't
+        #[cfg(feature = "validation")]
+        let new_name = truncate_graphemes(new_name, MAX_NAME_LENGTH);
+
+        user_details.name = new_name.to_string();
+    }
+
+    pub fn update_age(&mut self, new_age: i32) {
+        let user_details = match self {
+            // The '*' is used to dereference the value of the variable, so you can change it.
+            // De-referncing refers to accessing the underlying value the reference points to
+            User::Standard { user_details } => user_details,
+            User::Premium {
+                user_details,
+                ..
+            } => user_details,
+        };
+
+        user_details.age = Some(new_age);
+    }
+
+    // `None` forgets the date of birth along with the age worked out from it. Callers are expected
+    // to check the date with `date_of_birth_is_valid` first.
+    #[cfg(feature = "birthdays")]
+    pub fn update_date_of_birth(&mut self, date_of_birth: Option<Date>) {
+        let user_details = match self {
+            User::Standard { user_details } => user_details,
+            User::Premium { user_details, .. } => user_details,
+        };
+
+        user_details.age = date_of_birth.map(|date_of_birth| age_on(date_of_birth, today()));
+        user_details.date_of_birth = date_of_birth;
+    }
+
+    // Callers are expected to check the username with `username_is_valid` first, and that no one
+    // else has it
+    #[cfg(feature = "usernames")]
+    pub fn update_username(&mut self, username: Option<&str>) {
+        let user_details = match self {
+            User::Standard { user_details } => user_details,
+            User::Premium { user_details, .. } => user_details,
+        };
+
+        user_details.username = username.map(str::to_lowercase);
+    }
+
+    // Normalized to E.164 first, `None` removes the phone number
+    #[cfg(feature = "phones")]
+    pub fn update_phone_number(&mut self, phone_number: Option<&str>) -> Result<(), ApplicationError> {
+        let phone_number = phone_number.map(User::normalize_phone_number).transpose()?;
+        let user_details = match self {
+            User::Standard { user_details } => user_details,
+            User::Premium { user_details, .. } => user_details,
+        };
+
+        user_details.phone_number = phone_number;
+        Ok(())
+    }
+
+    // Merged the way a JSON merge patch merges the top level: a key set to `null` is removed, any
+    // other value replaces what the key had. Nothing changes when the result is too large.
+    #[cfg(feature = "metadata")]
+    pub fn merge_metadata(&mut self, patch: &Metadata) -> Result<(), ApplicationError> {
+        let mut metadata = self.metadata();
+        for (key, value) in patch {
+            match value {
+                serde_json::Value::Null => metadata.remove(key),
+                value => metadata.insert(key.clone(), value.clone()),
+            };
+        }
+        User::metadata_is_valid(&metadata)?;
+
+        let user_details = match self {
+            User::Standard { user_details } => user_details,
+            User::Premium { user_details, .. } => user_details,
+        };
+        user_details.metadata = metadata;
+        Ok(())
+    }
+
+    #[cfg(feature = "metadata")]
+    pub fn metadata_is_valid(metadata: &Metadata) -> Result<(), ApplicationError> {
+        let size = serde_json::to_vec(metadata).map_or(usize::MAX, |bytes| bytes.len());
+        if size > MAX_METADATA_BYTES {
+            return Err(ApplicationError::ValidationFailed(vec![FieldViolation {
+                field: "metadata".to_string(),
+                constraint: "max_size".to_string(),
+                message: format!("metadata must be at most {} bytes as JSON", MAX_METADATA_BYTES),
+            }]));
+        }
+
+        Ok(())
+    }
+
+    // The number in E.164 form, failing the way registration does for the same field
+    #[cfg(feature = "phones")]
+    pub fn normalize_phone_number(phone_number: &str) -> Result<String, ApplicationError> {
+        e164(phone_number).map_err(|(constraint, message)| {
+            ApplicationError::ValidationFailed(vec![FieldViolation {
+                field: "phoneNumber".to_string(),
+                constraint: constraint.to_string(),
+                message: message.to_string(),
+            }])
+        })
+    }
+
+    // Using just 'self' is a rare case where you want to take ownership of the original instance and use something new
+    // calling this function will prevent the original instance from being used, as this function
+    // takes ownership and then drop the original instance
+    #[allow(dead_code)]
+    fn update_to_premium(self) -> User {
+        match self {
+            User::Standard { user_details } => User::Premium {
+                user_details,
+                is_premium: true,
+                #[cfg(feature = "tiers")]
+                tier: Tier::default(),
+            },
+            User::Premium { .. } => self,
+        }
+    }
+
+    pub fn verify_password(&self, password: &str) -> Result<(), ApplicationError> {
+        #[cfg(feature = "tracing")]
+        let _entered = tracing::info_span!("user.hash").entered();
+
+        let users_password = &self.password().clone();
+        
+        let parsed_hash = PasswordHash::new(users_password).map_err(|_| ApplicationError::ApplicationError("Failed to parse password hash".to_string()))?;
+        
+        let verified_password = Argon2::default()
+            .verify_password(password.as_bytes(), &parsed_hash);
+        
+        match verified_password {
+            Ok(_) => Ok(()),
+            Err(_) => Err(ApplicationError::IncorrectPassword)
+        } 
+    }
+
+    // The character class rules above are easy to satisfy with guessable passwords like
+    // 'Password1', so the password is also scored against common patterns and the user's own details
+    #[cfg(feature = "strength")]
+    fn password_is_strong(password: &str, user_inputs: &[&str]) -> Result<(), ApplicationError> {
+        let entropy = zxcvbn::zxcvbn(password, user_inputs);
+        let score: u8 = entropy.score().into();
+
+        record("user.password_score", score);
+
+        if score >= MINIMUM_PASSWORD_SCORE {
+            return Ok(());
+        }
+
+        let (warning, suggestions) = match entropy.feedback() {
+            Some(feedback) => (
+                feedback.warning().map(|warning| warning.to_string()),
+                feedback
+                    .suggestions()
+                    .iter()
+                    .map(|suggestion| suggestion.to_string())
+                    .collect(),
+            ),
+            None => (None, Vec::new()),
+        };
+
+        Err(ApplicationError::WeakPassword {
+            score,
+            warning,
+            suggestions,
+        })
+    }
+
+    // For a name changed on its own, e.g. by a PATCH, where registration checks it with the rest of
+    // the request
+    #[cfg(feature = "validation")]
+    pub fn name_is_valid(name: &str) -> Result<(), ApplicationError> {
+        let valid = name_rule(name);
+        record("user.name_is_valid", if valid.is_ok() { "true" } else { "false" });
+
+        valid.map_err(|error| {
+            ApplicationError::InvalidName(error.message.unwrap_or(error.code).to_string())
+        })
+    }
+
+    // For an address given on its own, e.g. a secondary one added to an account, failing the way
+    // registration does for the same field
+    #[cfg(feature = "validation")]
+    pub fn email_address_is_valid(email_address: &str) -> Result<(), ApplicationError> {
+        email_address_rule(email_address).map_err(|error| {
+            ApplicationError::ValidationFailed(vec![FieldViolation {
+                field: "emailAddress".to_string(),
+                constraint: error.code.to_string(),
+                message: error.message.unwrap_or(error.code).to_string(),
+            }])
+        })
+    }
+
+    // Taken as of `today` so callers can check against their own clock. Failing the same way the
+    // other field checks do, with the field named `dateOfBirth`.
+    #[cfg(feature = "birthdays")]
+    pub fn date_of_birth_is_valid(date_of_birth: Date, today: Date) -> Result<(), ApplicationError> {
+        let violation = |constraint: &str, message: String| {
+            Err(ApplicationError::ValidationFailed(vec![FieldViolation {
+                field: "dateOfBirth".to_string(),
+                constraint: constraint.to_string(),
+                message,
+            }]))
+        };
+
+        if date_of_birth > today {
+            return violation("not_in_future", "date of birth can't be in the future".to_string());
+        }
+        let age = age_on(date_of_birth, today);
+        if age < MINIMUM_AGE {
+            return violation(
+                "minimum_age",
+                format!("users must be at least {} years old", MINIMUM_AGE),
+            );
+        }
+        if age > MAXIMUM_AGE {
+            return violation(
+                "maximum_age",
+                format!("a date of birth more than {} years ago isn't believable", MAXIMUM_AGE),
+            );
+        }
+
+        Ok(())
+    }
+
+    // Letters, digits and underscores, starting with a letter, so a username can't be mistaken for
+    // an email address or an id. Case doesn't matter, usernames are stored in lower case.
+    #[cfg(feature = "usernames")]
+    pub fn username_is_valid(username: &str) -> Result<(), ApplicationError> {
+        let violation = |constraint: &str, message: String| {
+            Err(ApplicationError::ValidationFailed(vec![FieldViolation {
+                field: "username".to_string(),
+                constraint: constraint.to_string(),
+                message,
+            }]))
+        };
+
+        if !(MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&username.len()) {
+            return violation(
+                "length",
+                format!(
+                    "username must be {} to {} characters long",
+                    MIN_USERNAME_LENGTH, MAX_USERNAME_LENGTH
+                ),
+            );
+        }
+        if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return violation(
+                "characters",
+                "username may only contain letters, digits and underscores".to_string(),
+            );
+        }
+        if !username.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            return violation("first_character", "username must start with a letter".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "birthdays")]
+fn today() -> Date {
+    time::OffsetDateTime::now_utc().date()
+}
+
+// Full years since `date_of_birth`. Someone born on 29 February turns a year older on 1 March in
+// the years without one.
+#[cfg(feature = "birthdays")]
+fn age_on(date_of_birth: Date, today: Date) -> i32 {
+    let had_birthday =
+        (today.month() as u8, today.day()) >= (date_of_birth.month() as u8, date_of_birth.day());
+
+    today.year() - date_of_birth.year() - if had_birthday { 0 } else { 1 }
+}
+
+impl std::fmt::Display for User {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            User::Standard { user_details } => {
+                write!(f, "Standard User: {}", user_details.email_address)
+            }
+            User::Premium {
+                user_details,
+                ..
+            } => write!(f, "Premium User: {}", user_details.email_address),
+        }
+    }
+}
+
+// Users are the same user if they have the same email address and tier, whatever else has changed
+impl PartialEq for User {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                User::Standard { user_details },
+                User::Standard {
+                    user_details: other_user_details,
+                },
+            ) => user_details.email_address == other_user_details.email_address,
+            (
+                User::Premium {
+                    user_details,
+                    ..
+                },
+                User::Premium {
+                    user_details: other_user_details,
+                    ..
+                },
+            ) => user_details.email_address == other_user_details.email_address,
+            _ => false,
+        }
+    }
+}
+
+// Cuts between graphemes, so an accented letter or an emoji is never split into broken pieces
+#[cfg(feature = "validation")]
+fn truncate_graphemes(input: &str, max: usize) -> &str {
+    match input.grapheme_indices(true).nth(max) {
+        Some((end, _)) => &input[..end],
+        None => input,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn when_new_user_is_created_should_be_standard() {
+        let user = User::new("test@test.com", "James", "Purple-Otter-Canoe-42").unwrap();
+        
+        if let User::Standard { user_details } = user {
+            assert_eq!(user_details.email_address, "test@test.com");
+            assert_eq!(user_details.name, "James");
+        } else {
+            panic!("Expected User::Standard variant");
+        }
+    }
+
+    #[test]
+    fn when_user_is_updated_to_premium_should_be_premium_user() {
+        let user = User::new("test@test.com", "James", "Purple-Otter-Canoe-42").unwrap();
+        
+        let premium_user = user.update_to_premium();
+
+        if let User::Premium { user_details, .. } = premium_user {
+            assert_eq!(user_details.email_address, "test@test.com");
+            assert_eq!(user_details.name, "James");
+        } else {
+            panic!("Expected User::Standard variant");
+        }
+    }
+
+    #[test]
+    fn when_a_user_is_created_should_be_able_to_update_age() {
+        let mut user = User::new("test@test.com", "James", "Purple-Otter-Canoe-42").unwrap();
+
+        assert_eq!(user.details().age, None);
+        
+        user.update_age(10);
+
+        assert_eq!(user.details().age.unwrap(), 10);
+    }
+
+    #[test]
+    fn when_a_user_is_created_should_be_able_to_update_name() {
+        let mut user = User::new("test@test.com", "James", "Purple-Otter-Canoe-42").unwrap();
+
+        assert_eq!(user.details().name, "James");
+        
+        user.update_name("John");
+
+        assert_eq!(user.details().name, "John");
+    }
+
+    #[cfg(feature = "validation")]
+    #[test]
+    fn when_user_is_created_with_an_invalid_email_should_return_error() {
+        let user = User::new("thisisaninvalidemail", "James", "Purple-Otter-Canoe-42");
+
+        assert!(user.is_err());
+    }
+
+    #[cfg(feature = "validation")]
+    #[test]
+    fn an_email_address_checked_on_its_own_should_fail_like_registration() {
+        let registration = RegisterUserRequest {
+            email_address: "thisisaninvalidemail".to_string(),
+            password: "Purple-Otter-Canoe-42".to_string(),
+            name: "James".to_string(),
+            phone_number: None,
+        }
+        .check();
+
+        assert!(User::email_address_is_valid("james@test.com").is_ok());
+        match (User::email_address_is_valid("thisisaninvalidemail"), registration) {
+            (
+                Err(ApplicationError::ValidationFailed(alone)),
+                Err(ApplicationError::ValidationFailed(registered)),
+            ) => assert_eq!(alone, registered),
+            other => panic!("Expected ApplicationError::ValidationFailed, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "validation")]
+    #[test]
+    fn when_user_is_created_with_an_invalid_password_should_return_error() {
+        let user = User::new("test@test.com", "James", "james");
+
+        assert!(user.is_err());
+    }
+
+    #[cfg(feature = "validation")]
+    #[test]
+    fn when_a_registration_breaks_several_rules_should_list_every_one() {
+        let request = RegisterUserRequest {
+            email_address: "thisisaninvalidemail".to_string(),
+            password: "james".to_string(),
+            name: "James".to_string(),
+            phone_number: None,
+        };
+
+        let violations = match request.check() {
+            Err(ApplicationError::ValidationFailed(violations)) => violations,
+            other => panic!("Expected ApplicationError::ValidationFailed, got {:?}", other),
+        };
+        let broken: Vec<(&str, &str)> = violations
+            .iter()
+            .map(|violation| (violation.field.as_str(), violation.constraint.as_str()))
+            .collect();
+
+        assert_eq!(
+            broken,
+            vec![
+                ("emailAddress", "email"),
+                ("password", "min_length"),
+                ("password", "uppercase"),
+            ]
+        );
+    }
+
+    #[cfg(feature = "strength")]
+    #[test]
+    fn when_user_is_created_with_a_guessable_password_should_return_the_strength_feedback() {
+        let user = User::new("test@test.com", "James", "James!23");
+
+        match user {
+            Err(ApplicationError::WeakPassword { score, suggestions, .. }) => {
+                assert!(score < MINIMUM_PASSWORD_SCORE);
+                assert!(!suggestions.is_empty());
+            }
+            _ => panic!("Expected ApplicationError::WeakPassword"),
+        }
+    }
+
+    #[test]
+    fn when_user_is_created_should_verify_a_matching_password() {
+        let user = User::new("test@test.com", "James", "Purple-Otter-Canoe-42").unwrap();
+        
+        assert_ne!(user.password(), "Test!23");
+        
+        let is_password_valid = user.verify_password("Purple-Otter-Canoe-42");
+        
+        assert!(is_password_valid.is_ok());
+    }
+
+    #[test]
+    fn when_user_is_created_should_fail_if_password_does_not_match() {
+        let user = User::new("test@test.com", "James", "Purple-Otter-Canoe-42").unwrap();
+
+        assert_ne!(user.password(), "Test!23");
+
+        let is_password_valid = user.verify_password("This is the wrong password");
+
+        assert!(is_password_valid.is_err());
+    }
+
+    #[cfg(feature = "ids")]
+    #[test]
+    fn when_a_user_is_updated_to_premium_should_keep_their_id() {
+        let id = Uuid::new_v4();
+        let user = User::builder("test@test.com", "James")
+            .hashed_password("hashed")
+            .id(id)
+            .build()
+            .unwrap();
+
+        let premium_user = user.update_to_premium();
+
+        assert_eq!(premium_user.id(), id);
+    }
+
+    #[test]
+    fn when_a_user_is_built_should_keep_every_field_that_was_set() {
+        let user = User::builder("test@test.com", "James")
+            .hashed_password("hashed")
+            .age(Some(36))
+            .premium()
+            .build()
+            .unwrap();
+
+        assert!(matches!(user, User::Premium { is_premium: true, .. }));
+        assert_eq!(user.age(), Some(36));
+        assert_eq!(user.password(), "hashed");
+        assert!(User::builder("test@test.com", "James").build().is_err());
+    }
+
+    #[cfg(feature = "birthdays")]
+    fn date(year: i32, month: u8, day: u8) -> Date {
+        Date::from_calendar_date(year, month.try_into().unwrap(), day).unwrap()
+    }
+
+    #[cfg(feature = "birthdays")]
+    #[test]
+    fn the_age_should_be_worked_out_from_the_date_of_birth() {
+        assert_eq!(age_on(date(1990, 6, 15), date(2026, 6, 14)), 35);
+        assert_eq!(age_on(date(1990, 6, 15), date(2026, 6, 15)), 36);
+        assert_eq!(age_on(date(2008, 2, 29), date(2027, 2, 28)), 18);
+        assert_eq!(age_on(date(2008, 2, 29), date(2027, 3, 1)), 19);
+
+        let mut user = User::builder("test@test.com", "James")
+            .hashed_password("hashed")
+            .date_of_birth(Some(date(1990, 6, 15)))
+            .build()
+            .unwrap();
+        assert_eq!(user.age(), Some(age_on(date(1990, 6, 15), today())));
+        assert_eq!(user.details().age, user.age());
+
+        user.update_date_of_birth(None);
+        assert_eq!((user.age(), user.date_of_birth()), (None, None));
+    }
+
+    #[cfg(feature = "usernames")]
+    #[test]
+    fn a_username_should_be_checked_for_length_and_characters() {
+        let constraint = |username: &str| match User::username_is_valid(username) {
+            Ok(()) => "valid".to_string(),
+            Err(ApplicationError::ValidationFailed(violations)) => violations[0].constraint.clone(),
+            Err(e) => panic!("unexpected {}", e),
+        };
+
+        assert_eq!(constraint("James_K2"), "valid");
+        assert_eq!(constraint("jk"), "length");
+        assert_eq!(constraint(&"j".repeat(31)), "length");
+        assert_eq!(constraint("james@test.com"), "characters");
+        assert_eq!(constraint("jämes"), "characters");
+        assert_eq!(constraint("2james"), "first_character");
+
+        let mut user = User::from("test@test.com", "James", "hashed");
+        user.update_username(Some("James_K2"));
+        assert_eq!(user.username().as_deref(), Some("james_k2"));
+    }
+
+    #[cfg(feature = "metadata")]
+    #[test]
+    fn metadata_should_be_merged_by_key_and_kept_to_its_size() {
+        let mut user = User::from("test@test.com", "James", "hashed");
+        let patch = |value: serde_json::Value| match value {
+            serde_json::Value::Object(patch) => patch,
+            _ => unreachable!(),
+        };
+
+        user.merge_metadata(&patch(serde_json::json!({"team": "otters", "seat": 4})))
+            .unwrap();
+        user.merge_metadata(&patch(serde_json::json!({"seat": null, "lead": true})))
+            .unwrap();
+        let oversized = user.merge_metadata(&patch(
+            serde_json::json!({"notes": "n".repeat(MAX_METADATA_BYTES)}),
+        ));
+
+        assert!(matches!(oversized, Err(ApplicationError::ValidationFailed(_))));
+        assert_eq!(
+            serde_json::Value::Object(user.metadata()),
+            serde_json::json!({"team": "otters", "lead": true})
+        );
+    }
+
+    #[cfg(all(feature = "tiers", feature = "ids"))]
+    #[test]
+    fn changing_tier_should_keep_the_user_and_change_what_they_can_do() {
+        let user = User::builder("test@test.com", "James")
+            .hashed_password("hashed")
+            .tier(Some(Tier::Gold))
+            .build()
+            .unwrap();
+        let id = user.id();
+
+        let downgraded = user.with_tier(Some(Tier::Silver));
+        assert_eq!(downgraded.tier(), Some(Tier::Silver));
+        assert!(!downgraded.capabilities().priority_support);
+
+        let standard = downgraded.with_tier(None);
+        assert!(matches!(standard, User::Standard { .. }));
+        assert_eq!(standard.id(), id);
+        assert_eq!(standard.capabilities().max_email_aliases, 1);
+
+        let platinum = standard.with_tier(Some(Tier::Platinum));
+        assert!(platinum.capabilities().early_access);
+        assert!(Tier::Silver < Tier::Gold && Tier::Gold < Tier::Platinum);
+        assert_eq!("platinum".parse::<Tier>().unwrap(), Tier::Platinum);
+    }
+
+    #[cfg(feature = "phones")]
+    #[test]
+    fn a_phone_number_should_be_normalized_to_e164() {
+        let normalized = |phone_number: &str| match User::normalize_phone_number(phone_number) {
+            Ok(normalized) => normalized,
+            Err(ApplicationError::ValidationFailed(violations)) => violations[0].constraint.clone(),
+            Err(e) => panic!("unexpected {}", e),
+        };
+
+        assert_eq!(normalized("+44 20 7946 0958"), "+442079460958");
+        assert_eq!(normalized("0044 (20) 7946-0958"), "+442079460958");
+        assert_eq!(normalized("+1.415.555.2671"), "+14155552671");
+        assert_eq!(normalized("020 7946 0958"), "country_code");
+        assert_eq!(normalized("+0 20 7946 0958"), "country_code");
+        assert_eq!(normalized("+44 20 7946 O958"), "characters");
+        assert_eq!(normalized("+44 123"), "length");
+        assert_eq!(normalized("+44 1234 5678 9012 34"), "length");
+    }
+
+    #[cfg(all(feature = "validation", feature = "phones"))]
+    #[test]
+    fn a_new_user_should_keep_the_normalized_phone_number_or_fail_like_registration() {
+        let user = User::builder("test@test.com", "James")
+            .password("Purple-Otter-Canoe-42")
+            .phone_number(Some("+44 20 7946 0958".to_string()))
+            .build()
+            .unwrap();
+        assert_eq!(user.phone_number().as_deref(), Some("+442079460958"));
+
+        let user = User::builder("test@test.com", "James")
+            .password("Purple-Otter-Canoe-42")
+            .phone_number(Some("020 7946 0958".to_string()))
+            .build();
+        match user {
+            Err(ApplicationError::ValidationFailed(violations)) => {
+                assert_eq!(violations.len(), 1);
+                assert_eq!(violations[0].field, "phoneNumber");
+                assert_eq!(violations[0].constraint, "country_code");
+            }
+            other => panic!("Expected ApplicationError::ValidationFailed, got {:?}", other.err()),
+        }
+    }
+
+    #[cfg(feature = "birthdays")]
+    #[test]
+    fn a_date_of_birth_in_the_future_or_too_recent_should_fail_validation() {
+        let today = date(2026, 10, 14);
+        let constraint = |date_of_birth| match User::date_of_birth_is_valid(date_of_birth, today) {
+            Ok(()) => "valid".to_string(),
+            Err(ApplicationError::ValidationFailed(violations)) => violations[0].constraint.clone(),
+            Err(e) => panic!("unexpected {}", e),
+        };
+
+        assert_eq!(constraint(date(1990, 6, 15)), "valid");
+        assert_eq!(constraint(date(2013, 10, 14)), "valid");
+        assert_eq!(constraint(date(2013, 10, 15)), "minimum_age");
+        assert_eq!(constraint(date(2026, 10, 15)), "not_in_future");
+        assert_eq!(constraint(date(1850, 1, 1)), "maximum_age");
+    }
+
+    #[cfg(feature = "validation")]
+    #[test]
+    fn when_a_user_is_built_with_a_password_should_validate_and_hash_it() {
+        let user = User::builder("test@test.com", "James")
+            .password("Purple-Otter-Canoe-42")
+            .build()
+            .unwrap();
+        let invalid = User::builder("thisisaninvalidemail", "James")
+            .password("Purple-Otter-Canoe-42")
+            .build();
+        let unvalidated = User::builder("thisisaninvalidemail", "")
+            .hashed_password("hashed")
+            .build();
+
+        assert!(user.verify_password("Purple-Otter-Canoe-42").is_ok());
+        assert!(invalid.is_err());
+        assert!(unvalidated.is_ok());
+    }
+
+    #[cfg(feature = "roles")]
+    #[test]
+    fn a_role_should_be_kept_separate_from_the_tier() {
+        let user = User::from("test@test.com", "James", "hashed");
+        let admin = User::builder("admin@test.com", "Admin")
+            .hashed_password("hashed")
+            .role(Role::Admin)
+            .build()
+            .unwrap();
+
+        let premium_admin = admin.update_to_premium();
+
+        assert_eq!(user.role(), Role::Member);
+        assert_eq!(premium_admin.role(), Role::Admin);
+        assert_eq!("admin".parse::<Role>().unwrap(), Role::Admin);
+        assert_eq!(Role::Member.as_str(), "member");
+        assert!("owner".parse::<Role>().is_err());
+    }
+
+    #[cfg(feature = "validation")]
+    #[test]
+    fn when_user_is_created_with_an_invalid_name_should_fail_validation_on_the_name() {
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let invalid_names = [
+            "".to_string(),
+            "   ".to_string(),
+            "James\u{0000}".to_string(),
+            "James\nBond".to_string(),
+            "a".repeat(MAX_NAME_LENGTH + 1),
+        ];
+
+        for name in invalid_names {
+            let user = User::new("test@test.com", &name, "Purple-Otter-Canoe-42");
+
+            match user {
+                Err(ApplicationError::ValidationFailed(violations)) => {
+                    assert_eq!(violations.len(), 1, "{:?}", name);
+                    assert_eq!(violations[0].field, "name");
+                }
+                _ => panic!("Expected ApplicationError::ValidationFailed for {:?}", name),
+            }
+            assert!(matches!(User::name_is_valid(&name), Err(ApplicationError::InvalidName(_))));
+        }
+        assert!(User::name_is_valid("Zoë Ångström").is_ok());
+        assert!(User::name_is_valid(&family.repeat(MAX_NAME_LENGTH)).is_ok());
+    }
+
+    #[cfg(feature = "validation")]
+    #[test]
+    fn when_a_name_is_updated_past_the_limit_should_be_truncated_between_graphemes() {
+        let mut user = User::new("test@test.com", "James", "Purple-Otter-Canoe-42").unwrap();
+        let accented = "e\u{0301}";
+
+        user.update_name(&accented.repeat(MAX_NAME_LENGTH + 5));
+
+        assert_eq!(user.name(), accented.repeat(MAX_NAME_LENGTH));
+    }
+}
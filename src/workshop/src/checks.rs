@@ -0,0 +1,189 @@
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+
+// Strong enough for the zxcvbn check introduced in module 11
+const PASSWORD: &str = "Purple-Otter-Canoe-42";
+
+#[derive(Clone, Copy, Debug)]
+pub enum Check {
+    RegisterReturnsCreated,
+    RegisteredUserCanBeRetrieved,
+    UnknownUserReturnsNotFound,
+    LoginWithCorrectPasswordSucceeds,
+    LoginWithWrongPasswordIsUnauthorized,
+    PasswordIsNotReturnedInPlainText,
+    InvalidEmailIsRejected,
+    DuplicateRegistrationReturnsConflict,
+}
+
+// Each module is expected to keep passing the checks of the modules before it
+pub fn checks_for_module(module: u8) -> Option<Vec<Check>> {
+    let mut checks = vec![
+        Check::RegisterReturnsCreated,
+        Check::RegisteredUserCanBeRetrieved,
+        Check::UnknownUserReturnsNotFound,
+    ];
+
+    match module {
+        5 => {}
+        6..=11 => {
+            checks.push(Check::LoginWithCorrectPasswordSucceeds);
+            checks.push(Check::LoginWithWrongPasswordIsUnauthorized);
+        }
+        _ => return None,
+    }
+    if module >= 8 {
+        checks.push(Check::PasswordIsNotReturnedInPlainText);
+        checks.push(Check::InvalidEmailIsRejected);
+    }
+    if module >= 11 {
+        checks.push(Check::DuplicateRegistrationReturnsConflict);
+    }
+
+    Some(checks)
+}
+
+fn unique_email() -> String {
+    format!("workshop-{}@test.com", uuid::Uuid::new_v4())
+}
+
+async fn register(client: &Client, base_url: &str, email: &str) -> Result<StatusCode, String> {
+    client
+        .post(format!("{}/users", base_url))
+        .json(&json!({"emailAddress": email, "name": "Workshop", "password": PASSWORD}))
+        .send()
+        .await
+        .map(|response| response.status())
+        .map_err(|e| e.to_string())
+}
+
+async fn login(
+    client: &Client,
+    base_url: &str,
+    email: &str,
+    password: &str,
+) -> Result<StatusCode, String> {
+    client
+        .post(format!("{}/login", base_url))
+        .json(&json!({"emailAddress": email, "password": password}))
+        .send()
+        .await
+        .map(|response| response.status())
+        .map_err(|e| e.to_string())
+}
+
+async fn get_user(client: &Client, base_url: &str, email: &str) -> Result<(StatusCode, Value), String> {
+    let response = client
+        .get(format!("{}/users/{}", base_url, email))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    let status = response.status();
+    let body = response.json().await.unwrap_or(Value::Null);
+
+    Ok((status, body))
+}
+
+fn expect_status(actual: StatusCode, expected: StatusCode) -> Result<(), String> {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(format!("expected {} but got {}", expected, actual))
+    }
+}
+
+// Registers a fresh user for the checks that need one to exist
+async fn registered_user(client: &Client, base_url: &str) -> Result<String, String> {
+    let email = unique_email();
+    let status = register(client, base_url, &email).await?;
+    expect_status(status, StatusCode::CREATED).map_err(|e| format!("registration failed, {}", e))?;
+
+    Ok(email)
+}
+
+impl Check {
+    pub fn description(&self) -> &'static str {
+        match self {
+            Check::RegisterReturnsCreated => "POST /users returns 201 Created",
+            Check::RegisteredUserCanBeRetrieved => "GET /users/{email} returns the registered user",
+            Check::UnknownUserReturnsNotFound => "GET /users/{email} returns 404 for unknown users",
+            Check::LoginWithCorrectPasswordSucceeds => "POST /login returns 200 with the right password",
+            Check::LoginWithWrongPasswordIsUnauthorized => "POST /login returns 401 with the wrong password",
+            Check::PasswordIsNotReturnedInPlainText => "passwords are hashed before they are stored",
+            Check::InvalidEmailIsRejected => "registering an invalid email address is rejected",
+            Check::DuplicateRegistrationReturnsConflict => "registering the same email twice returns 409 Conflict",
+        }
+    }
+
+    pub async fn run(&self, client: &Client, base_url: &str) -> Result<(), String> {
+        match self {
+            Check::RegisterReturnsCreated => {
+                let status = register(client, base_url, &unique_email()).await?;
+                expect_status(status, StatusCode::CREATED)
+            }
+            Check::RegisteredUserCanBeRetrieved => {
+                let email = registered_user(client, base_url).await?;
+                let (status, body) = get_user(client, base_url, &email).await?;
+                expect_status(status, StatusCode::OK)?;
+
+                match body.get("emailAddress").and_then(Value::as_str) {
+                    Some(returned) if returned == email => Ok(()),
+                    _ => Err(format!("expected emailAddress {} in {}", email, body)),
+                }
+            }
+            Check::UnknownUserReturnsNotFound => {
+                let (status, _) = get_user(client, base_url, &unique_email()).await?;
+                expect_status(status, StatusCode::NOT_FOUND)
+            }
+            Check::LoginWithCorrectPasswordSucceeds => {
+                let email = registered_user(client, base_url).await?;
+                expect_status(login(client, base_url, &email, PASSWORD).await?, StatusCode::OK)
+            }
+            Check::LoginWithWrongPasswordIsUnauthorized => {
+                let email = registered_user(client, base_url).await?;
+                let status = login(client, base_url, &email, "not-the-password").await?;
+                expect_status(status, StatusCode::UNAUTHORIZED)
+            }
+            Check::PasswordIsNotReturnedInPlainText => {
+                let email = registered_user(client, base_url).await?;
+                let (_, body) = get_user(client, base_url, &email).await?;
+
+                if body.to_string().contains(PASSWORD) {
+                    Err("the plain text password was returned".to_string())
+                } else {
+                    Ok(())
+                }
+            }
+            Check::InvalidEmailIsRejected => {
+                let status = register(client, base_url, "not-an-email-address").await?;
+                if status.is_success() {
+                    Err(format!("expected an error but got {}", status))
+                } else {
+                    Ok(())
+                }
+            }
+            Check::DuplicateRegistrationReturnsConflict => {
+                let email = registered_user(client, base_url).await?;
+                let status = register(client, base_url, &email).await?;
+                expect_status(status, StatusCode::CONFLICT)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn later_modules_should_include_the_checks_of_earlier_ones() {
+        let module_six = checks_for_module(6).unwrap();
+        let module_eleven = checks_for_module(11).unwrap();
+
+        assert_eq!(checks_for_module(5).unwrap().len(), 3);
+        assert_eq!(module_six.len(), 5);
+        assert_eq!(checks_for_module(8).unwrap().len(), 7);
+        assert_eq!(module_eleven.len(), 8);
+        assert!(checks_for_module(4).is_none());
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use reqwest::{Client, StatusCode};
+use serde_json::json;
+
+const PASSWORD: &str = "Purple-Otter-Canoe-42";
+
+// Used when no credential list is given, the kind of passwords that top every breach dump
+const COMMON_PASSWORDS: [&str; 8] = [
+    "123456",
+    "password",
+    "123456789",
+    "qwerty",
+    "Password1",
+    "letmein",
+    "iloveyou",
+    "Summer2024!",
+];
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Attack {
+    /// Replay a list of leaked email and password pairs against /login
+    CredentialStuffing,
+}
+
+pub struct LoadSettings {
+    pub base_url: String,
+    pub requests: usize,
+    pub concurrency: usize,
+    pub attack: Option<Attack>,
+    pub credentials: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Credential {
+    pub email_address: String,
+    pub password: String,
+}
+
+// One `email:password` pair per line, blank lines and lines starting with # are skipped
+pub fn parse_credentials(contents: &str) -> Vec<Credential> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .map(|(email_address, password)| Credential {
+            email_address: email_address.trim().to_string(),
+            password: password.to_string(),
+        })
+        .collect()
+}
+
+fn default_credentials() -> Vec<Credential> {
+    (0..25)
+        .flat_map(|user| {
+            COMMON_PASSWORDS.iter().map(move |password| Credential {
+                email_address: format!("victim-{}@test.com", user),
+                password: password.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct Tally {
+    statuses: BTreeMap<u16, usize>,
+    errors: usize,
+    // The attempt on which each status was first seen, shows when a protection kicked in
+    first_seen: BTreeMap<u16, usize>,
+}
+
+pub async fn run(settings: LoadSettings) -> Result<()> {
+    let client = Client::builder().timeout(Duration::from_secs(5)).build()?;
+
+    let credentials = match (settings.attack, &settings.credentials) {
+        (Some(Attack::CredentialStuffing), Some(path)) => {
+            let contents = std::fs::read_to_string(Path::new(path))
+                .with_context(|| format!("unable to read {}", path))?;
+            parse_credentials(&contents)
+        }
+        (Some(Attack::CredentialStuffing), None) => default_credentials(),
+        (None, _) => vec![register_user(&client, &settings.base_url).await?],
+    };
+    if credentials.is_empty() {
+        bail!("the credential list is empty");
+    }
+
+    match settings.attack {
+        Some(Attack::CredentialStuffing) => println!(
+            "Replaying {} credentials against {}/login, {} attempts with {} workers",
+            credentials.len(),
+            settings.base_url,
+            settings.requests,
+            settings.concurrency
+        ),
+        None => println!(
+            "Logging in as {} {} times with {} workers",
+            credentials[0].email_address, settings.requests, settings.concurrency
+        ),
+    }
+
+    let credentials = Arc::new(credentials);
+    let next = Arc::new(AtomicUsize::new(0));
+    let tally = Arc::new(Mutex::new(Tally::default()));
+    let started = Instant::now();
+
+    let mut workers = tokio::task::JoinSet::new();
+    for _ in 0..settings.concurrency.max(1) {
+        let (client, credentials, next, tally) =
+            (client.clone(), credentials.clone(), next.clone(), tally.clone());
+        let base_url = settings.base_url.clone();
+        let requests = settings.requests;
+
+        workers.spawn(async move {
+            loop {
+                let attempt = next.fetch_add(1, Ordering::Relaxed);
+                if attempt >= requests {
+                    break;
+                }
+
+                let credential = &credentials[attempt % credentials.len()];
+                let result = login(&client, &base_url, credential).await;
+
+                let mut tally = tally.lock().unwrap();
+                match result {
+                    Ok(status) => {
+                        *tally.statuses.entry(status.as_u16()).or_default() += 1;
+                        tally.first_seen.entry(status.as_u16()).or_insert(attempt + 1);
+                    }
+                    Err(_) => tally.errors += 1,
+                }
+            }
+        });
+    }
+    workers.join_all().await;
+
+    let elapsed = started.elapsed();
+    print_tally(&tally.lock().unwrap(), settings.requests, elapsed);
+
+    if settings.attack.is_some() {
+        print_protection_metrics(&client, &settings.base_url).await;
+    }
+
+    Ok(())
+}
+
+fn print_tally(tally: &Tally, requests: usize, elapsed: Duration) {
+    println!(
+        "\n{} requests in {:.2?} ({:.0}/s)",
+        requests,
+        elapsed,
+        requests as f64 / elapsed.as_secs_f64()
+    );
+    for (status, count) in &tally.statuses {
+        let status = StatusCode::from_u16(*status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        println!(
+            "  {:<28} {:>6}  first seen on attempt {}",
+            status.to_string(),
+            count,
+            tally.first_seen[&status.as_u16()]
+        );
+    }
+    if tally.errors > 0 {
+        println!("  {:<28} {:>6}", "connection errors", tally.errors);
+    }
+}
+
+async fn register_user(client: &Client, base_url: &str) -> Result<Credential> {
+    let credential = Credential {
+        email_address: format!("loadgen-{}@test.com", uuid::Uuid::new_v4()),
+        password: PASSWORD.to_string(),
+    };
+
+    let status = client
+        .post(format!("{}/users", base_url))
+        .json(&json!({
+            "emailAddress": credential.email_address,
+            "name": "Load Generator",
+            "password": credential.password,
+        }))
+        .send()
+        .await
+        .with_context(|| format!("is the API running on {}?", base_url))?
+        .status();
+    if status != StatusCode::CREATED {
+        bail!("registering the load generator's user returned {}", status);
+    }
+
+    Ok(credential)
+}
+
+async fn login(client: &Client, base_url: &str, credential: &Credential) -> Result<StatusCode> {
+    Ok(client
+        .post(format!("{}/login", base_url))
+        .json(&json!({
+            "emailAddress": credential.email_address,
+            "password": credential.password,
+        }))
+        .send()
+        .await?
+        .status())
+}
+
+// What the service's own metrics say about the attack, the anomaly detector only reports once its
+// next interval has elapsed
+async fn print_protection_metrics(client: &Client, base_url: &str) {
+    let Ok(response) = client.get(format!("{}/metrics", base_url)).send().await else {
+        return;
+    };
+    let Ok(metrics) = response.text().await else {
+        return;
+    };
+
+    let lines: Vec<&str> = metrics
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter(|line| {
+            ["login", "brute_force", "rate_anomaly"]
+                .iter()
+                .any(|name| line.contains(name))
+        })
+        .collect();
+
+    if !lines.is_empty() {
+        println!("\nReported by {}/metrics", base_url);
+        for line in lines {
+            println!("  {}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_should_be_parsed_one_pair_per_line() {
+        let credentials = parse_credentials("# leaked.txt\nalice@test.com:hunter2\n\nbob@test.com:pass:word\nnot-a-pair\n");
+
+        assert_eq!(
+            credentials,
+            vec![
+                Credential {
+                    email_address: "alice@test.com".to_string(),
+                    password: "hunter2".to_string(),
+                },
+                Credential {
+                    email_address: "bob@test.com".to_string(),
+                    password: "pass:word".to_string(),
+                },
+            ]
+        );
+    }
+}
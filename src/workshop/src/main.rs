@@ -0,0 +1,257 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use tokio::process;
+
+mod checks;
+mod loadgen;
+
+use checks::{checks_for_module, Check};
+use loadgen::{Attack, LoadSettings};
+
+#[derive(Parser)]
+#[command(name = "workshop", about = "Acceptance checks and demo tooling for the workshop modules")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Build a module, start its API and check it behaves the way the exercises expect
+    Check {
+        /// The module number, e.g. 9
+        module: u8,
+        /// Check the reference solution in src/solutions instead of your work in src/examples
+        #[arg(long)]
+        solutions: bool,
+        /// The port the module's API listens on
+        #[arg(long, default_value_t = 3000)]
+        port: u16,
+    },
+    /// List the checks that apply to each module
+    List,
+    /// Send logins to a running API, or attack it to demonstrate the login protections
+    Loadgen {
+        #[arg(long, default_value = "http://127.0.0.1:3000")]
+        base_url: String,
+        /// Total number of login requests to send
+        #[arg(long, default_value_t = 1000)]
+        requests: usize,
+        /// Number of requests in flight at once
+        #[arg(long, default_value_t = 10)]
+        concurrency: usize,
+        #[arg(long, value_enum)]
+        attack: Option<Attack>,
+        /// A file of `email:password` lines to replay, defaults to common passwords
+        #[arg(long, requires = "attack")]
+        credentials: Option<String>,
+    },
+}
+
+enum Outcome {
+    Pass,
+    Fail(String),
+}
+
+struct Report {
+    results: Vec<(String, Outcome)>,
+}
+
+impl Report {
+    fn record(&mut self, name: &str, outcome: Outcome) {
+        match &outcome {
+            Outcome::Pass => println!("  PASS  {}", name),
+            Outcome::Fail(reason) => println!("  FAIL  {}: {}", name, reason),
+        }
+        self.results.push((name.to_string(), outcome));
+    }
+
+    fn passed(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|(_, outcome)| matches!(outcome, Outcome::Pass))
+            .count()
+    }
+}
+
+fn module_directory(module: u8, solutions: bool) -> PathBuf {
+    let root = if solutions { "solutions" } else { "examples" };
+
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("..")
+        .join(root)
+        .join(format!("module{}", module))
+        .join("rust_app")
+}
+
+// Builds the module and returns the path of its API binary, taken from cargo's JSON output so it
+// works for modules in the shared workspace and for the ones with their own.
+async fn build(directory: &Path) -> Result<PathBuf, String> {
+    let manifest = directory.join("Cargo.toml");
+    let output = process::Command::new("cargo")
+        .arg("build")
+        .arg("--manifest-path")
+        .arg(&manifest)
+        .arg("--message-format=json-render-diagnostics")
+        .output()
+        .await
+        .map_err(|e| format!("unable to run cargo: {}", e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let tail: Vec<&str> = stderr.lines().rev().take(20).collect();
+        return Err(tail.into_iter().rev().collect::<Vec<_>>().join("\n"));
+    }
+
+    let canonical_manifest = manifest.canonicalize().map_err(|e| e.to_string())?;
+    let mut executables = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let Ok(message) = serde_json::from_str::<serde_json::Value>(line) else {
+            continue;
+        };
+        let from_module = message["manifest_path"]
+            .as_str()
+            .and_then(|path| Path::new(path).canonicalize().ok())
+            .is_some_and(|path| path == canonical_manifest);
+
+        if let (true, Some(executable)) = (from_module, message["executable"].as_str()) {
+            executables.push((message["target"]["name"].to_string(), PathBuf::from(executable)));
+        }
+    }
+
+    // Later modules add a worker and a CLI next to the API, which is always called rust_users
+    executables.sort_by_key(|(name, _)| name != "\"rust_users\"");
+    executables
+        .into_iter()
+        .next()
+        .map(|(_, executable)| executable)
+        .ok_or_else(|| "no binary was produced".to_string())
+}
+
+async fn wait_until_listening(child: &mut process::Child, port: u16) -> Result<(), String> {
+    for _ in 0..120 {
+        if let Ok(Some(status)) = child.try_wait() {
+            return Err(format!(
+                "the API exited with {}, is the database from docker-compose running?",
+                status
+            ));
+        }
+        if tokio::net::TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+
+    Err(format!("nothing was listening on port {} after 30 seconds", port))
+}
+
+async fn check_module(module: u8, solutions: bool, port: u16) -> Result<bool> {
+    let Some(checks) = checks_for_module(module) else {
+        bail!("there are no acceptance checks for module {}, try 5 to 11", module);
+    };
+    let directory = module_directory(module, solutions);
+    let Ok(directory) = directory.canonicalize() else {
+        bail!("{} does not exist", directory.display());
+    };
+
+    println!("Module {} ({})", module, directory.display());
+    let mut report = Report {
+        results: Vec::new(),
+    };
+
+    let executable = match build(&directory).await {
+        Ok(executable) => {
+            report.record("the module compiles", Outcome::Pass);
+            executable
+        }
+        Err(reason) => {
+            report.record("the module compiles", Outcome::Fail(reason));
+            return Ok(false);
+        }
+    };
+
+    // The API is started from its own directory so it picks up its config.json
+    let mut child = process::Command::new(&executable)
+        .current_dir(&directory)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("unable to start {}", executable.display()))?;
+
+    match wait_until_listening(&mut child, port).await {
+        Ok(_) => report.record("the API starts", Outcome::Pass),
+        Err(reason) => {
+            report.record("the API starts", Outcome::Fail(reason));
+            return Ok(false);
+        }
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()?;
+    let base_url = format!("http://127.0.0.1:{}", port);
+
+    for check in checks {
+        let outcome = match check.run(&client, &base_url).await {
+            Ok(_) => Outcome::Pass,
+            Err(reason) => Outcome::Fail(reason),
+        };
+        report.record(check.description(), outcome);
+    }
+
+    let _ = child.kill().await;
+
+    println!("{}/{} checks passed", report.passed(), report.results.len());
+
+    Ok(report.passed() == report.results.len())
+}
+
+fn list() {
+    for module in 5..=11 {
+        println!("Module {}", module);
+        for check in checks_for_module(module).unwrap_or_default() {
+            println!("  {}", Check::description(&check));
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Check {
+            module,
+            solutions,
+            port,
+        } => {
+            if !check_module(module, solutions, port).await? {
+                std::process::exit(1);
+            }
+        }
+        Command::List => list(),
+        Command::Loadgen {
+            base_url,
+            requests,
+            concurrency,
+            attack,
+            credentials,
+        } => {
+            loadgen::run(LoadSettings {
+                base_url,
+                requests,
+                concurrency,
+                attack,
+                credentials,
+            })
+            .await?
+        }
+    }
+
+    Ok(())
+}